@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use mediabox::time::ClockTime;
+use mediabox::TrackSelector;
+
 xflags::xflags! {
     src "./src/cli.rs"
 
@@ -17,8 +20,95 @@ xflags::xflags! {
             cmd packets {
                 optional --packets packet_filter: PacketFilter
                 optional --nal nal_filter: NalFilter
+                /// Skip packets before this time, e.g. `00:01:30` or `90s`.
+                optional --start start: ClockTime
+                /// Stop at the first packet after this time.
+                optional --end end: ClockTime
+            }
+
+            /// Measure the EBU R128 loudness and true peak of every audio track.
+            cmd audio {
+
+            }
+
+            /// Report the keyframe intervals and frame types of every video track.
+            cmd gop {
+
+            }
+
+            /// Report how far the timestamps of every track drift from a reference, and gaps in
+            /// them.
+            cmd sync {
+                /// Measure against the timestamps of this track instead of the first one.
+                optional --reference reference: u32
+                /// Measure against when packets arrive, for live inputs.
+                optional --wall-clock
+            }
+
+            /// Check the nesting and sizes of the elements of a Matroska file, and that mandatory
+            /// elements are present.
+            cmd ebml {
+
+            }
+
+            /// Dump the element tree of the container with the offsets, sizes and values of the
+            /// elements.
+            cmd structure {
+
             }
         }
+
+        /// Copy a single track or an attachment of an input into a file.
+        cmd extract {
+            required -i, --input input: String
+            required -o, --output output: String
+            /// The track to copy, e.g. `3`, `video`, `audio`, `subtitle` or `lang:jpn`.
+            optional --track track: TrackSelector
+            /// The name of the attachment to copy, e.g. `font.ttf`.
+            optional --attachment attachment: String
+        }
+
+        /// Remux an input into another container, optionally with only some of its tracks.
+        cmd remux {
+            required -i, --input input: String
+            required -o, --output output: String
+            /// Tracks to copy, e.g. `video`, `index:2`, `lang:jpn` or `codec:aac`. All tracks are
+            /// copied if none are given.
+            repeated --map map: TrackSelector
+        }
+
+        /// Copy tracks of several inputs into one file, e.g. to replace the audio of a movie.
+        cmd merge {
+            /// An input, referred to by its position starting from 0.
+            repeated -i, --input input: String
+            required -o, --output output: String
+            /// Tracks to take from an input, e.g. `0:video` or `1:lang:jpn`. All tracks of inputs
+            /// without a selector are taken.
+            repeated --map map: InputTrack
+            /// Shift the tracks of an input, e.g. `1:500ms` or `1:-2s`.
+            repeated --offset offset: InputShift
+        }
+
+        /// Copy the part of an input between two times into a file, starting at the keyframe
+        /// before the start.
+        cmd clip {
+            required -i, --input input: String
+            required -o, --output output: String
+            /// Where the clip starts, e.g. `00:01:00` or `60s`.
+            optional --start start: ClockTime
+            /// Where the clip ends, the end of the input if not given.
+            optional --end end: ClockTime
+        }
+
+        /// Remux an input, shifting and stretching the timestamps of its subtitles.
+        cmd subs {
+            required -i, --input input: String
+            required -o, --output output: String
+            /// Show subtitles later by this time, or earlier if negative, e.g. `2.5s` or `-500ms`.
+            optional --shift shift: Shift
+            /// Multiply subtitle timestamps by this factor, e.g. `1.001`.
+            optional --scale scale: f64
+        }
     }
 }
 
@@ -44,6 +134,73 @@ impl FromStr for NalFilter {
     }
 }
 
+/// A time to shift subtitles by, negative to show them earlier.
+#[derive(Debug, Clone, Copy)]
+pub struct Shift {
+    pub time: ClockTime,
+    pub earlier: bool,
+}
+
+impl FromStr for Shift {
+    type Err = anyhow::Error;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let (earlier, val) = match val.strip_prefix('-') {
+            Some(val) => (true, val),
+            None => (false, val.strip_prefix('+').unwrap_or(val)),
+        };
+
+        Ok(Shift {
+            time: val.parse()?,
+            earlier,
+        })
+    }
+}
+
+/// A [TrackSelector] for one of several inputs, e.g. `1:audio`.
+#[derive(Debug, Clone)]
+pub struct InputTrack {
+    pub input: usize,
+    pub selector: TrackSelector,
+}
+
+impl FromStr for InputTrack {
+    type Err = anyhow::Error;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let (input, selector) = val
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected an input and a track, e.g. `1:audio`"))?;
+
+        Ok(InputTrack {
+            input: input.parse()?,
+            selector: selector.parse()?,
+        })
+    }
+}
+
+/// A [Shift] for one of several inputs, e.g. `1:-500ms`.
+#[derive(Debug, Clone, Copy)]
+pub struct InputShift {
+    pub input: usize,
+    pub shift: Shift,
+}
+
+impl FromStr for InputShift {
+    type Err = anyhow::Error;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let (input, shift) = val
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected an input and a time, e.g. `1:500ms`"))?;
+
+        Ok(InputShift {
+            input: input.parse()?,
+            shift: shift.parse()?,
+        })
+    }
+}
+
 // generated start
 // The following code is generated by `xflags` macro.
 // Run `env UPDATE_XFLAGS=1 cargo build` to regenerate.
@@ -56,6 +213,11 @@ pub struct Mbox {
 #[derive(Debug)]
 pub enum MboxCmd {
     Analyze(Analyze),
+    Extract(Extract),
+    Remux(Remux),
+    Merge(Merge),
+    Clip(Clip),
+    Subs(Subs),
 }
 
 #[derive(Debug)]
@@ -68,6 +230,11 @@ pub struct Analyze {
 pub enum AnalyzeCmd {
     Codec(Codec),
     Packets(Packets),
+    Audio(Audio),
+    Gop(Gop),
+    Sync(Sync),
+    Ebml(Ebml),
+    Structure(Structure),
 }
 
 #[derive(Debug)]
@@ -77,6 +244,65 @@ pub struct Codec;
 pub struct Packets {
     pub packets: Option<PacketFilter>,
     pub nal: Option<NalFilter>,
+    pub start: Option<ClockTime>,
+    pub end: Option<ClockTime>,
+}
+
+#[derive(Debug)]
+pub struct Audio;
+
+#[derive(Debug)]
+pub struct Gop;
+
+#[derive(Debug)]
+pub struct Sync {
+    pub reference: Option<u32>,
+    pub wall_clock: bool,
+}
+
+#[derive(Debug)]
+pub struct Ebml;
+
+#[derive(Debug)]
+pub struct Structure;
+
+#[derive(Debug)]
+pub struct Extract {
+    pub input: String,
+    pub output: String,
+    pub track: Option<TrackSelector>,
+    pub attachment: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Remux {
+    pub input: String,
+    pub output: String,
+    pub map: Vec<TrackSelector>,
+}
+
+#[derive(Debug)]
+pub struct Merge {
+    pub input: Vec<String>,
+    pub output: String,
+    pub map: Vec<InputTrack>,
+    pub offset: Vec<InputShift>,
+}
+
+#[derive(Debug)]
+pub struct Clip {
+    pub input: String,
+    pub output: String,
+    pub start: Option<ClockTime>,
+    pub end: Option<ClockTime>,
+}
+
+#[derive(Debug)]
+pub struct Subs {
+    pub input: String,
+    pub output: String,
+    pub shift: Option<Shift>,
+    pub scale: Option<f64>,
 }
 
 impl Mbox {
@@ -6,6 +6,9 @@ use h264_reader::{
     rbsp::{decode_nal, BitReader},
 };
 
+use std::collections::HashMap;
+
+use mediabox::codec::h264::AvcDecoderConfig;
 use mediabox::format::*;
 use mediabox::io::*;
 use mediabox::*;
@@ -28,14 +31,81 @@ async fn run(args: Mbox) -> anyhow::Result<()> {
         MboxCmd::Analyze(args) => {
             analyze(args).await?;
         }
+        MboxCmd::Extract(args) => {
+            extract(args).await?;
+        }
+        MboxCmd::Remux(args) => {
+            remux_tracks(&args.input, &args.output, &args.map).await?;
+        }
+        MboxCmd::Merge(args) => {
+            merge_inputs(args).await?;
+        }
+        MboxCmd::Clip(args) => {
+            let start = args.start.map(|t| t.duration).unwrap_or_default();
+            let end = args.end.map(|t| t.duration);
+            clip(&args.input, &args.output, start, end).await?;
+        }
+        MboxCmd::Subs(args) => {
+            subs(args).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn extract(args: Extract) -> anyhow::Result<()> {
+    match (args.track, args.attachment) {
+        (Some(track), None) => extract_track(&args.input, track, &args.output).await?,
+        (None, Some(name)) => extract_attachment(&args.input, &name, &args.output).await?,
+        _ => anyhow::bail!("Expected either --track or --attachment"),
     }
 
     Ok(())
 }
 
+async fn merge_inputs(args: Merge) -> anyhow::Result<()> {
+    let mut inputs = Vec::new();
+    for (i, url) in args.input.iter().enumerate() {
+        let selectors = args
+            .map
+            .iter()
+            .filter(|m| m.input == i)
+            .map(|m| m.selector.clone())
+            .collect();
+
+        let demuxer = open_input(url).await?;
+        let mut input = format::merge::MergeInput::new(demuxer).with_tracks(selectors);
+        for offset in args.offset.iter().filter(|o| o.input == i) {
+            input = match offset.shift {
+                Shift { time, earlier: true } => input.with_advance(time.duration),
+                Shift { time, earlier: false } => input.with_delay(time.duration),
+            };
+        }
+
+        inputs.push(input);
+    }
+
+    merge(inputs, &args.output).await
+}
+
+async fn subs(args: Subs) -> anyhow::Result<()> {
+    let mut retimer = filter::SubtitleRetimer::new().with_scale(args.scale.unwrap_or(1.0));
+    retimer = match args.shift {
+        Some(Shift { time, earlier: true }) => retimer.with_advance(time.duration),
+        Some(Shift { time, earlier: false }) => retimer.with_delay(time.duration),
+        None => retimer,
+    };
+
+    remux_with_filter(&args.input, &args.output, |pkt| retimer.filter(pkt)).await
+}
+
 async fn analyze(args: Analyze) -> anyhow::Result<()> {
     let path = args.input.unwrap();
     let mut io = Io::open_file(&path).await?;
+    if let AnalyzeCmd::Ebml(args) = args.subcommand {
+        return analyze_ebml(args, io).await;
+    }
+
     let mut cxt = MediaContext::default();
     cxt.register_all();
 
@@ -45,16 +115,50 @@ async fn analyze(args: Analyze) -> anyhow::Result<()> {
     match args.subcommand {
         AnalyzeCmd::Codec(args) => analyze_codec(args, demuxer).await?,
         AnalyzeCmd::Packets(args) => analyze_packets(args, demuxer).await?,
+        AnalyzeCmd::Audio(args) => analyze_audio(args, &cxt, demuxer).await?,
+        AnalyzeCmd::Gop(args) => analyze_gop(args, demuxer).await?,
+        AnalyzeCmd::Sync(args) => analyze_sync(args, demuxer).await?,
+        AnalyzeCmd::Structure(args) => analyze_structure(args, demuxer).await?,
+        AnalyzeCmd::Ebml(_) => unreachable!(),
     }
 
     Ok(())
 }
 
 async fn analyze_codec(args: Codec, mut demuxer: Box<dyn Demuxer>) -> anyhow::Result<()> {
-    let movie = demuxer.start().await?;
+    let mut movie = demuxer.start().await?;
+
+    // the container doesn't store everything, so measure it from the packets
+    let mut estimator = filter::BitrateEstimator::new();
+    loop {
+        let pkt = match demuxer.read().await {
+            Ok(pkt) => pkt,
+            Err(e) if e.is_end_of_input() => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        estimator.filter(pkt);
+    }
+    estimator.apply(&mut movie);
+
+    if let Some(duration) = movie.duration() {
+        println!("Duration: {}", time::ClockTime::new(duration));
+    }
 
     for track in movie.tracks {
         println!("Track #{} ({}):", track.id, track.info.name);
+        if let Some(bitrate) = track.bitrate() {
+            println!("\tbitrate: {} kbit/s", bitrate / 1000);
+        }
+        if let Some(stats) = estimator.stats(track.id) {
+            println!("\tpackets: {} ({} B)", stats.packets, stats.bytes);
+            if let (Some(min), Some(max)) = (stats.min_pts, stats.max_pts) {
+                println!("\tpts: {:.3} s - {:.3} s", min.as_secs_f64(), max.as_secs_f64());
+            }
+            if let Some(fps) = stats.frame_rate().filter(|_| track.info.video().is_some()) {
+                println!("\tframe rate: {fps:.3} fps");
+            }
+        }
         if let Err(e) = print_track_codec(track) {
             eprintln!("Failed to parse track codec: {e}");
         }
@@ -78,14 +182,28 @@ fn print_track_codec(track: Track) -> anyhow::Result<()> {
 fn print_video_codec(info: &VideoInfo) -> anyhow::Result<()> {
     match &info.codec {
         VideoCodec::H264(codec) => print_h264_codec(codec)?,
+        VideoCodec::Av1(codec) => println!("av1C: {} B", codec.config.len()),
+        VideoCodec::Vp8(codec) | VideoCodec::Vp9(codec) => println!("vpcC: {codec:?}"),
+        VideoCodec::Raw(codec) => println!("pixel_format: {:?}", codec.format),
     }
 
     Ok(())
 }
 
 fn print_h264_codec(codec: &H264Codec) -> anyhow::Result<()> {
+    let config = AvcDecoderConfig::from(codec);
+
+    println!("AVCDecoderConfigurationRecord()");
+    println!("\tAVCProfileIndication: {}", config.profile_indication);
+    println!("\tprofile_compatibility: {:08b}", config.profile_compatibility);
+    println!("\tAVCLevelIndication: {}", config.level_indication);
+    println!("\tlengthSizeMinusOne: {}", config.length_size - 1);
+    println!("\tnumOfSequenceParameterSets: {}", config.sps.len());
+    println!("\tnumOfPictureParameterSets: {}", config.pps.len());
+    println!("\tsize: {} B", config.to_span().len());
+
     let sps_slice = codec.sps.to_slice();
-    let nal = decode_nal(&sps_slice[1..])?;
+    let nal = decode_nal(&sps_slice)?;
 
     let reader = BitReader::new(nal.as_ref());
     let sps = SeqParameterSet::from_bits(reader).map_err(|e| anyhow::anyhow!("{:?}", e))?;
@@ -136,7 +254,7 @@ fn print_h264_codec(codec: &H264Codec) -> anyhow::Result<()> {
     context.put_seq_param_set(sps.clone());
 
     let pps_slice = codec.pps.to_slice();
-    let nal = decode_nal(&pps_slice[1..])?;
+    let nal = decode_nal(&pps_slice)?;
 
     let reader = BitReader::new(nal.as_ref());
     let pps = PicParameterSet::from_bits(&context, reader).map_err(|e| anyhow::anyhow!("{:?}", e))?;
@@ -158,6 +276,15 @@ async fn analyze_packets(args: Packets, mut demuxer: Box<dyn Demuxer>) -> anyhow
     println!("idx\ttrack\ttime\tsize");
     for i in 0.. {
         let pkt = demuxer.read().await?;
+        let pts = time::to_duration(pkt.time.pts, pkt.time.timebase);
+
+        if args.start.is_some_and(|start| pts < start.duration) {
+            continue;
+        }
+
+        if args.end.is_some_and(|end| pts > end.duration) {
+            break;
+        }
 
         print!("{i}\t");
         print!("{}\t", pkt.track.id);
@@ -172,6 +299,222 @@ async fn analyze_packets(args: Packets, mut demuxer: Box<dyn Demuxer>) -> anyhow
     Ok(())
 }
 
+async fn analyze_audio(
+    _args: Audio,
+    cxt: &MediaContext,
+    mut demuxer: Box<dyn Demuxer>,
+) -> anyhow::Result<()> {
+    let movie = demuxer.start().await?;
+
+    let mut decoders = HashMap::new();
+    for track in &movie.tracks {
+        if !matches!(track.info.kind, MediaKind::Audio(_)) {
+            continue;
+        }
+
+        match cxt.find_decoder_for_track(track) {
+            Ok(decoder) => {
+                decoders.insert(track.id, decoder);
+            }
+            Err(e) => eprintln!("Skipping track #{} ({}): {e}", track.id, track.info.name),
+        }
+    }
+
+    anyhow::ensure!(!decoders.is_empty(), "No audio tracks which can be decoded");
+
+    let mut analyzer = filter::LoudnessAnalyzer::new();
+    loop {
+        let pkt = match demuxer.read().await {
+            Ok(pkt) => pkt,
+            Err(e) if e.is_end_of_input() => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let track_id = pkt.track.id;
+        let Some(decoder) = decoders.get_mut(&track_id) else {
+            continue;
+        };
+
+        decoder.feed(pkt)?;
+        while let Some(decoded) = decoder.receive() {
+            if let Some(frame) = decoded.into_audio() {
+                analyzer.filter(track_id, &frame);
+            }
+        }
+    }
+
+    for track in movie.tracks {
+        let Some(loudness) = analyzer.loudness(track.id) else {
+            continue;
+        };
+
+        println!("Track #{} ({}):", track.id, track.info.name);
+        println!("\tintegrated: {:.1} LUFS", loudness.integrated);
+        println!("\tmomentary max: {:.1} LUFS", loudness.momentary_max);
+        println!("\ttrue peak: {:.1} dBTP", loudness.true_peak);
+    }
+
+    Ok(())
+}
+
+async fn analyze_gop(_args: Gop, mut demuxer: Box<dyn Demuxer>) -> anyhow::Result<()> {
+    let movie = demuxer.start().await?;
+
+    let mut analyzer = filter::GopAnalyzer::new();
+    loop {
+        let pkt = match demuxer.read().await {
+            Ok(pkt) => pkt,
+            Err(e) if e.is_end_of_input() => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        analyzer.filter(pkt);
+    }
+
+    for track in movie.tracks {
+        let Some(stats) = analyzer.stats(track.id) else {
+            continue;
+        };
+
+        println!("Track #{} ({}):", track.id, track.info.name);
+        println!("\tframes: {}", stats.frames);
+        println!("\tkeyframes: {}", stats.keyframes.len());
+        let lengths = &stats.gop_lengths;
+        if let (Some(min), Some(max)) = (lengths.iter().min(), lengths.iter().max()) {
+            println!("\tGOP length: {min}-{max} frames");
+        }
+
+        let intervals = stats.keyframe_intervals();
+        if let (Some(min), Some(max)) = (intervals.iter().min(), intervals.iter().max()) {
+            println!(
+                "\tkeyframe interval: {:.3}-{:.3} s",
+                min.as_secs_f64(),
+                max.as_secs_f64()
+            );
+        }
+
+        if stats.i_frames + stats.p_frames + stats.b_frames > 0 {
+            println!(
+                "\tI/P/B frames: {}/{}/{}",
+                stats.i_frames, stats.p_frames, stats.b_frames
+            );
+        }
+
+        if stats.leading_frames > 0 {
+            println!(
+                "\t{} frames before the first keyframe",
+                stats.leading_frames
+            );
+        }
+
+        if !stats.is_regular() {
+            println!("\tIrregular keyframe cadence, unsuitable for HLS segmentation");
+        }
+    }
+
+    Ok(())
+}
+
+async fn analyze_sync(args: Sync, mut demuxer: Box<dyn Demuxer>) -> anyhow::Result<()> {
+    let movie = demuxer.start().await?;
+
+    let reference = match (args.wall_clock, args.reference) {
+        (true, _) => filter::SyncReference::WallClock,
+        (false, Some(id)) => filter::SyncReference::Track(id),
+        (false, None) => {
+            let track = movie.tracks.first().context("No tracks")?;
+            filter::SyncReference::Track(track.id)
+        }
+    };
+
+    let mut analyzer = filter::SyncAnalyzer::new().with_reference(reference);
+    loop {
+        let pkt = match demuxer.read().await {
+            Ok(pkt) => pkt,
+            Err(e) if e.is_end_of_input() => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        analyzer.filter(pkt);
+    }
+
+    for track in movie.tracks {
+        let Some(stats) = analyzer.stats(track.id) else {
+            continue;
+        };
+
+        println!("Track #{} ({}):", track.id, track.info.name);
+        println!("\tpackets: {}", stats.packets);
+        println!(
+            "\ttimestamps: {:.3}-{:.3} s",
+            stats.start.as_secs_f64(),
+            stats.end.as_secs_f64()
+        );
+
+        if reference != filter::SyncReference::Track(track.id) {
+            println!(
+                "\tdrift: {:+.3} s (max {:+.3} s)",
+                stats.drift, stats.max_drift
+            );
+        }
+
+        if !stats.gaps.is_empty() {
+            println!(
+                "\tgaps: {} totalling {:.3} s",
+                stats.gaps.len(),
+                stats.gap_duration().as_secs_f64()
+            );
+        }
+
+        for (at, length) in &stats.gaps {
+            println!(
+                "\t\t{:.3} s at {:.3} s",
+                length.as_secs_f64(),
+                at.as_secs_f64()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn analyze_ebml(_args: Ebml, mut io: Io) -> anyhow::Result<()> {
+    let diagnostics = format::mkv::validate(&mut io).await?;
+
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    println!("{} problems found", diagnostics.len());
+
+    Ok(())
+}
+
+async fn analyze_structure(_args: Structure, mut demuxer: Box<dyn Demuxer>) -> anyhow::Result<()> {
+    for node in demuxer.dump_structure().await? {
+        print_structure(&node, 0);
+    }
+
+    Ok(())
+}
+
+fn print_structure(node: &StructureNode, depth: usize) {
+    let size = match node.size {
+        Some(size) => format!("{size} B"),
+        None => "unknown size".to_string(),
+    };
+    let value = node
+        .value
+        .as_ref()
+        .map(|value| format!(": {value}"))
+        .unwrap_or_default();
+
+    println!("{}{} @ 0x{:x}, {size}{value}", "\t".repeat(depth), node.name, node.offset);
+
+    for child in &node.children {
+        print_structure(child, depth + 1);
+    }
+}
+
 fn print_packet(
     idx: usize,
     pkt: Packet,
@@ -0,0 +1,12 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // emits the flags to link against the system libass
+    #[cfg(feature = "libass")]
+    if let Err(e) = pkg_config::Config::new()
+        .atleast_version("0.13")
+        .probe("libass")
+    {
+        panic!("The libass feature needs libass 0.13 or newer: {e}");
+    }
+}
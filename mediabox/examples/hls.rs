@@ -24,18 +24,11 @@ async fn main() {
     let mut muxer = hls_mux.new_stream(&movie).await.unwrap();
     muxer.start(movie.tracks).await.unwrap();
 
-    // println!("pts,dts,keyframe,stream,length");
-    loop {
-        let pkt = demuxer.read().await.unwrap();
-
-        // println!("{:?}", pkt.time);
-        /*if pkt.track.id == subtitle_id {
-            println!(
-                "{}",
-                String::from_utf8(pkt.buffer.to_slice().into_owned()).unwrap()
-            );
-        }*/
+    while let Ok(pkt) = demuxer.read().await {
+        muxer.write(pkt).await.unwrap();
     }
+
+    muxer.stop().await.unwrap();
 }
 
 #[cfg(not(feature = "hls"))]
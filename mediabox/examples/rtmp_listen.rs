@@ -1,3 +1,4 @@
+use futures::StreamExt;
 use mediabox::format::mp4::*;
 use mediabox::format::rtmp::*;
 use mediabox::format::*;
@@ -7,30 +8,26 @@ use mediabox::io::*;
 async fn main() {
     env_logger::init();
 
-    let mut listener = RtmpListener::bind("127.0.0.1:1935")
+    let server = RtmpServer::bind("127.0.0.1:1935")
         .await
-        .expect("Failed to bind RTMP listener");
+        .expect("Failed to bind RTMP server")
+        .with_authenticator(|request| request.app() == "live");
+    let mut publishers = server.serve();
 
-    loop {
-        let request = listener
-            .accept()
-            .await
-            .expect("Failed to accept RTMP request");
+    while let Some(mut publish) = publishers.next().await {
+        tokio::spawn(async move {
+            eprintln!("{} is publishing {}", publish.addr, publish.key);
 
-        tokio::spawn(async {
-            eprintln!("Got RTMP request from {}", request.addr());
-
-            let mut session = request
-                .authenticate()
+            let streams = publish
+                .session
+                .streams()
                 .await
-                .expect("Failed to authenticate RTMP session");
-
-            let streams = session.streams().await.expect("Failed to get streams");
+                .expect("Failed to get streams");
             for stream in &streams {
                 eprintln!("{}: {:?}", stream.id, stream.info);
             }
 
-            let file = Io::create_file("file.mp4")
+            let file = Io::create_file(format!("{}.mp4", publish.key))
                 .await
                 .expect("Failed to create file");
             let mut writer = FragmentedMp4Muxer::new(file);
@@ -38,8 +35,18 @@ async fn main() {
             writer.start(streams).await.expect("Failed to start muxer");
 
             loop {
-                let pkt = session.read_frame().await.expect("Failed to read packet");
-                eprintln!("{:?}", pkt);
+                let pkt = publish
+                    .session
+                    .read_frame()
+                    .await
+                    .expect("Failed to read packet");
+
+                while let Some(event) = publish.session.next_event() {
+                    writer
+                        .handle_event(&event)
+                        .await
+                        .expect("Failed to handle stream change");
+                }
 
                 writer
                     .write(pkt)
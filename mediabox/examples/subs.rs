@@ -41,20 +41,20 @@ async fn main() {
     let transcode_mapping = movie.subtitles().filter_map(|t| transcode_subtitles(&cxt, t)).collect();
 
     let mut transcoder = PacketTranscoder::new(transcode_mapping);
-
-    loop {
-        let pkt = demuxer.read().await.unwrap();
-
-        transcoder
-            .process(pkt, |pkt| {
-                if pkt.track.info.name == "webvtt" {
-                    eprintln!(
-                        "{}",
-                        str::from_utf8(&pkt.buffer.to_slice()).expect("Failed to read string")
-                    );
-                }
-            })
-            .await
-            .unwrap();
+    transcoder.start(&movie.tracks);
+
+    let print = |pkt: Packet| {
+        if pkt.track.info.name == "webvtt" {
+            eprintln!(
+                "{}",
+                str::from_utf8(&pkt.buffer.to_slice()).expect("Failed to read string")
+            );
+        }
+    };
+
+    while let Ok(pkt) = demuxer.read().await {
+        transcoder.process(pkt, print).await.unwrap();
     }
+
+    transcoder.finish(print).await.unwrap();
 }
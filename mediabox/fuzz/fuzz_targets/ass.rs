@@ -0,0 +1,56 @@
+//! Decodes an arbitrary ASS script header and event, which has to end with an error rather than
+//! a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mediabox::{
+    codec::{ass::AssDecoder, AssCodec, Decoder, SubtitleCodec, SubtitleInfo},
+    Fraction, MediaInfo, MediaKind, MediaTime, Packet, Track,
+};
+
+use std::sync::Arc;
+
+fuzz_target!(|data: &[u8]| {
+    // the header and the event are separated by the first NUL
+    let (header, event) = match data.iter().position(|&b| b == 0) {
+        Some(i) => (&data[..i], &data[i + 1..]),
+        None => (&[][..], data),
+    };
+
+    let track = Track {
+        id: 1,
+        info: Arc::new(MediaInfo {
+            name: "ass",
+            kind: MediaKind::Subtitle(SubtitleInfo {
+                codec: SubtitleCodec::Ass(AssCodec {
+                    header: String::from_utf8_lossy(header).into_owned(),
+                }),
+            }),
+        }),
+        timebase: Fraction::new(1, 1000),
+        delay: 0,
+        metadata: Default::default(),
+    };
+
+    let mut decoder = AssDecoder::new();
+    if decoder.start(&track.info).is_err() {
+        return;
+    }
+
+    let packet = Packet {
+        time: MediaTime {
+            pts: 0,
+            dts: None,
+            duration: None,
+            timebase: track.timebase,
+        },
+        key: true,
+        track,
+        buffer: event.to_vec().into(),
+        side_data: Vec::new(),
+    };
+    if decoder.feed(packet).is_ok() {
+        while decoder.receive().is_some() {}
+    }
+});
@@ -0,0 +1,23 @@
+//! Demuxes arbitrary bytes as Matroska, which has to end with an error rather than a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mediabox::{
+    format::{mkv::MatroskaDemuxer, Demuxer},
+    io::Io,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(data.to_vec()));
+
+        if demuxer.start().await.is_ok() {
+            while demuxer.read().await.is_ok() {}
+        }
+    });
+});
@@ -0,0 +1,68 @@
+//! Stops long running jobs, e.g. a [`Recorder`](crate::recorder::Recorder), cleanly from
+//! another task.
+
+use futures::future::{self, Either};
+use tokio::sync::watch;
+
+use std::{future::Future, sync::Arc};
+
+/// A handle to ask a job to stop, shared by cloning it.
+///
+/// Jobs check it between packets and stop reading their input once it is cancelled, but still
+/// flush their encoders and finalize their outputs, so that nothing is cut off in the middle of
+/// a write. Streams of packets can be stopped with
+/// `demuxer.packets().take_until(token.cancelled())`.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+
+        CancellationToken {
+            sender: Arc::new(sender),
+        }
+    }
+
+    /// Asks every job holding a clone of this token to stop.
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+
+        // the sender lives as long as the token, so waiting can't fail
+        while !*receiver.borrow_and_update() {
+            if receiver.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Runs `fut` until it completes, or returns `None` if the token is cancelled first, e.g. to
+    /// stop waiting for the next packet of a live input.
+    pub async fn run_until_cancelled<F: Future>(&self, fut: F) -> Option<F::Output> {
+        if self.is_cancelled() {
+            return None;
+        }
+
+        match future::select(Box::pin(fut), Box::pin(self.cancelled())).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
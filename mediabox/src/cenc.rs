@@ -0,0 +1,101 @@
+//! Common Encryption (ISO/IEC 23001-7) of samples, which DRM systems like Widevine, PlayReady and
+//! FairPlay build on to protect DASH and HLS streams.
+//!
+//! Samples are encrypted by a [CencEncryptor] with the `cenc` feature, which attaches
+//! [`SideData::Encryption`](crate::SideData::Encryption) to every packet. The fragmented MP4 muxer
+//! writes it to `senc`, `saiz` and `saio` boxes when a track is given a [TrackEncryption].
+
+use crate::Track;
+
+#[cfg(feature = "cenc")]
+mod encryptor;
+
+#[cfg(feature = "cenc")]
+pub use encryptor::*;
+
+/// How samples are encrypted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scheme {
+    /// AES-CTR with an IV stored for every sample, the scheme used by most DASH players.
+    Cenc,
+    /// AES-CBC of every tenth block of video with a constant IV, required by FairPlay.
+    Cbcs,
+}
+
+impl Scheme {
+    pub fn fourcc(&self) -> &'static [u8; 4] {
+        match self {
+            Scheme::Cenc => b"cenc",
+            Scheme::Cbcs => b"cbcs",
+        }
+    }
+}
+
+/// An AES-128 key and the id players request it by from the license server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ContentKey {
+    pub key_id: [u8; 16],
+    pub key: [u8; 16],
+    /// The IV of the first sample. `cbcs` uses it for every sample, `cenc` increments the first 8
+    /// bytes for each sample.
+    pub iv: [u8; 16],
+}
+
+/// Decides which key each track is encrypted with.
+pub trait KeyProvider: Send + Sync {
+    /// The key to encrypt a track with, or [None] to leave it in the clear.
+    fn key(&self, track: &Track) -> Option<ContentKey>;
+}
+
+/// Encrypts every track with the same key.
+impl KeyProvider for ContentKey {
+    fn key(&self, _track: &Track) -> Option<ContentKey> {
+        Some(*self)
+    }
+}
+
+impl<F> KeyProvider for F
+where
+    F: Fn(&Track) -> Option<ContentKey> + Send + Sync,
+{
+    fn key(&self, track: &Track) -> Option<ContentKey> {
+        self(track)
+    }
+}
+
+/// The default encryption parameters of a track, as stored in its `tenc` box.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackEncryption {
+    pub scheme: Scheme,
+    pub key_id: [u8; 16],
+    /// The size of the IV stored with every sample, which is 0 with a constant IV.
+    pub per_sample_iv_size: u8,
+    pub constant_iv: Option<[u8; 16]>,
+    /// The number of encrypted and skipped 16 byte blocks of the pattern, where `(0, 0)` encrypts
+    /// every block.
+    pub pattern: (u8, u8),
+}
+
+/// A `pssh` box, which carries the data a DRM system needs to acquire the keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pssh {
+    pub system_id: [u8; 16],
+    pub key_ids: Vec<[u8; 16]>,
+    pub data: Vec<u8>,
+}
+
+impl Pssh {
+    pub const WIDEVINE: [u8; 16] = [
+        0xed, 0xef, 0x8b, 0xa9, 0x79, 0xd6, 0x4a, 0xce, 0xa3, 0xc8, 0x27, 0xdc, 0xd5, 0x1d, 0x21,
+        0xed,
+    ];
+    pub const PLAYREADY: [u8; 16] = [
+        0x9a, 0x04, 0xf0, 0x79, 0x98, 0x40, 0x42, 0x86, 0xab, 0x92, 0xe6, 0x5b, 0xe0, 0x88, 0x5f,
+        0x95,
+    ];
+    /// The W3C Common PSSH box, which lists the key ids for Clear Key.
+    pub const COMMON: [u8; 16] = [
+        0x10, 0x77, 0xef, 0xec, 0xc0, 0xb2, 0x4d, 0x02, 0xac, 0xe3, 0x3c, 0x1e, 0x52, 0xe2, 0xfb,
+        0x4b,
+    ];
+}
@@ -0,0 +1,437 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use log::*;
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    codec::nal::BitstreamFraming, filter::BitstreamConverterFilter, AudioCodec, MediaKind, Packet,
+    SideData, Track, VideoCodec,
+};
+
+use super::{KeyProvider, Scheme, TrackEncryption};
+
+const BLOCK_SIZE: usize = 16;
+
+/// How many bytes at the start of H.264 slices are left in the clear, which is enough for the
+/// slice header of all but the most unusual streams.
+const CLEAR_SLICE_HEADER: usize = 32;
+
+/// The `cbcs` pattern of video, encrypting one block out of every ten.
+const VIDEO_PATTERN: (u8, u8) = (1, 9);
+
+struct TrackState {
+    encryption: TrackEncryption,
+    cipher: Aes128,
+    /// The IV of the next sample.
+    iv: [u8; 16],
+    /// Whether the NAL unit headers are left in the clear, otherwise the whole sample is
+    /// encrypted.
+    subsamples: bool,
+}
+
+/// Encrypts the samples of the tracks a [KeyProvider] has a key for, and attaches
+/// [SideData::Encryption] describing how.
+///
+/// H.264 is converted to length prefixed NAL units first, as converting it after encryption
+/// would break it. Only H.264, AAC and Opus tracks can be encrypted.
+///
+/// ```ignore
+/// let mut encryptor = CencEncryptor::new(Scheme::Cenc, key);
+/// let tracks = encryptor.start(movie.tracks);
+///
+/// let mut muxer = FragmentedMp4Muxer::new(io);
+/// for track in &tracks {
+///     if let Some(encryption) = encryptor.track_encryption(track.id) {
+///         muxer = muxer.with_encryption(track.id, encryption.clone());
+///     }
+/// }
+///
+/// muxer.start(tracks).await?;
+/// while let Ok(pkt) = demuxer.read().await {
+///     muxer.write(encryptor.filter(pkt)).await?;
+/// }
+/// ```
+pub struct CencEncryptor {
+    scheme: Scheme,
+    provider: Box<dyn KeyProvider>,
+    converter: BitstreamConverterFilter,
+    tracks: HashMap<u32, TrackState>,
+}
+
+impl CencEncryptor {
+    pub fn new(scheme: Scheme, provider: impl KeyProvider + 'static) -> Self {
+        CencEncryptor {
+            scheme,
+            provider: Box::new(provider),
+            converter: BitstreamConverterFilter::new(BitstreamFraming::FourByteLength),
+            tracks: HashMap::new(),
+        }
+    }
+
+    /// Returns the tracks with H.264 converted to length prefixed NAL units.
+    pub fn start(&mut self, tracks: Vec<Track>) -> Vec<Track> {
+        let tracks = self.converter.start(tracks);
+
+        self.tracks.clear();
+        for track in &tracks {
+            let Some(key) = self.provider.key(track) else {
+                continue;
+            };
+
+            let subsamples = match &track.info.kind {
+                MediaKind::Video(info) if matches!(info.codec, VideoCodec::H264(_)) => true,
+                MediaKind::Audio(info)
+                    if matches!(info.codec, AudioCodec::Aac(_) | AudioCodec::Opus(_)) =>
+                {
+                    false
+                }
+                _ => {
+                    warn!(
+                        "Can't encrypt {} track {}, leaving it in the clear",
+                        track.info.name, track.id
+                    );
+                    continue;
+                }
+            };
+
+            let encryption = match self.scheme {
+                Scheme::Cenc => TrackEncryption {
+                    scheme: self.scheme,
+                    key_id: key.key_id,
+                    per_sample_iv_size: 8,
+                    constant_iv: None,
+                    pattern: (0, 0),
+                },
+                Scheme::Cbcs => TrackEncryption {
+                    scheme: self.scheme,
+                    key_id: key.key_id,
+                    per_sample_iv_size: 0,
+                    constant_iv: Some(key.iv),
+                    pattern: if subsamples { VIDEO_PATTERN } else { (0, 0) },
+                },
+            };
+
+            self.tracks.insert(
+                track.id,
+                TrackState {
+                    encryption,
+                    cipher: Aes128::new(&key.key.into()),
+                    iv: key.iv,
+                    subsamples,
+                },
+            );
+        }
+
+        tracks
+    }
+
+    /// How a track is encrypted, which is given to the muxer, or [None] if it's left in the clear.
+    pub fn track_encryption(&self, id: u32) -> Option<&TrackEncryption> {
+        self.tracks.get(&id).map(|state| &state.encryption)
+    }
+
+    pub fn filter(&mut self, packet: Packet) -> Packet {
+        let mut packet = self.converter.filter(packet);
+        let Some(state) = self.tracks.get_mut(&packet.track.id) else {
+            return packet;
+        };
+
+        let mut data = packet.buffer.to_slice().into_owned();
+        let subsamples = if state.subsamples {
+            nal_subsamples(&data)
+        } else {
+            Vec::new()
+        };
+        let ranges = protected_ranges(&subsamples, data.len());
+
+        let iv = match state.encryption.scheme {
+            Scheme::Cenc => {
+                // the IV is followed by the block counter
+                let mut counter = [0; 16];
+                counter[..8].copy_from_slice(&state.iv[..8]);
+                encrypt_ctr(&state.cipher, counter, &mut data, &ranges);
+
+                let next = u64::from_be_bytes(state.iv[..8].try_into().unwrap()).wrapping_add(1);
+                state.iv[..8].copy_from_slice(&next.to_be_bytes());
+
+                counter[..8].to_vec()
+            }
+            Scheme::Cbcs => {
+                encrypt_cbc(
+                    &state.cipher,
+                    &state.iv,
+                    &mut data,
+                    &ranges,
+                    state.encryption.pattern,
+                );
+
+                Vec::new()
+            }
+        };
+
+        packet.buffer = data.into();
+        packet.side_data.push(SideData::Encryption {
+            key_id: state.encryption.key_id,
+            iv,
+            subsamples,
+        });
+
+        packet
+    }
+}
+
+/// Leaves everything but the slice data of length prefixed H.264 NAL units in the clear. The
+/// encrypted part of each slice is a multiple of the block size, as `cenc` requires for video.
+fn nal_subsamples(data: &[u8]) -> Vec<(u16, u32)> {
+    let mut subsamples = Vec::new();
+    let mut clear = 0;
+    let mut offset = 0;
+
+    while offset + 4 <= data.len() {
+        let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let nal = &data[offset + 4..(offset + 4 + size).min(data.len())];
+
+        let is_slice = nal
+            .first()
+            .is_some_and(|header| (1..=5).contains(&(header & 0x1f)));
+        let protected = if is_slice {
+            nal.len().saturating_sub(CLEAR_SLICE_HEADER) / BLOCK_SIZE * BLOCK_SIZE
+        } else {
+            0
+        };
+
+        clear += 4 + nal.len() - protected;
+        if protected > 0 {
+            push_subsample(&mut subsamples, clear, protected);
+            clear = 0;
+        }
+
+        offset += 4 + nal.len();
+    }
+
+    clear += data.len() - offset;
+    if clear > 0 {
+        push_subsample(&mut subsamples, clear, 0);
+    }
+
+    subsamples
+}
+
+fn push_subsample(subsamples: &mut Vec<(u16, u32)>, mut clear: usize, protected: usize) {
+    while clear > u16::MAX as usize {
+        subsamples.push((u16::MAX, 0));
+        clear -= u16::MAX as usize;
+    }
+
+    subsamples.push((clear as u16, protected as u32));
+}
+
+/// The encrypted ranges of a sample, which is all of it without subsamples.
+fn protected_ranges(subsamples: &[(u16, u32)], len: usize) -> Vec<Range<usize>> {
+    if subsamples.is_empty() {
+        return std::iter::once(0..len).collect();
+    }
+
+    let mut offset = 0;
+    subsamples
+        .iter()
+        .map(|&(clear, protected)| {
+            let start = offset + clear as usize;
+            offset = start + protected as usize;
+
+            start..offset
+        })
+        .filter(|range| !range.is_empty())
+        .collect()
+}
+
+/// Encrypts the ranges with AES-CTR as one continuous stream, where the last 8 bytes of the
+/// counter block are incremented for every block.
+fn encrypt_ctr(cipher: &Aes128, mut counter: [u8; 16], data: &mut [u8], ranges: &[Range<usize>]) {
+    let mut keystream = [0; BLOCK_SIZE];
+    let mut used = BLOCK_SIZE;
+
+    for range in ranges {
+        for byte in &mut data[range.clone()] {
+            if used == BLOCK_SIZE {
+                keystream = counter;
+                cipher.encrypt_block(GenericArray::from_mut_slice(&mut keystream));
+
+                let next = u64::from_be_bytes(counter[8..].try_into().unwrap()).wrapping_add(1);
+                counter[8..].copy_from_slice(&next.to_be_bytes());
+                used = 0;
+            }
+
+            *byte ^= keystream[used];
+            used += 1;
+        }
+    }
+}
+
+/// Encrypts each range with AES-CBC starting from the IV, encrypting and skipping blocks by the
+/// pattern. A partial block at the end of a range is left in the clear.
+fn encrypt_cbc(
+    cipher: &Aes128,
+    iv: &[u8; 16],
+    data: &mut [u8],
+    ranges: &[Range<usize>],
+    (crypt, skip): (u8, u8),
+) {
+    let period = (crypt + skip) as usize;
+
+    for range in ranges {
+        let mut chain = *iv;
+
+        for (i, block) in data[range.clone()].chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            if crypt > 0 && i % period >= crypt as usize {
+                continue;
+            }
+
+            for (byte, prev) in block.iter_mut().zip(&chain) {
+                *byte ^= prev;
+            }
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+            chain.copy_from_slice(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::{cenc::ContentKey, Fraction, H264Codec, MediaInfo, MediaTime, VideoInfo};
+
+    const NIST_KEY: [u8; 16] = [
+        0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f,
+        0x3c,
+    ];
+    const NIST_PLAINTEXT: [u8; 32] = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf,
+        0x8e, 0x51,
+    ];
+
+    /// The examples of NIST SP 800-38A.
+    #[test]
+    fn nist_test_vectors() {
+        let cipher = Aes128::new(&NIST_KEY.into());
+
+        let mut data = NIST_PLAINTEXT;
+        let counter = [
+            0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd,
+            0xfe, 0xff,
+        ];
+        encrypt_ctr(&cipher, counter, &mut data, &[0..16, 16..32]);
+        assert_eq!(
+            [
+                0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d,
+                0xb6, 0xce, 0x98, 0x06, 0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff, 0x86, 0x17, 0x18, 0x7b,
+                0xb9, 0xff, 0xfd, 0xff,
+            ],
+            data
+        );
+
+        let mut data = NIST_PLAINTEXT;
+        let iv = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        encrypt_cbc(&cipher, &iv, &mut data, &[(0..32)], (0, 0));
+        assert_eq!(
+            [
+                0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9,
+                0x19, 0x7d, 0x50, 0x86, 0xcb, 0x9b, 0x50, 0x72, 0x19, 0xee, 0x95, 0xdb, 0x11, 0x3a,
+                0x91, 0x76, 0x78, 0xb2,
+            ],
+            data
+        );
+    }
+
+    #[test]
+    fn h264_slice_data_is_encrypted() {
+        let track = Track {
+            id: 1,
+            info: Arc::new(MediaInfo {
+                name: "h264",
+                kind: MediaKind::Video(VideoInfo {
+                    width: 320,
+                    height: 240,
+                    codec: VideoCodec::H264(H264Codec {
+                        bitstream_format: BitstreamFraming::FourByteLength,
+                        profile_indication: 100,
+                        profile_compatibility: 0,
+                        level_indication: 31,
+                        sps: vec![0x67].into(),
+                        pps: vec![0x68].into(),
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 90_000),
+            delay: 0,
+            metadata: Default::default(),
+        };
+        let key = ContentKey {
+            key_id: [1; 16],
+            key: NIST_KEY,
+            iv: [2; 16],
+        };
+
+        let mut encryptor = CencEncryptor::new(Scheme::Cenc, key);
+        let tracks = encryptor.start(vec![track]);
+        assert_eq!(8, encryptor.track_encryption(1).unwrap().per_sample_iv_size);
+
+        // an SEI followed by an IDR slice of 100 bytes
+        let mut sample = vec![0, 0, 0, 4, 0x06, 1, 2, 3, 0, 0, 0, 100, 0x65];
+        sample.extend((0..99).map(|i| i as u8));
+        let packet = |sample: &[u8]| Packet {
+            time: MediaTime {
+                pts: 0,
+                dts: None,
+                duration: None,
+                timebase: tracks[0].timebase,
+            },
+            key: true,
+            track: tracks[0].clone(),
+            buffer: sample.to_vec().into(),
+            side_data: Vec::new(),
+        };
+
+        let encrypted = encryptor.filter(packet(&sample));
+        let encrypted_data = encrypted.buffer.to_slice();
+        assert_eq!(
+            vec![SideData::Encryption {
+                key_id: [1; 16],
+                iv: vec![2; 8],
+                subsamples: vec![(48, 64)],
+            }],
+            encrypted.side_data
+        );
+        assert_eq!(&sample[..48], &encrypted_data[..48]);
+        assert_ne!(&sample[48..], &encrypted_data[48..]);
+
+        // CTR decrypts by encrypting again with the same counter
+        let mut decrypted = encrypted_data.into_owned();
+        let mut counter = [0; 16];
+        counter[..8].copy_from_slice(&[2; 8]);
+        encrypt_ctr(
+            &Aes128::new(&NIST_KEY.into()),
+            counter,
+            &mut decrypted,
+            &[(48..112)],
+        );
+        assert_eq!(sample, decrypted);
+
+        let next = encryptor.filter(packet(&sample));
+        assert_matches!(
+            &next.side_data[0],
+            SideData::Encryption { iv, .. } if iv == &[2, 2, 2, 2, 2, 2, 2, 3]
+        );
+    }
+}
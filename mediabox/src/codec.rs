@@ -1,17 +1,36 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::Duration,
+};
 
-use crate::{MediaInfo, MediaTime, Packet, Track};
+use crate::{Fraction, MediaInfo, MediaTime, MediaboxError, Packet, PixelFormat, Span, Track};
 
+pub mod aac;
 pub mod ass;
+#[cfg(feature = "ffmpeg")]
+pub mod ffmpeg;
+pub mod flac;
 pub mod h264;
+#[cfg(feature = "libass")]
+pub mod libass;
 pub mod nal;
+pub mod opus;
+pub mod pcm;
+#[cfg(feature = "rav1e")]
+pub mod rav1e;
+pub mod rawvideo;
+#[cfg(feature = "symphonia")]
+pub mod symphonia;
+pub mod vorbis;
+pub mod vpx;
 pub mod webvtt;
 
 /// Registers a decoder with mediabox
 #[macro_export]
 macro_rules! decoder {
     ($name:literal, $create:expr) => {
-        const META: crate::codec::DecoderMetadata = crate::codec::DecoderMetadata {
+        pub const DECODER_META: $crate::codec::DecoderMetadata = $crate::codec::DecoderMetadata {
             name: $name,
             create: $create,
         };
@@ -22,7 +41,7 @@ macro_rules! decoder {
 #[macro_export]
 macro_rules! encoder {
     ($name:literal, $create:expr) => {
-        const META: EncoderMetadata = EncoderMetadata {
+        pub const ENCODER_META: $crate::codec::EncoderMetadata = $crate::codec::EncoderMetadata {
             name: $name,
             create: $create,
         };
@@ -30,15 +49,102 @@ macro_rules! encoder {
 }
 
 pub trait Decoder: Send + Sync {
-    fn start(&mut self, info: &MediaInfo) -> anyhow::Result<()>;
-    fn feed(&mut self, packet: Packet) -> anyhow::Result<()>;
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()>;
+    fn feed(&mut self, packet: Packet) -> crate::Result<()>;
     fn receive(&mut self) -> Option<Decoded>;
+
+    /// Whether [`Decoder::feed`] would fail with [`MediaboxError::Full`] until some output is
+    /// received. Checking this first keeps the packet, which a failed feed drops.
+    fn is_full(&self) -> bool {
+        false
+    }
 }
 
 pub trait Encoder: Send + Sync {
-    fn start(&mut self, desc: CodecDescription) -> anyhow::Result<Track>;
-    fn feed(&mut self, raw: Decoded) -> anyhow::Result<()>;
+    /// Starts the encoder and returns the track of the encoded packets. The track's
+    /// [`Track::delay`] is the encoder's [`Encoder::delay`].
+    fn start(&mut self, desc: CodecDescription) -> crate::Result<Track>;
+    fn feed(&mut self, raw: Decoded) -> crate::Result<()>;
     fn receive(&mut self) -> Option<Packet>;
+
+    /// Whether [`Encoder::feed`] would fail with [`MediaboxError::Full`] until some output is
+    /// received. Checking this first keeps the frame, which a failed feed drops.
+    fn is_full(&self) -> bool {
+        false
+    }
+
+    /// Signals the end of the input, making encoders which buffer frames emit their remaining
+    /// packets.
+    fn flush(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    /// The number of priming samples, in the timebase of the encoded track, that the encoder
+    /// inserts before the first real sample. The priming samples are kept in the encoded packets
+    /// and trimmed by the muxer, e.g. with an edit list or `CodecDelay`.
+    fn delay(&self) -> u64 {
+        0
+    }
+}
+
+/// How many frames or packets a codec queues before it returns [`MediaboxError::Full`] when fed.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// The output of a decoder or encoder waiting to be received. It refuses more input once it is
+/// full, so a caller which doesn't keep up can't make it grow without bound.
+pub struct OutputQueue<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> OutputQueue<T> {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_QUEUE_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        OutputQueue {
+            items: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Fails with [`MediaboxError::Full`] if the queue is full, which codecs check before they
+    /// consume their input. Output of the input that passed the check is always queued, even if
+    /// it exceeds the capacity.
+    pub fn reserve(&self) -> crate::Result<()> {
+        if self.is_full() {
+            return Err(MediaboxError::Full);
+        }
+
+        Ok(())
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.items.push_back(item);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.items.len() >= self.capacity
+    }
+}
+
+impl<T> Default for OutputQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Clone)]
@@ -67,27 +173,189 @@ impl EncoderMetadata {
 
 pub enum CodecDescription {
     Subtitle(SubtitleDescription),
+    Video(VideoDescription),
 }
 
 impl CodecDescription {
     pub fn into_subtitle(self) -> Option<SubtitleDescription> {
         match self {
             CodecDescription::Subtitle(desc) => Some(desc),
+            _ => None,
         }
     }
+
+    pub fn into_video(self) -> Option<VideoDescription> {
+        match self {
+            CodecDescription::Video(desc) => Some(desc),
+            _ => None,
+        }
+    }
+}
+
+/// Describes the frames a video encoder will be fed with.
+#[derive(Debug, Clone)]
+pub struct VideoDescription {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// The timebase of the frame timestamps, which is also used for the encoded track.
+    pub timebase: Fraction,
+    /// Target bitrate in bits per second, or the encoder default if unset.
+    pub bitrate: Option<u64>,
 }
 
 /// Result from decoding a [`Packet`].
 pub enum Decoded {
     Subtitle(TextCue),
+    Audio(AudioFrame),
+    Video(VideoFrame),
+    SubtitleBitmap(SubtitleBitmap),
 }
 
 impl Decoded {
     pub fn into_subtitle(self) -> Option<TextCue> {
         match self {
             Decoded::Subtitle(cue) => Some(cue),
+            _ => None,
+        }
+    }
+
+    pub fn into_audio(self) -> Option<AudioFrame> {
+        match self {
+            Decoded::Audio(frame) => Some(frame),
+            _ => None,
+        }
+    }
+
+    pub fn into_video(self) -> Option<VideoFrame> {
+        match self {
+            Decoded::Video(frame) => Some(frame),
+            _ => None,
         }
     }
+
+    pub fn into_subtitle_bitmap(self) -> Option<SubtitleBitmap> {
+        match self {
+            Decoded::SubtitleBitmap(bitmap) => Some(bitmap),
+            _ => None,
+        }
+    }
+}
+
+/// Decoded PCM audio.
+#[derive(Debug, Clone)]
+pub struct AudioFrame {
+    pub time: MediaTime,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Interleaved samples in the range `-1.0..=1.0`.
+    pub samples: Vec<f32>,
+}
+
+impl AudioFrame {
+    /// The number of samples per channel.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+}
+
+/// A single plane of a [VideoFrame].
+#[derive(Debug, Clone)]
+pub struct VideoPlane {
+    pub data: Span,
+    /// The number of bytes between the start of two consecutive rows.
+    pub stride: usize,
+}
+
+/// Decoded, uncompressed video.
+#[derive(Debug, Clone)]
+pub struct VideoFrame {
+    pub time: MediaTime,
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    /// The planes of the frame, in the order defined by [PixelFormat::plane_sizes].
+    pub planes: Vec<VideoPlane>,
+}
+
+impl VideoFrame {
+    /// Converts the frame to tightly packed 8 bit RGB, e.g. to encode it as an image. YUV is
+    /// assumed to be limited range BT.601.
+    pub fn to_rgb24(&self) -> Vec<u8> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let planes = self
+            .planes
+            .iter()
+            .map(|plane| (plane.data.to_slice(), plane.stride))
+            .collect::<Vec<_>>();
+        let bpp = match self.format {
+            PixelFormat::Rgba => 4,
+            _ => 3,
+        };
+        let mut rgb = Vec::with_capacity(width * height * 3);
+
+        for y in 0..height {
+            for x in 0..width {
+                let (cx, cy) = (x / 2, y / 2);
+                let pixel = match self.format {
+                    PixelFormat::Yuv420p => yuv_to_rgb(
+                        planes[0].0[y * planes[0].1 + x],
+                        planes[1].0[cy * planes[1].1 + cx],
+                        planes[2].0[cy * planes[2].1 + cx],
+                    ),
+                    PixelFormat::Nv12 => yuv_to_rgb(
+                        planes[0].0[y * planes[0].1 + x],
+                        planes[1].0[cy * planes[1].1 + cx * 2],
+                        planes[1].0[cy * planes[1].1 + cx * 2 + 1],
+                    ),
+                    PixelFormat::Rgb24 | PixelFormat::Rgba => {
+                        let offset = y * planes[0].1 + x * bpp;
+                        let data = &planes[0].0[offset..offset + 3];
+
+                        [data[0], data[1], data[2]]
+                    }
+                };
+
+                rgb.extend_from_slice(&pixel);
+            }
+        }
+
+        rgb
+    }
+}
+
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let (d, e) = (u as i32 - 128, v as i32 - 128);
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+    ]
+}
+
+/// Rendered subtitles, to be blended onto video frames of the given size.
+#[derive(Debug, Clone)]
+pub struct SubtitleBitmap {
+    /// The time the images are shown, until the next bitmap if there is no duration.
+    pub time: MediaTime,
+    pub width: u32,
+    pub height: u32,
+    /// The images to blend in order, which is empty if no subtitles are shown.
+    pub images: Vec<OverlayImage>,
+}
+
+/// A part of a [SubtitleBitmap], placed at `x` and `y` in the video frame.
+#[derive(Debug, Clone)]
+pub struct OverlayImage {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// RGBA pixels without premultiplied alpha, row by row.
+    pub data: Vec<u8>,
 }
 
 #[derive(Clone, Debug)]
@@ -100,10 +368,33 @@ pub struct WebVttCodec {
     pub header: String,
 }
 
+/// The `.idx` file of VobSub subtitles, which holds the frame size and palette of the bitmaps.
+#[derive(Clone, Debug)]
+pub struct VobSubCodec {
+    pub idx: String,
+}
+
+impl VobSubCodec {
+    /// The 16 colors of the `palette` line as `0xRRGGBB`.
+    pub fn palette(&self) -> Option<Vec<u32>> {
+        let line = self
+            .idx
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("palette:"))?;
+
+        line.split(',')
+            .map(|color| u32::from_str_radix(color.trim(), 16).ok())
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum SubtitleCodec {
     Ass(AssCodec),
     WebVtt(WebVttCodec),
+    /// Blu-ray Presentation Graphic Stream subtitles, which describe their palettes in-band.
+    Pgs,
+    VobSub(VobSubCodec),
 }
 
 /// Information about a piece of subtitle media
@@ -121,39 +412,96 @@ impl fmt::Debug for SubtitleInfo {
             SubtitleCodec::WebVtt(_) => {
                 write!(f, "WebVTT")?;
             }
+            SubtitleCodec::Pgs => {
+                write!(f, "PGS")?;
+            }
+            SubtitleCodec::VobSub(_) => {
+                write!(f, "VobSub")?;
+            }
         }
 
         Ok(())
     }
 }
 
+/// Describes the subtitles a subtitle encoder will be fed with.
 #[derive(Default)]
 pub struct SubtitleDescription {
-    styles: HashMap<String, TextStyle>,
+    /// The styles [TextCue]s refer to, keyed by name.
+    pub styles: HashMap<String, TextStyle>,
 }
 
-#[derive(Default, Debug)]
+impl SubtitleDescription {
+    /// Creates a description with the styles declared in the codec header of a subtitle track.
+    pub fn from_info(info: &MediaInfo) -> Self {
+        let styles = match info.subtitle() {
+            Some(SubtitleInfo {
+                codec: SubtitleCodec::Ass(AssCodec { header }),
+            }) => ass::parse_styles(header),
+            _ => HashMap::new(),
+        };
+
+        SubtitleDescription { styles }
+    }
+
+    pub fn style(&self, name: &str) -> Option<&TextStyle> {
+        self.styles.get(name)
+    }
+}
+
+/// A named style which [TextCue]s refer to. Colors are `0xBBGGRR` like [TextFill].
+#[derive(Debug)]
 pub struct TextStyle {
-    font: Option<String>,
-    primary_color: Option<u32>,
-    secondary_color: Option<u32>,
-    outline_color: Option<u32>,
-    back_color: Option<u32>,
-    bold: bool,
-    italic: bool,
-    underline: bool,
-    strikeout: bool,
-    scale_x: f32,
-    scale_y: f32,
-    spacing: i32,
-    angle: i32,
-    border_style: Option<i32>,
-    outline: Option<i32>,
-    shadow: Option<i32>,
-    alignment: Option<i32>,
-    margin_left: Option<i32>,
-    margin_right: Option<i32>,
-    margin_vertical: Option<i32>,
+    pub font: Option<String>,
+    pub font_size: Option<f32>,
+    pub primary_color: Option<u32>,
+    pub secondary_color: Option<u32>,
+    pub outline_color: Option<u32>,
+    pub back_color: Option<u32>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikeout: bool,
+    /// Horizontal and vertical font scaling in percent.
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub spacing: f32,
+    pub angle: f32,
+    pub border_style: Option<i32>,
+    pub outline: Option<f32>,
+    pub shadow: Option<f32>,
+    pub alignment: Option<TextAlign>,
+    pub margin_left: Option<i32>,
+    pub margin_right: Option<i32>,
+    pub margin_vertical: Option<i32>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            font: None,
+            font_size: None,
+            primary_color: None,
+            secondary_color: None,
+            outline_color: None,
+            back_color: None,
+            bold: false,
+            italic: false,
+            underline: false,
+            strikeout: false,
+            scale_x: 100.0,
+            scale_y: 100.0,
+            spacing: 0.0,
+            angle: 0.0,
+            border_style: None,
+            outline: None,
+            shadow: None,
+            alignment: None,
+            margin_left: None,
+            margin_right: None,
+            margin_vertical: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -193,7 +541,44 @@ pub struct TextFill(ColorType, u32);
 #[derive(Eq, PartialEq, Debug)]
 pub struct TextAlpha(ColorType, u8);
 
-#[derive(Debug)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum KaraokeEffect {
+    /// The syllable is highlighted all at once.
+    Instant,
+    /// The highlight sweeps over the syllable from left to right.
+    Sweep,
+    /// The outline of the syllable is highlighted all at once.
+    Outline,
+}
+
+/// A karaoke syllable, highlighted for the given time after the previous syllable.
+#[derive(Eq, PartialEq, Debug)]
+pub struct Karaoke(KaraokeEffect, Duration);
+
+/// Fades the cue in and out over the given times.
+#[derive(Eq, PartialEq, Debug)]
+pub struct TextFade(Duration, Duration);
+
+/// Animates the cue from one position to another, over the whole cue if no times are given.
+#[derive(Debug, PartialEq)]
+pub struct TextMove {
+    from: TextPosition,
+    to: TextPosition,
+    /// The start and end of the movement, relative to the start of the cue.
+    times: Option<(Duration, Duration)>,
+}
+
+/// Gradually changes the style to that of `parts`, over the whole cue if no times are given.
+#[derive(Debug, PartialEq)]
+pub struct TextTransition {
+    /// The start and end of the transition, relative to the start of the cue.
+    times: Option<(Duration, Duration)>,
+    /// The exponent of the transition curve, 1 for a linear transition.
+    accel: f32,
+    parts: Vec<TextPart>,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum TextPart {
     Text(String),
     Italic(bool),
@@ -201,9 +586,20 @@ pub enum TextPart {
     Strikeout(bool),
     Border(f32),
     FontSize(u32),
+    /// Where the cue is anchored, relative to its [Position](TextPart::Position) or the video.
+    Align(TextAlign),
+    /// The anchor point in percent of the width and height of the video.
     Position(TextPosition),
     Fill(TextFill),
     Alpha(TextAlpha),
+    Karaoke(Karaoke),
+    Fade(TextFade),
+    /// Positions are in percent like [Position](TextPart::Position).
+    Move(TextMove),
+    Transition(TextTransition),
+    /// Turns drawing mode on at the given scale, or off for 0. Text in drawing mode holds vector
+    /// drawing commands rather than text to show.
+    Drawing(u32),
     LineBreak,
     SmartBreak,
 }
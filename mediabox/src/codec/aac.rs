@@ -0,0 +1,246 @@
+use bytes::{BufMut, BytesMut};
+
+/// Sampling frequencies indexed by `sampling_frequency_index`.
+const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The size of an ADTS header without a CRC.
+pub const ADTS_HEADER_LEN: usize = 7;
+
+/// The number of samples in a single raw AAC data block.
+pub const AAC_FRAME_SAMPLES: u64 = 1024;
+
+/// The largest frame, including the header, whose length fits in the 13 bit `frame_length`.
+pub const ADTS_MAX_FRAME_LEN: usize = 0x1fff;
+
+const AOT_AAC_LC: u8 = 2;
+const AOT_SBR: u8 = 5;
+const AOT_PS: u8 = 29;
+
+/// The subset of an `AudioSpecificConfig` needed to describe a plain AAC stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AudioSpecificConfig {
+    pub object_type: u8,
+    pub frequency_index: u8,
+    pub channel_config: u8,
+}
+
+impl AudioSpecificConfig {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        let object_type = data[0] >> 3;
+        let frequency_index = ((data[0] & 0b111) << 1) | (data[1] >> 7);
+        let channel_config = (data[1] >> 3) & 0b1111;
+
+        if object_type == 31 || frequency_index == 15 {
+            // escaped object types and explicit frequencies are not supported
+            return None;
+        }
+
+        Some(AudioSpecificConfig {
+            object_type,
+            frequency_index,
+            channel_config,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [
+            (self.object_type << 3) | (self.frequency_index >> 1),
+            ((self.frequency_index & 1) << 7) | (self.channel_config << 3),
+        ]
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        SAMPLE_RATES.get(self.frequency_index as usize).copied()
+    }
+
+    /// Returns the config to signal in ADTS headers, whose 2 bit profile can only describe the
+    /// object types 1 to 4. HE-AAC is signalled implicitly as AAC LC at the core sample rate,
+    /// which is the sample rate already stored before the SBR extension.
+    pub fn to_adts(&self) -> Option<Self> {
+        match self.object_type {
+            1..=4 => Some(*self),
+            AOT_SBR | AOT_PS => Some(AudioSpecificConfig {
+                object_type: AOT_AAC_LC,
+                ..*self
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the `sampling_frequency_index` of a sample rate, if it has one.
+    pub fn frequency_index(sample_rate: u32) -> Option<u8> {
+        SAMPLE_RATES
+            .iter()
+            .position(|&rate| rate == sample_rate)
+            .map(|index| index as u8)
+    }
+}
+
+/// A parsed ADTS frame header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AdtsHeader {
+    pub config: AudioSpecificConfig,
+    pub protection_absent: bool,
+    /// Length of the whole frame, including the header.
+    pub frame_length: u16,
+    pub raw_data_blocks: u8,
+}
+
+impl AdtsHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < ADTS_HEADER_LEN {
+            return None;
+        }
+
+        if data[0] != 0xff || data[1] & 0xf6 != 0xf0 {
+            return None;
+        }
+
+        let protection_absent = data[1] & 1 != 0;
+        let profile = data[2] >> 6;
+        let frequency_index = (data[2] >> 2) & 0b1111;
+        let channel_config = ((data[2] & 1) << 2) | (data[3] >> 6);
+        let frame_length =
+            ((data[3] as u16 & 0b11) << 11) | ((data[4] as u16) << 3) | (data[5] as u16 >> 5);
+        let raw_data_blocks = data[6] & 0b11;
+
+        let header = AdtsHeader {
+            config: AudioSpecificConfig {
+                object_type: profile + 1,
+                frequency_index,
+                channel_config,
+            },
+            protection_absent,
+            frame_length,
+            raw_data_blocks,
+        };
+
+        if header.config.sample_rate().is_none() || (frame_length as usize) < header.header_len() {
+            return None;
+        }
+
+        Some(header)
+    }
+
+    /// Creates a header for a frame carrying `payload_len` bytes of raw AAC data. Returns `None`
+    /// if the object type can't be signalled in ADTS, see [`AudioSpecificConfig::to_adts`], or
+    /// if the frame is longer than [`ADTS_MAX_FRAME_LEN`].
+    pub fn new(config: AudioSpecificConfig, payload_len: usize) -> Option<Self> {
+        let frame_length = ADTS_HEADER_LEN + payload_len;
+        if !(1..=4).contains(&config.object_type) || frame_length > ADTS_MAX_FRAME_LEN {
+            return None;
+        }
+
+        Some(AdtsHeader {
+            config,
+            protection_absent: true,
+            frame_length: frame_length as u16,
+            raw_data_blocks: 0,
+        })
+    }
+
+    /// The length of the header, including the CRC if present.
+    pub fn header_len(&self) -> usize {
+        if self.protection_absent {
+            ADTS_HEADER_LEN
+        } else {
+            ADTS_HEADER_LEN + 2
+        }
+    }
+
+    /// The length of the raw AAC data following the header.
+    pub fn payload_len(&self) -> usize {
+        self.frame_length as usize - self.header_len()
+    }
+
+    /// The number of samples contained in the frame.
+    pub fn samples(&self) -> u64 {
+        AAC_FRAME_SAMPLES * (self.raw_data_blocks as u64 + 1)
+    }
+
+    /// Writes the header without a CRC.
+    pub fn write(&self, buf: &mut BytesMut) {
+        let AudioSpecificConfig {
+            object_type,
+            frequency_index,
+            channel_config,
+        } = self.config;
+        let frame_length = self.frame_length;
+
+        buf.put_u8(0xff);
+        buf.put_u8(0xf1); // MPEG-4, layer 0, protection absent
+        buf.put_u8(((object_type - 1) << 6) | (frequency_index << 2) | (channel_config >> 2));
+        buf.put_u8(((channel_config & 0b11) << 6) | (frame_length >> 11) as u8);
+        buf.put_u8((frame_length >> 3) as u8);
+        buf.put_u8(((frame_length & 0b111) << 5) as u8 | 0b1_1111); // buffer fullness = 0x7ff
+        buf.put_u8(0b1111_1100 | self.raw_data_blocks);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(&[0x12, 0x10], 2, 4, 2 ; "aac lc 44.1 kHz stereo")]
+    #[test_case(&[0x11, 0x88], 2, 3, 1 ; "aac lc 48 kHz mono")]
+    fn parse_audio_specific_config(
+        data: &[u8],
+        object_type: u8,
+        frequency_index: u8,
+        channels: u8,
+    ) {
+        let config = AudioSpecificConfig::parse(data).unwrap();
+
+        assert_eq!(object_type, config.object_type);
+        assert_eq!(frequency_index, config.frequency_index);
+        assert_eq!(channels, config.channel_config);
+        assert_eq!(data, &config.to_bytes()[..]);
+    }
+
+    #[test_case(0)]
+    #[test_case(1)]
+    #[test_case(371)]
+    #[test_case(8184)]
+    fn adts_header_roundtrip(payload_len: usize) {
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+        let header = AdtsHeader::new(config, payload_len).unwrap();
+
+        let mut buf = BytesMut::new();
+        header.write(&mut buf);
+
+        assert_eq!(ADTS_HEADER_LEN, buf.len());
+        assert_eq!(Some(header), AdtsHeader::parse(&buf));
+        assert_eq!(payload_len, header.payload_len());
+    }
+
+    #[test]
+    fn adts_frame_length_limit() {
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+
+        assert!(AdtsHeader::new(config, ADTS_MAX_FRAME_LEN - ADTS_HEADER_LEN).is_some());
+        assert!(AdtsHeader::new(config, ADTS_MAX_FRAME_LEN - ADTS_HEADER_LEN + 1).is_none());
+    }
+
+    #[test_case(&[0x12, 0x10], Some(2) ; "aac lc")]
+    #[test_case(&[0x2b, 0x92, 0x08, 0x00], Some(2) ; "he-aac")]
+    #[test_case(&[0xeb, 0x92, 0x08, 0x00], Some(2) ; "he-aac v2")]
+    #[test_case(&[0x02, 0x10], None ; "null object type")]
+    #[test_case(&[0x3a, 0x10], None ; "twinvq")]
+    fn adts_object_type(data: &[u8], expected: Option<u8>) {
+        let config = AudioSpecificConfig::parse(data).unwrap();
+        let adts = config.to_adts();
+
+        assert_eq!(expected, adts.map(|c| c.object_type));
+        assert_eq!(
+            expected.is_some(),
+            AdtsHeader::new(adts.unwrap_or(config), 0).is_some()
+        );
+    }
+}
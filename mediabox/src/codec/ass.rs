@@ -1,12 +1,13 @@
 use super::{
-    ColorType, Decoded, Decoder, TextAlign, TextAlpha, TextCue, TextFill, TextPart, TextPosition,
-    TextStyle,
+    AssCodec, ColorType, Decoded, Decoder, Karaoke, KaraokeEffect, OutputQueue, SubtitleCodec,
+    SubtitleInfo, TextAlign, TextAlpha, TextCue, TextFade, TextFill, TextMove, TextPart,
+    TextPosition, TextStyle, TextTransition,
 };
-use crate::{decoder, MediaInfo, Packet};
+use crate::{decoder, MediaInfo, MediaboxError, Packet};
 
 use logos::{Lexer, Logos};
 
-use std::{borrow::Borrow, collections::VecDeque, str};
+use std::{borrow::Borrow, collections::HashMap, str, time::Duration};
 
 decoder!("ass", AssDecoder::create);
 
@@ -16,16 +17,36 @@ pub enum AssError {
     MissingField(&'static str),
 }
 
+impl From<AssError> for MediaboxError {
+    fn from(error: AssError) -> Self {
+        MediaboxError::invalid_data(error)
+    }
+}
+
+/// The script resolution positions are relative to if the header doesn't specify one.
+const DEFAULT_PLAY_RES: (f32, f32) = (384.0, 288.0);
+
+/// The fields of `Style` lines in ASS and SSA scripts if the styles section has no `Format` line.
+const STYLE_FORMAT: &str = "Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, \
+    OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, \
+    BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding";
+const SSA_STYLE_FORMAT: &str = "Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, \
+    TertiaryColour, BackColour, Bold, Italic, BorderStyle, Outline, Shadow, Alignment, MarginL, \
+    MarginR, MarginV, AlphaLevel, Encoding";
+
 pub struct AssDecoder {
-    styles: Vec<TextStyle>,
-    cues: VecDeque<TextCue>,
+    styles: HashMap<String, TextStyle>,
+    cues: OutputQueue<TextCue>,
+    /// The `PlayResX` and `PlayResY` of the script.
+    play_res: (f32, f32),
 }
 
 impl AssDecoder {
     pub fn new() -> Self {
         AssDecoder {
-            styles: Vec::new(),
-            cues: VecDeque::new(),
+            styles: HashMap::new(),
+            cues: OutputQueue::new(),
+            play_res: DEFAULT_PLAY_RES,
         }
     }
 
@@ -41,11 +62,21 @@ impl Default for AssDecoder {
 }
 
 impl Decoder for AssDecoder {
-    fn start(&mut self, info: &MediaInfo) -> anyhow::Result<()> {
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()> {
+        if let Some(SubtitleInfo {
+            codec: SubtitleCodec::Ass(AssCodec { header }),
+        }) = info.subtitle()
+        {
+            self.play_res = play_res(header);
+            self.styles = parse_styles(header);
+        }
+
         Ok(())
     }
 
-    fn feed(&mut self, pkt: Packet) -> anyhow::Result<()> {
+    fn feed(&mut self, pkt: Packet) -> crate::Result<()> {
+        self.cues.reserve()?;
+
         let data = pkt.buffer.to_slice();
         let line = str::from_utf8(data.borrow())?;
         let mut entries = line.split(',');
@@ -63,39 +94,206 @@ impl Decoder for AssDecoder {
         let cue = TextCue {
             time: pkt.time.clone(),
             style: style.to_string(),
-            text: parse_ass_text(text),
+            text: parse_ass_text(text, self.play_res),
         };
 
-        self.cues.push_back(cue);
+        self.cues.push(cue);
 
         Ok(())
     }
 
     fn receive(&mut self) -> Option<Decoded> {
-        self.cues.pop_front().map(Decoded::Subtitle)
+        self.cues.pop().map(Decoded::Subtitle)
+    }
+
+    fn is_full(&self) -> bool {
+        self.cues.is_full()
+    }
+}
+
+/// Finds the script resolution in the `[Script Info]` section. A missing dimension is derived from
+/// the other one assuming a 4:3 aspect ratio, like renderers do.
+fn play_res(header: &str) -> (f32, f32) {
+    let value = |name: &str| {
+        header.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name)
+                .then(|| value.trim().parse::<f32>().ok())
+                .flatten()
+                .filter(|v| *v > 0.0)
+        })
+    };
+
+    match (value("PlayResX"), value("PlayResY")) {
+        (Some(x), Some(y)) => (x, y),
+        (Some(x), None) => (x, x * 3.0 / 4.0),
+        (None, Some(y)) => (y * 4.0 / 3.0, y),
+        (None, None) => DEFAULT_PLAY_RES,
     }
 }
 
-fn parse_ass_text(text: &str) -> Vec<TextPart> {
-    let mut parts = Vec::new();
+/// Parses the `Style` lines of the `[V4+ Styles]` section, or `[V4 Styles]` for SSA scripts, keyed
+/// by style name.
+pub(crate) fn parse_styles(header: &str) -> HashMap<String, TextStyle> {
+    let mut styles = HashMap::new();
+    let mut legacy = None;
+    let mut format = Vec::new();
+
+    for line in header.lines().map(str::trim) {
+        if line.starts_with('[') {
+            legacy = match line.to_ascii_lowercase().as_str() {
+                "[v4+ styles]" => Some(false),
+                "[v4 styles]" => Some(true),
+                _ => None,
+            };
+            let default_format = match legacy {
+                Some(true) => SSA_STYLE_FORMAT,
+                _ => STYLE_FORMAT,
+            };
+            format = default_format.split(',').map(str::trim).collect();
+            continue;
+        }
 
-    let parser = AssParser::new(text);
-    for part in parser {
-        match part {
-            Ass::Text(text) => {
-                parts.push(TextPart::Text(text.to_string()));
+        let (Some(legacy), Some((key, value))) = (legacy, line.split_once(':')) else {
+            continue;
+        };
+
+        match key.trim() {
+            "Format" => format = value.split(',').map(str::trim).collect(),
+            "Style" => {
+                let values = value.splitn(format.len(), ',').map(str::trim);
+                let (name, style) = parse_style(format.iter().copied().zip(values), legacy);
+                styles.insert(name, style);
             }
-            Ass::Italic(on) => parts.push(TextPart::Italic(on)),
-            Ass::Fill(pos) => parts.push(TextPart::Fill(pos)),
-            Ass::Alpha(pos) => parts.push(TextPart::Alpha(pos)),
-            Ass::Position(pos) => parts.push(TextPart::Position(pos)),
-            Ass::LineBreak => parts.push(TextPart::LineBreak),
-            Ass::SmartBreak => parts.push(TextPart::SmartBreak),
             _ => {}
         }
     }
 
-    parts
+    styles
+}
+
+fn parse_style<'a>(
+    fields: impl Iterator<Item = (&'a str, &'a str)>,
+    legacy: bool,
+) -> (String, TextStyle) {
+    let mut name = String::new();
+    let mut style = TextStyle::default();
+
+    for (field, value) in fields {
+        let int = || value.parse::<i32>().ok();
+        let float = || value.parse::<f32>().ok();
+        // -1 is true, but renderers treat any non-zero value as set
+        let flag = int().is_some_and(|v| v != 0);
+
+        match field.to_ascii_lowercase().as_str() {
+            "name" => name = value.to_string(),
+            "fontname" => style.font = Some(value.to_string()),
+            "fontsize" => style.font_size = float(),
+            "primarycolour" => style.primary_color = style_color(value),
+            "secondarycolour" => style.secondary_color = style_color(value),
+            "outlinecolour" | "tertiarycolour" => style.outline_color = style_color(value),
+            "backcolour" => style.back_color = style_color(value),
+            "bold" => style.bold = flag,
+            "italic" => style.italic = flag,
+            "underline" => style.underline = flag,
+            "strikeout" => style.strikeout = flag,
+            "scalex" => style.scale_x = float().unwrap_or(100.0),
+            "scaley" => style.scale_y = float().unwrap_or(100.0),
+            "spacing" => style.spacing = float().unwrap_or(0.0),
+            "angle" => style.angle = float().unwrap_or(0.0),
+            "borderstyle" => style.border_style = int(),
+            "outline" => style.outline = float(),
+            "shadow" => style.shadow = float(),
+            "alignment" => {
+                // SSA puts top aligned styles at 5-7 and middle aligned ones at 9-11
+                let numpad = match int() {
+                    Some(n @ 1..=3) if legacy => Some(n),
+                    Some(n @ 5..=7) if legacy => Some(n + 2),
+                    Some(n @ 9..=11) if legacy => Some(n - 5),
+                    _ if legacy => None,
+                    n => n,
+                };
+                style.alignment = numpad.and_then(numpad_align);
+            }
+            "marginl" => style.margin_left = int(),
+            "marginr" => style.margin_right = int(),
+            "marginv" => style.margin_vertical = int(),
+            _ => {}
+        }
+    }
+
+    (name, style)
+}
+
+/// Parses a `&HAABBGGRR` style color, or the decimal colors of SSA scripts, dropping the alpha.
+fn style_color(value: &str) -> Option<u32> {
+    let color = match value
+        .strip_prefix("&H")
+        .or_else(|| value.strip_prefix("&h"))
+    {
+        Some(hex) => u32::from_str_radix(hex.trim_end_matches('&'), 16).ok()?,
+        None => value.parse::<i64>().ok()? as u32,
+    };
+
+    Some(color & 0xff_ffff)
+}
+
+/// Maps an alignment laid out like a numpad to a [TextAlign].
+fn numpad_align(numpad: i32) -> Option<TextAlign> {
+    match numpad {
+        1 => Some(TextAlign::BotLeft),
+        2 => Some(TextAlign::Bot),
+        3 => Some(TextAlign::BotRight),
+        4 => Some(TextAlign::MidLeft),
+        5 => Some(TextAlign::Mid),
+        6 => Some(TextAlign::MidRight),
+        7 => Some(TextAlign::TopLeft),
+        8 => Some(TextAlign::Top),
+        9 => Some(TextAlign::TopRight),
+        _ => None,
+    }
+}
+
+fn parse_ass_text(text: &str, play_res: (f32, f32)) -> Vec<TextPart> {
+    AssParser::new(text)
+        .filter_map(|part| text_part(part, play_res))
+        .collect()
+}
+
+fn text_part(part: Ass, play_res: (f32, f32)) -> Option<TextPart> {
+    let relative =
+        |TextPosition(x, y)| TextPosition(x * 100.0 / play_res.0, y * 100.0 / play_res.1);
+
+    let part = match part {
+        Ass::Text(text) => TextPart::Text(text.to_string()),
+        Ass::Italic(on) => TextPart::Italic(on),
+        Ass::Fill(fill) => TextPart::Fill(fill),
+        Ass::Alpha(alpha) => TextPart::Alpha(alpha),
+        Ass::Align(align) => TextPart::Align(align),
+        Ass::Position(pos) => TextPart::Position(relative(pos)),
+        Ass::Karaoke(karaoke) => TextPart::Karaoke(karaoke),
+        Ass::Fade(fade) => TextPart::Fade(fade),
+        Ass::Move(TextMove { from, to, times }) => TextPart::Move(TextMove {
+            from: relative(from),
+            to: relative(to),
+            times,
+        }),
+        Ass::Transition(AssTransition { times, accel, tags }) => {
+            TextPart::Transition(TextTransition {
+                times,
+                accel,
+                parts: Ass::lexer(tags)
+                    .filter_map(|part| text_part(part, play_res))
+                    .collect(),
+            })
+        }
+        Ass::Drawing(scale) => TextPart::Drawing(scale),
+        Ass::LineBreak => TextPart::LineBreak,
+        Ass::SmartBreak => TextPart::SmartBreak,
+        _ => return None,
+    };
+
+    Some(part)
 }
 
 fn italics<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<bool> {
@@ -111,18 +309,7 @@ fn italics<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<bool> {
 fn align<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<TextAlign> {
     let span = lex.slice();
 
-    match &span[3..4] {
-        "1" => Some(TextAlign::BotLeft),
-        "2" => Some(TextAlign::Bot),
-        "3" => Some(TextAlign::BotRight),
-        "4" => Some(TextAlign::MidLeft),
-        "5" => Some(TextAlign::Mid),
-        "6" => Some(TextAlign::MidRight),
-        "7" => Some(TextAlign::TopLeft),
-        "8" => Some(TextAlign::Top),
-        "9" => Some(TextAlign::TopRight),
-        _ => None,
-    }
+    numpad_align(span[3..4].parse().ok()?)
 }
 
 fn fontsize<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<u32> {
@@ -176,6 +363,105 @@ fn text_pos<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<TextPosition> {
     Some(TextPosition(x, y))
 }
 
+/// Parses the comma separated numbers between the parentheses of a tag.
+fn arguments(span: &str) -> Option<Vec<f32>> {
+    let (_, inside) = span.split_once('(')?;
+    let inside = inside.strip_suffix(')').unwrap_or(inside);
+
+    inside
+        .split(',')
+        .map(|arg| arg.trim().parse().ok())
+        .collect()
+}
+
+fn millis(ms: f32) -> Duration {
+    Duration::from_millis(ms.max(0.0) as u64)
+}
+
+fn karaoke<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<Karaoke> {
+    let span = lex.slice();
+
+    let (effect, digits) = match &span[1..3] {
+        "kf" => (KaraokeEffect::Sweep, &span[3..]),
+        "ko" => (KaraokeEffect::Outline, &span[3..]),
+        _ if span.starts_with(r"\K") => (KaraokeEffect::Sweep, &span[2..]),
+        _ => (KaraokeEffect::Instant, &span[2..]),
+    };
+
+    // in centiseconds
+    let duration = digits.parse::<u64>().ok()?;
+
+    Some(Karaoke(
+        effect,
+        Duration::from_millis(duration.saturating_mul(10)),
+    ))
+}
+
+fn fade<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<TextFade> {
+    let [fade_in, fade_out] = arguments(lex.slice())?[..] else {
+        return None;
+    };
+
+    Some(TextFade(millis(fade_in), millis(fade_out)))
+}
+
+fn text_move<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<TextMove> {
+    let args = arguments(lex.slice())?;
+
+    let times = match args[..] {
+        [_, _, _, _] => None,
+        [_, _, _, _, start, end] => Some((millis(start), millis(end))),
+        _ => return None,
+    };
+
+    Some(TextMove {
+        from: TextPosition(args[0], args[1]),
+        to: TextPosition(args[2], args[3]),
+        times,
+    })
+}
+
+/// Parses `\t([t1,t2,][accel,]tags)`. The tags may contain parentheses themselves, so the end is
+/// found by matching them.
+fn transition<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<AssTransition<'a>> {
+    let remainder = lex.remainder();
+
+    let mut depth = 1;
+    let end = remainder.find(|c| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+
+        depth == 0
+    });
+    let inside = &remainder[..end.unwrap_or(remainder.len())];
+    lex.bump(end.map_or(remainder.len(), |end| end + 1));
+
+    let (args, tags) = inside.split_at(inside.find('\\').unwrap_or(inside.len()));
+    let args = args
+        .split(',')
+        .map(str::trim)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| arg.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    let (times, accel) = match args[..] {
+        [] => (None, 1.0),
+        [accel] => (None, accel),
+        [start, end] => (Some((millis(start), millis(end))), 1.0),
+        [start, end, accel] => (Some((millis(start), millis(end))), accel),
+        _ => return None,
+    };
+
+    Some(AssTransition { times, accel, tags })
+}
+
+fn drawing<'a>(lex: &mut Lexer<'a, Ass<'a>>) -> Option<u32> {
+    lex.slice()[2..].parse().ok()
+}
+
 fn strip_last<'a>(lex: &mut Lexer<'a, AssText<'a>>) -> Option<&'a str> {
     let span = lex.slice();
 
@@ -203,27 +489,42 @@ pub enum Ass<'a> {
     #[error]
     Error,
 
-    #[regex(r"\\i\d", italics)]
+    #[regex(r"\\i[0-9]", italics)]
     Italic(bool),
 
-    #[regex(r"\\an\d", align, priority = 50)]
+    #[regex(r"\\an[0-9]", align, priority = 50)]
     Align(TextAlign),
 
     #[regex(r"\\r")]
     Reset,
 
-    #[regex(r"\\fs\d+", fontsize)]
+    #[regex(r"\\fs[0-9]+", fontsize)]
     FontSize(u32),
 
-    #[regex(r"\\\d?c&H[a-fA-F0-9]+&", text_fill)]
+    #[regex(r"\\[0-9]?c&H[a-fA-F0-9]+&", text_fill)]
     Fill(TextFill),
 
-    #[regex(r"\\\d?a&H[a-fA-F0-9]+&", text_alpha)]
+    #[regex(r"\\[0-9]?a&H[a-fA-F0-9]+&", text_alpha)]
     Alpha(TextAlpha),
 
-    #[regex(r"\\pos\(\d+(\.\d+)?,\d+(\.\d+)?\)", text_pos)]
+    #[regex(r"\\pos\([0-9]+(\.[0-9]+)?,[0-9]+(\.[0-9]+)?\)", text_pos)]
     Position(TextPosition),
 
+    #[regex(r"\\(k[fo]?|K)[0-9]+", karaoke)]
+    Karaoke(Karaoke),
+
+    #[regex(r"\\fad\([^)]*\)", fade)]
+    Fade(TextFade),
+
+    #[regex(r"\\move\([^)]*\)", text_move)]
+    Move(TextMove),
+
+    #[token(r"\t(", transition)]
+    Transition(AssTransition<'a>),
+
+    #[regex(r"\\p[0-9]+", drawing)]
+    Drawing(u32),
+
     Text(&'a str),
     LineBreak,
     SmartBreak,
@@ -233,6 +534,14 @@ pub enum Ass<'a> {
     Border(i32),
 }
 
+/// A `\t` tag, with the tags it transitions to left unparsed.
+#[derive(Debug, PartialEq)]
+pub struct AssTransition<'a> {
+    times: Option<(Duration, Duration)>,
+    accel: f32,
+    tags: &'a str,
+}
+
 struct AssParser<'a> {
     src: &'a str,
     in_braces: bool,
@@ -366,6 +675,43 @@ mod test {
             Position(TextPosition(123.456, 5.0)),
             Text("Position")
         ])]
+    #[test_case(
+        r"{\k50}Ka{\kf25}ra{\K10}o{\ko5}ke",
+        &[
+            Ass::Karaoke(crate::codec::Karaoke(KaraokeEffect::Instant, Duration::from_millis(500))),
+            Text("Ka"),
+            Ass::Karaoke(crate::codec::Karaoke(KaraokeEffect::Sweep, Duration::from_millis(250))),
+            Text("ra"),
+            Ass::Karaoke(crate::codec::Karaoke(KaraokeEffect::Sweep, Duration::from_millis(100))),
+            Text("o"),
+            Ass::Karaoke(crate::codec::Karaoke(KaraokeEffect::Outline, Duration::from_millis(50))),
+            Text("ke")
+        ])]
+    #[test_case(
+        r"{\fad(200,300)\move(10,20,30,40,0,500)}Moving",
+        &[
+            Fade(TextFade(Duration::from_millis(200), Duration::from_millis(300))),
+            Move(TextMove {
+                from: TextPosition(10.0, 20.0),
+                to: TextPosition(30.0, 40.0),
+                times: Some((Duration::ZERO, Duration::from_millis(500))),
+            }),
+            Text("Moving")
+        ])]
+    #[test_case(
+        r"{\t(0,1000,0.5,\c&H0000ff&\clip(1,2,3,4))\i1}Fading",
+        &[
+            Transition(AssTransition {
+                times: Some((Duration::ZERO, Duration::from_millis(1000))),
+                accel: 0.5,
+                tags: r"\c&H0000ff&\clip(1,2,3,4)",
+            }),
+            Italic(true),
+            Text("Fading")
+        ])]
+    #[test_case(
+        r"{\p1}m 0 0 l 100 0 100 100{\p0}",
+        &[Drawing(1), Text("m 0 0 l 100 0 100 100"), Drawing(0)])]
     fn parse(ass: &str, expected: &[Ass]) {
         eprintln!("{}", ass);
 
@@ -394,4 +740,77 @@ mod test {
 
         assert_eq!(&tokens[..], expected);
     }
+
+    #[test]
+    fn transition_parts() {
+        let parts = parse_ass_text(r"{\t(\move(0,0,192,144)\i1)}x", DEFAULT_PLAY_RES);
+
+        assert_eq!(
+            parts,
+            [
+                TextPart::Transition(TextTransition {
+                    times: None,
+                    accel: 1.0,
+                    parts: vec![
+                        TextPart::Move(TextMove {
+                            from: TextPosition(0.0, 0.0),
+                            to: TextPosition(50.0, 50.0),
+                            times: None,
+                        }),
+                        TextPart::Italic(true),
+                    ],
+                }),
+                TextPart::Text("x".into()),
+            ]
+        );
+    }
+
+    #[test_case(r"{\i١}x" ; "non-ascii italics")]
+    #[test_case(r"{\an٣}x" ; "non-ascii alignment")]
+    #[test_case(r"{\٣c&H0&}x" ; "non-ascii color")]
+    #[test_case(r"{\k9999999999999999999}x" ; "overflowing karaoke")]
+    fn malformed_tags(ass: &str) {
+        parse_ass_text(ass, DEFAULT_PLAY_RES);
+    }
+
+    #[test]
+    fn styles() {
+        let header = "[Script Info]\nScriptType: v4.00+\n\n\
+                      [V4+ Styles]\n\
+                      Format: Name, Fontname, Fontsize, PrimaryColour, Bold, Italic, Outline, Alignment, MarginV\n\
+                      Style: Default,Arial,20,&H00FFFFFF,0,0,2,2,10\n\
+                      Style: Sign,Comic Sans, 32.5,&H4000FFFF,-1,1,1.5,8,25\n\n\
+                      [Events]\n\
+                      Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n";
+
+        let styles = parse_styles(header);
+        assert_eq!(2, styles.len());
+
+        let sign = &styles["Sign"];
+        assert_eq!(Some("Comic Sans"), sign.font.as_deref());
+        assert_eq!(Some(32.5), sign.font_size);
+        assert_eq!(Some(0x00ffff), sign.primary_color);
+        assert!(sign.bold && sign.italic);
+        assert_eq!(Some(1.5), sign.outline);
+        assert_eq!(Some(TextAlign::Top), sign.alignment);
+        assert_eq!(Some(25), sign.margin_vertical);
+        assert_eq!(100.0, sign.scale_x);
+
+        let default = &styles["Default"];
+        assert!(!default.bold && !default.italic);
+        assert_eq!(Some(TextAlign::Bot), default.alignment);
+    }
+
+    #[test_case("[V4 Styles]\nStyle: Top,Arial,20,16777215,0,0,0,0,0,1,2,0,6,10,10,10,0,0", TextAlign::Top ; "ssa")]
+    #[test_case("[V4+ Styles]\nStyle: Top,Arial,20,&H0,&H0,&H0,&H0,0,0,0,0,100,100,0,0,1,2,0,8,10,10,10,1", TextAlign::Top ; "ass")]
+    fn style_alignment(header: &str, expected: TextAlign) {
+        assert_eq!(Some(expected), parse_styles(header)["Top"].alignment);
+    }
+
+    #[test_case("[Script Info]\nPlayResX: 1920\nPlayResY: 1080\n", (1920.0, 1080.0) ; "both")]
+    #[test_case("[Script Info]\nPlayResY:720", (960.0, 720.0) ; "height only")]
+    #[test_case("[Script Info]\nTitle: PlayResX", DEFAULT_PLAY_RES ; "missing")]
+    fn script_resolution(header: &str, expected: (f32, f32)) {
+        assert_eq!(expected, play_res(header));
+    }
 }
@@ -0,0 +1,497 @@
+//! Decoders and encoders backed by FFmpeg through [ffmpeg-next](https://github.com/zmwangx/rust-ffmpeg).
+//!
+//! H.265 isn't supported since there is no [VideoCodec] for it yet.
+
+use std::{collections::VecDeque, sync::Arc, sync::Mutex};
+
+use bytes::Bytes;
+use ffmpeg_next::{
+    codec::{self, Id},
+    decoder, encoder,
+    format::{sample, Pixel, Sample},
+    frame, Rational,
+};
+
+use crate::{
+    codec::{
+        h264::AvcDecoderConfig,
+        nal::{
+            convert_bitstream, get_codec_from_parameter_sets, parse_bitstream, BitstreamFraming,
+        },
+    },
+    AacCodec, AudioCodec, FlacCodec, Fraction, MediaInfo, MediaKind, MediaTime, OpusCodec, Packet,
+    PixelFormat, Span, Track, VideoCodec, VideoInfo,
+};
+
+use super::*;
+
+/// Decoders for the codecs FFmpeg is used for, registered by their [MediaInfo] name.
+pub const DECODERS: [DecoderMetadata; 6] = [
+    DecoderMetadata {
+        name: "h264",
+        create: || Box::new(FfmpegDecoder::new(Id::H264)),
+    },
+    DecoderMetadata {
+        name: "vp8",
+        create: || Box::new(FfmpegDecoder::new(Id::VP8)),
+    },
+    DecoderMetadata {
+        name: "vp9",
+        create: || Box::new(FfmpegDecoder::new(Id::VP9)),
+    },
+    DecoderMetadata {
+        name: "aac",
+        create: || Box::new(FfmpegDecoder::new(Id::AAC)),
+    },
+    DecoderMetadata {
+        name: "opus",
+        create: || Box::new(FfmpegDecoder::new(Id::OPUS)),
+    },
+    DecoderMetadata {
+        name: "vorbis",
+        create: || Box::new(FfmpegDecoder::new(Id::VORBIS)),
+    },
+    DecoderMetadata {
+        name: "flac",
+        create: || Box::new(FfmpegDecoder::new(Id::FLAC)),
+    },
+];
+
+pub const H264_ENCODER_META: EncoderMetadata = EncoderMetadata {
+    name: "h264",
+    create: || Box::new(FfmpegH264Encoder::new()),
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FfmpegError {
+    #[error("FFmpeg has no {0:?} {1}")]
+    CodecNotFound(Id, &'static str),
+
+    #[error("Unsupported pixel format {0:?}")]
+    UnsupportedPixelFormat(Pixel),
+
+    #[error("Unsupported sample format {0:?}")]
+    UnsupportedSampleFormat(Sample),
+
+    #[error("{0} not started")]
+    NotStarted(&'static str),
+
+    #[error("{0}")]
+    Ffmpeg(#[from] ffmpeg_next::Error),
+}
+
+impl From<FfmpegError> for MediaboxError {
+    fn from(error: FfmpegError) -> Self {
+        match error {
+            FfmpegError::CodecNotFound(..)
+            | FfmpegError::UnsupportedPixelFormat(_)
+            | FfmpegError::UnsupportedSampleFormat(_) => {
+                MediaboxError::unsupported(error.to_string())
+            }
+            FfmpegError::NotStarted(_) => MediaboxError::Other(error.into()),
+            FfmpegError::Ffmpeg(_) => MediaboxError::invalid_data(error),
+        }
+    }
+}
+
+impl From<ffmpeg_next::Error> for MediaboxError {
+    fn from(error: ffmpeg_next::Error) -> Self {
+        FfmpegError::Ffmpeg(error).into()
+    }
+}
+
+/// Decodes H.264 to [VideoFrame]s and AAC to [AudioFrame]s.
+pub struct FfmpegDecoder {
+    id: Id,
+    // the codec context is `Send` but not `Sync`, it is only ever accessed through `&mut self`
+    decoder: Mutex<Option<decoder::Opened>>,
+    /// The bitstream framing packets are converted to, matching the extradata given to FFmpeg.
+    framing: Option<(BitstreamFraming, BitstreamFraming)>,
+    timebase: Fraction,
+    decoded: VecDeque<Decoded>,
+}
+
+impl FfmpegDecoder {
+    pub fn new(id: Id) -> Self {
+        FfmpegDecoder {
+            id,
+            decoder: Mutex::new(None),
+            framing: None,
+            timebase: Fraction::new(1, 1),
+            decoded: VecDeque::new(),
+        }
+    }
+
+    fn receive_frames(&mut self) -> Result<(), FfmpegError> {
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .ok_or(FfmpegError::NotStarted("Decoder"))?;
+
+        loop {
+            // receiving fails with EAGAIN once the decoder needs more input
+            let decoded = match decoder.medium() {
+                ffmpeg_next::media::Type::Video => {
+                    let mut frame = frame::Video::empty();
+                    if decoder.receive_frame(&mut frame).is_err() {
+                        break;
+                    }
+
+                    Decoded::Video(video_frame(&frame, self.timebase)?)
+                }
+                _ => {
+                    let mut frame = frame::Audio::empty();
+                    if decoder.receive_frame(&mut frame).is_err() {
+                        break;
+                    }
+
+                    Decoded::Audio(audio_frame(&frame, self.timebase)?)
+                }
+            };
+
+            self.decoded.push_back(decoded);
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder for FfmpegDecoder {
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()> {
+        ffmpeg_next::init()?;
+
+        let codec = decoder::find(self.id).ok_or(FfmpegError::CodecNotFound(self.id, "decoder"))?;
+        let mut context = codec::Context::new_with_codec(codec);
+
+        match &info.kind {
+            MediaKind::Video(VideoInfo {
+                codec: VideoCodec::H264(h264),
+                ..
+            }) => {
+                set_extradata(
+                    &mut context,
+                    &AvcDecoderConfig::from(h264).to_span().to_slice(),
+                );
+                self.framing = Some((h264.bitstream_format, BitstreamFraming::FourByteLength));
+            }
+            // VP8 and VP9 are configured entirely in-band
+            MediaKind::Video(VideoInfo {
+                codec: VideoCodec::Vp8(_) | VideoCodec::Vp9(_),
+                ..
+            }) => {}
+            MediaKind::Audio(audio) => match &audio.codec {
+                AudioCodec::Aac(AacCodec { extra }) => set_extradata(&mut context, extra),
+                AudioCodec::Opus(OpusCodec { header }) | AudioCodec::Flac(FlacCodec { header }) => {
+                    set_extradata(&mut context, header)
+                }
+                AudioCodec::Vorbis(vorbis) => set_extradata(&mut context, &vorbis.to_xiph_laced()),
+                AudioCodec::Pcm(_) => {}
+            },
+            _ => {
+                return Err(MediaboxError::unsupported(format!(
+                    "Unsupported media for FFmpeg decoder: {:?}",
+                    info.kind
+                )))
+            }
+        }
+
+        let opened = context.decoder().open_as(codec)?;
+        *self.decoder.get_mut().unwrap() = Some(opened);
+
+        Ok(())
+    }
+
+    fn feed(&mut self, pkt: Packet) -> crate::Result<()> {
+        self.timebase = pkt.time.timebase;
+
+        let buffer = match self.framing {
+            Some((source, target)) => convert_bitstream(pkt.buffer, source, target),
+            None => pkt.buffer,
+        };
+
+        let mut packet = ffmpeg_next::Packet::copy(&buffer.to_slice());
+        packet.set_pts(Some(pkt.time.pts as i64));
+        packet.set_dts(pkt.time.dts.map(|dts| dts as i64));
+
+        self.decoder
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .ok_or(FfmpegError::NotStarted("Decoder"))?
+            .send_packet(&packet)?;
+
+        self.receive_frames()?;
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Decoded> {
+        self.decoded.pop_front()
+    }
+}
+
+/// Encodes [VideoFrame]s to H.264 with whichever H.264 encoder FFmpeg was built with, typically
+/// libx264.
+pub struct FfmpegH264Encoder {
+    encoder: Mutex<Option<encoder::Video>>,
+    track: Option<Track>,
+    packets: VecDeque<Packet>,
+}
+
+impl FfmpegH264Encoder {
+    pub fn new() -> Self {
+        FfmpegH264Encoder {
+            encoder: Mutex::new(None),
+            track: None,
+            packets: VecDeque::new(),
+        }
+    }
+}
+
+impl FfmpegH264Encoder {
+    fn receive_packets(&mut self) -> anyhow::Result<()> {
+        let track = self
+            .track
+            .as_ref()
+            .ok_or(FfmpegError::NotStarted("Encoder"))?;
+        let encoder = self
+            .encoder
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .ok_or(FfmpegError::NotStarted("Encoder"))?;
+
+        let mut packet = ffmpeg_next::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            let data = Bytes::copy_from_slice(packet.data().unwrap_or_default());
+
+            self.packets.push_back(Packet {
+                time: MediaTime {
+                    pts: packet.pts().unwrap_or_default() as u64,
+                    dts: packet.dts().map(|dts| dts as u64),
+                    duration: Some(packet.duration() as u64).filter(|&d| d > 0),
+                    timebase: track.timebase,
+                },
+                key: packet.is_key(),
+                track: track.clone(),
+                buffer: data.into(),
+                side_data: Vec::new(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for FfmpegH264Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder for FfmpegH264Encoder {
+    fn start(&mut self, desc: CodecDescription) -> crate::Result<Track> {
+        ffmpeg_next::init()?;
+
+        let desc = desc
+            .into_video()
+            .ok_or_else(|| anyhow::anyhow!("Expected video description"))?;
+
+        let codec =
+            encoder::find(Id::H264).ok_or(FfmpegError::CodecNotFound(Id::H264, "encoder"))?;
+        let mut context = codec::Context::new_with_codec(codec);
+        // puts the parameter sets in the extradata instead of in front of the first frame
+        context.set_flags(codec::Flags::GLOBAL_HEADER);
+
+        let mut video = context.encoder().video()?;
+        video.set_width(desc.width);
+        video.set_height(desc.height);
+        video.set_format(pixel(desc.format));
+        video.set_time_base(Rational::new(
+            desc.timebase.numerator as i32,
+            desc.timebase.denominator as i32,
+        ));
+        if let Some(bitrate) = desc.bitrate {
+            video.set_bit_rate(bitrate as usize);
+        }
+
+        let opened = video.open_as(codec)?;
+
+        let extradata = unsafe {
+            let context = opened.as_ptr();
+            std::slice::from_raw_parts((*context).extradata, (*context).extradata_size as usize)
+        };
+        let nal_units = parse_bitstream(
+            Bytes::copy_from_slice(extradata).into(),
+            BitstreamFraming::FourByteStartCode,
+        );
+        let find_nal = |nal_unit_type: u8| {
+            nal_units
+                .iter()
+                .find(|nal| nal.to_slice().first().map(|b| b & 0x1f) == Some(nal_unit_type))
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Encoder produced no parameter set {nal_unit_type}"))
+        };
+
+        let info = get_codec_from_parameter_sets(
+            find_nal(7)?,
+            find_nal(8)?,
+            BitstreamFraming::FourByteStartCode,
+        )?;
+
+        let track = Track {
+            id: 0,
+            info: Arc::new(info),
+            timebase: desc.timebase,
+            delay: self.delay(),
+            metadata: Default::default(),
+        };
+
+        *self.encoder.get_mut().unwrap() = Some(opened);
+        self.track = Some(track.clone());
+
+        Ok(track)
+    }
+
+    fn feed(&mut self, raw: Decoded) -> crate::Result<()> {
+        let input = raw
+            .into_video()
+            .ok_or_else(|| anyhow::anyhow!("Expected video frame"))?;
+        let encoder = self
+            .encoder
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .ok_or(FfmpegError::NotStarted("Encoder"))?;
+
+        let mut frame = frame::Video::new(pixel(input.format), input.width, input.height);
+        let sizes = input.format.plane_sizes(input.width, input.height);
+
+        for (i, (plane, (width, rows))) in input.planes.iter().zip(sizes).enumerate() {
+            let src = plane.data.to_slice();
+            let dst_stride = frame.stride(i);
+            let dst = frame.data_mut(i);
+
+            for row in 0..rows {
+                let src_row = &src[row * plane.stride..row * plane.stride + width];
+                dst[row * dst_stride..row * dst_stride + width].copy_from_slice(src_row);
+            }
+        }
+
+        frame.set_pts(Some(input.time.pts as i64));
+        encoder.send_frame(&frame)?;
+
+        Ok(self.receive_packets()?)
+    }
+
+    fn flush(&mut self) -> crate::Result<()> {
+        self.encoder
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .ok_or(FfmpegError::NotStarted("Encoder"))?
+            .send_eof()?;
+
+        Ok(self.receive_packets()?)
+    }
+
+    fn receive(&mut self) -> Option<Packet> {
+        self.packets.pop_front()
+    }
+}
+
+fn set_extradata(context: &mut codec::Context, data: &[u8]) {
+    use ffmpeg_next::ffi;
+
+    unsafe {
+        // FFmpeg requires padding after the extradata and frees it together with the context
+        let len = data.len() + ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+        let extradata = ffi::av_mallocz(len) as *mut u8;
+        std::ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+
+        let context = context.as_mut_ptr();
+        (*context).extradata = extradata;
+        (*context).extradata_size = data.len() as i32;
+    }
+}
+
+fn pixel(format: PixelFormat) -> Pixel {
+    match format {
+        PixelFormat::Yuv420p => Pixel::YUV420P,
+        PixelFormat::Nv12 => Pixel::NV12,
+        PixelFormat::Rgb24 => Pixel::RGB24,
+        PixelFormat::Rgba => Pixel::RGBA,
+    }
+}
+
+fn video_frame(frame: &frame::Video, timebase: Fraction) -> Result<VideoFrame, FfmpegError> {
+    let format = match frame.format() {
+        Pixel::YUV420P | Pixel::YUVJ420P => PixelFormat::Yuv420p,
+        Pixel::NV12 => PixelFormat::Nv12,
+        Pixel::RGB24 => PixelFormat::Rgb24,
+        Pixel::RGBA => PixelFormat::Rgba,
+        other => return Err(FfmpegError::UnsupportedPixelFormat(other)),
+    };
+
+    let planes = (0..frame.planes())
+        .map(|i| VideoPlane {
+            data: Span::from(Bytes::copy_from_slice(frame.data(i))),
+            stride: frame.stride(i),
+        })
+        .collect();
+
+    Ok(VideoFrame {
+        time: frame_time(frame.pts(), timebase),
+        width: frame.width(),
+        height: frame.height(),
+        format,
+        planes,
+    })
+}
+
+fn audio_frame(frame: &frame::Audio, timebase: Fraction) -> Result<AudioFrame, FfmpegError> {
+    let channels = frame.channels() as usize;
+    let count = frame.samples();
+
+    let sample = |data: &[u8], index: usize| -> f32 {
+        match frame.format() {
+            Sample::F32(_) => {
+                f32::from_ne_bytes(data[index * 4..index * 4 + 4].try_into().unwrap())
+            }
+            _ => {
+                i16::from_ne_bytes(data[index * 2..index * 2 + 2].try_into().unwrap()) as f32
+                    / 32768.0
+            }
+        }
+    };
+
+    let samples = match frame.format() {
+        Sample::F32(sample::Type::Planar) | Sample::I16(sample::Type::Planar) => (0..count)
+            .flat_map(|i| (0..channels).map(move |c| (c, i)))
+            .map(|(c, i)| sample(frame.data(c), i))
+            .collect(),
+        Sample::F32(sample::Type::Packed) | Sample::I16(sample::Type::Packed) => {
+            let data = frame.data(0);
+            (0..count * channels).map(|i| sample(data, i)).collect()
+        }
+        other => return Err(FfmpegError::UnsupportedSampleFormat(other)),
+    };
+
+    Ok(AudioFrame {
+        time: frame_time(frame.pts(), timebase),
+        sample_rate: frame.rate(),
+        channels: channels as u16,
+        samples,
+    })
+}
+
+fn frame_time(pts: Option<i64>, timebase: Fraction) -> MediaTime {
+    MediaTime {
+        pts: pts.unwrap_or_default().max(0) as u64,
+        dts: None,
+        duration: None,
+        timebase,
+    }
+}
@@ -0,0 +1,270 @@
+use bytes::Buf;
+
+use crate::FlacCodec;
+
+/// The marker at the start of native FLAC streams and of the Matroska `CodecPrivate`.
+pub const FLAC_MARKER: &[u8; 4] = b"fLaC";
+
+pub const METADATA_STREAMINFO: u8 = 0;
+pub const STREAMINFO_LEN: usize = 34;
+
+/// The smallest possible frame header, including the CRC-8.
+pub const FRAME_HEADER_MIN_LEN: usize = 6;
+
+/// The `STREAMINFO` metadata block, which describes the whole stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    pub min_frame_size: u32,
+    pub max_frame_size: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    /// The number of samples per channel, 0 if unknown.
+    pub total_samples: u64,
+}
+
+impl StreamInfo {
+    pub fn parse(mut data: &[u8]) -> Option<Self> {
+        if data.len() < STREAMINFO_LEN {
+            return None;
+        }
+
+        let min_block_size = data.get_u16();
+        let max_block_size = data.get_u16();
+        let min_frame_size = data.get_uint(3) as u32;
+        let max_frame_size = data.get_uint(3) as u32;
+
+        // 20 bits sample rate, 3 bits channels - 1, 5 bits bits per sample - 1, 36 bits samples
+        let packed = data.get_u64();
+        let sample_rate = (packed >> 44) as u32;
+        let channels = ((packed >> 41) & 0x7) as u8 + 1;
+        let bits_per_sample = ((packed >> 36) & 0x1f) as u8 + 1;
+        let total_samples = packed & 0xf_ffff_ffff;
+
+        if sample_rate == 0 {
+            return None;
+        }
+
+        Some(StreamInfo {
+            min_block_size,
+            max_block_size,
+            min_frame_size,
+            max_frame_size,
+            sample_rate,
+            channels,
+            bits_per_sample,
+            total_samples,
+        })
+    }
+}
+
+impl FlacCodec {
+    /// Builds the codec header from the contents of a `STREAMINFO` block.
+    pub fn from_stream_info(stream_info: &[u8]) -> Self {
+        let mut header = FLAC_MARKER.to_vec();
+        // the only metadata block, so it is also the last
+        header.push(0x80 | METADATA_STREAMINFO);
+        header.extend_from_slice(&(stream_info.len() as u32).to_be_bytes()[1..]);
+        header.extend_from_slice(stream_info);
+
+        FlacCodec { header }
+    }
+
+    /// Finds the `STREAMINFO` block, which is always the first metadata block.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        let [block_type, _, _, _, data @ ..] = self.header.strip_prefix(FLAC_MARKER)? else {
+            return None;
+        };
+
+        if block_type & 0x7f != METADATA_STREAMINFO {
+            return None;
+        }
+
+        StreamInfo::parse(data)
+    }
+}
+
+/// The parts of a frame header needed to packetize a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub variable_block_size: bool,
+    /// The number of samples per channel in the frame.
+    pub block_size: u32,
+    /// The frame number for fixed block size streams, otherwise the number of the first sample.
+    pub number: u64,
+}
+
+impl FrameHeader {
+    /// Parses a frame header, verifying its CRC-8.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let [0xff, sync @ (0xf8 | 0xf9), sizes, channels, rest @ ..] = data else {
+            return None;
+        };
+
+        let variable_block_size = sync & 1 != 0;
+        let block_size_code = sizes >> 4;
+        let sample_rate_code = sizes & 0xf;
+
+        // reserved values
+        if block_size_code == 0
+            || sample_rate_code == 0xf
+            || channels >> 4 > 0b1010
+            || (channels >> 1) & 0x7 == 0b011
+            || channels & 1 != 0
+        {
+            return None;
+        }
+
+        let (number, len) = read_coded_number(rest)?;
+        let mut rest = &rest[len..];
+
+        let block_size = match block_size_code {
+            1 => 192,
+            2..=5 => 576 << (block_size_code - 2),
+            6 => read_uint(&mut rest, 1)? as u32 + 1,
+            7 => read_uint(&mut rest, 2)? as u32 + 1,
+            _ => 256 << (block_size_code - 8),
+        };
+
+        match sample_rate_code {
+            12 => read_uint(&mut rest, 1)?,
+            13 | 14 => read_uint(&mut rest, 2)?,
+            _ => 0,
+        };
+
+        let header_len = data.len() - rest.len();
+        if *rest.first()? != crc8(&data[..header_len]) {
+            return None;
+        }
+
+        Some(FrameHeader {
+            variable_block_size,
+            block_size,
+            number,
+        })
+    }
+}
+
+fn read_uint(data: &mut &[u8], len: usize) -> Option<u64> {
+    if data.len() < len {
+        return None;
+    }
+
+    Some(data.get_uint(len))
+}
+
+/// Reads the UTF-8 like coded frame or sample number, returning it and its length.
+fn read_coded_number(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = match first.leading_ones() {
+        0 => return Some((first as u64, 1)),
+        1 => return None,
+        n @ 2..=7 => n as usize,
+        _ => return None,
+    };
+
+    let mut number = (first & (0xff >> (len + 1))) as u64;
+    for &byte in data.get(1..len)? {
+        if byte & 0xc0 != 0x80 {
+            return None;
+        }
+
+        number = (number << 6) | (byte & 0x3f) as u64;
+    }
+
+    Some((number, len))
+}
+
+/// CRC-8 with the polynomial x^8 + x^2 + x + 1, protecting frame headers.
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+
+        crc
+    })
+}
+
+/// CRC-16 with the polynomial x^16 + x^15 + x^2 + 1, protecting whole frames. A frame including
+/// its CRC-16 footer has a CRC of 0.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+
+        crc
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn frame_header(data: &[u8]) -> Vec<u8> {
+        let mut header = data.to_vec();
+        header.push(crc8(data));
+        header
+    }
+
+    #[test_case(&[0xff, 0xf8, 0xc9, 0x18, 0x00], false, 4096, 0 ; "fixed block size")]
+    #[test_case(&[0xff, 0xf8, 0x69, 0x18, 0x05, 0x7f], false, 128, 5 ; "8 bit block size")]
+    #[test_case(&[0xff, 0xf9, 0xc9, 0x18, 0xe0, 0x9f, 0x80], true, 4096, 0x7c0 ; "sample number")]
+    fn parse_frame_header(data: &[u8], variable_block_size: bool, block_size: u32, number: u64) {
+        assert_eq!(
+            Some(FrameHeader {
+                variable_block_size,
+                block_size,
+                number,
+            }),
+            FrameHeader::parse(&frame_header(data))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_crc() {
+        let mut header = frame_header(&[0xff, 0xf8, 0xc9, 0x18, 0x00]);
+        header[5] ^= 1;
+
+        assert_eq!(None, FrameHeader::parse(&header));
+    }
+
+    #[test]
+    fn stream_info_roundtrip() {
+        let mut stream_info = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0x0e, 0, 0x3c, 0x00];
+        // 44100 Hz, 2 channels, 16 bits, 1000 samples
+        let packed: u64 = (44100 << 44) | (1 << 41) | (15 << 36) | 1000;
+        stream_info.extend_from_slice(&packed.to_be_bytes());
+        stream_info.extend_from_slice(&[0; 16]);
+
+        let codec = FlacCodec::from_stream_info(&stream_info);
+
+        assert_eq!(
+            Some(StreamInfo {
+                min_block_size: 4096,
+                max_block_size: 4096,
+                min_frame_size: 14,
+                max_frame_size: 0x3c00,
+                sample_rate: 44100,
+                channels: 2,
+                bits_per_sample: 16,
+                total_samples: 1000,
+            }),
+            codec.stream_info()
+        );
+    }
+}
@@ -1 +1,592 @@
+//! H.264 codec private data.
 
+pub mod sei;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use h264_reader::rbsp::{decode_nal, BitRead, BitReader, BitReaderError};
+
+use crate::{Fraction, H264Codec, MediaInfo, MediaKind, Span, VideoCodec, VideoInfo};
+
+use super::nal::{get_codec_from_parameter_sets, BitWriter, BitstreamFraming};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AvcConfigError {
+    #[error("Unexpected end of AVCDecoderConfigurationRecord")]
+    UnexpectedEnd,
+
+    #[error("Unsupported AVCDecoderConfigurationRecord version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Unsupported NAL unit length size {0}")]
+    UnsupportedLengthSize(u8),
+
+    #[error("No {0} found in AVCDecoderConfigurationRecord")]
+    MissingParameterSet(&'static str),
+}
+
+/// An `AVCDecoderConfigurationRecord`, stored as `avcC` in MP4, as codec private data in Matroska
+/// and as the sequence header in FLV.
+///
+/// Parameter sets include their NAL unit header.
+#[derive(Debug, Clone)]
+pub struct AvcDecoderConfig {
+    pub profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    /// Size of the length prefix of each NAL unit in the samples, 2 or 4.
+    pub length_size: u8,
+    pub sps: Vec<Span>,
+    pub pps: Vec<Span>,
+}
+
+impl AvcDecoderConfig {
+    pub fn parse(data: Span) -> Result<Self, AvcConfigError> {
+        let bytes = data.to_bytes();
+        let mut reader = RecordReader {
+            data: &bytes,
+            pos: 0,
+        };
+
+        let header = reader.take(6)?;
+        if header[0] != 1 {
+            return Err(AvcConfigError::UnsupportedVersion(header[0]));
+        }
+
+        let length_size = (header[4] & 0b11) + 1;
+        if length_size != 2 && length_size != 4 {
+            return Err(AvcConfigError::UnsupportedLengthSize(length_size));
+        }
+
+        let sps = reader.parameter_sets((header[5] & 0b1_1111) as usize)?;
+        let pps_count = reader.take(1)?[0] as usize;
+        let pps = reader.parameter_sets(pps_count)?;
+
+        Ok(AvcDecoderConfig {
+            profile_indication: header[1],
+            profile_compatibility: header[2],
+            level_indication: header[3],
+            length_size,
+            sps,
+            pps,
+        })
+    }
+
+    pub fn to_span(&self) -> Span {
+        let mut buf = BytesMut::new();
+
+        buf.extend_from_slice(&[
+            1,
+            self.profile_indication,
+            self.profile_compatibility,
+            self.level_indication,
+            0b1111_1100 | (self.length_size - 1),
+            0b1110_0000 | self.sps.len() as u8,
+        ]);
+
+        for sps in &self.sps {
+            buf.put_u16(sps.len() as u16);
+            buf.extend_from_slice(&sps.to_slice());
+        }
+
+        buf.put_u8(self.pps.len() as u8);
+        for pps in &self.pps {
+            buf.put_u16(pps.len() as u16);
+            buf.extend_from_slice(&pps.to_slice());
+        }
+
+        buf.freeze().into()
+    }
+
+    pub fn bitstream_framing(&self) -> BitstreamFraming {
+        match self.length_size {
+            2 => BitstreamFraming::TwoByteLength,
+            _ => BitstreamFraming::FourByteLength,
+        }
+    }
+
+    /// Creates the [MediaInfo] for the stream described by the first SPS and PPS.
+    pub fn media_info(&self) -> anyhow::Result<MediaInfo> {
+        let sps = self
+            .sps
+            .first()
+            .ok_or(AvcConfigError::MissingParameterSet("SPS"))?;
+        let pps = self
+            .pps
+            .first()
+            .ok_or(AvcConfigError::MissingParameterSet("PPS"))?;
+
+        let mut info =
+            get_codec_from_parameter_sets(sps.clone(), pps.clone(), self.bitstream_framing())?;
+
+        if let MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(codec),
+            ..
+        }) = &mut info.kind
+        {
+            codec.profile_indication = self.profile_indication;
+            codec.profile_compatibility = self.profile_compatibility;
+            codec.level_indication = self.level_indication;
+        }
+
+        Ok(info)
+    }
+}
+
+struct RecordReader<'a> {
+    data: &'a Bytes,
+    pos: usize,
+}
+
+impl RecordReader<'_> {
+    fn take(&mut self, len: usize) -> Result<Bytes, AvcConfigError> {
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(AvcConfigError::UnexpectedEnd);
+        }
+
+        let bytes = self.data.slice(self.pos..end);
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    fn parameter_sets(&mut self, count: usize) -> Result<Vec<Span>, AvcConfigError> {
+        (0..count)
+            .map(|_| {
+                let len = self.take(2)?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+
+                Ok(self.take(len)?.into())
+            })
+            .collect()
+    }
+}
+
+impl From<&H264Codec> for AvcDecoderConfig {
+    /// Describes the codec with 4 byte NAL unit lengths, regardless of its current framing.
+    fn from(codec: &H264Codec) -> Self {
+        AvcDecoderConfig {
+            profile_indication: codec.profile_indication,
+            profile_compatibility: codec.profile_compatibility,
+            level_indication: codec.level_indication,
+            length_size: 4,
+            sps: vec![codec.sps.clone()],
+            pps: vec![codec.pps.clone()],
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpsRewriteError {
+    #[error("Invalid SPS: {0}")]
+    InvalidSps(String),
+}
+
+impl From<BitReaderError> for SpsRewriteError {
+    fn from(error: BitReaderError) -> Self {
+        SpsRewriteError::InvalidSps(format!("{error:?}"))
+    }
+}
+
+/// Changes to the fields of a sequence parameter set, made by [rewrite_sps]. Fields which are
+/// `None` are left as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpsRewrite {
+    pub level_idc: Option<u8>,
+    /// The width and height of a single pixel, e.g. 40:33 for 16:9 PAL at 720x576.
+    pub sample_aspect_ratio: Option<(u16, u16)>,
+    pub frame_rate: Option<Fraction>,
+}
+
+impl SpsRewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_level(mut self, level_idc: u8) -> Self {
+        self.level_idc = Some(level_idc);
+        self
+    }
+
+    pub fn with_sample_aspect_ratio(mut self, width: u16, height: u16) -> Self {
+        self.sample_aspect_ratio = Some((width, height));
+        self
+    }
+
+    pub fn with_frame_rate(mut self, frame_rate: Fraction) -> Self {
+        self.frame_rate = Some(frame_rate);
+        self
+    }
+}
+
+/// Copies the syntax elements of an RBSP while parsing it, so that some can be replaced.
+struct RbspCopier<'a> {
+    reader: BitReader<&'a [u8]>,
+    writer: BitWriter,
+}
+
+impl RbspCopier<'_> {
+    fn u(&mut self, bits: u32, name: &'static str) -> Result<u32, BitReaderError> {
+        let value = self.reader.read_u32(bits, name)?;
+        self.writer.u(bits, value);
+
+        Ok(value)
+    }
+
+    fn flag(&mut self, name: &'static str) -> Result<bool, BitReaderError> {
+        let value = self.reader.read_bool(name)?;
+        self.writer.flag(value);
+
+        Ok(value)
+    }
+
+    fn ue(&mut self, name: &'static str) -> Result<u32, BitReaderError> {
+        let value = self.reader.read_ue(name)?;
+        self.writer.ue(value);
+
+        Ok(value)
+    }
+
+    fn se(&mut self, name: &'static str) -> Result<i32, BitReaderError> {
+        let value = self.reader.read_se(name)?;
+        self.writer.se(value);
+
+        Ok(value)
+    }
+
+    fn hrd_parameters(&mut self) -> Result<(), BitReaderError> {
+        let cpb_cnt_minus1 = self.ue("cpb_cnt_minus1")?;
+        self.u(8, "bit_rate_scale, cpb_size_scale")?;
+
+        for _ in 0..=cpb_cnt_minus1 {
+            self.ue("bit_rate_value_minus1")?;
+            self.ue("cpb_size_value_minus1")?;
+            self.flag("cbr_flag")?;
+        }
+
+        self.u(20, "delay and time offset lengths")?;
+
+        Ok(())
+    }
+}
+
+/// Rewrites fields of a SPS, including its NAL unit header, without changing anything else about
+/// it. A VUI is added if the SPS has none and the aspect ratio or frame rate are set.
+pub fn rewrite_sps(sps: &[u8], rewrite: &SpsRewrite) -> Result<Vec<u8>, SpsRewriteError> {
+    let header = *sps
+        .first()
+        .ok_or_else(|| SpsRewriteError::InvalidSps("Empty NAL unit".to_string()))?;
+    let rbsp = decode_nal(sps).map_err(|e| SpsRewriteError::InvalidSps(e.to_string()))?;
+
+    let mut c = RbspCopier {
+        reader: BitReader::new(&rbsp[..]),
+        writer: BitWriter::default(),
+    };
+
+    let profile_idc = c.u(8, "profile_idc")?;
+    c.u(8, "constraint_flags")?;
+    let level_idc = c.reader.read_u8(8, "level_idc")?;
+    c.writer.u(8, rewrite.level_idc.unwrap_or(level_idc) as u32);
+    c.ue("seq_parameter_set_id")?;
+
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        let chroma_format_idc = c.ue("chroma_format_idc")?;
+        if chroma_format_idc == 3 {
+            c.flag("separate_colour_plane_flag")?;
+        }
+
+        c.ue("bit_depth_luma_minus8")?;
+        c.ue("bit_depth_chroma_minus8")?;
+        c.flag("qpprime_y_zero_transform_bypass_flag")?;
+
+        if c.flag("seq_scaling_matrix_present_flag")? {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if !c.flag("seq_scaling_list_present_flag")? {
+                    continue;
+                }
+
+                let (mut last_scale, mut next_scale) = (8, 8);
+                for _ in 0..if i < 6 { 16 } else { 64 } {
+                    if next_scale != 0 {
+                        next_scale = (last_scale + c.se("delta_scale")? + 256) % 256;
+                    }
+
+                    if next_scale != 0 {
+                        last_scale = next_scale;
+                    }
+                }
+            }
+        }
+    }
+
+    c.ue("log2_max_frame_num_minus4")?;
+    match c.ue("pic_order_cnt_type")? {
+        0 => {
+            c.ue("log2_max_pic_order_cnt_lsb_minus4")?;
+        }
+        1 => {
+            c.flag("delta_pic_order_always_zero_flag")?;
+            c.se("offset_for_non_ref_pic")?;
+            c.se("offset_for_top_to_bottom_field")?;
+            for _ in 0..c.ue("num_ref_frames_in_pic_order_cnt_cycle")? {
+                c.se("offset_for_ref_frame")?;
+            }
+        }
+        _ => {}
+    }
+
+    c.ue("max_num_ref_frames")?;
+    c.flag("gaps_in_frame_num_value_allowed_flag")?;
+    c.ue("pic_width_in_mbs_minus1")?;
+    c.ue("pic_height_in_map_units_minus1")?;
+    if !c.flag("frame_mbs_only_flag")? {
+        c.flag("mb_adaptive_frame_field_flag")?;
+    }
+    c.flag("direct_8x8_inference_flag")?;
+    if c.flag("frame_cropping_flag")? {
+        for name in ["left", "right", "top", "bottom"] {
+            c.ue(name)?;
+        }
+    }
+
+    let vui = c.reader.read_bool("vui_parameters_present_flag")?;
+    let add_vui = rewrite.sample_aspect_ratio.is_some() || rewrite.frame_rate.is_some();
+    c.writer.flag(vui || add_vui);
+    if vui || add_vui {
+        rewrite_vui(&mut c, rewrite, vui)?;
+    }
+
+    // the rest is rbsp_trailing_bits, which are written again
+    Ok(c.writer.finish(header))
+}
+
+/// Rewrites the VUI of a SPS, or writes a new one with only the rewritten fields if `present` is
+/// false.
+fn rewrite_vui(
+    c: &mut RbspCopier,
+    rewrite: &SpsRewrite,
+    present: bool,
+) -> Result<(), BitReaderError> {
+    let mut aspect_ratio = None;
+    if present && c.reader.read_bool("aspect_ratio_info_present_flag")? {
+        let idc = c.reader.read_u8(8, "aspect_ratio_idc")?;
+        aspect_ratio = Some(match idc {
+            // Extended_SAR
+            255 => (
+                idc,
+                c.reader.read_u16(16, "sar_width")?,
+                c.reader.read_u16(16, "sar_height")?,
+            ),
+            _ => (idc, 0, 0),
+        });
+    }
+
+    if let Some((width, height)) = rewrite.sample_aspect_ratio {
+        aspect_ratio = match (width, height) {
+            (1, 1) => Some((1, 0, 0)),
+            _ => Some((255, width, height)),
+        };
+    }
+
+    c.writer.flag(aspect_ratio.is_some());
+    if let Some((idc, width, height)) = aspect_ratio {
+        c.writer.u(8, idc as u32);
+        if idc == 255 {
+            c.writer.u(16, width as u32);
+            c.writer.u(16, height as u32);
+        }
+    }
+
+    if present {
+        if c.flag("overscan_info_present_flag")? {
+            c.flag("overscan_appropriate_flag")?;
+        }
+
+        if c.flag("video_signal_type_present_flag")? {
+            c.u(4, "video_format, video_full_range_flag")?;
+            if c.flag("colour_description_present_flag")? {
+                c.u(24, "colour_description")?;
+            }
+        }
+
+        if c.flag("chroma_loc_info_present_flag")? {
+            c.ue("chroma_sample_loc_type_top_field")?;
+            c.ue("chroma_sample_loc_type_bottom_field")?;
+        }
+    } else {
+        // overscan, video signal type and chroma location
+        c.writer.u(3, 0);
+    }
+
+    let mut timing = None;
+    if present && c.reader.read_bool("timing_info_present_flag")? {
+        timing = Some((
+            c.reader.read_u32(32, "num_units_in_tick")?,
+            c.reader.read_u32(32, "time_scale")?,
+            c.reader.read_bool("fixed_frame_rate_flag")?,
+        ));
+    }
+
+    if let Some(fps) = rewrite.frame_rate {
+        // a tick is a field, so two per frame
+        timing = Some((fps.denominator, fps.numerator * 2, true));
+    }
+
+    c.writer.flag(timing.is_some());
+    if let Some((num_units_in_tick, time_scale, fixed_frame_rate)) = timing {
+        c.writer.u(32, num_units_in_tick);
+        c.writer.u(32, time_scale);
+        c.writer.flag(fixed_frame_rate);
+    }
+
+    if present {
+        let nal_hrd = c.flag("nal_hrd_parameters_present_flag")?;
+        if nal_hrd {
+            c.hrd_parameters()?;
+        }
+
+        let vcl_hrd = c.flag("vcl_hrd_parameters_present_flag")?;
+        if vcl_hrd {
+            c.hrd_parameters()?;
+        }
+
+        if nal_hrd || vcl_hrd {
+            c.flag("low_delay_hrd_flag")?;
+        }
+
+        c.flag("pic_struct_present_flag")?;
+        if c.flag("bitstream_restriction_flag")? {
+            c.flag("motion_vectors_over_pic_boundaries_flag")?;
+            for name in [
+                "max_bytes_per_pic_denom",
+                "max_bits_per_mb_denom",
+                "log2_max_mv_length_horizontal",
+                "log2_max_mv_length_vertical",
+                "max_num_reorder_frames",
+                "max_dec_frame_buffering",
+            ] {
+                c.ue(name)?;
+            }
+        }
+    } else {
+        // HRD, pic_struct and bitstream restrictions
+        c.writer.u(4, 0);
+    }
+
+    Ok(())
+}
+
+/// Creates the [MediaInfo] of a H.264 track with its SPS rewritten.
+pub fn rewrite_media_info(info: &MediaInfo, rewrite: &SpsRewrite) -> anyhow::Result<MediaInfo> {
+    let MediaKind::Video(VideoInfo {
+        codec: VideoCodec::H264(codec),
+        ..
+    }) = &info.kind
+    else {
+        anyhow::bail!("Not a H.264 track");
+    };
+
+    let sps = rewrite_sps(&codec.sps.to_slice(), rewrite)?;
+
+    get_codec_from_parameter_sets(sps.into(), codec.pps.clone(), codec.bitstream_format)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::testsrc::sequence_parameter_set;
+    use assert_matches::assert_matches;
+    use h264_reader::nal::sps::{AspectRatioInfo, SeqParameterSet};
+    use test_case::test_case;
+
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x0a, 0xac, 0xd9, 0x41, 0x41, 0xfb, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00,
+        0x10, 0x00, 0x00, 0x03, 0x03, 0x20, 0xf1, 0x22, 0x59, 0x60,
+    ];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+
+    #[test]
+    fn parse_built_config() {
+        let config = AvcDecoderConfig {
+            profile_indication: 100,
+            profile_compatibility: 0,
+            level_indication: 10,
+            length_size: 4,
+            sps: vec![SPS.into()],
+            pps: vec![PPS.into()],
+        };
+
+        let data = config.to_span();
+        assert_eq!(&[1, 100, 0, 10, 0xff, 0xe1], &data.to_slice()[..6]);
+
+        let parsed = AvcDecoderConfig::parse(data.clone()).unwrap();
+        assert_eq!(SPS, &*parsed.sps[0].to_slice());
+        assert_eq!(PPS, &*parsed.pps[0].to_slice());
+
+        let info = parsed.media_info().unwrap();
+        let video = info.video().unwrap();
+        assert_eq!((320, 240), (video.width, video.height));
+
+        assert_matches!(
+            AvcDecoderConfig::parse(data.slice(..data.len() - 1)),
+            Err(AvcConfigError::UnexpectedEnd)
+        );
+    }
+
+    fn parse_sps(sps: &[u8]) -> SeqParameterSet {
+        SeqParameterSet::from_bits(BitReader::new(&*decode_nal(sps).unwrap())).unwrap()
+    }
+
+    #[test_case(SPS.to_vec() ; "high")]
+    #[test_case(sequence_parameter_set(100, 50, Fraction::new(25, 1)) ; "baseline with vui")]
+    fn rewrite_nothing(sps: Vec<u8>) {
+        assert_eq!(sps, rewrite_sps(&sps, &SpsRewrite::new()).unwrap());
+    }
+
+    #[test_case(SPS.to_vec() ; "high")]
+    #[test_case(sequence_parameter_set(100, 50, Fraction::new(25, 1)) ; "baseline with vui")]
+    fn rewrite_fields(sps: Vec<u8>) {
+        let rewrite = SpsRewrite::new()
+            .with_level(31)
+            .with_sample_aspect_ratio(4, 3)
+            .with_frame_rate(Fraction::new(30000, 1001));
+        let rewritten = parse_sps(&rewrite_sps(&sps, &rewrite).unwrap());
+        let original = parse_sps(&sps);
+
+        assert_eq!(31, rewritten.level_idc);
+        assert_eq!(
+            original.pixel_dimensions().unwrap(),
+            rewritten.pixel_dimensions().unwrap()
+        );
+
+        let vui = rewritten.vui_parameters.unwrap();
+        assert_matches!(vui.aspect_ratio_info, Some(AspectRatioInfo::Extended(4, 3)));
+
+        let timing = vui.timing_info.as_ref().unwrap();
+        assert_eq!((1001, 60000), (timing.num_units_in_tick, timing.time_scale));
+
+        // fields which weren't rewritten are kept
+        let restrictions = |vui: Option<&_>| {
+            vui.and_then(|vui: &h264_reader::nal::sps::VuiParameters| {
+                vui.bitstream_restrictions.as_ref()
+            })
+            .map(|r| r.max_num_reorder_frames)
+        };
+        assert_eq!(
+            restrictions(original.vui_parameters.as_ref()),
+            restrictions(Some(&vui))
+        );
+    }
+
+    #[test]
+    fn reject_truncated_sps() {
+        assert_matches!(
+            rewrite_sps(&SPS[..8], &SpsRewrite::new()),
+            Err(SpsRewriteError::InvalidSps(_))
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Supplemental enhancement information (SEI) messages of H.264 access units.
+
+use h264_reader::rbsp::{decode_nal, BitRead, BitReader};
+
+/// `payload_type` of a buffering period message.
+pub const BUFFERING_PERIOD: u32 = 0;
+
+/// `payload_type` of a picture timing message.
+pub const PIC_TIMING: u32 = 1;
+
+/// `payload_type` of user data registered by ITU-T T.35, e.g. closed captions.
+pub const USER_DATA_REGISTERED: u32 = 4;
+
+/// `payload_type` of user data identified by a UUID, e.g. the x264 settings.
+pub const USER_DATA_UNREGISTERED: u32 = 5;
+
+/// `payload_type` of a recovery point message.
+pub const RECOVERY_POINT: u32 = 6;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeiError {
+    #[error("Invalid SEI NAL unit: {0}")]
+    InvalidNal(#[from] std::io::Error),
+
+    #[error("SEI message ends after {0} bytes")]
+    Truncated(usize),
+}
+
+/// A single message of an SEI NAL unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeiMessage {
+    pub payload_type: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Parses the messages of an SEI NAL unit, including its NAL unit header.
+pub fn parse_sei(nal: &[u8]) -> Result<Vec<SeiMessage>, SeiError> {
+    let rbsp = decode_nal(nal)?;
+
+    let mut messages = Vec::new();
+    let mut pos = 0;
+
+    // rbsp_trailing_bits is a single set bit followed by zeros
+    while rbsp.get(pos).is_some_and(|&b| b != 0x80) {
+        let payload_type = read_ff_coded(&rbsp, &mut pos)?;
+        let payload_size = read_ff_coded(&rbsp, &mut pos)? as usize;
+
+        let payload = rbsp
+            .get(pos..pos + payload_size)
+            .ok_or(SeiError::Truncated(rbsp.len()))?;
+        pos += payload_size;
+
+        messages.push(SeiMessage {
+            payload_type,
+            payload: payload.to_vec(),
+        });
+    }
+
+    Ok(messages)
+}
+
+/// Reads a payload type or size, which is a sum of bytes where every byte but the last is 0xff.
+fn read_ff_coded(rbsp: &[u8], pos: &mut usize) -> Result<u32, SeiError> {
+    let mut value = 0u32;
+
+    loop {
+        let byte = *rbsp.get(*pos).ok_or(SeiError::Truncated(rbsp.len()))?;
+        *pos += 1;
+        value = value.saturating_add(byte as u32);
+
+        if byte != 0xff {
+            return Ok(value);
+        }
+    }
+}
+
+/// A recovery point message, which marks where decoding can start in a stream without IDR
+/// pictures, e.g. one using periodic intra refresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPoint {
+    /// The number of frames until the pictures are correct.
+    pub recovery_frame_cnt: u32,
+    /// Whether the pictures from the recovery point match those decoded from the start exactly.
+    pub exact_match: bool,
+    /// Whether the pictures around the recovery point may be corrupt because of an edit.
+    pub broken_link: bool,
+}
+
+impl RecoveryPoint {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let mut reader = BitReader::new(payload);
+
+        Some(RecoveryPoint {
+            recovery_frame_cnt: reader.read_ue("recovery_frame_cnt").ok()?,
+            exact_match: reader.read_bool("exact_match_flag").ok()?,
+            broken_link: reader.read_bool("broken_link_flag").ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn message(payload_type: u32, payload: &[u8]) -> SeiMessage {
+        SeiMessage {
+            payload_type,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test_case(&[0x06, 0x06, 0x01, 0xc4, 0x80], vec![message(6, &[0xc4])] ; "recovery point")]
+    #[test_case(
+        &[0x06, 0x05, 0x02, 0xaa, 0xbb, 0xff, 0x2d, 0x00, 0x80],
+        vec![message(5, &[0xaa, 0xbb]), message(300, &[])] ;
+        "multiple messages"
+    )]
+    #[test_case(
+        &[0x06, 0x04, 0x03, 0x00, 0x00, 0x03, 0x01, 0x80],
+        vec![message(4, &[0x00, 0x00, 0x01])] ;
+        "emulation prevention"
+    )]
+    fn parse_messages(nal: &[u8], expected: Vec<SeiMessage>) {
+        assert_eq!(expected, parse_sei(nal).unwrap());
+    }
+
+    #[test_case(&[0x06, 0x06, 0x04, 0xc4, 0x80])]
+    #[test_case(&[0x06, 0xff])]
+    fn reject_truncated(nal: &[u8]) {
+        assert!(matches!(parse_sei(nal), Err(SeiError::Truncated(_))));
+    }
+
+    #[test]
+    fn parse_recovery_point() {
+        // recovery_frame_cnt 2, broken_link_flag
+        let expected = RecoveryPoint {
+            recovery_frame_cnt: 2,
+            exact_match: false,
+            broken_link: true,
+        };
+
+        assert_eq!(Some(expected), RecoveryPoint::parse(&[0b0110_1000]));
+    }
+}
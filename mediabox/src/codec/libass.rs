@@ -0,0 +1,387 @@
+//! Renders ASS subtitles to bitmaps with [libass](https://github.com/libass/libass), e.g. to burn
+//! them into video frames.
+
+use std::{
+    collections::VecDeque,
+    ffi::{c_char, c_int, c_longlong, CString},
+    ptr,
+};
+
+use crate::{format::Attachment, Fraction, MediaInfo, MediaTime, MediaboxError, Packet};
+
+use super::*;
+
+#[repr(C)]
+struct AssLibrary {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct AssRenderer {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct AssTrack {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct AssImage {
+    w: c_int,
+    h: c_int,
+    stride: c_int,
+    bitmap: *const u8,
+    /// The color as `0xRRGGBBAA`, where the alpha is the transparency.
+    color: u32,
+    dst_x: c_int,
+    dst_y: c_int,
+    next: *const AssImage,
+    kind: c_int,
+}
+
+const ASS_FONTPROVIDER_AUTODETECT: c_int = 1;
+
+// linked by the build script, which finds libass with pkg-config
+extern "C" {
+    fn ass_library_init() -> *mut AssLibrary;
+    fn ass_library_done(library: *mut AssLibrary);
+    fn ass_add_font(library: *mut AssLibrary, name: *const c_char, data: *const c_char, size: c_int);
+
+    fn ass_renderer_init(library: *mut AssLibrary) -> *mut AssRenderer;
+    fn ass_renderer_done(renderer: *mut AssRenderer);
+    fn ass_set_frame_size(renderer: *mut AssRenderer, width: c_int, height: c_int);
+    fn ass_set_fonts(
+        renderer: *mut AssRenderer,
+        default_font: *const c_char,
+        default_family: *const c_char,
+        font_provider: c_int,
+        config: *const c_char,
+        update: c_int,
+    );
+    fn ass_render_frame(
+        renderer: *mut AssRenderer,
+        track: *mut AssTrack,
+        now: c_longlong,
+        detect_change: *mut c_int,
+    ) -> *const AssImage;
+
+    fn ass_new_track(library: *mut AssLibrary) -> *mut AssTrack;
+    fn ass_free_track(track: *mut AssTrack);
+    fn ass_process_codec_private(track: *mut AssTrack, data: *const c_char, size: c_int);
+    fn ass_process_chunk(
+        track: *mut AssTrack,
+        data: *const c_char,
+        size: c_int,
+        timecode: c_longlong,
+        duration: c_longlong,
+    );
+}
+
+/// libass works with timestamps in milliseconds.
+const TIMEBASE: Fraction = Fraction {
+    numerator: 1,
+    denominator: 1000,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LibassError {
+    #[error("Failed to initialize libass")]
+    Init,
+
+    #[error("Only ASS subtitles can be rendered")]
+    NotAss,
+
+    #[error("Renderer not started")]
+    NotStarted,
+}
+
+impl From<LibassError> for MediaboxError {
+    fn from(error: LibassError) -> Self {
+        match error {
+            LibassError::NotAss => MediaboxError::unsupported(error.to_string()),
+            LibassError::Init | LibassError::NotStarted => MediaboxError::Other(error.into()),
+        }
+    }
+}
+
+/// Renders the packets of an ASS track to a [SubtitleBitmap] per event, using the fonts of the
+/// system and those added with [SubtitleRenderer::add_font].
+///
+/// Events can overlap, so the bitmap of an event also contains the other events shown at its
+/// start. Use [SubtitleRenderer::render] to render the subtitles at the time of a video frame
+/// instead.
+pub struct SubtitleRenderer {
+    library: *mut AssLibrary,
+    renderer: *mut AssRenderer,
+    track: *mut AssTrack,
+    width: u32,
+    height: u32,
+    started: bool,
+    pending: VecDeque<MediaTime>,
+}
+
+// libass objects can be used from any thread, as long as it is one at a time
+unsafe impl Send for SubtitleRenderer {}
+unsafe impl Sync for SubtitleRenderer {}
+
+impl SubtitleRenderer {
+    /// Creates a renderer for video frames of the given size.
+    pub fn new(width: u32, height: u32) -> crate::Result<Self> {
+        unsafe {
+            let library = ass_library_init();
+            if library.is_null() {
+                return Err(LibassError::Init.into());
+            }
+
+            let renderer = ass_renderer_init(library);
+            let track = ass_new_track(library);
+            let renderer = SubtitleRenderer {
+                library,
+                renderer,
+                track,
+                width,
+                height,
+                started: false,
+                pending: VecDeque::new(),
+            };
+            if renderer.renderer.is_null() || renderer.track.is_null() {
+                return Err(LibassError::Init.into());
+            }
+
+            ass_set_frame_size(renderer.renderer, width as c_int, height as c_int);
+
+            Ok(renderer)
+        }
+    }
+
+    /// Adds the font attachments of a movie, see [SubtitleRenderer::add_font].
+    pub fn with_attachments(mut self, attachments: &[Attachment]) -> Self {
+        for attachment in attachments.iter().filter(|a| is_font(a)) {
+            self.add_font(&attachment.name, &attachment.data.to_slice());
+        }
+
+        self
+    }
+
+    /// Makes a font available to the subtitles, e.g. one embedded in a Matroska file. Fonts have
+    /// to be added before the renderer is started.
+    pub fn add_font(&mut self, name: &str, data: &[u8]) {
+        let Ok(name) = CString::new(name) else {
+            return;
+        };
+
+        unsafe {
+            ass_add_font(
+                self.library,
+                name.as_ptr(),
+                data.as_ptr().cast(),
+                data.len() as c_int,
+            );
+        }
+    }
+
+    /// Renders the subtitles shown at the given time, e.g. the timestamp of a video frame.
+    pub fn render(&mut self, time: &MediaTime) -> crate::Result<SubtitleBitmap> {
+        if !self.started {
+            return Err(LibassError::NotStarted.into());
+        }
+
+        let now = time.in_base(TIMEBASE).pts;
+        let mut images = Vec::new();
+
+        unsafe {
+            let mut image = ass_render_frame(
+                self.renderer,
+                self.track,
+                now as c_longlong,
+                ptr::null_mut(),
+            );
+
+            while let Some(ass) = image.as_ref() {
+                images.push(overlay_image(ass));
+                image = ass.next;
+            }
+        }
+
+        Ok(SubtitleBitmap {
+            time: time.clone(),
+            width: self.width,
+            height: self.height,
+            images,
+        })
+    }
+}
+
+impl Decoder for SubtitleRenderer {
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()> {
+        let Some(SubtitleInfo {
+            codec: SubtitleCodec::Ass(AssCodec { header }),
+        }) = info.subtitle()
+        else {
+            return Err(LibassError::NotAss.into());
+        };
+
+        unsafe {
+            if self.started {
+                // a track only takes one header, so restarting needs a new track
+                if !self.track.is_null() {
+                    ass_free_track(self.track);
+                }
+                self.track = ass_new_track(self.library);
+                self.pending.clear();
+            } else {
+                // scans the system fonts, so this is only done once
+                ass_set_fonts(
+                    self.renderer,
+                    ptr::null(),
+                    c"sans-serif".as_ptr(),
+                    ASS_FONTPROVIDER_AUTODETECT,
+                    ptr::null(),
+                    1,
+                );
+            }
+
+            if self.track.is_null() {
+                return Err(LibassError::Init.into());
+            }
+
+            ass_process_codec_private(self.track, header.as_ptr().cast(), header.len() as c_int);
+        }
+        self.started = true;
+
+        Ok(())
+    }
+
+    fn feed(&mut self, packet: Packet) -> crate::Result<()> {
+        if !self.started {
+            return Err(LibassError::NotStarted.into());
+        }
+
+        let time = packet.time.in_base(TIMEBASE);
+        let data = packet.buffer.to_slice();
+
+        unsafe {
+            ass_process_chunk(
+                self.track,
+                data.as_ptr().cast(),
+                data.len() as c_int,
+                time.pts as c_longlong,
+                time.duration.unwrap_or(0) as c_longlong,
+            );
+        }
+        self.pending.push_back(packet.time);
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Decoded> {
+        let time = self.pending.pop_front()?;
+
+        self.render(&time).ok().map(Decoded::SubtitleBitmap)
+    }
+}
+
+impl Drop for SubtitleRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.track.is_null() {
+                ass_free_track(self.track);
+            }
+            if !self.renderer.is_null() {
+                ass_renderer_done(self.renderer);
+            }
+            ass_library_done(self.library);
+        }
+    }
+}
+
+fn is_font(attachment: &Attachment) -> bool {
+    let name = attachment.name.to_ascii_lowercase();
+
+    attachment.mime.starts_with("font/")
+        || attachment.mime.contains("truetype")
+        || attachment.mime.contains("opentype")
+        || [".ttf", ".otf", ".ttc"].iter().any(|ext| name.ends_with(ext))
+}
+
+/// Colors the alpha mask of a libass image.
+///
+/// # Safety
+///
+/// The bitmap of the image has to be valid for `stride * h` bytes.
+unsafe fn overlay_image(image: &AssImage) -> OverlayImage {
+    let [r, g, b, transparency] = image.color.to_be_bytes();
+    let opacity = 255 - transparency as u32;
+
+    let (width, height) = (image.w.max(0) as usize, image.h.max(0) as usize);
+    let mut data = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = std::slice::from_raw_parts(image.bitmap.add(y * image.stride as usize), width);
+        for &coverage in row {
+            let alpha = (coverage as u32 * opacity + 127) / 255;
+            data.extend_from_slice(&[r, g, b, alpha as u8]);
+        }
+    }
+
+    OverlayImage {
+        x: image.dst_x,
+        y: image.dst_y,
+        width: width as u32,
+        height: height as u32,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test, MediaKind};
+
+    const HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 64
+PlayResY: 64
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, Alignment
+Style: Default,sans-serif,20,&H00FFFFFF,7
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
+
+    #[test]
+    fn render_drawing() {
+        let track = test::track(
+            1,
+            TIMEBASE,
+            "ass",
+            MediaKind::Subtitle(SubtitleInfo {
+                codec: SubtitleCodec::Ass(AssCodec {
+                    header: HEADER.into(),
+                }),
+            }),
+        );
+
+        let mut renderer = SubtitleRenderer::new(64, 64).unwrap();
+        renderer.start(&track.info).unwrap();
+        renderer.start(&track.info).unwrap();
+
+        // a vector drawing, which renders without any fonts installed
+        let event = b"0,0,Default,,0,0,0,,{\\p1}m 0 0 l 32 0 32 32 0 32{\\p0}";
+        let packet = test::packet(&track, 1000, Some(1000), event.to_vec());
+        let later = MediaTime {
+            pts: 3000,
+            ..packet.time.clone()
+        };
+        renderer.feed(packet).unwrap();
+
+        let Some(Decoded::SubtitleBitmap(bitmap)) = renderer.receive() else {
+            panic!("Expected a bitmap");
+        };
+        assert_eq!((64, 64), (bitmap.width, bitmap.height));
+        assert!(!bitmap.images.is_empty());
+        assert!(renderer.render(&later).unwrap().images.is_empty());
+    }
+}
@@ -1,15 +1,9 @@
-use h264_reader::{
-    annexb::AnnexBReader,
-    avcc::AvcDecoderConfigurationRecord,
-    nal::{Nal, NalHeader, RefNal, UnitType},
-    push::NalInterest,
-};
+use h264_reader::nal::{NalHeader, UnitType};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{BufMut, Bytes};
+use memchr::memmem;
 
-use std::io::Read;
-
-use crate::{H264Codec, MediaInfo, MediaKind, Span, VideoCodec, VideoInfo};
+use crate::{H264Codec, MediaInfo, MediaKind, Span, SpanBuilder, VideoCodec, VideoInfo};
 
 /// Describes how H.264 and H.265 NAL units are framed.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -35,117 +29,171 @@ impl BitstreamFraming {
             BitstreamFraming::FourByteStartCode
         )
     }
+
+    /// The size of the length prefix of each NAL unit, or [None] for start codes.
+    fn length_size(&self) -> Option<usize> {
+        match self {
+            BitstreamFraming::FourByteLength => Some(4),
+            BitstreamFraming::TwoByteLength => Some(2),
+            BitstreamFraming::FourByteStartCode => None,
+        }
+    }
 }
 
 const THREE_BYTE_STARTCODE: [u8; 3] = [0, 0, 1];
 const FOUR_BYTE_STARTCODE: [u8; 4] = [0, 0, 0, 1];
 
-/// Parses a H.26x bitstream framed in AVC format (length prefix) into NAL units.
-fn parse_bitstream_length_field<const N: usize, F: Fn([u8; N]) -> usize>(
-    bitstream: Span,
-    read: F,
-) -> Vec<Span> {
-    let mut nal_units = Vec::new();
-
-    let mut i = 0;
-    let len = bitstream.len();
-    while i < len - N {
-        let len_bytes = bitstream.slice(i..(i + N));
-        let len_bytes = len_bytes.to_slice();
-        let len_bytes = <[u8; N]>::try_from(&len_bytes[..]).unwrap();
-        let nal_unit_len = read(len_bytes); // BigEndian::read_u32(&nal_units[i..]) as usize;
-
-        i += N;
-
-        let nal_unit = bitstream.slice(i..(i + nal_unit_len));
-        nal_units.push(nal_unit);
-
-        i += nal_unit_len;
+fn byte_at(bitstream: &Span, mut i: usize) -> Option<u8> {
+    for chunk in bitstream.spans() {
+        match chunk.get(i) {
+            Some(&b) => return Some(b),
+            None => i -= chunk.len(),
+        }
     }
 
-    nal_units.into_iter().collect()
+    None
 }
 
-/// Parses a H.26x bitstream framed in Annex B format (start codes) into NAL units.
-fn parse_bitstream_start_codes(bitstream: Span) -> Vec<Span> {
-    let mut nal_units = Vec::new();
-    let mut current_nal = Vec::new();
-
-    let mut acc = AnnexBReader::accumulate(|nal: RefNal| {
-        if nal.is_complete() {
-            let mut complete_nal_unit = Vec::new();
-            nal.reader().read_to_end(&mut current_nal).unwrap();
-            std::mem::swap(&mut current_nal, &mut complete_nal_unit);
-            nal_units.push(complete_nal_unit.into());
+/// Finds the first 3 byte start code at or after `from`, including those spanning chunks.
+fn find_start_code(bitstream: &Span, from: usize) -> Option<usize> {
+    // the last 2 bytes before the current chunk
+    let mut tail = [0u8; 2];
+    let mut tail_len = 0;
+    let mut position = 0;
+
+    for chunk in bitstream.spans() {
+        let end = position + chunk.len();
+
+        if end > from {
+            if tail_len > 0 {
+                let mut window = [0u8; 4];
+                window[..tail_len].copy_from_slice(&tail[2 - tail_len..]);
+                let head = chunk.len().min(2);
+                window[tail_len..tail_len + head].copy_from_slice(&chunk[..head]);
+
+                let found = memmem::find_iter(&window[..tail_len + head], &THREE_BYTE_STARTCODE)
+                    .take_while(|&i| i < tail_len)
+                    .map(|i| position - tail_len + i)
+                    .find(|&offset| offset >= from);
+                if found.is_some() {
+                    return found;
+                }
+            }
+
+            let skip = from.saturating_sub(position);
+            if let Some(i) = memmem::find(&chunk[skip..], &THREE_BYTE_STARTCODE) {
+                return Some(position + skip + i);
+            }
         }
 
-        NalInterest::Buffer
-    });
-
-    for slice in bitstream.spans() {
-        acc.push(slice);
+        for &b in &chunk[chunk.len().saturating_sub(2)..] {
+            tail = [tail[1], b];
+            tail_len = (tail_len + 1).min(2);
+        }
+        position = end;
     }
-    acc.reset();
 
-    nal_units
+    None
 }
 
-/// Parses a H.26x bitstream in a given [BitstreamFraming] into NAL units.
-pub fn parse_bitstream(bitstream: Span, source: BitstreamFraming) -> Vec<Span> {
-    match source {
-        BitstreamFraming::TwoByteLength => {
-            parse_bitstream_length_field::<2, _>(bitstream, |b| u16::from_be_bytes(b) as usize)
-        }
-        BitstreamFraming::FourByteLength => {
-            parse_bitstream_length_field::<4, _>(bitstream, |b| u32::from_be_bytes(b) as usize)
+/// An iterator over the NAL units of a H.26x bitstream, which refer to the data of the bitstream
+/// rather than copying it. Truncated NAL units at the end of a length prefixed bitstream are
+/// skipped.
+pub struct NalUnits<'a> {
+    bitstream: &'a Span,
+    framing: BitstreamFraming,
+    /// The start of the next length prefix, or the next start code.
+    position: Option<usize>,
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = Span;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.bitstream.len();
+
+        match self.framing.length_size() {
+            Some(size) => {
+                let start = self.position? + size;
+                let nal_len = (start - size..start).try_fold(0usize, |acc, i| {
+                    byte_at(self.bitstream, i).map(|b| acc << 8 | b as usize)
+                });
+
+                match nal_len.map(|nal_len| start + nal_len) {
+                    Some(end) if end <= len => {
+                        self.position = Some(end);
+                        Some(self.bitstream.slice(start..end))
+                    }
+                    _ => {
+                        self.position = None;
+                        None
+                    }
+                }
+            }
+            None => loop {
+                let start = self.position? + THREE_BYTE_STARTCODE.len();
+                self.position = find_start_code(self.bitstream, start);
+                let mut end = self.position.unwrap_or(len);
+
+                // the zero byte of a 4 byte start code and any trailing_zero_8bits
+                while end > start && byte_at(self.bitstream, end - 1) == Some(0) {
+                    end -= 1;
+                }
+
+                if end > start {
+                    return Some(self.bitstream.slice(start..end));
+                }
+            },
         }
-        BitstreamFraming::FourByteStartCode => parse_bitstream_start_codes(bitstream),
     }
 }
 
-/// Frames NAL units with a given start code before each NAL.
-fn frame_nal_units_with_start_codes(nal_units: &[Span], codes: &'static [u8]) -> Span {
-    let mut spans = Vec::new();
+/// Iterates over the NAL units of a H.26x bitstream in a given [BitstreamFraming].
+pub fn nal_units(bitstream: &Span, source: BitstreamFraming) -> NalUnits<'_> {
+    let position = match source.length_size() {
+        Some(_) => Some(0),
+        None => find_start_code(bitstream, 0),
+    };
 
-    for nal in nal_units {
-        spans.push(Span::from(Bytes::from_static(codes)));
-        spans.push(nal.clone());
+    NalUnits {
+        bitstream,
+        framing: source,
+        position,
     }
-
-    spans.into_iter().collect()
 }
 
-/// Frames NAL units with a length prefix before each NAL.
-fn frame_nal_units_with_length<const N: usize, F: Fn(usize) -> [u8; N]>(
-    nal_units: &[Span],
-    func: F,
-) -> Span {
-    let mut spans = Vec::new();
+/// Parses a H.26x bitstream in a given [BitstreamFraming] into NAL units.
+pub fn parse_bitstream(bitstream: Span, source: BitstreamFraming) -> Vec<Span> {
+    nal_units(&bitstream, source).collect()
+}
 
+/// Writes NAL units framed with the specified [BitstreamFraming] to a [SpanBuilder].
+///
+/// The NAL units are assumed to have no prefix.
+pub fn frame_nal_units_into(
+    nal_units: impl IntoIterator<Item = Span>,
+    target: BitstreamFraming,
+    buf: &mut SpanBuilder,
+) {
     for nal in nal_units {
-        let len_bytes = func(nal.len());
-        spans.push(Span::from(len_bytes.to_vec()));
-        spans.push(nal.clone());
-    }
+        match target {
+            BitstreamFraming::TwoByteLength => buf.put_u16(nal.len() as u16),
+            BitstreamFraming::FourByteLength => buf.put_u32(nal.len() as u32),
+            BitstreamFraming::FourByteStartCode => buf.put_slice(&FOUR_BYTE_STARTCODE),
+        }
 
-    spans.into_iter().collect()
+        buf.put_span(nal);
+    }
 }
 
 /// Frame the given NAL units with the specified [BitstreamFraming].
 ///
 /// The NAL units are assumed to have no prefix.
 pub fn frame_nal_units(nal_units: &[Span], target: BitstreamFraming) -> Span {
-    match target {
-        BitstreamFraming::TwoByteLength => {
-            frame_nal_units_with_length(nal_units, |len| (len as u16).to_be_bytes())
-        }
-        BitstreamFraming::FourByteLength => {
-            frame_nal_units_with_length(nal_units, |len| (len as u32).to_be_bytes())
-        }
-        BitstreamFraming::FourByteStartCode => {
-            frame_nal_units_with_start_codes(nal_units, &FOUR_BYTE_STARTCODE[..])
-        }
-    }
+    let mut buf = SpanBuilder::new();
+    frame_nal_units_into(nal_units.iter().cloned(), target, &mut buf);
+
+    buf.build()
 }
 
 /// Converts a H.26x bitstream from a source [BitstreamFraming] to a
@@ -159,10 +207,10 @@ pub fn convert_bitstream(
         return bitstream;
     }
 
-    let nal_units = parse_bitstream(bitstream, source);
-    dbg!(&nal_units);
+    let mut buf = SpanBuilder::new();
+    frame_nal_units_into(nal_units(&bitstream, source), target, &mut buf);
 
-    frame_nal_units(&nal_units[..], target)
+    buf.build()
 }
 
 pub fn is_video_nal_unit(nal: &Bytes) -> bool {
@@ -179,48 +227,115 @@ pub fn nut_header(nal: &Bytes) -> Option<UnitType> {
     NalHeader::new(nal[0]).map(|h| h.nal_unit_type()).ok()
 }
 
-pub fn get_codec_from_mp4(
-    decoder_config: &AvcDecoderConfigurationRecord,
-) -> anyhow::Result<MediaInfo> {
-    let sps_bytes_no_header = decoder_config
-        .sequence_parameter_sets()
-        .next()
-        .ok_or(anyhow::anyhow!("No SPS found"))
-        .unwrap()
-        .unwrap();
-    let pps_bytes_no_header = decoder_config
-        .picture_parameter_sets()
-        .next()
-        .ok_or(anyhow::anyhow!("No PPS found"))
-        .unwrap()
-        .unwrap();
-
-    let mut sps_bytes = BytesMut::new();
-    sps_bytes.put_u8(UnitType::SeqParameterSet.id());
-    sps_bytes.extend_from_slice(&sps_bytes_no_header);
-    let sps_bytes = sps_bytes.freeze().into();
-
-    let mut pps_bytes = BytesMut::new();
-    pps_bytes.put_u8(UnitType::PicParameterSet.id());
-    pps_bytes.extend_from_slice(&pps_bytes_no_header);
-    let pps_bytes = pps_bytes.freeze().into();
+/// Writes the syntax elements of a NAL unit.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    data: Vec<u8>,
+    current: u8,
+    bits: u32,
+}
 
+impl BitWriter {
+    pub(crate) fn u(&mut self, bits: u32, value: u32) {
+        for i in (0..bits).rev() {
+            self.current = (self.current << 1) | ((value >> i) & 1) as u8;
+            self.bits += 1;
+
+            if self.bits == 8 {
+                self.data.push(self.current);
+                self.current = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    pub(crate) fn flag(&mut self, value: bool) {
+        self.u(1, value as u32);
+    }
+
+    /// Writes an unsigned Exp-Golomb code.
+    pub(crate) fn ue(&mut self, value: u32) {
+        let value = value as u64 + 1;
+        let bits = 64 - value.leading_zeros();
+
+        self.u(bits - 1, 0);
+        self.u(bits, value as u32);
+    }
+
+    /// Writes a signed Exp-Golomb code.
+    pub(crate) fn se(&mut self, value: i32) {
+        let mapped = if value > 0 {
+            value as u32 * 2 - 1
+        } else {
+            value.unsigned_abs() * 2
+        };
+
+        self.ue(mapped);
+    }
+
+    pub(crate) fn align(&mut self) {
+        while self.bits != 0 {
+            self.u(1, 0);
+        }
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(0, self.bits);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Ends the RBSP and returns it as a NAL unit with emulation prevention bytes.
+    pub(crate) fn finish(mut self, header: u8) -> Vec<u8> {
+        self.u(1, 1);
+        self.align();
+
+        let mut nal = Vec::with_capacity(self.data.len() + self.data.len() / 64 + 1);
+        nal.push(header);
+
+        let mut zeros = 0;
+        for byte in self.data {
+            if zeros == 2 && byte <= 3 {
+                nal.push(3);
+                zeros = 0;
+            }
+
+            nal.push(byte);
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+        }
+
+        nal
+    }
+}
+
+/// Creates the [MediaInfo] for a H.264 stream from its parameter sets.
+///
+/// The SPS and PPS must include their NAL unit header.
+pub fn get_codec_from_parameter_sets(
+    sps: Span,
+    pps: Span,
+    bitstream_format: BitstreamFraming,
+) -> anyhow::Result<MediaInfo> {
     use h264_reader::{
         nal::sps::SeqParameterSet,
         rbsp::{decode_nal, BitReader},
     };
-    let nal = decode_nal(&sps_bytes_no_header[..]).unwrap();
+
+    let sps_slice = sps.to_slice();
+    let nal = decode_nal(&sps_slice)?;
     let reader = BitReader::new(nal.as_ref());
-    let sps = SeqParameterSet::from_bits(reader).map_err(|e| anyhow::anyhow!("{:?}", e))?;
-    let (width, height) = sps.pixel_dimensions().unwrap();
+    let parsed_sps =
+        SeqParameterSet::from_bits(reader).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let (width, height) = parsed_sps
+        .pixel_dimensions()
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
     let codec = H264Codec {
-        bitstream_format: BitstreamFraming::FourByteLength,
-        profile_indication: decoder_config.avc_profile_indication().into(),
-        profile_compatibility: decoder_config.profile_compatibility().into(),
-        level_indication: decoder_config.avc_level_indication().level_idc(),
-        sps: sps_bytes,
-        pps: pps_bytes,
+        bitstream_format,
+        profile_indication: parsed_sps.profile_idc.into(),
+        profile_compatibility: parsed_sps.constraint_flags.into(),
+        level_indication: parsed_sps.level_idc,
+        sps: sps.clone(),
+        pps,
     };
 
     Ok(MediaInfo {
@@ -290,4 +405,31 @@ mod test {
 
         assert_eq!(expected, converted_bitstream.to_bytes());
     }
+
+    #[test_case(&[&[0, 0, 0, 1, 5, 6, 0, 0, 1, 1, 2, 0, 0]] ; "single chunk")]
+    #[test_case(&[&[0, 0], &[0, 1, 5, 6, 0], &[0], &[1, 1, 2], &[0, 0]] ; "start codes spanning chunks")]
+    #[test_case(&[&[9, 0, 0, 0, 1, 5], &[6, 0, 0, 1], &[1, 2, 0, 0]] ; "leading data")]
+    #[test_case(&[&[0, 0, 1], &[5, 6, 0, 0], &[1], &[1, 2]] ; "three byte start codes")]
+    fn parse_start_codes(chunks: &[&'static [u8]]) {
+        let bitstream = chunks.iter().map(|&c| Span::from(c)).collect::<Span>();
+        let nal_units = parse_bitstream(bitstream, FourByteStartCode)
+            .iter()
+            .map(|nal| nal.to_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![vec![5, 6], vec![1, 2]], nal_units);
+    }
+
+    #[test_case(&[&len(2), &[5, 6], &len(1)], &[&[5, 6]] ; "truncated length")]
+    #[test_case(&[&len(2), &[5, 6], &len(3), &[1, 2]], &[&[5, 6]] ; "truncated nal unit")]
+    #[test_case(&[&[0, 0]], &[] ; "short")]
+    fn parse_truncated_lengths(bitstream: &[&[u8]], expected: &[&[u8]]) {
+        let bitstream = Span::from(bitstream.concat());
+        let nal_units = parse_bitstream(bitstream, FourByteLength)
+            .iter()
+            .map(|nal| nal.to_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        assert_eq!(expected, nal_units);
+    }
 }
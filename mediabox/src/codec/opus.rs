@@ -0,0 +1,147 @@
+use bytes::{Buf, BufMut};
+
+/// Opus is always decoded at 48 kHz, regardless of the input sample rate.
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+
+const OPUS_HEAD_MAGIC: &[u8; 8] = b"OpusHead";
+
+/// The identification header of an Opus stream, as described in [RFC 7845].
+///
+/// [RFC 7845]: https://www.rfc-editor.org/rfc/rfc7845#section-5.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusHead {
+    pub channels: u8,
+    /// The number of 48 kHz samples to discard from the start of the decoded output.
+    pub pre_skip: u16,
+    pub input_sample_rate: u32,
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    /// The stream count, coupled count and channel mapping, only present when
+    /// `mapping_family` is not 0.
+    pub mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    pub fn parse(mut data: &[u8]) -> Option<Self> {
+        if data.len() < 19 || !data.starts_with(OPUS_HEAD_MAGIC) {
+            return None;
+        }
+
+        data.advance(OPUS_HEAD_MAGIC.len());
+
+        // only the major version is guaranteed to be compatible
+        if data.get_u8() >> 4 != 0 {
+            return None;
+        }
+
+        let channels = data.get_u8();
+        let pre_skip = data.get_u16_le();
+        let input_sample_rate = data.get_u32_le();
+        let output_gain = data.get_i16_le();
+        let mapping_family = data.get_u8();
+
+        let mapping = if mapping_family != 0 {
+            let len = 2 + channels as usize;
+            data.get(..len)?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        Some(OpusHead {
+            channels,
+            pre_skip,
+            input_sample_rate,
+            output_gain,
+            mapping_family,
+            mapping,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(19 + self.mapping.len());
+
+        buf.put_slice(OPUS_HEAD_MAGIC);
+        buf.put_u8(1); // version
+        buf.put_u8(self.channels);
+        buf.put_u16_le(self.pre_skip);
+        buf.put_u32_le(self.input_sample_rate);
+        buf.put_i16_le(self.output_gain);
+        buf.put_u8(self.mapping_family);
+        buf.put_slice(&self.mapping);
+
+        buf
+    }
+
+    /// Writes the contents of an MP4 `dOps` box, which stores the same fields as big endian.
+    pub fn write_dops(&self, buf: &mut impl BufMut) {
+        buf.put_u8(0); // version
+        buf.put_u8(self.channels);
+        buf.put_u16(self.pre_skip);
+        buf.put_u32(self.input_sample_rate);
+        buf.put_i16(self.output_gain);
+        buf.put_u8(self.mapping_family);
+        buf.put_slice(&self.mapping);
+    }
+}
+
+/// The number of 48 kHz samples in an Opus packet, from the TOC byte and frame count described
+/// in [RFC 6716].
+///
+/// [RFC 6716]: https://www.rfc-editor.org/rfc/rfc6716#section-3.1
+pub fn packet_duration(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+
+    // in units of 2.5 ms, i.e. 120 samples
+    let frame_size = match config {
+        0..=11 => [4, 8, 16, 24][config as usize % 4],
+        12..=15 => [4, 8][config as usize % 2],
+        _ => [1, 2, 4, 8][config as usize % 4],
+    };
+
+    let frames = match toc & 0b11 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => *packet.get(1)? as u32 & 0x3f,
+    };
+
+    Some(frame_size * frames * 120)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(&[0xfc], 960 ; "20 ms CELT")]
+    #[test_case(&[0x01], 960 ; "two 10 ms SILK frames")]
+    #[test_case(&[0x73, 0x03], 1440 ; "three 10 ms hybrid frames")]
+    fn duration_of_packet(packet: &[u8], expected: u32) {
+        assert_eq!(Some(expected), packet_duration(packet));
+    }
+
+    #[test]
+    fn opus_head_roundtrip() {
+        let data = [
+            b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', // magic
+            0x01, 0x02, // version, channels
+            0x38, 0x01, // pre_skip = 312
+            0x80, 0xbb, 0x00, 0x00, // input_sample_rate = 48000
+            0x00, 0x00, 0x00, // output_gain, mapping_family
+        ];
+
+        let head = OpusHead::parse(&data).unwrap();
+        assert_eq!(
+            (2, 312, 48000),
+            (head.channels, head.pre_skip, head.input_sample_rate)
+        );
+        assert_eq!(&data[..], &head.to_bytes()[..]);
+
+        let mut dops = Vec::new();
+        head.write_dops(&mut dops);
+        assert_eq!(
+            &[0x00, 0x02, 0x01, 0x38, 0x00, 0x00, 0xbb, 0x80, 0x00, 0x00, 0x00],
+            &dops[..]
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{decoder, AudioCodec, MediaInfo, Packet, PcmCodec, SampleFormat};
+
+use super::*;
+
+decoder!("pcm", PcmDecoder::create);
+
+/// Converts packets of uncompressed audio into floating point [AudioFrame]s.
+pub struct PcmDecoder {
+    info: Option<(u32, u16, SampleFormat)>,
+    frames: OutputQueue<AudioFrame>,
+}
+
+impl PcmDecoder {
+    pub fn new() -> Self {
+        PcmDecoder {
+            info: None,
+            frames: OutputQueue::new(),
+        }
+    }
+
+    fn create() -> Box<dyn Decoder> {
+        Box::new(Self::new())
+    }
+}
+
+impl Default for PcmDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for PcmDecoder {
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()> {
+        let audio = info
+            .audio()
+            .ok_or_else(|| anyhow::anyhow!("Expected audio track"))?;
+        let AudioCodec::Pcm(PcmCodec { format }) = audio.codec else {
+            return Err(MediaboxError::unsupported("Expected PCM audio"));
+        };
+
+        self.info = Some((audio.sample_rate, audio.sound_type.channel_count(), format));
+
+        Ok(())
+    }
+
+    fn feed(&mut self, pkt: Packet) -> crate::Result<()> {
+        self.frames.reserve()?;
+
+        let (sample_rate, channels, format) = self
+            .info
+            .ok_or_else(|| anyhow::anyhow!("Decoder not started"))?;
+
+        let data = pkt.buffer.to_slice();
+        let frame_size = format.bytes_per_sample() * channels as usize;
+        if !data.len().is_multiple_of(frame_size) {
+            return Err(MediaboxError::invalid_data(anyhow::anyhow!(
+                "{} B is not a whole number of {channels} channel {format:?} samples",
+                data.len()
+            )));
+        }
+
+        let samples = data
+            .chunks_exact(format.bytes_per_sample())
+            .map(|sample| format.to_f32(sample))
+            .collect();
+
+        self.frames.push(AudioFrame {
+            time: pkt.time,
+            sample_rate,
+            channels,
+            samples,
+        });
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Decoded> {
+        self.frames.pop().map(Decoded::Audio)
+    }
+
+    fn is_full(&self) -> bool {
+        self.frames.is_full()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(SampleFormat::U8, &[0x80], 0.0)]
+    #[test_case(SampleFormat::S16Le, &[0x00, 0xc0], -0.5)]
+    #[test_case(SampleFormat::S16Be, &[0x40, 0x00], 0.5)]
+    #[test_case(SampleFormat::S24Le, &[0x00, 0x00, 0x80], -1.0)]
+    #[test_case(SampleFormat::F32Le, &0.25f32.to_le_bytes(), 0.25)]
+    #[test_case(SampleFormat::F64Be, &(-0.75f64).to_be_bytes(), -0.75)]
+    fn convert_sample(format: SampleFormat, data: &[u8], expected: f32) {
+        assert_eq!(expected, format.to_f32(data));
+    }
+
+    #[test]
+    fn full_queue() {
+        use crate::{AudioInfo, Fraction, MediaKind, MediaTime, SoundType, Track};
+        use std::sync::Arc;
+
+        let info = Arc::new(MediaInfo {
+            name: "pcm",
+            kind: MediaKind::Audio(AudioInfo {
+                sample_rate: 8000,
+                sample_bpp: 8,
+                sound_type: SoundType::Mono,
+                codec: AudioCodec::Pcm(PcmCodec {
+                    format: SampleFormat::U8,
+                }),
+            }),
+        });
+        let packet = Packet {
+            time: MediaTime {
+                pts: 0,
+                dts: None,
+                duration: Some(1),
+                timebase: Fraction::new(1, 8000),
+            },
+            key: true,
+            track: Track {
+                id: 0,
+                info: info.clone(),
+                timebase: Fraction::new(1, 8000),
+                delay: 0,
+                metadata: Default::default(),
+            },
+            buffer: vec![0x80].into(),
+            side_data: Vec::new(),
+        };
+
+        let mut decoder = PcmDecoder::new();
+        decoder.start(&info).unwrap();
+        for _ in 0..DEFAULT_QUEUE_CAPACITY {
+            decoder.feed(packet.clone()).unwrap();
+        }
+        assert!(decoder.is_full());
+        assert!(matches!(
+            decoder.feed(packet.clone()),
+            Err(MediaboxError::Full)
+        ));
+
+        assert!(decoder.receive().is_some());
+        assert!(!decoder.is_full());
+        decoder.feed(packet).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! AV1 encoding with [rav1e](https://github.com/xiph/rav1e).
+
+use std::{collections::HashMap, collections::VecDeque, sync::Arc};
+
+use ::rav1e::prelude::{
+    ChromaSampling, Config, Context, EncoderConfig, EncoderStatus, FrameType, Rational,
+    SpeedSettings,
+};
+
+use crate::{
+    encoder, Av1Codec, MediaInfo, MediaKind, MediaTime, Packet, Track, VideoCodec, VideoInfo,
+};
+
+use super::*;
+
+encoder!("av1", Rav1eEncoder::create);
+
+/// The rav1e speed preset, 0 is the slowest and 10 the fastest.
+const SPEED_PRESET: u8 = 10;
+
+/// Encodes [PixelFormat::Yuv420p] frames to AV1.
+pub struct Rav1eEncoder {
+    context: Option<Context<u8>>,
+    track: Option<Track>,
+    /// Maps the input frame numbers reported by rav1e back to frame timestamps.
+    timestamps: HashMap<u64, u64>,
+    frame_count: u64,
+    packets: VecDeque<Packet>,
+}
+
+impl Rav1eEncoder {
+    pub fn new() -> Self {
+        Rav1eEncoder {
+            context: None,
+            track: None,
+            timestamps: HashMap::new(),
+            frame_count: 0,
+            packets: VecDeque::new(),
+        }
+    }
+
+    fn create() -> Box<dyn Encoder> {
+        Box::new(Self::new())
+    }
+
+    fn receive_packets(&mut self) -> anyhow::Result<()> {
+        let (Some(context), Some(track)) = (&mut self.context, &self.track) else {
+            anyhow::bail!("Encoder not started");
+        };
+
+        loop {
+            let packet = match context.receive_packet() {
+                Ok(packet) => packet,
+                Err(EncoderStatus::Encoded) => continue,
+                Err(EncoderStatus::NeedMoreData | EncoderStatus::LimitReached) => break,
+                Err(e) => anyhow::bail!("Failed to encode AV1: {e}"),
+            };
+
+            let pts = self
+                .timestamps
+                .remove(&packet.input_frameno)
+                .unwrap_or_default();
+
+            self.packets.push_back(Packet {
+                time: MediaTime {
+                    pts,
+                    dts: None,
+                    duration: None,
+                    timebase: track.timebase,
+                },
+                key: packet.frame_type == FrameType::KEY,
+                track: track.clone(),
+                buffer: packet.data.into(),
+                side_data: Vec::new(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Rav1eEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder for Rav1eEncoder {
+    fn start(&mut self, desc: CodecDescription) -> crate::Result<Track> {
+        let desc = desc
+            .into_video()
+            .ok_or_else(|| anyhow::anyhow!("Expected video description"))?;
+
+        if desc.format != PixelFormat::Yuv420p {
+            return Err(MediaboxError::unsupported(format!(
+                "Unsupported pixel format {:?}",
+                desc.format
+            )));
+        }
+
+        let config = EncoderConfig {
+            width: desc.width as usize,
+            height: desc.height as usize,
+            time_base: Rational::new(
+                desc.timebase.numerator as u64,
+                desc.timebase.denominator as u64,
+            ),
+            bit_depth: 8,
+            chroma_sampling: ChromaSampling::Cs420,
+            bitrate: desc.bitrate.unwrap_or_default() as i32,
+            speed_settings: SpeedSettings::from_preset(SPEED_PRESET),
+            ..Default::default()
+        };
+
+        let context: Context<u8> = Config::new()
+            .with_encoder_config(config)
+            .new_context()
+            .map_err(|e| anyhow::anyhow!("Invalid AV1 encoder configuration: {e}"))?;
+
+        let track = Track {
+            id: 0,
+            info: Arc::new(MediaInfo {
+                name: "av1",
+                kind: MediaKind::Video(VideoInfo {
+                    width: desc.width,
+                    height: desc.height,
+                    codec: VideoCodec::Av1(Av1Codec {
+                        config: context.container_sequence_header().into(),
+                    }),
+                }),
+            }),
+            timebase: desc.timebase,
+            delay: self.delay(),
+            metadata: Default::default(),
+        };
+
+        self.context = Some(context);
+        self.track = Some(track.clone());
+
+        Ok(track)
+    }
+
+    fn feed(&mut self, raw: Decoded) -> crate::Result<()> {
+        let input = raw
+            .into_video()
+            .ok_or_else(|| anyhow::anyhow!("Expected video frame"))?;
+        let context = self
+            .context
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Encoder not started"))?;
+
+        if input.format != PixelFormat::Yuv420p {
+            return Err(MediaboxError::unsupported(format!(
+                "Unsupported pixel format {:?}",
+                input.format
+            )));
+        }
+
+        let mut frame = context.new_frame();
+        for (plane, input) in frame.planes.iter_mut().zip(&input.planes) {
+            plane.copy_from_raw_u8(&input.data.to_slice(), input.stride, 1);
+        }
+
+        self.timestamps.insert(self.frame_count, input.time.pts);
+        self.frame_count += 1;
+
+        context
+            .send_frame(frame)
+            .map_err(|e| anyhow::anyhow!("Failed to send frame to AV1 encoder: {e}"))?;
+
+        Ok(self.receive_packets()?)
+    }
+
+    fn flush(&mut self) -> crate::Result<()> {
+        self.context
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Encoder not started"))?
+            .flush();
+
+        Ok(self.receive_packets()?)
+    }
+
+    fn receive(&mut self) -> Option<Packet> {
+        self.packets.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Fraction, Span};
+
+    #[test]
+    fn encode_gray_frames() {
+        let timebase = Fraction::new(1, 25);
+        let mut encoder = Rav1eEncoder::new();
+        let track = encoder
+            .start(CodecDescription::Video(VideoDescription {
+                width: 64,
+                height: 64,
+                format: PixelFormat::Yuv420p,
+                timebase,
+                bitrate: None,
+            }))
+            .unwrap();
+
+        let Some(VideoCodec::Av1(Av1Codec { config })) = track.info.video().map(|v| &v.codec)
+        else {
+            panic!("Expected AV1 track");
+        };
+        assert_eq!(0x81, config.to_slice()[0]);
+
+        for pts in 0..3 {
+            let plane = |size: usize| VideoPlane {
+                data: Span::from(vec![128u8; size * size]),
+                stride: size,
+            };
+
+            encoder
+                .feed(Decoded::Video(VideoFrame {
+                    time: MediaTime {
+                        pts,
+                        dts: None,
+                        duration: None,
+                        timebase,
+                    },
+                    width: 64,
+                    height: 64,
+                    format: PixelFormat::Yuv420p,
+                    planes: vec![plane(64), plane(32), plane(32)],
+                }))
+                .unwrap();
+        }
+        encoder.flush().unwrap();
+
+        let packets = std::iter::from_fn(|| encoder.receive()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![0, 1, 2],
+            packets.iter().map(|p| p.time.pts).collect::<Vec<_>>()
+        );
+        assert!(packets[0].key);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{decoder, MediaInfo, Packet, RawVideoCodec, VideoCodec};
+
+use super::*;
+
+decoder!("rawvideo", RawVideoDecoder::create);
+
+/// Splits packets of uncompressed video into the planes of a [VideoFrame] without copying.
+pub struct RawVideoDecoder {
+    info: Option<(u32, u32, PixelFormat)>,
+    frames: OutputQueue<VideoFrame>,
+}
+
+impl RawVideoDecoder {
+    pub fn new() -> Self {
+        RawVideoDecoder {
+            info: None,
+            frames: OutputQueue::new(),
+        }
+    }
+
+    fn create() -> Box<dyn Decoder> {
+        Box::new(Self::new())
+    }
+}
+
+impl Default for RawVideoDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for RawVideoDecoder {
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()> {
+        let video = info
+            .video()
+            .ok_or_else(|| anyhow::anyhow!("Expected video track"))?;
+        let VideoCodec::Raw(RawVideoCodec { format }) = video.codec else {
+            return Err(MediaboxError::unsupported("Expected raw video"));
+        };
+
+        self.info = Some((video.width, video.height, format));
+
+        Ok(())
+    }
+
+    fn feed(&mut self, pkt: Packet) -> crate::Result<()> {
+        self.frames.reserve()?;
+
+        let (width, height, format) = self
+            .info
+            .ok_or_else(|| anyhow::anyhow!("Decoder not started"))?;
+
+        let expected = format.frame_size(width, height);
+        if pkt.buffer.len() != expected {
+            return Err(MediaboxError::invalid_data(anyhow::anyhow!(
+                "Expected {expected} B for a {width}x{height} {format:?} frame, got {} B",
+                pkt.buffer.len()
+            )));
+        }
+
+        let mut offset = 0;
+        let planes = format
+            .plane_sizes(width, height)
+            .into_iter()
+            .map(|(stride, rows)| {
+                let data = pkt.buffer.slice(offset..offset + stride * rows);
+                offset += stride * rows;
+
+                VideoPlane { data, stride }
+            })
+            .collect();
+
+        self.frames.push(VideoFrame {
+            time: pkt.time,
+            width,
+            height,
+            format,
+            planes,
+        });
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Decoded> {
+        self.frames.pop().map(Decoded::Video)
+    }
+
+    fn is_full(&self) -> bool {
+        self.frames.is_full()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Fraction, MediaKind, Track, VideoInfo};
+    use std::sync::Arc;
+
+    #[test]
+    fn split_yuv420p_planes() {
+        let info = MediaInfo {
+            name: "rawvideo",
+            kind: MediaKind::Video(VideoInfo {
+                width: 4,
+                height: 2,
+                codec: VideoCodec::Raw(RawVideoCodec {
+                    format: PixelFormat::Yuv420p,
+                }),
+            }),
+        };
+        let track = Track {
+            id: 0,
+            info: Arc::new(info.clone()),
+            timebase: Fraction::new(1, 25),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        let mut decoder = RawVideoDecoder::new();
+        decoder.start(&info).unwrap();
+        decoder
+            .feed(Packet {
+                time: MediaTime {
+                    pts: 3,
+                    dts: None,
+                    duration: Some(1),
+                    timebase: track.timebase,
+                },
+                key: true,
+                track,
+                buffer: (0..12).collect::<Vec<u8>>().into(),
+                side_data: Vec::new(),
+            })
+            .unwrap();
+
+        let frame = decoder.receive().and_then(Decoded::into_video).unwrap();
+        let planes = frame
+            .planes
+            .iter()
+            .map(|p| (p.stride, p.data.to_slice().to_vec()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(3, frame.time.pts);
+        assert_eq!(
+            vec![
+                (4, vec![0, 1, 2, 3, 4, 5, 6, 7]),
+                (2, vec![8, 9]),
+                (2, vec![10, 11]),
+            ],
+            planes
+        );
+    }
+}
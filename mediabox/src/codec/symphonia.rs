@@ -0,0 +1,154 @@
+//! Audio decoders backed by [symphonia](https://github.com/pdeljanov/Symphonia).
+
+use symphonia_core::{
+    audio::SampleBuffer,
+    codecs::{CodecParameters, Decoder as _, DecoderOptions, CODEC_TYPE_AAC},
+    formats,
+};
+
+use crate::{decoder, AudioCodec, MediaInfo, MediaboxError, Packet};
+
+use super::{aac::AAC_FRAME_SAMPLES, AudioFrame, Decoded, Decoder, OutputQueue};
+
+decoder!("aac", AacDecoder::create);
+
+/// Decodes AAC-LC to PCM.
+pub struct AacDecoder {
+    decoder: Option<symphonia_codec_aac::AacDecoder>,
+    frames: OutputQueue<AudioFrame>,
+}
+
+impl AacDecoder {
+    pub fn new() -> Self {
+        AacDecoder {
+            decoder: None,
+            frames: OutputQueue::new(),
+        }
+    }
+
+    fn create() -> Box<dyn Decoder> {
+        Box::new(Self::new())
+    }
+}
+
+impl Default for AacDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for AacDecoder {
+    fn start(&mut self, info: &MediaInfo) -> crate::Result<()> {
+        let audio = info
+            .audio()
+            .ok_or_else(|| anyhow::anyhow!("Expected audio track"))?;
+        let AudioCodec::Aac(codec) = &audio.codec else {
+            return Err(MediaboxError::unsupported("Expected AAC audio"));
+        };
+
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_AAC)
+            .with_sample_rate(audio.sample_rate)
+            .with_extra_data(codec.extra.clone().into_boxed_slice());
+
+        let decoder = symphonia_codec_aac::AacDecoder::try_new(&params, &DecoderOptions::default())
+            .map_err(|e| anyhow::anyhow!("Failed to create AAC decoder: {e}"))?;
+        self.decoder = Some(decoder);
+
+        Ok(())
+    }
+
+    fn feed(&mut self, pkt: Packet) -> crate::Result<()> {
+        self.frames.reserve()?;
+
+        let decoder = self
+            .decoder
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Decoder not started"))?;
+
+        let data = pkt.buffer.to_slice();
+        let duration = pkt.time.duration.unwrap_or(AAC_FRAME_SAMPLES);
+        let packet = formats::Packet::new_from_slice(0, pkt.time.pts, duration, &data);
+
+        let decoded = decoder
+            .decode(&packet)
+            .map_err(MediaboxError::invalid_data)?;
+        let spec = *decoded.spec();
+
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+
+        self.frames.push(AudioFrame {
+            time: pkt.time,
+            sample_rate: spec.rate,
+            channels: spec.channels.count() as u16,
+            samples: samples.samples().to_vec(),
+        });
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Decoded> {
+        self.frames.pop().map(Decoded::Audio)
+    }
+
+    fn is_full(&self) -> bool {
+        self.frames.is_full()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AacCodec, AudioInfo, Fraction, MediaKind, MediaTime, SoundType, Track};
+    use std::sync::Arc;
+
+    #[test]
+    fn decode_silent_frame() {
+        // AAC-LC, 44.1 kHz stereo
+        let info = MediaInfo {
+            name: "aac",
+            kind: MediaKind::Audio(AudioInfo {
+                sample_rate: 44100,
+                sample_bpp: 16,
+                sound_type: SoundType::Stereo,
+                codec: AudioCodec::Aac(AacCodec {
+                    extra: vec![0x12, 0x10],
+                }),
+            }),
+        };
+        let track = Track {
+            id: 0,
+            info: Arc::new(info.clone()),
+            timebase: Fraction::new(1, 44100),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        let mut decoder = AacDecoder::new();
+        decoder.start(&info).unwrap();
+        decoder
+            .feed(Packet {
+                time: MediaTime {
+                    pts: 1024,
+                    dts: None,
+                    duration: Some(1024),
+                    timebase: track.timebase,
+                },
+                key: true,
+                track,
+                buffer: vec![0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00, 0x23, 0x80].into(),
+                side_data: Vec::new(),
+            })
+            .unwrap();
+
+        let frame = decoder.receive().and_then(Decoded::into_audio).unwrap();
+        assert_eq!(
+            (44100, 2, 1024),
+            (frame.sample_rate, frame.channels, frame.sample_count())
+        );
+        assert_eq!(1024, frame.time.pts);
+        assert!(frame.samples.iter().all(|s| s.abs() < 1e-3));
+    }
+}
@@ -0,0 +1,219 @@
+use crate::VorbisCodec;
+
+const IDENTIFICATION_MAGIC: &[u8; 7] = b"\x01vorbis";
+const SETUP_MAGIC: &[u8; 7] = b"\x05vorbis";
+
+/// The identification header of a Vorbis stream, as described in the [Vorbis I specification].
+///
+/// [Vorbis I specification]: https://xiph.org/vorbis/doc/Vorbis_I_spec.html#x1-630004.2.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VorbisIdHeader {
+    pub channels: u8,
+    pub sample_rate: u32,
+    /// The short and long block sizes in samples.
+    pub blocksizes: [u32; 2],
+}
+
+impl VorbisIdHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 30 || !data.starts_with(IDENTIFICATION_MAGIC) {
+            return None;
+        }
+
+        let channels = data[11];
+        let sample_rate = u32::from_le_bytes(data[12..16].try_into().ok()?);
+        let blocksizes = [1 << (data[28] & 0x0f), 1 << (data[28] >> 4)];
+
+        Some(VorbisIdHeader {
+            channels,
+            sample_rate,
+            blocksizes,
+        })
+    }
+}
+
+/// Finds the number of samples in Vorbis packets without decoding them, which depends on the
+/// block size of the packet and the one before it.
+///
+/// The block size of each mode is at the very end of the setup header, but the setup header can
+/// only be parsed front to back by decoding the codebooks. Like FFmpeg, the modes are instead
+/// found by reading backwards from the framing bit until the mode count matches.
+pub struct VorbisParser {
+    blocksizes: [u32; 2],
+    /// Whether each mode uses the long block size.
+    mode_blockflags: Vec<bool>,
+    mode_mask: u8,
+    previous_blocksize: Option<u32>,
+}
+
+impl VorbisParser {
+    pub fn new(codec: &VorbisCodec) -> Option<Self> {
+        let id = VorbisIdHeader::parse(&codec.headers[0])?;
+        let setup = &codec.headers[2];
+        if !setup.starts_with(SETUP_MAGIC) {
+            return None;
+        }
+
+        let mut bits = ReverseBits {
+            data: setup,
+            pos: 0,
+        };
+
+        // the last bit set is the framing bit
+        loop {
+            if bits.remaining() <= 97 {
+                return None;
+            }
+            if bits.read(1) == 1 {
+                break;
+            }
+        }
+        let modes_end = bits.pos;
+
+        // each mode is a block flag, two 16 bit zero fields and an 8 bit mapping, preceded by
+        // the 6 bit mode count
+        let mut mode_count = 0;
+        let mut last_mode_count = 0;
+        while bits.remaining() >= 97 && mode_count < 64 {
+            if bits.read(8) > 63 || bits.read(16) != 0 || bits.read(16) != 0 {
+                break;
+            }
+            bits.read(1);
+            mode_count += 1;
+
+            let pos = bits.pos;
+            if bits.read(6) as usize + 1 == mode_count {
+                last_mode_count = mode_count;
+            }
+            bits.pos = pos;
+        }
+
+        if last_mode_count == 0 {
+            return None;
+        }
+
+        bits.pos = modes_end;
+        let mut mode_blockflags = vec![false; last_mode_count];
+        for flag in mode_blockflags.iter_mut().rev() {
+            bits.read(40);
+            *flag = bits.read(1) == 1;
+        }
+
+        // the mode number follows the packet type bit
+        let mode_bits = usize::BITS - (last_mode_count - 1).leading_zeros();
+        let mode_mask = (((1u32 << mode_bits) - 1) << 1) as u8;
+
+        Some(VorbisParser {
+            blocksizes: id.blocksizes,
+            mode_blockflags,
+            mode_mask,
+            previous_blocksize: None,
+        })
+    }
+
+    /// The number of samples decoded from an audio packet, which is 0 for the first packet.
+    pub fn packet_duration(&mut self, packet: &[u8]) -> Option<u32> {
+        let first = *packet.first()?;
+
+        // header packets have odd packet types
+        if first & 1 != 0 {
+            return None;
+        }
+
+        let mode = ((first & self.mode_mask) >> 1) as usize;
+        let long = *self.mode_blockflags.get(mode)?;
+        let blocksize = self.blocksizes[long as usize];
+
+        let duration = self
+            .previous_blocksize
+            .map_or(0, |previous| (previous + blocksize) / 4);
+        self.previous_blocksize = Some(blocksize);
+
+        Some(duration)
+    }
+}
+
+/// Reads the bits of a Vorbis header backwards, starting with the last bit written.
+struct ReverseBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ReverseBits<'_> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    fn read(&mut self, count: usize) -> u32 {
+        (0..count).fold(0, |value, _| {
+            let byte = self.data[self.data.len() - 1 - self.pos / 8];
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            self.pos += 1;
+
+            (value << 1) | bit as u32
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Packs bits the way Vorbis does, starting with the least significant bit of each byte.
+    fn pack_bits(fields: &[(u32, usize)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut pos = 0;
+
+        for &(value, count) in fields {
+            for i in 0..count {
+                if pos % 8 == 0 {
+                    data.push(0);
+                }
+                *data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (pos % 8);
+                pos += 1;
+            }
+        }
+
+        data
+    }
+
+    #[test]
+    fn duration_from_block_sizes() {
+        let mut id = IDENTIFICATION_MAGIC.to_vec();
+        id.extend_from_slice(&[0, 0, 0, 0, 2, 0x44, 0xac, 0, 0]);
+        id.extend_from_slice(&[0; 12]);
+        id.extend_from_slice(&[0xb8, 0x01]); // 256 and 2048 samples, framing bit
+
+        // stand-in for the codebooks, floors, residues and mappings
+        let mut fields = vec![(0x5a, 8); 16];
+        fields.push((1, 6)); // two modes
+        for blockflag in [0, 1] {
+            fields.extend_from_slice(&[(blockflag, 1), (0, 16), (0, 16), (0, 8)]);
+        }
+        fields.push((1, 1)); // framing bit
+
+        let mut setup = SETUP_MAGIC.to_vec();
+        setup.extend(pack_bits(&fields));
+
+        let codec = VorbisCodec {
+            headers: [id, Vec::new(), setup],
+        };
+        assert_eq!(
+            Some(VorbisIdHeader {
+                channels: 2,
+                sample_rate: 44100,
+                blocksizes: [256, 2048],
+            }),
+            VorbisIdHeader::parse(&codec.headers[0])
+        );
+
+        let mut parser = VorbisParser::new(&codec).unwrap();
+        let durations = [0x02, 0x02, 0x00, 0x02]
+            .iter()
+            .map(|&first| parser.packet_duration(&[first]).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![0, 1024, 576, 576], durations);
+        assert_eq!(None, parser.packet_duration(&[0x03]));
+    }
+}
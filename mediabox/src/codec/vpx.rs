@@ -0,0 +1,122 @@
+use bytes::BufMut;
+
+use crate::VpxCodec;
+
+/// Feature IDs of the VP9 `CodecPrivate` in Matroska.
+const FEATURE_PROFILE: u8 = 1;
+const FEATURE_LEVEL: u8 = 2;
+const FEATURE_BIT_DEPTH: u8 = 3;
+const FEATURE_CHROMA_SUBSAMPLING: u8 = 4;
+
+/// Whether a VP8 frame is a keyframe, from the frame tag.
+pub fn vp8_is_keyframe(frame: &[u8]) -> Option<bool> {
+    Some(frame.first()? & 1 == 0)
+}
+
+/// Whether a VP9 frame is a keyframe, from the start of the uncompressed header. Frames which
+/// only show an earlier frame are never keyframes.
+pub fn vp9_is_keyframe(frame: &[u8]) -> Option<bool> {
+    let byte = *frame.first()?;
+    if byte >> 6 != 0b10 {
+        return None;
+    }
+
+    let profile = ((byte >> 4) & 1) << 1 | ((byte >> 5) & 1);
+    // profile 3 has an extra reserved bit
+    let byte = if profile == 3 { byte << 1 } else { byte };
+
+    let show_existing_frame = byte & 0b1000 != 0;
+    let frame_type = byte & 0b0100 != 0;
+
+    Some(!show_existing_frame && !frame_type)
+}
+
+impl VpxCodec {
+    /// Parses the optional VP9 `CodecPrivate` from Matroska, which is a list of features.
+    pub fn parse_codec_private(mut data: &[u8]) -> Self {
+        let mut codec = VpxCodec::default();
+
+        while let [id, len, rest @ ..] = data {
+            let Some((value, rest)) = rest.split_at_checked(*len as usize) else {
+                break;
+            };
+
+            if let [value] = value {
+                match *id {
+                    FEATURE_PROFILE => codec.profile = *value,
+                    FEATURE_LEVEL => codec.level = *value,
+                    FEATURE_BIT_DEPTH => codec.bit_depth = *value,
+                    FEATURE_CHROMA_SUBSAMPLING => codec.chroma_subsampling = *value,
+                    _ => {}
+                }
+            }
+
+            data = rest;
+        }
+
+        codec
+    }
+
+    pub fn to_codec_private(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        for (id, value) in [
+            (FEATURE_PROFILE, self.profile),
+            (FEATURE_LEVEL, self.level),
+            (FEATURE_BIT_DEPTH, self.bit_depth),
+            (FEATURE_CHROMA_SUBSAMPLING, self.chroma_subsampling),
+        ] {
+            buf.put_slice(&[id, 1, value]);
+        }
+
+        buf
+    }
+
+    /// Writes the contents of an MP4 `vpcC` box.
+    pub fn write_vpcc(&self, buf: &mut impl BufMut) {
+        buf.put_u32(1 << 24); // version + flags
+        buf.put_u8(self.profile);
+        buf.put_u8(self.level);
+        buf.put_u8((self.bit_depth << 4) | (self.chroma_subsampling << 1) | self.full_range as u8);
+        buf.put_u8(self.colour_primaries);
+        buf.put_u8(self.transfer_characteristics);
+        buf.put_u8(self.matrix_coefficients);
+        buf.put_u16(0); // codecInitializationDataSize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(&[0x82, 0x49, 0x83], Some(true) ; "profile 0 keyframe")]
+    #[test_case(&[0x86, 0x00], Some(false) ; "profile 0 inter frame")]
+    #[test_case(&[0x88], Some(false) ; "show existing frame")]
+    #[test_case(&[0xb1, 0x24], Some(true) ; "profile 3 keyframe")]
+    #[test_case(&[0x12], None ; "invalid frame marker")]
+    fn detect_vp9_keyframe(frame: &[u8], expected: Option<bool>) {
+        assert_eq!(expected, vp9_is_keyframe(frame));
+    }
+
+    #[test]
+    fn codec_private_roundtrip() {
+        let codec = VpxCodec {
+            profile: 2,
+            level: 31,
+            bit_depth: 10,
+            ..Default::default()
+        };
+
+        let parsed = VpxCodec::parse_codec_private(&codec.to_codec_private());
+        assert_eq!(
+            (2, 31, 10, 1),
+            (
+                parsed.profile,
+                parsed.level,
+                parsed.bit_depth,
+                parsed.chroma_subsampling
+            )
+        );
+    }
+}
@@ -1,6 +1,6 @@
-use std::{collections::VecDeque, io::Write, sync::Arc};
+use std::{io::Write, sync::Arc};
 
-use crate::{encoder, Fraction, MediaInfo, MediaKind, Track};
+use crate::{encoder, time::ClockTime, Fraction, MediaInfo, MediaKind, Track};
 
 use super::*;
 
@@ -10,16 +10,18 @@ encoder!("webvtt", WebVttEncoder::create);
 
 pub struct WebVttEncoder {
     track: Option<Track>,
-    queue: VecDeque<Packet>,
+    queue: OutputQueue<Packet>,
     cue_index: usize,
+    styles: HashMap<String, TextStyle>,
 }
 
 impl WebVttEncoder {
     pub fn new() -> Self {
         WebVttEncoder {
             track: None,
-            queue: VecDeque::new(),
+            queue: OutputQueue::new(),
             cue_index: 0,
+            styles: HashMap::new(),
         }
     }
 
@@ -35,7 +37,11 @@ impl Default for WebVttEncoder {
 }
 
 impl Encoder for WebVttEncoder {
-    fn start(&mut self, desc: CodecDescription) -> anyhow::Result<Track> {
+    fn start(&mut self, desc: CodecDescription) -> crate::Result<Track> {
+        if let Some(desc) = desc.into_subtitle() {
+            self.styles = desc.styles;
+        }
+
         let info = SubtitleInfo {
             codec: SubtitleCodec::WebVtt(WebVttCodec { header: "".into() }),
         };
@@ -47,6 +53,8 @@ impl Encoder for WebVttEncoder {
                 kind: MediaKind::Subtitle(info),
             }),
             timebase: WEBVTT_TIMEBASE,
+            delay: self.delay(),
+            metadata: Default::default(),
         };
 
         self.track = Some(track.clone());
@@ -54,31 +62,44 @@ impl Encoder for WebVttEncoder {
         Ok(track)
     }
 
-    fn feed(&mut self, raw: Decoded) -> anyhow::Result<()> {
+    fn feed(&mut self, raw: Decoded) -> crate::Result<()> {
+        self.queue.reserve()?;
+
         let cue = raw
             .into_subtitle()
             .ok_or_else(|| anyhow::anyhow!("Expected text cue"))?;
 
         let time = cue.time;
-        let timebase = time.timebase;
-        let begin_seconds = time.pts as f32 / timebase.denominator as f32;
-        let duration_seconds = time
+        let duration = time
             .duration
-            .ok_or_else(|| anyhow::anyhow!("Expected duration for subtitle"))?
-            as f32
-            / timebase.denominator as f32;
-        let end_seconds = begin_seconds + duration_seconds;
+            .ok_or_else(|| anyhow::anyhow!("Expected duration for subtitle"))?;
 
-        let begin = WebVttTime::from(begin_seconds);
-        let end = WebVttTime::from(end_seconds);
+        let begin = ClockTime::from_timestamp(time.pts, time.timebase);
+        let end = ClockTime::from_timestamp(time.pts + duration, time.timebase);
 
         let mut text = Vec::new();
 
         writeln!(&mut text, "{}", self.cue_index)?;
-        writeln!(&mut text, "{begin} --> {end}")?;
+        let style = self.styles.get(&cue.style);
+        writeln!(
+            &mut text,
+            "{begin} --> {end}{}",
+            cue_settings(&cue.text, style)
+        )?;
+
+        let mut tags = InlineTags {
+            italic: style.is_some_and(|style| style.italic),
+            ..Default::default()
+        };
+        let mut drawing = false;
         for part in cue.text {
             match part {
+                // vector drawings have no WebVTT equivalent, so their commands are dropped
+                TextPart::Drawing(scale) => drawing = scale > 0,
+                TextPart::Text(_) if drawing => {}
                 TextPart::Text(txt) => {
+                    tags.sync(&mut text);
+
                     for b in txt.into_bytes() {
                         match b {
                             // TODO: probably need &nbsp; as well...
@@ -94,10 +115,12 @@ impl Encoder for WebVttEncoder {
                 TextPart::SmartBreak => {
                     text.push(b'\n');
                 }
-                // TODO: add styling
+                TextPart::Italic(italic) => tags.italic = italic,
+                TextPart::Fill(TextFill(ColorType::Primary, color)) => tags.color = Some(color),
                 _ => {}
             }
         }
+        tags.close(&mut text);
         writeln!(&mut text)?;
 
         let pkt = Packet {
@@ -105,40 +128,134 @@ impl Encoder for WebVttEncoder {
             key: true,
             track: self.track.clone().expect("Encoder not started"),
             buffer: text.into(),
+            side_data: Vec::new(),
         };
 
         self.cue_index += 1;
-        self.queue.push_back(pkt);
+        self.queue.push(pkt);
 
         Ok(())
     }
 
     fn receive(&mut self) -> Option<Packet> {
-        self.queue.pop_front()
+        self.queue.pop()
+    }
+
+    fn is_full(&self) -> bool {
+        self.queue.is_full()
     }
 }
 
-struct WebVttTime(u32, u8, u8, u16);
+/// Maps the first alignment and position of a cue to WebVTT cue settings, which apply to the
+/// whole cue. The alignment falls back to the one of the cue style.
+fn cue_settings(parts: &[TextPart], style: Option<&TextStyle>) -> String {
+    let align = parts
+        .iter()
+        .find_map(|part| match part {
+            TextPart::Align(align) => Some(align),
+            _ => None,
+        })
+        .or_else(|| style?.alignment.as_ref());
+    let position = parts.iter().find_map(|part| match part {
+        TextPart::Position(position) => Some(position),
+        _ => None,
+    });
+
+    // horizontal and vertical alignment, 0 for left/top, 1 for center and 2 for right/bottom
+    let (h, v) = match align.unwrap_or(&TextAlign::Bot) {
+        TextAlign::TopLeft => (0, 0),
+        TextAlign::Top => (1, 0),
+        TextAlign::TopRight => (2, 0),
+        TextAlign::MidLeft => (0, 1),
+        TextAlign::Mid => (1, 1),
+        TextAlign::MidRight => (2, 1),
+        TextAlign::BotLeft => (0, 2),
+        TextAlign::Bot => (1, 2),
+        TextAlign::BotRight => (2, 2),
+    };
 
-impl From<f32> for WebVttTime {
-    fn from(val: f32) -> Self {
-        let h = (val / 3600.0).floor();
-        let m = (val / 60.0).floor() % 60.0;
-        let s = (val % 60.0).floor() % 60.0;
-        let ms = (val.fract() * 1000.0).round();
+    let mut settings = String::new();
+    if let Some(TextPosition(x, y)) = position {
+        let position_align = ["line-left", "center", "line-right"][h];
+        let line_align = ["start", "center", "end"][v];
 
-        WebVttTime(h as u32, m as u8, s as u8, ms as u16)
+        settings.push_str(&format!(
+            " position:{}%,{position_align} line:{}%,{line_align}",
+            percent(*x),
+            percent(*y)
+        ));
+    } else {
+        settings.push_str([" line:0", " line:50%,center", ""][v]);
     }
+
+    settings.push_str([" align:left", "", " align:right"][h]);
+
+    settings
+}
+
+fn percent(value: f32) -> f32 {
+    (value.clamp(0.0, 100.0) * 100.0).round() / 100.0
+}
+
+/// The inline tags of the text written so far. Color tags enclose italic tags, so changing the
+/// color reopens both to keep them properly nested.
+#[derive(Default)]
+struct InlineTags {
+    italic: bool,
+    color: Option<u32>,
+    open_italic: bool,
+    open_color: Option<u32>,
 }
 
-impl fmt::Display for WebVttTime {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let WebVttTime(h, m, s, ms) = self;
+impl InlineTags {
+    fn sync(&mut self, text: &mut Vec<u8>) {
+        if self.color != self.open_color {
+            self.close(text);
+
+            if let Some(color) = self.color {
+                text.extend(format!("<c.{}>", color_class(color)).as_bytes());
+            }
+            self.open_color = self.color;
+        }
+
+        if self.italic != self.open_italic {
+            text.extend(if self.italic { &b"<i>"[..] } else { b"</i>" });
+            self.open_italic = self.italic;
+        }
+    }
+
+    fn close(&mut self, text: &mut Vec<u8>) {
+        if self.open_italic {
+            text.extend(b"</i>");
+        }
+        if self.open_color.is_some() {
+            text.extend(b"</c>");
+        }
 
-        write!(f, "{h:02}:{m:02}:{s:02}.{ms:03}")
+        (self.open_italic, self.open_color) = (false, None);
     }
 }
 
+/// The class for an ASS `&HBBGGRR` color, one of the colors WebVTT players know without a
+/// stylesheet if possible.
+fn color_class(color: u32) -> String {
+    let rgb = ((color & 0xff) << 16) | (color & 0xff00) | ((color >> 16) & 0xff);
+
+    let name = match rgb {
+        0xffffff => "white",
+        0x00ff00 => "lime",
+        0x00ffff => "cyan",
+        0xff0000 => "red",
+        0xffff00 => "yellow",
+        0xff00ff => "magenta",
+        0x0000ff => "blue",
+        0x000000 => "black",
+        _ => return format!("color{rgb:06x}"),
+    };
+
+    name.to_string()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -157,9 +274,115 @@ mod test {
     #[test_case(3600.0 * 9.0, "09:00:00.000")]
     #[test_case(3600.0 * 11.0, "11:00:00.000")]
     #[test_case(3600.0 * 100.0, "100:00:00.000")]
-    fn cue_time_format(seconds: f32, expected: &str) {
-        let time: WebVttTime = seconds.into();
+    fn cue_time_format(seconds: f64, expected: &str) {
+        let time = ClockTime::new(std::time::Duration::from_secs_f64(seconds));
 
         assert_eq!(&format!("{time}"), expected);
     }
+
+    #[test]
+    fn styled_cue() {
+        let mut encoder = WebVttEncoder::new();
+        encoder
+            .start(CodecDescription::Subtitle(Default::default()))
+            .unwrap();
+
+        let cue = TextCue {
+            time: MediaTime {
+                pts: 1000,
+                dts: None,
+                duration: Some(1500),
+                timebase: Fraction::new(1, 1000),
+            },
+            style: "Default".into(),
+            text: vec![
+                TextPart::Align(TextAlign::TopLeft),
+                TextPart::Position(TextPosition(12.5, 25.0)),
+                TextPart::Text("Hello ".into()),
+                TextPart::Fill(TextFill(ColorType::Primary, 0x00ffff)),
+                TextPart::Text("yellow ".into()),
+                TextPart::Italic(true),
+                TextPart::Text("world".into()),
+                TextPart::Fill(TextFill(ColorType::Primary, 0x123456)),
+                TextPart::SmartBreak,
+                TextPart::Text("<3".into()),
+                TextPart::Drawing(1),
+                TextPart::Text("m 0 0 l 10 0 10 10".into()),
+                TextPart::Drawing(0),
+            ],
+        };
+        encoder.feed(Decoded::Subtitle(cue)).unwrap();
+
+        let packet = encoder.receive().unwrap();
+        assert_eq!(
+            "0\n\
+             00:00:01.000 --> 00:00:02.500 position:12.5%,line-left line:25%,start align:left\n\
+             Hello <c.yellow>yellow <i>world\n\
+             </i></c><c.color563412><i>&lt;3</i></c>\n",
+            String::from_utf8(packet.buffer.to_slice().to_vec()).unwrap()
+        );
+    }
+
+    #[test_case(TextAlign::Top, " line:0" ; "top")]
+    #[test_case(TextAlign::MidRight, " line:50%,center align:right" ; "middle right")]
+    #[test_case(TextAlign::Bot, "" ; "bottom")]
+    fn alignment_settings(align: TextAlign, expected: &str) {
+        assert_eq!(expected, cue_settings(&[TextPart::Align(align)], None));
+    }
+
+    #[tokio::test]
+    async fn transcode_more_than_the_queues_hold() {
+        use crate::{codec::ass::AssDecoder, PacketTranscoder, Transcode};
+
+        let mut encoder = WebVttEncoder::new();
+        encoder
+            .start(CodecDescription::Subtitle(Default::default()))
+            .unwrap();
+        let mapping = [(
+            1,
+            Transcode::Subtitles {
+                decoder: Box::new(AssDecoder::new()),
+                encoder: Box::new(encoder),
+            },
+        )];
+        let mut transcoder = PacketTranscoder::new(mapping.into_iter().collect());
+
+        let track = Track {
+            id: 1,
+            info: Arc::new(MediaInfo {
+                name: "ass",
+                kind: MediaKind::Subtitle(SubtitleInfo {
+                    codec: SubtitleCodec::Ass(AssCodec {
+                        header: String::new(),
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 1000),
+            delay: 0,
+            metadata: Default::default(),
+        };
+        transcoder.start(&[track.clone()]);
+
+        let count = DEFAULT_QUEUE_CAPACITY * 4;
+        let mut transcoded = 0;
+        for i in 0..count as u64 {
+            let pkt = Packet {
+                time: MediaTime {
+                    pts: i * 1000,
+                    dts: None,
+                    duration: Some(500),
+                    timebase: track.timebase,
+                },
+                key: true,
+                track: track.clone(),
+                buffer: b"0,0,Default,,0,0,0,,Text".to_vec().into(),
+                side_data: Vec::new(),
+            };
+
+            transcoder.process(pkt, |_| transcoded += 1).await.unwrap();
+        }
+        transcoder.finish(|_| transcoded += 1).await.unwrap();
+
+        assert_eq!(count, transcoded);
+    }
 }
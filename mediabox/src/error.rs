@@ -0,0 +1,143 @@
+use crate::io::IoError;
+
+pub type Result<T, E = MediaboxError> = std::result::Result<T, E>;
+
+/// The error returned by [`Decoder`](crate::codec::Decoder), [`Encoder`](crate::codec::Encoder),
+/// [`Demuxer`](crate::format::Demuxer) and [`Muxer`](crate::format::Muxer).
+#[derive(Debug, thiserror::Error)]
+pub enum MediaboxError {
+    /// Reading from or writing to the underlying [`Io`](crate::io::Io) failed.
+    #[error(transparent)]
+    Io(IoError),
+
+    /// The input ended, which is how demuxers signal that there are no more packets.
+    #[error("End of input")]
+    EndOfInput,
+
+    /// The input ended in the middle of a header, element or packet.
+    #[error("Truncated input: {0:#}")]
+    Truncated(anyhow::Error),
+
+    /// The input uses a codec or feature which isn't implemented.
+    #[error("Unsupported {0}")]
+    Unsupported(String),
+
+    /// The input is corrupt or not in the expected format.
+    #[error("Invalid data: {0:#}")]
+    InvalidData(anyhow::Error),
+
+    /// A decoder or encoder holds as much output as it may queue, which has to be received before
+    /// it accepts more input. The rejected input is dropped, so callers which can't produce it
+    /// again should check `is_full` on the decoder or encoder before feeding it.
+    #[error("Output queue is full")]
+    Full,
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl MediaboxError {
+    pub fn unsupported(what: impl Into<String>) -> Self {
+        MediaboxError::Unsupported(what.into())
+    }
+
+    pub fn invalid_data(error: impl Into<anyhow::Error>) -> Self {
+        MediaboxError::InvalidData(error.into())
+    }
+
+    /// Whether the error means that the input has ended rather than that it is invalid.
+    pub fn is_end_of_input(&self) -> bool {
+        matches!(self, MediaboxError::EndOfInput)
+    }
+}
+
+impl From<IoError> for MediaboxError {
+    fn from(error: IoError) -> Self {
+        match error {
+            IoError::Io(e) => e.into(),
+            IoError::Misc(e) => e.into(),
+            error => MediaboxError::Io(error),
+        }
+    }
+}
+
+/// Demuxers return [`MediaboxError::EndOfInput`] themselves when the input ends in between
+/// packets, so running out of data anywhere else means that the input is truncated.
+impl From<std::io::Error> for MediaboxError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => MediaboxError::Truncated(error.into()),
+            _ => MediaboxError::Io(IoError::Io(error)),
+        }
+    }
+}
+
+/// Errors which already are or wrap a [MediaboxError] or an I/O error keep their kind, anything
+/// else becomes [`MediaboxError::Other`]. Truncated inputs keep the context of the error, e.g.
+/// which header was being read.
+impl From<anyhow::Error> for MediaboxError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<MediaboxError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+
+        if error.chain().any(is_unexpected_eof) {
+            return MediaboxError::Truncated(error);
+        }
+
+        let error = match error.downcast::<IoError>() {
+            Ok(error) => return error.into(),
+            Err(error) => error,
+        };
+
+        match error.downcast::<std::io::Error>() {
+            Ok(error) => error.into(),
+            Err(error) => MediaboxError::Other(error),
+        }
+    }
+}
+
+fn is_unexpected_eof(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<std::io::Error>(),
+        Some(e) if e.kind() == std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+impl From<std::str::Utf8Error> for MediaboxError {
+    fn from(error: std::str::Utf8Error) -> Self {
+        MediaboxError::invalid_data(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keep_error_kinds() {
+        let eof = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        let truncated = MediaboxError::from(anyhow::Error::new(eof).context("Reading header"));
+        assert!(!truncated.is_end_of_input());
+        assert_eq!(
+            "Truncated input: Reading header: unexpected end of file",
+            truncated.to_string()
+        );
+
+        let end = anyhow::Error::new(MediaboxError::EndOfInput).context("Reading packet");
+        assert!(MediaboxError::from(end).is_end_of_input());
+
+        let unsupported = anyhow::Error::new(MediaboxError::unsupported("Opus in WAV"));
+        assert!(matches!(
+            MediaboxError::from(unsupported),
+            MediaboxError::Unsupported(_)
+        ));
+
+        let io = anyhow::Error::new(IoError::NotSeekable);
+        assert!(matches!(
+            MediaboxError::from(io),
+            MediaboxError::Io(IoError::NotSeekable)
+        ));
+    }
+}
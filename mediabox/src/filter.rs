@@ -0,0 +1,1866 @@
+//! Filters which rewrite packets between a demuxer and a muxer.
+//!
+//! Filters which change the tracks have a `start` method whose tracks the muxer is started with.
+//! Every packet read from the demuxer is then passed through `filter` until the input ends:
+//!
+//! ```ignore
+//! let mut mapper = TimebaseMapper::new().with_timebase(Fraction::new(1, 90_000));
+//!
+//! muxer.start(mapper.start(movie.tracks)).await?;
+//! loop {
+//!     let pkt = match demuxer.read().await {
+//!         Ok(pkt) => pkt,
+//!         Err(e) if e.is_end_of_input() => break,
+//!         Err(e) => return Err(e),
+//!     };
+//!
+//!     muxer.write(mapper.filter(pkt)).await?;
+//! }
+//! muxer.stop().await?;
+//! ```
+
+use log::*;
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    codec::{
+        h264::{
+            rewrite_media_info, rewrite_sps,
+            sei::{parse_sei, RecoveryPoint, RECOVERY_POINT},
+            SpsRewrite,
+        },
+        nal::{convert_bitstream, frame_nal_units, nal_units, BitstreamFraming},
+        AudioFrame,
+    },
+    format::Movie,
+    media::convert_timebase,
+    time::{from_duration, to_duration},
+    Fraction, H264Codec, MediaInfo, MediaKind, MediaTime, Packet, RandomAccess, SideData, Span,
+    Track, VideoCodec, VideoInfo,
+};
+
+/// How many packets H.264 frames are assumed to be reordered by, which is enough for B-frames
+/// referencing other B-frames.
+const DEFAULT_REORDER_DEPTH: usize = 2;
+
+/// Rewrites every H.264 packet to a target [BitstreamFraming], e.g. to turn an Annex B stream into
+/// length prefixed NAL units before muxing. Packets of other codecs are passed through unchanged.
+///
+/// Start with [BitstreamConverterFilter::start] and filter packets as in the
+/// [module example](self).
+pub struct BitstreamConverterFilter {
+    target: BitstreamFraming,
+    tracks: HashMap<u32, Track>,
+}
+
+impl BitstreamConverterFilter {
+    pub fn new(target: BitstreamFraming) -> Self {
+        BitstreamConverterFilter {
+            target,
+            tracks: HashMap::new(),
+        }
+    }
+
+    /// Returns the tracks with their [MediaInfo] updated to the target framing.
+    pub fn start(&mut self, tracks: Vec<Track>) -> Vec<Track> {
+        tracks
+            .into_iter()
+            .map(|track| {
+                let converted = self.convert_track(&track);
+                self.tracks.insert(track.id, converted.clone());
+
+                converted
+            })
+            .collect()
+    }
+
+    pub fn filter(&self, mut packet: Packet) -> Packet {
+        let Some(source) = framing(&packet.track.info) else {
+            return packet;
+        };
+
+        packet.buffer = convert_bitstream(packet.buffer, source, self.target);
+        packet.track = match self.tracks.get(&packet.track.id) {
+            Some(track) => track.clone(),
+            None => self.convert_track(&packet.track),
+        };
+
+        packet
+    }
+
+    fn convert_track(&self, track: &Track) -> Track {
+        let MediaKind::Video(VideoInfo {
+            width,
+            height,
+            codec: VideoCodec::H264(codec),
+        }) = &track.info.kind
+        else {
+            return track.clone();
+        };
+
+        Track {
+            info: Arc::new(MediaInfo {
+                name: track.info.name,
+                kind: MediaKind::Video(VideoInfo {
+                    width: *width,
+                    height: *height,
+                    codec: VideoCodec::H264(H264Codec {
+                        bitstream_format: self.target,
+                        ..codec.clone()
+                    }),
+                }),
+            }),
+            ..track.clone()
+        }
+    }
+}
+
+/// Rescales packet timestamps to another timebase and fills in missing decode timestamps, e.g.
+/// when remuxing Matroska, which only stores presentation timestamps, to a format which needs
+/// both.
+///
+/// If frames are reordered by at most `reorder_depth` packets, the decode timestamp of a packet
+/// is the `reorder_depth`-th last of the smallest presentation timestamps seen so far, which is
+/// never after its own presentation timestamp. The depth is taken from the SPS of H.264 streams
+/// if it's there, other codecs never reorder packets.
+///
+/// Start with [TimebaseMapper::start] and filter packets as in the [module example](self).
+pub struct TimebaseMapper {
+    timebase: Option<Fraction>,
+    reorder_depth: usize,
+    tracks: HashMap<u32, MappedTrack>,
+}
+
+struct MappedTrack {
+    track: Track,
+    reorder_depth: usize,
+    /// Presentation timestamps which have not been used as a decode timestamp yet.
+    pending: BinaryHeap<Reverse<u64>>,
+    last_dts: Option<u64>,
+}
+
+impl MappedTrack {
+    fn synthesize_dts(&mut self, pts: u64) -> u64 {
+        self.pending.push(Reverse(pts));
+
+        let dts = if self.pending.len() > self.reorder_depth {
+            self.pending.pop().map(|Reverse(ts)| ts).unwrap_or(pts)
+        } else {
+            // the first packets decode before the first frame is shown, one tick apart
+            let Reverse(first) = *self.pending.peek().unwrap();
+            first.saturating_sub((self.reorder_depth - self.pending.len() + 1) as u64)
+        };
+
+        self.monotonic(dts.min(pts), pts)
+    }
+
+    fn monotonic(&mut self, dts: u64, pts: u64) -> u64 {
+        let dts = self.last_dts.map_or(dts, |last| dts.max(last));
+        if dts > pts {
+            warn!(
+                "Track {} is reordered by more than {} packets",
+                self.track.id, self.reorder_depth
+            );
+        }
+
+        self.last_dts = Some(dts);
+
+        dts
+    }
+}
+
+impl TimebaseMapper {
+    /// Creates a mapper which keeps the timebase of every track.
+    pub fn new() -> Self {
+        TimebaseMapper {
+            timebase: None,
+            reorder_depth: DEFAULT_REORDER_DEPTH,
+            tracks: HashMap::new(),
+        }
+    }
+
+    /// Rescales all tracks to the given timebase.
+    pub fn with_timebase(mut self, timebase: Fraction) -> Self {
+        self.timebase = Some(timebase);
+        self
+    }
+
+    /// Sets how many packets H.264 frames may be reordered by if the stream doesn't tell.
+    pub fn with_reorder_depth(mut self, reorder_depth: usize) -> Self {
+        self.reorder_depth = reorder_depth;
+        self
+    }
+
+    /// Returns the tracks with their timebase and delay rescaled.
+    pub fn start(&mut self, tracks: Vec<Track>) -> Vec<Track> {
+        tracks
+            .into_iter()
+            .map(|track| {
+                let timebase = self.timebase.unwrap_or(track.timebase);
+                let mapped = Track {
+                    timebase,
+                    delay: convert_timebase(track.delay, track.timebase, timebase),
+                    ..track.clone()
+                };
+
+                let reorder_depth = match &track.info.kind {
+                    MediaKind::Video(VideoInfo {
+                        codec: VideoCodec::H264(codec),
+                        ..
+                    }) => codec
+                        .max_reorder_frames()
+                        .map_or(self.reorder_depth, |frames| frames as usize),
+                    _ => 0,
+                };
+
+                self.tracks.insert(
+                    track.id,
+                    MappedTrack {
+                        track: mapped.clone(),
+                        reorder_depth,
+                        pending: BinaryHeap::new(),
+                        last_dts: None,
+                    },
+                );
+
+                mapped
+            })
+            .collect()
+    }
+
+    /// Rescales the timestamps of a packet, packets of tracks which were not started are passed
+    /// through unchanged.
+    pub fn filter(&mut self, mut packet: Packet) -> Packet {
+        let Some(mapped) = self.tracks.get_mut(&packet.track.id) else {
+            return packet;
+        };
+
+        let time = &packet.time;
+        let timebase = mapped.track.timebase;
+        let convert = |ts| convert_timebase(ts, time.timebase, timebase);
+
+        let pts = convert(time.pts);
+        let dts = match time.dts {
+            Some(dts) => mapped.monotonic(convert(dts), pts),
+            None => mapped.synthesize_dts(pts),
+        };
+
+        packet.time = MediaTime {
+            pts,
+            dts: Some(dts),
+            // rounding the end instead of the duration keeps packets from drifting apart
+            duration: time
+                .duration
+                .map(|duration| convert(time.pts + duration) - pts),
+            timebase,
+        };
+        packet.track = mapped.track.clone();
+
+        packet
+    }
+}
+
+impl Default for TimebaseMapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Offsets and stretches the timestamps of subtitle packets, e.g. to fix subtitles timed for
+/// another cut or frame rate. Timestamps are scaled first and then shifted, clamping at zero, while
+/// durations are kept. Packets of other tracks are passed through unchanged.
+///
+/// Filter packets with [SubtitleRetimer::filter] as in the [module example](self).
+#[derive(Debug, Clone, Copy)]
+pub struct SubtitleRetimer {
+    /// Nanoseconds added to every timestamp, negative to show subtitles earlier.
+    shift: i128,
+    scale: f64,
+}
+
+impl SubtitleRetimer {
+    pub fn new() -> Self {
+        SubtitleRetimer {
+            shift: 0,
+            scale: 1.0,
+        }
+    }
+
+    /// Shows subtitles later by the given time.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.shift = delay.as_nanos() as i128;
+        self
+    }
+
+    /// Shows subtitles earlier by the given time.
+    pub fn with_advance(mut self, advance: Duration) -> Self {
+        self.shift = -(advance.as_nanos() as i128);
+        self
+    }
+
+    /// Multiplies all timestamps by the given factor, e.g. `1.001` for subtitles timed for
+    /// 24 fps shown with 23.976 fps video.
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn filter(&self, mut packet: Packet) -> Packet {
+        if !matches!(packet.track.info.kind, MediaKind::Subtitle(_)) {
+            return packet;
+        }
+
+        let timebase = packet.time.timebase;
+        let shift = self.shift * timebase.denominator as i128
+            / (timebase.numerator as i128 * 1_000_000_000);
+
+        packet.time.pts = self.retime(packet.time.pts, shift);
+        packet.time.dts = packet.time.dts.map(|dts| self.retime(dts, shift));
+
+        packet
+    }
+
+    fn retime(&self, ts: u64, shift: i128) -> u64 {
+        ((ts as f64 * self.scale).round() as i128 + shift).max(0) as u64
+    }
+}
+
+impl Default for SubtitleRetimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Measures the length, average bitrate and packet counts of every track from the packets
+/// passing through, for inputs whose container doesn't store them.
+///
+/// Filter packets with [BitrateEstimator::filter] as in the [module example](self), then call
+/// [BitrateEstimator::apply] once the input ends.
+#[derive(Default)]
+pub struct BitrateEstimator {
+    tracks: HashMap<u32, TrackStats>,
+}
+
+/// The packets of a track counted by [BitrateEstimator].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackStats {
+    pub packets: u64,
+    pub bytes: u64,
+    /// The earliest and latest presentation time of a packet.
+    pub min_pts: Option<Duration>,
+    pub max_pts: Option<Duration>,
+    /// The end of the packet which ends last, including its duration.
+    pub end: Duration,
+}
+
+impl TrackStats {
+    /// The time between the start of the first and the end of the last packet.
+    pub fn duration(&self) -> Option<Duration> {
+        Some(self.end.saturating_sub(self.min_pts?))
+    }
+
+    /// The average bitrate in bits per second.
+    pub fn bitrate(&self) -> Option<u64> {
+        let nanos = self.duration()?.as_nanos();
+
+        (self.bytes as u128 * 8 * 1_000_000_000)
+            .checked_div(nanos)
+            .map(|bitrate| bitrate as u64)
+    }
+
+    /// The average number of packets per second, which is the frame rate of video tracks.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let duration = self.duration().filter(|d| !d.is_zero())?;
+
+        Some(self.packets as f64 / duration.as_secs_f64())
+    }
+}
+
+impl BitrateEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a packet towards its track and passes it through unchanged.
+    pub fn filter(&mut self, packet: Packet) -> Packet {
+        let time = &packet.time;
+        let start = to_duration(time.pts, time.timebase);
+        let end = to_duration(time.pts + time.duration.unwrap_or(0), time.timebase);
+
+        let stats = self.tracks.entry(packet.track.id).or_default();
+        stats.packets += 1;
+        stats.bytes += packet.buffer.len() as u64;
+        stats.min_pts = Some(stats.min_pts.map_or(start, |min| min.min(start)));
+        stats.max_pts = Some(stats.max_pts.map_or(start, |max| max.max(start)));
+        stats.end = stats.end.max(end);
+
+        packet
+    }
+
+    pub fn stats(&self, track_id: u32) -> Option<&TrackStats> {
+        self.tracks.get(&track_id)
+    }
+
+    /// The time between the start of the first and the end of the last packet of a track.
+    pub fn duration(&self, track_id: u32) -> Option<Duration> {
+        self.stats(track_id)?.duration()
+    }
+
+    /// The average bitrate of a track in bits per second.
+    pub fn bitrate(&self, track_id: u32) -> Option<u64> {
+        self.stats(track_id)?.bitrate()
+    }
+
+    /// Fills in the lengths and bitrates of the tracks the container didn't store.
+    pub fn apply(&self, movie: &mut Movie) {
+        for track in &mut movie.tracks {
+            let metadata = &mut track.metadata;
+            metadata.duration = metadata.duration.or_else(|| self.duration(track.id));
+            metadata.bitrate = metadata.bitrate.or_else(|| self.bitrate(track.id));
+        }
+    }
+}
+
+/// Annotates H.264 packets with [SideData::RandomAccess] from their IDR pictures and recovery
+/// point SEI messages, so that segmenters can also cut streams which use recovery points instead
+/// of IDR pictures. Packets of other codecs are passed through unchanged.
+///
+/// Filter packets with [RandomAccessAnnotator::filter] as in the [module example](self).
+#[derive(Default)]
+pub struct RandomAccessAnnotator {
+    sei_messages: bool,
+}
+
+impl RandomAccessAnnotator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also attaches every SEI message of a packet as [SideData::Sei].
+    pub fn with_sei_messages(mut self) -> Self {
+        self.sei_messages = true;
+        self
+    }
+
+    pub fn filter(&self, mut packet: Packet) -> Packet {
+        let Some(framing) = framing(&packet.track.info) else {
+            return packet;
+        };
+
+        let mut access = None;
+        for nal in nal_units(&packet.buffer, framing) {
+            let nal_type = nal.spans().next().and_then(|b| b.first()).map(|b| b & 0x1f);
+
+            match nal_type {
+                Some(5) => access = Some(RandomAccess::Idr),
+                Some(6) => {
+                    let messages = match parse_sei(&nal.to_slice()) {
+                        Ok(messages) => messages,
+                        Err(e) => {
+                            debug!("Skipping SEI of track {}: {e}", packet.track.id);
+                            continue;
+                        }
+                    };
+
+                    for message in messages {
+                        let recovery = (message.payload_type == RECOVERY_POINT)
+                            .then(|| RecoveryPoint::parse(&message.payload))
+                            .flatten();
+
+                        if let (None, Some(point)) = (access, recovery) {
+                            access = Some(RandomAccess::Recovery {
+                                frames: point.recovery_frame_cnt,
+                                exact_match: point.exact_match,
+                                broken_link: point.broken_link,
+                            });
+                        }
+
+                        if self.sei_messages {
+                            packet.side_data.push(SideData::Sei {
+                                payload_type: message.payload_type,
+                                payload: message.payload,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        packet.side_data.extend(access.map(SideData::RandomAccess));
+        packet
+    }
+}
+
+/// Rewrites fields of the SPS of H.264 tracks with a [SpsRewrite], both in the track's
+/// [MediaInfo] and in-band. Keyframes without parameter sets get the rewritten ones inserted so
+/// decoders joining the stream mid-way pick up the changes.
+///
+/// Start with [SpsRewriteFilter::start] and filter packets as in the [module example](self).
+pub struct SpsRewriteFilter {
+    rewrite: SpsRewrite,
+    tracks: HashMap<u32, Track>,
+}
+
+impl SpsRewriteFilter {
+    pub fn new(rewrite: SpsRewrite) -> Self {
+        SpsRewriteFilter {
+            rewrite,
+            tracks: HashMap::new(),
+        }
+    }
+
+    /// Returns the tracks with the SPS of their [MediaInfo] rewritten.
+    pub fn start(&mut self, tracks: Vec<Track>) -> Vec<Track> {
+        tracks
+            .into_iter()
+            .map(|track| {
+                let rewritten = self.rewrite_track(&track);
+                self.tracks.insert(track.id, rewritten.clone());
+
+                rewritten
+            })
+            .collect()
+    }
+
+    pub fn filter(&self, mut packet: Packet) -> Packet {
+        let Some(framing) = framing(&packet.track.info) else {
+            return packet;
+        };
+
+        let track = match self.tracks.get(&packet.track.id) {
+            Some(track) => track.clone(),
+            None => self.rewrite_track(&packet.track),
+        };
+
+        let mut nals = nal_units(&packet.buffer, framing).collect::<Vec<_>>();
+        let mut has_sps = false;
+        for nal in nals.iter_mut().filter(|nal| nal_unit_type(nal) == Some(7)) {
+            has_sps = true;
+            match rewrite_sps(&nal.to_slice(), &self.rewrite) {
+                Ok(sps) => *nal = sps.into(),
+                Err(e) => warn!("Failed to rewrite SPS of track {}: {e}", track.id),
+            }
+        }
+
+        let codec = match &track.info.kind {
+            MediaKind::Video(VideoInfo {
+                codec: VideoCodec::H264(codec),
+                ..
+            }) => Some(codec),
+            _ => None,
+        };
+
+        if let (true, false, Some(codec)) = (packet.key, has_sps, codec) {
+            // parameter sets have to follow the access unit delimiter
+            let position = nals
+                .iter()
+                .take_while(|nal| nal_unit_type(nal) == Some(9))
+                .count();
+            nals.splice(position..position, [codec.sps.clone(), codec.pps.clone()]);
+        }
+
+        packet.buffer = frame_nal_units(&nals, framing);
+        packet.track = track;
+        packet
+    }
+
+    fn rewrite_track(&self, track: &Track) -> Track {
+        if framing(&track.info).is_none() {
+            return track.clone();
+        }
+
+        match rewrite_media_info(&track.info, &self.rewrite) {
+            Ok(info) => Track {
+                info: Arc::new(info),
+                ..track.clone()
+            },
+            Err(e) => {
+                warn!("Failed to rewrite SPS of track {}: {e}", track.id);
+                track.clone()
+            }
+        }
+    }
+}
+
+/// The length of the blocks loudness is measured over, in 100 ms steps.
+const BLOCK_STEPS: usize = 4;
+
+/// Blocks quieter than this don't count towards the integrated loudness, in LUFS.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks more than this much quieter than the ungated loudness don't count towards the
+/// integrated loudness, in LU.
+const RELATIVE_GATE: f64 = -10.0;
+
+/// The polyphase filter which oversamples by 4 to find true peaks, from ITU-R BS.1770-4 Annex 2.
+#[rustfmt::skip]
+const TRUE_PEAK_PHASES: [[f64; 12]; 4] = [
+    [
+        0.0017089843750, 0.0109863281250, -0.0196533203125, 0.0332031250000, -0.0594482421875,
+        0.1373291015625, 0.9721679687500, -0.1022949218750, 0.0476074218750, -0.0266113281250,
+        0.0148925781250, -0.0083007812500,
+    ],
+    [
+        -0.0291748046875, 0.0292968750000, -0.0517578125000, 0.0891113281250, -0.1665039062500,
+        0.4650878906250, 0.7797851562500, -0.2003173828125, 0.1015625000000, -0.0582275390625,
+        0.0330810546875, -0.0189208984375,
+    ],
+    [
+        -0.0189208984375, 0.0330810546875, -0.0582275390625, 0.1015625000000, -0.2003173828125,
+        0.7797851562500, 0.4650878906250, -0.1665039062500, 0.0891113281250, -0.0517578125000,
+        0.0292968750000, -0.0291748046875,
+    ],
+    [
+        -0.0083007812500, 0.0148925781250, -0.0266113281250, 0.0476074218750, -0.1022949218750,
+        0.9721679687500, 0.1373291015625, -0.0594482421875, 0.0332031250000, -0.0196533203125,
+        0.0109863281250, 0.0017089843750,
+    ],
+];
+
+/// The loudness of a track according to EBU R128.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// The gated loudness of the whole track in LUFS, or negative infinity if it is silent.
+    pub integrated: f64,
+    /// The highest loudness over 400 ms in LUFS.
+    pub momentary_max: f64,
+    /// The highest level of the signal between samples, in dBTP.
+    pub true_peak: f64,
+}
+
+/// Measures the loudness and true peak of every audio track from its decoded frames, e.g. to
+/// check that a feed is normalized to -23 LUFS.
+///
+/// ```ignore
+/// let mut analyzer = LoudnessAnalyzer::new();
+///
+/// decoder.feed(pkt)?;
+/// while let Some(frame) = decoder.receive().and_then(Decoded::into_audio) {
+///     analyzer.filter(pkt.track.id, &frame);
+/// }
+/// let loudness = analyzer.loudness(track.id);
+/// ```
+#[derive(Default)]
+pub struct LoudnessAnalyzer {
+    tracks: HashMap<u32, TrackLoudness>,
+}
+
+impl LoudnessAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Measures a frame of a track.
+    pub fn filter(&mut self, track_id: u32, frame: &AudioFrame) {
+        let track = self.tracks.entry(track_id).or_default();
+
+        if (track.sample_rate, track.channels.len()) != (frame.sample_rate, frame.channels as usize)
+        {
+            if track.sample_rate != 0 {
+                warn!("Audio format of track {track_id} changed, restarting the loudness filters");
+            }
+
+            track.restart(frame.sample_rate, frame.channels);
+        }
+
+        for samples in frame.samples.chunks_exact(frame.channels.max(1) as usize) {
+            track.push(samples);
+        }
+    }
+
+    /// The loudness of the 400 ms up to the latest frame of a track, in LUFS.
+    pub fn momentary(&self, track_id: u32) -> Option<f64> {
+        let track = self.tracks.get(&track_id)?;
+
+        track.blocks.last().copied().map(energy_to_loudness)
+    }
+
+    /// The loudness of a track so far.
+    pub fn loudness(&self, track_id: u32) -> Option<Loudness> {
+        let track = self.tracks.get(&track_id)?;
+
+        let momentary_max = track
+            .blocks
+            .iter()
+            .copied()
+            .map(energy_to_loudness)
+            .fold(f64::NEG_INFINITY, f64::max);
+
+        Some(Loudness {
+            integrated: track.integrated(),
+            momentary_max,
+            true_peak: 20.0 * track.true_peak.log10(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct TrackLoudness {
+    sample_rate: u32,
+    channels: Vec<ChannelLoudness>,
+    /// The number of samples in 100 ms.
+    step_len: usize,
+    /// The samples so far of the current 100 ms.
+    step_pos: usize,
+    /// The weighted energy of the latest steps, oldest first.
+    steps: Vec<f64>,
+    /// The mean energy of every 400 ms block, which overlap by 300 ms.
+    blocks: Vec<f64>,
+    true_peak: f64,
+}
+
+impl TrackLoudness {
+    fn restart(&mut self, sample_rate: u32, channels: u16) {
+        let weights: &[f64] = match channels {
+            // L, R, C, LFE, Ls, Rs, where the LFE doesn't count
+            6 => &[1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+            _ => &[],
+        };
+
+        self.sample_rate = sample_rate;
+        self.channels = (0..channels as usize)
+            .map(|i| ChannelLoudness::new(sample_rate, *weights.get(i).unwrap_or(&1.0)))
+            .collect();
+        self.step_len = (sample_rate as usize / 10).max(1);
+        self.step_pos = 0;
+        self.steps.clear();
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        for (channel, &sample) in self.channels.iter_mut().zip(samples) {
+            channel.push(sample as f64);
+            self.true_peak = self.true_peak.max(channel.peak);
+        }
+
+        self.step_pos += 1;
+        if self.step_pos < self.step_len {
+            return;
+        }
+
+        let energy = self
+            .channels
+            .iter_mut()
+            .map(|c| c.weight * std::mem::take(&mut c.energy))
+            .sum::<f64>();
+
+        self.step_pos = 0;
+        self.steps.push(energy / self.step_len as f64);
+        if self.steps.len() > BLOCK_STEPS {
+            self.steps.remove(0);
+        }
+
+        if self.steps.len() == BLOCK_STEPS {
+            self.blocks
+                .push(self.steps.iter().sum::<f64>() / BLOCK_STEPS as f64);
+        }
+    }
+
+    fn integrated(&self) -> f64 {
+        let gated_mean = |threshold: f64| {
+            let gated = self
+                .blocks
+                .iter()
+                .filter(|&&energy| energy_to_loudness(energy) > threshold)
+                .collect::<Vec<_>>();
+
+            gated.iter().copied().sum::<f64>() / gated.len() as f64
+        };
+
+        let relative = energy_to_loudness(gated_mean(ABSOLUTE_GATE)) + RELATIVE_GATE;
+        if relative.is_nan() {
+            return f64::NEG_INFINITY;
+        }
+
+        energy_to_loudness(gated_mean(relative.max(ABSOLUTE_GATE)))
+    }
+}
+
+struct ChannelLoudness {
+    weight: f64,
+    /// The high shelf and high pass filters of the K-weighting.
+    filters: [Biquad; 2],
+    /// The sum of the squared filtered samples of the current step.
+    energy: f64,
+    /// The latest samples, newest last, for oversampling.
+    history: [f64; 12],
+    peak: f64,
+}
+
+impl ChannelLoudness {
+    fn new(sample_rate: u32, weight: f64) -> Self {
+        ChannelLoudness {
+            weight,
+            filters: Biquad::k_weighting(sample_rate as f64),
+            energy: 0.0,
+            history: [0.0; 12],
+            peak: 0.0,
+        }
+    }
+
+    fn push(&mut self, sample: f64) {
+        let filtered = self.filters.iter_mut().fold(sample, |x, f| f.process(x));
+        self.energy += filtered * filtered;
+
+        self.history.rotate_left(1);
+        self.history[11] = sample;
+
+        self.peak = self.peak.max(sample.abs());
+        for phase in &TRUE_PEAK_PHASES {
+            let interpolated = phase
+                .iter()
+                .zip(self.history.iter().rev())
+                .map(|(c, x)| c * x)
+                .sum::<f64>();
+
+            self.peak = self.peak.max(interpolated.abs());
+        }
+    }
+}
+
+/// A second order IIR filter in direct form I.
+#[derive(Default, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// The two filters of the K-weighting in ITU-R BS.1770 for any sample rate, derived from the
+    /// analog filters the 48 kHz coefficients are based on.
+    fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+        use std::f64::consts::PI;
+
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        [shelf, high_pass]
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+
+        y
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// How much the keyframe intervals of a stream with a regular cadence may differ from the median
+/// interval, relative to it.
+const CADENCE_TOLERANCE: f64 = 0.1;
+
+/// The structure of the groups of pictures of a video track, as found by [GopAnalyzer].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GopStats {
+    pub frames: u64,
+    /// Frames before the first keyframe, which can't be decoded.
+    pub leading_frames: u64,
+    /// The presentation time of every keyframe.
+    pub keyframes: Vec<Duration>,
+    /// The number of frames from each keyframe up to the next one. The last GOP is cut short if
+    /// the input ended in the middle of it.
+    pub gop_lengths: Vec<u64>,
+    /// Frames by their slice type, only counted for H.264.
+    pub i_frames: u64,
+    pub p_frames: u64,
+    pub b_frames: u64,
+}
+
+impl GopStats {
+    /// The time from every keyframe to the next.
+    pub fn keyframe_intervals(&self) -> Vec<Duration> {
+        self.keyframes
+            .windows(2)
+            .map(|w| w[1].saturating_sub(w[0]))
+            .collect()
+    }
+
+    /// Whether the stream starts with a keyframe and has them at a steady interval, so that it
+    /// can be split into HLS segments of about the same length.
+    pub fn is_regular(&self) -> bool {
+        let mut intervals = self.keyframe_intervals();
+        intervals.sort();
+
+        let Some(&median) = intervals.get(intervals.len() / 2) else {
+            return self.leading_frames == 0 && !self.keyframes.is_empty();
+        };
+
+        let tolerance = median.mul_f64(CADENCE_TOLERANCE);
+        self.leading_frames == 0
+            && intervals
+                .iter()
+                .all(|&interval| interval.abs_diff(median) <= tolerance)
+    }
+}
+
+/// Finds the keyframes and frame types of every video track from the packets passing through,
+/// e.g. to check that a stream can be segmented for HLS.
+///
+/// For H.264 only IDR pictures count as keyframes, since decoding can't start at other
+/// pictures marked as keyframes without a recovery point.
+#[derive(Default)]
+pub struct GopAnalyzer {
+    tracks: HashMap<u32, GopStats>,
+}
+
+impl GopAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a video packet towards its track and passes it through unchanged.
+    pub fn filter(&mut self, packet: Packet) -> Packet {
+        if !matches!(packet.track.info.kind, MediaKind::Video(_)) {
+            return packet;
+        }
+
+        let stats = self.tracks.entry(packet.track.id).or_default();
+        stats.frames += 1;
+
+        let key = match framing(&packet.track.info) {
+            Some(framing) => {
+                let Some((slice_type, idr)) = h264_picture(&packet.buffer, framing) else {
+                    // parameter sets or SEI without a picture
+                    stats.frames -= 1;
+                    return packet;
+                };
+
+                match slice_type % 5 {
+                    0 | 3 => stats.p_frames += 1,
+                    1 => stats.b_frames += 1,
+                    _ => stats.i_frames += 1,
+                }
+
+                idr
+            }
+            None => packet.key,
+        };
+
+        if key {
+            let time = &packet.time;
+            stats.keyframes.push(to_duration(time.pts, time.timebase));
+            stats.gop_lengths.push(1);
+        } else if let Some(length) = stats.gop_lengths.last_mut() {
+            *length += 1;
+        } else {
+            stats.leading_frames += 1;
+        }
+
+        packet
+    }
+
+    pub fn stats(&self, track_id: u32) -> Option<&GopStats> {
+        self.tracks.get(&track_id)
+    }
+}
+
+/// Timestamps which jump ahead by more than this are counted as a gap by [SyncAnalyzer].
+const DEFAULT_GAP_THRESHOLD: Duration = Duration::from_millis(250);
+
+/// The clock [SyncAnalyzer] measures the progression of timestamps against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncReference {
+    /// When packets arrive, for live inputs.
+    #[default]
+    WallClock,
+    /// The timestamps of another track.
+    Track(u32),
+}
+
+/// How the timestamps of a track progressed, as found by [SyncAnalyzer].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncStats {
+    pub packets: u64,
+    /// The presentation time of the first packet.
+    pub start: Duration,
+    /// The latest presentation time a packet ended at.
+    pub end: Duration,
+    /// Where timestamps jumped ahead, and by how much.
+    pub gaps: Vec<(Duration, Duration)>,
+    /// How far the track is ahead of the reference at its last packet, in seconds. Negative when
+    /// it is behind.
+    pub drift: f64,
+    /// The drift furthest from zero seen at any packet, in seconds.
+    pub max_drift: f64,
+}
+
+impl SyncStats {
+    /// The total length of all gaps.
+    pub fn gap_duration(&self) -> Duration {
+        self.gaps.iter().map(|(_, length)| *length).sum()
+    }
+}
+
+/// Tracks how the timestamps of every track progress compared to a [SyncReference], to find
+/// inputs whose tracks drift apart or which have holes in them.
+///
+/// All tracks are measured from the first packet of the input, so a track starting later than
+/// the others starts out with a drift.
+///
+/// Filter packets with [SyncAnalyzer::filter] as in the [module example](self).
+pub struct SyncAnalyzer {
+    reference: SyncReference,
+    gap_threshold: Duration,
+    /// The presentation time and arrival of the first packet.
+    origin: Option<(Duration, Instant)>,
+    tracks: HashMap<u32, SyncStats>,
+}
+
+impl Default for SyncAnalyzer {
+    fn default() -> Self {
+        SyncAnalyzer {
+            reference: SyncReference::default(),
+            gap_threshold: DEFAULT_GAP_THRESHOLD,
+            origin: None,
+            tracks: HashMap::new(),
+        }
+    }
+}
+
+impl SyncAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_reference(mut self, reference: SyncReference) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    /// Sets how far timestamps have to jump ahead to be counted as a gap.
+    pub fn with_gap_threshold(mut self, threshold: Duration) -> Self {
+        self.gap_threshold = threshold;
+        self
+    }
+
+    /// Measures a packet which arrived just now and passes it through unchanged.
+    pub fn filter(&mut self, packet: Packet) -> Packet {
+        self.filter_at(packet, Instant::now())
+    }
+
+    /// Measures a packet which arrived at `arrival`, which is only used with
+    /// [SyncReference::WallClock].
+    pub fn filter_at(&mut self, packet: Packet, arrival: Instant) -> Packet {
+        let time = &packet.time;
+        let start = to_duration(time.pts, time.timebase);
+        let end = to_duration(time.pts + time.duration.unwrap_or(0), time.timebase);
+        let (origin, first_arrival) = *self.origin.get_or_insert((start, arrival));
+
+        let reference = match self.reference {
+            SyncReference::WallClock => Some(arrival.saturating_duration_since(first_arrival)),
+            SyncReference::Track(id) if id == packet.track.id => None,
+            SyncReference::Track(id) => self
+                .tracks
+                .get(&id)
+                .map(|stats| stats.end.saturating_sub(origin)),
+        };
+
+        let stats = self.tracks.entry(packet.track.id).or_insert(SyncStats {
+            start,
+            end,
+            ..Default::default()
+        });
+        stats.packets += 1;
+
+        if start > stats.end + self.gap_threshold {
+            stats.gaps.push((stats.end, start - stats.end));
+        }
+        stats.end = stats.end.max(end);
+
+        if let Some(reference) = reference {
+            let elapsed = stats.end.saturating_sub(origin);
+            stats.drift = (elapsed.as_nanos() as i128 - reference.as_nanos() as i128) as f64 / 1e9;
+            if stats.drift.abs() > stats.max_drift.abs() {
+                stats.max_drift = stats.drift;
+            }
+        }
+
+        packet
+    }
+
+    pub fn stats(&self, track_id: u32) -> Option<&SyncStats> {
+        self.tracks.get(&track_id)
+    }
+}
+
+/// Cuts the packets between two times out of an input, with timestamps starting at zero.
+///
+/// The output starts at the last keyframe at or before the start, so that it can be decoded
+/// from its first packet. Keyframes are taken from the video track, or the audio track if there
+/// is no video. Packets are only dropped as a whole, so audio may extend slightly past the
+/// range.
+///
+/// Start with [Clipper::start] and filter packets as in the [module example](self), writing every
+/// returned packet and stopping once [Clipper::is_finished].
+pub struct Clipper {
+    start: Duration,
+    end: Option<Duration>,
+    reference: Option<u32>,
+    /// The packets from the last keyframe before the start, until the start is reached.
+    pending: Vec<Packet>,
+    /// The time in the input the output starts at, once it is known.
+    origin: Option<Duration>,
+    finished: bool,
+}
+
+impl Clipper {
+    pub fn new(start: Duration) -> Self {
+        Clipper {
+            start,
+            end: None,
+            reference: None,
+            pending: Vec::new(),
+            origin: None,
+            finished: false,
+        }
+    }
+
+    /// Drops all packets presented at or after `end`.
+    pub fn with_end(mut self, end: Duration) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    /// Chooses the track whose keyframes the output starts at.
+    pub fn start(&mut self, tracks: Vec<Track>) -> Vec<Track> {
+        self.reference = tracks
+            .iter()
+            .find(|t| matches!(t.info.kind, MediaKind::Video(_)))
+            .or_else(|| {
+                tracks
+                    .iter()
+                    .find(|t| matches!(t.info.kind, MediaKind::Audio(_)))
+            })
+            .map(|t| t.id);
+
+        tracks
+    }
+
+    /// Whether the end has been reached by the keyframe track, after which no more packets
+    /// will be returned.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the packets to write, which may include packets held back earlier.
+    pub fn filter(&mut self, packet: Packet) -> Vec<Packet> {
+        let time = &packet.time;
+        let pts = to_duration(time.pts, time.timebase);
+        let is_reference = self.reference.is_none_or(|id| id == packet.track.id);
+
+        if self.end.is_some_and(|end| pts >= end) {
+            self.finished |= is_reference;
+            return Vec::new();
+        }
+
+        if self.finished {
+            return Vec::new();
+        }
+
+        let Some(origin) = self.origin else {
+            return self.find_origin(packet, pts, is_reference);
+        };
+
+        if pts < origin {
+            return Vec::new();
+        }
+
+        vec![shift(packet, origin)]
+    }
+
+    fn find_origin(&mut self, packet: Packet, pts: Duration, is_reference: bool) -> Vec<Packet> {
+        let keyframe = is_reference && packet.key;
+
+        // other tracks are held back until the keyframe track passes the start
+        if pts < self.start || (keyframe && pts == self.start) || !is_reference {
+            if keyframe {
+                self.pending.clear();
+            }
+
+            // packets before the first keyframe can't be decoded
+            if keyframe || !self.pending.is_empty() {
+                self.pending.push(packet);
+            }
+
+            return Vec::new();
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        let Some(first) = pending.first().or(keyframe.then_some(&packet)) else {
+            trace!("Dropping packet before first keyframe: {packet:?}");
+            return Vec::new();
+        };
+
+        let time = &first.time;
+        let origin = to_duration(time.dts.unwrap_or(time.pts), time.timebase);
+        self.origin = Some(origin);
+        debug!("Starting clip at {origin:?}");
+
+        pending
+            .into_iter()
+            .chain([packet])
+            .filter(|pkt| to_duration(pkt.time.pts, pkt.time.timebase) >= origin)
+            .map(|pkt| shift(pkt, origin))
+            .collect()
+    }
+}
+
+/// Moves the timestamps of a packet earlier by `origin`.
+fn shift(mut packet: Packet, origin: Duration) -> Packet {
+    let time = &mut packet.time;
+    let offset = from_duration(origin, time.timebase);
+    time.pts = time.pts.saturating_sub(offset);
+    time.dts = time.dts.map(|dts| dts.saturating_sub(offset));
+
+    packet
+}
+
+/// What [TimestampSanitizer] does with a packet which isn't decoded after the previous packet of
+/// its track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// Drops the packet.
+    Drop,
+    /// Shifts the packet and all following packets of the track so that it comes right after the
+    /// previous one, e.g. to join a stream whose timestamps restart.
+    Offset,
+    /// Moves only the packet to right after the previous one.
+    Clamp,
+}
+
+/// What [TimestampSanitizer] found in the packets of a track.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimestampStats {
+    pub packets: u64,
+    /// Packets with the same decode timestamp as the previous packet.
+    pub duplicated: u64,
+    /// Packets with an earlier decode timestamp than the previous packet.
+    pub non_monotonic: u64,
+    pub dropped: u64,
+    /// The number of ticks packets are currently shifted by with [TimestampPolicy::Offset].
+    pub offset: u64,
+}
+
+/// Repairs tracks whose decode timestamps are duplicated or go backwards, which muxers would
+/// otherwise write verbatim. Packets without a decode timestamp are checked by their
+/// presentation timestamp, so reordered video should be passed through [TimebaseMapper] first.
+///
+/// Filter packets with [TimestampSanitizer::filter] as in the [module example](self), skipping
+/// dropped ones.
+pub struct TimestampSanitizer {
+    policy: TimestampPolicy,
+    tracks: HashMap<u32, SanitizedTrack>,
+}
+
+#[derive(Default)]
+struct SanitizedTrack {
+    stats: TimestampStats,
+    last: Option<MediaTime>,
+}
+
+impl TimestampSanitizer {
+    pub fn new(policy: TimestampPolicy) -> Self {
+        TimestampSanitizer {
+            policy,
+            tracks: HashMap::new(),
+        }
+    }
+
+    pub fn filter(&mut self, mut packet: Packet) -> Option<Packet> {
+        let track = self.tracks.entry(packet.track.id).or_default();
+        let stats = &mut track.stats;
+        let time = &mut packet.time;
+
+        stats.packets += 1;
+        time.pts += stats.offset;
+        time.dts = time.dts.map(|dts| dts + stats.offset);
+
+        let Some(last) = &track.last else {
+            track.last = Some(time.clone());
+            return Some(packet);
+        };
+
+        let prev = last.dts.unwrap_or(last.pts);
+        let current = time.dts.unwrap_or(time.pts);
+        if current > prev {
+            track.last = Some(time.clone());
+            return Some(packet);
+        }
+
+        if current == prev {
+            stats.duplicated += 1;
+        } else {
+            stats.non_monotonic += 1;
+        }
+
+        debug!(
+            "Track {} goes from {prev} to {current}, applying {:?}",
+            packet.track.id, self.policy
+        );
+
+        // the earliest the packet can be decoded without overlapping the previous one
+        let next = prev + last.duration.filter(|&d| d > 0).unwrap_or(1);
+        match self.policy {
+            TimestampPolicy::Drop => {
+                stats.dropped += 1;
+                return None;
+            }
+            TimestampPolicy::Offset => {
+                let shift = next - current;
+                stats.offset += shift;
+                time.pts += shift;
+                time.dts = time.dts.map(|dts| dts + shift);
+            }
+            TimestampPolicy::Clamp => match &mut time.dts {
+                Some(dts) => {
+                    *dts = next;
+                    time.pts = time.pts.max(next);
+                }
+                None => time.pts = next,
+            },
+        }
+
+        track.last = Some(time.clone());
+
+        Some(packet)
+    }
+
+    /// The statistics of a track, if any of its packets have been filtered.
+    pub fn stats(&self, track_id: u32) -> Option<&TimestampStats> {
+        self.tracks.get(&track_id).map(|track| &track.stats)
+    }
+}
+
+fn nal_unit_type(nal: &Span) -> Option<u8> {
+    nal.spans().next().and_then(|b| b.first()).map(|b| b & 0x1f)
+}
+
+fn framing(info: &MediaInfo) -> Option<BitstreamFraming> {
+    match &info.kind {
+        MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(H264Codec {
+                bitstream_format, ..
+            }),
+            ..
+        }) => Some(*bitstream_format),
+        _ => None,
+    }
+}
+
+/// Reads the `slice_type` of the first slice in a H.264 access unit, and whether it is an IDR
+/// picture.
+fn h264_picture(buffer: &Span, framing: BitstreamFraming) -> Option<(u32, bool)> {
+    use h264_reader::rbsp::{decode_nal, BitRead, BitReader};
+
+    let nal = nal_units(buffer, framing)
+        .find(|nal| nal.len() > 1 && matches!(nal.slice(..1).to_slice()[0] & 0x1f, 1 | 5))?;
+
+    // the slice type comes right after `first_mb_in_slice`, well within the first bytes
+    let header = nal.slice(..nal.len().min(16));
+    let header = header.to_slice();
+    let rbsp = decode_nal(&header).ok()?;
+    let mut reader = BitReader::new(&rbsp[..]);
+    reader.read_ue("first_mb_in_slice").ok()?;
+    let slice_type = reader.read_ue("slice_type").ok()?;
+
+    Some((slice_type, header[0] & 0x1f == 5))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test;
+    use test_case::test_case;
+
+    #[test]
+    fn annexb_to_length_prefixed() {
+        let track = test::h264_track_with_framing(BitstreamFraming::FourByteStartCode);
+
+        let mut filter = BitstreamConverterFilter::new(BitstreamFraming::FourByteLength);
+        let tracks = filter.start(vec![track.clone()]);
+        assert_eq!(
+            Some(BitstreamFraming::FourByteLength),
+            framing(&tracks[0].info)
+        );
+
+        let packet = test::packet(
+            &track,
+            0,
+            None,
+            vec![0, 0, 0, 1, 0x65, 0xaa, 0, 0, 0, 1, 0x06, 0xbb],
+        );
+
+        let packet = filter.filter(packet);
+        assert_eq!(
+            &[0, 0, 0, 2, 0x65, 0xaa, 0, 0, 0, 2, 0x06, 0xbb],
+            &*packet.buffer.to_slice()
+        );
+        assert_eq!(
+            Some(BitstreamFraming::FourByteLength),
+            framing(&packet.track.info)
+        );
+    }
+
+    #[test]
+    fn rescale_and_synthesize_dts() {
+        let track = Track {
+            timebase: Fraction::new(1, 1000),
+            ..test::h264_track()
+        };
+
+        let mut mapper = TimebaseMapper::new()
+            .with_timebase(Fraction::new(1, 90_000))
+            .with_reorder_depth(1);
+        let tracks = mapper.start(vec![track.clone()]);
+        assert_eq!(90_000, tracks[0].timebase.denominator);
+
+        // I P B B in decode order
+        let times = [40, 160, 80, 120]
+            .into_iter()
+            .map(|pts| {
+                let packet = Packet {
+                    time: MediaTime {
+                        pts,
+                        dts: None,
+                        duration: Some(40),
+                        timebase: track.timebase,
+                    },
+                    key: pts == 40,
+                    track: track.clone(),
+                    buffer: vec![].into(),
+                    side_data: Vec::new(),
+                };
+
+                let time = mapper.filter(packet).time;
+                (time.pts, time.dts.unwrap(), time.duration.unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (3600, 3599, 3600),
+                (14400, 3600, 3600),
+                (7200, 7200, 3600),
+                (10800, 10800, 3600)
+            ],
+            times
+        );
+    }
+
+    #[test_case(TimestampPolicy::Drop, &[0, 3000, 6000], 0)]
+    #[test_case(TimestampPolicy::Offset, &[0, 3000, 6000, 11_000], 5000)]
+    #[test_case(TimestampPolicy::Clamp, &[0, 3000, 6000, 9000], 0)]
+    fn sanitize_timestamps(policy: TimestampPolicy, expected: &[u64], offset: u64) {
+        let track = test::h264_track();
+
+        let mut sanitizer = TimestampSanitizer::new(policy);
+        let times = [0, 3000, 1000, 6000]
+            .into_iter()
+            .filter_map(|pts| sanitizer.filter(test::packet(&track, pts, Some(3000), Vec::new())))
+            .map(|pkt| pkt.time.pts)
+            .collect::<Vec<_>>();
+
+        assert_eq!(expected, times);
+
+        let stats = sanitizer.stats(track.id).unwrap();
+        assert_eq!(4, stats.packets);
+        assert_eq!(offset, stats.offset);
+    }
+
+    #[test]
+    fn estimate_bitrate() {
+        let track = Track {
+            timebase: Fraction::new(1, 1000),
+            ..test::h264_track()
+        };
+
+        let mut estimator = BitrateEstimator::new();
+        for pts in (1000..3000).step_by(40) {
+            estimator.filter(test::packet(&track, pts, Some(40), vec![0u8; 500]));
+        }
+
+        let mut movie = Movie {
+            tracks: vec![track],
+            ..Default::default()
+        };
+        estimator.apply(&mut movie);
+
+        // 50 packets of 500 B over 2 s
+        assert_eq!(Some(Duration::from_secs(2)), movie.duration());
+        assert_eq!(Some(100_000), movie.tracks[0].bitrate());
+
+        let stats = estimator.stats(movie.tracks[0].id).unwrap();
+        assert_eq!((50, 25_000), (stats.packets, stats.bytes));
+        assert_eq!(Some(Duration::from_millis(2960)), stats.max_pts);
+        assert_eq!(Some(25.0), stats.frame_rate());
+    }
+
+    fn sine(sample_rate: u32, frequency: f64, level: f64, phase: f64, seconds: f64) -> AudioFrame {
+        let amplitude = 10f64.powf(level / 20.0);
+        let samples = (0..(sample_rate as f64 * seconds) as usize)
+            .map(|n| {
+                let t = n as f64 / sample_rate as f64;
+                (amplitude * (std::f64::consts::TAU * frequency * t + phase).sin()) as f32
+            })
+            .flat_map(|sample| [sample, sample])
+            .collect();
+
+        AudioFrame {
+            time: MediaTime {
+                pts: 0,
+                dts: None,
+                duration: None,
+                timebase: Fraction::new(1, sample_rate),
+            },
+            sample_rate,
+            channels: 2,
+            samples,
+        }
+    }
+
+    // a stereo 1 kHz sine at -23 dBFS is -23 LUFS according to EBU Tech 3341
+    #[test_case(48000)]
+    #[test_case(44100)]
+    fn loudness_of_sine(sample_rate: u32) {
+        let mut analyzer = LoudnessAnalyzer::new();
+        analyzer.filter(1, &sine(sample_rate, 1000.0, -23.0, 0.0, 10.0));
+
+        let loudness = analyzer.loudness(1).unwrap();
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "{loudness:?}");
+        assert!((loudness.momentary_max + 23.0).abs() < 0.1, "{loudness:?}");
+        assert!((loudness.true_peak + 23.0).abs() < 0.1, "{loudness:?}");
+        assert!((analyzer.momentary(1).unwrap() + 23.0).abs() < 0.1);
+        assert!(analyzer.loudness(2).is_none());
+    }
+
+    #[test]
+    fn gate_quiet_blocks() {
+        let mut analyzer = LoudnessAnalyzer::new();
+        analyzer.filter(1, &sine(48000, 1000.0, -23.0, 0.0, 10.0));
+        analyzer.filter(1, &sine(48000, 1000.0, -40.0, 0.0, 10.0));
+        analyzer.filter(1, &sine(48000, 1000.0, -100.0, 0.0, 10.0));
+
+        let loudness = analyzer.loudness(1).unwrap();
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "{loudness:?}");
+
+        analyzer.filter(2, &sine(48000, 1000.0, -100.0, 0.0, 1.0));
+        assert_eq!(f64::NEG_INFINITY, analyzer.loudness(2).unwrap().integrated);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // the samples of a quarter of the sample rate shifted by 45° miss the peaks by 3 dB
+        let mut analyzer = LoudnessAnalyzer::new();
+        let frame = sine(48000, 12000.0, -1.0, std::f64::consts::FRAC_PI_4, 1.0);
+        analyzer.filter(1, &frame);
+
+        let sample_peak = frame.samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!((20.0 * sample_peak.log10() + 4.01).abs() < 0.1);
+
+        let loudness = analyzer.loudness(1).unwrap();
+        assert!((loudness.true_peak + 1.0).abs() < 0.3, "{loudness:?}");
+    }
+
+    #[tokio::test]
+    async fn analyze_gops() {
+        use crate::format::testsrc::TestSrcDemuxer;
+
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(4)).with_color_bars(
+            64,
+            64,
+            Fraction::new(25, 1),
+        );
+        let (_, mut packets) = crate::test::read_movie_and_packets(&mut source).await;
+
+        // a B slice in place of a P slice
+        packets[1].buffer = vec![0, 0, 0, 2, 0x01, 0x9c].into();
+
+        let mut analyzer = GopAnalyzer::new();
+        for pkt in &packets {
+            analyzer.filter(pkt.clone());
+        }
+
+        let stats = analyzer.stats(0).unwrap();
+        assert_eq!(vec![25; 4], stats.gop_lengths);
+        assert_eq!((4, 95, 1), (stats.i_frames, stats.p_frames, stats.b_frames));
+        assert_eq!(vec![Duration::from_secs(1); 3], stats.keyframe_intervals());
+        assert!(stats.is_regular());
+
+        // the second IDR picture is replaced by a P slice
+        packets[25].buffer = packets[2].buffer.clone();
+
+        let mut analyzer = GopAnalyzer::new();
+        for pkt in &packets {
+            analyzer.filter(pkt.clone());
+        }
+
+        let stats = analyzer.stats(0).unwrap();
+        assert_eq!(vec![50, 25, 25], stats.gop_lengths);
+        assert!(!stats.is_regular());
+
+        // the input starts in the middle of a GOP
+        let mut analyzer = GopAnalyzer::new();
+        for pkt in packets.into_iter().skip(60) {
+            analyzer.filter(pkt);
+        }
+
+        let stats = analyzer.stats(0).unwrap();
+        assert_eq!(
+            (15, vec![25]),
+            (stats.leading_frames, stats.gop_lengths.clone())
+        );
+        assert!(!stats.is_regular());
+    }
+
+    #[tokio::test]
+    async fn annotate_random_access() {
+        use crate::format::testsrc::TestSrcDemuxer;
+
+        let mut source = TestSrcDemuxer::new(Duration::from_millis(120)).with_color_bars(
+            64,
+            64,
+            Fraction::new(25, 1),
+        );
+        let (_, mut packets) = crate::test::read_movie_and_packets(&mut source).await;
+
+        // a recovery point SEI with recovery_frame_cnt 0 and exact_match_flag before a P slice
+        let sei = [0, 0, 0, 5, 0x06, 0x06, 0x01, 0xc4, 0x80];
+        packets[2].buffer = [Span::from(sei.to_vec()), packets[2].buffer.clone()]
+            .into_iter()
+            .collect();
+
+        let annotator = RandomAccessAnnotator::new().with_sei_messages();
+        let packets = packets
+            .into_iter()
+            .map(|pkt| annotator.filter(pkt))
+            .collect::<Vec<_>>();
+
+        let recovery = RandomAccess::Recovery {
+            frames: 0,
+            exact_match: true,
+            broken_link: false,
+        };
+        let access = packets
+            .iter()
+            .map(Packet::random_access)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Some(RandomAccess::Idr), None, Some(recovery)], access);
+
+        let sei = SideData::Sei {
+            payload_type: RECOVERY_POINT,
+            payload: vec![0xc4],
+        };
+        assert_eq!(sei, packets[2].side_data[0]);
+    }
+
+    #[test]
+    fn retime_subtitles() {
+        use crate::codec::{AssCodec, SubtitleCodec, SubtitleInfo};
+
+        let subtitles = Track {
+            info: Arc::new(MediaInfo {
+                name: "ass",
+                kind: MediaKind::Subtitle(SubtitleInfo {
+                    codec: SubtitleCodec::Ass(AssCodec {
+                        header: String::new(),
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 1000),
+            ..test::h264_track()
+        };
+        let video = test::h264_track();
+
+        let packet = |track: &Track, pts| test::packet(track, pts, Some(2000), Vec::new());
+
+        let retimer = SubtitleRetimer::new()
+            .with_advance(Duration::from_millis(2500))
+            .with_scale(1.5);
+        let times = [1000, 10_000]
+            .into_iter()
+            .map(|pts| retimer.filter(packet(&subtitles, pts)).time)
+            .map(|time| (time.pts, time.duration.unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![(0, 2000), (12_500, 2000)], times);
+        assert_eq!(1000, retimer.filter(packet(&video, 1000)).time.pts);
+    }
+
+    #[tokio::test]
+    async fn rewrite_parameter_sets() {
+        use crate::format::testsrc::TestSrcDemuxer;
+
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(2)).with_color_bars(
+            64,
+            64,
+            Fraction::new(25, 1),
+        );
+        let (movie, mut packets) = crate::test::read_movie_and_packets(&mut source).await;
+
+        let MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(original),
+            ..
+        }) = &movie.tracks[0].info.kind
+        else {
+            panic!("Expected H.264");
+        };
+
+        // the second keyframe carries an access unit delimiter and its own SPS
+        let mut nals =
+            nal_units(&packets[25].buffer, BitstreamFraming::FourByteLength).collect::<Vec<_>>();
+        nals.splice(0..0, [vec![0x09, 0x10].into(), original.sps.clone()]);
+        packets[25].buffer = frame_nal_units(&nals, BitstreamFraming::FourByteLength);
+
+        let mut filter = SpsRewriteFilter::new(SpsRewrite::new().with_level(31));
+        let tracks = filter.start(movie.tracks.clone());
+        let MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(codec),
+            ..
+        }) = &tracks[0].info.kind
+        else {
+            panic!("Expected H.264");
+        };
+        assert_eq!(31, codec.level_indication);
+        assert_ne!(original.sps.to_slice(), codec.sps.to_slice());
+
+        let nals_of = |pkt: &Packet| {
+            nal_units(&pkt.buffer, BitstreamFraming::FourByteLength)
+                .map(|nal| nal.to_slice().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let first = filter.filter(packets[0].clone());
+        let nals = nals_of(&first);
+        assert_eq!(3, nals.len());
+        assert_eq!(codec.sps.to_slice(), nals[0]);
+        assert_eq!(codec.pps.to_slice(), nals[1]);
+        assert_eq!(nals_of(&packets[0])[0], nals[2]);
+        assert!(Arc::ptr_eq(&tracks[0].info, &first.track.info));
+
+        let second = filter.filter(packets[1].clone());
+        assert_eq!(nals_of(&packets[1]), nals_of(&second));
+
+        let nals = nals_of(&filter.filter(packets[25].clone()));
+        assert_eq!(3, nals.len());
+        assert_eq!(vec![0x09, 0x10], nals[0]);
+        assert_eq!(codec.sps.to_slice(), nals[1]);
+    }
+
+    #[test]
+    fn measure_sync() {
+        let video = Track {
+            timebase: Fraction::new(1, 1000),
+            ..test::h264_track()
+        };
+        let audio = Track {
+            id: 2,
+            ..video.clone()
+        };
+
+        let packet =
+            |track: &Track, pts, duration| test::packet(track, pts, Some(duration), Vec::new());
+
+        // the source delivers a 40 ms frame every 41 ms
+        let arrival = Instant::now();
+        let mut analyzer = SyncAnalyzer::new();
+        for n in 0..100 {
+            let pkt = packet(&video, n * 40, 40);
+            analyzer.filter_at(pkt, arrival + Duration::from_millis(n * 41));
+        }
+
+        let stats = analyzer.stats(1).unwrap();
+        assert_eq!(100, stats.packets);
+        assert_eq!(Duration::from_secs(4), stats.end);
+        assert_eq!(-0.059, stats.drift);
+
+        // the audio skips ahead by half a second after two seconds
+        let mut analyzer = SyncAnalyzer::new().with_reference(SyncReference::Track(1));
+        for n in 0..100 {
+            analyzer.filter(packet(&video, n * 40, 40));
+            for n in [n * 2, n * 2 + 1] {
+                let skip = if n >= 100 { 500 } else { 0 };
+                analyzer.filter(packet(&audio, n * 20 + skip, 20));
+            }
+        }
+
+        let stats = analyzer.stats(2).unwrap();
+        assert_eq!(
+            vec![(Duration::from_secs(2), Duration::from_millis(500))],
+            stats.gaps
+        );
+        assert!((stats.drift - 0.5).abs() < 0.05, "{}", stats.drift);
+        assert_eq!(stats.drift, stats.max_drift);
+        assert_eq!(0.0, analyzer.stats(1).unwrap().drift);
+    }
+
+    #[tokio::test]
+    async fn clip_from_keyframe() {
+        use crate::format::testsrc::TestSrcDemuxer;
+
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(4))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_sine(1000.0, 48_000);
+        let (movie, packets) = crate::test::read_movie_and_packets(&mut source).await;
+
+        let mut clipper =
+            Clipper::new(Duration::from_millis(1500)).with_end(Duration::from_millis(2500));
+        clipper.start(movie.tracks);
+
+        let mut clipped = Vec::new();
+        for pkt in packets {
+            clipped.extend(clipper.filter(pkt));
+            if clipper.is_finished() {
+                break;
+            }
+        }
+        assert!(clipper.is_finished());
+
+        // the clip starts at the keyframe at one second
+        let (video, audio): (Vec<_>, Vec<_>) = clipped.iter().partition(|p| p.track.id == 0);
+        assert_eq!(38, video.len());
+        assert!(video[0].key);
+        assert_eq!(0, video[0].time.pts);
+        assert_eq!(71, audio.len());
+        assert_eq!(
+            Duration::from_millis(1480),
+            to_duration(video[37].time.pts, video[37].time.timebase)
+        );
+    }
+}
@@ -1,49 +1,125 @@
-use std::{cmp::Ordering, fmt::Debug};
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Debug, str::FromStr, time::Duration};
 
 use async_trait::async_trait;
+use futures::stream::{self, LocalBoxStream, StreamExt};
 
 use crate::{
-    io::Io, AacCodec, AudioCodec, H264Codec, MediaTrackExt, Packet, Span, Track, VideoCodec,
+    io::Io, AacCodec, MediaboxError, AudioCodec, H264Codec, MediaInfo, MediaTrackExt, Packet, Span, Track, VideoCodec,
 };
 
 use std::fmt::Write;
 
-// pub mod hls;
+pub mod adts;
+pub mod ass;
+pub mod concat;
+pub mod flac;
+pub mod h264;
+#[cfg(feature = "hls")]
+pub mod hls;
+#[cfg(feature = "http-server")]
+pub mod http;
+pub mod interleave;
+pub mod merge;
+pub mod metrics;
 pub mod mkv;
 pub mod mp4;
+pub mod mse;
+pub mod ogg;
+pub mod probe;
 
 #[cfg(feature = "rtmp")]
 pub mod rtmp;
+#[cfg(feature = "rtsp")]
+pub mod rtsp;
+pub mod tee;
+pub mod testsrc;
+pub mod wav;
 pub mod webvtt;
 
-/// Registers a demuxer with mediabox
+/// Registers a demuxer with mediabox, optionally with URI patterns, see
+/// [`DemuxerMetadata::probe_uri`].
 #[macro_export]
 macro_rules! demuxer {
     ($name:literal, $create:expr, $probe:expr) => {
+        $crate::demuxer!($name, $create, $probe, []);
+    };
+    ($name:literal, $create:expr, $probe:expr, [$($pattern:literal),*]) => {
         pub const DEMUXER_META: $crate::format::DemuxerMetadata = $crate::format::DemuxerMetadata {
             name: $name,
             create: $create,
             probe: $probe,
+            patterns: &[$($pattern),*],
         };
     };
 }
 
-/// Registers a muxer with mediabox
+/// Registers a muxer with mediabox and the codecs it can store, by [`MediaInfo::name`],
+/// optionally with a constructor taking [`MuxerOptions`].
 #[macro_export]
 macro_rules! muxer {
-    ($name:literal, $create:expr) => {
+    ($name:literal, $create:expr, [$($codec:literal),*]) => {
+        pub const MUXER_META: $crate::format::MuxerMetadata = $crate::format::MuxerMetadata {
+            name: $name,
+            create: $create,
+            create_with_options: None,
+            codecs: &[$($codec),*],
+        };
+    };
+    ($name:literal, $create:expr, $create_with_options:expr, [$($codec:literal),*]) => {
         pub const MUXER_META: $crate::format::MuxerMetadata = $crate::format::MuxerMetadata {
             name: $name,
             create: $create,
+            create_with_options: Some($create_with_options),
+            codecs: &[$($codec),*],
         };
     };
 }
 
 #[async_trait(?Send)]
 pub trait Demuxer {
-    async fn start(&mut self) -> anyhow::Result<Movie>;
-    async fn read(&mut self) -> anyhow::Result<Packet>;
-    async fn stop(&mut self) -> anyhow::Result<()>;
+    async fn start(&mut self) -> crate::Result<Movie>;
+    async fn read(&mut self) -> crate::Result<Packet>;
+    async fn stop(&mut self) -> crate::Result<()>;
+
+    /// Captures the position and state of the demuxer between two packets, so that reading can
+    /// be continued later with [`Demuxer::resume`].
+    async fn save_state(&mut self) -> crate::Result<ResumeState> {
+        Err(MediaboxError::unsupported("Demuxer does not support resuming"))
+    }
+
+    /// Starts the demuxer from a previously saved state instead of the beginning of the input.
+    /// This is used instead of [`Demuxer::start`] and requires a seekable input.
+    async fn resume(&mut self, state: &ResumeState) -> crate::Result<Movie> {
+        Err(MediaboxError::unsupported("Demuxer does not support resuming"))
+    }
+
+    /// Reads the rest of the input and returns its container structure, e.g. the element tree of
+    /// a Matroska file. This is used instead of [`Demuxer::start`], to debug inputs.
+    async fn dump_structure(&mut self) -> crate::Result<Vec<StructureNode>> {
+        Err(MediaboxError::unsupported("Demuxer does not support dumping its structure"))
+    }
+
+    /// Returns the packets of the demuxer as a stream, which ends at the end of the input. Other
+    /// errors are yielded once, after which the stream ends.
+    fn packets(&mut self) -> LocalBoxStream<'_, crate::Result<Packet>> {
+        stream::unfold(Some(self), |demuxer| async move {
+            let demuxer = demuxer?;
+
+            match demuxer.read().await {
+                Ok(pkt) => Some((Ok(pkt), Some(demuxer))),
+                Err(e) if e.is_end_of_input() => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+        .boxed_local()
+    }
+
+    /// Takes the next event which happened before the packet last returned by
+    /// [`Demuxer::read`]. Only live sources which can restart or change mid-stream have events,
+    /// so callers should handle these before writing the packet.
+    fn next_event(&mut self) -> Option<DemuxerEvent> {
+        None
+    }
 
     fn create(io: Io) -> Box<dyn Demuxer>
     where
@@ -62,26 +138,129 @@ pub trait Demuxer {
 #[async_trait]
 pub trait Muxer: Send {
     /// Starts the muxer with the given tracks.
-    async fn start(&mut self, tracks: Vec<Track>) -> anyhow::Result<()>;
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()>;
+
+    /// Starts the muxer with the tracks of a movie. Formats which can store more than the tracks,
+    /// e.g. attachments, write those as well.
+    async fn start_movie(&mut self, movie: Movie) -> crate::Result<()> {
+        self.start(movie.tracks).await
+    }
 
     /// Writes a packet to the muxer.
     ///
     /// Note that this does not ensure something will be written to the output, as it may buffer
     /// packets internally in order to write its output correctly.
-    async fn write(&mut self, packet: Packet) -> anyhow::Result<()>;
+    async fn write(&mut self, packet: Packet) -> crate::Result<()>;
 
     /// Stops the muxer. This will flush any buffered packets and finalize the output if
     /// appropriate.
-    async fn stop(&mut self) -> anyhow::Result<()>;
+    async fn stop(&mut self) -> crate::Result<()>;
+
+    /// Reacts to a change of the input, e.g. by writing a new header. Muxers which can't
+    /// represent the change ignore it.
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        Ok(())
+    }
 
     fn into_io(self) -> Io;
 }
 
+/// Stands in for demuxers which wrap another demuxer or are handed out by a server, and so can't
+/// be created from an [Io]. Every read fails with [`MediaboxError::Unsupported`].
+pub(crate) struct UnsupportedDemuxer(pub &'static str);
+
+#[async_trait(?Send)]
+impl Demuxer for UnsupportedDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        Err(MediaboxError::unsupported(self.0))
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        Err(MediaboxError::unsupported(self.0))
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn create(_io: Io) -> Box<dyn Demuxer> {
+        Box::new(UnsupportedDemuxer("demuxer"))
+    }
+}
+
+/// Changes of a live input which demuxers report in between packets, see
+/// [`Demuxer::next_event`].
+#[derive(Debug, Clone)]
+pub enum DemuxerEvent {
+    /// The input started over with new tracks, e.g. after a publisher reconnected.
+    NewMovie(Movie),
+
+    /// The timestamps of the following packets don't continue from the previous ones.
+    Discontinuity,
+
+    /// The codec parameters of a track changed, e.g. an H.264 stream got a new SPS.
+    TrackParametersChanged(Track),
+}
+
+/// The state needed to resume demuxing an input, see [`Demuxer::save_state`].
+///
+/// The state only refers to the input by byte offsets and can be stored with
+/// [`ResumeState::to_bytes`] to continue after a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeState {
+    /// Offset of the next element to read in the input.
+    pub offset: u64,
+    /// Demuxer specific position, e.g. the timestamp of the current cluster.
+    pub timestamp: u64,
+    /// Copy of the headers needed to restore the tracks without reading them from the input.
+    pub headers: Vec<u8>,
+}
+
+impl ResumeState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(16 + self.headers.len());
+        data.extend_from_slice(&self.offset.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.headers);
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < 16 {
+            return None;
+        }
+
+        let (offset, rest) = data.split_at(8);
+        let (timestamp, headers) = rest.split_at(8);
+
+        Some(ResumeState {
+            offset: u64::from_be_bytes(offset.try_into().ok()?),
+            timestamp: u64::from_be_bytes(timestamp.try_into().ok()?),
+            headers: headers.to_vec(),
+        })
+    }
+}
+
+/// An element of the container structure of an input, see [`Demuxer::dump_structure`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureNode {
+    pub name: String,
+    /// Offset of the start of the element in the input.
+    pub offset: u64,
+    /// The size of the element including its header, unless it's unknown.
+    pub size: Option<u64>,
+    /// The value of the element in a readable form, if it has one.
+    pub value: Option<String>,
+    pub children: Vec<StructureNode>,
+}
+
 #[derive(Clone)]
 pub struct DemuxerMetadata {
     pub name: &'static str,
     create: fn(Io) -> Box<dyn Demuxer>,
     probe: fn(&[u8]) -> ProbeResult,
+    patterns: &'static [&'static str],
 }
 
 impl DemuxerMetadata {
@@ -92,18 +271,191 @@ impl DemuxerMetadata {
     pub fn probe(&self, data: &[u8]) -> ProbeResult {
         (self.probe)(data)
     }
+
+    /// Matches the URI against the patterns of the demuxer, which are either a scheme such as
+    /// `rtsp://` or a file extension such as `*.mkv`.
+    ///
+    /// A matching scheme is certain, while a matching extension is only a hint.
+    pub fn probe_uri(&self, uri: &str) -> ProbeResult {
+        let path = uri.split(['?', '#']).next().unwrap_or_default();
+
+        for pattern in self.patterns {
+            match pattern.strip_prefix('*') {
+                Some(extension) => {
+                    let matches = path.len() >= extension.len()
+                        && path.is_char_boundary(path.len() - extension.len())
+                        && path[path.len() - extension.len()..].eq_ignore_ascii_case(extension);
+
+                    if matches {
+                        return ProbeResult::Maybe(URI_EXTENSION_SCORE);
+                    }
+                }
+                None if uri.starts_with(pattern) => return ProbeResult::Yup,
+                None => {}
+            }
+        }
+
+        ProbeResult::Unsure
+    }
+
+    /// Combines the result of probing the data with that of the URI.
+    pub fn probe_with_uri(&self, uri: &str, data: &[u8]) -> ProbeResult {
+        match (self.probe(data), self.probe_uri(uri)) {
+            (ProbeResult::Yup, _) | (_, ProbeResult::Yup) => ProbeResult::Yup,
+            (ProbeResult::NeedMoreData, _) | (_, ProbeResult::NeedMoreData) => {
+                ProbeResult::NeedMoreData
+            }
+            (ProbeResult::Maybe(p), ProbeResult::Maybe(hint)) => {
+                ProbeResult::Maybe((p + hint).min(MAX_MAYBE_SCORE))
+            }
+            (result, ProbeResult::Unsure) | (ProbeResult::Unsure, result) => result,
+        }
+    }
 }
 
+/// How much a matching file extension adds to the probe score of a demuxer.
+const URI_EXTENSION_SCORE: f32 = 0.25;
+const MAX_MAYBE_SCORE: f32 = 0.99;
+
+type CreateMuxerWithOptions = fn(Io, &MuxerOptions) -> anyhow::Result<Box<dyn Muxer>>;
+
 #[derive(Clone)]
 pub struct MuxerMetadata {
-    name: &'static str,
+    pub name: &'static str,
     create: fn(Io) -> Box<dyn Muxer>,
+    create_with_options: Option<CreateMuxerWithOptions>,
+    codecs: &'static [&'static str],
 }
 
 impl MuxerMetadata {
     pub fn create(&self, io: Io) -> Box<dyn Muxer> {
         (self.create)(io)
     }
+
+    /// Creates the muxer with the given options, failing if any of them are unknown to it.
+    pub fn create_with_options(
+        &self,
+        io: Io,
+        options: &MuxerOptions,
+    ) -> anyhow::Result<Box<dyn Muxer>> {
+        match self.create_with_options {
+            Some(create) => create(io, options),
+            None => {
+                options.expect_only(self.name, &[])?;
+
+                Ok((self.create)(io))
+            }
+        }
+    }
+
+    /// The codecs the muxer can store, by [`MediaInfo::name`].
+    pub fn supported_codecs(&self) -> &'static [&'static str] {
+        self.codecs
+    }
+
+    pub fn supports(&self, info: &MediaInfo) -> bool {
+        self.codecs.contains(&info.name)
+    }
+
+    /// Checks that the muxer can store all tracks before anything is written, so that
+    /// unsupported tracks can be left out or transcoded instead of producing a broken output.
+    pub fn check_tracks(&self, tracks: &[Track]) -> crate::Result<()> {
+        match tracks.iter().find(|t| !self.supports(&t.info)) {
+            Some(track) => Err(MediaboxError::unsupported(format!(
+                "codec {} for the {} muxer in track #{}, supported codecs are {}",
+                track.info.name,
+                self.name,
+                track.id,
+                self.codecs.join(", ")
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MuxerOptionError {
+    #[error("Unknown option {1:?} for the {0} muxer")]
+    Unknown(&'static str, String),
+
+    #[error("Invalid value {1:?} for option {0:?}")]
+    InvalidValue(String, String),
+}
+
+/// Options for a muxer as key/value pairs, e.g. from the command line. Muxers which support
+/// options parse these into their own typed options, such as
+/// [`mkv::MatroskaOptions`](crate::format::mkv::MatroskaOptions).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MuxerOptions {
+    options: BTreeMap<String, String>,
+}
+
+impl MuxerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.options.insert(key.into(), value.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    /// Parses the value of an option, or `None` if it isn't set.
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>, MuxerOptionError> {
+        self.options
+            .get(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| MuxerOptionError::InvalidValue(key.to_string(), value.clone()))
+            })
+            .transpose()
+    }
+
+    /// Parses an option given in seconds, which may be fractional.
+    pub fn get_duration(&self, key: &str) -> Result<Option<Duration>, MuxerOptionError> {
+        self.get::<f64>(key)?
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs)
+                    .map_err(|_| MuxerOptionError::InvalidValue(key.to_string(), secs.to_string()))
+            })
+            .transpose()
+    }
+
+    /// Fails on the first option which isn't one of `keys`, so that typos don't go unnoticed.
+    pub fn expect_only(&self, muxer: &'static str, keys: &[&str]) -> Result<(), MuxerOptionError> {
+        match self.options.keys().find(|key| !keys.contains(&key.as_str())) {
+            Some(key) => Err(MuxerOptionError::Unknown(muxer, key.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for MuxerOptions {
+    type Err = anyhow::Error;
+
+    /// Parses comma separated `key=value` pairs.
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let mut options = MuxerOptions::new();
+
+        for option in val.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Expected key=value, found {option:?}"))?;
+
+            options.set(key.trim(), value.trim());
+        }
+
+        Ok(options)
+    }
 }
 
 /// A muxer that can handle splitting up the output into multiple segments.
@@ -111,10 +463,12 @@ pub struct SegmentMuxer {
     muxer: Box<dyn Muxer>,
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ProbeResult {
     Yup,
     Maybe(f32),
+    /// The data looks like the start of the format but is too short to tell.
+    NeedMoreData,
     Unsure,
 }
 
@@ -127,21 +481,43 @@ impl PartialOrd for ProbeResult {
             (Yup, _) => Ordering::Greater,
             (_, Yup) => Ordering::Less,
             (Maybe(p1), Maybe(p2)) => p1.partial_cmp(p2)?,
+            (Unsure, Unsure) | (NeedMoreData, NeedMoreData) => Ordering::Equal,
             (Unsure, _) => Ordering::Less,
             (_, Unsure) => Ordering::Greater,
+            (NeedMoreData, _) => Ordering::Less,
+            (_, NeedMoreData) => Ordering::Greater,
         };
 
         Some(ordering)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Movie {
     pub tracks: Vec<Track>,
     pub attachments: Vec<Attachment>,
+    pub chapters: Vec<Chapter>,
+    /// Tags describing the whole movie, e.g. `TITLE` or `ARTIST`.
+    pub metadata: BTreeMap<String, String>,
+    /// How much all timestamps were shifted by, for inputs which start with negative timestamps.
+    pub start_offset: Duration,
+    /// The length of the movie, if stored by the container.
+    pub duration: Option<Duration>,
 }
 
 impl Movie {
+    /// The length of the movie, falling back to the longest track if the container only stores
+    /// the length of each track.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+            .or_else(|| self.tracks.iter().filter_map(|t| t.metadata.duration).max())
+    }
+
+    /// The sum of the average bitrates of all tracks, if any are known.
+    pub fn bitrate(&self) -> Option<u64> {
+        self.tracks.iter().filter_map(Track::bitrate).reduce(|a, b| a + b)
+    }
+
     pub fn codec_string(&self) -> Option<String> {
         let video = self.tracks.video()?;
         let VideoCodec::H264(H264Codec {
@@ -149,7 +525,10 @@ impl Movie {
             profile_compatibility,
             level_indication,
             ..
-        }) = video.info.video()?.codec;
+        }) = video.info.video()?.codec
+        else {
+            return None;
+        };
 
         let mut codec = format!(
             "avc1.{:02x}{:02x}{:02x}",
@@ -157,9 +536,15 @@ impl Movie {
         );
 
         if let Some(audio) = self.tracks.audio() {
-            let AudioCodec::Aac(AacCodec { ref extra }) = audio.info.audio()?.codec;
-
-            write!(&mut codec, ",mp4a.40.{:02X}", extra[0] >> 3).ok()?;
+            match audio.info.audio()?.codec {
+                AudioCodec::Aac(AacCodec { ref extra }) => {
+                    write!(&mut codec, ",mp4a.40.{:02X}", extra[0] >> 3).ok()?
+                }
+                AudioCodec::Opus(_) => codec.push_str(",opus"),
+                AudioCodec::Vorbis(_) => codec.push_str(",vorbis"),
+                AudioCodec::Flac(_) => codec.push_str(",flac"),
+                AudioCodec::Pcm(_) => return None,
+            }
         }
 
         Some(codec)
@@ -182,3 +567,86 @@ impl Debug for Attachment {
         write!(f, "{:?} ({}) {} B", self.name, self.mime, self.data.len())
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub start: Duration,
+    pub end: Option<Duration>,
+    pub title: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    const META: DemuxerMetadata = DemuxerMetadata {
+        name: "test",
+        create: adts::AdtsDemuxer::create,
+        probe: |data| match data {
+            [0xff, ..] => ProbeResult::Maybe(0.5),
+            _ => ProbeResult::Unsure,
+        },
+        patterns: &["test://", "*.tst"],
+    };
+
+    #[test_case("test://host/stream", &[], ProbeResult::Yup ; "scheme")]
+    #[test_case("/tmp/file.TST?t=1", &[], ProbeResult::Maybe(0.25) ; "extension")]
+    #[test_case("/tmp/file.tst", &[0xff], ProbeResult::Maybe(0.75) ; "extension and data")]
+    #[test_case("/tmp/file.mkv", &[0xff], ProbeResult::Maybe(0.5) ; "data")]
+    #[test_case("/tmp/tst", &[], ProbeResult::Unsure ; "no match")]
+    fn probe_with_uri(uri: &str, data: &[u8], expected: ProbeResult) {
+        assert!(META.probe_with_uri(uri, data) == expected);
+    }
+
+    #[test]
+    fn parse_muxer_options() {
+        let options: MuxerOptions = "cluster_duration=2.5, max_cluster_size=1024".parse().unwrap();
+        let mkv = mkv::MatroskaOptions::try_from(&options).unwrap();
+
+        assert_eq!(Duration::from_millis(2500), mkv.cluster_duration);
+        assert_eq!(1024, mkv.max_cluster_size);
+    }
+
+    #[test]
+    fn reject_unsupported_tracks() {
+        let track = Track {
+            id: 3,
+            info: std::sync::Arc::new(MediaInfo {
+                name: "ass",
+                kind: crate::MediaKind::Subtitle(crate::codec::SubtitleInfo {
+                    codec: crate::codec::SubtitleCodec::Ass(crate::codec::AssCodec {
+                        header: String::new(),
+                    }),
+                }),
+            }),
+            timebase: crate::Fraction::new(1, 1000),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        assert!(mkv::MUXER_META.check_tracks(&[track.clone()]).is_ok());
+        assert_eq!(
+            "Unsupported codec ass for the mp4 muxer in track #3, supported codecs are h264, av1, \
+             vp8, vp9, aac, opus",
+            mp4::mp4::MUXER_META.check_tracks(&[track]).unwrap_err().to_string()
+        );
+    }
+
+    #[test_case("cluster_duration=-1" ; "negative duration")]
+    #[test_case("max_cluster_size=big" ; "not a number")]
+    #[test_case("fragment_duration=2" ; "unknown option")]
+    fn reject_muxer_options(options: &str) {
+        let options: MuxerOptions = options.parse().unwrap();
+
+        assert!(mkv::MatroskaOptions::try_from(&options).is_err());
+    }
+
+    #[test]
+    fn muxer_without_options_rejects_them() {
+        let options = MuxerOptions::new().with("segment_duration", 2);
+
+        assert!(adts::MUXER_META.create_with_options(Io::null(), &options).is_err());
+        assert!(adts::MUXER_META.create_with_options(Io::null(), &MuxerOptions::new()).is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+use async_trait::async_trait;
+use bytes::BytesMut;
+use log::*;
+
+use std::sync::Arc;
+
+use crate::{
+    codec::aac::{AdtsHeader, AudioSpecificConfig, ADTS_HEADER_LEN},
+    demuxer,
+    format::{probe, Demuxer, Movie, Muxer, ProbeResult},
+    io::Io,
+    muxer, AacCodec, AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime,
+    MediaboxError, Packet, SoundType, Span, Track,
+};
+
+demuxer!("adts", AdtsDemuxer::create, AdtsDemuxer::probe, ["*.aac"]);
+muxer!("adts", AdtsMuxer::create, ["aac"]);
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdtsError {
+    #[error("Invalid ADTS header")]
+    InvalidHeader,
+
+    #[error("Only a single AAC track is allowed.")]
+    InvalidTracks,
+
+    #[error("Unsupported AudioSpecificConfig")]
+    UnsupportedConfig,
+
+    #[error("AAC frame of {0} bytes is too large for ADTS")]
+    FrameTooLarge(usize),
+}
+
+impl From<AdtsError> for MediaboxError {
+    fn from(error: AdtsError) -> Self {
+        match error {
+            AdtsError::InvalidHeader => MediaboxError::invalid_data(error),
+            _ => MediaboxError::unsupported(error.to_string()),
+        }
+    }
+}
+
+/// A demuxer for raw AAC streams framed with ADTS headers, i.e. `.aac` files.
+pub struct AdtsDemuxer {
+    io: Io,
+    track: Option<Track>,
+    pending: Option<AdtsHeader>,
+    pts: u64,
+}
+
+impl AdtsDemuxer {
+    pub fn new(io: Io) -> Self {
+        AdtsDemuxer {
+            io,
+            track: None,
+            pending: None,
+            pts: 0,
+        }
+    }
+
+    async fn read_header(&mut self) -> anyhow::Result<AdtsHeader> {
+        let mut data = [0u8; ADTS_HEADER_LEN];
+        self.io.read_exact(&mut data).await?;
+
+        let header = AdtsHeader::parse(&data).ok_or(AdtsError::InvalidHeader)?;
+
+        if !header.protection_absent {
+            self.io.skip(2).await?;
+        }
+
+        Ok(header)
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for AdtsDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        // skip any garbage before the first frame, e.g. a leftover ID3 tag
+        let data = self.io.read_probe().await?;
+        match probe::adts_sync_offset(data) {
+            Some(0) | None => {}
+            Some(offset) => {
+                debug!("Skipping {offset} bytes before first ADTS frame");
+
+                self.io.skip(offset as u64).await?;
+            }
+        }
+
+        let header = self.read_header().await?;
+        let config = header.config;
+        let sample_rate = config.sample_rate().ok_or(AdtsError::InvalidHeader)?;
+
+        debug!("ADTS stream: {config:?}");
+
+        let info = MediaInfo {
+            name: "aac",
+            kind: MediaKind::Audio(AudioInfo {
+                sample_rate,
+                sample_bpp: 16,
+                sound_type: if config.channel_config > 1 {
+                    SoundType::Stereo
+                } else {
+                    SoundType::Mono
+                },
+                codec: AudioCodec::Aac(AacCodec {
+                    extra: config.to_bytes().to_vec(),
+                }),
+            }),
+        };
+
+        let track = Track {
+            id: 0,
+            info: Arc::new(info),
+            timebase: Fraction::new(1, sample_rate),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        self.track = Some(track.clone());
+        self.pending = Some(header);
+
+        Ok(Movie {
+            tracks: vec![track],
+            ..Default::default()
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        let header = match self.pending.take() {
+            Some(header) => header,
+            None if self.io.is_at_end().await? => return Err(MediaboxError::EndOfInput),
+            None => self.read_header().await?,
+        };
+
+        let track = self.track.clone().expect("Demuxer not started");
+
+        let mut buffer = vec![0u8; header.payload_len()];
+        self.io.read_exact(&mut buffer).await?;
+
+        let time = MediaTime {
+            pts: self.pts,
+            dts: None,
+            duration: Some(header.samples()),
+            timebase: track.timebase,
+        };
+
+        self.pts += header.samples();
+
+        Ok(Packet {
+            time,
+            key: true,
+            track,
+            buffer: buffer.into(),
+            side_data: Vec::new(),
+        })
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(Self::new(io))
+    }
+
+    fn probe(data: &[u8]) -> ProbeResult {
+        probe::adts(data)
+    }
+}
+
+/// A muxer which wraps raw AAC packets in ADTS headers.
+pub struct AdtsMuxer {
+    config: Option<AudioSpecificConfig>,
+    track: Option<Track>,
+    io: Io,
+}
+
+impl AdtsMuxer {
+    pub fn new(io: Io) -> Self {
+        AdtsMuxer {
+            config: None,
+            track: None,
+            io,
+        }
+    }
+
+    fn create(io: Io) -> Box<dyn Muxer> {
+        Box::new(Self::new(io))
+    }
+}
+
+#[async_trait]
+impl Muxer for AdtsMuxer {
+    async fn start(&mut self, mut tracks: Vec<Track>) -> crate::Result<()> {
+        if tracks.len() != 1 {
+            Err(AdtsError::InvalidTracks)?;
+        }
+
+        let track = tracks.swap_remove(0);
+
+        let Some(AudioInfo {
+            codec: AudioCodec::Aac(AacCodec { extra }),
+            ..
+        }) = track.info.audio()
+        else {
+            Err(AdtsError::InvalidTracks)?
+        };
+
+        let config = AudioSpecificConfig::parse(extra)
+            .and_then(|config| config.to_adts())
+            .ok_or(AdtsError::UnsupportedConfig)?;
+
+        self.config = Some(config);
+        self.track = Some(track);
+
+        Ok(())
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        let config = self.config.expect("Muxer not started");
+
+        let mut header = BytesMut::new();
+        AdtsHeader::new(config, packet.buffer.len())
+            .ok_or(AdtsError::FrameTooLarge(packet.buffer.len()))?
+            .write(&mut header);
+
+        let frame = [Span::from(header.freeze()), packet.buffer]
+            .into_iter()
+            .collect::<Span>();
+
+        self.io.write_span(frame).await?;
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        self.io.flush().await?;
+
+        Ok(())
+    }
+
+    fn into_io(self) -> Io {
+        self.io
+    }
+}
@@ -0,0 +1,204 @@
+use async_trait::async_trait;
+
+use crate::{
+    codec::{AssCodec, SubtitleCodec, SubtitleInfo},
+    format::Muxer,
+    io::Io,
+    muxer,
+    time::ClockTime,
+    MediaboxError, Packet, Track,
+};
+
+muxer!("ass", AssMuxer::create, ["ass"]);
+
+/// The fields of `Dialogue` lines, as expected by [AssMuxer].
+const EVENTS_FORMAT: &str =
+    "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AssMuxError {
+    #[error("Only a single ASS track is allowed.")]
+    InvalidTracks,
+
+    #[error("Invalid ASS event {0:?}")]
+    InvalidEvent(String),
+}
+
+impl From<AssMuxError> for MediaboxError {
+    fn from(error: AssMuxError) -> Self {
+        match error {
+            AssMuxError::InvalidTracks => MediaboxError::unsupported(error.to_string()),
+            AssMuxError::InvalidEvent(_) => MediaboxError::invalid_data(error),
+        }
+    }
+}
+
+/// A muxer which writes an ASS track as a standalone `.ass` script.
+///
+/// Packets hold events the way Matroska stores them, without their start and end times and
+/// prefixed with their position in the original script. Events are buffered and written in that
+/// order when the muxer is stopped.
+pub struct AssMuxer {
+    track: Option<Track>,
+    events: Vec<(u64, String)>,
+    io: Io,
+}
+
+impl AssMuxer {
+    pub fn new(io: Io) -> Self {
+        AssMuxer {
+            track: None,
+            events: Vec::new(),
+            io,
+        }
+    }
+
+    fn create(io: Io) -> Box<dyn Muxer> {
+        Box::new(Self::new(io))
+    }
+}
+
+#[async_trait]
+impl Muxer for AssMuxer {
+    async fn start(&mut self, mut tracks: Vec<Track>) -> crate::Result<()> {
+        if tracks.len() != 1 {
+            Err(AssMuxError::InvalidTracks)?;
+        }
+
+        let track = tracks.swap_remove(0);
+
+        let Some(SubtitleInfo {
+            codec: SubtitleCodec::Ass(AssCodec { header }),
+        }) = track.info.subtitle()
+        else {
+            Err(AssMuxError::InvalidTracks)?
+        };
+
+        let mut header = header.trim_end().to_string();
+        if !header.contains("[Events]") {
+            header.push_str("\n\n[Events]\n");
+            header.push_str(EVENTS_FORMAT);
+        }
+        header.push('\n');
+
+        self.io.write(header.as_bytes()).await?;
+        self.track = Some(track);
+
+        Ok(())
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        let data = packet.buffer.to_slice();
+        let event = String::from_utf8_lossy(&data);
+
+        // ReadOrder, Layer, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+        let mut fields = event.splitn(3, ',');
+        let (Some(read_order), Some(layer), Some(rest)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            Err(AssMuxError::InvalidEvent(event.to_string()))?
+        };
+        let read_order = match packet.read_order() {
+            Some(read_order) => read_order,
+            None => read_order
+                .trim()
+                .parse()
+                .map_err(|_| AssMuxError::InvalidEvent(event.to_string()))?,
+        };
+
+        let time = &packet.time;
+        let clock = |ts| {
+            ClockTime::from_timestamp(ts, time.timebase)
+                .with_hour_digits(1)
+                .with_fraction_digits(2)
+        };
+        let start = clock(time.pts);
+        let end = clock(time.pts + time.duration.unwrap_or(0));
+
+        self.events.push((
+            read_order,
+            format!("Dialogue: {layer},{start},{end},{}", rest.trim_end()),
+        ));
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        self.events.sort_by_key(|(read_order, _)| *read_order);
+
+        let mut events = String::new();
+        for (_, event) in self.events.drain(..) {
+            events.push_str(&event);
+            events.push('\n');
+        }
+
+        self.io.write(events.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    fn into_io(self) -> Io {
+        self.io
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{format::Movie, test, Fraction, MediaInfo, MediaKind, MediaTime};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn write_script_in_read_order() {
+        let track = Track {
+            id: 1,
+            info: Arc::new(MediaInfo {
+                name: "ass",
+                kind: MediaKind::Subtitle(SubtitleInfo {
+                    codec: SubtitleCodec::Ass(AssCodec {
+                        header: "[Script Info]\nScriptType: v4.00+\n".into(),
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 1000),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        let packets = [
+            (1, 1500, "1,0,Default,,0,0,0,,Second"),
+            (0, 1500, "0,0,Sign,,0,0,0,,First, with comma"),
+        ]
+        .into_iter()
+        .map(|(i, pts, event)| Packet {
+            time: MediaTime {
+                pts: pts + i * 61_000,
+                dts: None,
+                duration: Some(2_250),
+                timebase: track.timebase,
+            },
+            key: true,
+            track: track.clone(),
+            buffer: event.as_bytes().to_vec().into(),
+            side_data: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+        let mut muxer = AssMuxer::new(Io::from_stream(Box::new(Vec::<u8>::new())));
+        let movie = Movie {
+            tracks: vec![track],
+            ..Default::default()
+        };
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+
+        let script = String::from_utf8(muxer.into_io().into_bytes().unwrap().to_vec()).unwrap();
+
+        assert_eq!(
+            "[Script Info]\nScriptType: v4.00+\n\n[Events]\n\
+             Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+             Dialogue: 0,0:00:01.50,0:00:03.75,Sign,,0,0,0,,First, with comma\n\
+             Dialogue: 0,0:01:02.50,0:01:04.75,Default,,0,0,0,,Second\n",
+            script
+        );
+    }
+}
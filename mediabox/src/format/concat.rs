@@ -0,0 +1,266 @@
+use async_trait::async_trait;
+use log::*;
+use tokio::io::AsyncReadExt;
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{
+    format::{Demuxer, DemuxerEvent, Movie},
+    io::Io,
+    time::{from_duration, to_duration},
+    MediaContext, MediaboxError, Packet, Track,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConcatError {
+    #[error("No inputs to concatenate")]
+    NoInputs,
+
+    #[error("Input {0} has different tracks than the first input")]
+    TrackMismatch(usize),
+}
+
+impl From<ConcatError> for MediaboxError {
+    fn from(error: ConcatError) -> Self {
+        MediaboxError::invalid_data(error)
+    }
+}
+
+/// Plays multiple inputs with the same tracks one after another as a single movie, e.g. to stitch
+/// recorded segments back together.
+///
+/// The timestamps of each input continue from the end of the previous one, and a
+/// [`DemuxerEvent::Discontinuity`] is reported before the first packet of every input but the
+/// first.
+pub struct ConcatDemuxer {
+    playlist: Option<Io>,
+    inputs: VecDeque<Box<dyn Demuxer>>,
+    current: Option<Box<dyn Demuxer>>,
+    /// Number of the current input, counting from 0.
+    index: usize,
+    tracks: Vec<Track>,
+    /// How much the timestamps of the current input are shifted by.
+    offset: Duration,
+    /// The end of the latest packet so far, after shifting.
+    end: Duration,
+    events: VecDeque<DemuxerEvent>,
+}
+
+impl ConcatDemuxer {
+    pub fn new(inputs: Vec<Box<dyn Demuxer>>) -> Self {
+        ConcatDemuxer {
+            playlist: None,
+            inputs: inputs.into(),
+            current: None,
+            index: 0,
+            tracks: Vec::new(),
+            offset: Duration::ZERO,
+            end: Duration::ZERO,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Opens the inputs listed in a playlist with one URI per line. Empty lines and lines
+    /// starting with `#` are skipped.
+    async fn open_playlist(mut io: Io) -> anyhow::Result<VecDeque<Box<dyn Demuxer>>> {
+        let mut playlist = String::new();
+        io.reader()?.read_to_string(&mut playlist).await?;
+
+        let mut cxt = MediaContext::default();
+        cxt.register_all();
+
+        let mut inputs = VecDeque::new();
+        for uri in playlist.lines().map(str::trim) {
+            if uri.is_empty() || uri.starts_with('#') {
+                continue;
+            }
+
+            let mut io = Io::open(uri.to_string()).await?;
+            let meta = cxt.probe(&mut io).await?;
+            debug!("Opened {uri:?} as {}", meta.name);
+
+            inputs.push_back(meta.create(io));
+        }
+
+        Ok(inputs)
+    }
+
+    /// Starts the next input, continuing the timestamps from the end of the previous one.
+    async fn start_next(&mut self) -> crate::Result<Option<Movie>> {
+        if let Some(mut current) = self.current.take() {
+            current.stop().await?;
+            self.index += 1;
+        }
+
+        let Some(mut next) = self.inputs.pop_front() else {
+            return Ok(None);
+        };
+
+        let movie = next.start().await?;
+        self.current = Some(next);
+
+        if self.index > 0 {
+            if !same_tracks(&self.tracks, &movie.tracks) {
+                return Err(ConcatError::TrackMismatch(self.index).into());
+            }
+
+            self.offset = self.end.saturating_sub(movie.start_offset);
+            self.events.push_back(DemuxerEvent::Discontinuity);
+        }
+
+        Ok(Some(movie))
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for ConcatDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        if let Some(playlist) = self.playlist.take() {
+            self.inputs = Self::open_playlist(playlist).await?;
+        }
+
+        let movie = self.start_next().await?.ok_or(ConcatError::NoInputs)?;
+        self.tracks = movie.tracks.clone();
+
+        Ok(Movie {
+            // the other inputs are only started once they are reached
+            duration: None,
+            ..movie
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        loop {
+            let current = self.current.as_mut().ok_or(MediaboxError::EndOfInput)?;
+
+            let mut pkt = match current.read().await {
+                Ok(pkt) => pkt,
+                Err(e) if e.is_end_of_input() => {
+                    if self.start_next().await?.is_none() {
+                        return Err(MediaboxError::EndOfInput);
+                    }
+
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let offset = from_duration(self.offset, pkt.time.timebase);
+            pkt.time.pts += offset;
+            pkt.time.dts = pkt.time.dts.map(|dts| dts + offset);
+
+            let end = pkt.time.pts + pkt.time.duration.unwrap_or(0);
+            self.end = self.end.max(to_duration(end, pkt.time.timebase));
+
+            // packets refer to the tracks of the first input
+            if let Some(track) = self.tracks.iter().find(|t| t.id == pkt.track.id) {
+                pkt.track = track.clone();
+            }
+
+            return Ok(pkt);
+        }
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        if let Some(mut current) = self.current.take() {
+            current.stop().await?;
+        }
+        self.inputs.clear();
+
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<DemuxerEvent> {
+        if let Some(event) = self.current.as_mut().and_then(|c| c.next_event()) {
+            return Some(event);
+        }
+
+        self.events.pop_front()
+    }
+
+    /// Creates a demuxer for the inputs listed in a playlist, with one URI per line.
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(ConcatDemuxer {
+            playlist: Some(io),
+            ..ConcatDemuxer::new(Vec::new())
+        })
+    }
+}
+
+/// Whether the inputs have tracks with the same ids and codecs, in the same order.
+fn same_tracks(first: &[Track], other: &[Track]) -> bool {
+    first.len() == other.len()
+        && first
+            .iter()
+            .zip(other)
+            .all(|(a, b)| a.id == b.id && a.info.name == b.info.name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        format::{wav::*, Muxer},
+        test, AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, PcmCodec,
+        SampleFormat, SoundType,
+    };
+
+    async fn wav(samples: u64) -> Box<dyn Demuxer> {
+        let track = Track {
+            id: 0,
+            info: Arc::new(MediaInfo {
+                name: "pcm",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 8000,
+                    sample_bpp: 16,
+                    sound_type: SoundType::Mono,
+                    codec: AudioCodec::Pcm(PcmCodec {
+                        format: SampleFormat::S16Le,
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 8000),
+            delay: 0,
+            metadata: Default::default(),
+        };
+        let packet = Packet {
+            time: MediaTime {
+                pts: 0,
+                dts: None,
+                duration: Some(samples),
+                timebase: track.timebase,
+            },
+            key: true,
+            track: track.clone(),
+            buffer: vec![0; samples as usize * 2].into(),
+            side_data: Vec::new(),
+        };
+        let movie = Movie {
+            tracks: vec![track],
+            ..Default::default()
+        };
+
+        let mut muxer = WavMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &[packet]).await;
+
+        Box::new(WavDemuxer::new(Io::from_bytes(
+            muxer.into_io().into_bytes().unwrap(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn continue_timestamps_of_previous_input() {
+        let mut demuxer = ConcatDemuxer::new(vec![wav(2000).await, wav(1000).await]);
+        demuxer.start().await.unwrap();
+
+        let mut packets = Vec::new();
+        while let Ok(pkt) = demuxer.read().await {
+            let discontinuity = matches!(demuxer.next_event(), Some(DemuxerEvent::Discontinuity));
+            packets.push((pkt.time.pts, discontinuity));
+        }
+
+        assert_eq!(vec![(0, false), (1024, false), (2000, true)], packets);
+    }
+}
@@ -0,0 +1,315 @@
+use async_trait::async_trait;
+use bytes::BytesMut;
+use log::*;
+
+use std::sync::Arc;
+
+use crate::{
+    codec::flac::{
+        crc16, FrameHeader, StreamInfo, FLAC_MARKER, FRAME_HEADER_MIN_LEN, METADATA_STREAMINFO,
+    },
+    demuxer,
+    format::{probe, Demuxer, Movie, ProbeResult},
+    io::{read_up_to, Io},
+    time::to_duration,
+    AudioCodec, AudioInfo, FlacCodec, Fraction, MediaInfo, MediaKind, MediaTime, MediaboxError,
+    Packet, SoundType, Track,
+};
+
+demuxer!("flac", FlacDemuxer::create, FlacDemuxer::probe, ["*.flac"]);
+
+/// How much to read at a time while looking for the end of a frame.
+const READ_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FlacError {
+    #[error("Not a FLAC stream")]
+    InvalidMarker,
+
+    #[error("The first metadata block must be STREAMINFO")]
+    MissingStreamInfo,
+
+    #[error("Invalid STREAMINFO")]
+    InvalidStreamInfo,
+
+    #[error("Invalid frame header")]
+    InvalidFrame,
+}
+
+impl From<FlacError> for MediaboxError {
+    fn from(error: FlacError) -> Self {
+        MediaboxError::invalid_data(error)
+    }
+}
+
+/// A demuxer for native FLAC streams, i.e. `.flac` files.
+///
+/// Frames aren't prefixed with their size, so each packet is found by searching for the next
+/// frame header, verifying the CRC of both the header and the frame before it.
+pub struct FlacDemuxer {
+    io: Io,
+    track: Option<Track>,
+    stream_info: Option<StreamInfo>,
+    buffer: BytesMut,
+    eof: bool,
+}
+
+impl FlacDemuxer {
+    pub fn new(io: Io) -> Self {
+        FlacDemuxer {
+            io,
+            track: None,
+            stream_info: None,
+            buffer: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    /// Reads the metadata blocks, returning the contents of `STREAMINFO`.
+    async fn read_metadata(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut stream_info = None;
+
+        loop {
+            let mut header = [0u8; 4];
+            self.io.read_exact(&mut header).await?;
+
+            let [flags, len @ ..] = header;
+            let len = u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize;
+            let block_type = flags & 0x7f;
+
+            if stream_info.is_none() && block_type != METADATA_STREAMINFO {
+                Err(FlacError::MissingStreamInfo)?;
+            }
+
+            if block_type == METADATA_STREAMINFO {
+                let mut data = vec![0u8; len];
+                self.io.read_exact(&mut data).await?;
+                stream_info = Some(data);
+            } else {
+                debug!("Skipping metadata block {block_type} of {len} B");
+
+                self.io.skip(len as u64).await?;
+            }
+
+            // the last metadata block is flagged
+            if flags & 0x80 != 0 {
+                break;
+            }
+        }
+
+        Ok(stream_info.unwrap())
+    }
+
+    /// Finds where the frame at the start of the buffer ends, if the next frame header is in the
+    /// buffer.
+    fn frame_end(&self, first: &FrameHeader) -> Option<usize> {
+        let data = &self.buffer[..];
+
+        (FRAME_HEADER_MIN_LEN..data.len().saturating_sub(1))
+            .filter(|&offset| data[offset] == 0xff && data[offset + 1] & 0xfe == 0xf8)
+            .find(|&offset| {
+                FrameHeader::parse(&data[offset..]).is_some_and(|header| {
+                    header.variable_block_size == first.variable_block_size
+                        && crc16(&data[..offset]) == 0
+                })
+            })
+    }
+
+    async fn fill(&mut self) -> anyhow::Result<()> {
+        let mut data = Vec::with_capacity(READ_SIZE);
+        read_up_to(self.io.reader()?, &mut data, READ_SIZE).await?;
+
+        self.eof = data.len() < READ_SIZE;
+        self.buffer.extend_from_slice(&data);
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for FlacDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let data = self.io.read_probe().await?;
+        let id3_len = probe::id3v2_len(data);
+        if id3_len > 0 {
+            debug!("Skipping {id3_len} B ID3v2 tag");
+
+            self.io.skip(id3_len as u64).await?;
+        }
+
+        let mut marker = [0u8; 4];
+        self.io.read_exact(&mut marker).await?;
+        if &marker != FLAC_MARKER {
+            Err(FlacError::InvalidMarker)?;
+        }
+
+        let codec = FlacCodec::from_stream_info(&self.read_metadata().await?);
+        let stream_info = codec.stream_info().ok_or(FlacError::InvalidStreamInfo)?;
+
+        debug!("FLAC stream: {stream_info:?}");
+
+        let info = MediaInfo {
+            name: "flac",
+            kind: MediaKind::Audio(AudioInfo {
+                sample_rate: stream_info.sample_rate,
+                sample_bpp: stream_info.bits_per_sample as u32,
+                sound_type: if stream_info.channels > 1 {
+                    SoundType::Stereo
+                } else {
+                    SoundType::Mono
+                },
+                codec: AudioCodec::Flac(codec),
+            }),
+        };
+
+        let track = Track {
+            id: 0,
+            info: Arc::new(info),
+            timebase: Fraction::new(1, stream_info.sample_rate),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        // the number of samples is 0 if unknown
+        let duration = (stream_info.total_samples > 0)
+            .then(|| to_duration(stream_info.total_samples, track.timebase));
+
+        self.track = Some(track.clone());
+        self.stream_info = Some(stream_info);
+
+        Ok(Movie {
+            tracks: vec![track],
+            duration,
+            ..Default::default()
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        let track = self.track.clone().expect("Demuxer not started");
+        let max_block_size = self.stream_info.as_ref().unwrap().max_block_size as u64;
+
+        while self.buffer.len() < READ_SIZE && !self.eof {
+            self.fill().await?;
+        }
+
+        if self.buffer.is_empty() {
+            return Err(MediaboxError::EndOfInput);
+        }
+
+        let header = FrameHeader::parse(&self.buffer).ok_or(FlacError::InvalidFrame)?;
+        let pts = if header.variable_block_size {
+            header.number
+        } else {
+            header.number * max_block_size
+        };
+
+        let end = loop {
+            if let Some(end) = self.frame_end(&header) {
+                break end;
+            }
+
+            if self.eof {
+                break self.buffer.len();
+            }
+
+            self.fill().await?;
+        };
+
+        let buffer = self.buffer.split_to(end).freeze();
+
+        Ok(Packet {
+            time: MediaTime {
+                pts,
+                dts: None,
+                duration: Some(header.block_size as u64),
+                timebase: track.timebase,
+            },
+            key: true,
+            track,
+            buffer: buffer.into(),
+            side_data: Vec::new(),
+        })
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(Self::new(io))
+    }
+
+    fn probe(data: &[u8]) -> ProbeResult {
+        let data = &data[probe::id3v2_len(data).min(data.len())..];
+
+        if data.starts_with(FLAC_MARKER) {
+            ProbeResult::Yup
+        } else if FLAC_MARKER.starts_with(data) {
+            ProbeResult::NeedMoreData
+        } else {
+            ProbeResult::Unsure
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{codec::flac::crc8, test};
+
+    fn stream_info_block() -> Vec<u8> {
+        let mut block = vec![0x80 | METADATA_STREAMINFO, 0, 0, 34];
+        block.extend_from_slice(&[0x01, 0x00, 0x01, 0x00, 0, 0, 0, 0, 0, 0]);
+        // 8000 Hz, 1 channel, 16 bits, 512 samples
+        let packed: u64 = (8000 << 44) | (15 << 36) | 512;
+        block.extend_from_slice(&packed.to_be_bytes());
+        block.extend_from_slice(&[0; 16]);
+
+        block
+    }
+
+    /// A fixed block size frame of 256 samples, with a payload which can't be mistaken for a
+    /// frame header.
+    fn frame(number: u8, payload_len: usize) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xf8, 0x80, 0x08, number];
+        frame.push(crc8(&frame));
+        frame.extend(std::iter::repeat_n(number, payload_len));
+        frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+
+        frame
+    }
+
+    #[tokio::test]
+    async fn read_frames() {
+        let frames = [frame(0, 100), frame(1, 20000), frame(2, 5)];
+
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x02hi".to_vec();
+        data.extend_from_slice(FLAC_MARKER);
+        data.extend(stream_info_block());
+        data.extend(frames.concat());
+
+        assert!(matches!(FlacDemuxer::probe(&data), ProbeResult::Yup));
+
+        let mut demuxer = FlacDemuxer::new(Io::from_bytes(data));
+        let (movie, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        let audio = movie.tracks[0].info.audio().unwrap();
+        assert_eq!((8000, 16), (audio.sample_rate, audio.sample_bpp));
+        assert!(matches!(&audio.codec, AudioCodec::Flac(codec) if codec.stream_info().is_some()));
+
+        assert_eq!(
+            vec![(0, 256), (256, 256), (512, 256)],
+            packets
+                .iter()
+                .map(|p| (p.time.pts, p.time.duration.unwrap()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            frames.to_vec(),
+            packets
+                .iter()
+                .map(|p| p.buffer.to_slice().to_vec())
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use async_trait::async_trait;
+use bytes::BytesMut;
+use log::*;
+
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    codec::nal::{
+        frame_nal_units, get_codec_from_parameter_sets, parse_bitstream, BitstreamFraming,
+    },
+    demuxer,
+    format::{Demuxer, Movie, ProbeResult},
+    io::Io,
+    Fraction, MediaTime, MediaboxError, Packet, Span, Track,
+};
+
+demuxer!("h264", H264EsDemuxer::create, H264EsDemuxer::probe, ["*.h264", "*.264"]);
+
+const READ_SIZE: usize = 64 * 1024;
+const H264_ES_TIMEBASE: Fraction = Fraction::new(1, 90_000);
+const DEFAULT_FRAME_RATE: Fraction = Fraction::new(25, 1);
+
+const NAL_SLICE: u8 = 1;
+const NAL_IDR_SLICE: u8 = 5;
+const NAL_SEI: u8 = 6;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+#[derive(Debug, thiserror::Error)]
+pub enum H264EsError {
+    #[error("No SPS and PPS found in stream")]
+    MissingParameterSets,
+
+    #[error("End of stream")]
+    EndOfStream,
+}
+
+impl From<H264EsError> for MediaboxError {
+    fn from(error: H264EsError) -> Self {
+        match error {
+            H264EsError::EndOfStream => MediaboxError::EndOfInput,
+            _ => MediaboxError::invalid_data(error),
+        }
+    }
+}
+
+/// A demuxer for raw H.264 elementary streams in Annex B format, i.e. `.h264` or `.264` files.
+///
+/// Since the elementary stream carries no timing information, timestamps are derived from the
+/// frame rate signaled in the SPS, or from [`H264EsDemuxer::with_frame_rate`]. Packets are
+/// emitted in decode order.
+pub struct H264EsDemuxer {
+    io: Io,
+    frame_rate: Option<Fraction>,
+    buffer: BytesMut,
+    eof: bool,
+    nal_units: VecDeque<Span>,
+    access_unit: Vec<Span>,
+    access_units: VecDeque<Vec<Span>>,
+    track: Option<Track>,
+    frame_duration: u64,
+    frame: u64,
+}
+
+impl H264EsDemuxer {
+    pub fn new(io: Io) -> Self {
+        H264EsDemuxer {
+            io,
+            frame_rate: None,
+            buffer: BytesMut::new(),
+            eof: false,
+            nal_units: VecDeque::new(),
+            access_unit: Vec::new(),
+            access_units: VecDeque::new(),
+            track: None,
+            frame_duration: 0,
+            frame: 0,
+        }
+    }
+
+    /// Overrides the frame rate used to generate timestamps.
+    pub fn with_frame_rate(mut self, frame_rate: Fraction) -> Self {
+        self.frame_rate = Some(frame_rate);
+        self
+    }
+
+    /// Reads more data and splits off all complete NAL units.
+    async fn fill(&mut self) -> anyhow::Result<()> {
+        self.buffer.reserve(READ_SIZE);
+        let n = self.io.read_buf(&mut self.buffer).await?;
+        if n == 0 {
+            self.eof = true;
+        }
+
+        // a NAL unit is only known to be complete once the next start code has been seen
+        let complete = if self.eof {
+            self.buffer.len()
+        } else {
+            match last_start_code(&self.buffer) {
+                Some(pos) => pos,
+                None => return Ok(()),
+            }
+        };
+
+        if complete == 0 {
+            return Ok(());
+        }
+
+        let data = self.buffer.split_to(complete).freeze();
+        let nal_units = parse_bitstream(data.into(), BitstreamFraming::FourByteStartCode);
+
+        self.nal_units
+            .extend(nal_units.into_iter().filter(|n| n.len() > 0));
+
+        Ok(())
+    }
+
+    async fn next_nal_unit(&mut self) -> anyhow::Result<Option<Span>> {
+        while self.nal_units.is_empty() && !self.eof {
+            self.fill().await?;
+        }
+
+        Ok(self.nal_units.pop_front())
+    }
+
+    /// Reads NAL units until a complete access unit has been found.
+    async fn next_access_unit(&mut self) -> anyhow::Result<Vec<Span>> {
+        loop {
+            let Some(nal) = self.next_nal_unit().await? else {
+                if self.access_unit.is_empty() {
+                    return Err(H264EsError::EndOfStream.into());
+                }
+
+                return Ok(std::mem::take(&mut self.access_unit));
+            };
+
+            let has_picture = self.access_unit.iter().any(is_vcl);
+            let starts_access_unit = match nal_unit_type(&nal) {
+                NAL_SLICE | NAL_IDR_SLICE => is_first_slice(&nal),
+                NAL_SEI | NAL_SPS | NAL_PPS | NAL_AUD => true,
+                _ => false,
+            };
+
+            if has_picture && starts_access_unit {
+                let access_unit = std::mem::replace(&mut self.access_unit, vec![nal]);
+
+                return Ok(access_unit);
+            }
+
+            self.access_unit.push(nal);
+        }
+    }
+
+    fn frame_rate(&self, sps: &Span) -> Fraction {
+        use h264_reader::{
+            nal::sps::SeqParameterSet,
+            rbsp::{decode_nal, BitReader},
+        };
+
+        if let Some(frame_rate) = self.frame_rate {
+            return frame_rate;
+        }
+
+        let sps_slice = sps.to_slice();
+        let sps = decode_nal(&sps_slice)
+            .ok()
+            .and_then(|nal| SeqParameterSet::from_bits(BitReader::new(nal.as_ref())).ok());
+
+        sps.as_ref()
+            .and_then(|sps| sps.vui_parameters.as_ref())
+            .and_then(|vui| vui.timing_info.as_ref())
+            .filter(|t| t.num_units_in_tick > 0 && t.time_scale > 0)
+            .map(|t| Fraction::new(t.time_scale / 2, t.num_units_in_tick))
+            .unwrap_or_else(|| {
+                warn!("No frame rate found in SPS, assuming {DEFAULT_FRAME_RATE}");
+
+                DEFAULT_FRAME_RATE
+            })
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for H264EsDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let mut sps = None;
+        let mut pps = None;
+
+        while sps.is_none() || pps.is_none() {
+            let access_unit = match self.next_access_unit().await {
+                Ok(access_unit) => access_unit,
+                Err(_) => return Err(H264EsError::MissingParameterSets.into()),
+            };
+
+            for nal in &access_unit {
+                match nal_unit_type(nal) {
+                    NAL_SPS if sps.is_none() => sps = Some(nal.clone()),
+                    NAL_PPS if pps.is_none() => pps = Some(nal.clone()),
+                    _ => {}
+                }
+            }
+
+            self.access_units.push_back(access_unit);
+        }
+
+        let (sps, pps) = (sps.unwrap(), pps.unwrap());
+
+        let frame_rate = self.frame_rate(&sps);
+        self.frame_duration = H264_ES_TIMEBASE.denominator as u64 * frame_rate.denominator as u64
+            / frame_rate.numerator as u64;
+
+        debug!("H.264 elementary stream at {frame_rate} fps");
+
+        let info = get_codec_from_parameter_sets(sps, pps, BitstreamFraming::FourByteStartCode)?;
+
+        let track = Track {
+            id: 0,
+            info: Arc::new(info),
+            timebase: H264_ES_TIMEBASE,
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        self.track = Some(track.clone());
+
+        Ok(Movie {
+            tracks: vec![track],
+            ..Default::default()
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        let access_unit = match self.access_units.pop_front() {
+            Some(access_unit) => access_unit,
+            None => self.next_access_unit().await?,
+        };
+
+        let key = access_unit
+            .iter()
+            .any(|nal| nal_unit_type(nal) == NAL_IDR_SLICE);
+
+        let time = MediaTime {
+            pts: self.frame * self.frame_duration,
+            dts: None,
+            duration: Some(self.frame_duration),
+            timebase: H264_ES_TIMEBASE,
+        };
+
+        self.frame += 1;
+
+        Ok(Packet {
+            time,
+            key,
+            track: self.track.clone().expect("Demuxer not started"),
+            buffer: frame_nal_units(&access_unit, BitstreamFraming::FourByteStartCode),
+            side_data: Vec::new(),
+        })
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(Self::new(io))
+    }
+
+    fn probe(data: &[u8]) -> ProbeResult {
+        let header = match data {
+            [0, 0, 0, 1, header, ..] | [0, 0, 1, header, ..] => *header,
+            [] | [0] | [0, 0] | [0, 0, 0] | [0, 0, 0, 1] | [0, 0, 1] => {
+                return ProbeResult::NeedMoreData
+            }
+            _ => return ProbeResult::Unsure,
+        };
+
+        let forbidden_zero_bit = header & 0x80 != 0;
+
+        match header & 0x1f {
+            _ if forbidden_zero_bit => ProbeResult::Unsure,
+            NAL_SPS | NAL_AUD => ProbeResult::Maybe(0.75),
+            NAL_SEI => ProbeResult::Maybe(0.5),
+            _ => ProbeResult::Unsure,
+        }
+    }
+}
+
+fn nal_unit_type(nal: &Span) -> u8 {
+    nal.spans()
+        .next()
+        .and_then(|b| b.first())
+        .map(|b| b & 0x1f)
+        .unwrap_or(0)
+}
+
+fn is_vcl(nal: &Span) -> bool {
+    matches!(nal_unit_type(nal), NAL_SLICE | NAL_IDR_SLICE)
+}
+
+/// Whether the slice is the first of a picture, i.e. its `first_mb_in_slice` is 0 (encoded as a
+/// single set bit).
+fn is_first_slice(nal: &Span) -> bool {
+    nal.len() > 1 && nal.slice(1..2).to_slice()[0] & 0x80 != 0
+}
+
+/// Finds the position of the last start code in the buffer, including the leading zero of a 4
+/// byte start code.
+fn last_start_code(data: &[u8]) -> Option<usize> {
+    let pos = data.windows(3).rposition(|w| w == [0, 0, 1])?;
+
+    if pos > 0 && data[pos - 1] == 0 {
+        Some(pos - 1)
+    } else {
+        Some(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x0a, 0xac, 0xd9, 0x41, 0x41, 0xfb, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00,
+        0x10, 0x00, 0x00, 0x03, 0x03, 0x20, 0xf1, 0x22, 0x59, 0x60,
+    ];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+
+    #[tokio::test]
+    async fn split_access_units() {
+        let mut stream = Vec::new();
+        for nal in [
+            SPS,
+            PPS,
+            &[0x65, 0x88, 0x01],
+            &[0x41, 0x9a, 0x02],
+            &[0x41, 0x9a, 0x03],
+        ] {
+            stream.extend_from_slice(&[0, 0, 0, 1]);
+            stream.extend_from_slice(nal);
+        }
+
+        let io = Io::from_reader(Box::new(Cursor::new(stream)));
+        let mut demuxer = H264EsDemuxer::new(io).with_frame_rate(Fraction::new(30, 1));
+
+        let movie = demuxer.start().await.unwrap();
+        assert_eq!(1, movie.tracks.len());
+
+        let mut packets = Vec::new();
+        while let Ok(pkt) = demuxer.read().await {
+            packets.push(pkt);
+        }
+
+        let keys = packets.iter().map(|p| p.key).collect::<Vec<_>>();
+        let pts = packets.iter().map(|p| p.time.pts).collect::<Vec<_>>();
+
+        assert_eq!(vec![true, false, false], keys);
+        assert_eq!(vec![0, 3000, 6000], pts);
+    }
+}
@@ -1,44 +1,164 @@
-use std::{time::Duration, path::Path, fmt, io::Write};
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use log::*;
 
-use crate::{io::Io, Packet, Track};
+use crate::{io::Io, MediaTrackExt, Packet, Span, Track};
 
-use super::{Movie, Muxer};
+use super::{mp4::FragmentedMp4Muxer, DemuxerEvent, Movie, Muxer, MuxerOptionError, MuxerOptions};
+
+const DEFAULT_TARGET_DURATION: Duration = Duration::from_secs(6);
+
+/// Options of the HLS muxer, see [`HlsMuxer::with_options`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HlsOptions {
+    /// See [`HlsMuxer::with_target_duration`].
+    pub segment_duration: Duration,
+    pub playlist_type: HlsPlaylistType,
+}
+
+impl Default for HlsOptions {
+    fn default() -> Self {
+        HlsOptions {
+            segment_duration: DEFAULT_TARGET_DURATION,
+            playlist_type: HlsPlaylistType::Vod,
+        }
+    }
+}
+
+impl TryFrom<&MuxerOptions> for HlsOptions {
+    type Error = MuxerOptionError;
+
+    /// Reads `segment_duration` in seconds and `live_window`, which makes a live playlist of
+    /// that many segments.
+    fn try_from(options: &MuxerOptions) -> Result<Self, Self::Error> {
+        options.expect_only("hls", &["segment_duration", "live_window"])?;
+
+        let default = HlsOptions::default();
+        Ok(HlsOptions {
+            segment_duration: options
+                .get_duration("segment_duration")?
+                .unwrap_or(default.segment_duration),
+            playlist_type: options
+                .get("live_window")?
+                .map(|window| HlsPlaylistType::Live { window })
+                .unwrap_or(default.playlist_type),
+        })
+    }
+}
+
+/// The type of a HLS media playlist.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HlsPlaylistType {
+    /// The playlist is written once all segments are known and will never change.
+    Vod,
+
+    /// The playlist is rewritten after every segment and only lists the `window` most recent
+    /// segments.
+    Live { window: usize },
+}
 
 /// A muxer for the *HTTP Live Streaming* (HLS) format/protocol.
 ///
 /// *Note* that HLS is not just one file, but consists of several playlist files and multiple
-/// media segment files.
+/// media segment files. Only fragmented MP4 segments are produced, MPEG-TS segments are not
+/// supported, so players need to support HLS version 7.
 pub struct HlsMuxer {
-    master_playlist: Io,
+    master_playlist: Arc<Mutex<MasterPlaylist>>,
+    directory: PathBuf,
+    playlist_type: HlsPlaylistType,
+    target_duration: Duration,
     movies: u32,
 }
 
 impl HlsMuxer {
     pub async fn new<P: AsRef<Path> + fmt::Debug>(path: P) -> anyhow::Result<Self> {
-let mut master_playlist= Io::create_file(path).await?;
+        let path = path.as_ref().to_path_buf();
+        let directory = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+
+        let master_playlist = Arc::new(Mutex::new(MasterPlaylist {
+            path,
+            variants: Vec::new(),
+        }));
+        write_master_playlist(&master_playlist).await?;
 
-master_playlist.write(b"#EXTM3U\n").await?;
         Ok(HlsMuxer {
             master_playlist,
+            directory,
+            playlist_type: HlsPlaylistType::Vod,
+            target_duration: DEFAULT_TARGET_DURATION,
             movies: 0,
         })
     }
 
-    async fn write_variant_entry(&mut self, movie: &Movie, path: &str) -> anyhow::Result<()> {
-        let mut entry = Vec::new();
-        write_hls_stream_info_for_movie(&mut entry, movie, 500);
-        writeln!(&mut entry, "{}", path).unwrap();
+    /// Sets both the playlist type and target duration of all streams created after this call.
+    pub fn with_options(self, options: HlsOptions) -> Self {
+        self.with_playlist_type(options.playlist_type)
+            .with_target_duration(options.segment_duration)
+    }
 
-        self.master_playlist.write(&entry).await?;
+    /// Sets the playlist type of all streams created after this call.
+    pub fn with_playlist_type(mut self, playlist_type: HlsPlaylistType) -> Self {
+        self.playlist_type = playlist_type;
+        self
+    }
 
-        Ok(())
+    /// Sets the duration segments are cut at. Segments are always cut on keyframes, so they
+    /// may be longer than this.
+    pub fn with_target_duration(mut self, target_duration: Duration) -> Self {
+        self.target_duration = target_duration;
+        self
     }
-}
 
-pub struct HlsPlaylist {
-    
+    pub async fn new_stream(&mut self, movie: &Movie) -> anyhow::Result<HlsStreamMuxer> {
+        self.movies += 1;
+
+        let name = format!("movie_{}", self.movies);
+        let playlist = format!("{name}.m3u8");
+
+        let variant = Variant {
+            uri: playlist.clone(),
+            codecs: movie.codec_string(),
+            resolution: movie
+                .tracks
+                .video()
+                .and_then(|t| t.info.video())
+                .map(|v| (v.width, v.height)),
+            // replaced by the peak segment bitrate once segments have been written
+            bandwidth: movie.bitrate().unwrap_or(0),
+        };
+
+        let variant_idx = {
+            let mut master = self.master_playlist.lock().unwrap();
+            master.variants.push(variant);
+            master.variants.len() - 1
+        };
+        write_master_playlist(&self.master_playlist).await?;
+
+        Ok(HlsStreamMuxer {
+            master_playlist: self.master_playlist.clone(),
+            variant_idx,
+            directory: self.directory.clone(),
+            name,
+            playlist: self.directory.join(playlist),
+            playlist_type: self.playlist_type,
+            target_duration: self.target_duration,
+            muxer: None,
+            tracks: Vec::new(),
+            reference_track: None,
+            segment: None,
+            segments: Vec::new(),
+            segment_idx: 0,
+            init_idx: 0,
+            discontinuity: false,
+        })
+    }
 }
 
 pub enum HlsMediaType {
@@ -54,69 +174,550 @@ pub struct HlsMedia {
     default: Option<bool>,
 }
 
-impl HlsMuxer {
-    pub async fn new_playlist(&mut self, group: name: &str) {
+struct Variant {
+    uri: String,
+    codecs: Option<String>,
+    resolution: Option<(u32, u32)>,
+    bandwidth: u64,
+}
 
-    }
+struct MasterPlaylist {
+    path: PathBuf,
+    variants: Vec<Variant>,
+}
 
-    pub async fn new_stream(&mut self, movie: &Movie) -> anyhow::Result<HlsStreamMuxer> {
-        self.movies += 1;
+impl MasterPlaylist {
+    fn render(&self) -> Vec<u8> {
+        let mut playlist = Vec::new();
 
-        let path = format!("movie_{}.m3u8", self.movies);
+        writeln!(&mut playlist, "#EXTM3U").unwrap();
+        writeln!(&mut playlist, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
 
-        self.write_variant_entry(movie, &path).await?;
+        for variant in &self.variants {
+            write!(
+                &mut playlist,
+                "#EXT-X-STREAM-INF:BANDWIDTH={}",
+                variant.bandwidth
+            )
+            .unwrap();
 
-        Ok(HlsStreamMuxer {
-            playlist: Io::create_file(&path).await?,
-            segment_idx: 0,
-            segment_duration: Duration::from_secs(0)
-        })
+            if let Some((width, height)) = variant.resolution {
+                write!(&mut playlist, ",RESOLUTION={width}x{height}").unwrap();
+            }
+
+            if let Some(codecs) = &variant.codecs {
+                write!(&mut playlist, ",CODECS=\"{codecs}\"").unwrap();
+            }
+
+            writeln!(&mut playlist).unwrap();
+            writeln!(&mut playlist, "{}", variant.uri).unwrap();
+        }
+
+        playlist
     }
 }
 
+async fn write_master_playlist(master_playlist: &Mutex<MasterPlaylist>) -> anyhow::Result<()> {
+    let (path, playlist) = {
+        let master = master_playlist.lock().unwrap();
+
+        (master.path.clone(), master.render())
+    };
+
+    let mut io = Io::create_file(&path).await?;
+    io.write(&playlist).await?;
+    io.flush().await?;
+
+    Ok(())
+}
+
+/// A media segment which has been completely written.
+struct Segment {
+    uri: String,
+    init_uri: String,
+    discontinuity: bool,
+    duration: Duration,
+    size: u64,
+}
+
+/// The media segment currently being written.
+struct OpenSegment {
+    io: Io,
+    uri: String,
+    init_uri: String,
+    discontinuity: bool,
+    start: Duration,
+    end: Duration,
+    size: u64,
+}
+
+/// Muxes a single variant stream of a [`HlsMuxer`] into fragmented MP4 segments.
 pub struct HlsStreamMuxer {
-    playlist: Io,
+    master_playlist: Arc<Mutex<MasterPlaylist>>,
+    variant_idx: usize,
+    directory: PathBuf,
+    name: String,
+    playlist: PathBuf,
+    playlist_type: HlsPlaylistType,
+    target_duration: Duration,
+    muxer: Option<FragmentedMp4Muxer>,
+    tracks: Vec<u32>,
+    reference_track: Option<u32>,
+    segment: Option<OpenSegment>,
+    segments: Vec<Segment>,
     segment_idx: u32,
-    segment_duration: Duration,
+    init_idx: u32,
+    /// Whether the next segment doesn't continue from the previous one.
+    discontinuity: bool,
 }
 
 #[async_trait]
 impl Muxer for HlsStreamMuxer {
-    async fn start(&mut self, streams: Vec<Track>) -> anyhow::Result<()> {
-        self.write_preamble().await?;
+    async fn start(&mut self, streams: Vec<Track>) -> crate::Result<()> {
+        self.assign_tracks(&streams);
+        self.muxer = Some(FragmentedMp4Muxer::with_streams(&streams));
+        self.write_init_segment().await?;
 
-        todo!()
+        Ok(())
     }
 
-    async fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
-        todo!()
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        if !self.tracks.contains(&packet.track.id) {
+            return Ok(());
+        }
+
+        let is_reference = Some(packet.track.id) == self.reference_track;
+        let time = seconds(packet.time.pts, &packet);
+
+        if is_reference && packet.key {
+            let should_cut = self
+                .segment
+                .as_ref()
+                .map(|s| time.saturating_sub(s.start) >= self.target_duration)
+                .unwrap_or(true);
+
+            if should_cut {
+                // the held back packets belong to the segment before the cut, the one of the
+                // reference track ends where the new segment starts
+                let muxer = self.muxer.as_mut().expect("Muxer not started");
+                let mut data = Vec::from_iter(muxer.take_pending(&packet)?);
+                data.extend(muxer.flush_pending()?);
+                self.write_segment_data(data).await?;
+
+                self.finish_segment(Some(time)).await?;
+                self.open_segment(time).await?;
+            }
+        }
+
+        let segment = match self.segment.as_mut() {
+            Some(segment) => segment,
+            None => {
+                trace!("Dropping packet before first keyframe: {packet:?}");
+                return Ok(());
+            }
+        };
+
+        if is_reference {
+            let duration = packet
+                .time
+                .duration
+                .map(|d| seconds(d, &packet))
+                .unwrap_or_default();
+
+            segment.end = segment.end.max(time + duration);
+        }
+
+        let muxer = self.muxer.as_mut().expect("Muxer not started");
+        let data = muxer.push_packet(packet)?;
+        self.write_segment_data(data).await?;
+
+        Ok(())
     }
 
-    async fn stop(&mut self) -> anyhow::Result<()> {
-        todo!()
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        let muxer = self.muxer.as_mut().expect("Muxer not started");
+        let data = muxer.flush_pending()?;
+        self.write_segment_data(data).await?;
+
+        let muxer = self.muxer.as_mut().expect("Muxer not started");
+        muxer.handle_event(event).await?;
+
+        // players need to be told about the change before the segment following it
+        self.finish_segment(None).await?;
+        self.discontinuity = true;
+
+        match event {
+            DemuxerEvent::NewMovie(movie) => self.assign_tracks(&movie.tracks),
+            DemuxerEvent::TrackParametersChanged(_) => {}
+            DemuxerEvent::Discontinuity => return Ok(()),
+        }
+
+        self.init_idx += 1;
+        self.write_init_segment().await?;
+
+        Ok(())
     }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        if let Some(muxer) = self.muxer.as_mut() {
+            let data = muxer.flush_pending()?;
+            self.write_segment_data(data).await?;
+        }
+
+        self.finish_segment(None).await?;
+
+        self.write_playlist(true).await?;
+
+        Ok(())
+    }
+
+    fn into_io(self) -> Io {
+        Io::null()
+    }
+}
+
+fn seconds(time: u64, packet: &Packet) -> Duration {
+    crate::time::to_duration(time, packet.time.timebase)
 }
 
 impl HlsStreamMuxer {
-    async fn write_preamble(&mut self) -> anyhow::Result<()> {
-        let preamble = b"#EXTM3U
-#EXT-X-PLAYLIST-TYPE:VOD
-#EXT-X-TARGETDURATION:10
-#EXT-X-VERSION:4
-#EXT-X-MEDIA-SEQUENCE:0";
+    fn init_segment_uri(&self) -> String {
+        match self.init_idx {
+            0 => format!("{}_init.mp4", self.name),
+            idx => format!("{}_init_{idx}.mp4", self.name),
+        }
+    }
+
+    fn assign_tracks(&mut self, streams: &[Track]) {
+        // Segments are cut based on the video track if there is one, since that is the only
+        // place where it matters that a segment starts with a keyframe.
+        self.reference_track = streams.video().or_else(|| streams.audio()).map(|t| t.id);
+        self.tracks = [streams.video(), streams.audio()]
+            .into_iter()
+            .flatten()
+            .map(|t| t.id)
+            .collect();
+    }
 
-        self.playlist.write(preamble).await?;
+    async fn write_init_segment(&mut self) -> anyhow::Result<()> {
+        let muxer = self.muxer.as_ref().expect("Muxer not started");
+
+        let mut init = Io::create_file(self.directory.join(self.init_segment_uri())).await?;
+        init.write_span(muxer.initialization_segment()?).await?;
+        init.flush().await?;
+
+        Ok(())
+    }
+
+    /// Appends fragments written by the muxer to the current segment.
+    async fn write_segment_data(&mut self, data: Vec<Span>) -> anyhow::Result<()> {
+        let Some(segment) = &mut self.segment else {
+            return Ok(());
+        };
+
+        for data in data {
+            segment.size += data.len() as u64;
+            segment.io.write_span(data).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn open_segment(&mut self, start: Duration) -> anyhow::Result<()> {
+        let uri = format!("{}_{}.m4s", self.name, self.segment_idx);
+        let io = Io::create_file(self.directory.join(&uri)).await?;
+
+        debug!("Opening HLS segment {uri:?} at {start:?}");
+
+        self.segment_idx += 1;
+        self.segment = Some(OpenSegment {
+            io,
+            uri,
+            init_uri: self.init_segment_uri(),
+            discontinuity: std::mem::take(&mut self.discontinuity),
+            start,
+            end: start,
+            size: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Closes the current segment, if any. The segment ends at `end` if it is known, otherwise
+    /// at the end of the last packet written to it.
+    async fn finish_segment(&mut self, end: Option<Duration>) -> anyhow::Result<()> {
+        let Some(mut segment) = self.segment.take() else {
+            return Ok(());
+        };
+        segment.io.flush().await?;
+
+        let end = end.unwrap_or(segment.end);
+        let duration = end.saturating_sub(segment.start);
+
+        self.segments.push(Segment {
+            uri: segment.uri,
+            init_uri: segment.init_uri,
+            discontinuity: segment.discontinuity,
+            duration,
+            size: segment.size,
+        });
+
+        self.update_bandwidth().await?;
+
+        if let HlsPlaylistType::Live { .. } = self.playlist_type {
+            self.write_playlist(false).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the `BANDWIDTH` attribute of this stream in the master playlist with the peak
+    /// segment bitrate.
+    async fn update_bandwidth(&mut self) -> anyhow::Result<()> {
+        let peak = self
+            .segments
+            .iter()
+            .filter(|s| !s.duration.is_zero())
+            .map(|s| (s.size as f64 * 8.0 / s.duration.as_secs_f64()) as u64)
+            .max()
+            .unwrap_or(0);
+
+        let changed = {
+            let mut master = self.master_playlist.lock().unwrap();
+            let variant = &mut master.variants[self.variant_idx];
+            let changed = variant.bandwidth != peak;
+            variant.bandwidth = peak;
+
+            changed
+        };
+
+        if changed {
+            write_master_playlist(&self.master_playlist).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_playlist(&mut self, end: bool) -> anyhow::Result<()> {
+        let first_seq = match self.playlist_type {
+            HlsPlaylistType::Vod => 0,
+            HlsPlaylistType::Live { window } => self.segments.len().saturating_sub(window),
+        };
+        let (removed, segments) = self.segments.split_at(first_seq);
+        let discontinuity_seq = removed.iter().filter(|s| s.discontinuity).count();
+
+        let target_duration = segments
+            .iter()
+            .map(|s| s.duration.as_secs_f64().ceil() as u64)
+            .max()
+            .unwrap_or(self.target_duration.as_secs());
+
+        let mut playlist = Vec::new();
+
+        writeln!(&mut playlist, "#EXTM3U")?;
+        writeln!(&mut playlist, "#EXT-X-VERSION:7")?;
+        writeln!(&mut playlist, "#EXT-X-TARGETDURATION:{target_duration}")?;
+        writeln!(&mut playlist, "#EXT-X-MEDIA-SEQUENCE:{first_seq}")?;
+        if discontinuity_seq > 0 {
+            writeln!(
+                &mut playlist,
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{discontinuity_seq}"
+            )?;
+        }
+        if self.playlist_type == HlsPlaylistType::Vod {
+            writeln!(&mut playlist, "#EXT-X-PLAYLIST-TYPE:VOD")?;
+        }
+        writeln!(&mut playlist, "#EXT-X-INDEPENDENT-SEGMENTS")?;
+
+        let mut init_uri = None;
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.discontinuity && i > 0 {
+                writeln!(&mut playlist, "#EXT-X-DISCONTINUITY")?;
+            }
+            if init_uri != Some(&segment.init_uri) {
+                writeln!(&mut playlist, "#EXT-X-MAP:URI=\"{}\"", segment.init_uri)?;
+                init_uri = Some(&segment.init_uri);
+            }
+            writeln!(
+                &mut playlist,
+                "#EXTINF:{:.3},",
+                segment.duration.as_secs_f64()
+            )?;
+            writeln!(&mut playlist, "{}", segment.uri)?;
+        }
+
+        if end {
+            writeln!(&mut playlist, "#EXT-X-ENDLIST")?;
+        }
+
+        let mut io = Io::create_file(&self.playlist).await?;
+        io.write(&playlist).await?;
+        io.flush().await?;
 
         Ok(())
     }
 }
 
-fn write_hls_stream_info_for_movie(entry: &mut Vec<u8>, movie: &Movie, bandwidth: u64) {
-    write!(entry, "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth}").unwrap();
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{format::testsrc::TestSrcDemuxer, format::Demuxer, test, Fraction};
+
+    #[tokio::test]
+    async fn time_samples_across_segments() {
+        let dir = std::env::temp_dir().join(format!("mediabox-hls-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let fps = Fraction::new(25, 1);
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(4)).with_color_bars(64, 64, fps);
+        let movie = source.start().await.unwrap();
+
+        let mut hls = HlsMuxer::new(dir.join("master.m3u8"))
+            .await
+            .unwrap()
+            .with_target_duration(Duration::from_secs(1));
+        let mut stream = hls.new_stream(&movie).await.unwrap();
+        stream.start(movie.tracks).await.unwrap();
+        while let Ok(mut pkt) = source.read().await {
+            // timed by the next packet, even across segments
+            pkt.time.duration = None;
+            stream.write(pkt).await.unwrap();
+        }
+        stream.stop().await.unwrap();
+
+        let mut durations = Vec::new();
+        for idx in 0..stream.segments.len() {
+            let segment = std::fs::read(dir.join(format!("movie_1_{idx}.m4s"))).unwrap();
+            for (_, moof) in test::mp4_boxes(&segment)
+                .into_iter()
+                .filter(|(name, _)| name == b"moof")
+            {
+                let trun = test::find_mp4_box(moof, &[b"traf", b"trun"]).unwrap();
+                durations.push(u32::from_be_bytes(trun[12..16].try_into().unwrap()));
+            }
+        }
+
+        assert!(stream.segments.len() > 1);
+        assert_eq!(100, durations.len());
+        assert!(durations.iter().all(|&d| d == durations[0]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes 5 seconds of 25 fps video with keyframes every second, and silent audio, into a
+    /// new HLS stream in an empty directory.
+    async fn write_stream(name: &str, options: HlsOptions) -> (PathBuf, Movie, HlsStreamMuxer) {
+        let dir = std::env::temp_dir().join(format!("mediabox-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(5))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_silence(48_000);
+        let movie = source.start().await.unwrap();
+
+        let mut hls = HlsMuxer::new(dir.join("master.m3u8"))
+            .await
+            .unwrap()
+            .with_options(options);
+        let mut stream = hls.new_stream(&movie).await.unwrap();
+        stream.start(movie.tracks.clone()).await.unwrap();
+        while let Ok(pkt) = source.read().await {
+            stream.write(pkt).await.unwrap();
+        }
+        stream.stop().await.unwrap();
 
-    if let Some(codec) = movie.codec_string() {
-        write!(entry, ",CODECS=\"{codec}\"").unwrap();
+        (dir, movie, stream)
     }
 
-    writeln!(entry).unwrap();
+    fn read_lines(path: PathBuf) -> Vec<String> {
+        let playlist = std::fs::read_to_string(path).unwrap();
+
+        playlist.lines().map(String::from).collect()
+    }
+
+    fn tags<'a>(lines: &'a [String], tag: &str) -> Vec<&'a str> {
+        lines.iter().filter_map(|l| l.strip_prefix(tag)).collect()
+    }
+
+    fn segment_uris(lines: &[String]) -> Vec<&str> {
+        lines
+            .iter()
+            .filter(|l| !l.starts_with('#'))
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cut_segments_on_keyframes() {
+        let options = HlsOptions {
+            segment_duration: Duration::from_millis(1500),
+            ..Default::default()
+        };
+        let (dir, _, _) = write_stream("hls-vod", options).await;
+
+        let lines = read_lines(dir.join("movie_1.m3u8"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the keyframes are a second apart, so segments are cut at the second keyframe
+        assert_eq!(vec!["2.000,", "2.000,", "1.000,"], tags(&lines, "#EXTINF:"));
+        assert_eq!(vec!["2"], tags(&lines, "#EXT-X-TARGETDURATION:"));
+        assert_eq!(vec!["0"], tags(&lines, "#EXT-X-MEDIA-SEQUENCE:"));
+        assert_eq!(vec!["VOD"], tags(&lines, "#EXT-X-PLAYLIST-TYPE:"));
+        assert_eq!(
+            vec!["URI=\"movie_1_init.mp4\""],
+            tags(&lines, "#EXT-X-MAP:")
+        );
+        assert_eq!(
+            vec!["movie_1_0.m4s", "movie_1_1.m4s", "movie_1_2.m4s"],
+            segment_uris(&lines)
+        );
+        assert_eq!(Some("#EXT-X-ENDLIST"), lines.last().map(String::as_str));
+    }
+
+    #[tokio::test]
+    async fn only_list_live_window() {
+        let options = HlsOptions {
+            segment_duration: Duration::from_secs(1),
+            playlist_type: HlsPlaylistType::Live { window: 2 },
+        };
+        let (dir, _, _) = write_stream("hls-live", options).await;
+
+        let lines = read_lines(dir.join("movie_1.m3u8"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(vec!["3"], tags(&lines, "#EXT-X-MEDIA-SEQUENCE:"));
+        assert_eq!(vec!["movie_1_3.m4s", "movie_1_4.m4s"], segment_uris(&lines));
+        assert_eq!(vec!["1.000,", "1.000,"], tags(&lines, "#EXTINF:"));
+        assert_eq!(vec!["1"], tags(&lines, "#EXT-X-TARGETDURATION:"));
+        assert!(tags(&lines, "#EXT-X-PLAYLIST-TYPE:").is_empty());
+    }
+
+    #[tokio::test]
+    async fn describe_streams_in_master_playlist() {
+        let (dir, movie, stream) = write_stream("hls-master", HlsOptions::default()).await;
+
+        let lines = read_lines(dir.join("master.m3u8"));
+        let peak = stream
+            .segments
+            .iter()
+            .map(|s| {
+                let size = std::fs::metadata(dir.join(&s.uri)).unwrap().len();
+                (size as f64 * 8.0 / s.duration.as_secs_f64()) as u64
+            })
+            .max()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let codecs = movie.codec_string().unwrap();
+        assert!(codecs.starts_with("avc1.") && codecs.ends_with(",mp4a.40.02"));
+        assert_eq!(
+            vec![format!(
+                "BANDWIDTH={peak},RESOLUTION=64x64,CODECS=\"{codecs}\""
+            )],
+            tags(&lines, "#EXT-X-STREAM-INF:")
+        );
+        assert_eq!(vec!["movie_1.m3u8"], segment_uris(&lines));
+    }
 }
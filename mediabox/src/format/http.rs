@@ -0,0 +1,411 @@
+//! Serves the output of a muxer to HTTP clients while it is being written, e.g. to preview a live
+//! pipeline in a browser or VLC.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    channel::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    StreamExt,
+};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::*;
+use tokio::io::AsyncWrite;
+
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use crate::{
+    format::{DemuxerEvent, Movie, Muxer},
+    io::Io,
+    MediaTrackExt, Packet, Track,
+};
+
+const DEFAULT_CLIENT_BUFFER: usize = 64;
+
+/// Collects what the muxer writes, so it can be passed on to the clients after every call.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Bytes {
+        std::mem::take(&mut *self.0.lock().unwrap()).into()
+    }
+}
+
+impl AsyncWrite for SharedBuffer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct Client {
+    tx: Sender<Bytes>,
+    /// Whether the client fell behind, in which case it skips ahead to the next key frame.
+    lagging: bool,
+}
+
+#[derive(Default)]
+struct Broadcast {
+    /// What the muxer wrote before the first packet, e.g. an initialization segment.
+    header: Vec<u8>,
+    /// Everything written since the last key frame, which late joiners start from.
+    group: Vec<u8>,
+    clients: Vec<Client>,
+    client_buffer: usize,
+    closed: bool,
+}
+
+impl Broadcast {
+    fn join(&mut self) -> Receiver<Bytes> {
+        let (mut tx, rx) = channel(self.client_buffer);
+
+        let start = [&self.header[..], &self.group[..]].concat();
+        if !start.is_empty() {
+            let _ = tx.try_send(start.into());
+        }
+
+        if !self.closed {
+            self.clients.push(Client { tx, lagging: false });
+        }
+
+        rx
+    }
+
+    /// Sends data to every client which keeps up, where `key` is whether the data starts with a
+    /// key frame.
+    fn send(&mut self, data: Bytes, key: bool) {
+        if key {
+            self.group.clear();
+        }
+        self.group.extend_from_slice(&data);
+
+        self.clients.retain_mut(|client| {
+            if client.lagging && !key {
+                return true;
+            }
+
+            match client.tx.try_send(data.clone()) {
+                Ok(()) => {
+                    client.lagging = false;
+                    true
+                }
+                Err(e) if e.is_full() => {
+                    debug!("HTTP client is lagging behind, skipping to the next key frame");
+                    client.lagging = true;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.clients.clear();
+    }
+}
+
+/// Serves the output of a muxer over HTTP with chunked transfer encoding, to any number of
+/// clients.
+///
+/// Clients which connect later get what the muxer wrote before the first packet, followed by
+/// everything from the last key frame on. Each client has its own buffer, and a client which
+/// can't keep up skips to the next key frame rather than holding up the muxer.
+pub struct HttpStreamingSink {
+    muxer: Box<dyn Muxer>,
+    output: SharedBuffer,
+    state: Arc<Mutex<Broadcast>>,
+    /// The track whose key frames start a new group, or any track if there is no video.
+    key_track: Option<u32>,
+    local_addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl HttpStreamingSink {
+    /// Starts serving on `addr`, with the output of the muxer created by `create`.
+    pub async fn bind(
+        addr: SocketAddr,
+        create: impl FnOnce(Io) -> Box<dyn Muxer>,
+    ) -> crate::Result<Self> {
+        Self::bind_with_content_type(addr, "application/octet-stream", create).await
+    }
+
+    /// Like [HttpStreamingSink::bind], with the `Content-Type` of the responses, e.g.
+    /// `video/x-matroska`.
+    pub async fn bind_with_content_type(
+        addr: SocketAddr,
+        content_type: &str,
+        create: impl FnOnce(Io) -> Box<dyn Muxer>,
+    ) -> crate::Result<Self> {
+        let output = SharedBuffer::default();
+        let state = Arc::new(Mutex::new(Broadcast {
+            client_buffer: DEFAULT_CLIENT_BUFFER,
+            ..Default::default()
+        }));
+
+        let content_type = content_type.to_string();
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let state = service_state.clone();
+            let content_type = content_type.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = respond(&state, &content_type, request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)
+            .map_err(|e| anyhow::anyhow!("Failed to bind {addr}: {e}"))?
+            .serve(make_service);
+        let local_addr = server.local_addr();
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let server = server.with_graceful_shutdown(async {
+                let _ = shutdown_rx.await;
+            });
+            if let Err(e) = server.await {
+                warn!("HTTP streaming server failed: {e}");
+            }
+        });
+        debug!("Serving HTTP stream on {local_addr}");
+
+        Ok(HttpStreamingSink {
+            muxer: create(Io::from_stream(Box::new(output.clone()))),
+            output,
+            state,
+            key_track: None,
+            local_addr,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// How many chunks are buffered for each client before it is considered to be lagging.
+    pub fn with_client_buffer(self, chunks: usize) -> Self {
+        self.state.lock().unwrap().client_buffer = chunks.max(1);
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    /// Makes what the muxer has written so far the start of the stream for new clients.
+    fn publish_header(&mut self) {
+        let header = self.output.take();
+
+        let mut state = self.state.lock().unwrap();
+        state.header = header.to_vec();
+        state.send(header, true);
+        state.group.clear();
+    }
+
+    fn publish(&mut self, key: bool) {
+        let data = self.output.take();
+        if data.is_empty() && !key {
+            return;
+        }
+
+        self.state.lock().unwrap().send(data, key);
+    }
+
+    fn assign_key_track(&mut self, tracks: &[Track]) {
+        self.key_track = tracks.video().map(|t| t.id);
+    }
+}
+
+#[async_trait]
+impl Muxer for HttpStreamingSink {
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+        self.assign_key_track(&tracks);
+        self.muxer.start(tracks).await?;
+        self.publish_header();
+
+        Ok(())
+    }
+
+    async fn start_movie(&mut self, movie: Movie) -> crate::Result<()> {
+        self.assign_key_track(&movie.tracks);
+        self.muxer.start_movie(movie).await?;
+        self.publish_header();
+
+        Ok(())
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        let key = packet.key && self.key_track.map_or(true, |id| id == packet.track.id);
+
+        self.muxer.write(packet).await?;
+        self.publish(key);
+
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        self.muxer.handle_event(event).await?;
+
+        match event {
+            DemuxerEvent::NewMovie(movie) => {
+                self.assign_key_track(&movie.tracks);
+                self.publish_header();
+            }
+            DemuxerEvent::TrackParametersChanged(_) => self.publish_header(),
+            DemuxerEvent::Discontinuity => self.publish(false),
+        }
+
+        Ok(())
+    }
+
+    /// Stops the muxer and ends the responses of all clients.
+    async fn stop(&mut self) -> crate::Result<()> {
+        let result = self.muxer.stop().await;
+        self.publish(false);
+
+        self.state.lock().unwrap().close();
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+
+        result
+    }
+
+    /// The output is sent to the clients, so this returns [Io::null].
+    fn into_io(self) -> Io {
+        Io::null()
+    }
+}
+
+fn respond(state: &Mutex<Broadcast>, content_type: &str, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        return response;
+    }
+
+    let rx = state.lock().unwrap().join();
+    trace!("HTTP client requested {}", request.uri());
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(rx.map(Ok::<_, Infallible>)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        format::mp4::FragmentedMp4Muxer, AacCodec, AudioCodec, AudioInfo, Fraction, MediaInfo,
+        MediaKind, MediaTime, SoundType,
+    };
+
+    #[test]
+    fn lagging_client_skips_to_key_frame() {
+        let mut state = Broadcast {
+            client_buffer: 1,
+            ..Default::default()
+        };
+        let mut rx = state.join();
+
+        // the buffer of a channel has an extra slot for every sender
+        for data in [&b"a"[..], b"b", b"c"] {
+            state.send(Bytes::from_static(data), false);
+        }
+        assert_eq!(Bytes::from_static(b"a"), rx.try_recv().unwrap());
+        assert_eq!(Bytes::from_static(b"b"), rx.try_recv().unwrap());
+
+        state.send(Bytes::from_static(b"d"), false);
+        state.send(Bytes::from_static(b"e"), true);
+        assert_eq!(Bytes::from_static(b"e"), rx.try_recv().unwrap());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn late_joiner_starts_at_key_frame() {
+        let mut sink = HttpStreamingSink::bind("127.0.0.1:0".parse().unwrap(), |io| {
+            Box::new(FragmentedMp4Muxer::new(io))
+        })
+        .await
+        .unwrap();
+
+        let track = Track {
+            id: 1,
+            info: Arc::new(MediaInfo {
+                name: "aac",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 48000,
+                    sample_bpp: 16,
+                    sound_type: SoundType::Stereo,
+                    codec: AudioCodec::Aac(AacCodec {
+                        extra: vec![0x11, 0x90],
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 48000),
+            delay: 0,
+            metadata: Default::default(),
+        };
+        sink.start(vec![track.clone()]).await.unwrap();
+
+        for pts in [0, 1024] {
+            sink.write(Packet {
+                time: MediaTime {
+                    pts,
+                    dts: None,
+                    duration: Some(1024),
+                    timebase: track.timebase,
+                },
+                key: true,
+                track: track.clone(),
+                buffer: vec![0x21, 0x10].into(),
+                side_data: Vec::new(),
+            })
+            .await
+            .unwrap();
+        }
+
+        let uri = format!("http://{}/", sink.local_addr()).parse().unwrap();
+        let response = hyper::Client::new().get(uri).await.unwrap();
+        assert_eq!(1, sink.clients());
+        sink.stop().await.unwrap();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(b"ftyp", &body[4..8]);
+        assert_eq!(1, body.windows(4).filter(|w| w == b"moof").count());
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{format::Muxer, time, MediaTime, Packet, Track};
+
+const DEFAULT_MAX_LATENCY: Duration = Duration::from_secs(1);
+
+/// Buffers packets from multiple tracks and releases them in decode order.
+///
+/// A packet is released once every track has a packet queued, since nothing earlier can arrive
+/// after that. If a track stops delivering packets (e.g. a sparse subtitle track), packets are
+/// released anyway once the queued packets span more than the maximum latency.
+pub struct PacketInterleaver {
+    queues: BTreeMap<u32, VecDeque<Packet>>,
+    max_latency: Duration,
+    latest: Option<Duration>,
+}
+
+impl PacketInterleaver {
+    pub fn new(tracks: &[Track]) -> Self {
+        PacketInterleaver {
+            queues: tracks.iter().map(|t| (t.id, VecDeque::new())).collect(),
+            max_latency: DEFAULT_MAX_LATENCY,
+            latest: None,
+        }
+    }
+
+    /// Sets how far apart the earliest and latest queued packets may be before the earliest is
+    /// released regardless of whether all tracks have packets queued.
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    pub fn push(&mut self, packet: Packet) {
+        let time = decode_time(&packet.time);
+        self.latest = Some(self.latest.map_or(time, |latest| latest.max(time)));
+
+        self.queues
+            .entry(packet.track.id)
+            .or_default()
+            .push_back(packet);
+    }
+
+    /// Returns the next packet in decode order if it is ready to be released.
+    pub fn pop(&mut self) -> Option<Packet> {
+        let (id, time) = self.earliest()?;
+
+        let all_queued = self.queues.values().all(|q| !q.is_empty());
+        let latency = self.latest.unwrap_or_default().saturating_sub(time);
+
+        if all_queued || latency > self.max_latency {
+            self.queues.get_mut(&id)?.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the next packet in decode order, regardless of whether more packets are expected.
+    /// Used to drain the queue at the end of the input.
+    pub fn flush(&mut self) -> Option<Packet> {
+        let (id, _) = self.earliest()?;
+
+        self.queues.get_mut(&id)?.pop_front()
+    }
+
+    /// Queues a packet and writes all packets ready to be released to the muxer.
+    pub async fn write(&mut self, muxer: &mut dyn Muxer, packet: Packet) -> anyhow::Result<()> {
+        self.push(packet);
+
+        while let Some(packet) = self.pop() {
+            muxer.write(packet).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes all queued packets to the muxer.
+    pub async fn finish(&mut self, muxer: &mut dyn Muxer) -> anyhow::Result<()> {
+        while let Some(packet) = self.flush() {
+            muxer.write(packet).await?;
+        }
+
+        Ok(())
+    }
+
+    fn earliest(&self) -> Option<(u32, Duration)> {
+        self.queues
+            .iter()
+            .filter_map(|(id, q)| q.front().map(|p| (*id, decode_time(&p.time))))
+            .min_by_key(|(_, time)| *time)
+    }
+}
+
+fn decode_time(time: &MediaTime) -> Duration {
+    time::to_duration(time.dts.unwrap_or(time.pts), time.timebase)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        codec::{SubtitleCodec, SubtitleInfo, WebVttCodec},
+        test, Fraction, MediaKind,
+    };
+
+    fn track(id: u32, timebase: Fraction) -> Track {
+        let kind = MediaKind::Subtitle(SubtitleInfo {
+            codec: SubtitleCodec::WebVtt(WebVttCodec {
+                header: String::new(),
+            }),
+        });
+
+        test::track(id, timebase, "webvtt", kind)
+    }
+
+    fn packet(track: &Track, pts: u64) -> Packet {
+        test::packet(track, pts, None, Vec::new())
+    }
+
+    #[test]
+    fn releases_in_decode_order() {
+        let a = track(0, Fraction::new(1, 1000));
+        let b = track(1, Fraction::new(1, 90_000));
+        let mut interleaver = PacketInterleaver::new(&[a.clone(), b.clone()]);
+
+        interleaver.push(packet(&a, 0));
+        interleaver.push(packet(&a, 40));
+        assert!(interleaver.pop().is_none());
+
+        interleaver.push(packet(&b, 1800));
+        interleaver.push(packet(&b, 5400));
+
+        let mut released = Vec::new();
+        while let Some(pkt) = interleaver.pop() {
+            released.push((pkt.track.id, pkt.time.pts));
+        }
+        while let Some(pkt) = interleaver.flush() {
+            released.push((pkt.track.id, pkt.time.pts));
+        }
+
+        assert_eq!(vec![(0, 0), (1, 1800), (0, 40), (1, 5400)], released);
+    }
+
+    #[test]
+    fn releases_after_max_latency() {
+        let a = track(0, Fraction::new(1, 1000));
+        let b = track(1, Fraction::new(1, 1000));
+        let mut interleaver =
+            PacketInterleaver::new(&[a.clone(), b]).with_max_latency(Duration::from_millis(100));
+
+        interleaver.push(packet(&a, 0));
+        interleaver.push(packet(&a, 100));
+        assert!(interleaver.pop().is_none());
+
+        interleaver.push(packet(&a, 200));
+        assert_eq!(Some(0), interleaver.pop().map(|p| p.time.pts));
+        assert!(interleaver.pop().is_none());
+    }
+}
@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use log::*;
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    format::{Demuxer, DemuxerEvent, Movie},
+    io::Io,
+    time::{from_duration, to_duration},
+    MediaboxError, Packet, Track, TrackSelector,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    #[error("No inputs to merge")]
+    NoInputs,
+
+    #[error("No tracks matching {1:?} in input {0}")]
+    NoTracks(usize, Vec<TrackSelector>),
+}
+
+impl From<MergeError> for MediaboxError {
+    fn from(error: MergeError) -> Self {
+        MediaboxError::invalid_data(error)
+    }
+}
+
+/// An input of a [`MergeDemuxer`] with the tracks to take from it.
+pub struct MergeInput {
+    demuxer: Box<dyn Demuxer>,
+    selectors: Vec<TrackSelector>,
+    delay: Duration,
+    advance: Duration,
+}
+
+impl MergeInput {
+    /// Takes all tracks of the input.
+    pub fn new(demuxer: Box<dyn Demuxer>) -> Self {
+        MergeInput {
+            demuxer,
+            selectors: Vec::new(),
+            delay: Duration::ZERO,
+            advance: Duration::ZERO,
+        }
+    }
+
+    /// Only takes the tracks matching any of the selectors, see [`TrackSelector::select_all`].
+    pub fn with_tracks(mut self, selectors: Vec<TrackSelector>) -> Self {
+        self.selectors = selectors;
+        self
+    }
+
+    /// Plays the tracks of this input later by `delay`.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Plays the tracks of this input earlier by `advance`. Packets which would start before
+    /// zero are dropped.
+    pub fn with_advance(mut self, advance: Duration) -> Self {
+        self.advance = advance;
+        self
+    }
+}
+
+/// A started input with its next packet.
+struct MergeSource {
+    input: MergeInput,
+    /// The merged tracks, by the id of the track in this input.
+    tracks: HashMap<u32, Track>,
+    next: Option<Packet>,
+    ended: bool,
+}
+
+impl MergeSource {
+    /// Reads the next packet of a selected track into `next`, unless the input has ended.
+    async fn fill(&mut self) -> crate::Result<()> {
+        while self.next.is_none() && !self.ended {
+            let mut pkt = match self.input.demuxer.read().await {
+                Ok(pkt) => pkt,
+                Err(e) if e.is_end_of_input() => {
+                    self.ended = true;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let Some(track) = self.tracks.get(&pkt.track.id) else {
+                continue;
+            };
+
+            let time = &mut pkt.time;
+            let delay = from_duration(self.input.delay, time.timebase);
+            let advance = from_duration(self.input.advance, time.timebase);
+            let Some(pts) = (time.pts + delay).checked_sub(advance) else {
+                trace!("Dropping packet before the start: {pkt:?}");
+                continue;
+            };
+
+            time.pts = pts;
+            time.dts = time.dts.map(|dts| (dts + delay).saturating_sub(advance));
+            pkt.track = track.clone();
+
+            self.next = Some(pkt);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads several inputs at once as a single movie, e.g. to replace the audio of a movie or to
+/// add subtitles from a separate file.
+///
+/// The tracks taken from every input are numbered from 0 in the order of the inputs, and
+/// packets are returned in decode order across all inputs. The attachments, chapters and tags
+/// are those of the first input.
+pub struct MergeDemuxer {
+    sources: Vec<MergeSource>,
+    inputs: Vec<MergeInput>,
+}
+
+impl MergeDemuxer {
+    pub fn new(inputs: Vec<MergeInput>) -> Self {
+        MergeDemuxer {
+            sources: Vec::new(),
+            inputs,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for MergeDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let mut merged: Option<Movie> = None;
+        let mut tracks = Vec::new();
+
+        for (i, mut input) in std::mem::take(&mut self.inputs).into_iter().enumerate() {
+            let movie = input.demuxer.start().await?;
+
+            let selected = TrackSelector::select_all(&input.selectors, &movie.tracks);
+            if selected.is_empty() {
+                return Err(MergeError::NoTracks(i, input.selectors).into());
+            }
+
+            let mut source = MergeSource {
+                input,
+                tracks: HashMap::new(),
+                next: None,
+                ended: false,
+            };
+            for track in selected {
+                let merged = Track {
+                    id: tracks.len() as u32,
+                    ..track.clone()
+                };
+
+                tracks.push(merged.clone());
+                source.tracks.insert(track.id, merged);
+            }
+
+            merged.get_or_insert(movie);
+            self.sources.push(source);
+        }
+
+        let movie = merged.ok_or(MergeError::NoInputs)?;
+
+        Ok(Movie {
+            tracks,
+            duration: None,
+            ..movie
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        for source in &mut self.sources {
+            source.fill().await?;
+        }
+
+        let source = self
+            .sources
+            .iter_mut()
+            .filter(|s| s.next.is_some())
+            .min_by_key(|s| {
+                let time = &s.next.as_ref().unwrap().time;
+                to_duration(time.dts.unwrap_or(time.pts), time.timebase)
+            })
+            .ok_or(MediaboxError::EndOfInput)?;
+
+        Ok(source.next.take().unwrap())
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        for source in &mut self.sources {
+            source.input.demuxer.stop().await?;
+        }
+        self.sources.clear();
+
+        Ok(())
+    }
+
+    /// Passes on the events of all inputs. Since every input is read ahead by a packet, an event
+    /// may be reported one packet early.
+    fn next_event(&mut self) -> Option<DemuxerEvent> {
+        for source in &mut self.sources {
+            while let Some(event) = source.input.demuxer.next_event() {
+                match event {
+                    DemuxerEvent::Discontinuity => return Some(event),
+                    DemuxerEvent::TrackParametersChanged(track) => {
+                        let Some(merged) = source.tracks.get_mut(&track.id) else {
+                            continue;
+                        };
+
+                        merged.info = track.info;
+                        return Some(DemuxerEvent::TrackParametersChanged(merged.clone()));
+                    }
+                    DemuxerEvent::NewMovie(_) => {
+                        warn!("Ignoring new tracks of an input, which can't be merged");
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(MergeDemuxer::new(Vec::new()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{format::testsrc::TestSrcDemuxer, test, Fraction};
+
+    #[tokio::test]
+    async fn merge_audio_of_other_input() {
+        let video = TestSrcDemuxer::new(Duration::from_secs(1))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_silence(48_000);
+        let audio = TestSrcDemuxer::new(Duration::from_secs(1)).with_sine(1000.0, 48_000);
+
+        let mut demuxer = MergeDemuxer::new(vec![
+            MergeInput::new(Box::new(video)).with_tracks(vec![TrackSelector::Video]),
+            MergeInput::new(Box::new(audio)).with_delay(Duration::from_millis(250)),
+        ]);
+        let (movie, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        let names = movie
+            .tracks
+            .iter()
+            .map(|t| (t.id, t.info.name))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(0, "h264"), (1, "pcm")], names);
+
+        let audio = packets.iter().find(|p| p.track.id == 1).unwrap();
+        assert_eq!(12_000, audio.time.pts);
+
+        let times = packets
+            .iter()
+            .map(|p| to_duration(p.time.dts.unwrap_or(p.time.pts), p.time.timebase))
+            .collect::<Vec<_>>();
+        assert!(times.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(25, packets.iter().filter(|p| p.track.id == 0).count());
+    }
+}
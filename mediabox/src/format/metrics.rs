@@ -0,0 +1,246 @@
+use async_trait::async_trait;
+
+use std::sync::Arc;
+
+use crate::{
+    format::{Demuxer, DemuxerEvent, Movie, Muxer, ResumeState, StructureNode, UnsupportedDemuxer},
+    io::Io,
+    Packet, Track,
+};
+
+/// Whether a packet was read from an input or written to an output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Receives what a [`MeteredDemuxer`] or [`MeteredMuxer`] reads and writes, e.g. to export them
+/// as Prometheus counters. Rates like packets per second are left to the sink.
+///
+/// The sink is shared between tasks and called for every packet, so it should only update some
+/// counters, e.g. atomics.
+pub trait MetricsSink: Send + Sync {
+    /// A packet of `size` bytes of `track` passed through the stream named `name`.
+    fn record_packet(&self, name: &str, direction: Direction, track: &Track, size: usize);
+
+    /// Reading or writing failed, other than by reaching the end of the input.
+    fn record_error(&self, name: &str, direction: Direction) {}
+
+    /// The input reported a change, e.g. a publisher reconnected.
+    fn record_event(&self, name: &str, event: &DemuxerEvent) {}
+}
+
+/// Reports the packets read from a demuxer to a [`MetricsSink`].
+pub struct MeteredDemuxer {
+    demuxer: Box<dyn Demuxer>,
+    name: String,
+    sink: Arc<dyn MetricsSink>,
+}
+
+impl MeteredDemuxer {
+    pub fn new(
+        demuxer: Box<dyn Demuxer>,
+        name: impl Into<String>,
+        sink: Arc<dyn MetricsSink>,
+    ) -> Self {
+        MeteredDemuxer {
+            demuxer,
+            name: name.into(),
+            sink,
+        }
+    }
+
+    fn record<T>(&self, result: crate::Result<T>) -> crate::Result<T> {
+        if result.as_ref().is_err_and(|e| !e.is_end_of_input()) {
+            self.sink.record_error(&self.name, Direction::In);
+        }
+
+        result
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for MeteredDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let result = self.demuxer.start().await;
+        self.record(result)
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        let result = self.demuxer.read().await;
+        if let Ok(pkt) = &result {
+            let size = pkt.buffer.len();
+            self.sink
+                .record_packet(&self.name, Direction::In, &pkt.track, size);
+        }
+
+        self.record(result)
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        self.demuxer.stop().await
+    }
+
+    async fn save_state(&mut self) -> crate::Result<ResumeState> {
+        self.demuxer.save_state().await
+    }
+
+    async fn resume(&mut self, state: &ResumeState) -> crate::Result<Movie> {
+        let result = self.demuxer.resume(state).await;
+        self.record(result)
+    }
+
+    async fn dump_structure(&mut self) -> crate::Result<Vec<StructureNode>> {
+        self.demuxer.dump_structure().await
+    }
+
+    fn next_event(&mut self) -> Option<DemuxerEvent> {
+        let event = self.demuxer.next_event()?;
+        self.sink.record_event(&self.name, &event);
+
+        Some(event)
+    }
+
+    /// A metered demuxer always wraps another one, see [`MeteredDemuxer::new`], so this returns
+    /// a demuxer which fails to start.
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(UnsupportedDemuxer("metered demuxer without an input"))
+    }
+}
+
+/// Reports the packets written to a muxer to a [`MetricsSink`].
+pub struct MeteredMuxer {
+    muxer: Box<dyn Muxer>,
+    name: String,
+    sink: Arc<dyn MetricsSink>,
+}
+
+impl MeteredMuxer {
+    pub fn new(muxer: Box<dyn Muxer>, name: impl Into<String>, sink: Arc<dyn MetricsSink>) -> Self {
+        MeteredMuxer {
+            muxer,
+            name: name.into(),
+            sink,
+        }
+    }
+
+    fn record(&self, result: crate::Result<()>) -> crate::Result<()> {
+        if result.is_err() {
+            self.sink.record_error(&self.name, Direction::Out);
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl Muxer for MeteredMuxer {
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+        let result = self.muxer.start(tracks).await;
+        self.record(result)
+    }
+
+    async fn start_movie(&mut self, movie: Movie) -> crate::Result<()> {
+        let result = self.muxer.start_movie(movie).await;
+        self.record(result)
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        let track = packet.track.clone();
+        let size = packet.buffer.len();
+
+        let result = self.muxer.write(packet).await;
+        if result.is_ok() {
+            self.sink
+                .record_packet(&self.name, Direction::Out, &track, size);
+        }
+
+        self.record(result)
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        let result = self.muxer.stop().await;
+        self.record(result)
+    }
+
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        let result = self.muxer.handle_event(event).await;
+        self.record(result)
+    }
+
+    /// The wrapped muxer is boxed and can't give up its [Io], so this returns [Io::null].
+    fn into_io(self) -> Io {
+        Io::null()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        format::{mkv::MatroskaMuxer, testsrc::TestSrcDemuxer},
+        Fraction, MediaboxError,
+    };
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+    /// Sums up the packets and bytes per direction and track.
+    #[derive(Default)]
+    struct Counters {
+        totals: Mutex<HashMap<(Direction, u32), (usize, usize)>>,
+    }
+
+    impl MetricsSink for Counters {
+        fn record_packet(&self, name: &str, direction: Direction, track: &Track, size: usize) {
+            assert_eq!("camera", name);
+
+            let mut totals = self.totals.lock().unwrap();
+            let total = totals.entry((direction, track.id)).or_default();
+            total.0 += 1;
+            total.1 += size;
+        }
+    }
+
+    #[tokio::test]
+    async fn count_packets_in_and_out() {
+        let counters = Arc::new(Counters::default());
+
+        let source = TestSrcDemuxer::new(Duration::from_secs(1))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_silence(48_000);
+        let mut demuxer = MeteredDemuxer::new(Box::new(source), "camera", counters.clone());
+        let mut muxer = MeteredMuxer::new(
+            Box::new(MatroskaMuxer::new(Io::memory())),
+            "camera",
+            counters.clone(),
+        );
+
+        let movie = demuxer.start().await.unwrap();
+        muxer.start_movie(movie).await.unwrap();
+        let mut bytes = 0;
+        while let Ok(pkt) = demuxer.read().await {
+            bytes += pkt.buffer.len();
+            muxer.write(pkt).await.unwrap();
+        }
+        muxer.stop().await.unwrap();
+
+        let totals = counters.totals.lock().unwrap();
+        assert_eq!(25, totals[&(Direction::In, 0)].0);
+        assert_eq!(totals[&(Direction::In, 0)], totals[&(Direction::Out, 0)]);
+        assert_eq!(totals[&(Direction::In, 1)], totals[&(Direction::Out, 1)]);
+        assert_eq!(
+            bytes,
+            totals[&(Direction::In, 0)].1 + totals[&(Direction::In, 1)].1
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_to_start_without_an_input() {
+        let mut demuxer = MeteredDemuxer::create(Io::null());
+
+        assert!(matches!(
+            demuxer.start().await,
+            Err(MediaboxError::Unsupported(_))
+        ));
+    }
+}
@@ -1,12 +1,38 @@
+/// Writes a master element, patching in its size once the contents have been written.
+macro_rules! write_element {
+    ($buf:expr, $id:expr, $b:block) => {{
+        let _: &mut $crate::SpanBuilder = $buf; // type-check.
+        ebml::write_id($buf, $id);
+        // always use 8 byte sizes so they can be filled in afterwards
+        let size = $buf.reserve(8);
+        let r = {
+            $b;
+        };
+        let mut len = (($buf.len() - size.end()) as u64).to_be_bytes();
+        len[0] = 0x01;
+        $buf.patch(size, &len);
+        r
+    }};
+}
+
 mod ebml;
 mod demux;
 mod mux;
+mod structure;
+mod validate;
+pub mod webm;
 
+use crate::MediaboxError;
 use ebml::*;
 pub use demux::*;
 pub use mux::*;
+pub use validate::*;
 
 const EBML_HEADER: u32 = 0x1a45dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
 const EBML_DOC_TYPE: u32 = 0x4282;
 const EBML_DOC_TYPE_VERSION: u32 = 0x4287;
 const EBML_DOC_TYPE_READ_VERSION: u32 = 0x4285;
@@ -15,10 +41,14 @@ const SEEK_HEAD: u32 = 0x114d9b74;
 const SEEK: u32 = 0x4dbb;
 const SEEK_ID: u32 = 0x53ab;
 const SEEK_POSITION: u32 = 0x53ac;
+const VOID: u32 = 0xec;
+const CRC32: u32 = 0xbf;
 const INFO: u32 = 0x1549a966;
 const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
 const DURATION: u32 = 0x4489;
 const DATE_UTC: u32 = 0x4461;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
 const TRACKS: u32 = 0x1654ae6b;
 const TRACK_ENTRY: u32 = 0xae;
 const TRACK_NUMBER: u32 = 0xd7;
@@ -26,17 +56,84 @@ const TRACK_UID: u32 = 0x73c5;
 const TRACK_TYPE: u32 = 0x83;
 const CODEC_ID: u32 = 0x86;
 const CODEC_PRIVATE: u32 = 0x63a2;
+const CODEC_DELAY: u32 = 0x56aa;
+const SEEK_PRE_ROLL: u32 = 0x56bb;
+const DEFAULT_DURATION: u32 = 0x23e383;
+const TRACK_TIMESTAMP_SCALE: u32 = 0x23314f;
+const NAME: u32 = 0x536e;
+const LANGUAGE: u32 = 0x22b59c;
+const LANGUAGE_BCP47: u32 = 0x22b59d;
+const FLAG_DEFAULT: u32 = 0x88;
+const FLAG_FORCED: u32 = 0x55aa;
 const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const COLOUR_SPACE: u32 = 0x2eb524;
 const AUDIO: u32 = 0xe1;
 const SAMPLING_FREQUENCY: u32 = 0xb5;
 const CHANNELS: u32 = 0x9f;
 const BIT_DEPTH: u32 = 0x6264;
 const CLUSTER: u32 = 0x1f43b675;
 const TIMESTAMP: u32 = 0xe7;
+const SILENT_TRACKS: u32 = 0x5854;
+const POSITION: u32 = 0xa7;
+const PREV_SIZE: u32 = 0xab;
+const ENCRYPTED_BLOCK: u32 = 0xaf;
 const SIMPLE_BLOCK: u32 = 0xa3;
 const BLOCK_GROUP: u32 = 0xa0;
 const BLOCK: u32 = 0xa1;
 const BLOCK_DURATION: u32 = 0x9b;
+const CUES: u32 = 0x1c53bb6b;
+const CUE_POINT: u32 = 0xbb;
+const CUE_TIME: u32 = 0xb3;
+const CUE_TRACK_POSITIONS: u32 = 0xb7;
+const CUE_TRACK: u32 = 0xf7;
+const CUE_CLUSTER_POSITION: u32 = 0xf1;
+const TAGS: u32 = 0x1254c367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63c0;
+const TARGET_TYPE_VALUE: u32 = 0x68ca;
+const TAG_TRACK_UID: u32 = 0x63c5;
+const TAG_EDITION_UID: u32 = 0x63c9;
+const TAG_CHAPTER_UID: u32 = 0x63c4;
+const TAG_ATTACHMENT_UID: u32 = 0x63c6;
+const SIMPLE_TAG: u32 = 0x67c8;
+const TAG_NAME: u32 = 0x45a3;
+const TAG_STRING: u32 = 0x4487;
+const ATTACHMENTS: u32 = 0x1941a469;
+const ATTACHED_FILE: u32 = 0x61a7;
+const FILE_NAME: u32 = 0x466e;
+const FILE_MEDIA_TYPE: u32 = 0x4660;
+const FILE_DATA: u32 = 0x465c;
+const FILE_UID: u32 = 0x46ae;
+const CHAPTERS: u32 = 0x1043a770;
+const EDITION_ENTRY: u32 = 0x45b9;
+const CHAPTER_ATOM: u32 = 0xb6;
+const CHAPTER_UID: u32 = 0x73c4;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_TIME_END: u32 = 0x92;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+
+/// Tags targeting this level apply to the whole movie.
+const TARGET_TYPE_MOVIE: u64 = 50;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+const TRACK_TYPE_SUBTITLE: u64 = 0x11;
+
+/// The duration of frames in nanoseconds, for codecs where every frame has the same number of
+/// samples.
+fn fixed_frame_duration(info: &crate::MediaInfo) -> Option<u64> {
+    match info.audio()? {
+        crate::AudioInfo {
+            codec: crate::AudioCodec::Aac(_),
+            sample_rate,
+            ..
+        } if *sample_rate > 0 => Some(1024 * 1_000_000_000 / *sample_rate as u64),
+        _ => None,
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum MkvError {
@@ -49,6 +146,18 @@ pub enum MkvError {
     #[error("Unsupported variable integer ID: {0}")]
     UnsupportedVid(u8),
 
+    #[error("Invalid block lacing")]
+    InvalidLacing,
+
+    #[error("Invalid block size: {0}")]
+    InvalidBlockSize(u64),
+
+    #[error("Invalid timestamp scale: {0}")]
+    InvalidTimestampScale(u64),
+
+    #[error("Invalid codec private data for {0}")]
+    InvalidCodecPrivate(&'static str),
+
     #[error("Invalid float size: {0}")]
     InvalidFloatSize(u64),
 
@@ -58,6 +167,12 @@ pub enum MkvError {
     #[error("No element 0x{0:08x} was found")]
     MissingElement(u32),
 
+    #[error("Unexpected element 0x{0:08x} between blocks")]
+    UnexpectedElement(u32),
+
+    #[error("Invalid EBML structure: {} ({} problems)", .0[0], .0.len())]
+    InvalidStructure(Vec<Diagnostic>),
+
     #[error("Invalid UTF-8: {0}")]
     Utf8Error(#[from] std::string::FromUtf8Error),
 
@@ -67,22 +182,38 @@ pub enum MkvError {
     #[error("{0}")]
     StdIo(#[from] std::io::Error),
 
+    #[error("{0}")]
+    AvcConfig(#[from] crate::codec::h264::AvcConfigError),
+
     // lazy
     #[error("{0}")]
     Misc(#[from] anyhow::Error),
 }
 
+impl From<MkvError> for MediaboxError {
+    fn from(error: MkvError) -> Self {
+        match error {
+            MkvError::NotEnoughData => MediaboxError::EndOfInput,
+            MkvError::UnsupportedVint(_) | MkvError::UnsupportedVid(_) => {
+                MediaboxError::unsupported(error.to_string())
+            }
+            MkvError::Io(e) => e.into(),
+            MkvError::StdIo(e) => e.into(),
+            MkvError::Misc(e) => e.into(),
+            _ => MediaboxError::invalid_data(error),
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
-
     use test_case::test_case;
     use tokio::io::BufReader;
 
-    use crate::{format::{Muxer, Demuxer}, test_files, test::{TestFile, self}, io::Io};
+    use crate::{format::{Muxer, Demuxer, Movie, ResumeState}, test_files, test::{TestFile, self}, io::Io};
 
-    use super::{MatroskaDemuxer, MatroskaMuxer};
+    use super::*;
 
     test_files!{
         #[tokio::test]
@@ -94,13 +225,737 @@ mod test {
 
             test::write_movie_and_packets(&mut muxer, movie, &packets).await;
 
-            let buffer: Box<Vec<u8>> = muxer.into_io().into_writer().unwrap();
-            let buffer = Box::new(Cursor::new(*buffer));
-            let mut demuxer  = MatroskaDemuxer::new(Io::from_reader(buffer));
+            let buffer = muxer.into_io().into_bytes().unwrap();
+            let mut demuxer  = MatroskaDemuxer::new(Io::from_bytes(buffer));
 
             let (new_movie, new_packets) = test::read_movie_and_packets(&mut demuxer).await;
 
 
         }
     }
+
+    #[tokio::test]
+    async fn write_read_generated_packets() {
+        use crate::{format::testsrc::TestSrcDemuxer, Fraction};
+        use std::time::Duration;
+
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(2))
+            .with_color_bars(320, 240, Fraction::new(25, 1))
+            .with_silence(48000);
+        let (movie, packets) = test::read_movie_and_packets(&mut source).await;
+
+        let io = Io::from_stream(Box::new(Vec::<u8>::new()));
+        let mut muxer = MatroskaMuxer::new(io);
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let (new_movie, new_packets) = test::read_mkv_from_io(Io::from_bytes(buffer)).await;
+
+        assert_eq!(2, new_movie.tracks.len());
+        assert_eq!(packets.len(), new_packets.len());
+        for (pkt, new_pkt) in packets.iter().zip(&new_packets) {
+            assert_eq!(pkt.track.id + 1, new_pkt.track.id);
+            assert_eq!(pkt.key, new_pkt.key);
+            assert_eq!(pkt.buffer.to_bytes(), new_pkt.buffer.to_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn codec_delay_is_written_in_nanoseconds() {
+        use crate::{time::to_duration, Fraction, Track};
+        use std::time::Duration;
+
+        // three AAC frames of priming samples, i.e. 64 ms
+        let track = Track { timebase: Fraction::new(1024, 48_000), delay: 3, ..test::aac_track() };
+        let packets = (0..4).map(|i| test::packet(&track, i, Some(1), vec![0; 8])).collect::<Vec<_>>();
+        let movie = Movie { tracks: vec![track], ..Default::default() };
+
+        let io = Io::from_stream(Box::new(Vec::<u8>::new()));
+        let mut muxer = MatroskaMuxer::new(io);
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let (new_movie, _) = test::read_mkv_from_io(Io::from_bytes(buffer)).await;
+
+        let track = &new_movie.tracks[0];
+        assert_eq!(Duration::from_millis(64), to_duration(track.delay, track.timebase));
+    }
+
+    fn element(id: u32, data: &[u8]) -> Vec<u8> {
+        let id = id.to_be_bytes();
+        let skip = id.iter().take_while(|&&b| b == 0).count();
+
+        let mut buf = id[skip..].to_vec();
+        buf.push(0x01); // 8 byte size
+        buf.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        buf.extend_from_slice(data);
+
+        buf
+    }
+
+    fn subtitle_mkv_with_clusters(clusters: &[Vec<u8>]) -> Vec<u8> {
+        let track = element(TRACK_ENTRY, &[
+            element(TRACK_NUMBER, &[1]),
+            element(CODEC_ID, b"S_TEXT/ASS"),
+            element(CODEC_PRIVATE, b"[Script Info]"),
+        ].concat());
+
+        let mut segment = [
+            element(INFO, &element(TIMESTAMP_SCALE, &1_000_000u32.to_be_bytes())),
+            element(TRACKS, &track),
+        ].concat();
+
+        for cluster in clusters {
+            segment.extend(element(CLUSTER, cluster));
+        }
+
+        [element(EBML_HEADER, &element(EBML_DOC_TYPE, b"matroska")), element(SEGMENT, &segment)].concat()
+    }
+
+    fn subtitle_mkv() -> Vec<u8> {
+        let clusters = (0..3u8).map(|cluster| {
+            let mut data = element(TIMESTAMP, &[cluster]);
+            for block in 0..3u8 {
+                data.extend(element(SIMPLE_BLOCK, &[0x81, 0, block, 0x80, cluster, block]));
+            }
+
+            data
+        }).collect::<Vec<_>>();
+
+        subtitle_mkv_with_clusters(&clusters)
+    }
+
+    #[tokio::test]
+    async fn resume_continues_with_next_packet() {
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(subtitle_mkv()));
+
+        demuxer.start().await.unwrap();
+        for _ in 0..4 {
+            demuxer.read().await.unwrap();
+        }
+
+        let state = demuxer.save_state().await.unwrap();
+        let expected = demuxer.read().await.unwrap();
+
+        let state = ResumeState::from_bytes(&state.to_bytes()).unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(subtitle_mkv()));
+
+        let movie = demuxer.resume(&state).await.unwrap();
+        let pkt = demuxer.read().await.unwrap();
+
+        assert_eq!(1, movie.tracks.len());
+        assert_eq!(expected.time.pts, pkt.time.pts);
+        assert_eq!(expected.buffer.to_slice(), pkt.buffer.to_slice());
+    }
+
+    fn overflowing_ebml_lacing() -> Vec<u8> {
+        // 256 frames, each one 2^55 bytes larger than the one before
+        let mut block = vec![0x81, 0, 0, 0x86, 0xff];
+        for _ in 0..255 {
+            block.extend([0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        }
+
+        element(SIMPLE_BLOCK, &block)
+    }
+
+    #[test_case(element(SIMPLE_BLOCK, &[0x81]) ; "block shorter than its header")]
+    #[test_case([&[0xa3, 0x01, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe][..], &[0x81, 0, 0, 0x80]].concat() ; "block larger than the input")]
+    #[test_case(overflowing_ebml_lacing() ; "overflowing lace sizes")]
+    #[test_case([element(TIMESTAMP, &[0xff; 8]), element(SIMPLE_BLOCK, &[0x81, 0x7f, 0xff, 0x80, 1])].concat() ; "overflowing timestamp")]
+    #[tokio::test]
+    async fn malformed_blocks_are_errors(cluster: Vec<u8>) {
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(subtitle_mkv_with_clusters(&[cluster])));
+
+        demuxer.start().await.unwrap();
+        while demuxer.read().await.is_ok() {}
+    }
+
+    /// Returns a single byte for every read.
+    struct ByteReader(std::io::Cursor<Vec<u8>>);
+
+    impl tokio::io::AsyncRead for ByteReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let mut byte = [0];
+            let n = std::io::Read::read(&mut self.0, &mut byte)?;
+            buf.put_slice(&byte[..n]);
+
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test_case(vec![0; 10], 9 ; "invalid id")]
+    #[test_case(element(TRACK_NUMBER, &[1]), 1 ; "unexpected element")]
+    #[tokio::test]
+    async fn resync_after_corrupt_data(garbage: Vec<u8>, skipped: u64) {
+        let first = [element(TIMESTAMP, &[0]), element(SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 0]), garbage].concat();
+        let second = [element(TIMESTAMP, &[1]), element(SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 1])].concat();
+        let data = subtitle_mkv_with_clusters(&[first, second]);
+
+        // the ID of the next cluster is split between reads from a stream
+        let stream = Io::from_reader(Box::new(ByteReader(std::io::Cursor::new(data.clone()))));
+
+        for io in [Io::from_bytes(data), stream] {
+            let mut demuxer = MatroskaDemuxer::new(io);
+            let (_, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+            assert_eq!(vec![0, 1], packets.iter().map(|p| p.time.pts).collect::<Vec<_>>());
+            assert_eq!(&MatroskaStats { resyncs: 1, skipped_bytes: skipped }, demuxer.stats());
+        }
+    }
+
+    #[tokio::test]
+    async fn report_truncated_block() {
+        let cluster = [element(TIMESTAMP, &[0]), element(SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 0, 1, 2])].concat();
+        let mut data = subtitle_mkv_with_clusters(&[cluster]);
+        data.truncate(data.len() - 2);
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(data));
+        let error = test::try_read_movie_and_packets(&mut demuxer).await.unwrap_err();
+
+        assert!(matches!(error, MediaboxError::Truncated(_)), "{error}");
+    }
+
+    #[tokio::test]
+    async fn zero_timestamp_scale_is_an_error() {
+        let segment = element(INFO, &element(TIMESTAMP_SCALE, &[0]));
+        let data = [element(EBML_HEADER, &element(EBML_DOC_TYPE, b"matroska")), element(SEGMENT, &segment)].concat();
+
+        assert!(MatroskaDemuxer::new(Io::from_bytes(data)).start().await.is_err());
+    }
+
+    fn ass_track() -> crate::Track {
+        use crate::{codec::{AssCodec, SubtitleCodec, SubtitleInfo}, Fraction, MediaInfo, MediaKind, Track};
+        use std::sync::Arc;
+
+        Track {
+            id: 1,
+            info: Arc::new(MediaInfo {
+                name: "ass",
+                kind: MediaKind::Subtitle(SubtitleInfo {
+                    codec: SubtitleCodec::Ass(AssCodec { header: "[Script Info]".into() }),
+                }),
+            }),
+            timebase: Fraction::new(1, 1000),
+            delay: 0,
+            metadata: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn shift_negative_timestamps() {
+        let cluster = [
+            element(TIMESTAMP, &[0]),
+            element(SIMPLE_BLOCK, &[&[0x81][..], &(-20i16).to_be_bytes(), &[0x80, 1]].concat()),
+            element(SIMPLE_BLOCK, &[0x81, 0, 10, 0x80, 2]),
+        ].concat();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(subtitle_mkv_with_clusters(&[cluster])));
+
+        let (movie, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        assert_eq!(std::time::Duration::from_millis(20), movie.start_offset);
+        assert_eq!(vec![0, 30], packets.iter().map(|p| p.time.pts).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn write_statistics_tags() {
+        use crate::{MediaTime, Packet};
+
+        let track = ass_track();
+
+        let packets = (0..4u64).map(|i| Packet {
+            time: MediaTime {
+                pts: i * 1000,
+                dts: None,
+                duration: Some(500),
+                timebase: track.timebase,
+            },
+            key: true,
+            track: track.clone(),
+            buffer: vec![0u8; 250].into(),
+            side_data: Vec::new(),
+        }).collect::<Vec<_>>();
+
+        // the tags are written after the clusters, and found through the SeekHead
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        let movie = Movie { tracks: vec![track], ..Default::default() };
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let contains = |needle: &[u8]| buffer.windows(needle.len()).any(|w| w == needle);
+
+        assert!(contains(b"NUMBER_OF_FRAMES\x44\x87\x81\x34"));
+        assert!(contains(b"NUMBER_OF_BYTES\x44\x87\x84\x31\x30\x30\x30"));
+        assert!(contains(b"DURATION\x44\x87\x92\x30\x30:00:03.500000000"));
+        // 1000 bytes over 3.5 s
+        assert!(contains(b"BPS\x44\x87\x84\x32\x32\x38\x35"));
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let (new_movie, new_packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        assert_eq!(1, new_movie.tracks.len());
+        assert_eq!(Some(2285), new_movie.tracks[0].bitrate());
+        assert_eq!(Some(std::time::Duration::from_millis(3500)), new_movie.tracks[0].metadata.duration);
+        assert_eq!(
+            packets.iter().map(|p| (p.time.pts, p.time.duration)).collect::<Vec<_>>(),
+            new_packets.iter().map(|p| (p.time.pts, p.time.duration)).collect::<Vec<_>>(),
+        );
+    }
+
+    #[tokio::test]
+    async fn read_duration_and_statistics_tags() {
+        let track = element(TRACK_ENTRY, &[
+            element(TRACK_NUMBER, &[1]),
+            element(TRACK_UID, &[0x42]),
+            element(CODEC_ID, b"S_TEXT/ASS"),
+            element(CODEC_PRIVATE, b"[Script Info]"),
+        ].concat());
+        let simple_tag = |name: &str, value: &str| {
+            element(SIMPLE_TAG, &[element(TAG_NAME, name.as_bytes()), element(TAG_STRING, value.as_bytes())].concat())
+        };
+        let tag = element(TAG, &[
+            element(TARGETS, &element(TAG_TRACK_UID, &[0x42])),
+            simple_tag("BPS", "2285"),
+            simple_tag("DURATION", "00:00:03.500000000"),
+        ].concat());
+
+        let segment = [
+            element(INFO, &[
+                element(TIMESTAMP_SCALE, &1_000_000u32.to_be_bytes()),
+                element(DURATION, &4000.5f64.to_be_bytes()),
+            ].concat()),
+            element(TRACKS, &track),
+            element(TAGS, &tag),
+        ].concat();
+        let data = [element(EBML_HEADER, &element(EBML_DOC_TYPE, b"matroska")), element(SEGMENT, &segment)].concat();
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(data));
+        let movie = demuxer.start().await.unwrap();
+
+        assert_eq!(Some(std::time::Duration::from_micros(4_000_500)), movie.duration());
+        assert_eq!(Some(2285), movie.tracks[0].bitrate());
+        assert_eq!(Some(std::time::Duration::from_millis(3500)), movie.tracks[0].metadata.duration);
+        assert!(movie.metadata.is_empty());
+    }
+
+    #[tokio::test]
+    async fn default_duration_of_blocks() {
+        let track = element(TRACK_ENTRY, &[
+            element(TRACK_NUMBER, &[1]),
+            element(CODEC_ID, b"S_TEXT/ASS"),
+            element(CODEC_PRIVATE, b"[Script Info]"),
+            element(DEFAULT_DURATION, &40_000_000u32.to_be_bytes()),
+        ].concat());
+        let cluster = [
+            element(TIMESTAMP, &[0]),
+            element(SIMPLE_BLOCK, &[0x81, 0, 0, 0x80, 1]),
+            element(BLOCK_GROUP, &[
+                element(BLOCK, &[0x81, 0, 40, 0, 2]),
+                element(BLOCK_DURATION, &[100]),
+            ].concat()),
+        ].concat();
+
+        let segment = [
+            element(INFO, &element(TIMESTAMP_SCALE, &1_000_000u32.to_be_bytes())),
+            element(TRACKS, &track),
+            element(CLUSTER, &cluster),
+        ].concat();
+        let data = [element(EBML_HEADER, &element(EBML_DOC_TYPE, b"matroska")), element(SEGMENT, &segment)].concat();
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(data));
+        let (_, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        assert_eq!(
+            vec![(0, Some(40)), (40, Some(100))],
+            packets.iter().map(|p| (p.time.pts, p.time.duration)).collect::<Vec<_>>(),
+        );
+    }
+
+    #[tokio::test]
+    async fn write_read_attachments() {
+        use crate::format::Attachment;
+
+        let font = (0..=255u8).cycle().take(1000).collect::<Vec<_>>();
+        let movie = Movie {
+            tracks: vec![ass_track()],
+            attachments: vec![Attachment {
+                name: "font.ttf".into(),
+                mime: "font/ttf".into(),
+                data: font.clone().into(),
+            }],
+            ..Default::default()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let movie = demuxer.start().await.unwrap();
+
+        assert_eq!(1, movie.tracks.len());
+        assert_eq!(1, movie.attachments.len());
+
+        let attachment = &movie.attachments[0];
+        assert_eq!(("font.ttf", "font/ttf"), (&attachment.name[..], &attachment.mime[..]));
+        assert_eq!(font, attachment.data.to_slice().to_vec());
+    }
+
+    #[tokio::test]
+    async fn write_read_bitmap_subtitles() {
+        use crate::codec::{SubtitleCodec, SubtitleInfo, VobSubCodec};
+        use crate::{MediaInfo, MediaKind};
+        use std::sync::Arc;
+
+        let idx = "size: 720x480\npalette: 000000, ffffff, 808080, 00ff00\n";
+        let subtitle = |id, name, codec| crate::Track {
+            id,
+            info: Arc::new(MediaInfo {
+                name,
+                kind: MediaKind::Subtitle(SubtitleInfo { codec }),
+            }),
+            ..ass_track()
+        };
+        let movie = Movie {
+            tracks: vec![
+                subtitle(1, "pgs", SubtitleCodec::Pgs),
+                subtitle(2, "vobsub", SubtitleCodec::VobSub(VobSubCodec { idx: idx.into() })),
+            ],
+            ..Default::default()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let movie = demuxer.start().await.unwrap();
+
+        let names = movie.tracks.iter().map(|t| t.info.name).collect::<Vec<_>>();
+        assert_eq!(vec!["pgs", "vobsub"], names);
+
+        let Some(SubtitleInfo { codec: SubtitleCodec::VobSub(vobsub) }) = movie.tracks[1].info.subtitle() else {
+            panic!("Expected a VobSub track");
+        };
+        assert_eq!(idx, vobsub.idx);
+        assert_eq!(Some(vec![0, 0xffffff, 0x808080, 0xff00]), vobsub.palette());
+    }
+
+    #[tokio::test]
+    async fn write_read_track_metadata() {
+        use crate::TrackMetadata;
+
+        let metadata = TrackMetadata {
+            language: Some("jpn".into()),
+            title: Some("Signs".into()),
+            default: false,
+            forced: true,
+            ..Default::default()
+        };
+        let mut english = ass_track();
+        english.id = 2;
+        english.metadata.language = Some("en-US".into());
+
+        let movie = Movie {
+            tracks: vec![crate::Track { metadata: metadata.clone(), ..ass_track() }, english],
+            ..Default::default()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let movie = demuxer.start().await.unwrap();
+
+        assert_eq!(metadata, movie.tracks[0].metadata);
+        assert_eq!(Some("en-US"), movie.tracks[1].metadata.language.as_deref());
+        assert!(movie.tracks[1].metadata.default);
+    }
+
+    #[tokio::test]
+    async fn write_read_xiph_audio() {
+        use crate::{AudioCodec, AudioInfo, FlacCodec, MediaInfo, MediaKind, OpusCodec, SoundType, Track, VorbisCodec};
+        use std::sync::Arc;
+
+        let audio = |id, name, codec| Track {
+            id,
+            info: Arc::new(MediaInfo {
+                name,
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 48000,
+                    sample_bpp: 16,
+                    sound_type: SoundType::Stereo,
+                    codec,
+                }),
+            }),
+            ..ass_track()
+        };
+
+        let header = b"OpusHead\x01\x02\x38\x01\x80\xbb\0\0\0\0\0".to_vec();
+        let headers = [vec![1; 30], vec![3; 300], vec![5; 4000]];
+        let flac = FlacCodec::from_stream_info(&[7; 34]).header;
+        let movie = Movie {
+            tracks: vec![
+                audio(1, "opus", AudioCodec::Opus(OpusCodec { header: header.clone() })),
+                audio(2, "vorbis", AudioCodec::Vorbis(VorbisCodec { headers: headers.clone() })),
+                audio(3, "flac", AudioCodec::Flac(FlacCodec { header: flac.clone() })),
+            ],
+            ..Default::default()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let movie = demuxer.start().await.unwrap();
+
+        let codecs = movie.tracks.iter().map(|t| (t.info.name, &t.info.audio().unwrap().codec)).collect::<Vec<_>>();
+        assert!(matches!(codecs[0], ("opus", AudioCodec::Opus(OpusCodec { header: h })) if *h == header));
+        assert!(matches!(codecs[1], ("vorbis", AudioCodec::Vorbis(VorbisCodec { headers: h })) if *h == headers));
+        assert!(matches!(codecs[2], ("flac", AudioCodec::Flac(FlacCodec { header: h })) if *h == flac));
+    }
+
+    #[test_case(crate::SampleFormat::S16Be ; "s16be")]
+    #[test_case(crate::SampleFormat::S24Le ; "s24le")]
+    #[test_case(crate::SampleFormat::F64Le ; "f64le")]
+    #[tokio::test]
+    async fn write_read_pcm(format: crate::SampleFormat) {
+        use crate::{AudioCodec, AudioInfo, MediaInfo, MediaKind, PcmCodec, SoundType, Track};
+        use std::sync::Arc;
+
+        let track = Track {
+            info: Arc::new(MediaInfo {
+                name: "pcm",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 44100,
+                    sample_bpp: format.bytes_per_sample() as u32 * 8,
+                    sound_type: SoundType::Mono,
+                    codec: AudioCodec::Pcm(PcmCodec { format }),
+                }),
+            }),
+            ..ass_track()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, Movie { tracks: vec![track], ..Default::default() }, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let movie = demuxer.start().await.unwrap();
+
+        assert!(matches!(movie.tracks[0].info.audio().unwrap().codec, AudioCodec::Pcm(PcmCodec { format: f }) if f == format));
+    }
+
+    #[tokio::test]
+    async fn write_read_vp9() {
+        use crate::{MediaInfo, MediaKind, MediaTime, Packet, Track, VideoCodec, VideoInfo, VpxCodec};
+        use std::sync::Arc;
+
+        let codec = VpxCodec { profile: 2, level: 41, bit_depth: 10, ..Default::default() };
+        let track = Track {
+            info: Arc::new(MediaInfo {
+                name: "vp9",
+                kind: MediaKind::Video(VideoInfo { width: 1920, height: 1080, codec: VideoCodec::Vp9(codec.clone()) }),
+            }),
+            ..ass_track()
+        };
+
+        let packets = [0x82, 0x86, 0x86, 0x82].into_iter().enumerate().map(|(i, header)| Packet {
+            time: MediaTime { pts: i as u64 * 40, dts: None, duration: None, timebase: track.timebase },
+            key: header == 0x82,
+            track: track.clone(),
+            buffer: vec![header, 0x49, 0x83, 0x42].into(),
+            side_data: Vec::new(),
+        }).collect::<Vec<_>>();
+
+        let mut muxer = MatroskaMuxer::new(Io::memory()).with_profile(MatroskaProfile::WebM);
+        test::write_movie_and_packets(&mut muxer, Movie { tracks: vec![track], ..Default::default() }, &packets).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let (movie, new_packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        let video = movie.tracks[0].info.video().unwrap();
+        assert!(matches!(&video.codec, VideoCodec::Vp9(c) if *c == codec));
+        assert_eq!((1920, 1080), (video.width, video.height));
+        assert_eq!(
+            packets.iter().map(|p| (p.time.pts, p.key)).collect::<Vec<_>>(),
+            new_packets.iter().map(|p| (p.time.pts, p.key)).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test_case(MatroskaOptions::default(), 4 ; "keyframes")]
+    #[test_case(MatroskaOptions { cluster_on_keyframes: false, ..Default::default() }, 1 ; "duration")]
+    #[test_case(MatroskaOptions {
+        cluster_on_keyframes: false,
+        cluster_duration: std::time::Duration::from_millis(1500),
+        ..Default::default()
+    }, 2 ; "short duration")]
+    #[test_case(MatroskaOptions { cluster_on_keyframes: false, max_cluster_size: 1, ..Default::default() }, 4 ; "size")]
+    #[tokio::test]
+    async fn cut_clusters(options: MatroskaOptions, clusters: usize) {
+        use crate::{MediaInfo, MediaKind, MediaTime, Packet, Track, VideoCodec, VideoInfo};
+        use std::sync::Arc;
+
+        let track = Track {
+            info: Arc::new(MediaInfo {
+                name: "vp8",
+                kind: MediaKind::Video(VideoInfo { width: 64, height: 64, codec: VideoCodec::Vp8(Default::default()) }),
+            }),
+            ..ass_track()
+        };
+
+        let packets = (0..4).map(|i| Packet {
+            time: MediaTime { pts: i * 1000, dts: None, duration: None, timebase: track.timebase },
+            key: true,
+            track: track.clone(),
+            buffer: vec![0x10, 0x02, 0x00, 0x9d].into(),
+            side_data: Vec::new(),
+        }).collect::<Vec<_>>();
+
+        let mut muxer = MatroskaMuxer::new(Io::memory()).with_options(options);
+        test::write_movie_and_packets(&mut muxer, Movie { tracks: vec![track], ..Default::default() }, &packets).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let cluster_id = CLUSTER.to_be_bytes();
+        assert_eq!(clusters, buffer.windows(4).filter(|w| *w == cluster_id).count());
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let (_, new_packets) = test::read_movie_and_packets(&mut demuxer).await;
+        assert_eq!(
+            packets.iter().map(|p| p.time.pts).collect::<Vec<_>>(),
+            new_packets.iter().map(|p| p.time.pts).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test_case(true ; "seekable")]
+    #[test_case(false ; "streaming")]
+    #[tokio::test]
+    async fn write_seek_head(seekable: bool) {
+        use crate::{MediaInfo, MediaKind, MediaTime, Packet, Track, VideoCodec, VideoInfo};
+        use std::sync::Arc;
+
+        let track = Track {
+            info: Arc::new(MediaInfo {
+                name: "vp8",
+                kind: MediaKind::Video(VideoInfo { width: 64, height: 64, codec: VideoCodec::Vp8(Default::default()) }),
+            }),
+            ..ass_track()
+        };
+
+        let packets = (0..4).map(|i| Packet {
+            time: MediaTime { pts: i * 1000, dts: None, duration: None, timebase: track.timebase },
+            key: true,
+            track: track.clone(),
+            buffer: vec![0x10, 0x02, 0x00, 0x9d].into(),
+            side_data: Vec::new(),
+        }).collect::<Vec<_>>();
+
+        let io = if seekable { Io::memory() } else { Io::from_stream(Box::new(Vec::<u8>::new())) };
+        let mut muxer = MatroskaMuxer::new(io);
+        test::write_movie_and_packets(&mut muxer, Movie { tracks: vec![track], ..Default::default() }, &packets).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let segment = buffer.windows(4).position(|w| w == SEGMENT.to_be_bytes()).unwrap();
+        let segment_start = segment + 12;
+        let segment_size = u64::from_be_bytes(buffer[segment + 4..segment_start].try_into().unwrap());
+
+        if !seekable {
+            assert_eq!(0x01ff_ffff_ffff_ffff, segment_size);
+            assert_eq!(VOID as u8, buffer[segment_start]);
+        } else {
+            assert_eq!(0x0100_0000_0000_0000 | (buffer.len() - segment_start) as u64, segment_size);
+            assert_eq!(SEEK_HEAD.to_be_bytes(), buffer[segment_start..segment_start + 4]);
+
+            // SeekID with a 4 byte ID, followed by its SeekPosition
+            let mut ids = Vec::new();
+            for (i, _) in buffer.windows(3).enumerate().filter(|(_, w)| *w == [0x53, 0xab, 0x84]) {
+                let id = &buffer[i + 3..i + 7];
+                let len = (buffer[i + 9] & 0x7f) as usize;
+                let position = buffer[i + 10..i + 10 + len].iter().fold(0, |p, &b| p << 8 | b as usize);
+
+                assert_eq!(id, &buffer[segment_start + position..segment_start + position + 4]);
+                ids.push(u32::from_be_bytes(id.try_into().unwrap()));
+            }
+
+            assert_eq!(vec![INFO, TRACKS, CUES, TAGS], ids);
+        }
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let (_, new_packets) = test::read_movie_and_packets(&mut demuxer).await;
+        assert_eq!(packets.len(), new_packets.len());
+    }
+
+    #[tokio::test]
+    async fn webm_only_allows_webm_codecs() {
+        use crate::{AudioCodec, AudioInfo, MediaInfo, MediaKind, OpusCodec, SoundType, Track};
+        use std::sync::Arc;
+
+        let mut muxer = MatroskaMuxer::new(Io::memory()).with_profile(MatroskaProfile::WebM);
+        assert!(muxer.start(vec![ass_track()]).await.is_err());
+
+        let opus = Track {
+            info: Arc::new(MediaInfo {
+                name: "opus",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 48000,
+                    sample_bpp: 16,
+                    sound_type: SoundType::Stereo,
+                    codec: AudioCodec::Opus(OpusCodec { header: b"OpusHead".to_vec() }),
+                }),
+            }),
+            ..ass_track()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory()).with_profile(MatroskaProfile::WebM);
+        test::write_movie_and_packets(&mut muxer, Movie { tracks: vec![opus], ..Default::default() }, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        assert!(buffer.windows(4).any(|w| w == b"webm"));
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        assert_eq!(1, demuxer.start().await.unwrap().tracks.len());
+    }
+
+    #[tokio::test]
+    async fn write_read_chapters_and_metadata() {
+        use crate::format::Chapter;
+        use std::time::Duration;
+
+        let chapters = vec![
+            Chapter {
+                start: Duration::ZERO,
+                end: Some(Duration::from_secs(90)),
+                title: Some("Intro".into()),
+            },
+            Chapter {
+                start: Duration::from_millis(90_500),
+                end: None,
+                title: None,
+            },
+        ];
+        let movie = Movie {
+            tracks: vec![ass_track()],
+            chapters: chapters.clone(),
+            metadata: [("TITLE".to_string(), "Test".to_string())].into(),
+            ..Default::default()
+        };
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &[]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer));
+        let movie = demuxer.start().await.unwrap();
+
+        assert_eq!(chapters, movie.chapters);
+        // the statistics tags are specific to the track
+        assert_eq!(1, movie.metadata.len());
+        assert_eq!(Some("Test"), movie.metadata.get("TITLE").map(|s| &s[..]));
+    }
 }
@@ -1,21 +1,34 @@
 use aho_corasick::AhoCorasick;
 use anyhow::Context;
 use async_trait::async_trait;
-use h264_reader::avcc::AvcDecoderConfigurationRecord;
+use bytes::{Buf, Bytes};
 use log::*;
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::SeekFrom,
+    sync::Arc,
+    time::Duration,
+};
 
 use super::*;
 use super::ebml::*;
+use super::structure::read_structure;
+use super::validate::{spec, ROOT};
 
 use crate::{
-    codec::{nal::get_codec_from_mp4, AssCodec, SubtitleCodec, SubtitleInfo},
+    codec::{
+        h264::AvcDecoderConfig,
+        vpx::{vp8_is_keyframe, vp9_is_keyframe},
+        AssCodec, SubtitleCodec, SubtitleInfo, VobSubCodec,
+    },
     demuxer,
-    format::{ProbeResult, Demuxer, Movie},
+    format::{Attachment, Chapter, ProbeResult, Demuxer, Movie, ResumeState, StructureNode},
     io::Io,
-    AacCodec, AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, Packet, SoundType,
-    Track,
+    time::{from_duration, parse_duration, to_duration},
+    AacCodec, AudioCodec, AudioInfo, FlacCodec, Fraction, MediaInfo, MediaKind, MediaTime,
+    OpusCodec, Packet, PcmCodec, SampleFormat, SideData, SoundType, Track, TrackMetadata,
+    VideoCodec, VideoInfo, VorbisCodec, VpxCodec,
 };
 
 macro_rules! ebml {
@@ -23,9 +36,9 @@ macro_rules! ebml {
         let mut i = 0;
         while i < $size {
             let (len, id) = vid($io).await?;
-            i += len as u64;
+            i = i.saturating_add(len as u64);
             let (len, size) = vint($io).await?;
-            i += len as u64;
+            i = i.saturating_add(len as u64);
 
             match (id, size) {
                 $( $pat => $blk, )*
@@ -36,18 +49,62 @@ macro_rules! ebml {
                 }
             }
 
-            i += size;
+            // an unknown size has all bits set, and lasts until the end of the input
+            i = i.saturating_add(size);
         }
     }
 }
 
-demuxer!("mkv", MatroskaDemuxer::create, MatroskaDemuxer::probe);
+const LACING_NONE: u8 = 0b00;
+const LACING_XIPH: u8 = 0b01;
+const LACING_FIXED: u8 = 0b10;
+const LACING_EBML: u8 = 0b11;
+
+demuxer!(
+    "mkv",
+    MatroskaDemuxer::create,
+    MatroskaDemuxer::probe,
+    ["*.mkv", "*.mka", "*.mks", "*.webm"]
+);
+
+/// Counters of the corrupt data a [`MatroskaDemuxer`] recovered from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatroskaStats {
+    /// How often the demuxer skipped to the next cluster after corrupt data.
+    pub resyncs: u64,
+    /// The bytes skipped looking for the next cluster.
+    pub skipped_bytes: u64,
+}
 
 pub struct MatroskaDemuxer {
     io: Io,
     streams: Vec<Track>,
+    attachments: Vec<Attachment>,
+    chapters: Vec<Chapter>,
+    metadata: BTreeMap<String, String>,
+    /// The segment duration in timestamp ticks, which may be fractional.
+    duration: Option<f64>,
+    /// Track ids by track UID, which tags refer to tracks by.
+    track_uids: HashMap<u64, u32>,
+    /// Tags which apply to a single track, by track UID.
+    track_tags: HashMap<u64, Vec<(String, String)>>,
+    /// The position of the segment data, which the SeekHead is relative to.
+    segment_start: Option<u64>,
+    /// The position of the Tags listed in the SeekHead, relative to the segment data.
+    tags_position: Option<u64>,
+    tags_read: bool,
+    /// The default duration of frames in nanoseconds, by track.
+    default_durations: HashMap<u32, u64>,
+    /// Frames left from a laced block.
+    pending: VecDeque<Packet>,
     timebase: Fraction,
+    first_cluster_size: Option<u64>,
+    /// Added to all timestamps, so that blocks with negative timestamps start at 0.
+    offset: u64,
     current_cluster_ts: u64,
+    header_len: Option<u64>,
+    strict: bool,
+    stats: MatroskaStats,
 }
 
 impl MatroskaDemuxer {
@@ -55,9 +112,48 @@ impl MatroskaDemuxer {
         MatroskaDemuxer {
             io,
             streams: Vec::new(),
+            attachments: Vec::new(),
+            chapters: Vec::new(),
+            metadata: BTreeMap::new(),
+            duration: None,
+            track_uids: HashMap::new(),
+            track_tags: HashMap::new(),
+            segment_start: None,
+            tags_position: None,
+            tags_read: false,
+            default_durations: HashMap::new(),
+            pending: VecDeque::new(),
             timebase: Fraction::new(1, 1),
+            first_cluster_size: None,
+            offset: 0,
             current_cluster_ts: 0,
+            header_len: None,
+            strict: false,
+            stats: MatroskaStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &MatroskaStats {
+        &self.stats
+    }
+
+    /// Validates the structure of the whole input when starting, failing with every problem
+    /// found instead of skipping over what isn't expected. Only works for seekable inputs.
+    pub fn with_strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    async fn validate(&mut self) -> Result<(), MkvError> {
+        let start = self.io.read_position().await?;
+        let diagnostics = validate(&mut self.io).await?;
+        self.io.seek_read(SeekFrom::Start(start)).await?;
+
+        if !diagnostics.is_empty() {
+            return Err(MkvError::InvalidStructure(diagnostics));
         }
+
+        Ok(())
     }
 
     async fn parse_ebml_header(&mut self) -> Result<(), MkvError> {
@@ -96,13 +192,31 @@ impl MatroskaDemuxer {
         if id != SEGMENT {
             return Err(MkvError::UnexpectedId(SEGMENT, id));
         }
+        self.segment_start = self.io.read_position().await.ok();
 
         ebml!(&mut self.io, size,
+            (self::SEEK_HEAD, size) => {
+                self.parse_seek_head(size).await?;
+            },
             (self::INFO, size) => {
                 self.parse_segment_info(size).await?;
             },
             (self::TRACKS, size) => {
                 self.parse_track_entries(size).await?;
+            },
+            (self::ATTACHMENTS, size) => {
+                self.parse_attachments(size).await?;
+            },
+            (self::CHAPTERS, size) => {
+                self.parse_chapters(size).await?;
+            },
+            (self::TAGS, size) => {
+                self.parse_tags(size).await?;
+                self.tags_read = true;
+            },
+            // the headers end at the first cluster, whose children are read as packets
+            (self::CLUSTER, size) => {
+                self.first_cluster_size = Some(size);
                 break;
             }
         );
@@ -110,12 +224,76 @@ impl MatroskaDemuxer {
         Ok(())
     }
 
+    async fn parse_seek_head(&mut self, size: u64) -> Result<(), MkvError> {
+        ebml!(&mut self.io, size,
+            (self::SEEK, size) => {
+                let mut id = None;
+                let mut position = None;
+
+                ebml!(&mut self.io, size,
+                    (self::SEEK_ID, size) => {
+                        id = Some(vu(&mut self.io, size).await?);
+                    },
+                    (self::SEEK_POSITION, size) => {
+                        position = Some(vu(&mut self.io, size).await?);
+                    }
+                );
+
+                if id == Some(TAGS as u64) {
+                    self.tags_position = position;
+                }
+            }
+        );
+
+        Ok(())
+    }
+
+    /// Reads the Tags written after the clusters, e.g. the statistics tags written once a muxer
+    /// knows them, and seeks back to `header_len`.
+    async fn read_trailing_tags(&mut self, header_len: u64) -> Result<(), MkvError> {
+        let (Some(segment_start), Some(position)) = (self.segment_start, self.tags_position) else {
+            return Ok(());
+        };
+        if self.tags_read {
+            return Ok(());
+        }
+
+        self.io
+            .seek_read(SeekFrom::Start(segment_start + position))
+            .await?;
+        let result = async {
+            let (_, id) = vid(&mut self.io).await?;
+            let (_, size) = vint(&mut self.io).await?;
+            if id != TAGS {
+                return Err(MkvError::UnexpectedId(TAGS, id));
+            }
+
+            self.parse_tags(size).await
+        }
+        .await;
+        self.io.seek_read(SeekFrom::Start(header_len)).await?;
+
+        match result {
+            Ok(()) => self.tags_read = true,
+            Err(e) => warn!("Failed to read the Tags listed in the SeekHead: {e}"),
+        }
+
+        Ok(())
+    }
+
     async fn parse_segment_info(&mut self, size: u64) -> Result<(), MkvError> {
         ebml!(&mut self.io, size,
             (self::TIMESTAMP_SCALE, size) => {
                 let scale = vu(&mut self.io, size).await?;
+                let denominator = u32::try_from(scale / 1000)
+                    .ok()
+                    .filter(|&d| d > 0)
+                    .ok_or(MkvError::InvalidTimestampScale(scale))?;
 
-                self.timebase = Fraction::new(1, scale as u32 / 1000);
+                self.timebase = Fraction::new(1, denominator);
+            },
+            (self::DURATION, size) => {
+                self.duration = Some(vfloat(&mut self.io, size).await?);
             }
         );
 
@@ -132,22 +310,196 @@ impl MatroskaDemuxer {
         Ok(())
     }
 
+    async fn parse_attachments(&mut self, size: u64) -> Result<(), MkvError> {
+        ebml!(&mut self.io, size,
+            (self::ATTACHED_FILE, size) => {
+                let attachment = self.parse_attached_file(size).await?;
+
+                debug!("Attachment: {attachment:?}");
+                self.attachments.push(attachment);
+            }
+        );
+
+        Ok(())
+    }
+
+    async fn parse_attached_file(&mut self, size: u64) -> Result<Attachment, MkvError> {
+        let mut name = None;
+        let mut mime = None;
+        let mut data = None;
+
+        ebml!(&mut self.io, size,
+            (self::FILE_NAME, size) => {
+                name = Some(vstr(&mut self.io, size).await?);
+            },
+            (self::FILE_MEDIA_TYPE, size) => {
+                mime = Some(vstr(&mut self.io, size).await?);
+            },
+            (self::FILE_DATA, size) => {
+                data = Some(vbin(&mut self.io, size).await?);
+            }
+        );
+
+        Ok(Attachment {
+            name: mand(name, FILE_NAME)?,
+            mime: mand(mime, FILE_MEDIA_TYPE)?,
+            data: mand(data, FILE_DATA)?.into(),
+        })
+    }
+
+    async fn parse_chapters(&mut self, size: u64) -> Result<(), MkvError> {
+        ebml!(&mut self.io, size,
+            (self::EDITION_ENTRY, size) => {
+                let mut chapters = Vec::new();
+
+                ebml!(&mut self.io, size,
+                    (self::CHAPTER_ATOM, size) => {
+                        let chapter = self.parse_chapter_atom(size).await?;
+
+                        debug!("Chapter: {chapter:?}");
+                        chapters.push(chapter);
+                    }
+                );
+
+                // only the first edition is used
+                if self.chapters.is_empty() {
+                    self.chapters = chapters;
+                }
+            }
+        );
+
+        Ok(())
+    }
+
+    async fn parse_chapter_atom(&mut self, size: u64) -> Result<Chapter, MkvError> {
+        let mut start = None;
+        let mut end = None;
+        let mut title = None;
+
+        ebml!(&mut self.io, size,
+            (self::CHAPTER_TIME_START, size) => {
+                start = Some(vu(&mut self.io, size).await?);
+            },
+            (self::CHAPTER_TIME_END, size) => {
+                end = Some(vu(&mut self.io, size).await?);
+            },
+            (self::CHAPTER_DISPLAY, size) => {
+                ebml!(&mut self.io, size,
+                    (self::CHAP_STRING, size) => {
+                        let string = vstr(&mut self.io, size).await?;
+                        title.get_or_insert(string);
+                    }
+                );
+            }
+        );
+
+        // chapter times are always in nanoseconds
+        Ok(Chapter {
+            start: Duration::from_nanos(mand(start, CHAPTER_TIME_START)?),
+            end: end.map(Duration::from_nanos),
+            title,
+        })
+    }
+
+    /// Parses the tags which apply to the whole movie or to a single track, tags for chapters
+    /// are ignored.
+    async fn parse_tags(&mut self, size: u64) -> Result<(), MkvError> {
+        ebml!(&mut self.io, size,
+            (self::TAG, size) => {
+                let mut global = true;
+                let mut track_uid = None;
+                let mut tags = Vec::new();
+
+                ebml!(&mut self.io, size,
+                    (self::TARGETS, size) => {
+                        ebml!(&mut self.io, size,
+                            (self::TARGET_TYPE_VALUE, size) => {
+                                global &= vu(&mut self.io, size).await? >= TARGET_TYPE_MOVIE;
+                            },
+                            (self::TAG_TRACK_UID, size) => {
+                                let uid = vu(&mut self.io, size).await?;
+
+                                // a UID of 0 applies to everything
+                                global &= uid == 0;
+                                track_uid = Some(uid).filter(|&uid| uid != 0);
+                            },
+                            (
+                                self::TAG_EDITION_UID
+                                | self::TAG_CHAPTER_UID
+                                | self::TAG_ATTACHMENT_UID,
+                                size
+                            ) => {
+                                // a UID of 0 applies to everything
+                                global &= vu(&mut self.io, size).await? == 0;
+                            }
+                        );
+                    },
+                    (self::SIMPLE_TAG, size) => {
+                        let mut name = None;
+                        let mut value = None;
+
+                        ebml!(&mut self.io, size,
+                            (self::TAG_NAME, size) => {
+                                name = Some(vstr(&mut self.io, size).await?);
+                            },
+                            (self::TAG_STRING, size) => {
+                                value = Some(vstr(&mut self.io, size).await?);
+                            }
+                        );
+
+                        if let (Some(name), Some(value)) = (name, value) {
+                            tags.push((name, value));
+                        }
+                    }
+                );
+
+                if global {
+                    self.metadata.extend(tags);
+                } else if let Some(uid) = track_uid {
+                    self.track_tags.entry(uid).or_default().extend(tags);
+                }
+            }
+        );
+
+        Ok(())
+    }
+
     async fn parse_track_entry(&mut self, size: u64) -> Result<(), MkvError> {
         let mut track_number = None;
+        let mut track_uid = None;
         // let mut track_type = None;
         let mut codec_id = None;
         let mut codec_private = None;
+        let mut codec_delay = None;
+        let mut default_duration = None;
+        let mut video = None;
         let mut audio = None;
+        let mut language = None;
+        let mut language_bcp47 = None;
+        let mut metadata = TrackMetadata::default();
 
         ebml!(&mut self.io, size,
             (self::TRACK_NUMBER, size) => {
                 track_number = Some(vu(&mut self.io, size).await?);
             },
-            /*(self::TRACK_UID, size) => {
-                let uid = vu(&mut self.io, size).await?;
-
-                debug!("TrackUID: {uid:016x}");
-            },*/
+            (self::NAME, size) => {
+                metadata.title = Some(vstr(&mut self.io, size).await?);
+            },
+            (self::LANGUAGE, size) => {
+                language = Some(vstr(&mut self.io, size).await?);
+            },
+            (self::LANGUAGE_BCP47, size) => {
+                language_bcp47 = Some(vstr(&mut self.io, size).await?);
+            },
+            (self::FLAG_DEFAULT, size) => {
+                metadata.default = vu(&mut self.io, size).await? != 0;
+            },
+            (self::FLAG_FORCED, size) => {
+                metadata.forced = vu(&mut self.io, size).await? != 0;
+            },
+            (self::TRACK_UID, size) => {
+                track_uid = Some(vu(&mut self.io, size).await?);
+            },
             /*(self::TRACK_TYPE, size) => {
                 track_type = Some(vu(&mut self.io, size).await?);
             },*/
@@ -157,14 +509,36 @@ impl MatroskaDemuxer {
             (self::CODEC_PRIVATE, size) => {
                 codec_private = Some(vbin(&mut self.io, size).await?);
             },
+            (self::CODEC_DELAY, size) => {
+                codec_delay = Some(vu(&mut self.io, size).await?);
+            },
+            (self::DEFAULT_DURATION, size) => {
+                default_duration = Some(vu(&mut self.io, size).await?).filter(|&d| d > 0);
+            },
+            (self::TRACK_TIMESTAMP_SCALE, size) => {
+                let scale = vfloat(&mut self.io, size).await?;
+                if scale != 1.0 {
+                    warn!("Ignoring unsupported TrackTimestampScale {scale}");
+                }
+            },
+            (self::VIDEO, size) => {
+                video = Some(self.parse_video(size).await?);
+            },
             (self::AUDIO, size) => {
                 audio = Some(self.parse_audio(size).await?);
             }
         );
 
+        // LanguageBCP47 takes precedence, and an undetermined language is the same as none
+        metadata.language = language_bcp47.or(language).filter(|l| l != "und");
+
         let track_number = mand(track_number, TRACK_NUMBER)?;
         let codec_id = mand(codec_id, CODEC_ID)?;
 
+        if let Some(uid) = track_uid {
+            self.track_uids.insert(uid, track_number as u32);
+        }
+
         let info = match codec_id.as_str() {
             "S_TEXT/ASS" => {
                 let codec_private = mand(codec_private, CODEC_PRIVATE)?;
@@ -179,22 +553,92 @@ impl MatroskaDemuxer {
                     }),
                 }
             }
+            "S_HDMV/PGS" => MediaInfo {
+                name: "pgs",
+                kind: MediaKind::Subtitle(SubtitleInfo {
+                    codec: SubtitleCodec::Pgs,
+                }),
+            },
+            "S_VOBSUB" => {
+                let codec_private = mand(codec_private, CODEC_PRIVATE)?;
+                let idx = String::from_utf8(codec_private)?;
+
+                MediaInfo {
+                    name: "vobsub",
+                    kind: MediaKind::Subtitle(SubtitleInfo {
+                        codec: SubtitleCodec::VobSub(VobSubCodec { idx }),
+                    }),
+                }
+            }
             "V_MPEG4/ISO/AVC" => {
                 let codec_private = mand(codec_private, CODEC_PRIVATE)?;
 
-                let avc_record: AvcDecoderConfigurationRecord = codec_private
-                    .as_slice()
-                    .try_into()
-                    .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                AvcDecoderConfig::parse(codec_private.into())?.media_info()?
+            }
+            "V_VP8" | "V_VP9" => {
+                let video = mand(video, VIDEO)?;
+
+                let (name, codec) = if codec_id == "V_VP8" {
+                    ("vp8", VideoCodec::Vp8(VpxCodec::default()))
+                } else {
+                    let codec = codec_private
+                        .map(|data| VpxCodec::parse_codec_private(&data))
+                        .unwrap_or_default();
 
-                get_codec_from_mp4(&avc_record).unwrap()
+                    ("vp9", VideoCodec::Vp9(codec))
+                };
+
+                MediaInfo {
+                    name,
+                    kind: MediaKind::Video(VideoInfo {
+                        width: video.pixel_width as u32,
+                        height: video.pixel_height as u32,
+                        codec,
+                    }),
+                }
             }
-            "A_AAC" => {
+            "A_AAC" | "A_OPUS" | "A_VORBIS" | "A_FLAC" | "A_PCM/INT/LIT" | "A_PCM/INT/BIG"
+            | "A_PCM/FLOAT/IEEE" => {
                 let audio = mand(audio, AUDIO)?;
-                let codec_private = mand(codec_private, CODEC_PRIVATE)?;
+
+                let (name, codec) = match codec_id.as_str() {
+                    "A_AAC" => {
+                        let extra = mand(codec_private, CODEC_PRIVATE)?;
+                        ("aac", AudioCodec::Aac(AacCodec { extra }))
+                    }
+                    "A_OPUS" => {
+                        let header = mand(codec_private, CODEC_PRIVATE)?;
+                        ("opus", AudioCodec::Opus(OpusCodec { header }))
+                    }
+                    "A_VORBIS" => {
+                        // the identification, comment and setup headers are stored Xiph laced
+                        let codec_private = mand(codec_private, CODEC_PRIVATE)?;
+                        let headers = split_laced_frames(LACING_XIPH, codec_private.into())?
+                            .into_iter()
+                            .map(Vec::from)
+                            .collect::<Vec<_>>();
+                        let headers = <[Vec<u8>; 3]>::try_from(headers)
+                            .map_err(|_| MkvError::InvalidCodecPrivate("A_VORBIS"))?;
+
+                        ("vorbis", AudioCodec::Vorbis(VorbisCodec { headers }))
+                    }
+                    "A_FLAC" => {
+                        let header = mand(codec_private, CODEC_PRIVATE)?;
+                        ("flac", AudioCodec::Flac(FlacCodec { header }))
+                    }
+                    _ => {
+                        let bit_depth = audio.bit_depth.unwrap_or(8);
+                        let Some(format) = pcm_format(&codec_id, bit_depth) else {
+                            warn!("Unsupported {bit_depth} bit {codec_id:?} audio");
+                            return Ok(());
+                        };
+
+                        ("pcm", AudioCodec::Pcm(PcmCodec { format }))
+                    }
+                };
 
                 MediaInfo {
-                    name: "aac",
+                    name,
                     kind: MediaKind::Audio(AudioInfo {
                         sample_rate: audio.sampling_frequency as u32,
                         sample_bpp: audio.bit_depth.unwrap_or(8) as u32,
@@ -203,9 +647,7 @@ impl MatroskaDemuxer {
                         } else {
                             SoundType::Mono
                         },
-                        codec: AudioCodec::Aac(AacCodec {
-                            extra: codec_private,
-                        }),
+                        codec,
                     }),
                 }
             }
@@ -219,8 +661,19 @@ impl MatroskaDemuxer {
             id: track_number as u32,
             info: Arc::new(info),
             timebase: self.timebase,
+            // CodecDelay is always stored in nanoseconds
+            delay: codec_delay
+                .map(|ns| from_duration(Duration::from_nanos(ns), self.timebase))
+                .unwrap_or(0),
+            metadata,
         };
 
+        // needed to spread out laced frames, which is mostly done for audio
+        let default_duration = default_duration.or_else(|| fixed_frame_duration(&stream.info));
+        if let Some(duration) = default_duration {
+            self.default_durations.insert(stream.id, duration);
+        }
+
         self.streams.push(stream);
 
         Ok(())
@@ -254,16 +707,81 @@ impl MatroskaDemuxer {
         })
     }
 
-    async fn parse_video(&mut self, size: u64) -> Result<(), MkvError> {
+    async fn parse_video(&mut self, size: u64) -> Result<Video, MkvError> {
+        let mut pixel_width = None;
+        let mut pixel_height = None;
+
         ebml!(&mut self.io, size,
-            (self::TRACK_NUMBER, size) => {
+            (self::PIXEL_WIDTH, size) => {
+                pixel_width = Some(vu(&mut self.io, size).await?);
+            },
+            (self::PIXEL_HEIGHT, size) => {
+                pixel_height = Some(vu(&mut self.io, size).await?);
             }
         );
 
-        Ok(())
+        Ok(Video {
+            pixel_width: pixel_width.ok_or(MkvError::MissingElement(PIXEL_WIDTH))?,
+            pixel_height: pixel_height.ok_or(MkvError::MissingElement(PIXEL_HEIGHT))?,
+        })
+    }
+
+    /// Finds the offset needed to make the timestamps of the blocks in the first cluster
+    /// non-negative, and returns to the start of the cluster.
+    async fn find_start_offset(&mut self, cluster_start: u64) -> Result<u64, MkvError> {
+        let Some(size) = self.first_cluster_size.filter(|&size| !is_unknown_size(size)) else {
+            return Ok(0);
+        };
+
+        let earliest = self.earliest_timestamp(size).await;
+        self.io.seek_read(SeekFrom::Start(cluster_start)).await?;
+
+        match earliest {
+            Ok(earliest) => Ok(earliest.min(0).unsigned_abs()),
+            Err(e) => {
+                warn!("Failed to find the earliest timestamp: {e}");
+                Ok(0)
+            }
+        }
+    }
+
+    async fn earliest_timestamp(&mut self, cluster_size: u64) -> Result<i64, MkvError> {
+        let mut cluster_ts = 0;
+        let mut earliest = i64::MAX;
+
+        ebml!(&mut self.io, cluster_size,
+            (self::TIMESTAMP, size) => {
+                cluster_ts = vu(&mut self.io, size).await? as i64;
+            },
+            (self::SIMPLE_BLOCK, size) => {
+                let relative = self.read_block_timestamp(size).await?;
+                earliest = earliest.min(cluster_ts.saturating_add(relative as i64));
+            },
+            (self::BLOCK_GROUP, size) => {
+                ebml!(&mut self.io, size,
+                    (self::BLOCK, size) => {
+                        let relative = self.read_block_timestamp(size).await?;
+                        earliest = earliest.min(cluster_ts.saturating_add(relative as i64));
+                    }
+                );
+            }
+        );
+
+        Ok(earliest)
     }
 
-    async fn read_block(&mut self, size: u64) -> Result<Option<Packet>, MkvError> {
+    /// Reads only the timestamp of a block, relative to its cluster, and skips the rest.
+    async fn read_block_timestamp(&mut self, size: u64) -> Result<i16, MkvError> {
+        let (len, _) = vint(&mut self.io).await?;
+        let rest = block_data_size(size, len as u64 + 2)?;
+        let relative = be16(&mut self.io).await?;
+        self.io.skip(rest).await?;
+
+        Ok(relative)
+    }
+
+    /// Reads the frames of a block, which all have the timestamp of the block.
+    async fn read_block(&mut self, size: u64) -> Result<Vec<Packet>, MkvError> {
         use tokio::io::AsyncReadExt;
 
         let (len, track_number) = vint(&mut self.io).await?;
@@ -271,102 +789,316 @@ impl MatroskaDemuxer {
         let track = if let Some(track) = self.streams.iter().find(|s| s.id == track_number as u32) {
             track.clone()
         } else {
-            self.io.skip(size - len as u64).await?;
+            self.io.skip(block_data_size(size, len as u64)?).await?;
 
-            return Ok(None);
+            return Ok(Vec::new());
         };
 
-        let reader = self.io.reader()?;
-        let timestamp = reader.read_u16().await?;
-        let flags = reader.read_u8().await?;
+        let data_size = block_data_size(size, len as u64 + 3)?;
+        let relative = be16(&mut self.io).await?;
+        let flags = self.io.reader()?.read_u8().await?;
 
         let key = (flags & 0b1000_0000) != 0;
+        let lacing = (flags >> 1) & 0b11;
 
-        let mut buffer = vec![0u8; size as usize - len as usize - 3];
-        reader.read_exact(&mut buffer).await?;
+        let buffer = vbin(&mut self.io, data_size).await?;
+        let frames = split_laced_frames(lacing, buffer.into())?;
+
+        let pts = (self.current_cluster_ts as i64)
+            .saturating_add(relative as i64)
+            .saturating_add(self.offset as i64);
+        let pts = u64::try_from(pts).unwrap_or_else(|_| {
+            // only possible for inputs whose start could not be scanned
+            warn!("Clamping negative timestamp {pts} of track {}", track.id);
+            0
+        });
 
         let time = MediaTime {
-            pts: self.current_cluster_ts + timestamp as u64,
+            pts,
             dts: None,
             duration: None,
             timebase: self.timebase,
         };
 
-        Ok(Some(Packet {
-            time,
-            track,
-            key,
-            buffer: buffer.into(),
-        }))
+        Ok(frames
+            .into_iter()
+            .map(|frame| Packet {
+                time: time.clone(),
+                track: track.clone(),
+                // only SimpleBlocks have a keyframe flag, so check the bitstream when possible
+                key: is_keyframe(&track, &frame).unwrap_or(key),
+                side_data: side_data(&track, &frame),
+                buffer: frame.into(),
+            })
+            .collect())
+    }
+
+    /// Queues the frames of a block, spreading laced frames over the duration of the block.
+    fn queue_block(&mut self, mut frames: Vec<Packet>, block_duration: Option<u64>) {
+        let Some(first) = frames.first() else {
+            return;
+        };
+
+        let default_duration = self
+            .default_durations
+            .get(&first.track.id)
+            .map(|&ns| from_duration(Duration::from_nanos(ns), self.timebase));
+
+        if frames.len() == 1 {
+            frames[0].time.duration = block_duration.or(default_duration);
+        } else {
+            let count = frames.len() as u64;
+            let frame_duration = block_duration.map(|d| d / count).or(default_duration);
+
+            if let Some(duration) = frame_duration {
+                for (i, frame) in (0u64..).zip(&mut frames) {
+                    frame.time.pts = frame.time.pts.saturating_add(i.saturating_mul(duration));
+                    frame.time.duration = Some(duration);
+                }
+            }
+        }
+
+        self.pending.extend(frames);
+    }
+
+    /// Reads the next element of a cluster, queuing the frames of blocks.
+    async fn read_element(&mut self) -> Result<(), MkvError> {
+        if self.io.is_at_end().await? {
+            return Err(MkvError::NotEnoughData);
+        }
+
+        let (_, id) = vid(&mut self.io).await?;
+        let (_, size) = vint(&mut self.io).await?;
+
+        match id {
+            self::CLUSTER => {}
+            self::TIMESTAMP => {
+                self.current_cluster_ts = vu(&mut self.io, size).await?;
+                trace!("cluster_ts: {}", self.current_cluster_ts);
+            }
+            self::BLOCK_GROUP => {
+                let mut frames = Vec::new();
+                let mut block_duration = None;
+
+                ebml!(&mut self.io, size,
+                    (BLOCK, size) => {
+                        frames = self.read_block(size).await?;
+                    },
+                    (BLOCK_DURATION, size) => {
+                        block_duration = Some(vu(&mut self.io, size).await?);
+                    }
+                );
+
+                self.queue_block(frames, block_duration);
+            }
+            self::SIMPLE_BLOCK => {
+                let frames = self.read_block(size).await?;
+                self.queue_block(frames, None);
+            }
+            _ if expected_between_blocks(id) => {
+                trace!("Ignoring element 0x{id:08x} ({size} B)");
+                self.io.skip(size).await?;
+            }
+            _ => return Err(MkvError::UnexpectedElement(id)),
+        }
+
+        Ok(())
+    }
+
+    /// Skips corrupt data up to and including the ID of the next cluster, returning how many
+    /// bytes were skipped before the ID.
+    async fn resync(&mut self) -> Result<u64, MkvError> {
+        let id = CLUSTER.to_be_bytes();
+        let ac = AhoCorasick::new([&id[..]]);
+        let mut skipped = 0u64;
+        // how much of the ID was at the end of the data skipped so far
+        let mut partial = 0;
+
+        loop {
+            let buf = self.io.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(MkvError::NotEnoughData);
+            }
+
+            // the rest of an ID which was split between two reads
+            if partial > 0 {
+                let len = (id.len() - partial).min(buf.len());
+
+                if buf[..len] == id[partial..partial + len] {
+                    partial += len;
+                    skipped += len as u64;
+                    self.io.consume(len);
+
+                    if partial == id.len() {
+                        return Ok(skipped - id.len() as u64);
+                    }
+
+                    continue;
+                }
+            }
+
+            if let Some(found) = ac.find(buf) {
+                let (start, end) = (found.start(), found.end());
+                self.io.consume(end);
+
+                return Ok(skipped + start as u64);
+            }
+
+            let len = buf.len();
+            partial = (1..id.len())
+                .rev()
+                .find(|&n| buf.ends_with(&id[..n]))
+                .unwrap_or(0);
+            self.io.consume(len);
+            skipped += len as u64;
+        }
     }
 }
 
+struct Video {
+    pixel_width: u64,
+    pixel_height: u64,
+}
+
 struct Audio {
     sampling_frequency: f64,
     channels: u64,
     bit_depth: Option<u64>,
 }
 
-#[async_trait(?Send)]
-impl Demuxer for MatroskaDemuxer {
-    async fn start(&mut self) -> anyhow::Result<Movie> {
+impl MatroskaDemuxer {
+    async fn read_headers(&mut self) -> anyhow::Result<()> {
         self.parse_ebml_header()
             .await
             .context("Parsing EBML header")?;
-        self.find_tracks().await.context("Finding tracks")?;
+        match self.find_tracks().await {
+            // a segment of unknown size without any clusters ends with the input
+            Err(MkvError::StdIo(e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof && !self.streams.is_empty() => {}
+            result => result.context("Finding tracks")?,
+        }
 
-        Ok(Movie {
-            tracks: self.streams.clone(),
-            attachments: Vec::new(),
+        Ok(())
+    }
+
+    fn movie(&self) -> Movie {
+        let mut tracks = self.streams.clone();
+        for (uid, tags) in &self.track_tags {
+            let Some(track) = self
+                .track_uids
+                .get(uid)
+                .and_then(|id| tracks.iter_mut().find(|t| t.id == *id))
+            else {
+                continue;
+            };
+
+            apply_statistics_tags(&mut track.metadata, tags);
+        }
+
+        let timebase = self.timebase.numerator as f64 / self.timebase.denominator as f64;
+
+        Movie {
+            tracks,
+            attachments: self.attachments.clone(),
+            chapters: self.chapters.clone(),
+            metadata: self.metadata.clone(),
+            start_offset: to_duration(self.offset, self.timebase),
+            duration: self
+                .duration
+                .filter(|d| d.is_finite() && *d >= 0.0)
+                .and_then(|d| Duration::try_from_secs_f64(d * timebase).ok()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for MatroskaDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        if self.strict {
+            self.validate().await?;
+        }
+
+        self.read_headers().await?;
+
+        // only known for seekable inputs, which are the only ones that can be resumed
+        self.header_len = self.io.read_position().await.ok();
+        if let Some(header_len) = self.header_len {
+            self.read_trailing_tags(header_len).await?;
+            self.offset = self.find_start_offset(header_len).await?;
+        }
+
+        Ok(self.movie())
+    }
+
+    async fn save_state(&mut self) -> crate::Result<ResumeState> {
+        let header_len = self
+            .header_len
+            .ok_or_else(|| anyhow::anyhow!("Demuxer not started or input not seekable"))?;
+        if !self.pending.is_empty() {
+            return Err(MediaboxError::unsupported(
+                "Can't save the state in the middle of a laced block",
+            ));
+        }
+        let offset = self.io.read_position().await?;
+
+        self.io.seek_read(SeekFrom::Start(0)).await?;
+        let headers = vbin(&mut self.io, header_len).await?;
+        self.io.seek_read(SeekFrom::Start(offset)).await?;
+
+        Ok(ResumeState {
+            offset,
+            timestamp: self.current_cluster_ts,
+            headers,
         })
     }
 
-    async fn read(&mut self) -> anyhow::Result<Packet> {
-        loop {
-            let (_, id) = vid(&mut self.io).await?;
-            let (_, size) = vint(&mut self.io).await?;
+    async fn resume(&mut self, state: &ResumeState) -> crate::Result<Movie> {
+        // parse the saved headers instead of the input
+        let headers = Io::from_reader(Box::new(std::io::Cursor::new(state.headers.clone())));
+        let io = std::mem::replace(&mut self.io, headers);
 
-            match id {
-                self::CLUSTER => {
-                    continue;
-                }
-                self::TIMESTAMP => {
-                    self.current_cluster_ts = vu(&mut self.io, size).await?;
-                    trace!("cluster_ts: {}", self.current_cluster_ts);
-                }
-                self::BLOCK_GROUP => {
-                    let mut pkt = None;
-                    let mut block_duration = None;
+        let result = self.read_headers().await;
+        self.io = io;
+        result?;
 
-                    ebml!(&mut self.io, size,
-                        (BLOCK, size) => {
-                            pkt = self.read_block(size).await?;
-                        },
-                        (BLOCK_DURATION, size) => {
-                            block_duration = Some(vu(&mut self.io, size).await?);
-                        }
-                    );
+        let header_len = state.headers.len() as u64;
+        self.header_len = Some(header_len);
+        self.read_trailing_tags(header_len).await?;
+        self.offset = self.find_start_offset(header_len).await?;
+        self.current_cluster_ts = state.timestamp;
+        self.io.seek_read(SeekFrom::Start(state.offset)).await?;
 
-                    if let Some(mut pkt) = pkt {
-                        pkt.time.duration = block_duration;
+        Ok(self.movie())
+    }
 
-                        return Ok(pkt);
-                    }
-                }
-                self::SIMPLE_BLOCK => {
-                    if let Some(pkt) = self.read_block(size).await? {
-                        return Ok(pkt);
-                    }
-                }
-                _ => {
-                    trace!("Ignoring element 0x{id:08x} ({size} B)");
-                    self.io.skip(size).await?;
+    async fn dump_structure(&mut self) -> crate::Result<Vec<StructureNode>> {
+        Ok(read_structure(&mut self.io).await?)
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        loop {
+            if let Some(pkt) = self.pending.pop_front() {
+                return Ok(pkt);
+            }
+
+            match self.read_element().await {
+                Ok(()) => {}
+                Err(e) if is_corrupt(&e) => {
+                    warn!("Skipping to the next cluster after corrupt data: {e}");
+
+                    let skipped = self.resync().await?;
+                    self.stats.resyncs += 1;
+                    self.stats.skipped_bytes += skipped;
+
+                    // the ID of the cluster has been read, so continue with its size
+                    vint(&mut self.io).await?;
                 }
+                Err(e) => return Err(e.into()),
             }
         }
     }
 
-    async fn stop(&mut self) -> anyhow::Result<()> {
+    async fn stop(&mut self) -> crate::Result<()> {
         Ok(())
     }
 
@@ -378,6 +1110,7 @@ impl Demuxer for MatroskaDemuxer {
         let patterns = &[
             &EBML_HEADER.to_be_bytes()[..],
             b"matroska",
+            b"webm",
             &SEGMENT.to_be_bytes()[..],
             &CLUSTER.to_be_bytes()[..],
         ];
@@ -388,30 +1121,230 @@ impl Demuxer for MatroskaDemuxer {
             score += 0.25;
         }
 
+        let header = EBML_HEADER.to_be_bytes();
         if score >= 1.0 {
             ProbeResult::Yup
-        } else {
+        } else if score > 0.0 {
             ProbeResult::Maybe(score)
+        } else if data.len() < header.len() && header.starts_with(data) {
+            ProbeResult::NeedMoreData
+        } else {
+            ProbeResult::Unsure
         }
     }
 }
 
-fn mand<T>(value: Option<T>, id: u32) -> Result<T, MkvError> {
-    value.ok_or(MkvError::MissingElement(id))
+/// Reads the statistics tags written by mkvmerge and most other muxers. Tracks without any frames
+/// have zeroed statistics, which are ignored.
+fn apply_statistics_tags(metadata: &mut TrackMetadata, tags: &[(String, String)]) {
+    for (name, value) in tags {
+        match name.as_str() {
+            "BPS" => metadata.bitrate = value.trim().parse().ok().filter(|&bps| bps > 0),
+            "DURATION" => {
+                metadata.duration = parse_duration(value).ok().filter(|d| !d.is_zero());
+            }
+            _ => {}
+        }
+    }
 }
 
-async fn vbin(io: &mut Io, size: u64) -> Result<Vec<u8>, MkvError> {
-    let mut data = vec![0u8; size as usize];
+fn pcm_format(codec_id: &str, bit_depth: u64) -> Option<SampleFormat> {
+    let format = match (codec_id, bit_depth) {
+        // 8 bit samples are always unsigned
+        (_, 8) => SampleFormat::U8,
+        ("A_PCM/INT/LIT", 16) => SampleFormat::S16Le,
+        ("A_PCM/INT/LIT", 24) => SampleFormat::S24Le,
+        ("A_PCM/INT/LIT", 32) => SampleFormat::S32Le,
+        ("A_PCM/INT/BIG", 16) => SampleFormat::S16Be,
+        ("A_PCM/INT/BIG", 24) => SampleFormat::S24Be,
+        ("A_PCM/INT/BIG", 32) => SampleFormat::S32Be,
+        ("A_PCM/FLOAT/IEEE", 32) => SampleFormat::F32Le,
+        ("A_PCM/FLOAT/IEEE", 64) => SampleFormat::F64Le,
+        _ => return None,
+    };
+
+    Some(format)
+}
 
-    io.read_exact(&mut data).await?;
+/// Whether a frame is a keyframe according to its bitstream, for codecs where it's cheap to tell.
+fn is_keyframe(track: &Track, frame: &[u8]) -> Option<bool> {
+    match track.info.video()?.codec {
+        VideoCodec::Vp8(_) => vp8_is_keyframe(frame),
+        VideoCodec::Vp9(_) => vp9_is_keyframe(frame),
+        _ => None,
+    }
+}
 
-    Ok(data)
+/// Per-packet metadata which is stored in the frame data, such as the `ReadOrder` of ASS events.
+fn side_data(track: &Track, frame: &[u8]) -> Vec<SideData> {
+    let Some(SubtitleInfo {
+        codec: SubtitleCodec::Ass(_),
+    }) = track.info.subtitle()
+    else {
+        return Vec::new();
+    };
+
+    let read_order = frame.split(|&b| b == b',').next().and_then(|field| {
+        std::str::from_utf8(field)
+            .ok()
+            .and_then(|field| field.trim().parse().ok())
+    });
+
+    read_order.map(SideData::ReadOrder).into_iter().collect()
 }
 
-async fn be16(io: &mut Io) -> Result<i16, MkvError> {
+/// Splits the data of a block into its frames, using the lacing from the block flags.
+fn split_laced_frames(lacing: u8, mut data: Bytes) -> Result<Vec<Bytes>, MkvError> {
+    if lacing == LACING_NONE {
+        return Ok(vec![data]);
+    }
+
+    let count = *data.first().ok_or(MkvError::InvalidLacing)? as usize + 1;
+    data.advance(1);
+
+    // the size of the last frame is implied by the others
+    let mut sizes = Vec::with_capacity(count);
+    match lacing {
+        LACING_XIPH => {
+            for _ in 1..count {
+                let mut size = 0;
+                loop {
+                    let byte = *data.first().ok_or(MkvError::InvalidLacing)?;
+                    data.advance(1);
+                    size += byte as usize;
+
+                    if byte != 0xff {
+                        break;
+                    }
+                }
+
+                sizes.push(size);
+            }
+        }
+        LACING_EBML => {
+            let (_, first) = lace_vint(&mut data)?;
+            let mut size = first as i64;
+            sizes.push(first as usize);
+
+            // the other sizes are stored as signed differences to the previous size
+            for _ in 2..count {
+                let (len, value) = lace_vint(&mut data)?;
+                size = size
+                    .checked_add(value as i64 - ((1 << (7 * len - 1)) - 1))
+                    .ok_or(MkvError::InvalidLacing)?;
+
+                sizes.push(usize::try_from(size).map_err(|_| MkvError::InvalidLacing)?);
+            }
+        }
+        LACING_FIXED => {
+            if !data.len().is_multiple_of(count) {
+                return Err(MkvError::InvalidLacing);
+            }
+
+            sizes.resize(count - 1, data.len() / count);
+        }
+        _ => unreachable!(),
+    }
+
+    let mut frames = Vec::with_capacity(count);
+    for size in sizes {
+        if size > data.len() {
+            return Err(MkvError::InvalidLacing);
+        }
+
+        frames.push(data.split_to(size));
+    }
+    frames.push(data);
+
+    Ok(frames)
+}
+
+/// Reads a variable size integer from a lace header.
+fn lace_vint(data: &mut Bytes) -> Result<(u32, u64), MkvError> {
+    let first = *data.first().ok_or(MkvError::InvalidLacing)?;
+    let len = first.leading_zeros() + 1;
+    if len > 8 || data.len() < len as usize {
+        return Err(MkvError::InvalidLacing);
+    }
+
+    let value = data[1..len as usize]
+        .iter()
+        .fold(first as u64 & (0xff >> len), |value, &b| (value << 8) | b as u64);
+    data.advance(len as usize);
+
+    Ok((len, value))
+}
+
+/// The size of a block after its header of `header_len` bytes.
+pub(super) fn block_data_size(size: u64, header_len: u64) -> Result<u64, MkvError> {
+    size.checked_sub(header_len)
+        .ok_or(MkvError::InvalidBlockSize(size))
+}
+
+/// Whether an error means the input is corrupt, which can be recovered from at the next cluster.
+fn is_corrupt(error: &MkvError) -> bool {
+    matches!(
+        error,
+        MkvError::UnsupportedVint(_)
+            | MkvError::UnsupportedVid(_)
+            | MkvError::InvalidLacing
+            | MkvError::InvalidBlockSize(_)
+            | MkvError::InvalidFloatSize(_)
+            | MkvError::UnexpectedElement(_)
+    )
+}
+
+/// Whether an element can be found between the blocks of clusters, as the child of a cluster or
+/// a segment.
+fn expected_between_blocks(id: u32) -> bool {
+    spec(id).is_some_and(|spec| {
+        spec.parents.is_empty()
+            || spec
+                .parents
+                .iter()
+                .any(|&parent| matches!(parent, ROOT | SEGMENT | CLUSTER))
+    })
+}
+
+/// Whether an element size is the reserved value for an unknown size, which has all bits set.
+pub(super) fn is_unknown_size(size: u64) -> bool {
+    (1..=8).any(|len| size == (1 << (7 * len)) - 1)
+}
+
+fn mand<T>(value: Option<T>, id: u32) -> Result<T, MkvError> {
+    value.ok_or(MkvError::MissingElement(id))
+}
+
+pub(super) async fn be16(io: &mut Io) -> Result<i16, MkvError> {
     let mut data = [0u8; 2];
 
     io.read_exact(&mut data).await?;
 
     Ok(i16::from_be_bytes(data))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(LACING_XIPH, &[2, 2, 0xff, 1] ; "xiph")]
+    #[test_case(LACING_EBML, &[2, 0x82, 0x60, 0xfd] ; "ebml")]
+    fn split_frames(lacing: u8, header: &[u8]) {
+        // frames of 2, 256 and 3 bytes
+        let frames = [&[1u8; 2][..], &[2u8; 256][..], &[3u8; 3][..]];
+        let data = [header, &frames.concat()].concat();
+
+        let split = split_laced_frames(lacing, data.into()).unwrap();
+
+        assert_eq!(&frames[..], &split.iter().map(|f| &f[..]).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn split_fixed_frames() {
+        let split = split_laced_frames(LACING_FIXED, vec![2, 1, 1, 2, 2, 3, 3].into()).unwrap();
+
+        assert_eq!(vec![&[1, 1][..], &[2, 2], &[3, 3]], split);
+        assert!(split_laced_frames(LACING_FIXED, vec![2, 1, 1, 2, 2, 3].into()).is_err());
+    }
+}
@@ -1,22 +1,35 @@
-use bytes::BytesMut;
+use bytes::BufMut;
 
 use crate::{
-    codec::{nal::get_codec_from_mp4, AssCodec, SubtitleCodec, SubtitleInfo},
+    codec::{AssCodec, SubtitleCodec, SubtitleInfo},
     demuxer,
     format::{ProbeResult, Demuxer, Movie},
     io::Io,
     AacCodec, AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, Packet, SoundType,
-    Track,
+    SpanBuilder, Track,
 };
 
 use super::*;
 
-pub async fn vstr(io: &mut Io, size: u64) -> Result<String, MkvError> {
-    let mut data = vec![0u8; size as usize];
+/// How much of an element is allocated up front, larger elements grow the buffer as they are read
+/// so a corrupt size can't allocate more memory than the input holds.
+const MAX_PREALLOCATION: u64 = 1 << 20;
+
+pub async fn vbin(io: &mut Io, size: u64) -> Result<Vec<u8>, MkvError> {
+    use tokio::io::AsyncReadExt;
 
-    io.read_exact(&mut data).await?;
+    let mut data = Vec::with_capacity(size.min(MAX_PREALLOCATION) as usize);
+    io.reader()?.take(size).read_to_end(&mut data).await?;
 
-    Ok(String::from_utf8(data)?)
+    if (data.len() as u64) < size {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(data)
+}
+
+pub async fn vstr(io: &mut Io, size: u64) -> Result<String, MkvError> {
+    Ok(String::from_utf8(vbin(io, size).await?)?)
 }
 
 pub async fn vfloat(io: &mut Io, size: u64) -> Result<f64, MkvError> {
@@ -117,8 +130,61 @@ pub async fn vid(io: &mut Io) -> Result<(u8, u32), MkvError> {
     Ok((len as u8, value))
 }
 
-fn write_vint(buf: &mut BytesMut, value: u64) {
+/// Writes a variable size integer using the least amount of bytes.
+pub fn write_vint(buf: &mut SpanBuilder, value: u64) {
+    // a value with all bits set is reserved for an unknown size
+    let len = (1..=8u32)
+        .find(|len| value < (1 << (7 * len)) - 1)
+        .expect("EBML integer out of range");
+
+    let marked = value | (1 << (7 * len));
+    buf.put_slice(&marked.to_be_bytes()[8 - len as usize..]);
+}
+
+/// Writes an element ID, which already includes its length marker.
+pub fn write_id(buf: &mut SpanBuilder, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
 
+    buf.put_slice(&bytes[skip..]);
+}
+
+pub fn write_uint(buf: &mut SpanBuilder, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+
+    write_binary(buf, id, &bytes[skip..]);
+}
+
+pub fn write_float(buf: &mut SpanBuilder, id: u32, value: f64) {
+    write_binary(buf, id, &value.to_be_bytes());
+}
+
+pub fn write_string(buf: &mut SpanBuilder, id: u32, value: &str) {
+    write_binary(buf, id, value.as_bytes());
+}
+
+pub fn write_binary(buf: &mut SpanBuilder, id: u32, value: &[u8]) {
+    write_id(buf, id);
+    write_vint(buf, value.len() as u64);
+    buf.put_slice(value);
+}
+
+/// Writes a Void element taking up exactly `len` bytes, which has to be at least 2.
+pub fn write_void(buf: &mut SpanBuilder, len: usize) {
+    write_id(buf, VOID);
+
+    let padding = if len < 9 {
+        write_vint(buf, len as u64 - 2);
+        len - 2
+    } else {
+        let mut size = ((len - 9) as u64).to_be_bytes();
+        size[0] = 0x01;
+        buf.put_slice(&size);
+        len - 9
+    };
+
+    buf.put_slice(&vec![0; padding]);
 }
 
 
@@ -145,13 +211,11 @@ mod test {
 
     #[tokio::test]
     async fn read_write_vint() {
-        let mut buf = BytesMut::new();
-
         for i in 0..100 { // u32::max_value() {
-            buf.clear();
+            let mut buf = SpanBuilder::new();
             write_vint(&mut buf, i);
 
-            let mut io = Io::from_reader(Box::new(Cursor::new(buf.to_vec())));
+            let mut io = Io::from_bytes(buf.build().to_bytes());
             let (_len, value) = super::vint(&mut io).await.unwrap();
 
             assert_eq!(i, value);
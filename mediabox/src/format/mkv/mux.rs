@@ -1,43 +1,811 @@
 use async_trait::async_trait;
+use bytes::BufMut;
+use log::*;
 
-use crate::{Track, io::Io, format::Muxer, muxer, Packet};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::SeekFrom,
+    time::Duration,
+};
 
-muxer!("mkv", MatroskaMuxer::create);
+use super::ebml::*;
+use super::*;
+
+use crate::{
+    codec::{
+        h264::AvcDecoderConfig,
+        nal::{convert_bitstream, BitstreamFraming},
+        AssCodec, SubtitleCodec, VobSubCodec, WebVttCodec,
+    },
+    format::{Attachment, Chapter, Movie, Muxer, MuxerOptionError, MuxerOptions},
+    io::Io,
+    muxer,
+    time::{to_duration, ClockTime},
+    AacCodec, AudioCodec, Av1Codec, FlacCodec, Fraction, H264Codec, MediaInfo, MediaKind,
+    OpusCodec, Packet, PcmCodec, PixelFormat, RawVideoCodec, SampleFormat, Span, SpanBuilder,
+    Track, VideoCodec, VideoInfo,
+};
+
+muxer!(
+    "mkv",
+    MatroskaMuxer::create,
+    MatroskaMuxer::create_with_options,
+    [
+        "h264", "av1", "vp8", "vp9", "rawvideo", "aac", "opus", "vorbis", "flac", "pcm", "ass",
+        "webvtt", "pgs", "vobsub"
+    ]
+);
+
+/// All timestamps are written in milliseconds.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+const MKV_TIMEBASE: Fraction = Fraction::new(1, (1_000_000_000 / TIMESTAMP_SCALE_NS) as u32);
+
+
+/// The amount of audio Opus decoders need to converge after seeking, as recommended by RFC 7845.
+const OPUS_SEEK_PRE_ROLL_NS: u64 = 80_000_000;
+
+/// The space reserved for the SeekHead at the start of the segment, which fits an entry for
+/// every top level element that is written.
+const SEEK_HEAD_SIZE: usize = 256;
+
+const WRITING_APP_NAME: &str = concat!("mediabox ", env!("CARGO_PKG_VERSION"));
+
+/// Per-track counters written as statistics tags when the muxer is stopped.
+#[derive(Default, Debug, Clone)]
+struct TrackStatistics {
+    bytes: u64,
+    frames: u64,
+    first_ts: Option<u64>,
+    end_ts: u64,
+}
+
+impl TrackStatistics {
+    fn add(&mut self, ts: u64, duration: u64, size: usize) {
+        self.bytes += size as u64;
+        self.frames += 1;
+        self.first_ts = Some(self.first_ts.map_or(ts, |first| first.min(ts)));
+        self.end_ts = self.end_ts.max(ts + duration);
+    }
+
+    fn duration_ms(&self) -> u64 {
+        self.end_ts - self.first_ts.unwrap_or(self.end_ts)
+    }
+
+    fn write_tags(&self, buf: &mut SpanBuilder, track_uid: u64) {
+        let duration = self.duration_ms();
+        let bps = (self.bytes * 8 * 1000).checked_div(duration).unwrap_or(0);
+        let duration = ClockTime::from_timestamp(duration, MKV_TIMEBASE).with_fraction_digits(9);
+
+        let tags = [
+            ("BPS", bps.to_string()),
+            ("DURATION", duration.to_string()),
+            ("NUMBER_OF_FRAMES", self.frames.to_string()),
+            ("NUMBER_OF_BYTES", self.bytes.to_string()),
+            ("_STATISTICS_WRITING_APP", WRITING_APP_NAME.to_string()),
+            (
+                "_STATISTICS_TAGS",
+                "BPS DURATION NUMBER_OF_FRAMES NUMBER_OF_BYTES".to_string(),
+            ),
+        ];
+
+        write_element!(buf, TAG, {
+            write_element!(buf, TARGETS, {
+                write_uint(buf, TAG_TRACK_UID, track_uid);
+            });
+
+            for (name, value) in tags {
+                write_element!(buf, SIMPLE_TAG, {
+                    write_string(buf, TAG_NAME, name);
+                    write_string(buf, TAG_STRING, &value);
+                });
+            }
+        });
+    }
+}
+
+/// The flavour of Matroska written by [MatroskaMuxer].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MatroskaProfile {
+    #[default]
+    Matroska,
+    /// The subset of Matroska supported by browsers, which only allows VP8, VP9, AV1, Opus,
+    /// Vorbis and WebVTT, and no attachments.
+    WebM,
+}
+
+impl MatroskaProfile {
+    fn doc_type(&self) -> &'static str {
+        match self {
+            MatroskaProfile::Matroska => "matroska",
+            MatroskaProfile::WebM => "webm",
+        }
+    }
+
+    fn supports(&self, info: &MediaInfo) -> bool {
+        if *self == MatroskaProfile::Matroska {
+            return true;
+        }
+
+        match &info.kind {
+            MediaKind::Video(video) => matches!(
+                video.codec,
+                VideoCodec::Vp8(_) | VideoCodec::Vp9(_) | VideoCodec::Av1(_)
+            ),
+            MediaKind::Audio(audio) => {
+                matches!(audio.codec, AudioCodec::Opus(_) | AudioCodec::Vorbis(_))
+            }
+            MediaKind::Subtitle(subtitle) => matches!(subtitle.codec, SubtitleCodec::WebVtt(_)),
+        }
+    }
+}
+
+struct MkvTrack {
+    track: Track,
+    number: u64,
+    stats: TrackStatistics,
+}
+
+struct CuePoint {
+    ts: u64,
+    track: u64,
+    /// The position of the cluster relative to the segment data.
+    cluster_position: u64,
+}
+
+/// Options of the Matroska and WebM muxers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatroskaOptions {
+    /// Clusters are closed once they span this long.
+    pub cluster_duration: Duration,
+    /// Start a new cluster at every video keyframe, so that players can seek to the start of
+    /// a cluster.
+    pub cluster_on_keyframes: bool,
+    /// Clusters are closed before they grow larger than this many bytes.
+    pub max_cluster_size: usize,
+}
+
+impl Default for MatroskaOptions {
+    fn default() -> Self {
+        MatroskaOptions {
+            cluster_duration: Duration::from_secs(5),
+            cluster_on_keyframes: true,
+            max_cluster_size: 5 * 1024 * 1024,
+        }
+    }
+}
+
+impl TryFrom<&MuxerOptions> for MatroskaOptions {
+    type Error = MuxerOptionError;
+
+    fn try_from(options: &MuxerOptions) -> Result<Self, Self::Error> {
+        options.expect_only(
+            "mkv",
+            &["cluster_duration", "cluster_on_keyframes", "max_cluster_size"],
+        )?;
+
+        let default = MatroskaOptions::default();
+        Ok(MatroskaOptions {
+            cluster_duration: options
+                .get_duration("cluster_duration")?
+                .unwrap_or(default.cluster_duration),
+            cluster_on_keyframes: options
+                .get("cluster_on_keyframes")?
+                .unwrap_or(default.cluster_on_keyframes),
+            max_cluster_size: options
+                .get("max_cluster_size")?
+                .unwrap_or(default.max_cluster_size),
+        })
+    }
+}
 
 pub struct MatroskaMuxer {
-    video: Option<Track>,
-    audio: Option<Track>,
+    profile: MatroskaProfile,
+    options: MatroskaOptions,
+    tracks: HashMap<u32, MkvTrack>,
+    has_video: bool,
+    attachments: Vec<Attachment>,
+    chapters: Vec<Chapter>,
+    metadata: BTreeMap<String, String>,
+    cluster: SpanBuilder,
+    cluster_ts: Option<u64>,
+    /// The track to add a cue point for when the current cluster is written.
+    cluster_cue: Option<u64>,
+    cues: Vec<CuePoint>,
+    /// The top level elements written so far, with their position relative to the segment data.
+    seek_entries: Vec<(u32, u64)>,
+    segment_start: u64,
+    position: u64,
     io: Io,
 }
 
 impl MatroskaMuxer {
     pub fn new(io: Io) -> Self {
         MatroskaMuxer {
-            video: None,
-            audio: None,
+            profile: MatroskaProfile::Matroska,
+            options: MatroskaOptions::default(),
+            tracks: HashMap::new(),
+            has_video: false,
+            attachments: Vec::new(),
+            chapters: Vec::new(),
+            metadata: BTreeMap::new(),
+            cluster: SpanBuilder::new(),
+            cluster_ts: None,
+            cluster_cue: None,
+            cues: Vec::new(),
+            seek_entries: Vec::new(),
+            segment_start: 0,
+            position: 0,
             io,
         }
     }
 
+    pub fn with_profile(mut self, profile: MatroskaProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn with_options(mut self, options: MatroskaOptions) -> Self {
+        self.options = options;
+        self
+    }
+
     fn create(io: Io) -> Box<dyn Muxer> {
         Box::new(Self::new(io))
     }
+
+    fn create_with_options(io: Io, options: &MuxerOptions) -> anyhow::Result<Box<dyn Muxer>> {
+        let options = MatroskaOptions::try_from(options)?;
+
+        Ok(Box::new(Self::new(io).with_options(options)))
+    }
+
+    pub(super) fn create_webm(io: Io) -> Box<dyn Muxer> {
+        Box::new(Self::new(io).with_profile(MatroskaProfile::WebM))
+    }
+
+    pub(super) fn create_webm_with_options(
+        io: Io,
+        options: &MuxerOptions,
+    ) -> anyhow::Result<Box<dyn Muxer>> {
+        let options = MatroskaOptions::try_from(options)?;
+
+        Ok(Box::new(Self::new(io).with_profile(MatroskaProfile::WebM).with_options(options)))
+    }
+
+    /// Whether a block with the given timestamp has to be put into a new cluster.
+    fn needs_new_cluster(&self, ts: u64, key: bool, is_video: bool) -> bool {
+        let Some(cluster_ts) = self.cluster_ts else {
+            return true;
+        };
+
+        let relative = ts as i64 - cluster_ts as i64;
+        let starts_gop =
+            self.options.cluster_on_keyframes && key && is_video && !self.cluster.is_empty();
+
+        starts_gop
+            || relative < i16::MIN as i64
+            || relative > i16::MAX as i64
+            || self.cluster.len() > self.options.max_cluster_size
+            || relative > self.options.cluster_duration.as_millis() as i64
+    }
+
+    async fn flush_cluster(&mut self) -> anyhow::Result<()> {
+        let Some(cluster_ts) = self.cluster_ts.take() else {
+            return Ok(());
+        };
+
+        let blocks = std::mem::take(&mut self.cluster).build();
+
+        if let Some(track) = self.cluster_cue.take() {
+            self.cues.push(CuePoint {
+                ts: cluster_ts,
+                track,
+                cluster_position: self.position - self.segment_start,
+            });
+        }
+
+        let mut buf = SpanBuilder::new();
+        write_element!(&mut buf, CLUSTER, {
+            write_uint(&mut buf, TIMESTAMP, cluster_ts);
+            buf.put_span(blocks);
+        });
+
+        self.write_span(buf.build()).await
+    }
+
+    async fn write_span(&mut self, span: Span) -> anyhow::Result<()> {
+        self.position += span.len() as u64;
+        self.io.write_span(span).await?;
+
+        Ok(())
+    }
+
+    /// Writes a top level element of the segment, remembering its position for the SeekHead.
+    async fn write_level1(&mut self, id: u32, buf: SpanBuilder) -> anyhow::Result<()> {
+        self.seek_entries.push((id, self.position - self.segment_start));
+        self.write_span(buf.build()).await
+    }
+
+    /// Replaces the Void reserved at the start of the segment with a SeekHead, and fills in the
+    /// size of the segment.
+    async fn write_seek_head(&mut self) -> anyhow::Result<()> {
+        let mut buf = SpanBuilder::new();
+        write_element!(&mut buf, SEEK_HEAD, {
+            for &(id, position) in &self.seek_entries {
+                write_element!(&mut buf, SEEK, {
+                    write_binary(&mut buf, SEEK_ID, &id.to_be_bytes());
+                    write_uint(&mut buf, SEEK_POSITION, position);
+                });
+            }
+        });
+
+        // a Void takes up at least 2 bytes
+        let Some(padding) = SEEK_HEAD_SIZE
+            .checked_sub(buf.len())
+            .filter(|&padding| padding != 1)
+        else {
+            warn!("SeekHead of {} B does not fit the space reserved for it", buf.len());
+            return Ok(());
+        };
+        if padding > 0 {
+            write_void(&mut buf, padding);
+        }
+
+        let mut segment_size = (self.position - self.segment_start).to_be_bytes();
+        segment_size[0] = 0x01;
+
+        self.io.seek(SeekFrom::Start(self.segment_start - 8)).await?;
+        self.io.write(&segment_size).await?;
+        self.io.write_span(buf.build()).await?;
+        self.io.seek(SeekFrom::Start(self.position)).await?;
+
+        Ok(())
+    }
+}
+
+fn write_header(buf: &mut SpanBuilder, profile: MatroskaProfile) {
+    write_element!(buf, EBML_HEADER, {
+        write_uint(buf, EBML_VERSION, 1);
+        write_uint(buf, EBML_READ_VERSION, 1);
+        write_uint(buf, EBML_MAX_ID_LENGTH, 4);
+        write_uint(buf, EBML_MAX_SIZE_LENGTH, 8);
+        write_string(buf, EBML_DOC_TYPE, profile.doc_type());
+        write_uint(buf, EBML_DOC_TYPE_VERSION, 4);
+        write_uint(buf, EBML_DOC_TYPE_READ_VERSION, 2);
+    });
+}
+
+fn write_info(buf: &mut SpanBuilder) {
+    write_element!(buf, INFO, {
+        write_uint(buf, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        write_string(buf, MUXING_APP, WRITING_APP_NAME);
+        write_string(buf, WRITING_APP, WRITING_APP_NAME);
+    });
+}
+
+fn write_attachments(buf: &mut SpanBuilder, attachments: &[Attachment]) {
+    write_element!(buf, ATTACHMENTS, {
+        for (uid, attachment) in (1..).zip(attachments) {
+            write_element!(buf, ATTACHED_FILE, {
+                write_string(buf, FILE_NAME, &attachment.name);
+                write_string(buf, FILE_MEDIA_TYPE, &attachment.mime);
+
+                write_id(buf, FILE_DATA);
+                write_vint(buf, attachment.data.len() as u64);
+                buf.put_span(attachment.data.clone());
+
+                write_uint(buf, FILE_UID, uid);
+            });
+        }
+    });
+}
+
+fn write_chapters(buf: &mut SpanBuilder, chapters: &[Chapter]) {
+    write_element!(buf, CHAPTERS, {
+        write_element!(buf, EDITION_ENTRY, {
+            for (uid, chapter) in (1..).zip(chapters) {
+                write_element!(buf, CHAPTER_ATOM, {
+                    write_uint(buf, CHAPTER_UID, uid);
+                    write_uint(buf, CHAPTER_TIME_START, chapter.start.as_nanos() as u64);
+                    if let Some(end) = chapter.end {
+                        write_uint(buf, CHAPTER_TIME_END, end.as_nanos() as u64);
+                    }
+
+                    if let Some(title) = &chapter.title {
+                        write_element!(buf, CHAPTER_DISPLAY, {
+                            write_string(buf, CHAP_STRING, title);
+                        });
+                    }
+                });
+            }
+        });
+    });
+}
+
+fn write_cues(buf: &mut SpanBuilder, cues: &[CuePoint]) {
+    write_element!(buf, CUES, {
+        for cue in cues {
+            write_element!(buf, CUE_POINT, {
+                write_uint(buf, CUE_TIME, cue.ts);
+                write_element!(buf, CUE_TRACK_POSITIONS, {
+                    write_uint(buf, CUE_TRACK, cue.track);
+                    write_uint(buf, CUE_CLUSTER_POSITION, cue.cluster_position);
+                });
+            });
+        }
+    });
+}
+
+fn write_metadata(buf: &mut SpanBuilder, metadata: &BTreeMap<String, String>) {
+    write_element!(buf, TAGS, {
+        write_element!(buf, TAG, {
+            write_element!(buf, TARGETS, {
+                write_uint(buf, TARGET_TYPE_VALUE, TARGET_TYPE_MOVIE);
+            });
+
+            for (name, value) in metadata {
+                write_element!(buf, SIMPLE_TAG, {
+                    write_string(buf, TAG_NAME, name);
+                    write_string(buf, TAG_STRING, value);
+                });
+            }
+        });
+    });
+}
+
+/// Whether the language can be stored in the legacy `Language` element.
+fn is_iso639_2(language: &str) -> bool {
+    language.len() == 3 && language.bytes().all(|b| b.is_ascii_lowercase())
+}
+
+fn write_track_entry(buf: &mut SpanBuilder, track: &Track, number: u64) -> anyhow::Result<()> {
+    write_element!(buf, TRACK_ENTRY, {
+        write_uint(buf, TRACK_NUMBER, number);
+        write_uint(buf, TRACK_UID, number);
+
+        let metadata = &track.metadata;
+        if let Some(title) = &metadata.title {
+            write_string(buf, NAME, title);
+        }
+        match metadata.language.as_deref() {
+            Some(language) if is_iso639_2(language) => write_string(buf, LANGUAGE, language),
+            Some(language) => write_string(buf, LANGUAGE_BCP47, language),
+            // Language defaults to English
+            None => write_string(buf, LANGUAGE, "und"),
+        }
+        write_uint(buf, FLAG_DEFAULT, metadata.default as u64);
+        write_uint(buf, FLAG_FORCED, metadata.forced as u64);
+
+        if let Some(duration) = fixed_frame_duration(&track.info) {
+            write_uint(buf, DEFAULT_DURATION, duration);
+        }
+
+        if track.delay > 0 {
+            let delay = to_duration(track.delay, track.timebase);
+            write_uint(buf, CODEC_DELAY, delay.as_nanos() as u64);
+        }
+
+        match &track.info.kind {
+            MediaKind::Video(video) => {
+                write_uint(buf, TRACK_TYPE, TRACK_TYPE_VIDEO);
+
+                match &video.codec {
+                    VideoCodec::H264(codec) => {
+                        write_string(buf, CODEC_ID, "V_MPEG4/ISO/AVC");
+                        write_binary(
+                            buf,
+                            CODEC_PRIVATE,
+                            &AvcDecoderConfig::from(codec).to_span().to_slice(),
+                        );
+                    }
+                    VideoCodec::Av1(Av1Codec { config }) => {
+                        write_string(buf, CODEC_ID, "V_AV1");
+                        write_binary(buf, CODEC_PRIVATE, &config.to_slice());
+                    }
+                    VideoCodec::Vp8(_) => {
+                        write_string(buf, CODEC_ID, "V_VP8");
+                    }
+                    VideoCodec::Vp9(vp9) => {
+                        write_string(buf, CODEC_ID, "V_VP9");
+                        write_binary(buf, CODEC_PRIVATE, &vp9.to_codec_private());
+                    }
+                    VideoCodec::Raw(_) => {
+                        write_string(buf, CODEC_ID, "V_UNCOMPRESSED");
+                    }
+                }
+
+                write_element!(buf, VIDEO, {
+                    write_uint(buf, PIXEL_WIDTH, video.width as u64);
+                    write_uint(buf, PIXEL_HEIGHT, video.height as u64);
+
+                    if let VideoCodec::Raw(RawVideoCodec { format }) = &video.codec {
+                        write_binary(buf, COLOUR_SPACE, &colour_space(*format));
+                    }
+                });
+            }
+            MediaKind::Audio(audio) => {
+                write_uint(buf, TRACK_TYPE, TRACK_TYPE_AUDIO);
+
+                match &audio.codec {
+                    AudioCodec::Aac(AacCodec { extra }) => {
+                        write_string(buf, CODEC_ID, "A_AAC");
+                        write_binary(buf, CODEC_PRIVATE, extra);
+                    }
+                    AudioCodec::Opus(OpusCodec { header }) => {
+                        write_string(buf, CODEC_ID, "A_OPUS");
+                        write_binary(buf, CODEC_PRIVATE, header);
+                        write_uint(buf, SEEK_PRE_ROLL, OPUS_SEEK_PRE_ROLL_NS);
+                    }
+                    AudioCodec::Vorbis(vorbis) => {
+                        write_string(buf, CODEC_ID, "A_VORBIS");
+                        write_binary(buf, CODEC_PRIVATE, &vorbis.to_xiph_laced());
+                    }
+                    AudioCodec::Flac(FlacCodec { header }) => {
+                        write_string(buf, CODEC_ID, "A_FLAC");
+                        write_binary(buf, CODEC_PRIVATE, header);
+                    }
+                    AudioCodec::Pcm(PcmCodec { format }) => {
+                        let Some(codec_id) = pcm_codec_id(*format) else {
+                            anyhow::bail!("{format:?} audio can't be stored in Matroska");
+                        };
+
+                        write_string(buf, CODEC_ID, codec_id);
+                    }
+                }
+
+                write_element!(buf, AUDIO, {
+                    write_float(buf, SAMPLING_FREQUENCY, audio.sample_rate as f64);
+                    write_uint(buf, CHANNELS, audio.sound_type.channel_count() as u64);
+                    write_uint(buf, BIT_DEPTH, audio.sample_bpp as u64);
+                });
+            }
+            MediaKind::Subtitle(subtitle) => {
+                write_uint(buf, TRACK_TYPE, TRACK_TYPE_SUBTITLE);
+
+                match &subtitle.codec {
+                    SubtitleCodec::Ass(AssCodec { header }) => {
+                        write_string(buf, CODEC_ID, "S_TEXT/ASS");
+                        write_string(buf, CODEC_PRIVATE, header);
+                    }
+                    SubtitleCodec::WebVtt(WebVttCodec { header }) => {
+                        write_string(buf, CODEC_ID, "S_TEXT/WEBVTT");
+                        write_string(buf, CODEC_PRIVATE, header);
+                    }
+                    SubtitleCodec::Pgs => {
+                        write_string(buf, CODEC_ID, "S_HDMV/PGS");
+                    }
+                    SubtitleCodec::VobSub(VobSubCodec { idx }) => {
+                        write_string(buf, CODEC_ID, "S_VOBSUB");
+                        write_string(buf, CODEC_PRIVATE, idx);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Converts the packet data to what Matroska expects for the codec.
+fn block_data(packet: &Packet) -> Span {
+    match &packet.track.info.kind {
+        MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(H264Codec {
+                bitstream_format, ..
+            }),
+            ..
+        }) => convert_bitstream(
+            packet.buffer.clone(),
+            *bitstream_format,
+            BitstreamFraming::FourByteLength,
+        ),
+        _ => packet.buffer.clone(),
+    }
+}
+
+/// The FourCC describing the layout of `V_UNCOMPRESSED` frames.
+fn colour_space(format: PixelFormat) -> [u8; 4] {
+    match format {
+        PixelFormat::Yuv420p => *b"I420",
+        PixelFormat::Nv12 => *b"NV12",
+        PixelFormat::Rgb24 => [b'R', b'G', b'B', 24],
+        PixelFormat::Rgba => *b"RGBA",
+    }
+}
+
+/// The codec ID of PCM audio, the bit depth is stored separately.
+fn pcm_codec_id(format: SampleFormat) -> Option<&'static str> {
+    match format {
+        SampleFormat::F32Le | SampleFormat::F64Le => Some("A_PCM/FLOAT/IEEE"),
+        SampleFormat::F32Be | SampleFormat::F64Be => None,
+        // 8 bit samples are always unsigned
+        format if format.is_big_endian() => Some("A_PCM/INT/BIG"),
+        _ => Some("A_PCM/INT/LIT"),
+    }
 }
 
 #[async_trait]
 impl Muxer for MatroskaMuxer {
-    async fn start(&mut self, streams: Vec<Track>) -> anyhow::Result<()> {
+    async fn start(&mut self, streams: Vec<Track>) -> crate::Result<()> {
+        // track numbers start at 1, keep the ids as they are unless one of them is 0
+        let offset = if streams.iter().any(|t| t.id == 0) {
+            1
+        } else {
+            0
+        };
+
+        if let Some(track) = streams.iter().find(|t| !self.profile.supports(&t.info)) {
+            return Err(MediaboxError::unsupported(format!(
+                "Track {:?} can't be stored in {:?}",
+                track, self.profile
+            )));
+        }
+
+        let mut buf = SpanBuilder::new();
+        write_header(&mut buf, self.profile);
+
+        // segment of unknown size, so that it can be written without seeking
+        write_id(&mut buf, SEGMENT);
+        buf.put_slice(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        let segment_start = buf.len() as u64;
+
+        // replaced by the SeekHead once the positions of all elements are known, or left as
+        // padding when the output can't be seeked
+        write_void(&mut buf, SEEK_HEAD_SIZE);
+
+        self.write_span(buf.build()).await?;
+        self.segment_start = segment_start;
+
+        let mut buf = SpanBuilder::new();
+        write_info(&mut buf);
+        self.write_level1(INFO, buf).await?;
+
+        let mut buf = SpanBuilder::new();
+        write_element!(&mut buf, TRACKS, {
+            for track in &streams {
+                let number = track.id as u64 + offset;
+                write_track_entry(&mut buf, track, number)?;
+            }
+        });
+        self.write_level1(TRACKS, buf).await?;
+
+        if !self.chapters.is_empty() {
+            let mut buf = SpanBuilder::new();
+            write_chapters(&mut buf, &self.chapters);
+            self.write_level1(CHAPTERS, buf).await?;
+        }
+        if !self.attachments.is_empty() {
+            if self.profile == MatroskaProfile::WebM {
+                warn!("Dropping {} attachments, WebM can't store them", self.attachments.len());
+            } else {
+                let mut buf = SpanBuilder::new();
+                write_attachments(&mut buf, &self.attachments);
+                self.write_level1(ATTACHMENTS, buf).await?;
+            }
+        }
+        if !self.metadata.is_empty() {
+            let mut buf = SpanBuilder::new();
+            write_metadata(&mut buf, &self.metadata);
+            self.write_level1(TAGS, buf).await?;
+        }
+
+        self.has_video = streams.iter().any(|t| t.is_video());
+        self.tracks = streams
+            .into_iter()
+            .map(|track| {
+                let number = track.id as u64 + offset;
+                let mkv_track = MkvTrack {
+                    track,
+                    number,
+                    stats: TrackStatistics::default(),
+                };
+
+                (mkv_track.track.id, mkv_track)
+            })
+            .collect();
+
         Ok(())
     }
 
-    async fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
+    async fn start_movie(&mut self, movie: Movie) -> crate::Result<()> {
+        self.attachments = movie.attachments;
+        self.chapters = movie.chapters;
+        self.metadata = movie.metadata;
+
+        self.start(movie.tracks).await
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        let track = match self.tracks.get(&packet.track.id) {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+
+        let number = track.number;
+        let is_video = track.track.is_video();
+        let is_subtitle = track.track.info.subtitle().is_some();
+
+        let time = packet.time.in_base(MKV_TIMEBASE);
+        let data = block_data(&packet);
+
+        if self.needs_new_cluster(time.pts, packet.key, is_video) {
+            self.flush_cluster().await?;
+            self.cluster_ts = Some(time.pts);
+
+            // players seek to video keyframes, or to any cluster if there is no video
+            self.cluster_cue = (packet.key && (is_video || !self.has_video)).then_some(number);
+        }
+
+        // a new cluster has been started if the timestamp did not fit
+        let relative = i16::try_from(time.pts as i64 - self.cluster_ts.unwrap_or(0) as i64)
+            .map_err(|_| anyhow::anyhow!("Timestamp {} out of range of its cluster", time.pts))?;
+
+        let mut block = SpanBuilder::new();
+        write_vint(&mut block, number);
+        block.put_i16(relative);
+
+        // subtitles need their duration, which can only be stored in a block group
+        match time.duration {
+            Some(duration) if is_subtitle => {
+                block.put_u8(0);
+
+                write_element!(&mut self.cluster, BLOCK_GROUP, {
+                    write_id(&mut self.cluster, BLOCK);
+                    write_vint(&mut self.cluster, (block.len() + data.len()) as u64);
+                    self.cluster.put_span(block.build());
+                    self.cluster.put_span(data.clone());
+
+                    write_uint(&mut self.cluster, BLOCK_DURATION, duration);
+                });
+            }
+            _ => {
+                block.put_u8(if packet.key { 0b1000_0000 } else { 0 });
+
+                write_id(&mut self.cluster, SIMPLE_BLOCK);
+                write_vint(&mut self.cluster, (block.len() + data.len()) as u64);
+                self.cluster.put_span(block.build());
+                self.cluster.put_span(data.clone());
+            }
+        }
+
+        if let Some(track) = self.tracks.get_mut(&packet.track.id) {
+            track
+                .stats
+                .add(time.pts, time.duration.unwrap_or(0), data.len());
+        }
+
         Ok(())
     }
 
-    async fn stop(&mut self) -> anyhow::Result<()> {
+    async fn stop(&mut self) -> crate::Result<()> {
+        self.flush_cluster().await?;
+
+        if !self.cues.is_empty() {
+            let mut buf = SpanBuilder::new();
+            write_cues(&mut buf, &self.cues);
+            self.write_level1(CUES, buf).await?;
+        }
+
+        let mut tracks = self.tracks.values().collect::<Vec<_>>();
+        tracks.sort_by_key(|t| t.number);
+
+        let mut buf = SpanBuilder::new();
+        write_element!(&mut buf, TAGS, {
+            for track in tracks {
+                debug!("Track {} statistics: {:?}", track.number, track.stats);
+
+                track.stats.write_tags(&mut buf, track.number);
+            }
+        });
+        self.write_level1(TAGS, buf).await?;
+
+        if self.io.seekable() {
+            self.write_seek_head().await?;
+        }
+
         Ok(())
     }
-    
+
     fn into_io(self) -> Io {
         self.io
     }
@@ -0,0 +1,187 @@
+use log::*;
+
+use super::demux::{be16, block_data_size, is_unknown_size};
+use super::validate::{element_name, read_header, spec, ElementType};
+use super::*;
+
+use crate::{format::StructureNode, io::Io};
+
+/// A master element whose children are being read.
+struct OpenNode {
+    /// The end of the element, unless its size is unknown.
+    end: Option<u64>,
+    id: u32,
+    node: StructureNode,
+}
+
+/// Reads the element tree of the rest of the input, with the values of the elements which are
+/// known. Reading stops at the first element which can't be read.
+pub(super) async fn read_structure(io: &mut Io) -> Result<Vec<StructureNode>, MkvError> {
+    let mut roots = Vec::new();
+    let mut open: Vec<OpenNode> = Vec::new();
+    let mut pos = io.read_position().await.unwrap_or(0);
+
+    loop {
+        while let Some(element) = open.pop_if(|e| e.end.is_some_and(|end| pos >= end)) {
+            add_node(element.node, &mut open, &mut roots);
+        }
+
+        let offset = pos;
+        let (len, id, size) = match read_header(io).await {
+            Ok(Some(header)) => header,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Stopped reading the structure at 0x{offset:x}: {e}");
+                break;
+            }
+        };
+        pos += len as u64;
+
+        let spec = spec(id);
+
+        // an element of unknown size ends at the first element which can't be its child
+        while let Some(element) =
+            open.pop_if(|e| e.end.is_none() && spec.is_some_and(|spec| spec.ends(e.id)))
+        {
+            add_node(element.node, &mut open, &mut roots);
+        }
+
+        let unknown_size = is_unknown_size(size);
+        let mut node = StructureNode {
+            name: element_name(id),
+            offset,
+            size: (!unknown_size).then_some(len as u64 + size),
+            value: None,
+            children: Vec::new(),
+        };
+
+        let ty = spec.map(|spec| spec.ty);
+        if ty == Some(ElementType::Master) {
+            open.push(OpenNode {
+                end: (!unknown_size).then_some(pos + size),
+                id,
+                node,
+            });
+            continue;
+        }
+
+        if unknown_size {
+            warn!("Stopped reading the structure at 0x{offset:x}: unknown size");
+            add_node(node, &mut open, &mut roots);
+            break;
+        }
+
+        match read_value(io, id, ty, size).await {
+            Ok(value) => node.value = value,
+            Err(e) => {
+                warn!("Stopped reading the structure at 0x{offset:x}: {e}");
+                add_node(node, &mut open, &mut roots);
+                break;
+            }
+        }
+        pos += size;
+
+        add_node(node, &mut open, &mut roots);
+    }
+
+    while let Some(element) = open.pop() {
+        add_node(element.node, &mut open, &mut roots);
+    }
+
+    Ok(roots)
+}
+
+fn add_node(node: StructureNode, open: &mut [OpenNode], roots: &mut Vec<StructureNode>) {
+    match open.last_mut() {
+        Some(parent) => parent.node.children.push(node),
+        None => roots.push(node),
+    }
+}
+
+/// Reads the data of an element, returning its value if it can be shown.
+async fn read_value(
+    io: &mut Io,
+    id: u32,
+    ty: Option<ElementType>,
+    size: u64,
+) -> Result<Option<String>, MkvError> {
+    let value = match (ty, id) {
+        (Some(ElementType::Uint), _) => vu(io, size).await?.to_string(),
+        (Some(ElementType::Float), _) => vfloat(io, size).await?.to_string(),
+        (Some(ElementType::Utf8), _) => format!("{:?}", vstr(io, size).await?),
+        (_, SEEK_ID) => {
+            let data = vbin(io, size).await?;
+            element_name(data.iter().fold(0, |id, &b| (id << 8) | b as u32))
+        }
+        (_, SIMPLE_BLOCK | BLOCK) => {
+            let (len, track) = vint(io).await?;
+            let rest = block_data_size(size, len as u64 + 3)?;
+            let timestamp = be16(io).await?;
+            let flags = vu(io, 1).await?;
+            io.skip(rest).await?;
+
+            // only SimpleBlocks have a keyframe flag
+            let key = if id == SIMPLE_BLOCK && flags & 0x80 != 0 {
+                ", keyframe"
+            } else {
+                ""
+            };
+
+            format!("track {track}, timestamp {timestamp}{key}")
+        }
+        _ => {
+            io.skip(size).await?;
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        format::{testsrc::TestSrcDemuxer, Demuxer, Muxer},
+        test, Fraction,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn dump_element_tree() {
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(1)).with_color_bars(
+            64,
+            64,
+            Fraction::new(25, 1),
+        );
+        let (movie, packets) = test::read_movie_and_packets(&mut source).await;
+
+        let mut muxer = MatroskaMuxer::new(Io::memory());
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+        let buffer = muxer.into_io().into_bytes().unwrap();
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer.clone()));
+        let roots = demuxer.dump_structure().await.unwrap();
+
+        let names = roots.iter().map(|n| n.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["EBML", "Segment"], names);
+        let size = roots.iter().map(|n| n.size.unwrap()).sum::<u64>();
+        assert_eq!(buffer.len() as u64, size);
+
+        let find = |parent: &StructureNode, name: &str| {
+            parent
+                .children
+                .iter()
+                .find(|n| n.name == name)
+                .cloned()
+                .unwrap()
+        };
+        let track = find(&find(&roots[1], "Tracks"), "TrackEntry");
+        let codec = find(&track, "CodecID");
+        assert_eq!(Some("\"V_MPEG4/ISO/AVC\""), codec.value.as_deref());
+
+        let cluster = find(&roots[1], "Cluster");
+        let block = find(&cluster, "SimpleBlock");
+        assert!(block.value.unwrap().ends_with("timestamp 0, keyframe"));
+    }
+}
@@ -0,0 +1,556 @@
+use std::fmt;
+
+use super::demux::is_unknown_size;
+use super::*;
+
+use crate::io::Io;
+
+/// The parent of top level elements.
+pub(super) const ROOT: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ElementType {
+    Master,
+    Uint,
+    Float,
+    Utf8,
+    Binary,
+}
+
+pub(super) struct ElementSpec {
+    pub id: u32,
+    pub name: &'static str,
+    pub ty: ElementType,
+    /// The elements this one may be a child of, any element if empty.
+    pub parents: &'static [u32],
+    /// Children which have to be present since they have no default value.
+    pub mandatory: &'static [u32],
+}
+
+impl ElementSpec {
+    /// Whether the element ends a parent of unknown size, since it can't be its child.
+    pub fn ends(&self, parent: u32) -> bool {
+        !self.parents.is_empty() && !self.parents.contains(&parent)
+    }
+}
+
+const fn master(
+    id: u32,
+    name: &'static str,
+    parents: &'static [u32],
+    mandatory: &'static [u32],
+) -> ElementSpec {
+    ElementSpec {
+        id,
+        name,
+        ty: ElementType::Master,
+        parents,
+        mandatory,
+    }
+}
+
+const fn leaf(
+    id: u32,
+    name: &'static str,
+    ty: ElementType,
+    parents: &'static [u32],
+) -> ElementSpec {
+    ElementSpec {
+        id,
+        name,
+        ty,
+        parents,
+        mandatory: &[],
+    }
+}
+
+use ElementType::*;
+
+/// The elements the demuxer knows about, other elements are only checked against the size of
+/// their parent.
+const ELEMENTS: &[ElementSpec] = &[
+    master(EBML_HEADER, "EBML", &[ROOT], &[]),
+    leaf(EBML_VERSION, "EBMLVersion", Uint, &[EBML_HEADER]),
+    leaf(EBML_READ_VERSION, "EBMLReadVersion", Uint, &[EBML_HEADER]),
+    leaf(EBML_MAX_ID_LENGTH, "EBMLMaxIDLength", Uint, &[EBML_HEADER]),
+    leaf(
+        EBML_MAX_SIZE_LENGTH,
+        "EBMLMaxSizeLength",
+        Uint,
+        &[EBML_HEADER],
+    ),
+    leaf(EBML_DOC_TYPE, "DocType", Utf8, &[EBML_HEADER]),
+    leaf(
+        EBML_DOC_TYPE_VERSION,
+        "DocTypeVersion",
+        Uint,
+        &[EBML_HEADER],
+    ),
+    leaf(
+        EBML_DOC_TYPE_READ_VERSION,
+        "DocTypeReadVersion",
+        Uint,
+        &[EBML_HEADER],
+    ),
+    leaf(VOID, "Void", Binary, &[]),
+    leaf(CRC32, "CRC-32", Binary, &[]),
+    master(SEGMENT, "Segment", &[ROOT], &[INFO]),
+    master(SEEK_HEAD, "SeekHead", &[SEGMENT], &[SEEK]),
+    master(SEEK, "Seek", &[SEEK_HEAD], &[SEEK_ID, SEEK_POSITION]),
+    leaf(SEEK_ID, "SeekID", Binary, &[SEEK]),
+    leaf(SEEK_POSITION, "SeekPosition", Uint, &[SEEK]),
+    master(INFO, "Info", &[SEGMENT], &[]),
+    leaf(TIMESTAMP_SCALE, "TimestampScale", Uint, &[INFO]),
+    leaf(DURATION, "Duration", Float, &[INFO]),
+    leaf(DATE_UTC, "DateUTC", Binary, &[INFO]),
+    leaf(MUXING_APP, "MuxingApp", Utf8, &[INFO]),
+    leaf(WRITING_APP, "WritingApp", Utf8, &[INFO]),
+    master(TRACKS, "Tracks", &[SEGMENT], &[TRACK_ENTRY]),
+    master(
+        TRACK_ENTRY,
+        "TrackEntry",
+        &[TRACKS],
+        &[TRACK_NUMBER, TRACK_UID, TRACK_TYPE, CODEC_ID],
+    ),
+    leaf(TRACK_NUMBER, "TrackNumber", Uint, &[TRACK_ENTRY]),
+    leaf(TRACK_UID, "TrackUID", Uint, &[TRACK_ENTRY]),
+    leaf(TRACK_TYPE, "TrackType", Uint, &[TRACK_ENTRY]),
+    leaf(CODEC_ID, "CodecID", Utf8, &[TRACK_ENTRY]),
+    leaf(CODEC_PRIVATE, "CodecPrivate", Binary, &[TRACK_ENTRY]),
+    leaf(CODEC_DELAY, "CodecDelay", Uint, &[TRACK_ENTRY]),
+    leaf(SEEK_PRE_ROLL, "SeekPreRoll", Uint, &[TRACK_ENTRY]),
+    leaf(DEFAULT_DURATION, "DefaultDuration", Uint, &[TRACK_ENTRY]),
+    leaf(
+        TRACK_TIMESTAMP_SCALE,
+        "TrackTimestampScale",
+        Float,
+        &[TRACK_ENTRY],
+    ),
+    leaf(NAME, "Name", Utf8, &[TRACK_ENTRY]),
+    leaf(LANGUAGE, "Language", Utf8, &[TRACK_ENTRY]),
+    leaf(LANGUAGE_BCP47, "LanguageBCP47", Utf8, &[TRACK_ENTRY]),
+    leaf(FLAG_DEFAULT, "FlagDefault", Uint, &[TRACK_ENTRY]),
+    leaf(FLAG_FORCED, "FlagForced", Uint, &[TRACK_ENTRY]),
+    master(VIDEO, "Video", &[TRACK_ENTRY], &[PIXEL_WIDTH, PIXEL_HEIGHT]),
+    leaf(PIXEL_WIDTH, "PixelWidth", Uint, &[VIDEO]),
+    leaf(PIXEL_HEIGHT, "PixelHeight", Uint, &[VIDEO]),
+    leaf(COLOUR_SPACE, "ColourSpace", Binary, &[VIDEO]),
+    master(AUDIO, "Audio", &[TRACK_ENTRY], &[]),
+    leaf(SAMPLING_FREQUENCY, "SamplingFrequency", Float, &[AUDIO]),
+    leaf(CHANNELS, "Channels", Uint, &[AUDIO]),
+    leaf(BIT_DEPTH, "BitDepth", Uint, &[AUDIO]),
+    master(CLUSTER, "Cluster", &[SEGMENT], &[TIMESTAMP]),
+    leaf(TIMESTAMP, "Timestamp", Uint, &[CLUSTER]),
+    master(SILENT_TRACKS, "SilentTracks", &[CLUSTER], &[]),
+    leaf(POSITION, "Position", Uint, &[CLUSTER]),
+    leaf(PREV_SIZE, "PrevSize", Uint, &[CLUSTER]),
+    leaf(ENCRYPTED_BLOCK, "EncryptedBlock", Binary, &[CLUSTER]),
+    leaf(SIMPLE_BLOCK, "SimpleBlock", Binary, &[CLUSTER]),
+    master(BLOCK_GROUP, "BlockGroup", &[CLUSTER], &[BLOCK]),
+    leaf(BLOCK, "Block", Binary, &[BLOCK_GROUP]),
+    leaf(BLOCK_DURATION, "BlockDuration", Uint, &[BLOCK_GROUP]),
+    master(CUES, "Cues", &[SEGMENT], &[CUE_POINT]),
+    master(
+        CUE_POINT,
+        "CuePoint",
+        &[CUES],
+        &[CUE_TIME, CUE_TRACK_POSITIONS],
+    ),
+    leaf(CUE_TIME, "CueTime", Uint, &[CUE_POINT]),
+    master(
+        CUE_TRACK_POSITIONS,
+        "CueTrackPositions",
+        &[CUE_POINT],
+        &[CUE_TRACK, CUE_CLUSTER_POSITION],
+    ),
+    leaf(CUE_TRACK, "CueTrack", Uint, &[CUE_TRACK_POSITIONS]),
+    leaf(
+        CUE_CLUSTER_POSITION,
+        "CueClusterPosition",
+        Uint,
+        &[CUE_TRACK_POSITIONS],
+    ),
+    master(ATTACHMENTS, "Attachments", &[SEGMENT], &[ATTACHED_FILE]),
+    master(
+        ATTACHED_FILE,
+        "AttachedFile",
+        &[ATTACHMENTS],
+        &[FILE_NAME, FILE_MEDIA_TYPE, FILE_DATA, FILE_UID],
+    ),
+    leaf(FILE_NAME, "FileName", Utf8, &[ATTACHED_FILE]),
+    leaf(FILE_MEDIA_TYPE, "FileMediaType", Utf8, &[ATTACHED_FILE]),
+    leaf(FILE_DATA, "FileData", Binary, &[ATTACHED_FILE]),
+    leaf(FILE_UID, "FileUID", Uint, &[ATTACHED_FILE]),
+    master(CHAPTERS, "Chapters", &[SEGMENT], &[EDITION_ENTRY]),
+    master(EDITION_ENTRY, "EditionEntry", &[CHAPTERS], &[CHAPTER_ATOM]),
+    master(
+        CHAPTER_ATOM,
+        "ChapterAtom",
+        &[EDITION_ENTRY, CHAPTER_ATOM],
+        &[CHAPTER_UID, CHAPTER_TIME_START],
+    ),
+    leaf(CHAPTER_UID, "ChapterUID", Uint, &[CHAPTER_ATOM]),
+    leaf(
+        CHAPTER_TIME_START,
+        "ChapterTimeStart",
+        Uint,
+        &[CHAPTER_ATOM],
+    ),
+    leaf(CHAPTER_TIME_END, "ChapterTimeEnd", Uint, &[CHAPTER_ATOM]),
+    master(
+        CHAPTER_DISPLAY,
+        "ChapterDisplay",
+        &[CHAPTER_ATOM],
+        &[CHAP_STRING],
+    ),
+    leaf(CHAP_STRING, "ChapString", Utf8, &[CHAPTER_DISPLAY]),
+    master(TAGS, "Tags", &[SEGMENT], &[TAG]),
+    master(TAG, "Tag", &[TAGS], &[TARGETS, SIMPLE_TAG]),
+    master(TARGETS, "Targets", &[TAG], &[]),
+    leaf(TARGET_TYPE_VALUE, "TargetTypeValue", Uint, &[TARGETS]),
+    leaf(TAG_TRACK_UID, "TagTrackUID", Uint, &[TARGETS]),
+    leaf(TAG_EDITION_UID, "TagEditionUID", Uint, &[TARGETS]),
+    leaf(TAG_CHAPTER_UID, "TagChapterUID", Uint, &[TARGETS]),
+    leaf(TAG_ATTACHMENT_UID, "TagAttachmentUID", Uint, &[TARGETS]),
+    master(SIMPLE_TAG, "SimpleTag", &[TAG, SIMPLE_TAG], &[TAG_NAME]),
+    leaf(TAG_NAME, "TagName", Utf8, &[SIMPLE_TAG]),
+    leaf(TAG_STRING, "TagString", Utf8, &[SIMPLE_TAG]),
+];
+
+pub(super) fn spec(id: u32) -> Option<&'static ElementSpec> {
+    ELEMENTS.iter().find(|spec| spec.id == id)
+}
+
+pub(super) fn element_name(id: u32) -> String {
+    match spec(id) {
+        Some(spec) => spec.name.to_string(),
+        None => format!("0x{id:x}"),
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum EbmlProblem {
+    #[error("Not allowed inside {0}")]
+    Misplaced(String),
+
+    #[error("Ends {0} B past the end of its parent")]
+    ExceedsParent(u64),
+
+    #[error("Ends {0} B past the end of the input")]
+    Truncated(u64),
+
+    #[error("Missing mandatory element {0}")]
+    MissingElement(String),
+
+    #[error("Unknown size, which only Segment and Cluster may have")]
+    UnknownSize,
+
+    #[error("Invalid size for its type: {0} B")]
+    InvalidSize(u64),
+
+    #[error("Invalid UTF-8")]
+    InvalidString,
+
+    #[error("Unreadable element: {0}")]
+    Unreadable(String),
+}
+
+/// A problem found in the structure of a Matroska file, see [`validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The names of the element and its parents, e.g. `Segment/Tracks/TrackEntry`. Elements
+    /// which aren't known are named by their ID.
+    pub path: String,
+    /// The position of the start of the element in the input.
+    pub offset: u64,
+    pub problem: EbmlProblem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at 0x{:x}: {}", self.path, self.offset, self.problem)
+    }
+}
+
+/// A master element whose children are being read.
+struct OpenElement {
+    id: u32,
+    path: String,
+    offset: u64,
+    /// The end of the element, unless its size is unknown.
+    end: Option<u64>,
+    children: Vec<u32>,
+}
+
+/// Reads the whole input and checks the nesting and sizes of its elements and that mandatory
+/// elements are present, instead of skipping over what the demuxer doesn't expect.
+///
+/// Reading stops at the first element which can't be read, after reporting it.
+pub async fn validate(io: &mut Io) -> Result<Vec<Diagnostic>, MkvError> {
+    let mut diagnostics = Vec::new();
+    let mut open: Vec<OpenElement> = Vec::new();
+    let mut pos = 0u64;
+
+    loop {
+        while let Some(parent) = open.pop_if(|e| e.end.is_some_and(|end| pos >= end)) {
+            close(parent, &mut diagnostics);
+        }
+
+        let offset = pos;
+        let (len, id, size) = match read_header(io).await {
+            Ok(Some(header)) => header,
+            Ok(None) => break,
+            Err(e) => {
+                diagnostics.push(Diagnostic {
+                    path: open.last().map_or(String::new(), |e| e.path.clone()),
+                    offset,
+                    problem: EbmlProblem::Unreadable(e.to_string()),
+                });
+                break;
+            }
+        };
+        pos += len as u64;
+
+        let spec = spec(id);
+        let unknown_size = is_unknown_size(size);
+
+        // an element of unknown size ends at the first element which can't be its child
+        while let Some(parent) =
+            open.pop_if(|e| e.end.is_none() && spec.is_some_and(|spec| spec.ends(e.id)))
+        {
+            close(parent, &mut diagnostics);
+        }
+        let path = match open.last() {
+            Some(parent) => format!("{}/{}", parent.path, element_name(id)),
+            None => element_name(id),
+        };
+        let mut report = |problem| {
+            diagnostics.push(Diagnostic {
+                path: path.clone(),
+                offset,
+                problem,
+            })
+        };
+
+        let parent_id = open.last().map_or(ROOT, |e| e.id);
+        if spec.is_some_and(|spec| spec.ends(parent_id)) {
+            let parent = match parent_id {
+                ROOT => "the top level".to_string(),
+                id => element_name(id),
+            };
+            report(EbmlProblem::Misplaced(parent));
+        }
+
+        let end = (!unknown_size).then(|| pos.saturating_add(size));
+        if let (Some(end), Some(parent_end)) = (end, open.last().and_then(|e| e.end)) {
+            if end > parent_end {
+                report(EbmlProblem::ExceedsParent(end - parent_end));
+            }
+        }
+
+        if let Some(parent) = open.last_mut() {
+            parent.children.push(id);
+        }
+
+        let ty = spec.map(|spec| spec.ty);
+        if unknown_size && !matches!(id, SEGMENT | CLUSTER) {
+            report(EbmlProblem::UnknownSize);
+
+            // the end of anything but a master element can't be found
+            if ty != Some(Master) {
+                break;
+            }
+        }
+
+        if ty == Some(Master) {
+            open.push(OpenElement {
+                id,
+                path,
+                offset,
+                end,
+                children: Vec::new(),
+            });
+            continue;
+        }
+
+        let valid_size = match ty {
+            Some(Uint) => size <= 8,
+            Some(Float) => matches!(size, 0 | 4 | 8),
+            _ => true,
+        };
+        if !valid_size {
+            report(EbmlProblem::InvalidSize(size));
+        }
+
+        let read = match read_data(io, size, ty == Some(Utf8) && valid_size).await {
+            Ok((read, valid)) => {
+                if !valid {
+                    report(EbmlProblem::InvalidString);
+                }
+
+                read
+            }
+            Err(e) => {
+                report(EbmlProblem::Unreadable(e.to_string()));
+                break;
+            }
+        };
+        pos += read;
+
+        if read < size {
+            report(EbmlProblem::Truncated(size - read));
+            break;
+        }
+    }
+
+    // the elements still open end with the input
+    while let Some(element) = open.pop() {
+        if let Some(end) = element.end.filter(|&end| end > pos) {
+            diagnostics.push(Diagnostic {
+                path: element.path.clone(),
+                offset: element.offset,
+                problem: EbmlProblem::Truncated(end - pos),
+            });
+        }
+
+        close(element, &mut diagnostics);
+    }
+
+    diagnostics.sort_by_key(|d| d.offset);
+
+    Ok(diagnostics)
+}
+
+/// Reads the ID and size of the next element, returning their total length. Returns `None` at
+/// the end of the input.
+pub(super) async fn read_header(io: &mut Io) -> Result<Option<(u8, u32, u64)>, MkvError> {
+    let (id_len, id) = match vid(io).await {
+        Ok(vid) => vid,
+        Err(MkvError::StdIo(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    let (len, size) = vint(io).await?;
+
+    Ok(Some((id_len + len, id, size)))
+}
+
+/// Reports the mandatory children of an element which weren't found.
+fn close(element: OpenElement, diagnostics: &mut Vec<Diagnostic>) {
+    let Some(spec) = spec(element.id) else {
+        return;
+    };
+
+    for &mandatory in spec.mandatory {
+        if !element.children.contains(&mandatory) {
+            diagnostics.push(Diagnostic {
+                path: element.path.clone(),
+                offset: element.offset,
+                problem: EbmlProblem::MissingElement(element_name(mandatory)),
+            });
+        }
+    }
+}
+
+/// Reads up to `size` bytes of element data, checking that it's valid UTF-8 if `string` is
+/// set. Returns how many bytes were read, which is less than `size` at the end of the input.
+async fn read_data(io: &mut Io, size: u64, string: bool) -> Result<(u64, bool), MkvError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut reader = io.reader()?.take(size);
+
+    if string {
+        let mut data = Vec::new();
+        let read = reader.read_to_end(&mut data).await? as u64;
+
+        Ok((read, std::str::from_utf8(&data).is_ok()))
+    } else {
+        let read = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+
+        Ok((read, true))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        format::{testsrc::TestSrcDemuxer, Demuxer, Muxer},
+        test, Fraction, SpanBuilder,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn validate_muxed_file() {
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(1))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_sine(1000.0, 48_000);
+        let (movie, packets) = test::read_movie_and_packets(&mut source).await;
+
+        let mut muxer = MatroskaMuxer::new(Io::from_stream(Box::new(Vec::<u8>::new())));
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+        let buffer = muxer.into_io().into_bytes().unwrap();
+
+        let diagnostics = validate(&mut Io::from_bytes(buffer.clone())).await.unwrap();
+        assert_eq!(Vec::<Diagnostic>::new(), diagnostics);
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(buffer)).with_strict();
+        let (_, read) = test::read_movie_and_packets(&mut demuxer).await;
+        assert_eq!(packets.len(), read.len());
+    }
+
+    #[tokio::test]
+    async fn report_structure_problems() {
+        let mut buf = SpanBuilder::new();
+        write_element!(&mut buf, EBML_HEADER, {
+            write_string(&mut buf, EBML_DOC_TYPE, "matroska");
+        });
+        write_element!(&mut buf, SEGMENT, {
+            write_element!(&mut buf, INFO, {
+                write_uint(&mut buf, TIMESTAMP_SCALE, 1_000_000);
+            });
+            write_element!(&mut buf, TRACKS, {
+                write_element!(&mut buf, TRACK_ENTRY, {
+                    write_uint(&mut buf, TRACK_NUMBER, 1);
+                    write_uint(&mut buf, TRACK_UID, 1);
+                    write_uint(&mut buf, TRACK_TYPE, 1);
+                    write_uint(&mut buf, PIXEL_WIDTH, 64);
+                });
+            });
+
+            // a cluster claiming more data than there is
+            write_id(&mut buf, CLUSTER);
+            write_vint(&mut buf, 100);
+            write_uint(&mut buf, TIMESTAMP, 0);
+        });
+        let data = buf.build().to_bytes();
+
+        let diagnostics = validate(&mut Io::from_bytes(data.clone())).await.unwrap();
+        let problems = diagnostics
+            .iter()
+            .map(|d| (d.path.as_str(), d.problem.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    "Segment/Tracks/TrackEntry",
+                    EbmlProblem::MissingElement("CodecID".into())
+                ),
+                (
+                    "Segment/Tracks/TrackEntry/PixelWidth",
+                    EbmlProblem::Misplaced("TrackEntry".into())
+                ),
+                ("Segment/Cluster", EbmlProblem::ExceedsParent(97)),
+                ("Segment/Cluster", EbmlProblem::Truncated(97)),
+            ],
+            problems
+        );
+
+        let mut demuxer = MatroskaDemuxer::new(Io::from_bytes(data)).with_strict();
+        let error = demuxer.start().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Segment/Tracks/TrackEntry at 0x"));
+    }
+}
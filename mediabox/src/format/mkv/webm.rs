@@ -0,0 +1,11 @@
+//! WebM, written by [MatroskaMuxer] with the WebM profile.
+
+use super::MatroskaMuxer;
+use crate::muxer;
+
+muxer!(
+    "webm",
+    MatroskaMuxer::create_webm,
+    MatroskaMuxer::create_webm_with_options,
+    ["vp8", "vp9", "av1", "opus", "vorbis", "webvtt"]
+);
@@ -1,9 +1,17 @@
-use bytes::{BufMut, BytesMut};
+use bytes::BufMut;
+
+use std::time::Duration;
 
 use crate::{
-    codec::nal::{convert_bitstream, frame_nal_units, BitstreamFraming},
-    AudioCodec, AudioInfo, H264Codec, MediaKind, MediaTime, Packet, Span, Track, VideoCodec,
-    VideoInfo,
+    cenc::TrackEncryption,
+    codec::{
+        h264::AvcDecoderConfig,
+        nal::{convert_bitstream, BitstreamFraming},
+        opus::{OpusHead, OPUS_SAMPLE_RATE},
+    },
+    time::{from_duration, to_duration},
+    AudioCodec, AudioInfo, Av1Codec, Fraction, H264Codec, MediaInfo, MediaKind, MediaTime,
+    OpusCodec, Packet, Span, SpanBuilder, Track, VideoCodec, VideoInfo,
 };
 
 // Wonderful macro taken from https://github.com/scottlamb/retina/ examples
@@ -11,23 +19,22 @@ macro_rules! write_box {
     ($buf:expr, $fourcc:expr, $b:block) => {
         #[allow(clippy::unnecessary_mut_passed)]
         {
-            let _: &mut bytes::BytesMut = $buf; // type-check.
-            let pos_start = $buf.len();
+            let _: &mut $crate::SpanBuilder = $buf; // type-check.
+            let size = $buf.reserve(4);
             let fourcc: &[u8; 4] = $fourcc;
-            $buf.extend_from_slice(&[0, 0, 0, 0, fourcc[0], fourcc[1], fourcc[2], fourcc[3]]);
+            $buf.put_slice(fourcc);
             let r = {
                 $b;
             };
-            let pos_end = $buf.len();
-            let len = pos_end.checked_sub(pos_start).unwrap();
-            $buf[pos_start..pos_start + 4].copy_from_slice(&(len as u32).to_be_bytes()[..]);
+            let len = $buf.len() - size.position();
+            $buf.patch(size, &(len as u32).to_be_bytes());
             r
         }
     };
 }
 
-mod fmp4;
-mod mp4;
+pub mod fmp4;
+pub mod mp4;
 
 pub use fmp4::*;
 pub use mp4::*;
@@ -48,7 +55,7 @@ fn get_packet_sample_data(packet: &Packet) -> Span {
     }
 }
 
-fn type_check<R, T: FnOnce(&mut bytes::BytesMut) -> R>(f: T) -> T {
+fn type_check<R, T: FnOnce(&mut SpanBuilder) -> R>(f: T) -> T {
     f
 }
 
@@ -56,26 +63,29 @@ macro_rules! write_base_descriptor {
     ($buf:expr, $tag:expr, $b:expr) => {
         #[allow(clippy::unnecessary_mut_passed)]
         {
-            let _: &mut bytes::BytesMut = $buf; // type-check.
+            let _: &mut SpanBuilder = $buf; // type-check.
             let f = type_check($b); // type-check.
-            let mut buf = BytesMut::new();
+            let mut buf = SpanBuilder::new();
             let r = f(&mut buf);
 
             write_base_descriptor_header($buf, $tag, buf.len() as u32);
-            $buf.extend_from_slice(&buf);
+            $buf.put_span(buf.build());
 
             r
         }
     };
 }
 
-fn write_mvhd(buf: &mut BytesMut) {
+/// Durations of the movie, like those of edits, are in milliseconds.
+const MOVIE_TIMESCALE: u32 = 1_000;
+
+fn write_mvhd(buf: &mut SpanBuilder, duration: u64, next_track_id: u32) {
     write_box!(buf, b"mvhd", {
         buf.put_u32(1 << 24); // version
         buf.put_u64(0); // creation_time
         buf.put_u64(0); // modification_time
-        buf.put_u32(1_000); // timescale
-        buf.put_u64(0);
+        buf.put_u32(MOVIE_TIMESCALE); // timescale
+        buf.put_u64(duration); // duration
         buf.put_u32(0x00010000); // rate
         buf.put_u16(0x0100); // volume
         buf.put_u16(0); // reserved
@@ -86,7 +96,7 @@ fn write_mvhd(buf: &mut BytesMut) {
         for _ in 0..6 {
             buf.put_u32(0); // pre_defined
         }
-        buf.put_u32(u32::MAX); // next_track_id
+        buf.put_u32(next_track_id); // next_track_id
     });
 }
 
@@ -95,61 +105,207 @@ struct TrackBuilder {
     track: Track,
     id: u32,
     sample_entries: Vec<SampleEntry>,
+    /// The number of samples at the start added by the encoder, which are not presented.
+    encoder_delay: u64,
+    /// When the earliest track of the movie starts, tracks starting later are delayed by an
+    /// empty edit.
+    movie_start: Duration,
+    /// Whether the samples are encrypted, which wraps the sample entry in an `encv` or `enca`.
+    encryption: Option<TrackEncryption>,
 }
 
 impl TrackBuilder {
     fn new(track: Track, id: u32) -> Self {
         TrackBuilder {
+            encoder_delay: track.delay,
             track,
             id,
             sample_entries: Vec::new(),
+            movie_start: Duration::ZERO,
+            encryption: None,
         }
     }
 
     fn add_sample(&mut self, entry: SampleEntry) {
         self.sample_entries.push(entry);
     }
+
+    /// The duration of each sample, which is the time until the next sample is decoded.
+    fn sample_durations(&self) -> Vec<u64> {
+        let entries = &self.sample_entries;
+
+        let mut durations = entries
+            .windows(2)
+            .map(|pair| decode_time(&pair[1].time).saturating_sub(decode_time(&pair[0].time)))
+            .collect::<Vec<_>>();
+
+        // the last sample lasts as long as it says, or as long as the one before it
+        if let Some(last) = entries.last() {
+            let duration = last
+                .time
+                .duration
+                .or_else(|| durations.last().copied())
+                .unwrap_or(0);
+            durations.push(duration);
+        }
+
+        durations
+    }
+
+    /// The duration of the media in the timescale of the track.
+    fn duration(&self) -> u64 {
+        self.sample_durations().iter().sum()
+    }
+
+    /// The duration of the track in the timescale of the movie, which is the length of its
+    /// edits.
+    fn movie_duration(&self) -> u64 {
+        let edits = self.edit_list();
+        if edits.is_empty() {
+            let duration = to_duration(self.duration(), self.track.timebase);
+            return from_duration(duration, Fraction::new(1, MOVIE_TIMESCALE));
+        }
+
+        edits.iter().map(|edit| edit.segment_duration).sum()
+    }
+
+    /// When the first sample is presented.
+    fn start(&self) -> Option<Duration> {
+        let pts = self.sample_entries.iter().map(|e| e.time.pts).min()?;
+
+        Some(to_duration(pts, self.track.timebase))
+    }
+
+    /// The edits which present the samples at their timestamps, with the media starting at the
+    /// decode time of the first sample.
+    fn edit_list(&self) -> Vec<Edit> {
+        let movie_timebase = Fraction::new(1, MOVIE_TIMESCALE);
+        let entries = &self.sample_entries;
+
+        let (Some(first_pts), Some(first_dts)) = (
+            entries.iter().map(|e| e.time.pts).min(),
+            entries
+                .iter()
+                .map(|e| e.time.dts.unwrap_or(e.time.pts))
+                .min(),
+        ) else {
+            // without samples only the encoder delay is known, the edit lasts until the end
+            if self.encoder_delay == 0 {
+                return Vec::new();
+            }
+
+            return vec![Edit {
+                segment_duration: 0,
+                media_time: self.encoder_delay as i64,
+            }];
+        };
+
+        let mut edits = Vec::new();
+
+        let start = to_duration(first_pts, self.track.timebase);
+        let empty = from_duration(start.saturating_sub(self.movie_start), movie_timebase);
+        if empty > 0 {
+            edits.push(Edit {
+                segment_duration: empty,
+                media_time: -1,
+            });
+        }
+
+        // B-frames are decoded before the first sample is presented
+        let media_time = first_pts - first_dts + self.encoder_delay;
+        if media_time > 0 || !edits.is_empty() {
+            let end = entries
+                .iter()
+                .map(|e| e.time.pts + e.time.duration.unwrap_or(0))
+                .max()
+                .unwrap_or(first_pts);
+            let duration = end.saturating_sub(first_pts + self.encoder_delay);
+
+            edits.push(Edit {
+                segment_duration: from_duration(
+                    to_duration(duration, self.track.timebase),
+                    movie_timebase,
+                ),
+                media_time: media_time as i64,
+            });
+        }
+
+        edits
+    }
+}
+
+/// An entry of an edit list, with the duration in the timescale of the movie and the media time
+/// in the timescale of the track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Edit {
+    segment_duration: u64,
+    /// Where the edit starts in the media, or -1 for an empty edit.
+    media_time: i64,
 }
 
 #[derive(Clone)]
 struct SampleEntry {
     is_sync: bool,
+    /// Where the sample is in the file.
+    offset: u64,
     size: u64,
     time: MediaTime,
 }
 
-fn write_trak(buf: &mut BytesMut, builder: TrackBuilder) -> anyhow::Result<()> {
-    let stream = builder.track;
-    let track_id = builder.id;
+fn decode_time(time: &MediaTime) -> u64 {
+    time.dts.unwrap_or(time.pts)
+}
+
+/// Groups equal values which follow each other, as the number of values and the value.
+fn run_lengths<T: PartialEq>(values: impl IntoIterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
 
+    runs
+}
+
+fn write_trak(buf: &mut SpanBuilder, builder: &TrackBuilder) -> anyhow::Result<()> {
+    let stream = &builder.track;
+    let edits = builder.edit_list();
     let timebase = stream.timebase.simplify().denominator;
 
     write_box!(buf, b"trak", {
-        write_tkhd(buf, track_id, 0, 0);
+        write_tkhd(buf, builder.id, builder.movie_duration(), &stream.info)?;
+        write_edts(buf, &edits);
+        write_udta(buf, stream.metadata.title.as_deref());
 
         write_box!(buf, b"mdia", {
-            write_mdhd(buf, timebase);
-            write_hdlr(buf);
+            write_mdhd(
+                buf,
+                timebase,
+                builder.duration(),
+                stream.metadata.language.as_deref(),
+            );
+            write_hdlr(buf, &stream.info.kind)?;
 
             write_box!(buf, b"minf", {
                 match stream.info.kind {
                     MediaKind::Video(_) => {
                         write_box!(buf, b"vmhd", {
-                            buf.put_u32(1);
-                            buf.put_u64(0);
+                            buf.put_u32(1); // version, flags
+                            buf.put_u64(0); // graphicsmode, opcolor
                         });
                     }
-                    MediaKind::Audio(_) => {
-                        write_box!(buf, b"soun", {
-                            buf.put_u32(1);
-                            buf.put_u64(0);
+                    _ => {
+                        write_box!(buf, b"smhd", {
+                            buf.put_u32(0); // version, flags
+                            buf.put_u32(0); // balance, reserved
                         });
                     }
-                    _ => todo!(),
                 }
                 write_dinf(buf);
 
-                write_stbl(buf, stream, &builder.sample_entries)?;
+                write_stbl(buf, builder)?;
             });
         });
     });
@@ -157,66 +313,61 @@ fn write_trak(buf: &mut BytesMut, builder: TrackBuilder) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn write_video_trak(buf: &mut BytesMut, builder: TrackBuilder) -> anyhow::Result<()> {
-    let stream = builder.track;
-    let track_id = builder.id;
-
-    let info = stream
-        .info
-        .video()
-        .expect("Video stream should contain video info");
-    let timebase = stream.timebase.simplify().denominator;
-
-    write_box!(buf, b"trak", {
-        let width = u32::from(u16::try_from(info.width)?) << 16;
-        let height = u32::from(u16::try_from(info.height)?) << 16;
-
-        write_tkhd(buf, track_id, width, height);
-
-        write_box!(buf, b"mdia", {
-            write_mdhd(buf, timebase);
-            write_hdlr(buf);
-
-            write_box!(buf, b"minf", {
-                write_box!(buf, b"vmhd", {
-                    buf.put_u32(1);
-                    buf.put_u64(0);
-                });
-                write_dinf(buf);
+fn write_stsd(
+    buf: &mut SpanBuilder,
+    track: &Track,
+    encryption: Option<&TrackEncryption>,
+) -> anyhow::Result<()> {
+    write_box!(buf, b"stsd", {
+        buf.put_u32(0); // version
+        buf.put_u32(1); // entry_count
 
-                write_video_stbl(buf, info, &builder.sample_entries)?;
-            });
-        });
+        match encryption {
+            Some(encryption) => write_encrypted_sample_entry(buf, track, encryption)?,
+            None => write_sample_description(buf, track)?,
+        }
     });
 
     Ok(())
 }
 
-fn write_audio_trak(buf: &mut BytesMut, builder: TrackBuilder) -> anyhow::Result<()> {
-    let stream = builder.track;
-    let track_id = builder.id;
-
-    let info = stream
-        .info
-        .audio()
-        .expect("Audio stream should contain audio info");
-    let timebase = stream.timebase.simplify().denominator;
+fn write_sample_description(buf: &mut SpanBuilder, track: &Track) -> anyhow::Result<()> {
+    match &track.info.kind {
+        MediaKind::Video(info) => write_video_sample_entry(buf, info),
+        MediaKind::Audio(info) => write_audio_sample_description(buf, info),
+        MediaKind::Subtitle(_) => anyhow::bail!("Subtitles can't be stored in MP4"),
+    }
+}
 
-    write_box!(buf, b"trak", {
-        write_tkhd(buf, track_id, 0, 0);
+/// Writes the sample entry as an `encv` or `enca`, with the original format in its `sinf`.
+fn write_encrypted_sample_entry(
+    buf: &mut SpanBuilder,
+    track: &Track,
+    encryption: &TrackEncryption,
+) -> anyhow::Result<()> {
+    let mut entry = SpanBuilder::new();
+    write_sample_description(&mut entry, track)?;
+    let entry = entry.build().to_bytes();
 
-        write_box!(buf, b"mdia", {
-            write_mdhd(buf, timebase);
-            write_hdlr(buf);
+    let fourcc = match track.info.kind {
+        MediaKind::Video(_) => b"encv",
+        _ => b"enca",
+    };
 
-            write_box!(buf, b"minf", {
-                write_box!(buf, b"soun", {
-                    buf.put_u32(1);
-                    buf.put_u64(0);
-                });
-                write_dinf(buf);
+    write_box!(buf, fourcc, {
+        buf.put_slice(&entry[8..]);
 
-                write_audio_stbl(buf, info)?;
+        write_box!(buf, b"sinf", {
+            write_box!(buf, b"frma", {
+                buf.put_slice(&entry[4..8]); // data_format
+            });
+            write_box!(buf, b"schm", {
+                buf.put_u32(0); // version, flags
+                buf.put_slice(encryption.scheme.fourcc()); // scheme_type
+                buf.put_u32(0x0001_0000); // scheme_version
+            });
+            write_box!(buf, b"schi", {
+                write_tenc(buf, encryption);
             });
         });
     });
@@ -224,151 +375,162 @@ fn write_audio_trak(buf: &mut BytesMut, builder: TrackBuilder) -> anyhow::Result
     Ok(())
 }
 
-fn write_stsd(buf: &mut BytesMut, track: Track) -> anyhow::Result<()> {
-    write_box!(buf, b"stsd", {
-        buf.put_u32(0); // version
-        buf.put_u32(1); // entry_count
-
-        match &track.info.kind {
-            MediaKind::Video(info) => write_video_sample_entry(buf, info)?,
-            MediaKind::Audio(info) => write_audio_sample_description(buf, info)?,
-            _ => todo!(),
-        }
-    });
-
-    Ok(())
-}
-
-fn write_stss(buf: &mut BytesMut, entries: &[SampleEntry]) {
-    let sync_samples = entries
-        .iter()
-        .enumerate()
-        .filter_map(|(idx, entry)| {
-            if entry.is_sync {
-                Some(idx as u32 + 1)
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-
-    write_box!(buf, b"stss", {
-        buf.put_u32(0); // version
-        buf.put_u32(sync_samples.len() as u32); // len
-
-        for idx in sync_samples {
-            buf.put_u32(idx); // sample_number
+fn write_tenc(buf: &mut SpanBuilder, encryption: &TrackEncryption) {
+    write_box!(buf, b"tenc", {
+        // version 1 has the encryption pattern
+        let (crypt, skip) = encryption.pattern;
+        let version = u32::from(crypt != 0 || skip != 0);
+        buf.put_u32(version << 24); // version, flags
+        buf.put_u8(0); // reserved
+        buf.put_u8((crypt << 4) | (skip & 0xf)); // default_crypt_byte_block, default_skip_byte_block
+        buf.put_u8(1); // default_isProtected
+        buf.put_u8(encryption.per_sample_iv_size); // default_Per_Sample_IV_Size
+        buf.put_slice(&encryption.key_id); // default_KID
+        if let (0, Some(iv)) = (encryption.per_sample_iv_size, encryption.constant_iv) {
+            buf.put_u8(iv.len() as u8); // default_constant_IV_size
+            buf.put_slice(&iv); // default_constant_IV
         }
     });
 }
 
-fn write_stbl(buf: &mut BytesMut, track: Track, entries: &[SampleEntry]) -> anyhow::Result<()> {
-    write_box!(buf, b"stbl", {
-        write_stsd(buf, track)?;
-        write_stss(buf, entries);
+/// Writes the sample tables, which are empty for fragmented files.
+fn write_stbl(buf: &mut SpanBuilder, builder: &TrackBuilder) -> anyhow::Result<()> {
+    let entries = &builder.sample_entries;
 
-        write_box!(buf, b"stsc", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stsz", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // sample_size
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stco", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // len
-        });
-    });
-
-    Ok(())
-}
-
-fn write_video_stbl(
-    buf: &mut BytesMut,
-    info: &VideoInfo,
-    entries: &[SampleEntry],
-) -> anyhow::Result<()> {
     write_box!(buf, b"stbl", {
-        write_box!(buf, b"stsd", {
-            buf.put_u32(0); // version
-            buf.put_u32(1); // entry_count
+        write_stsd(buf, &builder.track, builder.encryption.as_ref())?;
 
-            write_video_sample_entry(buf, info)?;
-        });
-        write_box!(buf, b"stss", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // len
-        });
+        let durations = run_lengths(builder.sample_durations());
         write_box!(buf, b"stts", {
-            buf.put_u32(0);
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stsc", {
             buf.put_u32(0); // version
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stsz", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // sample_size
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stco", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // len
+            buf.put_u32(durations.len() as u32); // entry_count
+            for (count, duration) in durations {
+                buf.put_u32(count); // sample_count
+                buf.put_u32(duration as u32); // sample_delta
+            }
         });
-    });
 
-    Ok(())
-}
+        let offsets = run_lengths(
+            entries
+                .iter()
+                .map(|e| e.time.pts as i64 - decode_time(&e.time) as i64),
+        );
+        if offsets.iter().any(|&(_, offset)| offset != 0) {
+            // version 1 allows negative composition time offsets
+            write_box!(buf, b"ctts", {
+                buf.put_u32(1 << 24); // version
+                buf.put_u32(offsets.len() as u32); // entry_count
+                for (count, offset) in offsets {
+                    buf.put_u32(count); // sample_count
+                    buf.put_i32(offset as i32); // sample_offset
+                }
+            });
+        }
 
-fn write_audio_stbl(buf: &mut BytesMut, info: &AudioInfo) -> anyhow::Result<()> {
-    write_box!(buf, b"stbl", {
-        write_box!(buf, b"stsd", {
-            buf.put_u32(0); // version
-            buf.put_u32(1); // entry_count
+        // every sample is a sync sample when there is no stss
+        if entries.iter().any(|e| !e.is_sync) {
+            write_box!(buf, b"stss", {
+                buf.put_u32(0); // version
+                buf.put_u32(entries.iter().filter(|e| e.is_sync).count() as u32); // entry_count
+                for (number, _) in (1..).zip(entries).filter(|(_, e)| e.is_sync) {
+                    buf.put_u32(number); // sample_number
+                }
+            });
+        }
 
-            write_audio_sample_description(buf, info)?;
-        });
-        write_box!(buf, b"stss", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stts", {
-            buf.put_u32(0);
-            buf.put_u32(0); // len
-        });
+        // samples written right after each other form a chunk
+        let mut chunks: Vec<(u64, u32)> = Vec::new();
+        let mut chunk_end = None;
+        for entry in entries {
+            match chunks.last_mut() {
+                Some((_, count)) if chunk_end == Some(entry.offset) => *count += 1,
+                _ => chunks.push((entry.offset, 1)),
+            }
+            chunk_end = Some(entry.offset + entry.size);
+        }
+
+        let mut sample_counts = Vec::new();
+        for (first_chunk, &(_, count)) in (1u32..).zip(&chunks) {
+            if sample_counts.last().map(|&(_, last)| last) != Some(count) {
+                sample_counts.push((first_chunk, count));
+            }
+        }
         write_box!(buf, b"stsc", {
             buf.put_u32(0); // version
-            buf.put_u32(0); // len
+            buf.put_u32(sample_counts.len() as u32); // entry_count
+            for (first_chunk, count) in sample_counts {
+                buf.put_u32(first_chunk); // first_chunk
+                buf.put_u32(count); // samples_per_chunk
+                buf.put_u32(1); // sample_description_index
+            }
         });
+
         write_box!(buf, b"stsz", {
             buf.put_u32(0); // version
-            buf.put_u32(0); // sample_size
-            buf.put_u32(0); // len
-        });
-        write_box!(buf, b"stco", {
-            buf.put_u32(0); // version
-            buf.put_u32(0); // len
+            match &run_lengths(entries.iter().map(|e| e.size))[..] {
+                &[(count, size)] => {
+                    buf.put_u32(size as u32); // sample_size
+                    buf.put_u32(count); // sample_count
+                }
+                _ => {
+                    buf.put_u32(0); // sample_size
+                    buf.put_u32(entries.len() as u32); // sample_count
+                    for entry in entries {
+                        buf.put_u32(entry.size as u32); // entry_size
+                    }
+                }
+            }
         });
+
+        // 64 bit offsets are only needed for files larger than 4 GiB
+        if chunks.iter().all(|&(offset, _)| offset <= u32::MAX as u64) {
+            write_box!(buf, b"stco", {
+                buf.put_u32(0); // version
+                buf.put_u32(chunks.len() as u32); // entry_count
+                for (offset, _) in chunks {
+                    buf.put_u32(offset as u32); // chunk_offset
+                }
+            });
+        } else {
+            write_box!(buf, b"co64", {
+                buf.put_u32(0); // version
+                buf.put_u32(chunks.len() as u32); // entry_count
+                for (offset, _) in chunks {
+                    buf.put_u64(offset); // chunk_offset
+                }
+            });
+        }
     });
 
     Ok(())
 }
 
-fn write_tkhd(buf: &mut BytesMut, track_id: u32, width: u32, height: u32) {
+fn write_tkhd(
+    buf: &mut SpanBuilder,
+    track_id: u32,
+    duration: u64,
+    info: &MediaInfo,
+) -> anyhow::Result<()> {
+    let (width, height) = match info.video() {
+        Some(video) => (
+            u32::from(u16::try_from(video.width)?) << 16,
+            u32::from(u16::try_from(video.height)?) << 16,
+        ),
+        None => (0, 0),
+    };
+    let volume = if info.audio().is_some() { 0x0100 } else { 0 };
+
     write_box!(buf, b"tkhd", {
         buf.put_u32((1 << 24) | 7); // version, flags
         buf.put_u64(0); // creation_time
         buf.put_u64(0); // modification_time
         buf.put_u32(track_id); // track_id
         buf.put_u32(0); // reserved
-        buf.put_u64(0); // duration
+        buf.put_u64(duration); // duration
         buf.put_u64(0); // reserved
         buf.put_u16(0); // layer
         buf.put_u16(0); // alternate_group
-        buf.put_u16(0); // volume
+        buf.put_u16(volume); // volume
         buf.put_u16(0); // reserved
         for v in &[0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000] {
             buf.put_u32(*v); // matrix
@@ -376,34 +538,88 @@ fn write_tkhd(buf: &mut BytesMut, track_id: u32, width: u32, height: u32) {
         buf.put_u32(width);
         buf.put_u32(height);
     });
+
+    Ok(())
 }
 
-fn write_mdhd(buf: &mut BytesMut, timebase: u32) {
+fn write_edts(buf: &mut SpanBuilder, edits: &[Edit]) {
+    if edits.is_empty() {
+        return;
+    }
+
+    write_box!(buf, b"edts", {
+        write_box!(buf, b"elst", {
+            buf.put_u32(1 << 24); // version
+            buf.put_u32(edits.len() as u32); // entry_count
+            for edit in edits {
+                buf.put_u64(edit.segment_duration); // segment_duration
+                buf.put_i64(edit.media_time); // media_time
+                buf.put_u16(1); // media_rate_integer
+                buf.put_u16(0); // media_rate_fraction
+            }
+        });
+    });
+}
+
+fn write_mdhd(buf: &mut SpanBuilder, timebase: u32, duration: u64, language: Option<&str>) {
+    // ISO 639-2 codes are packed as three 5 bit letters, other languages are stored in `elng`
+    let packed = language
+        .map(str::as_bytes)
+        .filter(|l| l.len() == 3 && l.iter().all(u8::is_ascii_lowercase))
+        .map(|l| l.iter().fold(0u16, |packed, c| (packed << 5) | (c - 0x60) as u16))
+        .unwrap_or(0x55c4); // und
+
     write_box!(buf, b"mdhd", {
         buf.put_u32(1 << 24); // version
         buf.put_u64(0); // creation_time
         buf.put_u64(0); // modification_time
         buf.put_u32(timebase); // timebase
-        buf.put_u64(0);
-        buf.put_u32(0x55c40000); // language=und + pre-defined
+        buf.put_u64(duration); // duration
+        buf.put_u16(packed); // language
+        buf.put_u16(0); // pre_defined
+    });
+
+    if let Some(language) = language {
+        write_box!(buf, b"elng", {
+            buf.put_u32(0); // version
+            buf.put_slice(language.as_bytes());
+            buf.put_u8(0);
+        });
+    }
+}
+
+/// Writes the track title as a QuickTime `name` box.
+fn write_udta(buf: &mut SpanBuilder, title: Option<&str>) {
+    let Some(title) = title else {
+        return;
+    };
+
+    write_box!(buf, b"udta", {
+        write_box!(buf, b"name", {
+            buf.put_slice(title.as_bytes());
+        });
     });
 }
 
-fn write_hdlr(buf: &mut BytesMut) {
+fn write_hdlr(buf: &mut SpanBuilder, kind: &MediaKind) -> anyhow::Result<()> {
+    let handler = match kind {
+        MediaKind::Video(_) => b"vide",
+        MediaKind::Audio(_) => b"soun",
+        MediaKind::Subtitle(_) => anyhow::bail!("Subtitles can't be stored in MP4"),
+    };
+
     write_box!(buf, b"hdlr", {
-        buf.extend_from_slice(&[
-            0x00, 0x00, 0x00, 0x00, // version + flags
-            0x00, 0x00, 0x00, 0x00, // pre_defined
-            b's', b'o', b'u', b'n', // handler = vide
-            0x00, 0x00, 0x00, 0x00, // reserved[0]
-            0x00, 0x00, 0x00, 0x00, // reserved[1]
-            0x00, 0x00, 0x00, 0x00, // reserved[2]
-            0x00, // name, zero-terminated (empty)
-        ]);
+        buf.put_u32(0); // version, flags
+        buf.put_u32(0); // pre_defined
+        buf.put_slice(handler); // handler_type
+        buf.put_slice(&[0; 12]); // reserved
+        buf.put_u8(0); // name, zero-terminated (empty)
     });
+
+    Ok(())
 }
 
-fn write_dinf(buf: &mut BytesMut) {
+fn write_dinf(buf: &mut SpanBuilder) {
     write_box!(buf, b"dinf", {
         write_box!(buf, b"dref", {
             buf.put_u32(0);
@@ -415,7 +631,7 @@ fn write_dinf(buf: &mut BytesMut) {
     });
 }
 
-fn write_audio_sample_description(buf: &mut BytesMut, info: &AudioInfo) -> anyhow::Result<()> {
+fn write_audio_sample_description(buf: &mut SpanBuilder, info: &AudioInfo) -> anyhow::Result<()> {
     match &info.codec {
         AudioCodec::Aac(params) => {
             write_box!(buf, b"mp4a", {
@@ -434,49 +650,70 @@ fn write_audio_sample_description(buf: &mut BytesMut, info: &AudioInfo) -> anyho
                 });
             });
         }
+        AudioCodec::Opus(OpusCodec { header }) => {
+            let head = OpusHead::parse(header)
+                .ok_or_else(|| anyhow::anyhow!("Invalid Opus identification header"))?;
+
+            write_box!(buf, b"Opus", {
+                // the sample rate is always 48 kHz, the input sample rate is only kept in dOps
+                write_audio_sample_entry(buf, 1, head.channels as u16, 16, OPUS_SAMPLE_RATE);
+
+                write_box!(buf, b"dOps", {
+                    head.write_dops(buf);
+                });
+            });
+        }
+        AudioCodec::Vorbis(_) => anyhow::bail!("Vorbis can't be stored in MP4"),
+        AudioCodec::Flac(_) => anyhow::bail!("FLAC can't be stored in MP4"),
+        AudioCodec::Pcm(_) => anyhow::bail!("PCM audio can't be stored in MP4"),
     }
 
     Ok(())
 }
 
-fn write_video_sample_entry(buf: &mut BytesMut, info: &VideoInfo) -> anyhow::Result<()> {
+fn write_video_sample_entry(buf: &mut SpanBuilder, info: &VideoInfo) -> anyhow::Result<()> {
     match &info.codec {
         VideoCodec::H264(params) => {
             write_box!(buf, b"avc1", {
                 write_visual_sample_entry(buf, 1, info.width as u16, info.height as u16);
 
                 write_box!(buf, b"avcC", {
-                    buf.extend_from_slice(&[
-                        1,
-                        params.profile_indication,
-                        params.profile_compatibility,
-                        params.level_indication,
-                        0b0000_0000 | 3, // length_minus_one, 1 + 1 == 2
-                        0b0000_0000 | 1, // sps_count
-                    ]);
-
-                    let sps =
-                        frame_nal_units(&[params.sps.clone()], BitstreamFraming::TwoByteLength);
-                    for span in sps.spans() {
-                        buf.extend_from_slice(span);
-                    }
+                    buf.put_span(AvcDecoderConfig::from(params).to_span());
+                });
+            });
+        }
+        VideoCodec::Av1(Av1Codec { config }) => {
+            write_box!(buf, b"av01", {
+                write_visual_sample_entry(buf, 1, info.width as u16, info.height as u16);
 
-                    buf.put_u8(1); // pps_count
-                    let pps =
-                        frame_nal_units(&[params.pps.clone()], BitstreamFraming::TwoByteLength);
-                    for span in pps.spans() {
-                        buf.extend_from_slice(span);
-                    }
+                write_box!(buf, b"av1C", {
+                    buf.put_span(config.clone());
                 });
             });
         }
+        VideoCodec::Vp8(vpx) | VideoCodec::Vp9(vpx) => {
+            let fourcc = if let VideoCodec::Vp8(_) = info.codec {
+                b"vp08"
+            } else {
+                b"vp09"
+            };
+
+            write_box!(buf, fourcc, {
+                write_visual_sample_entry(buf, 1, info.width as u16, info.height as u16);
+
+                write_box!(buf, b"vpcC", {
+                    vpx.write_vpcc(buf);
+                });
+            });
+        }
+        VideoCodec::Raw(_) => anyhow::bail!("Raw video can't be stored in MP4"),
     }
 
     Ok(())
 }
 
 fn write_audio_sample_entry(
-    buf: &mut BytesMut,
+    buf: &mut SpanBuilder,
     data_reference_index: u16,
     channel_count: u16,
     sample_size: u16,
@@ -484,7 +721,7 @@ fn write_audio_sample_entry(
 ) {
     write_sample_entry(buf, data_reference_index);
 
-    buf.extend_from_slice(&[0u8; 8]);
+    buf.put_slice(&[0u8; 8]);
     buf.put_u16(channel_count);
     buf.put_u16(sample_size);
     buf.put_u32(0);
@@ -492,17 +729,17 @@ fn write_audio_sample_entry(
 }
 
 fn write_visual_sample_entry(
-    buf: &mut BytesMut,
+    buf: &mut SpanBuilder,
     data_reference_index: u16,
     width: u16,
     height: u16,
 ) {
     write_sample_entry(buf, data_reference_index);
 
-    buf.extend_from_slice(&[0u8; 16]);
+    buf.put_slice(&[0u8; 16]);
     buf.put_u16(width);
     buf.put_u16(height);
-    buf.extend_from_slice(&[
+    buf.put_slice(&[
         0x00, 0x48, 0x00, 0x00, // horizresolution
         0x00, 0x48, 0x00, 0x00, // vertresolution
         0x00, 0x00, 0x00, 0x00, // reserved
@@ -519,8 +756,8 @@ fn write_visual_sample_entry(
     ]);
 }
 
-fn write_sample_entry(buf: &mut BytesMut, data_reference_index: u16) {
-    buf.extend_from_slice(&[0u8; 6]);
+fn write_sample_entry(buf: &mut SpanBuilder, data_reference_index: u16) {
+    buf.put_slice(&[0u8; 6]);
     buf.put_u16(data_reference_index);
 }
 
@@ -530,7 +767,7 @@ const DECODER_SPECIFIC_DESCR_TAG: u8 = 0x5;
 const SL_CONFIG_DESCR_TAG: u8 = 0x6;
 
 fn write_es_descriptor(
-    buf: &mut BytesMut,
+    buf: &mut SpanBuilder,
     es_id: u16,
     object_type_indication: u8,
     decoder_specific: Option<&[u8]>,
@@ -542,11 +779,11 @@ fn write_es_descriptor(
         write_base_descriptor!(buf, DECODER_CONFIG_DESCR_TAG, |buf| {
             buf.put_u8(object_type_indication);
             buf.put_u8((0x05 << 2) | 1); // streamtype + upstream + reserved
-            buf.extend_from_slice(&[0u8; 11]);
+            buf.put_slice(&[0u8; 11]);
 
             if let Some(specific) = decoder_specific {
                 write_base_descriptor!(buf, DECODER_SPECIFIC_DESCR_TAG, |buf| {
-                    buf.extend_from_slice(specific);
+                    buf.put_slice(specific);
                 });
             }
         });
@@ -558,7 +795,7 @@ fn write_es_descriptor(
     });
 }
 
-fn write_base_descriptor_header(buf: &mut BytesMut, tag: u8, size: u32) {
+fn write_base_descriptor_header(buf: &mut SpanBuilder, tag: u8, size: u32) {
     buf.put_u8(tag);
 
     let size = 1 + size - size_of_length(size);
@@ -583,3 +820,65 @@ fn size_of_length(size: u32) -> u32 {
         _ => 4,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AacCodec, MediaInfo, SoundType};
+    use std::sync::Arc;
+    use test_case::test_case;
+
+    #[test_case(0, 0, &[(0, 0), (3600, 3600)], vec![] ; "starts at zero")]
+    #[test_case(0, 40, &[(3600, 0), (14400, 3600), (7200, 7200), (10800, 10800)], vec![(160, 3600)] ; "b-frames")]
+    #[test_case(0, 0, &[(90_000, 90_000), (93_600, 93_600)], vec![(1000, -1), (80, 0)] ; "starts later")]
+    #[test_case(1800, 0, &[(0, 0), (3600, 3600)], vec![(60, 1800)] ; "encoder delay")]
+    fn edit_list(
+        encoder_delay: u64,
+        movie_start_ms: u64,
+        times: &[(u64, u64)],
+        edits: Vec<(u64, i64)>,
+    ) {
+        let track = Track {
+            id: 1,
+            info: Arc::new(MediaInfo {
+                name: "aac",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 48_000,
+                    sample_bpp: 16,
+                    sound_type: SoundType::Stereo,
+                    codec: AudioCodec::Aac(AacCodec {
+                        extra: vec![0x11, 0x90],
+                    }),
+                }),
+            }),
+            timebase: Fraction::new(1, 90_000),
+            delay: encoder_delay,
+            metadata: Default::default(),
+        };
+
+        let mut builder = TrackBuilder::new(track.clone(), 1);
+        builder.movie_start = Duration::from_millis(movie_start_ms);
+        for &(pts, dts) in times {
+            builder.add_sample(SampleEntry {
+                is_sync: true,
+                offset: 0,
+                size: 0,
+                time: MediaTime {
+                    pts,
+                    dts: Some(dts),
+                    duration: Some(3600),
+                    timebase: track.timebase,
+                },
+            });
+        }
+
+        assert_eq!(
+            edits,
+            builder
+                .edit_list()
+                .into_iter()
+                .map(|edit| (edit.segment_duration, edit.media_time))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,20 +1,30 @@
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
+use bytes::BufMut;
 use log::*;
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use crate::{
-    codec::nal::{convert_bitstream, BitstreamFraming},
-    format::Muxer,
+    cenc::{Pssh, TrackEncryption},
+    codec::aac::AAC_FRAME_SAMPLES,
+    format::{DemuxerEvent, Muxer},
     io::Io,
-    muxer, H264Codec, MediaDuration, MediaKind, MediaTime, Packet, Span, Track, VideoCodec,
-    VideoInfo,
+    media::convert_timebase,
+    muxer,
+    time::to_duration,
+    AudioCodec, Fraction, MediaDuration, MediaTime, Packet, SideData, Span, SpanBuilder, Track,
 };
 
-use super::{write_audio_trak, write_video_trak, TrackBuilder};
+use super::{write_trak, TrackBuilder};
 
-muxer!("fmp4", FragmentedMp4Muxer::create);
+muxer!(
+    "fmp4",
+    FragmentedMp4Muxer::create,
+    ["h264", "av1", "vp8", "vp9", "aac", "opus"]
+);
 
 pub struct FragmentedMp4Muxer {
     video: Option<Track>,
@@ -22,22 +32,26 @@ pub struct FragmentedMp4Muxer {
     start_times: HashMap<u32, MediaTime>,
     prev_times: HashMap<u32, MediaTime>,
     track_mapping: HashMap<u32, u32>,
+    /// Decode time where each track continues after a discontinuity.
+    decode_offsets: HashMap<u32, u64>,
+    /// Decode time after the last sample written of each track.
+    end_times: HashMap<u32, u64>,
+    /// The last packet of each track which had no duration, held back by
+    /// [FragmentedMp4Muxer::push_packet] until the next packet of the track tells how long it
+    /// lasts.
+    pending: HashMap<u32, Packet>,
+    /// Tracks which had a sample duration guessed, to only warn once.
+    guessed: HashSet<u32>,
+    /// Encryption of the tracks whose samples carry [SideData::Encryption].
+    encryption: HashMap<u32, TrackEncryption>,
+    pssh: Vec<Pssh>,
     io: Io,
     seq: u64,
 }
 
 impl FragmentedMp4Muxer {
     pub fn with_streams(streams: &[Track]) -> Self {
-        let mut muxer = FragmentedMp4Muxer {
-            video: None,
-            audio: None,
-            start_times: HashMap::new(),
-            prev_times: HashMap::new(),
-            track_mapping: HashMap::new(),
-            io: Io::null(),
-            seq: 0,
-        };
-
+        let mut muxer = Self::new(Io::null());
         muxer.assign_streams(streams);
 
         muxer
@@ -50,20 +64,40 @@ impl FragmentedMp4Muxer {
             start_times: HashMap::new(),
             prev_times: HashMap::new(),
             track_mapping: HashMap::new(),
+            decode_offsets: HashMap::new(),
+            end_times: HashMap::new(),
+            pending: HashMap::new(),
+            guessed: HashSet::new(),
+            encryption: HashMap::new(),
+            pssh: Vec::new(),
             io,
             seq: 0,
         }
     }
 
+    /// Marks the samples of a track as encrypted, e.g. with the parameters of a
+    /// [`CencEncryptor`](crate::cenc::CencEncryptor). Every packet of the track then has to carry
+    /// [SideData::Encryption].
+    pub fn with_encryption(mut self, track_id: u32, encryption: TrackEncryption) -> Self {
+        self.encryption.insert(track_id, encryption);
+        self
+    }
+
+    /// Adds a `pssh` box to the initialization segment.
+    pub fn with_pssh(mut self, pssh: Pssh) -> Self {
+        self.pssh.push(pssh);
+        self
+    }
+
     fn create(io: Io) -> Box<dyn Muxer> {
         Box::new(Self::new(io))
     }
 
     pub fn initialization_segment(&self) -> anyhow::Result<Span> {
-        let mut buf = BytesMut::new();
+        let mut buf = SpanBuilder::new();
 
         write_box!(&mut buf, b"ftyp", {
-            buf.extend_from_slice(b"isom\0\0\0\0isomiso5dash");
+            buf.put_slice(b"isom\0\0\0\0isomiso5dash");
         });
 
         write_box!(&mut buf, b"moov", {
@@ -83,7 +117,7 @@ impl FragmentedMp4Muxer {
                 for _ in 0..6 {
                     buf.put_u32(0); // pre_defined
                 }
-                buf.put_u32(2); // next_track_id
+                buf.put_u32(self.track_mapping.len() as u32 + 1); // next_track_id
             });
             write_box!(&mut buf, b"mvex", {
                 write_box!(&mut buf, b"mehd", {
@@ -112,105 +146,70 @@ impl FragmentedMp4Muxer {
                 }
             });
 
-            if let Some(video) = &self.video {
-                let builder = TrackBuilder::new(video.clone(), self.track_mapping[&video.id]);
-                write_video_trak(&mut buf, builder)?;
+            for track in [&self.video, &self.audio].into_iter().flatten() {
+                let mut builder = TrackBuilder::new(track.clone(), self.track_mapping[&track.id]);
+                builder.encryption = self.encryption.get(&track.id).cloned();
+                write_trak(&mut buf, &builder)?;
             }
-            if let Some(audio) = &self.audio {
-                let builder = TrackBuilder::new(audio.clone(), self.track_mapping[&audio.id]);
-                write_audio_trak(&mut buf, builder)?;
+
+            for pssh in &self.pssh {
+                write_pssh(&mut buf, pssh);
             }
         });
 
-        Ok(buf.freeze().into())
+        Ok(buf.build())
     }
 
     pub fn write_media_segment(&mut self, packet: Packet) -> anyhow::Result<Span> {
-        let prev_time = self
-            .prev_times
-            .entry(packet.track.id)
-            .or_insert_with(|| packet.time.clone());
-        let start_time = self
-            .start_times
-            .entry(packet.track.id)
-            .or_insert_with(|| packet.time.clone());
-
-        let media_duration = packet.time.clone() - prev_time.clone();
-        let base_offset = prev_time.clone() - start_time.clone();
-
-        let track_id = self.track_mapping[&packet.track.id];
+        self.write_many_media_segments(&[packet])
+    }
 
-        let duration = if media_duration.duration == 0 {
-            packet.guess_duration().unwrap_or_else(|| {
-                MediaDuration::from_duration(Duration::from_millis(16), packet.track.timebase)
-            })
-        } else {
-            media_duration
+    /// Writes a fragment for the packet of the same track held back by
+    /// [FragmentedMp4Muxer::push_packet], which lasts until `next`.
+    pub fn take_pending(&mut self, next: &Packet) -> anyhow::Result<Option<Span>> {
+        let Some(mut pending) = self.pending.remove(&next.track.id) else {
+            return Ok(None);
         };
 
-        // let duration = duration.in_base(Fraction::new(1, 90_000));
-
-        let duration = duration.duration;
-
-        let mut buf = BytesMut::new();
-        let data_offset_pos;
-
-        write_box!(&mut buf, b"moof", {
-            write_box!(&mut buf, b"mfhd", {
-                buf.put_u32(0 << 24); // version
-                buf.put_u64(self.seq); // creation_time
-            });
+        let gap = decode_time(&next.time).saturating_sub(decode_time(&pending.time));
+        if gap > 0 {
+            pending.time.duration = Some(gap);
+        }
 
-            write_box!(&mut buf, b"traf", {
-                write_box!(&mut buf, b"tfhd", {
-                    let flags = 0x0200_00; // base_is_moof
-                    buf.put_u32(flags); // version, flags
-                    buf.put_u32(track_id); // track_id
-                });
-                write_box!(&mut buf, b"trun", {
-                    let flags = 0x0000_01 | // offset_present
-                        0x0000_04 | // first_flags_present
-                        0x0001_00 | // duration_present
-                        0x0002_00; // size_present
-                    buf.put_u32(flags); // version, flags
-                    buf.put_u32(1); // sample_len
-
-                    data_offset_pos = buf.len();
-                    buf.put_u32(0); // data_offset
-                    buf.put_u32(if packet.key { 0x10000 } else { 0 }); // first_sample_flags
-                    buf.put_u32(duration as u32);
-                    buf.put_u32(packet.buffer.len() as _);
-                });
-                write_box!(&mut buf, b"tfdt", {
-                    buf.put_u32(1 << 24); // version
-                    buf.put_u64(base_offset.duration as u64); // decode_time
-                });
-            });
-        });
+        self.write_media_segment(pending).map(Some)
+    }
 
-        let len = (buf.len() as u32 + 8).to_be_bytes();
-        buf[data_offset_pos..(data_offset_pos + 4)].copy_from_slice(&len);
+    /// Writes the fragments which are ready once `packet` has been seen. A packet without a
+    /// duration is held back until the next packet of its track tells how long it lasts.
+    pub fn push_packet(&mut self, packet: Packet) -> anyhow::Result<Vec<Span>> {
+        let mut media_segments = Vec::new();
+        media_segments.extend(self.take_pending(&packet)?);
 
-        let moof = buf.freeze();
+        match packet.time.duration {
+            Some(duration) if duration > 0 => {
+                media_segments.push(self.write_media_segment(packet)?);
+            }
+            _ => {
+                self.pending.insert(packet.track.id, packet);
+            }
+        }
 
-        let mut mdat_header = BytesMut::new();
-        mdat_header.put_u32(packet.buffer.len() as u32 + 8);
-        mdat_header.extend_from_slice(b"mdat");
-        let mdat_header = mdat_header.freeze();
+        Ok(media_segments)
+    }
 
-        let sample_data = super::get_packet_sample_data(&packet);
+    /// Writes fragments for all held back packets, whose durations can only be guessed.
+    pub fn flush_pending(&mut self) -> anyhow::Result<Vec<Span>> {
+        let mut pending = self.pending.drain().map(|(_, pkt)| pkt).collect::<Vec<_>>();
+        pending.sort_by_key(|pkt| to_duration(decode_time(&pkt.time), pkt.time.timebase));
 
-        let segment = [moof.into(), mdat_header.into(), sample_data]
+        pending
             .into_iter()
-            .collect::<Span>();
-
-        self.seq += 1;
-        self.prev_times.insert(packet.track.id, packet.time);
-
-        Ok(segment)
+            .map(|packet| self.write_media_segment(packet))
+            .collect()
     }
 
-    fn get_packet_time(&mut self, packet: &Packet) -> (MediaDuration, MediaDuration) {
+    /// Finds the decode time, duration and composition offset of the next sample of a track.
+    fn sample_timing(&mut self, packet: &Packet) -> SampleTiming {
         let prev_time = self
             .prev_times
             .entry(packet.track.id)
@@ -220,99 +219,145 @@ impl FragmentedMp4Muxer {
             .entry(packet.track.id)
             .or_insert_with(|| packet.time.clone());
 
-        let media_duration = packet.time.clone() - prev_time.clone();
-        let base_offset = prev_time.clone() - start_time.clone();
+        // samples are laid out by decode time, B-frames are shown later than they are decoded
+        let base_decode_time = decode_time(&packet.time).saturating_sub(decode_time(start_time))
+            + self.decode_offsets.get(&packet.track.id).copied().unwrap_or(0);
+        let composition_offset = packet.time.pts as i64 - decode_time(&packet.time) as i64;
+        let gap = decode_time(&packet.time) as i64 - decode_time(prev_time) as i64;
+
+        // packets held back by push_packet have their duration set from the next packet, the
+        // others are timed by their codec or assumed to last as long as the previous one
+        let duration = packet
+            .time
+            .duration
+            .filter(|&duration| duration > 0)
+            .or_else(|| frame_duration(packet))
+            .or_else(|| (gap > 0).then_some(gap as u64))
+            .or_else(|| {
+                packet
+                    .guess_duration()
+                    .map(|duration| duration.duration as u64)
+                    .filter(|&duration| duration > 0)
+            })
+            .unwrap_or_else(|| {
+                if self.guessed.insert(packet.track.id) {
+                    warn!(
+                        "Guessing the sample duration of track {}, which may cause drift",
+                        packet.track.id
+                    );
+                }
 
-        let duration = if media_duration.duration == 0 {
-            packet.guess_duration().unwrap_or_else(|| {
                 MediaDuration::from_duration(Duration::from_millis(16), packet.track.timebase)
-            })
-        } else {
-            media_duration
-        };
+                    .duration as u64
+            });
 
+        self.end_times.insert(packet.track.id, base_decode_time + duration);
         self.prev_times.insert(packet.track.id, packet.time.clone());
 
-        (base_offset, duration)
+        SampleTiming {
+            decode_time: base_decode_time,
+            duration,
+            composition_offset,
+        }
+    }
+
+    /// Whether packets of the track are written, as only the first video and audio tracks are.
+    pub fn has_track(&self, id: u32) -> bool {
+        self.track_mapping.contains_key(&id)
     }
 
+    /// Writes a fragment holding all packets, with a `traf` for each of their tracks.
     pub fn write_many_media_segments(&mut self, packets: &[Packet]) -> anyhow::Result<Span> {
-        // TODO: audio?
-        let track_id = self.track_mapping[&packets[0].track.id];
+        let mut tracks: Vec<(u32, Vec<&Packet>)> = Vec::new();
+        for packet in packets {
+            if !self.track_mapping.contains_key(&packet.track.id) {
+                anyhow::bail!("Track {} was not given to the muxer", packet.track.id);
+            }
 
-        let mut buf = BytesMut::new();
-        let data_offset_pos;
+            match tracks.iter_mut().find(|(id, _)| *id == packet.track.id) {
+                Some((_, samples)) => samples.push(packet),
+                None => tracks.push((packet.track.id, vec![packet])),
+            }
+        }
+
+        // the samples of each track are stored next to each other in the mdat
+        let track_data = tracks
+            .iter()
+            .map(|(_, samples)| {
+                samples
+                    .iter()
+                    .map(|packet| super::get_packet_sample_data(packet))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut buf = SpanBuilder::new();
+        let mut data_offsets = Vec::new();
 
         write_box!(&mut buf, b"moof", {
             write_box!(&mut buf, b"mfhd", {
                 buf.put_u32(0 << 24); // version
-                buf.put_u32(self.seq as u32); // sequence_id
+                buf.put_u32(self.seq as u32); // sequence_number
             });
 
-            write_box!(&mut buf, b"traf", {
-                write_box!(&mut buf, b"tfhd", {
-                    let flags = 0x0200_00; // base_is_moof
-                    buf.put_u32(flags); // version, flags
-                    buf.put_u32(track_id); // track_id
-                });
-                write_box!(&mut buf, b"trun", {
-                    let flags = 0x0000_01 | // offset_present
-                        0x0001_00 | // duration_present
-                        0x0002_00 | // size_present
-                        0x0004_00; // sample_flags_prsent
-                    buf.put_u32(flags); // version, flags
-                    buf.put_u32(packets.len() as u32); // sample_len
-
-                    data_offset_pos = buf.len();
-                    buf.put_u32(0); // data_offset
-                    for pkt in packets {
-                        let (_base_offset, duration) = self.get_packet_time(&pkt);
-                        let track_id = self.track_mapping[&pkt.track.id];
-                        let duration = duration.duration;
-
-                        buf.put_u32(duration as u32);
-                        buf.put_u32(pkt.buffer.len() as _);
-                        buf.put_u32(if pkt.key { 0x10000 } else { 0 }); // first_sample_flags
+            for ((id, samples), data) in tracks.iter().zip(&track_data) {
+                let timings = samples
+                    .iter()
+                    .map(|packet| self.sample_timing(packet))
+                    .collect::<Vec<_>>();
+
+                write_box!(&mut buf, b"traf", {
+                    write_box!(&mut buf, b"tfhd", {
+                        let flags = 0x0200_00; // base_is_moof
+                        buf.put_u32(flags); // version, flags
+                        buf.put_u32(self.track_mapping[id]); // track_id
+                    });
+                    write_box!(&mut buf, b"tfdt", {
+                        buf.put_u32(1 << 24); // version
+                        buf.put_u64(timings[0].decode_time); // decode_time
+                    });
+                    write_box!(&mut buf, b"trun", {
+                        let flags = 0x0000_01 | // offset_present
+                            0x0001_00 | // duration_present
+                            0x0002_00 | // size_present
+                            0x0004_00 | // flags_present
+                            0x0008_00; // composition_time_offset_present
+                        // version 1 allows negative composition time offsets
+                        buf.put_u32((1 << 24) | flags); // version, flags
+                        buf.put_u32(samples.len() as u32); // sample_len
+
+                        data_offsets.push(buf.reserve(4));
+                        for ((packet, timing), data) in samples.iter().zip(&timings).zip(data) {
+                            buf.put_u32(timing.duration as u32);
+                            buf.put_u32(data.len() as u32);
+                            buf.put_u32(if packet.key { 0 } else { 0x10000 }); // sample_is_non_sync_sample
+                            buf.put_i32(timing.composition_offset as i32);
+                        }
+                    });
+
+                    if let Some(encryption) = self.encryption.get(id) {
+                        write_sample_encryption(&mut buf, encryption, samples)?;
                     }
                 });
-                write_box!(&mut buf, b"tfdt", {
-                    buf.put_u32(1 << 24); // version
-                    buf.put_u64(0); // decode_time
-                });
-            });
+            }
         });
 
-        let len = (buf.len() as u32 + 8).to_be_bytes();
-        buf[data_offset_pos..(data_offset_pos + 4)].copy_from_slice(&len);
-
-        let moof = buf.freeze();
-
-        let mut mdat_header = BytesMut::new();
-        mdat_header.put_u32(packets.iter().map(|p| p.buffer.len()).sum::<usize>() as u32 + 8);
-        mdat_header.extend_from_slice(b"mdat");
-        let mdat_header = mdat_header.freeze();
-
-        let sample_data = packets.iter().map(|packet| match packet.track.info.kind {
-            MediaKind::Video(VideoInfo {
-                codec:
-                    VideoCodec::H264(H264Codec {
-                        bitstream_format, ..
-                    }),
-                ..
-            }) => convert_bitstream(
-                packet.buffer.clone(),
-                bitstream_format,
-                BitstreamFraming::FourByteLength,
-            ),
-            _ => packet.buffer.clone(),
-        });
+        // offsets are relative to the start of the moof, the samples start after the mdat header
+        let mut offset = buf.len() as u32 + 8;
+        for (reserved, data) in data_offsets.into_iter().zip(&track_data) {
+            buf.patch(reserved, &offset.to_be_bytes());
+            offset += data.iter().map(|data| data.len() as u32).sum::<u32>();
+        }
 
-        let segment = [moof.into(), mdat_header.into()]
-            .into_iter()
-            .chain(sample_data)
-            .collect::<Span>();
+        buf.put_u32(offset - buf.len() as u32);
+        buf.put_slice(b"mdat");
+        for data in track_data.into_iter().flatten() {
+            buf.put_span(data);
+        }
 
-        Ok(segment)
+        self.seq += 1;
+
+        Ok(buf.build())
     }
 
     fn assign_streams(&mut self, streams: &[Track]) {
@@ -334,11 +379,47 @@ impl FragmentedMp4Muxer {
 
         debug!("Track mappings: {:?}", self.track_mapping);
     }
+
+    /// Applies a change of the input, returning whether a new initialization segment has to be
+    /// written before the following media segments.
+    pub fn apply_event(&mut self, event: &DemuxerEvent) -> bool {
+        match event {
+            DemuxerEvent::NewMovie(movie) => {
+                // tracks which keep their id continue where they left off
+                self.video = None;
+                self.audio = None;
+                self.track_mapping.clear();
+                self.restart_timeline();
+                self.assign_streams(&movie.tracks);
+            }
+            DemuxerEvent::Discontinuity => {
+                self.restart_timeline();
+                return false;
+            }
+            DemuxerEvent::TrackParametersChanged(track) => {
+                for current in [&mut self.video, &mut self.audio].into_iter().flatten() {
+                    if current.id == track.id {
+                        *current = track.clone();
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Makes the following samples continue from the end of the previous ones, regardless of
+    /// their timestamps.
+    fn restart_timeline(&mut self) {
+        self.decode_offsets = self.end_times.clone();
+        self.start_times.clear();
+        self.prev_times.clear();
+    }
 }
 
 #[async_trait]
 impl Muxer for FragmentedMp4Muxer {
-    async fn start(&mut self, streams: Vec<Track>) -> anyhow::Result<()> {
+    async fn start(&mut self, streams: Vec<Track>) -> crate::Result<()> {
         self.assign_streams(&streams);
         let init_segment = self.initialization_segment()?;
 
@@ -347,23 +428,410 @@ impl Muxer for FragmentedMp4Muxer {
         Ok(())
     }
 
-    async fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
-        if !self.track_mapping.contains_key(&packet.track.id) {
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        if !self.has_track(packet.track.id) {
             return Ok(());
         }
 
-        let media_segment = self.write_media_segment(packet)?;
+        for media_segment in self.push_packet(packet)? {
+            self.io.write_span(media_segment).await?;
+        }
 
-        self.io.write_span(media_segment).await?;
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        // held back packets are timed against the next packet, which is unrelated after the event
+        for media_segment in self.flush_pending()? {
+            self.io.write_span(media_segment).await?;
+        }
+
+        if self.apply_event(event) {
+            let init_segment = self.initialization_segment()?;
+            self.io.write_span(init_segment).await?;
+        }
 
         Ok(())
     }
 
-    async fn stop(&mut self) -> anyhow::Result<()> {
+    async fn stop(&mut self) -> crate::Result<()> {
+        for media_segment in self.flush_pending()? {
+            self.io.write_span(media_segment).await?;
+        }
+
         Ok(())
     }
-    
+
     fn into_io(self) -> Io {
         self.io
     }
 }
+
+/// Writes the `senc` holding the IV and subsamples of every sample, and the `saiz` and `saio`
+/// pointing to it.
+fn write_sample_encryption(
+    buf: &mut SpanBuilder,
+    encryption: &TrackEncryption,
+    samples: &[&Packet],
+) -> anyhow::Result<()> {
+    let infos = samples
+        .iter()
+        .map(|packet| {
+            packet
+                .side_data
+                .iter()
+                .find_map(|side_data| match side_data {
+                    SideData::Encryption { iv, subsamples, .. } => Some((iv, subsamples)),
+                    _ => None,
+                })
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Sample of encrypted track {} isn't encrypted",
+                        packet.track.id
+                    )
+                })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let iv_size = encryption.per_sample_iv_size as usize;
+    let has_subsamples = infos.iter().any(|(_, subsamples)| !subsamples.is_empty());
+    let info_sizes = infos
+        .iter()
+        .map(|(_, subsamples)| {
+            let subsample_size = if has_subsamples {
+                2 + 6 * subsamples.len()
+            } else {
+                0
+            };
+
+            // saiz stores the size of each sample's info in a byte
+            u8::try_from(iv_size + subsample_size).map_err(|_| {
+                anyhow::anyhow!(
+                    "Encryption info of {} subsamples doesn't fit in a saiz box",
+                    subsamples.len()
+                )
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // the offset of the first sample's info, relative to the moof
+    let info_offset;
+    write_box!(buf, b"senc", {
+        buf.put_u32(if has_subsamples { 0x2 } else { 0 }); // version, flags
+        buf.put_u32(infos.len() as u32); // sample_count
+
+        info_offset = buf.len();
+        for (iv, subsamples) in &infos {
+            if iv.len() != iv_size {
+                anyhow::bail!("Expected an IV of {iv_size} bytes, got {}", iv.len());
+            }
+            buf.put_slice(iv); // InitializationVector
+            if has_subsamples {
+                buf.put_u16(subsamples.len() as u16); // subsample_count
+                for (clear, protected) in subsamples.iter() {
+                    buf.put_u16(*clear); // BytesOfClearData
+                    buf.put_u32(*protected); // BytesOfProtectedData
+                }
+            }
+        }
+    });
+
+    write_box!(buf, b"saiz", {
+        let same_size = info_sizes.windows(2).all(|pair| pair[0] == pair[1]);
+        buf.put_u32(0); // version, flags
+        buf.put_u8(if same_size { info_sizes[0] } else { 0 }); // default_sample_info_size
+        buf.put_u32(info_sizes.len() as u32); // sample_count
+        if !same_size {
+            buf.put_slice(&info_sizes); // sample_info_size
+        }
+    });
+
+    write_box!(buf, b"saio", {
+        buf.put_u32(0); // version, flags
+        buf.put_u32(1); // entry_count
+        buf.put_u32(info_offset as u32); // offset
+    });
+
+    Ok(())
+}
+
+fn write_pssh(buf: &mut SpanBuilder, pssh: &Pssh) {
+    write_box!(buf, b"pssh", {
+        // version 1 lists the key ids
+        let version = u32::from(!pssh.key_ids.is_empty());
+        buf.put_u32(version << 24); // version, flags
+        buf.put_slice(&pssh.system_id); // SystemID
+        if version > 0 {
+            buf.put_u32(pssh.key_ids.len() as u32); // KID_count
+            for key_id in &pssh.key_ids {
+                buf.put_slice(key_id); // KID
+            }
+        }
+        buf.put_u32(pssh.data.len() as u32); // DataSize
+        buf.put_slice(&pssh.data); // Data
+    });
+}
+
+struct SampleTiming {
+    decode_time: u64,
+    duration: u64,
+    composition_offset: i64,
+}
+
+fn decode_time(time: &MediaTime) -> u64 {
+    time.dts.unwrap_or(time.pts)
+}
+
+/// The duration of a sample if its codec has a fixed frame size, like AAC.
+fn frame_duration(packet: &Packet) -> Option<u64> {
+    let audio = packet.track.info.audio()?;
+
+    match audio.codec {
+        AudioCodec::Aac(_) if audio.sample_rate > 0 => Some(convert_timebase(
+            AAC_FRAME_SAMPLES,
+            Fraction::new(1, audio.sample_rate),
+            packet.track.timebase,
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test;
+
+    /// A frame decoded at `dts`, which is a keyframe unless it is reordered.
+    fn packet(track: &Track, pts: u64, dts: u64) -> Packet {
+        let mut packet = test::packet(track, pts, Some(3600), vec![0, 0, 0, 1, 0x65]);
+        packet.time.dts = Some(dts);
+        packet.key = pts == dts;
+
+        packet
+    }
+
+    /// Reads the composition time offset of the single sample and the decode time of a fragment.
+    fn sample_times(segment: &[u8]) -> (i32, u64) {
+        let find = |name: &[u8]| segment.windows(4).position(|w| w == name).unwrap() + 4;
+
+        let trun = &segment[find(b"trun")..];
+        let tfdt = &segment[find(b"tfdt")..];
+
+        (
+            i32::from_be_bytes(trun[24..28].try_into().unwrap()),
+            u64::from_be_bytes(tfdt[4..12].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn composition_time_offsets() {
+        let track = test::h264_track();
+        let mut muxer = FragmentedMp4Muxer::with_streams(&[track.clone()]);
+
+        let times = [(3600, 0), (14400, 3600), (7200, 7200), (10800, 10800)]
+            .into_iter()
+            .map(|(pts, dts)| {
+                let segment = muxer.write_media_segment(packet(&track, pts, dts)).unwrap();
+                sample_times(&segment.to_slice())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![(3600, 0), (10800, 3600), (0, 7200), (0, 10800)], times);
+    }
+
+    #[tokio::test]
+    async fn continue_after_discontinuity() {
+        let track = test::h264_track();
+        let mut muxer = FragmentedMp4Muxer::with_streams(&[track.clone()]);
+
+        let mut decode_times = Vec::new();
+        for pts in [90_000, 93_600, 0, 3600] {
+            if pts == 0 {
+                muxer.handle_event(&DemuxerEvent::Discontinuity).await.unwrap();
+            }
+
+            let segment = muxer.write_media_segment(packet(&track, pts, pts)).unwrap();
+            decode_times.push(sample_times(&segment.to_slice()).1);
+        }
+
+        assert_eq!(vec![0, 3600, 7200, 10800], decode_times);
+    }
+
+    #[tokio::test]
+    async fn durations_from_next_packet() {
+        let track = test::aac_track();
+
+        let mut muxer = FragmentedMp4Muxer::new(Io::from_stream(Box::new(Vec::<u8>::new())));
+        muxer.start(vec![track.clone()]).await.unwrap();
+        for pts in [0, 1000, 1900] {
+            muxer
+                .write(test::packet(&track, pts, None, vec![1; 3]))
+                .await
+                .unwrap();
+        }
+        muxer.stop().await.unwrap();
+
+        let output = muxer.into_io().into_bytes().unwrap();
+        let durations = test::mp4_boxes(&output)
+            .into_iter()
+            .filter(|(name, _)| name == b"moof")
+            .map(|(_, moof)| {
+                let trun = test::find_mp4_box(moof, &[b"traf", b"trun"]).unwrap();
+                u32::from_be_bytes(trun[12..16].try_into().unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        // the last one has no next packet, so it lasts one AAC frame
+        assert_eq!(vec![1000, 900, 1024], durations);
+    }
+
+    /// Reads the track id, decode time and sample data of each `traf` in a fragment.
+    fn parse_fragment(segment: &[u8]) -> Vec<(u32, u64, Vec<&[u8]>)> {
+        let boxes = test::mp4_boxes(segment);
+        assert_eq!(
+            vec![*b"moof", *b"mdat"],
+            boxes.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+        );
+
+        let mut trafs = Vec::new();
+        for (name, traf) in test::mp4_boxes(boxes[0].1) {
+            if &name != b"traf" {
+                continue;
+            }
+
+            let (mut track_id, mut decode_time, mut samples) = (0, 0, Vec::new());
+            for (name, data) in test::mp4_boxes(traf) {
+                match &name {
+                    b"tfhd" => track_id = u32::from_be_bytes(data[4..8].try_into().unwrap()),
+                    b"tfdt" => decode_time = u64::from_be_bytes(data[4..12].try_into().unwrap()),
+                    b"trun" => {
+                        let count = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+                        let mut offset = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+
+                        for sample in data[12..].chunks(16).take(count) {
+                            let size = u32::from_be_bytes(sample[4..8].try_into().unwrap()) as usize;
+                            samples.push(&segment[offset..offset + size]);
+                            offset += size;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            trafs.push((track_id, decode_time, samples));
+        }
+
+        trafs
+    }
+
+    #[test]
+    fn fragment_with_many_tracks() {
+        let video = test::h264_track();
+        let audio = test::aac_track();
+        let mut muxer = FragmentedMp4Muxer::with_streams(&[video.clone(), audio.clone()]);
+
+        let audio_packet = |pts, data| test::packet(&audio, pts, Some(1024), vec![data; 3]);
+
+        let first = [
+            packet(&video, 0, 0),
+            audio_packet(0, 1),
+            audio_packet(1024, 2),
+            packet(&video, 3600, 3600),
+        ];
+        let second = [audio_packet(2048, 3), packet(&video, 7200, 7200)];
+
+        let segment = muxer.write_many_media_segments(&first).unwrap();
+        let segment = segment.to_slice();
+        assert_eq!(
+            vec![
+                (1, 0, vec![&[0, 0, 0, 1, 0x65][..], &[0, 0, 0, 1, 0x65]]),
+                (2, 0, vec![&[1, 1, 1][..], &[2, 2, 2]]),
+            ],
+            parse_fragment(&segment)
+        );
+
+        let segment = muxer.write_many_media_segments(&second).unwrap();
+        let segment = segment.to_slice();
+        assert_eq!(
+            vec![
+                (2, 2048, vec![&[3, 3, 3][..]]),
+                (1, 7200, vec![&[0, 0, 0, 1, 0x65][..]]),
+            ],
+            parse_fragment(&segment)
+        );
+    }
+
+    #[test]
+    fn encrypted_fragment() {
+        let track = test::h264_track();
+        let encryption = TrackEncryption {
+            scheme: crate::cenc::Scheme::Cenc,
+            key_id: [1; 16],
+            per_sample_iv_size: 8,
+            constant_iv: None,
+            pattern: (0, 0),
+        };
+        let mut muxer = FragmentedMp4Muxer::with_streams(&[track.clone()])
+            .with_encryption(track.id, encryption)
+            .with_pssh(Pssh {
+                system_id: Pssh::COMMON,
+                key_ids: vec![[1; 16]],
+                data: Vec::new(),
+            });
+
+        let init = muxer.initialization_segment().unwrap().to_slice().into_owned();
+        for name in [b"encv", b"frma", b"tenc", b"pssh"] {
+            assert!(init.windows(4).any(|w| w == name));
+        }
+
+        let mut packet = packet(&track, 0, 0);
+        packet.side_data.push(SideData::Encryption {
+            key_id: [1; 16],
+            iv: vec![7; 8],
+            subsamples: vec![(5, 0)],
+        });
+        let segment = muxer.write_media_segment(packet).unwrap();
+        let segment = segment.to_slice();
+
+        let moof = test::mp4_boxes(&segment)[0].1;
+        let traf = test::mp4_boxes(moof)[1].1;
+        let boxes = test::mp4_boxes(traf);
+        assert_eq!(
+            vec![*b"tfhd", *b"tfdt", *b"trun", *b"senc", *b"saiz", *b"saio"],
+            boxes.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+        );
+
+        // the offset points to the IV and subsamples of the sample
+        let offset = u32::from_be_bytes(boxes[5].1[8..12].try_into().unwrap()) as usize;
+        assert_eq!(
+            &[7, 7, 7, 7, 7, 7, 7, 7, 0, 1, 0, 5, 0, 0, 0, 0],
+            &segment[offset..offset + 16]
+        );
+        assert_eq!(16, boxes[4].1[4]);
+    }
+
+    #[test]
+    fn reject_sample_encryption_info_too_large_for_saiz() {
+        let track = test::h264_track();
+        let encryption = TrackEncryption {
+            scheme: crate::cenc::Scheme::Cenc,
+            key_id: [1; 16],
+            per_sample_iv_size: 8,
+            constant_iv: None,
+            pattern: (0, 0),
+        };
+        let mut muxer = FragmentedMp4Muxer::with_streams(&[track.clone()])
+            .with_encryption(track.id, encryption);
+
+        // 8 + 2 + 6 * 42 bytes is more than a byte can hold
+        let mut packet = packet(&track, 0, 0);
+        packet.side_data.push(SideData::Encryption {
+            key_id: [1; 16],
+            iv: vec![7; 8],
+            subsamples: vec![(5, 0); 42],
+        });
+
+        let error = muxer.write_media_segment(packet).unwrap_err();
+        assert!(error.to_string().contains("saiz"));
+    }
+}
@@ -1,88 +1,139 @@
 use anyhow::Context;
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
+use bytes::BufMut;
 use log::*;
 
-use std::{collections::HashMap, io::SeekFrom, time::Duration};
+use std::{collections::HashMap, io::SeekFrom};
 
-use crate::{
-    codec::nal::{convert_bitstream, BitstreamFraming},
-    format::Muxer,
-    io::Io,
-    muxer, H264Codec, MediaDuration, MediaKind, MediaTime, Packet, Span, Track, VideoCodec,
-    VideoInfo,
-};
+use crate::{format::Muxer, io::Io, muxer, MediaboxError, Packet, SpanBuilder, Track};
 
-use super::{write_audio_trak, write_video_trak, SampleEntry, TrackBuilder};
+use super::{write_stsd, SampleEntry, TrackBuilder};
 
-muxer!("fmp4", Mp4Muxer::create);
+muxer!(
+    "mp4",
+    Mp4Muxer::create,
+    ["h264", "av1", "vp8", "vp9", "aac", "opus"]
+);
 
+/// A muxer which writes all samples into a single `mdat`, followed by the `moov` describing
+/// them. The output has to be seekable to fill in the size of the `mdat`.
 pub struct Mp4Muxer {
-    video: Option<Track>,
-    audio: Option<Track>,
     track_builders: HashMap<u32, TrackBuilder>,
+    encoder_delays: HashMap<u32, u64>,
     io: Io,
     mdat_start: u64,
+    /// Where the next sample is written.
+    position: u64,
 }
 
 impl Mp4Muxer {
     pub fn new(io: Io) -> Self {
         Mp4Muxer {
-            video: None,
-            audio: None,
             track_builders: HashMap::new(),
+            encoder_delays: HashMap::new(),
             io,
             mdat_start: 0,
+            position: 0,
         }
     }
 
+    /// Skips the first `delay` samples of a track when it is played, overriding the delay of the
+    /// track itself. Used for the priming samples of audio encoders.
+    pub fn with_encoder_delay(mut self, track_id: u32, delay: u64) -> Self {
+        self.encoder_delays.insert(track_id, delay);
+        self
+    }
+
     fn create(io: Io) -> Box<dyn Muxer> {
         Box::new(Self::new(io))
     }
 
     async fn write_moov_box(&mut self) -> anyhow::Result<()> {
-        let mut buf = BytesMut::new();
+        let mut builders = self.track_builders.values().cloned().collect::<Vec<_>>();
+        builders.sort_by_key(|builder| builder.id);
 
-        write_box!(&mut buf, b"moov", {
-            super::write_mvhd(&mut buf);
+        let movie_start = builders
+            .iter()
+            .filter_map(TrackBuilder::start)
+            .min()
+            .unwrap_or_default();
 
-            write_box!(&mut buf, b"mvex", {
-                write_box!(&mut buf, b"mehd", {
-                    buf.put_u32(1 << 24); // version
-                    buf.put_u64(0); // duration
-                });
-            });
+        let mut buf = SpanBuilder::new();
+        let mut traks = SpanBuilder::new();
+        let mut duration = 0;
 
-            for builder in self.track_builders.values() {
-                super::write_trak(&mut buf, builder.clone())?;
+        for builder in &mut builders {
+            builder.movie_start = movie_start;
+            if let Some(&delay) = self.encoder_delays.get(&builder.track.id) {
+                builder.encoder_delay = delay;
             }
+
+            duration = duration.max(builder.movie_duration());
+            super::write_trak(&mut traks, builder)?;
+        }
+
+        write_box!(&mut buf, b"moov", {
+            super::write_mvhd(&mut buf, duration, builders.len() as u32 + 1);
+            buf.put_span(traks.build());
         });
 
+        self.io.write_span(buf.build()).await?;
+
         Ok(())
     }
 }
 
 #[async_trait]
 impl Muxer for Mp4Muxer {
-    async fn start(&mut self, streams: Vec<Track>) -> anyhow::Result<()> {
-        let mut buf = BytesMut::new();
+    async fn start(&mut self, streams: Vec<Track>) -> crate::Result<()> {
+        if !self.io.seekable() {
+            return Err(MediaboxError::unsupported(
+                "MP4 can only be written to seekable outputs, fragmented MP4 can be streamed",
+            ));
+        }
+
+        for track in streams {
+            if track.info.subtitle().is_some() {
+                warn!(
+                    "Dropping track {}, subtitles can't be stored in MP4",
+                    track.id
+                );
+                continue;
+            }
+
+            // fail before any samples are written
+            write_stsd(&mut SpanBuilder::new(), &track, None)?;
+
+            let id = self.track_builders.len() as u32 + 1;
+            self.track_builders
+                .insert(track.id, TrackBuilder::new(track, id));
+        }
+
+        let mut buf = SpanBuilder::new();
 
         write_box!(&mut buf, b"ftyp", {
-            buf.extend_from_slice(b"isom\0\0\0\0isomiso5dash");
+            buf.put_slice(b"isom\0\0\x02\0isomiso2avc1mp41");
         });
 
-        self.mdat_start = self
+        let start = self
             .io
             .seek(SeekFrom::Current(0))
             .await
             .context("Failed to get mdat position")?;
-        // 8 byte length and 'mdat'
-        buf.extend_from_slice(b"\0\0\0\0\0\0\0\0mdat");
+        self.mdat_start = start + buf.len() as u64;
+
+        // 4 byte length, filled in when stopping. The free box before it is replaced by a 64 bit
+        // length if the samples don't fit
+        buf.put_slice(b"\0\0\0\x08free");
+        buf.put_slice(b"\0\0\0\0mdat");
+
+        self.position = start + buf.len() as u64;
+        self.io.write_span(buf.build()).await?;
 
         Ok(())
     }
 
-    async fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
         let builders = &mut self.track_builders;
         let Some(builder) = builders.get_mut(&packet.track.id) else {
             return Ok(());
@@ -92,35 +143,234 @@ impl Muxer for Mp4Muxer {
 
         let sample_entry = SampleEntry {
             is_sync: packet.key,
+            offset: self.position,
             time: packet.time.clone(),
             size: sample_data.len() as u64,
         };
 
         builder.add_sample(sample_entry);
 
+        self.position += sample_data.len() as u64;
         self.io.write_span(sample_data).await?;
 
         Ok(())
     }
 
-    async fn stop(&mut self) -> anyhow::Result<()> {
-        let current = self.io.seek(SeekFrom::Current(0)).await?;
+    async fn stop(&mut self) -> crate::Result<()> {
+        let mdat_len = self.position - self.mdat_start - 8;
 
-        let mdat_len = (current - self.mdat_start) as u64;
+        match u32::try_from(mdat_len) {
+            Ok(len) => {
+                self.io.seek(SeekFrom::Start(self.mdat_start + 8)).await?;
+                self.io.write(&len.to_be_bytes()).await?;
+            }
+            Err(_) => {
+                let mut header = SpanBuilder::new();
+                header.put_u32(1); // size, see largesize
+                header.put_slice(b"mdat");
+                header.put_u64(mdat_len + 8); // largesize
 
-        self.io.seek(SeekFrom::Start(self.mdat_start)).await?;
-        self.io.write(&mdat_len.to_be_bytes()[..]).await?;
-        self.io.seek(SeekFrom::Start(current)).await?;
+                self.io.seek(SeekFrom::Start(self.mdat_start)).await?;
+                self.io.write_span(header.build()).await?;
+            }
+        }
+        self.io.seek(SeekFrom::Start(self.position)).await?;
 
         self.write_moov_box().await?;
         Ok(())
     }
-    
+
     fn into_io(self) -> Io {
         self.io
     }
 }
 
-struct DecodeTimeBuffer {
-    buffer: Vec<Packet>,
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{format::Movie, test};
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Reads the entries of a full box made of `fields` 32 bit integers each.
+    fn table(data: &[u8], fields: usize) -> Vec<Vec<u32>> {
+        (0..u32_at(data, 4) as usize)
+            .map(|i| {
+                (0..fields)
+                    .map(|f| u32_at(data, 8 + (i * fields + f) * 4))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Finds the data of each sample of a track through its chunk offsets and sample sizes.
+    fn read_samples<'a>(file: &'a [u8], stbl: &[u8]) -> Vec<&'a [u8]> {
+        let offsets = table(test::find_mp4_box(stbl, &[b"stco"]).unwrap(), 1);
+        let stsc = table(test::find_mp4_box(stbl, &[b"stsc"]).unwrap(), 3);
+
+        let stsz = test::find_mp4_box(stbl, &[b"stsz"]).unwrap();
+        let sample_count = u32_at(stsz, 8) as usize;
+        let sizes = match u32_at(stsz, 4) {
+            0 => (0..sample_count)
+                .map(|i| u32_at(stsz, 12 + i * 4))
+                .collect(),
+            size => vec![size; sample_count],
+        };
+
+        let mut sizes = sizes.into_iter();
+        let mut samples = Vec::new();
+        for (chunk, offset) in (1..).zip(offsets) {
+            let entry = stsc.iter().rev().find(|e| e[0] <= chunk).unwrap();
+
+            let mut offset = offset[0] as usize;
+            for size in sizes.by_ref().take(entry[1] as usize) {
+                samples.push(&file[offset..offset + size as usize]);
+                offset += size as usize;
+            }
+        }
+
+        samples
+    }
+
+    #[tokio::test]
+    async fn write_sample_tables() {
+        let (video, audio) = (test::h264_track(), test::aac_track());
+
+        // only the first frame is a keyframe
+        let frame = |pts, nal| Packet {
+            key: pts == 0,
+            ..test::packet(&video, pts, Some(3600), vec![0, 0, 0, 1, nal])
+        };
+        let packets = [
+            frame(0, 0x65),
+            test::packet(&audio, 0, Some(1024), vec![1; 3]),
+            test::packet(&audio, 1024, Some(1024), vec![2; 3]),
+            frame(3600, 0x41),
+            frame(7200, 0x42),
+            test::packet(&audio, 2048, Some(1024), vec![3; 4]),
+        ];
+
+        let mut muxer = Mp4Muxer::new(Io::memory());
+        let movie = Movie {
+            tracks: vec![video.clone(), audio.clone()],
+            ..Default::default()
+        };
+        test::write_movie_and_packets(&mut muxer, movie, &packets).await;
+
+        let file = muxer.into_io().into_bytes().unwrap();
+        let boxes = test::mp4_boxes(&file);
+        assert_eq!(
+            vec![*b"ftyp", *b"free", *b"mdat", *b"moov"],
+            boxes.iter().map(|(ty, _)| *ty).collect::<Vec<_>>()
+        );
+        assert_eq!(25, boxes[2].1.len());
+
+        let traks = test::mp4_boxes(boxes[3].1)
+            .into_iter()
+            .filter(|(ty, _)| ty == b"trak")
+            .map(|(_, trak)| trak)
+            .collect::<Vec<_>>();
+        assert_eq!(2, traks.len());
+
+        for (trak, track) in traks.into_iter().zip([&video, &audio]) {
+            let stbl = test::find_mp4_box(trak, &[b"mdia", b"minf", b"stbl"]).unwrap();
+            let expected = packets
+                .iter()
+                .filter(|p| p.track.id == track.id)
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                expected
+                    .iter()
+                    .map(|p| p.buffer.to_slice().to_vec())
+                    .collect::<Vec<_>>(),
+                read_samples(&file, stbl),
+            );
+
+            let stts = table(test::find_mp4_box(stbl, &[b"stts"]).unwrap(), 2);
+            let duration = expected[0].time.duration.unwrap() as u32;
+            assert_eq!(vec![vec![3, duration]], stts);
+        }
+
+        let video_stbl = test::find_mp4_box(&file, &[b"moov", b"trak", b"mdia", b"minf", b"stbl"]);
+        let stss = table(
+            test::find_mp4_box(video_stbl.unwrap(), &[b"stss"]).unwrap(),
+            1,
+        );
+        assert_eq!(vec![vec![1]], stss);
+    }
+
+    #[tokio::test]
+    async fn write_large_file() {
+        use std::io::{Read, Seek};
+        use tokio::io::AsyncWriteExt;
+
+        let audio = test::aac_track();
+        let path = std::env::temp_dir().join(format!("mediabox-large-{}.mp4", std::process::id()));
+
+        let mut muxer = Mp4Muxer::new(Io::create_file(&path).await.unwrap());
+        muxer.start(vec![audio.clone()]).await.unwrap();
+        muxer
+            .write(test::packet(&audio, 0, Some(1024), vec![1; 3]))
+            .await
+            .unwrap();
+
+        // leave a sparse hole in the mdat, as if 4 GiB of samples had been written
+        muxer.position += 1 << 32;
+        muxer
+            .io
+            .seek(SeekFrom::Start(muxer.position))
+            .await
+            .unwrap();
+
+        muxer
+            .write(test::packet(&audio, 1024, Some(1024), vec![2; 3]))
+            .await
+            .unwrap();
+        muxer.stop().await.unwrap();
+
+        let mut file = muxer.into_io().into_writer::<tokio::fs::File>().unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        let mut read_at = |offset: u64, len: usize| {
+            let mut data = vec![0; len];
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.read_exact(&mut data).unwrap();
+            data
+        };
+
+        // the free box before the mdat holds its 64 bit size
+        let header = read_at(0, 48);
+        let mdat_start = u32_at(&header, 0) as u64;
+        assert_eq!(1, u32_at(&header, mdat_start as usize));
+        assert_eq!(
+            b"mdat",
+            &header[mdat_start as usize + 4..mdat_start as usize + 8]
+        );
+        let mdat_len =
+            u64::from_be_bytes(header[mdat_start as usize + 8..][..8].try_into().unwrap());
+        assert_eq!(16 + 6 + (1 << 32), mdat_len);
+
+        let moov_start = mdat_start + mdat_len;
+        let moov_len = std::fs::metadata(&path).unwrap().len() - moov_start;
+        let moov = read_at(moov_start, moov_len as usize);
+        std::fs::remove_file(&path).unwrap();
+
+        let co64 = test::find_mp4_box(
+            &moov,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"co64"],
+        )
+        .unwrap();
+        let offsets = (0..u32_at(co64, 4) as usize)
+            .map(|i| u64::from_be_bytes(co64[8 + i * 8..][..8].try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![mdat_start + 16, mdat_start + 16 + 3 + (1 << 32)],
+            offsets
+        );
+    }
 }
@@ -0,0 +1,301 @@
+//! Streams fragmented MP4 to browsers which play it with
+//! [Media Source Extensions](https://www.w3.org/TR/media-source-2/), e.g. over a WebSocket.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Sink, SinkExt};
+
+use std::ops::Range;
+
+use crate::{
+    format::{mp4::FragmentedMp4Muxer, DemuxerEvent, Movie, Muxer},
+    io::Io,
+    AudioCodec, MediaInfo, MediaTrackExt, MediaboxError, Packet, Span, Track, VideoCodec,
+};
+
+/// The kind of segment pushed to the sink of a [MseMuxer].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// The `ftyp` and `moov` describing the tracks, which has to be appended to the
+    /// `SourceBuffer` before any media segments. It is sent again when the tracks change.
+    Init,
+    /// A `moof` and `mdat` holding the samples of a packet.
+    Media,
+}
+
+type SegmentCallback = Box<dyn FnMut(SegmentKind, Range<u64>) + Send>;
+
+/// Pushes the initialization and media segments of a fragmented MP4 stream to a [Sink], with
+/// one message per segment.
+///
+/// Only the first video and audio tracks are written, see [mime_type] for the type to create the
+/// `SourceBuffer` with.
+pub struct MseMuxer<S> {
+    sink: S,
+    muxer: Option<FragmentedMp4Muxer>,
+    on_segment: Option<SegmentCallback>,
+    position: u64,
+}
+
+impl<S> MseMuxer<S>
+where
+    S: Sink<Bytes> + Unpin + Send,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(sink: S) -> Self {
+        MseMuxer {
+            sink,
+            muxer: None,
+            on_segment: None,
+            position: 0,
+        }
+    }
+
+    /// Calls `callback` with the byte range of every segment pushed to the sink, counting from
+    /// the start of the stream. This can be used to index the stream, e.g. to let clients which
+    /// join later start from the latest initialization segment.
+    pub fn with_segment_callback(
+        mut self,
+        callback: impl FnMut(SegmentKind, Range<u64>) + Send + 'static,
+    ) -> Self {
+        self.on_segment = Some(Box::new(callback));
+        self
+    }
+
+    /// The number of bytes pushed to the sink so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    async fn push(&mut self, kind: SegmentKind, segment: Span) -> crate::Result<()> {
+        let segment = segment.to_bytes();
+        let range = self.position..self.position + segment.len() as u64;
+
+        self.sink
+            .send(segment)
+            .await
+            .map_err(|e| MediaboxError::Other(e.into()))?;
+
+        self.position = range.end;
+        if let Some(on_segment) = &mut self.on_segment {
+            on_segment(kind, range);
+        }
+
+        Ok(())
+    }
+
+    fn muxer(&mut self) -> &mut FragmentedMp4Muxer {
+        self.muxer.as_mut().expect("Muxer not started")
+    }
+}
+
+#[async_trait]
+impl<S> Muxer for MseMuxer<S>
+where
+    S: Sink<Bytes> + Unpin + Send,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+        let muxer = FragmentedMp4Muxer::with_streams(&tracks);
+        let init_segment = muxer.initialization_segment()?;
+        self.muxer = Some(muxer);
+
+        self.push(SegmentKind::Init, init_segment).await
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        if !self.muxer().has_track(packet.track.id) {
+            return Ok(());
+        }
+
+        let media_segment = self.muxer().write_media_segment(packet)?;
+
+        self.push(SegmentKind::Media, media_segment).await
+    }
+
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        if self.muxer().apply_event(event) {
+            let init_segment = self.muxer().initialization_segment()?;
+            self.push(SegmentKind::Init, init_segment).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        self.sink
+            .flush()
+            .await
+            .map_err(|e| MediaboxError::Other(e.into()))
+    }
+
+    /// The segments are pushed to the sink, so this returns [Io::null].
+    fn into_io(self) -> Io {
+        Io::null()
+    }
+}
+
+/// The MIME type with the `codecs` parameter for the tracks a [MseMuxer] writes, which is passed
+/// to `MediaSource.addSourceBuffer`, e.g. `video/mp4; codecs="avc1.64001f,mp4a.40.2"`.
+pub fn mime_type(movie: &Movie) -> Option<String> {
+    let video = movie.tracks.video();
+    let audio = movie.tracks.audio();
+
+    let codecs = [video, audio]
+        .into_iter()
+        .flatten()
+        .map(|track| codec_string(&track.info))
+        .collect::<Option<Vec<_>>>()?;
+    if codecs.is_empty() {
+        return None;
+    }
+
+    let kind = if video.is_some() { "video" } else { "audio" };
+
+    Some(format!("{kind}/mp4; codecs=\"{}\"", codecs.join(",")))
+}
+
+/// The codec as described by RFC 6381, or [None] if it can't be stored in fragmented MP4.
+pub fn codec_string(info: &MediaInfo) -> Option<String> {
+    if let Some(video) = info.video() {
+        return match &video.codec {
+            VideoCodec::H264(h264) => Some(format!(
+                "avc1.{:02x}{:02x}{:02x}",
+                h264.profile_indication, h264.profile_compatibility, h264.level_indication
+            )),
+            VideoCodec::Av1(av1) => {
+                let config = av1.config.to_slice();
+                let (profile, level) = (config.get(1)? >> 5, config.get(1)? & 0x1f);
+                let flags = config.get(2)?;
+                let tier = if flags & 0x80 != 0 { 'H' } else { 'M' };
+                let bit_depth = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+                    (true, true) => 12,
+                    (true, false) => 10,
+                    _ => 8,
+                };
+
+                Some(format!("av01.{profile}.{level:02}{tier}.{bit_depth:02}"))
+            }
+            VideoCodec::Vp8(_) => Some("vp8".into()),
+            VideoCodec::Vp9(vp9) => {
+                // browsers reject a level of 0, so unknown levels are reported as the lowest one
+                let level = if vp9.level == 0 { 10 } else { vp9.level };
+
+                Some(format!(
+                    "vp09.{:02}.{:02}.{:02}",
+                    vp9.profile, level, vp9.bit_depth
+                ))
+            }
+            VideoCodec::Raw(_) => None,
+        };
+    }
+
+    match &info.audio()?.codec {
+        AudioCodec::Aac(aac) => {
+            let object_type = aac.extra.first().map_or(2, |b| b >> 3);
+
+            Some(format!("mp4a.40.{object_type}"))
+        }
+        AudioCodec::Opus(_) => Some("opus".into()),
+        AudioCodec::Vorbis(_) | AudioCodec::Flac(_) | AudioCodec::Pcm(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{channel::mpsc, StreamExt};
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{test, AudioInfo, Fraction, MediaKind, OpusCodec, SoundType, VideoInfo, VpxCodec};
+
+    fn vp9_track() -> Track {
+        test::track(
+            1,
+            Fraction::new(1, 1000),
+            "vp9",
+            MediaKind::Video(VideoInfo {
+                width: 320,
+                height: 240,
+                codec: VideoCodec::Vp9(VpxCodec {
+                    profile: 0,
+                    level: 0,
+                    bit_depth: 8,
+                    chroma_subsampling: 1,
+                    full_range: false,
+                    colour_primaries: 1,
+                    transfer_characteristics: 1,
+                    matrix_coefficients: 1,
+                }),
+            }),
+        )
+    }
+
+    fn opus_track() -> Track {
+        test::track(
+            2,
+            Fraction::new(1, 48_000),
+            "opus",
+            MediaKind::Audio(AudioInfo {
+                sample_rate: 48000,
+                sample_bpp: 16,
+                sound_type: SoundType::Stereo,
+                codec: AudioCodec::Opus(OpusCodec { header: Vec::new() }),
+            }),
+        )
+    }
+
+    #[test_case(vec![test::h264_track(), test::aac_track()], Some("video/mp4; codecs=\"avc1.64000a,mp4a.40.2\"") ; "h264 and aac")]
+    #[test_case(vec![vp9_track()], Some("video/mp4; codecs=\"vp09.00.10.08\"") ; "vp9")]
+    #[test_case(vec![opus_track()], Some("audio/mp4; codecs=\"opus\"") ; "opus")]
+    #[test_case(Vec::new(), None ; "no tracks")]
+    fn mime_types(tracks: Vec<Track>, expected: Option<&str>) {
+        let movie = Movie {
+            tracks,
+            ..Default::default()
+        };
+
+        assert_eq!(expected.map(String::from), mime_type(&movie));
+    }
+
+    #[tokio::test]
+    async fn push_segments() {
+        let (tx, rx) = mpsc::unbounded();
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+
+        let callback_ranges = ranges.clone();
+        let mut muxer = MseMuxer::new(tx).with_segment_callback(move |kind, range| {
+            callback_ranges.lock().unwrap().push((kind, range));
+        });
+
+        let track = test::aac_track();
+        muxer.start(vec![track.clone()]).await.unwrap();
+        muxer
+            .write(test::packet(&track, 0, Some(1024), vec![0x21, 0x10]))
+            .await
+            .unwrap();
+        muxer.stop().await.unwrap();
+        drop(muxer);
+
+        let segments = rx.collect::<Vec<Bytes>>().await;
+        assert_eq!(2, segments.len());
+        assert_eq!(b"ftyp", &segments[0][4..8]);
+        assert_eq!(b"moof", &segments[1][4..8]);
+
+        let init = segments[0].len() as u64;
+        let media = init + segments[1].len() as u64;
+        assert_eq!(
+            vec![
+                (SegmentKind::Init, 0..init),
+                (SegmentKind::Media, init..media)
+            ],
+            *ranges.lock().unwrap()
+        );
+    }
+}
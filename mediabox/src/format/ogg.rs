@@ -0,0 +1,655 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::*;
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+
+use crate::{
+    codec::{
+        opus::{self, OpusHead, OPUS_SAMPLE_RATE},
+        vorbis::{VorbisIdHeader, VorbisParser},
+    },
+    demuxer,
+    format::{Demuxer, DemuxerEvent, Movie, ProbeResult},
+    io::Io,
+    AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, MediaboxError, OpusCodec,
+    Packet, SoundType, Track, VorbisCodec,
+};
+
+demuxer!(
+    "ogg",
+    OggDemuxer::create,
+    OggDemuxer::probe,
+    ["*.ogg", "*.oga", "*.opus"]
+);
+
+const OGG_MAGIC: &[u8; 4] = b"OggS";
+const PAGE_HEADER_LEN: usize = 27;
+
+const FLAG_CONTINUED: u8 = 0x01;
+const FLAG_BOS: u8 = 0x02;
+const FLAG_EOS: u8 = 0x04;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OggError {
+    #[error("Missing OggS capture pattern")]
+    InvalidCapture,
+
+    #[error("Unsupported Ogg version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid {0} header")]
+    InvalidHeader(&'static str),
+
+    #[error("No Opus or Vorbis streams")]
+    NoStreams,
+}
+
+impl From<OggError> for MediaboxError {
+    fn from(error: OggError) -> Self {
+        match error {
+            OggError::UnsupportedVersion(_) | OggError::NoStreams => {
+                MediaboxError::unsupported(error.to_string())
+            }
+            _ => MediaboxError::invalid_data(error),
+        }
+    }
+}
+
+/// The CRC used by Ogg pages, with polynomial `0x04c11db7` and no reflection.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u32) << 24), |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+struct Page {
+    flags: u8,
+    /// The end time of the last packet which ends on this page, if any does.
+    granule_position: Option<u64>,
+    serial: u32,
+    segments: Vec<u8>,
+    data: Bytes,
+}
+
+enum StreamCodec {
+    Opus,
+    Vorbis(Option<VorbisParser>),
+    /// Streams of other codecs, e.g. Theora video, are skipped.
+    Unsupported,
+}
+
+struct OggStream {
+    serial: u32,
+    codec: StreamCodec,
+    headers: Vec<Vec<u8>>,
+    track: Option<Track>,
+    /// The start of a packet which continues on the next page.
+    partial: Vec<u8>,
+    /// Where the next packet starts, once it is known from a granule position.
+    next_pts: Option<u64>,
+    /// Packets and their durations which wait for a page with a granule position.
+    untimed: Vec<(Bytes, u64)>,
+}
+
+impl OggStream {
+    fn header_count(&self) -> usize {
+        match self.codec {
+            // OpusHead and OpusTags
+            StreamCodec::Opus => 2,
+            // identification, comment and setup
+            StreamCodec::Vorbis(_) => 3,
+            StreamCodec::Unsupported => 0,
+        }
+    }
+
+    fn has_headers(&self) -> bool {
+        self.headers.len() >= self.header_count()
+    }
+
+    /// Creates the track of the stream once all headers have been read.
+    fn create_track(&mut self, id: u32) -> Result<(), OggError> {
+        let (info, timebase, delay) = match &mut self.codec {
+            StreamCodec::Opus => {
+                let head =
+                    OpusHead::parse(&self.headers[0]).ok_or(OggError::InvalidHeader("Opus"))?;
+                let info = audio_info(
+                    "opus",
+                    OPUS_SAMPLE_RATE,
+                    head.channels,
+                    AudioCodec::Opus(OpusCodec {
+                        header: self.headers[0].clone(),
+                    }),
+                );
+
+                (
+                    info,
+                    Fraction::new(1, OPUS_SAMPLE_RATE),
+                    head.pre_skip as u64,
+                )
+            }
+            StreamCodec::Vorbis(parser) => {
+                let id = VorbisIdHeader::parse(&self.headers[0])
+                    .ok_or(OggError::InvalidHeader("Vorbis"))?;
+                let codec = VorbisCodec {
+                    headers: self.headers.clone().try_into().unwrap(),
+                };
+
+                *parser = VorbisParser::new(&codec);
+                if parser.is_none() {
+                    warn!("Unable to find the Vorbis modes, packets will lack durations");
+                }
+
+                let info = audio_info(
+                    "vorbis",
+                    id.sample_rate,
+                    id.channels,
+                    AudioCodec::Vorbis(codec),
+                );
+
+                (info, Fraction::new(1, id.sample_rate), 0)
+            }
+            StreamCodec::Unsupported => return Ok(()),
+        };
+
+        self.track = Some(Track {
+            id,
+            info: Arc::new(info),
+            timebase,
+            delay,
+            metadata: Default::default(),
+        });
+
+        Ok(())
+    }
+
+    fn packet_duration(&mut self, data: &[u8]) -> u64 {
+        let duration = match &mut self.codec {
+            StreamCodec::Opus => opus::packet_duration(data),
+            StreamCodec::Vorbis(Some(parser)) => parser.packet_duration(data),
+            _ => None,
+        };
+
+        duration.unwrap_or(0) as u64
+    }
+
+    /// Times the packets waiting for a granule position, which is the end of the last of them.
+    fn time_packets(&mut self, granule_position: u64, last_page: bool) -> Vec<Packet> {
+        let Some(track) = self.track.clone() else {
+            return Vec::new();
+        };
+
+        let total = self
+            .untimed
+            .iter()
+            .map(|(_, duration)| duration)
+            .sum::<u64>();
+        let mut pts = self
+            .next_pts
+            .unwrap_or_else(|| granule_position.saturating_sub(total));
+        let count = self.untimed.len();
+
+        let packets = self
+            .untimed
+            .drain(..)
+            .enumerate()
+            .map(|(i, (data, mut duration))| {
+                // the granule position of the last page cuts off the padding of the last packet
+                if last_page && i + 1 == count {
+                    duration = duration.min(granule_position.saturating_sub(pts));
+                }
+
+                let packet = Packet {
+                    time: MediaTime {
+                        pts,
+                        dts: None,
+                        duration: Some(duration),
+                        timebase: track.timebase,
+                    },
+                    key: true,
+                    track: track.clone(),
+                    buffer: data.into(),
+                    side_data: Vec::new(),
+                };
+                pts += duration;
+
+                packet
+            })
+            .collect();
+
+        self.next_pts = Some(pts);
+
+        packets
+    }
+}
+
+fn audio_info(name: &'static str, sample_rate: u32, channels: u8, codec: AudioCodec) -> MediaInfo {
+    MediaInfo {
+        name,
+        kind: MediaKind::Audio(AudioInfo {
+            sample_rate,
+            sample_bpp: 16,
+            sound_type: if channels > 1 {
+                SoundType::Stereo
+            } else {
+                SoundType::Mono
+            },
+            codec,
+        }),
+    }
+}
+
+/// Parses the tags of an `OpusTags` or Vorbis comment header, which follow the magic of the
+/// header.
+fn parse_comments(mut data: &[u8]) -> Vec<(String, String)> {
+    let mut comments = Vec::new();
+
+    let Some(vendor_len) = take_u32(&mut data) else {
+        return comments;
+    };
+    let count = take(&mut data, vendor_len as usize).and_then(|_| take_u32(&mut data));
+
+    for _ in 0..count.unwrap_or(0) {
+        let Some(comment) = take_u32(&mut data).and_then(|len| take(&mut data, len as usize))
+        else {
+            break;
+        };
+
+        if let Some((key, value)) = String::from_utf8_lossy(comment).split_once('=') {
+            comments.push((key.to_ascii_uppercase(), value.to_string()));
+        }
+    }
+
+    comments
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let value = data.get(..len)?;
+    *data = &data[len..];
+
+    Some(value)
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+}
+
+enum Queued {
+    Packet(Packet),
+    Event(DemuxerEvent),
+}
+
+/// A demuxer for Ogg streams of Opus or Vorbis audio, i.e. `.opus`, `.ogg` and `.oga` files.
+///
+/// Ogg only stores the end time of the last packet finishing on each page, so the packets of a
+/// page are timed backwards from it using the durations found in the packets themselves. Chained
+/// streams, e.g. from internet radio, are reported as [`DemuxerEvent::NewMovie`].
+pub struct OggDemuxer {
+    io: Io,
+    streams: Vec<OggStream>,
+    /// Whether a new chained stream is being set up.
+    starting: bool,
+    metadata: BTreeMap<String, String>,
+    queue: VecDeque<Queued>,
+    events: VecDeque<DemuxerEvent>,
+}
+
+impl OggDemuxer {
+    pub fn new(io: Io) -> Self {
+        OggDemuxer {
+            io,
+            streams: Vec::new(),
+            starting: true,
+            metadata: BTreeMap::new(),
+            queue: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    async fn read_page(&mut self) -> crate::Result<Page> {
+        loop {
+            if self.io.is_at_end().await? {
+                return Err(MediaboxError::EndOfInput);
+            }
+
+            let mut header = [0u8; PAGE_HEADER_LEN];
+            self.io.read_exact(&mut header).await?;
+
+            if &header[..4] != OGG_MAGIC {
+                Err(OggError::InvalidCapture)?;
+            }
+            if header[4] != 0 {
+                Err(OggError::UnsupportedVersion(header[4]))?;
+            }
+
+            let mut segments = vec![0u8; header[26] as usize];
+            self.io.read_exact(&mut segments).await?;
+
+            let mut data = vec![0u8; segments.iter().map(|&len| len as usize).sum()];
+            self.io.read_exact(&mut data).await?;
+
+            let crc = u32::from_le_bytes(header[22..26].try_into().unwrap());
+            header[22..26].fill(0);
+            if crc32(&[&header[..], &segments, &data].concat()) != crc {
+                warn!("Skipping Ogg page with invalid CRC");
+                continue;
+            }
+
+            let granule_position = u64::from_le_bytes(header[6..14].try_into().unwrap());
+
+            return Ok(Page {
+                flags: header[5],
+                granule_position: (granule_position != u64::MAX).then_some(granule_position),
+                serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+                segments,
+                data: data.into(),
+            });
+        }
+    }
+
+    /// Splits a page into packets, returning whether it completed the headers of a new stream.
+    fn process_page(&mut self, page: Page) -> crate::Result<bool> {
+        let bos = page.flags & FLAG_BOS != 0;
+        if bos {
+            if !self.starting {
+                debug!("Chained Ogg stream {}", page.serial);
+
+                self.streams.clear();
+                self.metadata.clear();
+                self.starting = true;
+            }
+
+            let codec = if page.data.starts_with(b"OpusHead") {
+                StreamCodec::Opus
+            } else if page.data.starts_with(b"\x01vorbis") {
+                StreamCodec::Vorbis(None)
+            } else {
+                warn!("Skipping unsupported Ogg stream {}", page.serial);
+                StreamCodec::Unsupported
+            };
+
+            self.streams.push(OggStream {
+                serial: page.serial,
+                codec,
+                headers: Vec::new(),
+                track: None,
+                partial: Vec::new(),
+                next_pts: None,
+                untimed: Vec::new(),
+            });
+        }
+
+        let id = self.streams.iter().position(|s| s.serial == page.serial);
+        let Some(stream) = id.map(|id| &mut self.streams[id]) else {
+            warn!("Skipping page of unknown Ogg stream {}", page.serial);
+            return Ok(false);
+        };
+
+        if page.flags & FLAG_CONTINUED == 0 && !stream.partial.is_empty() {
+            warn!("Dropping incomplete packet of Ogg stream {}", page.serial);
+            stream.partial.clear();
+        }
+
+        let mut offset = 0;
+        for &len in &page.segments {
+            let len = len as usize;
+            stream
+                .partial
+                .extend_from_slice(&page.data[offset..offset + len]);
+            offset += len;
+
+            // packets continue in the next segment if this one is full
+            if len == 255 {
+                continue;
+            }
+
+            let data = std::mem::take(&mut stream.partial);
+            if !stream.has_headers() {
+                stream.headers.push(data);
+                if stream.has_headers() {
+                    stream.create_track(id.unwrap() as u32)?;
+                }
+            } else if stream.track.is_some() {
+                let duration = stream.packet_duration(&data);
+                stream.untimed.push((data.into(), duration));
+            }
+        }
+
+        if let Some(granule_position) = page.granule_position {
+            let packets = stream.time_packets(granule_position, page.flags & FLAG_EOS != 0);
+            self.queue.extend(packets.into_iter().map(Queued::Packet));
+        }
+
+        // all streams start before any of them continue
+        Ok(self.starting && !bos && self.streams.iter().all(OggStream::has_headers))
+    }
+
+    fn movie(&mut self) -> crate::Result<Movie> {
+        self.starting = false;
+
+        for stream in &self.streams {
+            let comments = match stream.codec {
+                StreamCodec::Opus => stream.headers[1].strip_prefix(b"OpusTags"),
+                StreamCodec::Vorbis(_) => stream.headers[1].strip_prefix(b"\x03vorbis"),
+                StreamCodec::Unsupported => None,
+            };
+
+            for (key, value) in comments.map(parse_comments).unwrap_or_default() {
+                self.metadata.entry(key).or_insert(value);
+            }
+        }
+
+        let tracks = self
+            .streams
+            .iter()
+            .filter_map(|stream| stream.track.clone())
+            .collect::<Vec<_>>();
+
+        if tracks.is_empty() {
+            Err(OggError::NoStreams)?;
+        }
+
+        Ok(Movie {
+            tracks,
+            metadata: self.metadata.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for OggDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        loop {
+            let page = self.read_page().await?;
+            if self.process_page(page)? {
+                return self.movie();
+            }
+        }
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        loop {
+            match self.queue.pop_front() {
+                Some(Queued::Packet(packet)) => return Ok(packet),
+                Some(Queued::Event(event)) => {
+                    self.events.push_back(event);
+                    continue;
+                }
+                None => {}
+            }
+
+            let page = match self.read_page().await {
+                Ok(page) => page,
+                // streams which end without a final granule position continue from the
+                // previous one
+                Err(e) if e.is_end_of_input() => {
+                    for stream in &mut self.streams {
+                        let end = stream.next_pts.unwrap_or(0)
+                            + stream.untimed.iter().map(|(_, d)| d).sum::<u64>();
+                        let packets = stream.time_packets(end, false);
+                        self.queue.extend(packets.into_iter().map(Queued::Packet));
+                    }
+
+                    if self.queue.is_empty() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if self.process_page(page)? {
+                let movie = self.movie()?;
+                self.queue
+                    .push_back(Queued::Event(DemuxerEvent::NewMovie(movie)));
+            }
+        }
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<DemuxerEvent> {
+        self.events.pop_front()
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(Self::new(io))
+    }
+
+    fn probe(data: &[u8]) -> ProbeResult {
+        if !data.starts_with(OGG_MAGIC) {
+            return if OGG_MAGIC.starts_with(data) {
+                ProbeResult::NeedMoreData
+            } else {
+                ProbeResult::Unsure
+            };
+        }
+
+        let Some(&segment_count) = data.get(26) else {
+            return ProbeResult::NeedMoreData;
+        };
+        let payload = &data[(PAGE_HEADER_LEN + segment_count as usize).min(data.len())..];
+
+        if payload.starts_with(b"OpusHead") || payload.starts_with(b"\x01vorbis") {
+            ProbeResult::Yup
+        } else if payload.len() < 8 {
+            ProbeResult::NeedMoreData
+        } else {
+            // Ogg with a codec that isn't supported
+            ProbeResult::Maybe(0.5)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A page with the given packets, where a last packet with a length divisible by 255
+    /// continues on the next page.
+    fn page(flags: u8, granule_position: u64, serial: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut segments = Vec::new();
+        for packet in packets {
+            segments.extend(std::iter::repeat_n(255, packet.len() / 255));
+            segments.push((packet.len() % 255) as u8);
+        }
+        if segments.last() == Some(&0) {
+            segments.pop();
+        }
+
+        let mut data = OGG_MAGIC.to_vec();
+        data.extend_from_slice(&[0, flags]);
+        data.extend_from_slice(&granule_position.to_le_bytes());
+        data.extend_from_slice(&serial.to_le_bytes());
+        data.extend_from_slice(&[0; 8]); // sequence number, CRC
+        data.push(segments.len() as u8);
+        data.extend(segments);
+        data.extend(packets.concat());
+
+        let crc = crc32(&data);
+        data[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        data
+    }
+
+    fn opus_stream(serial: u32, title: &str) -> Vec<u8> {
+        let head = OpusHead {
+            channels: 2,
+            pre_skip: 312,
+            input_sample_rate: 48000,
+            output_gain: 0,
+            mapping_family: 0,
+            mapping: Vec::new(),
+        };
+
+        let comment = format!("title={title}");
+        let mut tags = b"OpusTags\x04\x00\x00\x00test\x01\x00\x00\x00".to_vec();
+        tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        tags.extend_from_slice(comment.as_bytes());
+
+        [
+            page(FLAG_BOS, 0, serial, &[&head.to_bytes()]),
+            page(0, 0, serial, &[&tags]),
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn read_chained_opus() {
+        // 20 ms packets, one of which continues on the next page
+        let long = [0xfc; 400];
+        let mut data = opus_stream(1, "First");
+        data.extend(page(0, 2880, 1, &[&[0xfc; 10], &[0xfc; 300], &[0xfc; 10]]));
+        data.extend(page(0, u64::MAX, 1, &[&long[..255]]));
+        data.extend(page(FLAG_CONTINUED | FLAG_EOS, 3380, 1, &[&long[255..]]));
+        data.extend(opus_stream(2, "Second"));
+        data.extend(page(FLAG_EOS, 960, 2, &[&[0xfc; 20]]));
+
+        assert!(matches!(OggDemuxer::probe(&data), ProbeResult::Yup));
+
+        let mut demuxer = OggDemuxer::new(Io::from_bytes(data));
+        let movie = demuxer.start().await.unwrap();
+
+        assert_eq!(Some("First"), movie.metadata.get("TITLE").map(String::as_str));
+        assert_eq!(312, movie.tracks[0].delay);
+        assert!(matches!(
+            movie.tracks[0].info.audio().unwrap().codec,
+            AudioCodec::Opus(_)
+        ));
+
+        let mut packets = Vec::new();
+        let mut titles = Vec::new();
+        while let Ok(packet) = demuxer.read().await {
+            while let Some(DemuxerEvent::NewMovie(movie)) = demuxer.next_event() {
+                titles.push(movie.metadata["TITLE"].clone());
+            }
+
+            let time = &packet.time;
+            packets.push((time.pts, time.duration.unwrap(), packet.buffer.len()));
+        }
+
+        assert_eq!(
+            vec![
+                (0, 960, 10),
+                (960, 960, 300),
+                (1920, 960, 10),
+                (2880, 500, 400),
+                (0, 960, 20)
+            ],
+            packets
+        );
+        assert_eq!(vec!["Second".to_string()], titles);
+    }
+}
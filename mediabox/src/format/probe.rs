@@ -0,0 +1,258 @@
+//! Detectors for formats which lack a distinctive file header.
+//!
+//! Raw audio streams consist of nothing but frames beginning with a short syncword, which is
+//! easily found by accident in other data. These detectors instead verify that a chain of
+//! consecutive frames can be parsed.
+
+use super::ProbeResult;
+use crate::codec::aac::AdtsHeader;
+
+/// The number of consecutive frames needed to be certain about a format.
+const MIN_FRAMES: usize = 3;
+
+/// How far into the data to look for the first frame.
+const MAX_SYNC_OFFSET: usize = 4096;
+
+/// Detects an AAC stream framed with ADTS headers.
+pub fn adts(data: &[u8]) -> ProbeResult {
+    frame_chain(data, adts_frame_length)
+}
+
+/// Detects an AAC stream framed as a LOAS `AudioSyncStream`.
+pub fn loas(data: &[u8]) -> ProbeResult {
+    frame_chain(data, loas_frame_length)
+}
+
+/// Detects a MPEG audio (MP1/MP2/MP3) stream, skipping a leading ID3v2 tag if present.
+pub fn mpeg_audio(data: &[u8]) -> ProbeResult {
+    let data = &data[id3v2_len(data).min(data.len())..];
+
+    frame_chain(data, mpeg_audio_frame_length)
+}
+
+/// Finds the offset of the first ADTS frame which is followed by a chain of valid frames.
+pub fn adts_sync_offset(data: &[u8]) -> Option<usize> {
+    find_chain(data, adts_frame_length).map(|(start, _)| start)
+}
+
+fn adts_frame_length(data: &[u8]) -> Option<usize> {
+    AdtsHeader::parse(data).map(|header| header.frame_length as usize)
+}
+
+fn frame_chain<F: Fn(&[u8]) -> Option<usize>>(data: &[u8], parse: F) -> ProbeResult {
+    match find_chain(data, &parse) {
+        None => ProbeResult::Unsure,
+        Some((0, frames)) if frames >= MIN_FRAMES => ProbeResult::Yup,
+        Some((_, frames)) if frames >= MIN_FRAMES => ProbeResult::Maybe(0.9),
+        // the chain is only short because the last frame is cut off
+        Some((start, _)) if chain_end(data, start, &parse) > data.len() => {
+            ProbeResult::NeedMoreData
+        }
+        Some((_, frames)) => ProbeResult::Maybe(0.25 * frames as f32),
+    }
+}
+
+/// Returns where the chain of frames starting at `start` ends, which is past the end of the data
+/// if the last frame is cut off.
+fn chain_end<F: Fn(&[u8]) -> Option<usize>>(data: &[u8], start: usize, parse: F) -> usize {
+    let mut offset = start;
+
+    while let Some(len) = data.get(offset..).and_then(&parse) {
+        if len == 0 {
+            break;
+        }
+
+        offset += len;
+    }
+
+    offset
+}
+
+/// Finds the start and length of the first chain of [`MIN_FRAMES`] consecutive frames, or the
+/// longest chain if there is none. `parse` returns the length of the frame at the start of the
+/// given data if there is one.
+fn find_chain<F: Fn(&[u8]) -> Option<usize>>(data: &[u8], parse: F) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+
+    for start in 0..data.len().min(MAX_SYNC_OFFSET) {
+        let mut frames = 0;
+        let mut offset = start;
+
+        while let Some(len) = data.get(offset..).and_then(&parse) {
+            if len == 0 {
+                break;
+            }
+
+            frames += 1;
+            offset += len;
+
+            if offset == data.len() {
+                // ran out of data exactly at a frame boundary, trust what we have seen
+                frames = frames.max(MIN_FRAMES);
+                break;
+            }
+        }
+
+        if frames >= MIN_FRAMES {
+            return Some((start, frames));
+        }
+
+        if frames > best.map_or(0, |(_, n)| n) {
+            best = Some((start, frames));
+        }
+    }
+
+    best
+}
+
+fn loas_frame_length(data: &[u8]) -> Option<usize> {
+    if data.len() < 3 || data[0] != 0x56 || data[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+
+    let len = ((data[1] as usize & 0x1f) << 8) | data[2] as usize;
+
+    Some(3 + len)
+}
+
+const MPEG1_BITRATES: [[u32; 15]; 3] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+
+const MPEG2_BITRATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+const MPEG_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+fn mpeg_audio_frame_length(data: &[u8]) -> Option<usize> {
+    if data.len() < 4 || data[0] != 0xff || data[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+
+    let version = (data[1] >> 3) & 0b11;
+    let layer = (data[1] >> 1) & 0b11;
+    let bitrate_idx = (data[2] >> 4) as usize;
+    let sample_rate_idx = ((data[2] >> 2) & 0b11) as usize;
+    let padding = ((data[2] >> 1) & 1) as u32;
+
+    if version == 0b01 || layer == 0b00 || bitrate_idx == 0 || bitrate_idx == 15 {
+        return None;
+    }
+
+    let sample_rate = MPEG_SAMPLE_RATES.get(sample_rate_idx)?
+        >> match version {
+            0b11 => 0, // MPEG-1
+            0b10 => 1, // MPEG-2
+            _ => 2,    // MPEG-2.5
+        };
+
+    // layer is stored as 3 - (layer number - 1)
+    let layer = 4 - layer as usize;
+    let bitrate = 1000
+        * if version == 0b11 {
+            MPEG1_BITRATES[layer - 1][bitrate_idx]
+        } else {
+            MPEG2_BITRATES[(layer - 1).min(1)][bitrate_idx]
+        };
+
+    let len = match layer {
+        1 => (12 * bitrate / sample_rate + padding) * 4,
+        3 if version != 0b11 => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    };
+
+    Some(len as usize)
+}
+
+/// The length of an ID3v2 tag at the start of the data, or 0 if there is none.
+pub(crate) fn id3v2_len(data: &[u8]) -> usize {
+    match data {
+        [b'I', b'D', b'3', _, _, flags, size @ ..] if size.len() >= 4 => {
+            let size = size[..4]
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7f) as usize);
+            let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+
+            10 + size + footer
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::aac::AudioSpecificConfig;
+    use bytes::BytesMut;
+    use test_case::test_case;
+
+    fn adts_frames(count: usize) -> Vec<u8> {
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+
+        let mut buf = BytesMut::new();
+        for _ in 0..count {
+            AdtsHeader::new(config, 100).unwrap().write(&mut buf);
+            buf.extend_from_slice(&[0u8; 100]);
+        }
+
+        buf.to_vec()
+    }
+
+    fn loas_frames(count: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for _ in 0..count {
+            buf.extend_from_slice(&[0x56, 0xe0, 50]);
+            buf.extend_from_slice(&[0u8; 50]);
+        }
+
+        buf
+    }
+
+    fn mp3_frames(count: usize, id3: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if id3 {
+            buf.extend_from_slice(b"ID3\x04\x00\x00\x00\x00\x00\x05hello");
+        }
+
+        // MPEG-1 layer III, 128 kbit/s, 44.1 kHz, no padding => 417 bytes
+        for _ in 0..count {
+            buf.extend_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+            buf.extend_from_slice(&[0u8; 413]);
+        }
+
+        buf
+    }
+
+    #[test_case(adts_frames(4), super::adts, ProbeResult::Yup ; "adts")]
+    #[test_case(loas_frames(4), super::loas, ProbeResult::Yup ; "loas")]
+    #[test_case(mp3_frames(4, false), super::mpeg_audio, ProbeResult::Yup ; "mp3")]
+    #[test_case(mp3_frames(2, false)[..600].to_vec(), super::mpeg_audio, ProbeResult::NeedMoreData ; "cut off mp3")]
+    #[test_case(mp3_frames(4, true), super::mpeg_audio, ProbeResult::Yup ; "mp3 with id3")]
+    #[test_case(adts_frames(4), super::mpeg_audio, ProbeResult::Unsure ; "adts is not mp3")]
+    #[test_case(mp3_frames(4, false), super::adts, ProbeResult::Unsure ; "mp3 is not adts")]
+    #[test_case(vec![0xff, 0xf1, 0x50, 0x80], super::adts, ProbeResult::Unsure ; "truncated adts")]
+    fn detect(data: Vec<u8>, detector: fn(&[u8]) -> ProbeResult, expected: ProbeResult) {
+        assert!(detector(&data) == expected);
+    }
+
+    #[test]
+    fn garbage_before_sync() {
+        let mut data = vec![0x12, 0x34, 0x56];
+        data.extend(adts_frames(4));
+
+        assert!(adts(&data) == ProbeResult::Maybe(0.9));
+        assert_eq!(Some(3), adts_sync_offset(&data));
+    }
+}
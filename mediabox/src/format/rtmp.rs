@@ -17,13 +17,24 @@ use rml_rtmp::{
     time::RtmpTimestamp,
 };
 
-use bytes::{BufMut, BytesMut};
+use async_trait::async_trait;
 use log::*;
 use tokio::net::{tcp, TcpListener, TcpStream, ToSocketAddrs};
 
-use std::{collections::VecDeque, io::Read, net::SocketAddr, sync::Arc};
+use std::{collections::VecDeque, io::Read, net::SocketAddr, sync::Arc, time::Duration};
 
-use crate::{codec::nal::BitstreamFraming, media, Fraction, Track};
+use crate::{
+    codec::{h264::AvcDecoderConfig, nal::BitstreamFraming},
+    format::{Demuxer, DemuxerEvent, Movie, UnsupportedDemuxer},
+    io::Io,
+    media, Fraction, MediaboxError, Track,
+};
+
+mod mux;
+mod server;
+
+pub use mux::*;
+pub use server::*;
 
 const RTMP_TIMEBASE: Fraction = Fraction::new(1, 1000);
 const RTMP_AAC_TIMEBASE: Fraction = Fraction::new(1, 48000);
@@ -105,8 +116,9 @@ impl RtmpRequest {
             }
         }
 
-        let meta =
+        let (meta, rest) =
             wait_for_metadata(&mut self.server_session, &mut self.read, &mut rtmp_tx).await?;
+        new_results.extend(rest);
 
         Ok(RtmpSession::new(
             meta,
@@ -133,7 +145,7 @@ async fn wait_for_metadata(
     rtmp_server_session: &mut ServerSession,
     read: &mut tcp::OwnedReadHalf,
     rtmp_tx: &mut Sender<Packet>,
-) -> anyhow::Result<StreamMetadata> {
+) -> anyhow::Result<(StreamMetadata, Vec<ServerSessionResult>)> {
     use tokio::io::AsyncReadExt;
 
     debug!("Waiting for metadata");
@@ -145,14 +157,18 @@ async fn wait_for_metadata(
             anyhow::bail!("EOS");
         }
 
-        for res in rtmp_server_session.handle_input(&buf[..n]).map_err(|e| e)? {
+        let mut results = rtmp_server_session.handle_input(&buf[..n])?.into_iter();
+        while let Some(res) = results.next() {
             match res {
                 ServerSessionResult::OutboundResponse(pkt) => rtmp_tx.send(pkt).await?,
                 ServerSessionResult::RaisedEvent(ServerSessionEvent::StreamMetadataChanged {
                     app_name: _,
                     stream_key: _,
                     metadata,
-                }) => return Ok(metadata),
+                }) => {
+                    // the first frames can arrive together with the metadata
+                    return Ok((metadata, results.collect()));
+                }
                 _ => {}
             }
         }
@@ -274,8 +290,10 @@ pub struct RtmpSession {
     read: tcp::OwnedReadHalf,
     server_session: ServerSession,
     rtmp_tx: Sender<Packet>,
+    timeout: Option<Duration>,
 
     video_stream: Option<media::Track>,
+    video_config: Vec<u8>,
     video_time: u64,
     prev_video_time: Option<RtmpTimestamp>,
 
@@ -284,7 +302,14 @@ pub struct RtmpSession {
     prev_audio_time: Option<RtmpTimestamp>,
 
     results: VecDeque<ServerSessionResult>,
-    frames: VecDeque<media::Packet>,
+    received: VecDeque<Received>,
+    events: VecDeque<DemuxerEvent>,
+}
+
+/// A frame or a change of the stream, in the order they were received.
+enum Received {
+    Frame(media::Packet),
+    Event(DemuxerEvent),
 }
 
 impl RtmpSession {
@@ -300,8 +325,10 @@ impl RtmpSession {
             read,
             server_session,
             rtmp_tx,
+            timeout: None,
 
             video_stream: None,
+            video_config: Vec::new(),
             video_time: 0,
             prev_video_time: None,
 
@@ -310,10 +337,18 @@ impl RtmpSession {
             prev_audio_time: None,
 
             results,
-            frames: VecDeque::new(),
+            received: VecDeque::new(),
+            events: VecDeque::new(),
         }
     }
 
+    /// Ends the session with an error if nothing is received from the publisher for the given
+    /// duration.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     fn assign_audio_stream(&mut self, tag: flvparse::AudioTag) -> anyhow::Result<()> {
         let codec_info = get_audio_codec_info(&tag)?;
 
@@ -321,6 +356,8 @@ impl RtmpSession {
             id: 1,
             info: Arc::new(codec_info),
             timebase: RTMP_AAC_TIMEBASE,
+            delay: 0,
+            metadata: Default::default(),
         });
 
         Ok(())
@@ -332,7 +369,10 @@ impl RtmpSession {
         packet: flvparse::AvcVideoPacket,
     ) -> anyhow::Result<()> {
         let codec_info = match packet.packet_type {
-            flvparse::AvcPacketType::SequenceHeader => get_codec_from_mp4(&packet)?,
+            flvparse::AvcPacketType::SequenceHeader => {
+                self.video_config = packet.avc_data.to_vec();
+                get_codec_from_mp4(&packet)?
+            }
             flvparse::AvcPacketType::NALU => get_codec_from_nalu(&packet)?,
             _ => anyhow::bail!("Unsupported AVC packet type: {:?}", packet.packet_type),
         };
@@ -341,6 +381,8 @@ impl RtmpSession {
             id: 0,
             info: Arc::new(codec_info),
             timebase: RTMP_TIMEBASE,
+            delay: 0,
+            metadata: Default::default(),
         });
 
         Ok(())
@@ -354,6 +396,25 @@ impl RtmpSession {
             return Ok(());
         }
 
+        // encoders send a new sequence header when e.g. the resolution changes
+        if let flvparse::AvcPacketType::SequenceHeader = video_packet.packet_type {
+            if video_packet.avc_data == self.video_config {
+                return Ok(());
+            }
+            self.video_config = video_packet.avc_data.to_vec();
+
+            let mut track = self.video_stream.clone().unwrap();
+            track.info = Arc::new(get_codec_from_mp4(&video_packet)?);
+
+            debug!("Video parameters changed: {:?}", track.info);
+
+            self.video_stream = Some(track.clone());
+            self.received
+                .push_back(Received::Event(DemuxerEvent::TrackParametersChanged(track)));
+
+            return Ok(());
+        }
+
         if self.prev_video_time.is_none() {
             self.prev_video_time = Some(timestamp);
         }
@@ -377,9 +438,10 @@ impl RtmpSession {
             track: self.video_stream.clone().unwrap(),
             key: video_tag.header.frame_type == flvparse::FrameType::Key,
             buffer: video_packet.avc_data.to_vec().into(),
+            side_data: Vec::new(),
         };
 
-        self.frames.push_back(pkt);
+        self.received.push_back(Received::Frame(pkt));
 
         self.prev_video_time = Some(timestamp);
 
@@ -419,9 +481,10 @@ impl RtmpSession {
             key: true,
             buffer: Bytes::from(audio_tag.body.data[1..].to_vec()).into(),
             track: self.audio_stream.clone().unwrap(),
+            side_data: Vec::new(),
         };
 
-        self.frames.push_back(frame);
+        self.received.push_back(Received::Frame(frame));
 
         self.prev_audio_time = Some(timestamp);
 
@@ -470,10 +533,20 @@ impl RtmpSession {
     async fn fetch(&mut self) -> anyhow::Result<()> {
         use tokio::io::AsyncReadExt;
 
+        if !self.results.is_empty() {
+            let results = std::mem::take(&mut self.results);
+            return self.process_results(results).await;
+        }
+
         let mut buf = [0u8; 1024];
-        let n = self.read.read(&mut buf).await?;
+        let n = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.read.read(&mut buf))
+                .await
+                .map_err(|_| anyhow::anyhow!("Nothing received from publisher for {timeout:?}"))??,
+            None => self.read.read(&mut buf).await?,
+        };
         if n == 0 {
-            anyhow::bail!("EOS");
+            return Err(MediaboxError::EndOfInput.into());
         }
 
         let results = self.server_session.handle_input(&buf[..n])?;
@@ -507,12 +580,53 @@ impl RtmpSession {
 
     pub async fn read_frame(&mut self) -> anyhow::Result<media::Packet> {
         loop {
-            if let Some(frame) = self.frames.pop_front() {
-                return Ok(frame);
+            match self.received.pop_front() {
+                Some(Received::Frame(frame)) => return Ok(frame),
+                Some(Received::Event(event)) => self.events.push_back(event),
+                None => self.fetch().await?,
             }
+        }
+    }
 
-            self.fetch().await?;
+    /// Takes the next change of the stream which happened before the frame last returned by
+    /// [`RtmpSession::read_frame`].
+    pub fn next_event(&mut self) -> Option<DemuxerEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for RtmpSession {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let tracks = self.streams().await?;
+
+        let mut movie = Movie {
+            tracks,
+            ..Default::default()
+        };
+        if let Some(encoder) = &self.meta.encoder {
+            movie.metadata.insert("ENCODER".into(), encoder.clone());
         }
+
+        Ok(movie)
+    }
+
+    async fn read(&mut self) -> crate::Result<media::Packet> {
+        Ok(self.read_frame().await?)
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<DemuxerEvent> {
+        RtmpSession::next_event(self)
+    }
+
+    /// RTMP sessions are accepted by an [RtmpServer] rather than opened from an [Io], so this
+    /// returns a demuxer which fails to start.
+    fn create(_io: Io) -> Box<dyn Demuxer> {
+        Box::new(UnsupportedDemuxer("RTMP session not accepted by an RtmpServer"))
     }
 }
 
@@ -546,63 +660,9 @@ fn get_codec_from_nalu(packet: &flvparse::AvcVideoPacket) -> anyhow::Result<medi
 }
 
 fn get_codec_from_mp4(packet: &flvparse::AvcVideoPacket) -> anyhow::Result<media::MediaInfo> {
-    use h264_reader::avcc::AvcDecoderConfigurationRecord;
-
-    let avc_record: AvcDecoderConfigurationRecord = packet
-        .avc_data
-        .try_into()
-        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
-
-    let sps_bytes_no_header = avc_record
-        .sequence_parameter_sets()
-        .next()
-        .ok_or(anyhow::anyhow!("No SPS found"))
-        .unwrap()
-        .unwrap();
-    let pps_bytes_no_header = avc_record
-        .picture_parameter_sets()
-        .next()
-        .ok_or(anyhow::anyhow!("No PPS found"))
-        .unwrap()
-        .unwrap();
-
-    let mut sps_bytes = BytesMut::new();
-    sps_bytes.put_u8(UnitType::SeqParameterSet.id());
-    sps_bytes.extend_from_slice(&sps_bytes_no_header);
-    let sps_bytes = sps_bytes.freeze();
-
-    let mut pps_bytes = BytesMut::new();
-    pps_bytes.put_u8(UnitType::PicParameterSet.id());
-    pps_bytes.extend_from_slice(&pps_bytes_no_header);
-    let pps_bytes = pps_bytes.freeze().into();
+    let config = AvcDecoderConfig::parse(Bytes::copy_from_slice(packet.avc_data).into())?;
 
-    use h264_reader::{
-        nal::sps::SeqParameterSet,
-        rbsp::{decode_nal, BitReader},
-    };
-    let nal = decode_nal(&sps_bytes_no_header[..]).unwrap();
-    let reader = BitReader::new(nal.as_ref());
-    let sps = SeqParameterSet::from_bits(reader).map_err(|e| anyhow::anyhow!("{:?}", e))?;
-    let (width, height) = sps.pixel_dimensions().unwrap();
-
-    let codec = media::H264Codec {
-        bitstream_format: BitstreamFraming::FourByteLength,
-        profile_indication: avc_record.avc_profile_indication().into(),
-        profile_compatibility: avc_record.profile_compatibility().into(),
-        level_indication: avc_record.avc_level_indication().level_idc(),
-        // FIXME Always uses first set
-        sps: sps_bytes.into(),
-        pps: pps_bytes,
-    };
-
-    Ok(media::MediaInfo {
-        name: "h264",
-        kind: media::MediaKind::Video(media::VideoInfo {
-            width,
-            height,
-            codec: media::VideoCodec::H264(codec),
-        }),
-    })
+    config.media_info()
 }
 
 fn find_parameter_sets(bytes: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
@@ -636,15 +696,8 @@ fn find_parameter_sets(bytes: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
 }
 
 fn get_video_codec_info(sps: Vec<u8>, pps: Vec<u8>) -> anyhow::Result<media::MediaInfo> {
-    let mut sps_bytes = BytesMut::new();
-    sps_bytes.put_u8(UnitType::SeqParameterSet.id());
-    sps_bytes.extend_from_slice(&sps);
-    let sps_bytes = sps_bytes.freeze().into();
-
-    let mut pps_bytes = BytesMut::new();
-    pps_bytes.put_u8(UnitType::PicParameterSet.id());
-    pps_bytes.extend_from_slice(&pps);
-    let pps_bytes = pps_bytes.freeze().into();
+    let sps_bytes = Bytes::copy_from_slice(&sps).into();
+    let pps_bytes = pps.into();
 
     use h264_reader::{
         nal::sps::SeqParameterSet,
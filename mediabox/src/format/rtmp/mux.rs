@@ -0,0 +1,405 @@
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::*;
+use rml_rtmp::{
+    handshake::{Handshake, HandshakeProcessResult, PeerType},
+    sessions::{
+        ClientSession, ClientSessionConfig, ClientSessionError, ClientSessionEvent,
+        ClientSessionResult, PublishRequestType, StreamMetadata,
+    },
+    time::RtmpTimestamp,
+};
+
+use crate::{
+    codec::{
+        h264::AvcDecoderConfig,
+        nal::{convert_bitstream, BitstreamFraming},
+    },
+    format::Muxer,
+    io::Io,
+    muxer, AudioCodec, MediaboxError, Packet, Track, VideoCodec,
+};
+
+use super::RTMP_TIMEBASE;
+
+muxer!("rtmp", RtmpMuxer::create, ["h264", "aac"]);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RtmpPublishError {
+    #[error("Invalid RTMP URI {0:?}, expected rtmp://host/app/key")]
+    InvalidUri(String),
+
+    #[error("At most one H.264 and one AAC track can be published")]
+    InvalidTracks,
+
+    #[error("Server rejected the request: {0}")]
+    Rejected(String),
+
+    #[error("Connection closed by server")]
+    Closed,
+
+    #[error(transparent)]
+    Session(#[from] ClientSessionError),
+}
+
+impl From<RtmpPublishError> for MediaboxError {
+    fn from(error: RtmpPublishError) -> Self {
+        match error {
+            RtmpPublishError::InvalidTracks => MediaboxError::unsupported(error.to_string()),
+            _ => MediaboxError::Other(error.into()),
+        }
+    }
+}
+
+/// Publishes H.264 and AAC tracks to an RTMP server such as a streaming ingest, e.g.
+/// `rtmp://live.example.com/app/stream-key`.
+pub struct RtmpMuxer {
+    io: Io,
+    session: Option<ClientSession>,
+    video: Option<(u32, BitstreamFraming)>,
+    audio: Option<u32>,
+}
+
+impl RtmpMuxer {
+    pub fn new(io: Io) -> Self {
+        RtmpMuxer {
+            io,
+            session: None,
+            video: None,
+            audio: None,
+        }
+    }
+
+    fn create(io: Io) -> Box<dyn Muxer> {
+        Box::new(Self::new(io))
+    }
+
+    async fn send(&mut self, results: Vec<ClientSessionResult>) -> crate::Result<()> {
+        for result in results {
+            if let ClientSessionResult::OutboundResponse(packet) = result {
+                self.io.write(&packet.bytes).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handshake(&mut self) -> crate::Result<Vec<u8>> {
+        let mut handshake = Handshake::new(PeerType::Client);
+        let p0_and_p1 = handshake
+            .generate_outbound_p0_and_p1()
+            .map_err(|e| anyhow::anyhow!("RTMP handshake failed: {e:?}"))?;
+        self.io.write(&p0_and_p1).await?;
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = self.io.read(&mut buf).await?;
+            if n == 0 {
+                return Err(RtmpPublishError::Closed.into());
+            }
+
+            match handshake
+                .process_bytes(&buf[..n])
+                .map_err(|e| anyhow::anyhow!("RTMP handshake failed: {e:?}"))?
+            {
+                HandshakeProcessResult::InProgress { response_bytes } => {
+                    self.io.write(&response_bytes).await?;
+                }
+                HandshakeProcessResult::Completed {
+                    response_bytes,
+                    remaining_bytes,
+                } => {
+                    self.io.write(&response_bytes).await?;
+                    return Ok(remaining_bytes);
+                }
+            }
+        }
+    }
+
+    /// Reads from the server until `accepted` returns true for an event, answering any other
+    /// messages in the meantime.
+    async fn wait_for(
+        &mut self,
+        mut input: Vec<u8>,
+        accepted: impl Fn(&ClientSessionEvent) -> bool,
+    ) -> crate::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let session = self.session.as_mut().expect("Session not created");
+            let results = session
+                .handle_input(&input)
+                .map_err(RtmpPublishError::from)?;
+
+            let mut done = false;
+            let mut outbound = Vec::new();
+            for result in results {
+                match result {
+                    ClientSessionResult::RaisedEvent(
+                        ClientSessionEvent::ConnectionRequestRejected { description },
+                    ) => return Err(RtmpPublishError::Rejected(description).into()),
+                    ClientSessionResult::RaisedEvent(event) => {
+                        trace!("RTMP event: {event:?}");
+                        done |= accepted(&event);
+                    }
+                    result => outbound.push(result),
+                }
+            }
+            self.send(outbound).await?;
+
+            if done {
+                return Ok(());
+            }
+
+            let n = self.io.read(&mut buf).await?;
+            if n == 0 {
+                return Err(RtmpPublishError::Closed.into());
+            }
+            input = buf[..n].to_vec();
+        }
+    }
+
+    fn session(&mut self) -> &mut ClientSession {
+        self.session.as_mut().expect("Muxer not started")
+    }
+}
+
+#[async_trait]
+impl Muxer for RtmpMuxer {
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+        let (app, key) = parse_publish_uri(self.io.uri().as_str())?;
+
+        let mut metadata = StreamMetadata::new();
+        let mut headers = Vec::new();
+        for track in &tracks {
+            if let Some(video) = track.info.video() {
+                let VideoCodec::H264(codec) = &video.codec else {
+                    return Err(RtmpPublishError::InvalidTracks.into());
+                };
+                if self.video.is_some() {
+                    return Err(RtmpPublishError::InvalidTracks.into());
+                }
+
+                metadata.video_width = Some(video.width);
+                metadata.video_height = Some(video.height);
+                metadata.video_codec = Some("avc1".into());
+                headers.push((true, video_sequence_header(&AvcDecoderConfig::from(codec))));
+                self.video = Some((track.id, codec.bitstream_format));
+            } else if let Some(audio) = track.info.audio() {
+                let AudioCodec::Aac(codec) = &audio.codec else {
+                    return Err(RtmpPublishError::InvalidTracks.into());
+                };
+                if self.audio.is_some() {
+                    return Err(RtmpPublishError::InvalidTracks.into());
+                }
+
+                metadata.audio_codec = Some("mp4a".into());
+                metadata.audio_sample_rate = Some(audio.sample_rate);
+                headers.push((false, audio_sequence_header(&codec.extra)));
+                self.audio = Some(track.id);
+            } else {
+                return Err(RtmpPublishError::InvalidTracks.into());
+            }
+        }
+
+        let remaining = self.handshake().await?;
+
+        let config = ClientSessionConfig {
+            tc_url: Some(tc_url(self.io.uri().as_str(), &app)),
+            ..ClientSessionConfig::new()
+        };
+        let (session, results) = ClientSession::new(config).map_err(RtmpPublishError::from)?;
+        self.session = Some(session);
+        self.send(results).await?;
+
+        let request = self
+            .session()
+            .request_connection(app)
+            .map_err(RtmpPublishError::from)?;
+        self.send(vec![request]).await?;
+        self.wait_for(remaining, |e| {
+            matches!(e, ClientSessionEvent::ConnectionRequestAccepted)
+        })
+        .await?;
+
+        let request = self
+            .session()
+            .request_publishing(key, PublishRequestType::Live)
+            .map_err(RtmpPublishError::from)?;
+        self.send(vec![request]).await?;
+        self.wait_for(Vec::new(), |e| {
+            matches!(e, ClientSessionEvent::PublishRequestAccepted)
+        })
+        .await?;
+        debug!("Publishing to {}", self.io.uri().as_str());
+
+        let mut results = vec![self
+            .session()
+            .publish_metadata(&metadata)
+            .map_err(RtmpPublishError::from)?];
+        for (video, header) in headers {
+            let timestamp = RtmpTimestamp::new(0);
+            let result = if video {
+                self.session().publish_video_data(header, timestamp, false)
+            } else {
+                self.session().publish_audio_data(header, timestamp, false)
+            };
+            results.push(result.map_err(RtmpPublishError::from)?);
+        }
+        self.send(results).await?;
+
+        Ok(())
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        let time = packet.time.in_base(RTMP_TIMEBASE);
+        let dts = time.dts.unwrap_or(time.pts);
+        let timestamp = RtmpTimestamp::new(dts as u32);
+
+        let result = match self.video {
+            Some((id, framing)) if id == packet.track.id => {
+                let data =
+                    convert_bitstream(packet.buffer, framing, BitstreamFraming::FourByteLength);
+                let tag = video_tag(packet.key, time.pts as i64 - dts as i64, &data.to_slice());
+
+                self.session()
+                    .publish_video_data(tag, timestamp, !packet.key)
+            }
+            _ if self.audio == Some(packet.track.id) => {
+                let tag = audio_tag(&packet.buffer.to_slice());
+
+                self.session().publish_audio_data(tag, timestamp, false)
+            }
+            _ => {
+                warn!("Dropping packet of unknown track #{}", packet.track.id);
+                return Ok(());
+            }
+        };
+
+        let result = result.map_err(RtmpPublishError::from)?;
+        self.send(vec![result]).await
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        let results = match self.session.as_mut() {
+            Some(session) => session.stop_publishing().map_err(RtmpPublishError::from)?,
+            None => return Ok(()),
+        };
+
+        self.send(results).await
+    }
+
+    fn into_io(self) -> Io {
+        self.io
+    }
+}
+
+/// Splits the path of `rtmp://host/app/key` into the application and the stream key, which can
+/// contain further slashes and a query string.
+fn parse_publish_uri(uri: &str) -> Result<(String, String), RtmpPublishError> {
+    let path = uri
+        .strip_prefix("rtmp://")
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .ok_or_else(|| RtmpPublishError::InvalidUri(uri.to_string()))?;
+
+    match path.split_once('/') {
+        Some((app, key)) if !app.is_empty() && !key.is_empty() => {
+            Ok((app.to_string(), key.to_string()))
+        }
+        _ => Err(RtmpPublishError::InvalidUri(uri.to_string())),
+    }
+}
+
+/// The URL of the application, which servers expect in the `connect` command.
+fn tc_url(uri: &str, app: &str) -> String {
+    let host = uri
+        .strip_prefix("rtmp://")
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_default();
+
+    format!("rtmp://{host}/{app}")
+}
+
+/// An FLV video tag body with the `AVCDecoderConfigurationRecord` of the track.
+fn video_sequence_header(config: &AvcDecoderConfig) -> Bytes {
+    let mut tag = BytesMut::new();
+    tag.put_slice(&[0x17, 0x00, 0x00, 0x00, 0x00]);
+    tag.put_slice(&config.to_span().to_slice());
+
+    tag.freeze()
+}
+
+/// An FLV video tag body with length prefixed NAL units, where `cts` is the composition time
+/// offset in milliseconds.
+fn video_tag(key: bool, cts: i64, data: &[u8]) -> Bytes {
+    let mut tag = BytesMut::with_capacity(data.len() + 5);
+    tag.put_u8(if key { 0x17 } else { 0x27 });
+    tag.put_u8(0x01);
+    tag.put_slice(&(cts as i32).to_be_bytes()[1..]);
+    tag.put_slice(data);
+
+    tag.freeze()
+}
+
+/// An FLV audio tag body with the `AudioSpecificConfig` of the track.
+fn audio_sequence_header(config: &[u8]) -> Bytes {
+    let mut tag = BytesMut::with_capacity(config.len() + 2);
+    // AAC is always signalled as 44 kHz 16 bit stereo, the real format is in the config
+    tag.put_slice(&[0xAF, 0x00]);
+    tag.put_slice(config);
+
+    tag.freeze()
+}
+
+/// An FLV audio tag body with a raw AAC frame.
+fn audio_tag(data: &[u8]) -> Bytes {
+    let mut tag = BytesMut::with_capacity(data.len() + 2);
+    tag.put_slice(&[0xAF, 0x01]);
+    tag.put_slice(data);
+
+    tag.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("rtmp://localhost/live/key", Some(("live", "key")) ; "app and key")]
+    #[test_case("rtmp://localhost:1936/live/a/b?token=1", Some(("live", "a/b?token=1")) ; "nested key")]
+    #[test_case("rtmp://localhost/live", None ; "missing key")]
+    #[test_case("rtsp://localhost/live/key", None ; "wrong scheme")]
+    fn parse_uri(uri: &str, expected: Option<(&str, &str)>) {
+        let parsed = parse_publish_uri(uri).ok();
+
+        assert_eq!(
+            expected.map(|(app, key)| (app.to_string(), key.to_string())),
+            parsed
+        );
+    }
+
+    #[test]
+    fn video_tag_round_trip() {
+        let data = [0, 0, 0, 2, 0x65, 0x88];
+        let tag = video_tag(true, 80, &data);
+
+        let (_, packet) = super::super::parse_video_tag(&tag).unwrap();
+
+        assert_eq!(80, packet.composition_time);
+        assert_eq!(&data[..], packet.avc_data);
+    }
+
+    #[test]
+    fn audio_sequence_header_round_trip() {
+        let config = [0x12, 0x10];
+        let tag = audio_sequence_header(&config);
+
+        let tag = super::super::parse_audio_tag(&tag).unwrap();
+        let info = super::super::get_audio_codec_info(&tag).unwrap();
+
+        let AudioCodec::Aac(codec) = &info.audio().unwrap().codec else {
+            panic!("Expected AAC");
+        };
+        assert_eq!(&config[..], &codec.extra[..]);
+    }
+}
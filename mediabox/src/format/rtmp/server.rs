@@ -0,0 +1,217 @@
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    SinkExt,
+};
+use log::*;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use super::{RtmpRequest, RtmpSession};
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Authenticator = Arc<dyn Fn(&RtmpRequest) -> bool + Send + Sync>;
+
+/// A publisher which connected to an [RtmpServer] and was allowed to publish.
+pub struct RtmpPublish {
+    pub app: String,
+    pub key: String,
+    pub addr: SocketAddr,
+    /// The published stream, which is read as a [`Demuxer`](crate::format::Demuxer).
+    pub session: RtmpSession,
+}
+
+/// Accepts any number of concurrent RTMP publishers, e.g. as the ingest of a streaming service.
+///
+/// Every connection is handled on its own task until the publisher has sent its stream metadata,
+/// so a slow or stalled client doesn't hold up the others.
+pub struct RtmpServer {
+    listener: TcpListener,
+    authenticator: Option<Authenticator>,
+    handshake_timeout: Duration,
+    read_timeout: Option<Duration>,
+}
+
+impl RtmpServer {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> anyhow::Result<Self> {
+        Ok(RtmpServer {
+            listener: TcpListener::bind(addr).await?,
+            authenticator: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            read_timeout: None,
+        })
+    }
+
+    /// Decides whether a publish request is accepted, e.g. by checking the stream key. Rejected
+    /// publishers are disconnected.
+    pub fn with_authenticator(
+        mut self,
+        authenticator: impl Fn(&RtmpRequest) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// How long a publisher has from connecting until it has sent its stream metadata.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Ends sessions which haven't received anything for the given duration.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Starts accepting publishers in the background. The returned stream yields every accepted
+    /// publisher, and the server stops once it is dropped.
+    pub fn serve(self) -> Receiver<RtmpPublish> {
+        let (tx, rx) = channel(16);
+
+        tokio::spawn(self.accept_loop(tx));
+
+        rx
+    }
+
+    async fn accept_loop(self, tx: Sender<RtmpPublish>) {
+        while !tx.is_closed() {
+            let (socket, addr) = match self.listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept RTMP connection: {e}");
+                    continue;
+                }
+            };
+
+            let mut tx = tx.clone();
+            let authenticator = self.authenticator.clone();
+            let handshake_timeout = self.handshake_timeout;
+            let read_timeout = self.read_timeout;
+
+            tokio::spawn(async move {
+                let publish = tokio::time::timeout(
+                    handshake_timeout,
+                    accept_publisher(socket, addr, authenticator, read_timeout),
+                )
+                .await;
+
+                match publish {
+                    Ok(Ok(Some(publish))) => {
+                        let _ = tx.send(publish).await;
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => warn!("RTMP publisher {addr} failed: {e}"),
+                    Err(_) => warn!("RTMP publisher {addr} timed out"),
+                }
+            });
+        }
+
+        trace!("RTMP server stopped");
+    }
+}
+
+async fn accept_publisher(
+    socket: TcpStream,
+    addr: SocketAddr,
+    authenticator: Option<Authenticator>,
+    read_timeout: Option<Duration>,
+) -> anyhow::Result<Option<RtmpPublish>> {
+    let request = RtmpRequest::from_socket(socket, addr).await?;
+
+    if let Some(authenticator) = authenticator {
+        if !authenticator(&request) {
+            info!(
+                "Rejected RTMP publisher {addr} for {}/{}",
+                request.app(),
+                request.key()
+            );
+            return Ok(None);
+        }
+    }
+
+    let app = request.app().to_string();
+    let key = request.key().to_string();
+    debug!("Accepted RTMP publisher {addr} for {app}/{key}");
+
+    let mut session = request.authenticate().await?;
+    if let Some(timeout) = read_timeout {
+        session = session.with_timeout(timeout);
+    }
+
+    Ok(Some(RtmpPublish {
+        app,
+        key,
+        addr,
+        session,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        format::{rtmp::RtmpMuxer, Demuxer, Muxer},
+        io::Io,
+        test,
+    };
+
+    #[test_case("key", true ; "accepted")]
+    #[test_case("wrong", false ; "rejected")]
+    #[tokio::test]
+    async fn publish(key: &str, accepted: bool) {
+        let server = RtmpServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_authenticator(|request| request.key() == "key");
+        let addr = server.local_addr().unwrap();
+        let mut publishers = server.serve();
+
+        let io = Io::connect_tcp(format!("rtmp://{addr}/live/{key}"), 1935)
+            .await
+            .unwrap();
+        let mut muxer = RtmpMuxer::new(io);
+        let track = test::aac_track();
+
+        let started = muxer.start(vec![track.clone()]).await;
+        assert_eq!(accepted, started.is_ok());
+        if !accepted {
+            return;
+        }
+
+        muxer
+            .write(test::packet(&track, 1024, Some(1024), vec![0x21, 0x10]))
+            .await
+            .unwrap();
+
+        let mut publish = publishers.next().await.unwrap();
+        assert_eq!(("live", "key"), (&publish.app[..], &publish.key[..]));
+
+        let movie = publish.session.start().await.unwrap();
+        assert_eq!(
+            vec!["aac"],
+            movie.tracks.iter().map(|t| t.info.name).collect::<Vec<_>>()
+        );
+
+        let pkt = publish.session.read().await.unwrap();
+        assert_eq!(&[0x21, 0x10][..], &pkt.buffer.to_slice()[..]);
+    }
+
+    #[tokio::test]
+    async fn fail_to_start_a_session_without_a_server() {
+        let mut demuxer = RtmpSession::create(Io::null());
+
+        assert!(matches!(
+            demuxer.start().await,
+            Err(crate::MediaboxError::Unsupported(_))
+        ));
+    }
+}
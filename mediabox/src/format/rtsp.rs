@@ -0,0 +1,621 @@
+//! An RTSP client for ingesting H.264 and AAC streams, e.g. from IP cameras.
+//!
+//! Media is received as RTP interleaved on the RTSP connection, so only a single TCP connection
+//! to the server is needed.
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use log::*;
+
+use std::{
+    collections::VecDeque,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    codec::{
+        aac::AudioSpecificConfig,
+        nal::{get_codec_from_parameter_sets, BitstreamFraming},
+    },
+    demuxer,
+    format::{Demuxer, Movie},
+    io::Io,
+    AacCodec, AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, MediaboxError,
+    Packet, SoundType, Span, Track,
+};
+
+pub mod rtp;
+pub mod sdp;
+
+use rtp::{AacDepacketizer, Depacketizer, H264Depacketizer, RtpPacket, Timeline};
+use sdp::{MediaDescription, SessionDescription};
+
+demuxer!("rtsp", RtspDemuxer::create, RtspDemuxer::probe, ["rtsp://"]);
+
+const USER_AGENT: &str = concat!("mediabox/", env!("CARGO_PKG_VERSION"));
+const READ_SIZE: usize = 64 * 1024;
+
+/// The session timeout assumed when the server does not specify one.
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RtspError {
+    #[error("Invalid RTSP message")]
+    InvalidMessage,
+
+    #[error("{method} request failed: {status} {reason}")]
+    Status {
+        method: &'static str,
+        status: u16,
+        reason: String,
+    },
+
+    #[error("Invalid session description")]
+    InvalidSdp,
+
+    #[error("Missing or invalid format parameter {0:?}")]
+    InvalidParameter(&'static str),
+
+    #[error("Invalid RTP packet")]
+    InvalidRtp,
+
+    #[error("Unsupported H.264 packetization type {0}")]
+    UnsupportedPacketization(u8),
+
+    #[error("No supported streams in session description")]
+    NoStreams,
+
+    #[error("Connection closed by server")]
+    EndOfStream,
+}
+
+impl From<RtspError> for MediaboxError {
+    fn from(error: RtspError) -> Self {
+        match error {
+            RtspError::UnsupportedPacketization(_) | RtspError::NoStreams => {
+                MediaboxError::unsupported(error.to_string())
+            }
+            RtspError::EndOfStream => MediaboxError::EndOfInput,
+            _ => MediaboxError::invalid_data(error),
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+}
+
+enum Message {
+    Response(Response),
+    /// A request sent by the server, which are not acted upon.
+    Request(String),
+    /// An interleaved RTP or RTCP packet.
+    Data {
+        channel: u8,
+        payload: Bytes,
+    },
+}
+
+/// Splits the next complete message off the start of the buffer.
+fn parse_message(buffer: &mut BytesMut) -> Result<Option<Message>, RtspError> {
+    if buffer.first() == Some(&b'$') {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+
+        let channel = buffer[1];
+        let len = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        if buffer.len() < 4 + len {
+            return Ok(None);
+        }
+
+        buffer.advance(4);
+        let payload = buffer.split_to(len).freeze();
+
+        return Ok(Some(Message::Data { channel, payload }));
+    }
+
+    let Some(head_len) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+
+    let head = std::str::from_utf8(&buffer[..head_len]).map_err(|_| RtspError::InvalidMessage)?;
+    let mut lines = head.lines();
+    let start_line = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect::<Vec<_>>();
+
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, v)| v.parse::<usize>())
+        .transpose()
+        .map_err(|_| RtspError::InvalidMessage)?
+        .unwrap_or(0);
+
+    if buffer.len() < head_len + 4 + content_length {
+        return Ok(None);
+    }
+
+    buffer.advance(head_len + 4);
+    let body = buffer.split_to(content_length).freeze();
+
+    let Some(status_line) = start_line.strip_prefix("RTSP/1.0 ") else {
+        return Ok(Some(Message::Request(start_line)));
+    };
+
+    let (status, reason) = status_line.split_once(' ').unwrap_or((status_line, ""));
+
+    Ok(Some(Message::Response(Response {
+        status: status.parse().map_err(|_| RtspError::InvalidMessage)?,
+        reason: reason.to_string(),
+        headers,
+        body,
+    })))
+}
+
+/// Resolves the `a=control` attribute of a session description against the base URL.
+fn control_url(base: &str, control: Option<&str>) -> String {
+    match control {
+        None | Some("*") => base.to_string(),
+        Some(control) if control.starts_with("rtsp://") => control.to_string(),
+        Some(control) => format!("{}/{}", base.trim_end_matches('/'), control),
+    }
+}
+
+/// Creates the track information and depacketizer for a media description, or `None` if the
+/// encoding is not supported.
+fn stream_for_media(
+    media: &MediaDescription,
+) -> Result<Option<(MediaInfo, Depacketizer)>, RtspError> {
+    let parameter = |name: &'static str| {
+        media
+            .format_parameters
+            .get(name)
+            .ok_or(RtspError::InvalidParameter(name))
+    };
+
+    match &media.encoding.to_ascii_uppercase()[..] {
+        "H264" => {
+            let mut sps = None;
+            let mut pps = None;
+            for set in parameter("sprop-parameter-sets")?.split(',') {
+                let nal = base64::decode(set)
+                    .map_err(|_| RtspError::InvalidParameter("sprop-parameter-sets"))?;
+
+                match nal.first().map(|h| h & 0x1f) {
+                    Some(7) => sps = Some(nal),
+                    Some(8) => pps = Some(nal),
+                    _ => {}
+                }
+            }
+
+            let (Some(sps), Some(pps)) = (sps, pps) else {
+                return Err(RtspError::InvalidParameter("sprop-parameter-sets"));
+            };
+
+            let info = get_codec_from_parameter_sets(
+                Span::from(sps),
+                Span::from(pps),
+                BitstreamFraming::FourByteLength,
+            )
+            .map_err(|_| RtspError::InvalidParameter("sprop-parameter-sets"))?;
+
+            Ok(Some((info, Depacketizer::H264(H264Depacketizer::new()))))
+        }
+        "MPEG4-GENERIC" if media.kind == "audio" => {
+            let config = parameter("config")?;
+            let config = (0..config.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(config.get(i..i + 2).unwrap_or_default(), 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| RtspError::InvalidParameter("config"))?;
+            let parsed =
+                AudioSpecificConfig::parse(&config).ok_or(RtspError::InvalidParameter("config"))?;
+
+            let channels = media.channels.unwrap_or(parsed.channel_config as u16);
+            let info = MediaInfo {
+                name: "aac",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: parsed.sample_rate().unwrap_or(media.clock_rate),
+                    sample_bpp: 16,
+                    sound_type: if channels > 1 {
+                        SoundType::Stereo
+                    } else {
+                        SoundType::Mono
+                    },
+                    codec: AudioCodec::Aac(AacCodec { extra: config }),
+                }),
+            };
+
+            let depacketizer = AacDepacketizer::new(
+                bit_length(media, "sizelength", None, 1..=32)?,
+                bit_length(media, "indexlength", Some(0), 0..=32)?,
+                bit_length(media, "indexdeltalength", Some(0), 0..=32)?,
+            );
+
+            Ok(Some((info, Depacketizer::Aac(depacketizer))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reads one of the AU header field lengths of an `mpeg4-generic` stream. The fields are read
+/// as 32 bit integers, and every AU header needs a size, so lengths outside of `valid` are
+/// rejected.
+fn bit_length(
+    media: &MediaDescription,
+    name: &'static str,
+    default: Option<usize>,
+    valid: RangeInclusive<usize>,
+) -> Result<usize, RtspError> {
+    let length = match media.format_parameters.get(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| RtspError::InvalidParameter(name))?,
+        None => default.ok_or(RtspError::InvalidParameter(name))?,
+    };
+
+    if !valid.contains(&length) {
+        return Err(RtspError::InvalidParameter(name));
+    }
+
+    Ok(length)
+}
+
+struct RtspStream {
+    /// The interleaved channel RTP packets are received on, RTCP uses the next channel.
+    channel: u8,
+    track: Track,
+    depacketizer: Depacketizer,
+    timeline: Timeline,
+}
+
+/// A demuxer which receives streams from an RTSP server.
+///
+/// The [Io] must be a connection to the server, as created by [`Io::connect_tcp`] or
+/// [`Io::open`] with an `rtsp://` URL. The URL of the [Io] is used for the requests.
+pub struct RtspDemuxer {
+    io: Io,
+    url: String,
+    cseq: u32,
+    session: Option<String>,
+    session_timeout: Duration,
+    last_request: Instant,
+    buffer: BytesMut,
+    streams: Vec<RtspStream>,
+    packets: VecDeque<Packet>,
+}
+
+impl RtspDemuxer {
+    pub fn new(io: Io) -> Self {
+        RtspDemuxer {
+            url: io.uri().as_str().to_string(),
+            io,
+            cseq: 0,
+            session: None,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            last_request: Instant::now(),
+            buffer: BytesMut::new(),
+            streams: Vec::new(),
+            packets: VecDeque::new(),
+        }
+    }
+
+    async fn next_message(&mut self) -> anyhow::Result<Message> {
+        loop {
+            if let Some(message) = parse_message(&mut self.buffer)? {
+                return Ok(message);
+            }
+
+            self.buffer.reserve(READ_SIZE);
+            if self.io.read_buf(&mut self.buffer).await? == 0 {
+                return Err(RtspError::EndOfStream.into());
+            }
+        }
+    }
+
+    /// Sends a request without waiting for the response, returning its sequence number.
+    async fn send_request(
+        &mut self,
+        method: &'static str,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> anyhow::Result<u32> {
+        use std::fmt::Write;
+
+        self.cseq += 1;
+
+        let mut request = format!(
+            "{method} {url} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: {USER_AGENT}\r\n",
+            self.cseq
+        );
+        if let Some(session) = &self.session {
+            write!(request, "Session: {session}\r\n")?;
+        }
+        for (name, value) in headers {
+            write!(request, "{name}: {value}\r\n")?;
+        }
+        request.push_str("\r\n");
+
+        trace!("Sending RTSP request:\n{request}");
+
+        self.io.write(request.as_bytes()).await?;
+        self.last_request = Instant::now();
+
+        Ok(self.cseq)
+    }
+
+    async fn request(
+        &mut self,
+        method: &'static str,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> anyhow::Result<Response> {
+        let cseq = self.send_request(method, url, headers).await?;
+
+        loop {
+            let response = match self.next_message().await? {
+                Message::Response(response) => response,
+                Message::Request(line) => {
+                    debug!("Ignoring RTSP request from server: {line}");
+                    continue;
+                }
+                Message::Data { .. } => continue,
+            };
+
+            if response.header("CSeq").and_then(|c| c.parse().ok()) != Some(cseq) {
+                continue;
+            }
+
+            if !(200..300).contains(&response.status) {
+                return Err(RtspError::Status {
+                    method,
+                    status: response.status,
+                    reason: response.reason,
+                }
+                .into());
+            }
+
+            return Ok(response);
+        }
+    }
+
+    async fn setup(&mut self, url: &str, channel: u8) -> anyhow::Result<u8> {
+        let transport = format!(
+            "RTP/AVP/TCP;unicast;interleaved={}-{}",
+            channel,
+            channel + 1
+        );
+        let response = self
+            .request("SETUP", url, &[("Transport", &transport)])
+            .await?;
+
+        if let Some(session) = response.header("Session") {
+            let mut parts = session.split(';');
+            self.session = parts.next().map(|s| s.trim().to_string());
+
+            if let Some(timeout) = parts
+                .filter_map(|p| p.trim().strip_prefix("timeout="))
+                .find_map(|t| t.parse().ok())
+            {
+                self.session_timeout = Duration::from_secs(timeout);
+            }
+        }
+
+        // the server may pick other channels than the ones requested
+        let channel = response
+            .header("Transport")
+            .and_then(|t| {
+                t.split(';')
+                    .find_map(|p| p.trim().strip_prefix("interleaved="))
+            })
+            .and_then(|c| c.split('-').next())
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(channel);
+
+        Ok(channel)
+    }
+
+    fn push_rtp(&mut self, channel: u8, payload: Bytes) -> anyhow::Result<()> {
+        let Some(stream) = self.streams.iter_mut().find(|s| s.channel == channel) else {
+            return Ok(());
+        };
+
+        let packet = match RtpPacket::parse(payload) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Dropping RTP packet on channel {channel}: {e}");
+                return Ok(());
+            }
+        };
+
+        for frame in stream.depacketizer.push(packet)? {
+            self.packets.push_back(Packet {
+                time: MediaTime {
+                    pts: stream.timeline.extend(frame.timestamp),
+                    dts: None,
+                    duration: None,
+                    timebase: stream.track.timebase,
+                },
+                key: frame.key,
+                track: stream.track.clone(),
+                buffer: frame.data,
+                side_data: Vec::new(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for RtspDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let url = self.url.clone();
+        let response = self
+            .request("DESCRIBE", &url, &[("Accept", "application/sdp")])
+            .await?;
+
+        let base = response
+            .header("Content-Base")
+            .or_else(|| response.header("Content-Location"))
+            .unwrap_or(&url)
+            .to_string();
+        let text = std::str::from_utf8(&response.body).map_err(|_| RtspError::InvalidSdp)?;
+        let session = SessionDescription::parse(text)?;
+
+        for media in &session.media {
+            let Some((info, depacketizer)) = stream_for_media(media)? else {
+                debug!(
+                    "Skipping unsupported {} stream {:?}",
+                    media.kind, media.encoding
+                );
+                continue;
+            };
+
+            let id = self.streams.len() as u32;
+            let channel = self
+                .setup(&control_url(&base, media.control.as_deref()), id as u8 * 2)
+                .await?;
+
+            self.streams.push(RtspStream {
+                channel,
+                track: Track {
+                    id,
+                    info: Arc::new(info),
+                    timebase: Fraction::new(1, media.clock_rate),
+                    delay: 0,
+                    metadata: Default::default(),
+                },
+                depacketizer,
+                timeline: Timeline::default(),
+            });
+        }
+
+        if self.streams.is_empty() {
+            return Err(RtspError::NoStreams.into());
+        }
+
+        let url = control_url(&base, session.control.as_deref());
+        self.request("PLAY", &url, &[("Range", "npt=0.000-")])
+            .await?;
+        self.url = url;
+
+        Ok(Movie {
+            tracks: self.streams.iter().map(|s| s.track.clone()).collect(),
+            ..Default::default()
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(packet);
+            }
+
+            // servers end sessions without any requests within the timeout
+            if self.last_request.elapsed() > self.session_timeout / 2 {
+                let url = self.url.clone();
+                self.send_request("GET_PARAMETER", &url, &[]).await?;
+            }
+
+            match self.next_message().await? {
+                Message::Data { channel, payload } => self.push_rtp(channel, payload)?,
+                Message::Response(response) if !(200..300).contains(&response.status) => {
+                    warn!(
+                        "RTSP server responded with {} {}",
+                        response.status, response.reason
+                    );
+                }
+                Message::Response(_) => {}
+                Message::Request(line) => debug!("Ignoring RTSP request from server: {line}"),
+            }
+        }
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        if self.session.is_some() {
+            let url = self.url.clone();
+            self.send_request("TEARDOWN", &url, &[]).await?;
+            self.session = None;
+        }
+
+        Ok(())
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(Self::new(io))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn parse_interleaved_and_response() {
+        let mut buffer = BytesMut::from(
+            &b"$\x02\x00\x03abcRTSP/1.0 200 OK\r\nCSeq: 3\r\nContent-Length: 2\r\n\r\nhi$\x00"[..],
+        );
+
+        let Some(Message::Data { channel, payload }) = parse_message(&mut buffer).unwrap() else {
+            panic!("Expected interleaved data");
+        };
+        assert_eq!((2, &b"abc"[..]), (channel, &payload[..]));
+
+        let Some(Message::Response(response)) = parse_message(&mut buffer).unwrap() else {
+            panic!("Expected response");
+        };
+        assert_eq!(
+            (200, Some("3"), &b"hi"[..]),
+            (response.status, response.header("cseq"), &response.body[..])
+        );
+
+        assert!(parse_message(&mut buffer).unwrap().is_none());
+        assert_eq!(&b"$\x00"[..], &buffer[..]);
+    }
+
+    #[test_case(None, "rtsp://cam/live/")]
+    #[test_case(Some("*"), "rtsp://cam/live/")]
+    #[test_case(Some("trackID=1"), "rtsp://cam/live/trackID=1")]
+    #[test_case(Some("rtsp://other/track"), "rtsp://other/track")]
+    fn resolve_control_url(control: Option<&str>, expected: &str) {
+        assert_eq!(expected, control_url("rtsp://cam/live/", control));
+    }
+
+    #[test_case(Some("13"), Some(13) ; "valid")]
+    #[test_case(None, None ; "missing")]
+    #[test_case(Some("0"), None ; "zero")]
+    #[test_case(Some("33"), None ; "too long")]
+    fn validate_size_length(value: Option<&str>, expected: Option<usize>) {
+        let mut media = MediaDescription::default();
+        if let Some(value) = value {
+            media
+                .format_parameters
+                .insert("sizelength".into(), value.into());
+        }
+
+        assert_eq!(
+            expected,
+            bit_length(&media, "sizelength", None, 1..=32).ok()
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! RTP packet parsing and depacketization of H.264 ([RFC 6184]) and AAC ([RFC 3640]) payloads.
+//!
+//! [RFC 6184]: https://www.rfc-editor.org/rfc/rfc6184
+//! [RFC 3640]: https://www.rfc-editor.org/rfc/rfc3640
+
+use bytes::{Bytes, BytesMut};
+use log::*;
+
+use crate::{
+    codec::{
+        aac::AAC_FRAME_SAMPLES,
+        nal::{frame_nal_units, BitstreamFraming},
+    },
+    Span,
+};
+
+use super::RtspError;
+
+const NAL_IDR_SLICE: u8 = 5;
+const NAL_STAP_A: u8 = 24;
+const NAL_FU_A: u8 = 28;
+
+/// A parsed RTP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpPacket {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Bytes,
+}
+
+impl RtpPacket {
+    pub fn parse(data: Bytes) -> Result<Self, RtspError> {
+        if data.len() < 12 || data[0] >> 6 != 2 {
+            return Err(RtspError::InvalidRtp);
+        }
+
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0x0f) as usize;
+
+        let mut start = 12 + csrc_count * 4;
+        if extension {
+            let header = data.get(start..start + 4).ok_or(RtspError::InvalidRtp)?;
+            start += 4 + u16::from_be_bytes([header[2], header[3]]) as usize * 4;
+        }
+
+        let mut end = data.len();
+        if padding {
+            end = end.saturating_sub(data[end - 1] as usize);
+        }
+
+        if start > end {
+            return Err(RtspError::InvalidRtp);
+        }
+
+        Ok(RtpPacket {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7f,
+            sequence: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ssrc: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            payload: data.slice(start..end),
+        })
+    }
+}
+
+/// A complete frame reassembled from one or more RTP packets.
+#[derive(Debug, Clone)]
+pub struct RtpFrame {
+    pub timestamp: u32,
+    pub key: bool,
+    pub data: Span,
+}
+
+pub enum Depacketizer {
+    H264(H264Depacketizer),
+    Aac(AacDepacketizer),
+}
+
+impl Depacketizer {
+    pub fn push(&mut self, packet: RtpPacket) -> Result<Vec<RtpFrame>, RtspError> {
+        match self {
+            Depacketizer::H264(depacketizer) => depacketizer.push(packet),
+            Depacketizer::Aac(depacketizer) => depacketizer.push(packet),
+        }
+    }
+}
+
+/// Reassembles H.264 access units from single NAL unit, STAP-A and FU-A packets. Access units
+/// are emitted with [BitstreamFraming::FourByteLength].
+#[derive(Default)]
+pub struct H264Depacketizer {
+    nal_units: Vec<Span>,
+    timestamp: u32,
+    fragment: Option<BytesMut>,
+    sequence: Option<u16>,
+}
+
+impl H264Depacketizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, packet: RtpPacket) -> Result<Vec<RtpFrame>, RtspError> {
+        let mut frames = Vec::new();
+
+        if let Some(sequence) = self.sequence {
+            if packet.sequence != sequence.wrapping_add(1) && self.fragment.take().is_some() {
+                warn!(
+                    "Lost RTP packets before {}, dropping fragmented NAL unit",
+                    packet.sequence
+                );
+            }
+        }
+        self.sequence = Some(packet.sequence);
+
+        // the marker bit may have been lost with the last packet of the previous access unit
+        if packet.timestamp != self.timestamp {
+            frames.extend(self.finish());
+            self.fragment = None;
+        }
+        self.timestamp = packet.timestamp;
+
+        let payload = packet.payload;
+        let nal_type = payload.first().ok_or(RtspError::InvalidRtp)? & 0x1f;
+
+        match nal_type {
+            1..=23 => self.nal_units.push(payload.into()),
+            NAL_STAP_A => {
+                let mut rest = payload.slice(1..);
+                while rest.len() >= 2 {
+                    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let nal = rest.get(2..2 + len).ok_or(RtspError::InvalidRtp)?;
+
+                    self.nal_units.push(Bytes::copy_from_slice(nal).into());
+                    rest = rest.slice(2 + len..);
+                }
+            }
+            NAL_FU_A => {
+                let header = *payload.get(1).ok_or(RtspError::InvalidRtp)?;
+                let start = header & 0x80 != 0;
+                let end = header & 0x40 != 0;
+
+                if start {
+                    let mut fragment = BytesMut::with_capacity(payload.len() * 4);
+                    fragment.extend_from_slice(&[(payload[0] & 0xe0) | (header & 0x1f)]);
+                    self.fragment = Some(fragment);
+                }
+
+                if let Some(fragment) = &mut self.fragment {
+                    fragment.extend_from_slice(&payload[2..]);
+                }
+
+                if end {
+                    if let Some(fragment) = self.fragment.take() {
+                        self.nal_units.push(fragment.freeze().into());
+                    }
+                }
+            }
+            _ => return Err(RtspError::UnsupportedPacketization(nal_type)),
+        }
+
+        if packet.marker {
+            frames.extend(self.finish());
+        }
+
+        Ok(frames)
+    }
+
+    fn finish(&mut self) -> Option<RtpFrame> {
+        if self.nal_units.is_empty() {
+            return None;
+        }
+
+        let nal_units = std::mem::take(&mut self.nal_units);
+        let key = nal_units
+            .iter()
+            .any(|n| n.to_slice().first().map(|h| h & 0x1f) == Some(NAL_IDR_SLICE));
+
+        Some(RtpFrame {
+            timestamp: self.timestamp,
+            key,
+            data: frame_nal_units(&nal_units, BitstreamFraming::FourByteLength),
+        })
+    }
+}
+
+/// Splits `mpeg4-generic` AAC packets into raw AAC frames using the AU headers.
+pub struct AacDepacketizer {
+    size_length: usize,
+    index_length: usize,
+    index_delta_length: usize,
+    fragment: Option<(u32, usize, BytesMut)>,
+}
+
+impl AacDepacketizer {
+    pub fn new(size_length: usize, index_length: usize, index_delta_length: usize) -> Self {
+        AacDepacketizer {
+            size_length,
+            index_length,
+            index_delta_length,
+            fragment: None,
+        }
+    }
+
+    pub fn push(&mut self, packet: RtpPacket) -> Result<Vec<RtpFrame>, RtspError> {
+        let payload = packet.payload;
+        if payload.len() < 2 {
+            return Err(RtspError::InvalidRtp);
+        }
+
+        let headers_bits = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let headers_len = headers_bits.div_ceil(8);
+        let headers = payload
+            .get(2..2 + headers_len)
+            .ok_or(RtspError::InvalidRtp)?;
+        let mut data = payload.slice(2 + headers_len..);
+
+        let mut sizes = Vec::new();
+        let mut offset = 0;
+        while offset + self.size_length <= headers_bits {
+            sizes.push(read_bits(headers, offset, self.size_length) as usize);
+
+            let start = offset;
+            offset += self.size_length;
+            offset += if sizes.len() == 1 {
+                self.index_length
+            } else {
+                self.index_delta_length
+            };
+
+            // AU headers without any bits would be read forever
+            if offset == start {
+                return Err(RtspError::InvalidRtp);
+            }
+        }
+
+        // a single access unit larger than the MTU is fragmented over several packets
+        if let [size] = sizes[..] {
+            if size > data.len() || self.fragment.is_some() {
+                let (timestamp, size, fragment) = self
+                    .fragment
+                    .get_or_insert_with(|| (packet.timestamp, size, BytesMut::with_capacity(size)));
+                fragment.extend_from_slice(&data);
+
+                if fragment.len() < *size {
+                    return Ok(Vec::new());
+                }
+
+                let timestamp = *timestamp;
+                let (_, _, fragment) = self.fragment.take().unwrap();
+
+                return Ok(vec![RtpFrame {
+                    timestamp,
+                    key: true,
+                    data: fragment.freeze().into(),
+                }]);
+            }
+        }
+
+        let mut frames = Vec::with_capacity(sizes.len());
+        for (i, size) in sizes.into_iter().enumerate() {
+            if size > data.len() {
+                return Err(RtspError::InvalidRtp);
+            }
+
+            frames.push(RtpFrame {
+                timestamp: packet
+                    .timestamp
+                    .wrapping_add(i as u32 * AAC_FRAME_SAMPLES as u32),
+                key: true,
+                data: data.split_to(size).into(),
+            });
+        }
+
+        Ok(frames)
+    }
+}
+
+fn read_bits(data: &[u8], offset: usize, count: usize) -> u32 {
+    (offset..offset + count).fold(0, |value, bit| {
+        let set = data[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        (value << 1) | set as u32
+    })
+}
+
+/// Extends 32-bit RTP timestamps to 64 bits, starting at zero.
+#[derive(Default)]
+pub struct Timeline {
+    last: Option<u32>,
+    current: i64,
+}
+
+impl Timeline {
+    pub fn extend(&mut self, timestamp: u32) -> u64 {
+        if let Some(last) = self.last {
+            self.current += timestamp.wrapping_sub(last) as i32 as i64;
+        }
+        self.last = Some(timestamp);
+
+        // packets reordered before the first one are clamped to the start
+        self.current.max(0) as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn rtp(sequence: u16, timestamp: u32, marker: bool, payload: &[u8]) -> RtpPacket {
+        RtpPacket {
+            marker,
+            payload_type: 96,
+            sequence,
+            timestamp,
+            ssrc: 1,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn parse_packet_with_csrc_and_padding() {
+        let data = [
+            0xa1, 0xe0, 0x00, 0x07, 0x00, 0x00, 0x0b, 0xb8, 0xde, 0xad, 0xbe, 0xef, // header
+            0x00, 0x00, 0x00, 0x01, // csrc
+            0x65, 0x88, // payload
+            0x00, 0x02, // padding
+        ];
+
+        let packet = RtpPacket::parse(Bytes::copy_from_slice(&data)).unwrap();
+
+        assert_eq!(
+            (true, 96, 7, 3000, 0xdeadbeef),
+            (
+                packet.marker,
+                packet.payload_type,
+                packet.sequence,
+                packet.timestamp,
+                packet.ssrc
+            )
+        );
+        assert_eq!(&[0x65, 0x88][..], &packet.payload[..]);
+    }
+
+    #[test]
+    fn reassemble_h264_access_unit() {
+        let mut depacketizer = H264Depacketizer::new();
+
+        let stap_a = [0x18, 0x00, 0x02, 0x67, 0x42, 0x00, 0x02, 0x68, 0xce];
+        let fu_start = [0x7c, 0x85, 0xaa, 0xbb];
+        let fu_end = [0x7c, 0x45, 0xcc];
+
+        assert!(depacketizer
+            .push(rtp(1, 3000, false, &stap_a))
+            .unwrap()
+            .is_empty());
+        assert!(depacketizer
+            .push(rtp(2, 3000, false, &fu_start))
+            .unwrap()
+            .is_empty());
+
+        let frames = depacketizer.push(rtp(3, 3000, true, &fu_end)).unwrap();
+
+        assert_eq!(1, frames.len());
+        assert!(frames[0].key);
+        assert_eq!(
+            &[
+                0, 0, 0, 2, 0x67, 0x42, //
+                0, 0, 0, 2, 0x68, 0xce, //
+                0, 0, 0, 4, 0x65, 0xaa, 0xbb, 0xcc,
+            ][..],
+            &frames[0].data.to_slice()[..]
+        );
+    }
+
+    #[test]
+    fn drop_fragment_after_packet_loss() {
+        let mut depacketizer = H264Depacketizer::new();
+
+        depacketizer
+            .push(rtp(1, 3000, false, &[0x7c, 0x85, 0xaa]))
+            .unwrap();
+        let frames = depacketizer
+            .push(rtp(3, 3000, true, &[0x7c, 0x45, 0xcc]))
+            .unwrap();
+
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn split_aac_access_units() {
+        let mut depacketizer = AacDepacketizer::new(13, 3, 3);
+
+        // two AU headers of 16 bits, with sizes of 2 and 3 bytes
+        let payload = [0x00, 0x20, 0x00, 0x10, 0x00, 0x18, 1, 2, 3, 4, 5];
+        let frames = depacketizer.push(rtp(1, 1000, true, &payload)).unwrap();
+
+        assert_eq!(
+            vec![(1000, vec![1, 2]), (2024, vec![3, 4, 5])],
+            frames
+                .iter()
+                .map(|f| (f.timestamp, f.data.to_slice().to_vec()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reject_aac_headers_without_bits() {
+        let mut depacketizer = AacDepacketizer::new(0, 0, 0);
+
+        let payload = [0x00, 0x10, 0x00, 0x10, 1, 2];
+        assert!(depacketizer.push(rtp(1, 1000, true, &payload)).is_err());
+    }
+
+    #[test]
+    fn reassemble_fragmented_aac_access_unit() {
+        let mut depacketizer = AacDepacketizer::new(13, 3, 3);
+
+        // a single AU header with a size of 4 bytes, split over two packets
+        assert!(depacketizer
+            .push(rtp(1, 1000, false, &[0x00, 0x10, 0x00, 0x20, 1, 2]))
+            .unwrap()
+            .is_empty());
+        let frames = depacketizer
+            .push(rtp(2, 1000, true, &[0x00, 0x10, 0x00, 0x20, 3, 4]))
+            .unwrap();
+
+        assert_eq!(vec![1, 2, 3, 4], frames[0].data.to_slice().to_vec());
+    }
+
+    #[test_case(&[0, 3000, 6000], &[0, 3000, 6000] ; "increasing")]
+    #[test_case(&[u32::MAX - 999, 1000], &[0, 2000] ; "wrapping")]
+    #[test_case(&[3000, 0, 6000], &[0, 0, 3000] ; "reordered")]
+    fn extend_timestamps(timestamps: &[u32], expected: &[u64]) {
+        let mut timeline = Timeline::default();
+
+        assert_eq!(
+            expected,
+            timestamps
+                .iter()
+                .map(|&t| timeline.extend(t))
+                .collect::<Vec<_>>()
+        );
+    }
+}
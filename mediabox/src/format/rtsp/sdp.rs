@@ -0,0 +1,156 @@
+//! The subset of the Session Description Protocol ([RFC 8866]) needed to set up RTSP streams.
+//!
+//! [RFC 8866]: https://www.rfc-editor.org/rfc/rfc8866
+
+use std::collections::HashMap;
+
+use super::RtspError;
+
+/// A session description, as returned by a `DESCRIBE` request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionDescription {
+    /// The session level `a=control` attribute.
+    pub control: Option<String>,
+    pub media: Vec<MediaDescription>,
+}
+
+/// A single `m=` section of a [SessionDescription].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaDescription {
+    /// The media type, e.g. `video` or `audio`.
+    pub kind: String,
+    pub payload_type: u8,
+    /// The encoding name from `a=rtpmap`, e.g. `H264` or `MPEG4-GENERIC`.
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u16>,
+    /// The format parameters from `a=fmtp`, with lowercase keys.
+    pub format_parameters: HashMap<String, String>,
+    pub control: Option<String>,
+}
+
+impl SessionDescription {
+    pub fn parse(text: &str) -> Result<Self, RtspError> {
+        let mut session = SessionDescription::default();
+
+        for line in text.lines().map(str::trim) {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            match key {
+                "m" => session.media.push(parse_media(value)?),
+                "a" => {
+                    let (name, value) = value.split_once(':').unwrap_or((value, ""));
+                    match session.media.last_mut() {
+                        Some(media) => media.parse_attribute(name, value)?,
+                        None if name == "control" => session.control = Some(value.to_string()),
+                        None => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(session)
+    }
+}
+
+impl MediaDescription {
+    fn parse_attribute(&mut self, name: &str, value: &str) -> Result<(), RtspError> {
+        match name {
+            "control" => self.control = Some(value.to_string()),
+            "rtpmap" => {
+                let (_, encoding) = value.split_once(' ').ok_or(RtspError::InvalidSdp)?;
+                let mut parts = encoding.trim().split('/');
+
+                self.encoding = parts.next().unwrap_or_default().to_string();
+                self.clock_rate = parts
+                    .next()
+                    .and_then(|r| r.parse().ok())
+                    .ok_or(RtspError::InvalidSdp)?;
+                self.channels = parts.next().and_then(|c| c.parse().ok());
+            }
+            "fmtp" => {
+                let (_, parameters) = value.split_once(' ').unwrap_or((value, ""));
+
+                self.format_parameters = parameters
+                    .split(';')
+                    .filter_map(|p| p.trim().split_once('='))
+                    .map(|(k, v)| (k.to_lowercase(), v.to_string()))
+                    .collect();
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_media(value: &str) -> Result<MediaDescription, RtspError> {
+    let mut parts = value.split_whitespace();
+    let kind = parts.next().ok_or(RtspError::InvalidSdp)?;
+    let payload_type = parts
+        .nth(2)
+        .and_then(|pt| pt.parse().ok())
+        .ok_or(RtspError::InvalidSdp)?;
+
+    Ok(MediaDescription {
+        kind: kind.to_string(),
+        payload_type,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_camera_description() {
+        let sdp = "v=0\r\n\
+            o=- 0 0 IN IP4 127.0.0.1\r\n\
+            s=Camera\r\n\
+            a=control:*\r\n\
+            m=video 0 RTP/AVP 96\r\n\
+            a=rtpmap:96 H264/90000\r\n\
+            a=fmtp:96 packetization-mode=1;sprop-parameter-sets=Z0IAHpWoLQSZ,aM4G4g==\r\n\
+            a=control:trackID=0\r\n\
+            m=audio 0 RTP/AVP 97\r\n\
+            a=rtpmap:97 MPEG4-GENERIC/44100/2\r\n\
+            a=fmtp:97 streamtype=5; mode=AAC-hbr; config=1210; SizeLength=13\r\n\
+            a=control:trackID=1\r\n";
+
+        let session = SessionDescription::parse(sdp).unwrap();
+
+        assert_eq!(Some("*"), session.control.as_deref());
+        assert_eq!(2, session.media.len());
+
+        let video = &session.media[0];
+        assert_eq!(
+            ("video", 96, "H264", 90000, None),
+            (
+                &video.kind[..],
+                video.payload_type,
+                &video.encoding[..],
+                video.clock_rate,
+                video.channels
+            )
+        );
+        assert_eq!(
+            Some("Z0IAHpWoLQSZ,aM4G4g=="),
+            video
+                .format_parameters
+                .get("sprop-parameter-sets")
+                .map(|s| &s[..])
+        );
+
+        let audio = &session.media[1];
+        assert_eq!(Some(2), audio.channels);
+        assert_eq!(Some("trackID=1"), audio.control.as_deref());
+        assert_eq!(
+            Some("13"),
+            audio.format_parameters.get("sizelength").map(|s| &s[..])
+        );
+    }
+}
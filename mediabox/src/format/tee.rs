@@ -0,0 +1,247 @@
+use async_trait::async_trait;
+use log::*;
+
+use crate::{
+    format::{DemuxerEvent, Movie, Muxer},
+    io::Io,
+    Packet, Track,
+};
+
+/// What a [TeeMuxer] does when one of its outputs fails.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TeeFailurePolicy {
+    /// Fails the whole muxer with the error of the output.
+    #[default]
+    Abort,
+    /// Stops writing to the failed output and continues with the others, until all of them
+    /// have failed.
+    DropSink,
+}
+
+struct TeeSink {
+    muxer: Box<dyn Muxer>,
+    failed: bool,
+}
+
+/// Writes every packet to several muxers, e.g. to archive a stream into a Matroska file while
+/// also serving it over HLS.
+pub struct TeeMuxer {
+    sinks: Vec<TeeSink>,
+    policy: TeeFailurePolicy,
+}
+
+impl TeeMuxer {
+    pub fn new(muxers: Vec<Box<dyn Muxer>>) -> Self {
+        TeeMuxer {
+            sinks: muxers
+                .into_iter()
+                .map(|muxer| TeeSink {
+                    muxer,
+                    failed: false,
+                })
+                .collect(),
+            policy: TeeFailurePolicy::default(),
+        }
+    }
+
+    pub fn with_failure_policy(mut self, policy: TeeFailurePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Whether the output at the given index has been dropped after failing.
+    pub fn has_failed(&self, index: usize) -> bool {
+        self.sinks.get(index).is_some_and(|s| s.failed)
+    }
+
+    /// Applies the failure policy to the result of an output, failing if the policy is to abort
+    /// or no outputs are left.
+    fn handle_result(&mut self, index: usize, result: crate::Result<()>) -> crate::Result<()> {
+        let Err(e) = result else {
+            return Ok(());
+        };
+
+        if self.policy == TeeFailurePolicy::Abort {
+            return Err(e);
+        }
+
+        warn!("Dropping output #{index} of tee muxer: {e}");
+        self.sinks[index].failed = true;
+
+        if self.sinks.iter().all(|s| s.failed) {
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Muxer for TeeMuxer {
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+        for i in 0..self.sinks.len() {
+            let result = self.sinks[i].muxer.start(tracks.clone()).await;
+            self.handle_result(i, result)?;
+        }
+
+        Ok(())
+    }
+
+    async fn start_movie(&mut self, movie: Movie) -> crate::Result<()> {
+        for i in 0..self.sinks.len() {
+            let result = self.sinks[i].muxer.start_movie(movie.clone()).await;
+            self.handle_result(i, result)?;
+        }
+
+        Ok(())
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        for i in 0..self.sinks.len() {
+            if self.sinks[i].failed {
+                continue;
+            }
+
+            let result = self.sinks[i].muxer.write(packet.clone()).await;
+            self.handle_result(i, result)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops every output, even if stopping another one failed.
+    async fn stop(&mut self) -> crate::Result<()> {
+        let mut error = None;
+
+        for i in 0..self.sinks.len() {
+            if self.sinks[i].failed {
+                continue;
+            }
+
+            let result = self.sinks[i].muxer.stop().await;
+            if let Err(e) = self.handle_result(i, result) {
+                error.get_or_insert(e);
+            }
+        }
+
+        error.map_or(Ok(()), Err)
+    }
+
+    async fn handle_event(&mut self, event: &DemuxerEvent) -> crate::Result<()> {
+        for i in 0..self.sinks.len() {
+            if self.sinks[i].failed {
+                continue;
+            }
+
+            let result = self.sinks[i].muxer.handle_event(event).await;
+            self.handle_result(i, result)?;
+        }
+
+        Ok(())
+    }
+
+    /// The outputs write to their own [Io], so this returns [Io::null].
+    fn into_io(self) -> Io {
+        Io::null()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        codec::{SubtitleCodec, SubtitleInfo, WebVttCodec},
+        Fraction, MediaInfo, MediaKind, MediaTime, MediaboxError,
+    };
+    use test_case::test_case;
+
+    /// Counts the packets written to it, failing once it has written `capacity` packets.
+    struct CountingMuxer {
+        capacity: usize,
+        written: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Muxer for CountingMuxer {
+        async fn start(&mut self, _tracks: Vec<Track>) -> crate::Result<()> {
+            Ok(())
+        }
+
+        async fn write(&mut self, _packet: Packet) -> crate::Result<()> {
+            if self.written.load(Ordering::Relaxed) == self.capacity {
+                return Err(MediaboxError::unsupported("more packets"));
+            }
+
+            self.written.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        }
+
+        async fn stop(&mut self) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn into_io(self) -> Io {
+            Io::null()
+        }
+    }
+
+    #[test_case(TeeFailurePolicy::Abort, &[Err(()), Err(())], 1 ; "abort")]
+    #[test_case(TeeFailurePolicy::DropSink, &[Ok(()), Ok(())], 3 ; "drop sink")]
+    #[tokio::test]
+    async fn output_fails(policy: TeeFailurePolicy, results: &[Result<(), ()>], written: usize) {
+        let counters = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+        let muxer = |capacity, written: &Arc<AtomicUsize>| -> Box<dyn Muxer> {
+            Box::new(CountingMuxer {
+                capacity,
+                written: written.clone(),
+            })
+        };
+
+        let mut tee = TeeMuxer::new(vec![muxer(1, &counters[0]), muxer(10, &counters[1])])
+            .with_failure_policy(policy);
+        tee.start(Vec::new()).await.unwrap();
+
+        let mut packet = Packet {
+            time: MediaTime {
+                pts: 0,
+                dts: None,
+                duration: None,
+                timebase: Fraction::new(1, 1000),
+            },
+            key: true,
+            track: Track {
+                id: 1,
+                info: Arc::new(MediaInfo {
+                    name: "webvtt",
+                    kind: MediaKind::Subtitle(SubtitleInfo {
+                        codec: SubtitleCodec::WebVtt(WebVttCodec {
+                            header: "WEBVTT".into(),
+                        }),
+                    }),
+                }),
+                timebase: Fraction::new(1, 1000),
+                delay: 0,
+                metadata: Default::default(),
+            },
+            buffer: Vec::new().into(),
+            side_data: Vec::new(),
+        };
+
+        tee.write(packet.clone()).await.unwrap();
+        for &expected in results {
+            packet.time.pts += 1;
+            assert_eq!(expected, tee.write(packet.clone()).await.map_err(|_| ()));
+        }
+        tee.stop().await.unwrap();
+
+        assert_eq!(policy == TeeFailurePolicy::DropSink, tee.has_failed(0));
+        assert_eq!(1, counters[0].load(Ordering::Relaxed));
+        assert_eq!(written, counters[1].load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,633 @@
+use async_trait::async_trait;
+
+use std::{f32::consts::TAU, sync::Arc, time::Duration};
+
+use crate::{
+    codec::{
+        aac::{AudioSpecificConfig, AAC_FRAME_SAMPLES},
+        nal::{frame_nal_units, get_codec_from_parameter_sets, BitWriter, BitstreamFraming},
+    },
+    format::{Demuxer, Movie},
+    io::Io,
+    time::{from_duration, to_duration},
+    AacCodec, AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, MediaboxError,
+    Packet, PcmCodec, SampleFormat, SoundType, Span, Track,
+};
+
+/// The number of samples per channel in each audio packet.
+const SAMPLES_PER_PACKET: u64 = AAC_FRAME_SAMPLES;
+
+/// The amplitude of the sine wave, about -10 dBFS.
+const SINE_AMPLITUDE: f32 = 0.316;
+
+/// A raw data block of silent AAC-LC stereo, which is valid for every sample rate.
+const AAC_SILENCE: [u8; 9] = [0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00, 0x23, 0x80];
+
+/// The Y'CbCr values of 75% colour bars from left to right: white, yellow, cyan, green, magenta,
+/// red, blue and black.
+const BARS: [[u8; 3]; 8] = [
+    [180, 128, 128],
+    [162, 44, 142],
+    [131, 156, 44],
+    [112, 72, 58],
+    [84, 184, 198],
+    [65, 100, 212],
+    [35, 212, 114],
+    [16, 128, 128],
+];
+
+/// The largest picture that can be generated, in macroblocks. This is the limit of level 5.1.
+const MAX_MACROBLOCKS: u32 = 36864;
+
+/// Bits of `frame_num` in the generated slices.
+const FRAME_NUM_BITS: u32 = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TestSrcError {
+    #[error("No video or audio to generate")]
+    NoSources,
+
+    #[error("Can't generate a {0}x{1} picture")]
+    UnsupportedSize(u32, u32),
+
+    #[error("Invalid frame rate {0}/{1}")]
+    InvalidFrameRate(u32, u32),
+
+    #[error("Unsupported sample rate {0} Hz")]
+    UnsupportedSampleRate(u32),
+}
+
+impl From<TestSrcError> for MediaboxError {
+    fn from(error: TestSrcError) -> Self {
+        MediaboxError::unsupported(error.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Audio {
+    /// A sine wave at the given frequency, as 16-bit PCM.
+    Sine(f32),
+    /// Silent AAC frames.
+    Silence,
+}
+
+/// Synthesizes media instead of reading it, so tests and examples can run without fixture files.
+///
+/// The video is H.264 with 75% colour bars, where the first frame of every second is an IDR
+/// picture of uncompressed macroblocks and the rest are skipped. The audio is either a sine wave
+/// as PCM or silent AAC. Packets of all tracks are returned in timestamp order until the duration
+/// has passed.
+pub struct TestSrcDemuxer {
+    video: Option<(u32, u32, Fraction)>,
+    audio: Option<(Audio, u32)>,
+    duration: Duration,
+    generators: Vec<Generator>,
+}
+
+impl TestSrcDemuxer {
+    /// Creates a demuxer without any tracks, which generates the given duration once tracks are
+    /// added.
+    pub fn new(duration: Duration) -> Self {
+        TestSrcDemuxer {
+            video: None,
+            audio: None,
+            duration,
+            generators: Vec::new(),
+        }
+    }
+
+    /// Adds a H.264 track with colour bars. The width and height must be even.
+    pub fn with_color_bars(mut self, width: u32, height: u32, fps: Fraction) -> Self {
+        self.video = Some((width, height, fps));
+        self
+    }
+
+    /// Adds a stereo PCM track with a sine wave, replacing any other audio.
+    pub fn with_sine(mut self, frequency: f32, sample_rate: u32) -> Self {
+        self.audio = Some((Audio::Sine(frequency), sample_rate));
+        self
+    }
+
+    /// Adds a stereo AAC track of silence, replacing any other audio.
+    pub fn with_silence(mut self, sample_rate: u32) -> Self {
+        self.audio = Some((Audio::Silence, sample_rate));
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Demuxer for TestSrcDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        self.generators.clear();
+
+        if let Some((width, height, fps)) = self.video {
+            let id = self.generators.len() as u32;
+            self.generators
+                .push(Generator::color_bars(id, width, height, fps)?);
+        }
+
+        if let Some((audio, sample_rate)) = self.audio {
+            let id = self.generators.len() as u32;
+            self.generators
+                .push(Generator::audio(id, audio, sample_rate)?);
+        }
+
+        if self.generators.is_empty() {
+            Err(TestSrcError::NoSources)?;
+        }
+
+        Ok(Movie {
+            tracks: self.generators.iter().map(|g| g.track.clone()).collect(),
+            duration: Some(self.duration),
+            ..Default::default()
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        let duration = self.duration;
+        let generator = self
+            .generators
+            .iter_mut()
+            .filter(|g| g.time() < duration)
+            .min_by_key(|g| g.time())
+            .ok_or(MediaboxError::EndOfInput)?;
+
+        Ok(generator.next(duration))
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    /// Creates ten seconds of 320x240 colour bars at 25 fps with a 1 kHz tone.
+    fn create(_io: Io) -> Box<dyn Demuxer> {
+        Box::new(
+            TestSrcDemuxer::new(Duration::from_secs(10))
+                .with_color_bars(320, 240, Fraction::new(25, 1))
+                .with_sine(1000.0, 48000),
+        )
+    }
+}
+
+enum Source {
+    ColorBars {
+        /// The IDR pictures, with alternating `idr_pic_id` so that consecutive ones differ.
+        keyframes: [Span; 2],
+        macroblocks: u32,
+        /// The number of frames from one IDR picture to the next.
+        gop: u64,
+    },
+    Sine {
+        frequency: f32,
+        sample_rate: u32,
+    },
+    Silence,
+}
+
+struct Generator {
+    track: Track,
+    source: Source,
+    /// The timestamp of the next packet.
+    pts: u64,
+}
+
+impl Generator {
+    fn color_bars(id: u32, width: u32, height: u32, fps: Fraction) -> crate::Result<Self> {
+        let (mb_width, mb_height) = (width.div_ceil(16), height.div_ceil(16));
+        if width == 0
+            || height == 0
+            || !width.is_multiple_of(2)
+            || !height.is_multiple_of(2)
+            || mb_width * mb_height > MAX_MACROBLOCKS
+        {
+            Err(TestSrcError::UnsupportedSize(width, height))?;
+        }
+
+        if fps.numerator == 0 || fps.denominator == 0 {
+            Err(TestSrcError::InvalidFrameRate(
+                fps.numerator,
+                fps.denominator,
+            ))?;
+        }
+
+        let sps = Span::from(sequence_parameter_set(width, height, fps));
+        let pps = Span::from(picture_parameter_set());
+        let info = get_codec_from_parameter_sets(sps, pps, BitstreamFraming::FourByteLength)?;
+        let keyframes = [0, 1].map(|idr_pic_id| idr_slice(width, mb_width, mb_height, idr_pic_id));
+
+        Ok(Generator {
+            track: Track {
+                id,
+                timebase: Fraction::new(fps.denominator, fps.numerator),
+                info: Arc::new(info),
+                delay: 0,
+                metadata: Default::default(),
+            },
+            source: Source::ColorBars {
+                keyframes,
+                macroblocks: mb_width * mb_height,
+                gop: fps.decimal().ceil().max(1.0) as u64,
+            },
+            pts: 0,
+        })
+    }
+
+    fn audio(id: u32, audio: Audio, sample_rate: u32) -> crate::Result<Self> {
+        let (info, source) = match audio {
+            Audio::Sine(frequency) => {
+                if sample_rate == 0 {
+                    Err(TestSrcError::UnsupportedSampleRate(sample_rate))?;
+                }
+
+                let info = MediaInfo {
+                    name: "pcm",
+                    kind: MediaKind::Audio(AudioInfo {
+                        sample_rate,
+                        sample_bpp: 16,
+                        sound_type: SoundType::Stereo,
+                        codec: AudioCodec::Pcm(PcmCodec {
+                            format: SampleFormat::S16Le,
+                        }),
+                    }),
+                };
+
+                (
+                    info,
+                    Source::Sine {
+                        frequency,
+                        sample_rate,
+                    },
+                )
+            }
+            Audio::Silence => {
+                let config = AudioSpecificConfig {
+                    object_type: 2,
+                    frequency_index: AudioSpecificConfig::frequency_index(sample_rate)
+                        .ok_or(TestSrcError::UnsupportedSampleRate(sample_rate))?,
+                    channel_config: 2,
+                };
+
+                let info = MediaInfo {
+                    name: "aac",
+                    kind: MediaKind::Audio(AudioInfo {
+                        sample_rate,
+                        sample_bpp: 16,
+                        sound_type: SoundType::Stereo,
+                        codec: AudioCodec::Aac(AacCodec {
+                            extra: config.to_bytes().to_vec(),
+                        }),
+                    }),
+                };
+
+                (info, Source::Silence)
+            }
+        };
+
+        Ok(Generator {
+            track: Track {
+                id,
+                timebase: Fraction::new(1, sample_rate),
+                info: Arc::new(info),
+                delay: 0,
+                metadata: Default::default(),
+            },
+            source,
+            pts: 0,
+        })
+    }
+
+    fn time(&self) -> Duration {
+        to_duration(self.pts, self.track.timebase)
+    }
+
+    fn next(&mut self, end: Duration) -> Packet {
+        let pts = self.pts;
+
+        let (buffer, duration, key) = match &self.source {
+            Source::ColorBars {
+                keyframes,
+                macroblocks,
+                gop,
+            } => {
+                let frame = pts % gop;
+                let buffer = if frame == 0 {
+                    keyframes[(pts / gop % 2) as usize].clone()
+                } else {
+                    skipped_slice(*macroblocks, frame)
+                };
+
+                (buffer, 1, frame == 0)
+            }
+            Source::Sine {
+                frequency,
+                sample_rate,
+            } => {
+                // the last packet ends exactly at the end
+                let remaining = from_duration(end, self.track.timebase).saturating_sub(pts);
+                let samples = SAMPLES_PER_PACKET.min(remaining.max(1));
+
+                let mut buffer = Vec::with_capacity(samples as usize * 4);
+                for n in pts..pts + samples {
+                    let phase = (n as f64 * *frequency as f64 / *sample_rate as f64).fract();
+                    let sample =
+                        ((phase as f32 * TAU).sin() * SINE_AMPLITUDE * i16::MAX as f32) as i16;
+
+                    buffer.extend_from_slice(&sample.to_le_bytes());
+                    buffer.extend_from_slice(&sample.to_le_bytes());
+                }
+
+                (Span::from(buffer), samples, true)
+            }
+            Source::Silence => (Span::from(&AAC_SILENCE[..]), SAMPLES_PER_PACKET, true),
+        };
+
+        self.pts += duration;
+
+        Packet {
+            time: MediaTime {
+                pts,
+                dts: None,
+                duration: Some(duration),
+                timebase: self.track.timebase,
+            },
+            key,
+            track: self.track.clone(),
+            buffer,
+            side_data: Vec::new(),
+        }
+    }
+}
+
+/// Creates a constrained baseline SPS with the frame rate in its timing info.
+pub(crate) fn sequence_parameter_set(width: u32, height: u32, fps: Fraction) -> Vec<u8> {
+    let (mb_width, mb_height) = (width.div_ceil(16), height.div_ceil(16));
+    let mut w = BitWriter::default();
+
+    w.u(8, 66); // profile_idc
+    w.u(8, 0b1100_0000); // constraint_set0_flag, constraint_set1_flag
+    w.u(8, if mb_width * mb_height <= 8192 { 40 } else { 51 }); // level_idc
+    w.ue(0); // seq_parameter_set_id
+    w.ue(FRAME_NUM_BITS - 4); // log2_max_frame_num_minus4
+    w.ue(2); // pic_order_cnt_type
+    w.ue(1); // max_num_ref_frames
+    w.flag(false); // gaps_in_frame_num_value_allowed_flag
+    w.ue(mb_width - 1);
+    w.ue(mb_height - 1);
+    w.flag(true); // frame_mbs_only_flag
+    w.flag(true); // direct_8x8_inference_flag
+
+    let (crop_right, crop_bottom) = ((mb_width * 16 - width) / 2, (mb_height * 16 - height) / 2);
+    w.flag(crop_right != 0 || crop_bottom != 0);
+    if crop_right != 0 || crop_bottom != 0 {
+        w.ue(0);
+        w.ue(crop_right);
+        w.ue(0);
+        w.ue(crop_bottom);
+    }
+
+    w.flag(true); // vui_parameters_present_flag
+    w.u(4, 0); // aspect ratio, overscan, video signal type and chroma location
+    w.flag(true); // timing_info_present_flag
+    w.u(32, fps.denominator); // num_units_in_tick
+    w.u(32, fps.numerator * 2); // time_scale
+    w.flag(true); // fixed_frame_rate_flag
+    w.u(4, 0); // HRD, pic_struct and bitstream restrictions
+
+    w.finish(0x67)
+}
+
+fn picture_parameter_set() -> Vec<u8> {
+    let mut w = BitWriter::default();
+
+    w.ue(0); // pic_parameter_set_id
+    w.ue(0); // seq_parameter_set_id
+    w.flag(false); // entropy_coding_mode_flag
+    w.flag(false); // bottom_field_pic_order_in_frame_present_flag
+    w.ue(0); // num_slice_groups_minus1
+    w.ue(0); // num_ref_idx_l0_default_active_minus1
+    w.ue(0); // num_ref_idx_l1_default_active_minus1
+    w.flag(false); // weighted_pred_flag
+    w.u(2, 0); // weighted_bipred_idc
+    w.se(0); // pic_init_qp_minus26
+    w.se(0); // pic_init_qs_minus26
+    w.se(0); // chroma_qp_index_offset
+    w.flag(false); // deblocking_filter_control_present_flag
+    w.flag(false); // constrained_intra_pred_flag
+    w.flag(false); // redundant_pic_cnt_present_flag
+
+    w.finish(0x68)
+}
+
+/// Creates an IDR picture of colour bars where every macroblock is stored as I_PCM, so no
+/// encoder is needed.
+fn idr_slice(width: u32, mb_width: u32, mb_height: u32, idr_pic_id: u32) -> Span {
+    let mut w = BitWriter::default();
+
+    w.ue(0); // first_mb_in_slice
+    w.ue(7); // slice_type, I
+    w.ue(0); // pic_parameter_set_id
+    w.u(FRAME_NUM_BITS, 0); // frame_num
+    w.ue(idr_pic_id);
+    w.flag(false); // no_output_of_prior_pics_flag
+    w.flag(false); // long_term_reference_flag
+    w.se(0); // slice_qp_delta
+
+    // the bar of every column, with the padding past the edge using the last one
+    let bar = |x: u32| &BARS[(x * 8 / width).min(7) as usize];
+    let mut samples = Vec::with_capacity(384);
+
+    for _ in 0..mb_height {
+        for mb_x in 0..mb_width {
+            samples.clear();
+            for _ in 0..16 {
+                samples.extend((0..16).map(|x| bar(mb_x * 16 + x)[0]));
+            }
+            for component in 1..3 {
+                for _ in 0..8 {
+                    samples.extend((0..8).map(|x| bar(mb_x * 16 + x * 2)[component]));
+                }
+            }
+
+            w.ue(25); // mb_type, I_PCM
+            w.align();
+            w.bytes(&samples);
+        }
+    }
+
+    frame_nal_units(
+        &[Span::from(w.finish(0x65))],
+        BitstreamFraming::FourByteLength,
+    )
+}
+
+/// Creates a picture which repeats the previous one by skipping every macroblock.
+fn skipped_slice(macroblocks: u32, frame: u64) -> Span {
+    let mut w = BitWriter::default();
+
+    w.ue(0); // first_mb_in_slice
+    w.ue(5); // slice_type, P
+    w.ue(0); // pic_parameter_set_id
+    w.u(FRAME_NUM_BITS, frame as u32 % (1 << FRAME_NUM_BITS)); // frame_num
+    w.flag(false); // num_ref_idx_active_override_flag
+    w.flag(false); // ref_pic_list_modification_flag_l0
+    w.flag(false); // adaptive_ref_pic_marking_mode_flag
+    w.se(0); // slice_qp_delta
+    w.ue(macroblocks); // mb_skip_run
+
+    frame_nal_units(
+        &[Span::from(w.finish(0x41))],
+        BitstreamFraming::FourByteLength,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::VideoCodec;
+    use h264_reader::{
+        nal::{
+            pps::{ParamSetId, PicParameterSet},
+            slice::SliceHeader,
+            sps::SeqParameterSet,
+            NalHeader,
+        },
+        rbsp::{decode_nal, BitRead, BitReader},
+        Context,
+    };
+    use test_case::test_case;
+
+    async fn read_all(demuxer: &mut TestSrcDemuxer) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        loop {
+            match demuxer.read().await {
+                Ok(pkt) => packets.push(pkt),
+                Err(e) if e.is_end_of_input() => return packets,
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    /// Parses the slices with h264-reader and checks the macroblocks of IDR pictures.
+    fn check_slice(ctx: &Context, packet: &Packet, width: u32) {
+        let data = packet.buffer.to_bytes();
+        assert_eq!(
+            data.len() - 4,
+            u32::from_be_bytes(data[..4].try_into().unwrap()) as usize
+        );
+
+        let header = NalHeader::new(data[4]).unwrap();
+        let rbsp = decode_nal(&data[4..]).unwrap();
+        let mut reader = BitReader::new(&rbsp[..]);
+        SliceHeader::from_bits(ctx, &mut reader, header).unwrap();
+
+        let sps = ctx.sps_by_id(ParamSetId::from_u32(0).unwrap()).unwrap();
+        let mb_width = sps.pic_width_in_mbs_minus1 + 1;
+        let macroblocks = mb_width * (sps.pic_height_in_map_units_minus1 + 1);
+
+        if !packet.key {
+            assert_eq!(macroblocks, reader.read_ue("mb_skip_run").unwrap());
+            reader.finish_rbsp().unwrap();
+            return;
+        }
+
+        for mb in 0..macroblocks {
+            assert_eq!(25, reader.read_ue("mb_type").unwrap());
+            while reader.reader().is_none() {
+                assert!(!reader.read_bool("pcm_alignment_zero_bit").unwrap());
+            }
+
+            let data = reader.reader().unwrap();
+            let (samples, rest) = data.split_at(384);
+            *data = rest;
+
+            let x = (mb % mb_width) * 16;
+            let expected = BARS[(x * 8 / width).min(7) as usize];
+            assert_eq!(expected, [samples[0], samples[256], samples[320]]);
+        }
+
+        reader.finish_rbsp().unwrap();
+    }
+
+    #[test_case(320, 240, Fraction::new(25, 1))]
+    #[test_case(100, 50, Fraction::new(30000, 1001))]
+    #[tokio::test]
+    async fn color_bars(width: u32, height: u32, fps: Fraction) {
+        let mut demuxer =
+            TestSrcDemuxer::new(Duration::from_secs(3)).with_color_bars(width, height, fps);
+        let movie = demuxer.start().await.unwrap();
+
+        let MediaKind::Video(video) = &movie.tracks[0].info.kind else {
+            panic!("Not a video track");
+        };
+        let VideoCodec::H264(codec) = &video.codec else {
+            panic!("Not H.264");
+        };
+        assert_eq!((width, height), (video.width, video.height));
+
+        let mut ctx = Context::default();
+        let sps = decode_nal(&codec.sps.to_slice()).unwrap().into_owned();
+        ctx.put_seq_param_set(SeqParameterSet::from_bits(BitReader::new(&sps[..])).unwrap());
+        let pps = decode_nal(&codec.pps.to_slice()).unwrap().into_owned();
+        let pps = PicParameterSet::from_bits(&ctx, BitReader::new(&pps[..])).unwrap();
+        ctx.put_pic_param_set(pps);
+
+        let packets = read_all(&mut demuxer).await;
+        let frames = (3.0 * fps.decimal()).ceil() as usize;
+        assert_eq!(frames, packets.len());
+
+        let gop = fps.decimal().ceil() as usize;
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!((i as u64, i % gop == 0), (packet.time.pts, packet.key));
+            check_slice(&ctx, packet, width);
+        }
+    }
+
+    #[tokio::test]
+    async fn interleaved_tracks() {
+        let mut demuxer = TestSrcDemuxer::new(Duration::from_secs(1))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_sine(440.0, 44100);
+        let movie = demuxer.start().await.unwrap();
+        assert_eq!(2, movie.tracks.len());
+
+        let packets = read_all(&mut demuxer).await;
+        let times = packets
+            .iter()
+            .map(|p| to_duration(p.time.pts, p.time.timebase))
+            .collect::<Vec<_>>();
+        assert!(times.windows(2).all(|t| t[0] <= t[1]));
+
+        let audio = packets
+            .iter()
+            .filter(|p| p.track.id == 1)
+            .collect::<Vec<_>>();
+        assert_eq!(25, packets.len() - audio.len());
+
+        // the last packet of audio is cut short to end at exactly one second
+        let samples = audio.iter().map(|p| p.time.duration.unwrap()).sum::<u64>();
+        let bytes = audio.iter().map(|p| p.buffer.len()).sum::<usize>();
+        assert_eq!((44100, 44100 * 4), (samples, bytes));
+    }
+
+    #[test_case(48000 => matches Ok(_))]
+    #[test_case(44100 => matches Ok(_))]
+    #[test_case(12345 => matches Err(_))]
+    #[tokio::test]
+    async fn silence(sample_rate: u32) -> crate::Result<()> {
+        let mut demuxer = TestSrcDemuxer::new(Duration::from_millis(100)).with_silence(sample_rate);
+        let movie = demuxer.start().await?;
+        assert_eq!("aac", movie.tracks[0].info.name);
+
+        let packets = read_all(&mut demuxer).await;
+        let frames = (sample_rate as u64).div_ceil(10 * SAMPLES_PER_PACKET);
+        assert_eq!(frames as usize, packets.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_sources() {
+        let mut demuxer = TestSrcDemuxer::new(Duration::from_secs(1));
+        assert!(demuxer.start().await.is_err());
+    }
+}
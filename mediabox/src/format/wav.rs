@@ -0,0 +1,470 @@
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, BytesMut};
+use log::*;
+
+use std::{io::SeekFrom, sync::Arc};
+
+use crate::{
+    demuxer,
+    format::{Demuxer, Movie, Muxer, ProbeResult},
+    io::{read_up_to, Io},
+    muxer,
+    time::to_duration,
+    AudioCodec, AudioInfo, Fraction, MediaInfo, MediaKind, MediaTime, MediaboxError, Packet,
+    PcmCodec, SampleFormat, SoundType, Track,
+};
+
+demuxer!("wav", WavDemuxer::create, WavDemuxer::probe, ["*.wav"]);
+muxer!("wav", WavMuxer::create, ["pcm"]);
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// The number of samples per channel in each packet.
+const SAMPLES_PER_PACKET: usize = 1024;
+
+/// The size of the header written by [WavMuxer], up to the start of the samples.
+const HEADER_LEN: u32 = 44;
+
+/// The chunk size used by streams whose length is not known up front.
+const UNKNOWN_SIZE: u32 = u32::MAX;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WavError {
+    #[error("Not a RIFF WAVE file")]
+    InvalidHeader,
+
+    #[error("No fmt chunk before the data chunk")]
+    MissingFormat,
+
+    #[error("Unsupported WAVE format 0x{0:04x} with {1} bits per sample")]
+    UnsupportedFormat(u16, u16),
+
+    #[error("Unsupported channel count {0}")]
+    UnsupportedChannels(u16),
+
+    #[error("Only a single PCM track is allowed.")]
+    InvalidTracks,
+
+    #[error("{0:?} samples can't be stored in WAV")]
+    UnsupportedSampleFormat(SampleFormat),
+}
+
+impl From<WavError> for MediaboxError {
+    fn from(error: WavError) -> Self {
+        match error {
+            WavError::InvalidHeader | WavError::MissingFormat => MediaboxError::invalid_data(error),
+            _ => MediaboxError::unsupported(error.to_string()),
+        }
+    }
+}
+
+/// A demuxer for uncompressed audio in RIFF WAVE files.
+pub struct WavDemuxer {
+    io: Io,
+    track: Option<Track>,
+    /// The size of one sample for all channels.
+    block_align: usize,
+    /// The amount of sample data left, unknown for streams.
+    remaining: Option<u64>,
+    pts: u64,
+}
+
+impl WavDemuxer {
+    pub fn new(io: Io) -> Self {
+        WavDemuxer {
+            io,
+            track: None,
+            block_align: 0,
+            remaining: None,
+            pts: 0,
+        }
+    }
+
+    async fn read_chunk_header(&mut self) -> anyhow::Result<([u8; 4], u32)> {
+        let mut header = [0u8; 8];
+        self.io.read_exact(&mut header).await?;
+
+        let mut header = &header[..];
+        let mut id = [0u8; 4];
+        header.copy_to_slice(&mut id);
+
+        Ok((id, header.get_u32_le()))
+    }
+}
+
+/// Parses the contents of a `fmt ` chunk.
+fn parse_format(mut data: &[u8]) -> Result<AudioInfo, WavError> {
+    if data.len() < 16 {
+        return Err(WavError::InvalidHeader);
+    }
+
+    let mut format_tag = data.get_u16_le();
+    let channels = data.get_u16_le();
+    let sample_rate = data.get_u32_le();
+    let _byte_rate = data.get_u32_le();
+    let _block_align = data.get_u16_le();
+    let bits_per_sample = data.get_u16_le();
+
+    // the actual format is the start of the sub format GUID
+    if format_tag == WAVE_FORMAT_EXTENSIBLE && data.len() >= 10 {
+        format_tag = (&data[8..]).get_u16_le();
+    }
+
+    let format = match (format_tag, bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8) => SampleFormat::U8,
+        (WAVE_FORMAT_PCM, 16) => SampleFormat::S16Le,
+        (WAVE_FORMAT_PCM, 24) => SampleFormat::S24Le,
+        (WAVE_FORMAT_PCM, 32) => SampleFormat::S32Le,
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => SampleFormat::F32Le,
+        (WAVE_FORMAT_IEEE_FLOAT, 64) => SampleFormat::F64Le,
+        _ => return Err(WavError::UnsupportedFormat(format_tag, bits_per_sample)),
+    };
+
+    let sound_type = match channels {
+        1 => SoundType::Mono,
+        2 => SoundType::Stereo,
+        _ => return Err(WavError::UnsupportedChannels(channels)),
+    };
+
+    Ok(AudioInfo {
+        sample_rate,
+        sample_bpp: bits_per_sample as u32,
+        sound_type,
+        codec: AudioCodec::Pcm(PcmCodec { format }),
+    })
+}
+
+#[async_trait(?Send)]
+impl Demuxer for WavDemuxer {
+    async fn start(&mut self) -> crate::Result<Movie> {
+        let (riff, _) = self.read_chunk_header().await?;
+        let mut wave = [0u8; 4];
+        self.io.read_exact(&mut wave).await?;
+
+        if &riff != b"RIFF" || &wave != b"WAVE" {
+            Err(WavError::InvalidHeader)?;
+        }
+
+        let mut audio = None;
+        loop {
+            let (id, size) = self.read_chunk_header().await?;
+
+            match &id {
+                b"fmt " => {
+                    let mut data = vec![0u8; size as usize];
+                    self.io.read_exact(&mut data).await?;
+                    audio = Some(parse_format(&data)?);
+
+                    self.io.skip(size as u64 & 1).await?;
+                }
+                b"data" => {
+                    // streaming writers leave the size as 0 or the largest possible value
+                    self.remaining = (size != 0 && size != UNKNOWN_SIZE).then_some(size as u64);
+                    break;
+                }
+                _ => {
+                    debug!("Skipping {:?} chunk", String::from_utf8_lossy(&id));
+
+                    // chunks are padded to an even size
+                    self.io.skip(size as u64 + (size as u64 & 1)).await?;
+                }
+            }
+        }
+
+        let audio = audio.ok_or(WavError::MissingFormat)?;
+        let AudioCodec::Pcm(PcmCodec { format }) = audio.codec else {
+            unreachable!()
+        };
+
+        debug!("WAVE stream: {audio:?}");
+
+        self.block_align = format.bytes_per_sample() * audio.sound_type.channel_count() as usize;
+
+        let track = Track {
+            id: 0,
+            timebase: Fraction::new(1, audio.sample_rate),
+            info: Arc::new(MediaInfo {
+                name: "pcm",
+                kind: MediaKind::Audio(audio),
+            }),
+            delay: 0,
+            metadata: Default::default(),
+        };
+
+        let duration = self
+            .remaining
+            .map(|size| to_duration(size / self.block_align as u64, track.timebase));
+
+        self.track = Some(track.clone());
+
+        Ok(Movie {
+            tracks: vec![track],
+            duration,
+            ..Default::default()
+        })
+    }
+
+    async fn read(&mut self) -> crate::Result<Packet> {
+        let track = self.track.clone().expect("Demuxer not started");
+
+        let mut size = (SAMPLES_PER_PACKET * self.block_align) as u64;
+        if let Some(remaining) = self.remaining {
+            size = size.min(remaining);
+        }
+
+        let mut buffer = Vec::with_capacity(size as usize);
+        read_up_to(self.io.reader()?, &mut buffer, size as usize).await?;
+
+        // a truncated file can end in the middle of a sample
+        buffer.truncate(buffer.len() - buffer.len() % self.block_align);
+        if buffer.is_empty() {
+            return Err(MediaboxError::EndOfInput);
+        }
+
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= buffer.len() as u64;
+        }
+
+        let samples = (buffer.len() / self.block_align) as u64;
+        let time = MediaTime {
+            pts: self.pts,
+            dts: None,
+            duration: Some(samples),
+            timebase: track.timebase,
+        };
+
+        self.pts += samples;
+
+        Ok(Packet {
+            time,
+            key: true,
+            track,
+            buffer: buffer.into(),
+            side_data: Vec::new(),
+        })
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn create(io: Io) -> Box<dyn Demuxer> {
+        Box::new(Self::new(io))
+    }
+
+    fn probe(data: &[u8]) -> ProbeResult {
+        if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+            ProbeResult::Yup
+        } else if data.len() < 12 && data.starts_with(&b"RIFF"[..data.len().min(4)]) {
+            ProbeResult::NeedMoreData
+        } else {
+            ProbeResult::Unsure
+        }
+    }
+}
+
+/// A muxer writing uncompressed little endian audio to RIFF WAVE files.
+///
+/// The chunk sizes are filled in when stopping if the output is seekable, otherwise they are
+/// left as the largest possible size, like most streaming writers do.
+pub struct WavMuxer {
+    data_len: u64,
+    io: Io,
+}
+
+impl WavMuxer {
+    pub fn new(io: Io) -> Self {
+        WavMuxer { data_len: 0, io }
+    }
+
+    fn create(io: Io) -> Box<dyn Muxer> {
+        Box::new(Self::new(io))
+    }
+}
+
+fn write_header(buf: &mut BytesMut, audio: &AudioInfo, format: SampleFormat, data_len: u32) {
+    let channels = audio.sound_type.channel_count();
+    let block_align = format.bytes_per_sample() as u16 * channels;
+    let format_tag = if format.is_float() {
+        WAVE_FORMAT_IEEE_FLOAT
+    } else {
+        WAVE_FORMAT_PCM
+    };
+
+    buf.put_slice(b"RIFF");
+    buf.put_u32_le(data_len.saturating_add(HEADER_LEN - 8));
+    buf.put_slice(b"WAVE");
+
+    buf.put_slice(b"fmt ");
+    buf.put_u32_le(16);
+    buf.put_u16_le(format_tag);
+    buf.put_u16_le(channels);
+    buf.put_u32_le(audio.sample_rate);
+    buf.put_u32_le(audio.sample_rate * block_align as u32); // byte_rate
+    buf.put_u16_le(block_align);
+    buf.put_u16_le(format.bytes_per_sample() as u16 * 8);
+
+    buf.put_slice(b"data");
+    buf.put_u32_le(data_len);
+}
+
+#[async_trait]
+impl Muxer for WavMuxer {
+    async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+        let [track] = &tracks[..] else {
+            Err(WavError::InvalidTracks)?
+        };
+
+        let Some(
+            audio @ AudioInfo {
+                codec: AudioCodec::Pcm(PcmCodec { format }),
+                ..
+            },
+        ) = track.info.audio()
+        else {
+            Err(WavError::InvalidTracks)?
+        };
+
+        if format.is_big_endian() {
+            Err(WavError::UnsupportedSampleFormat(*format))?;
+        }
+
+        let data_len = if self.io.seekable() { 0 } else { UNKNOWN_SIZE };
+
+        let mut header = BytesMut::new();
+        write_header(&mut header, audio, *format, data_len);
+        self.io.write(&header).await?;
+
+        Ok(())
+    }
+
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+        self.data_len += packet.buffer.len() as u64;
+        self.io.write_span(packet.buffer).await?;
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> crate::Result<()> {
+        // chunks are padded to an even size
+        if self.data_len % 2 == 1 {
+            self.io.write(&[0]).await?;
+        }
+
+        if !self.io.seekable() {
+            return Ok(());
+        }
+
+        let data_len = u32::try_from(self.data_len).unwrap_or_else(|_| {
+            warn!("{} B of samples is too large for WAV", self.data_len);
+            UNKNOWN_SIZE
+        });
+        let riff_len = data_len.saturating_add(HEADER_LEN - 8 + data_len % 2);
+
+        self.io.seek(SeekFrom::Start(4)).await?;
+        self.io.write(&riff_len.to_le_bytes()).await?;
+        self.io.seek(SeekFrom::Start(HEADER_LEN as u64 - 4)).await?;
+        self.io.write(&data_len.to_le_bytes()).await?;
+        self.io.seek(SeekFrom::End(0)).await?;
+
+        Ok(())
+    }
+
+    fn into_io(self) -> Io {
+        self.io
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test;
+    use test_case::test_case;
+
+    fn pcm_track(format: SampleFormat) -> Track {
+        Track {
+            id: 0,
+            info: Arc::new(MediaInfo {
+                name: "pcm",
+                kind: MediaKind::Audio(AudioInfo {
+                    sample_rate: 8000,
+                    sample_bpp: format.bytes_per_sample() as u32 * 8,
+                    sound_type: SoundType::Stereo,
+                    codec: AudioCodec::Pcm(PcmCodec { format }),
+                }),
+            }),
+            timebase: Fraction::new(1, 8000),
+            delay: 0,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test_case(Io::memory() ; "seekable")]
+    #[test_case(Io::from_stream(Box::new(Vec::<u8>::new())) ; "stream")]
+    #[tokio::test]
+    async fn write_read_samples(io: Io) {
+        let track = pcm_track(SampleFormat::S16Le);
+        let samples = (0..3000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let packet = Packet {
+            time: MediaTime {
+                pts: 0,
+                dts: None,
+                duration: Some(3000),
+                timebase: track.timebase,
+            },
+            key: true,
+            track: track.clone(),
+            buffer: samples.clone().into(),
+            side_data: Vec::new(),
+        };
+
+        let mut muxer = WavMuxer::new(io);
+        let movie = Movie {
+            tracks: vec![track],
+            ..Default::default()
+        };
+        test::write_movie_and_packets(&mut muxer, movie, &[packet]).await;
+
+        let buffer = muxer.into_io().into_bytes().unwrap();
+        assert!(matches!(WavDemuxer::probe(&buffer), ProbeResult::Yup));
+
+        let mut demuxer = WavDemuxer::new(Io::from_bytes(buffer));
+        let (movie, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        let audio = movie.tracks[0].info.audio().unwrap();
+        assert!(matches!(
+            audio.codec,
+            AudioCodec::Pcm(PcmCodec {
+                format: SampleFormat::S16Le
+            })
+        ));
+        assert_eq!(
+            vec![(0, 1024), (1024, 1024), (2048, 952)],
+            packets
+                .iter()
+                .map(|p| (p.time.pts, p.time.duration.unwrap()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            samples,
+            packets
+                .iter()
+                .flat_map(|p| p.buffer.to_slice().to_vec())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_big_endian_samples() {
+        let mut muxer = WavMuxer::new(Io::memory());
+
+        assert!(muxer
+            .start(vec![pcm_track(SampleFormat::S16Be)])
+            .await
+            .is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use crate::{io::Io, Packet, Track};
+use crate::{io::Io, MediaboxError, Packet, Track};
 
 use super::Muxer;
 
@@ -10,6 +10,12 @@ pub enum WebVttError {
     InvalidTracks,
 }
 
+impl From<WebVttError> for MediaboxError {
+    fn from(error: WebVttError) -> Self {
+        MediaboxError::unsupported(error.to_string())
+    }
+}
+
 pub struct WebVttMuxer {
     track: Option<Track>,
     io: Io,
@@ -17,7 +23,7 @@ pub struct WebVttMuxer {
 
 #[async_trait]
 impl Muxer for WebVttMuxer {
-    async fn start(&mut self, mut tracks: Vec<Track>) -> anyhow::Result<()> {
+    async fn start(&mut self, mut tracks: Vec<Track>) -> crate::Result<()> {
         if tracks.len() != 1 {
             Err(WebVttError::InvalidTracks)?;
         }
@@ -35,13 +41,13 @@ impl Muxer for WebVttMuxer {
         Ok(())
     }
 
-    async fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
+    async fn write(&mut self, packet: Packet) -> crate::Result<()> {
         self.io.write_span(packet.buffer).await?;
 
         Ok(())
     }
 
-    async fn stop(&mut self) -> anyhow::Result<()> {
+    async fn stop(&mut self) -> crate::Result<()> {
         Ok(())
     }
     
@@ -1,4 +1,4 @@
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncSeek, AsyncWrite, BufReader};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, BufReader};
 
 #[cfg(feature = "fs")]
 use tokio::fs::File;
@@ -7,10 +7,18 @@ use anyhow::Context;
 use downcast::{downcast, Any};
 use fluent_uri::Uri;
 
-use std::{io::SeekFrom, path::Path};
+use bytes::Bytes;
+
+use std::{
+    io::{Cursor, SeekFrom},
+    path::Path,
+};
 
 use crate::Span;
 
+#[cfg(feature = "http")]
+pub mod http;
+
 pub trait WriteSeek: Any + AsyncWrite + AsyncSeek + Unpin + Sync + Send + 'static {}
 pub trait Write: Any + AsyncWrite + Unpin + Sync + Send {}
 
@@ -158,10 +166,15 @@ pub enum IoError {
     Misc(#[from] anyhow::Error),
 }
 
+/// The default amount of data returned by [`Io::read_probe`].
+pub const DEFAULT_PROBE_SIZE: usize = 64 * 1024;
+
 pub struct Io {
     uri: Uri<String>,
     writer: Option<Writer>,
     reader: Option<Reader>,
+    probe_size: usize,
+    probe: Vec<u8>,
 }
 
 fn uri_from_path(path: &Path) -> Result<Uri<String>, IoError> {
@@ -171,6 +184,24 @@ fn uri_from_path(path: &Path) -> Result<Uri<String>, IoError> {
     Ok(uri)
 }
 
+/// Reads into `buf` until it holds `size` bytes or the input ends.
+pub(crate) async fn read_up_to<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    size: usize,
+) -> std::io::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    while buf.len() < size {
+        let remaining = (size - buf.len()) as u64;
+        if (&mut *reader).take(remaining).read_to_end(buf).await? == 0 {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(feature = "fs")]
 impl Io {
     pub async fn create_file<P: AsRef<Path>>(path: P) -> Result<Self, IoError> {
@@ -181,6 +212,8 @@ impl Io {
             uri,
             writer: Some(Writer::Seekable(Box::new(file))),
             reader: None,
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
         })
     }
 
@@ -194,6 +227,60 @@ impl Io {
             uri,
             writer: None,
             reader: Some(Reader::Seekable(BufReader::new(Box::new(file)))),
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "net")]
+impl Io {
+    /// Opens a TCP connection to the host of the given URI, using `default_port` if the URI has
+    /// none.
+    pub async fn connect_tcp(uri: String, default_port: u16) -> Result<Self, IoError> {
+        let uri = Uri::parse_from(uri).map_err(|e| e.1)?;
+        let authority = uri
+            .authority()
+            .ok_or_else(|| anyhow::anyhow!("URI {:?} has no host", uri.as_str()))?;
+        let port = match authority.port() {
+            Some(port) => port.parse().context("Invalid port")?,
+            None => default_port,
+        };
+
+        let socket = tokio::net::TcpStream::connect((authority.host().as_str(), port)).await?;
+        socket.set_nodelay(true)?;
+        let (read, write) = socket.into_split();
+
+        Ok(Io {
+            uri,
+            writer: Some(Writer::Stream(Box::new(write))),
+            reader: Some(Reader::Stream(BufReader::new(Box::new(read)))),
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+impl Io {
+    /// Opens an `http://` URL for reading. The input is seekable if the server supports range
+    /// requests, and a stream otherwise.
+    pub async fn open_http(uri: String) -> Result<Self, IoError> {
+        let (reader, seekable) = http::HttpReader::open(&uri).await?;
+        let uri = Uri::parse_from(uri).map_err(|e| e.1)?;
+
+        let reader = if seekable {
+            Reader::Seekable(BufReader::new(Box::new(reader)))
+        } else {
+            Reader::Stream(BufReader::new(Box::new(reader)))
+        };
+
+        Ok(Io {
+            uri,
+            writer: None,
+            reader: Some(reader),
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
         })
     }
 }
@@ -211,6 +298,8 @@ impl Io {
             uri,
             writer: None,
             reader: Some(reader),
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
         })
     }
 }
@@ -221,6 +310,8 @@ impl Io {
             uri: Uri::parse_from(String::new()).unwrap(),
             writer: None,
             reader: None,
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
         }
     }
 
@@ -229,19 +320,90 @@ impl Io {
 
         match uri.scheme().map(|s| s.as_str()) {
             Some("file") | None => {}
+            #[cfg(feature = "rtsp")]
+            Some("rtsp") => return Io::connect_tcp(uri.into_string(), 554).await,
+            #[cfg(feature = "http")]
+            Some("http") => return Io::open_http(uri.into_string()).await,
             Some(scheme) => {
                 return Err(IoError::UnsupportedScheme(scheme.to_string()));
             }
         }
 
-        todo!()
+        #[cfg(feature = "fs")]
+        return Io::open_file(uri.path().as_str()).await;
+
+        #[cfg(not(feature = "fs"))]
+        Err(IoError::UnsupportedScheme("file".to_string()))
     }
 
+    /// Creates an output for the given URI.
+    pub async fn create(uri: String) -> Result<Self, IoError> {
+        let uri = Uri::parse_from(uri).map_err(|e| e.1)?;
+
+        match uri.scheme().map(|s| s.as_str()) {
+            Some("file") | None => {}
+            #[cfg(feature = "rtmp")]
+            Some("rtmp") => return Io::connect_tcp(uri.into_string(), 1935).await,
+            Some(scheme) => {
+                return Err(IoError::UnsupportedScheme(scheme.to_string()));
+            }
+        }
+
+        #[cfg(feature = "fs")]
+        return Io::create_file(uri.path().as_str()).await;
+
+        #[cfg(not(feature = "fs"))]
+        Err(IoError::UnsupportedScheme("file".to_string()))
+    }
+
+    /// Creates a seekable input reading from memory.
+    pub fn from_bytes(bytes: impl Into<Bytes>) -> Self {
+        Io::from_seekable_reader(Box::new(Cursor::new(bytes.into())))
+    }
+
+    /// Creates a seekable output writing to memory. The written data can be retrieved with
+    /// [`Io::into_bytes`].
+    pub fn memory() -> Self {
+        Io::from_seekable_writer(Box::new(Cursor::new(Vec::<u8>::new())))
+    }
+
+    /// Returns the data of an in-memory output created with [`Io::memory`], or with
+    /// [`Io::from_stream`] writing to a `Vec<u8>`.
+    pub fn into_bytes(mut self) -> Result<Bytes, IoError> {
+        let bytes = match self.writer.take().ok_or(IoError::NotWriteable)? {
+            Writer::Seekable(writer) => writer
+                .downcast::<Cursor<Vec<u8>>>()
+                .ok()
+                .map(|cursor| cursor.into_inner()),
+            Writer::Stream(writer) => writer.downcast::<Vec<u8>>().ok().map(|buf| *buf),
+        };
+
+        bytes
+            .map(Bytes::from)
+            .ok_or_else(|| anyhow::anyhow!("Output is not in memory").into())
+    }
+
+    /// Creates an output writing to any seekable sink. Muxers which go back and patch their
+    /// headers, like the MP4 muxer, need one of these.
+    pub fn from_seekable_writer(writer: Box<dyn WriteSeek>) -> Self {
+        Io {
+            uri: Uri::parse_from(String::new()).unwrap(),
+            writer: Some(Writer::Seekable(writer)),
+            reader: None,
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
+        }
+    }
+
+    /// Creates an output writing to a sink which can't seek, e.g. a socket, pipe or HTTP
+    /// response body. Muxers write their output strictly in order to these.
     pub fn from_stream(writer: Box<dyn Write>) -> Self {
         Io {
             uri: Uri::parse_from(String::new()).unwrap(),
             writer: Some(Writer::Stream(writer)),
             reader: None,
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
         }
     }
 
@@ -250,9 +412,25 @@ impl Io {
             uri: Uri::parse_from(String::new()).unwrap(),
             writer: None,
             reader: Some(Reader::Stream(BufReader::new(reader))),
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
         }
     }
 
+    pub fn from_seekable_reader(reader: Box<dyn ReadSeek>) -> Self {
+        Io {
+            uri: Uri::parse_from(String::new()).unwrap(),
+            writer: None,
+            reader: Some(Reader::Seekable(BufReader::new(reader))),
+            probe_size: DEFAULT_PROBE_SIZE,
+            probe: Vec::new(),
+        }
+    }
+
+    pub fn uri(&self) -> &Uri<String> {
+        &self.uri
+    }
+
     pub async fn write_span(&mut self, span: Span) -> Result<(), IoError> {
         use tokio::io::AsyncWriteExt;
 
@@ -289,6 +467,21 @@ impl Io {
         Ok(())
     }
 
+    /// Waits until everything written so far has reached the output, e.g. the file, whose writes
+    /// otherwise complete in the background.
+    pub async fn flush(&mut self) -> Result<(), IoError> {
+        use tokio::io::AsyncWriteExt;
+
+        let writer = self.writer.as_mut().ok_or(IoError::NotWriteable)?;
+
+        match writer {
+            Writer::Seekable(writer) => writer.flush().await?,
+            Writer::Stream(writer) => writer.flush().await?,
+        }
+
+        Ok(())
+    }
+
     pub fn reader(&mut self) -> Result<&mut (dyn AsyncRead + Unpin), IoError> {
         let reader = self.reader.as_mut().ok_or(IoError::NotReadable)?;
 
@@ -298,6 +491,35 @@ impl Io {
         }
     }
 
+    /// Reads whatever is available into `buf`, returning 0 at the end of the input.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        use tokio::io::AsyncReadExt;
+
+        let reader = self.reader.as_mut().ok_or(IoError::NotReadable)?;
+
+        let n = match reader {
+            Reader::Seekable(reader) => reader.read(buf).await?,
+            Reader::Stream(reader) => reader.read(buf).await?,
+        };
+
+        Ok(n)
+    }
+
+    /// Reads whatever is available into the spare capacity of `buf`, returning 0 at the end of
+    /// the input.
+    pub async fn read_buf<B: bytes::BufMut>(&mut self, buf: &mut B) -> Result<usize, IoError> {
+        use tokio::io::AsyncReadExt;
+
+        let reader = self.reader.as_mut().ok_or(IoError::NotReadable)?;
+
+        let n = match reader {
+            Reader::Seekable(reader) => reader.read_buf(buf).await?,
+            Reader::Stream(reader) => reader.read_buf(buf).await?,
+        };
+
+        Ok(n)
+    }
+
     pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError> {
         use tokio::io::AsyncReadExt;
 
@@ -311,15 +533,89 @@ impl Io {
         Ok(())
     }
 
-    pub async fn read_probe(&mut self) -> Result<&[u8], IoError> {
-        let reader = self.reader.as_mut().ok_or(IoError::NotWriteable)?;
+    /// Returns the data buffered by the reader, reading more if the buffer is empty. The data
+    /// stays buffered until it's marked as read with [`Io::consume`], and is empty at the end of
+    /// the input.
+    pub async fn fill_buf(&mut self) -> Result<&[u8], IoError> {
+        use tokio::io::AsyncBufReadExt;
 
-        let inner_bytes = match reader {
+        let reader = self.reader.as_mut().ok_or(IoError::NotReadable)?;
+
+        let buf = match reader {
             Reader::Seekable(reader) => reader.fill_buf().await?,
             Reader::Stream(reader) => reader.fill_buf().await?,
         };
 
-        Ok(inner_bytes)
+        Ok(buf)
+    }
+
+    /// Whether the input has ended, reading more data if none is buffered. Demuxers check this
+    /// in between packets, to tell the end of the input from a truncated one.
+    pub async fn is_at_end(&mut self) -> Result<bool, IoError> {
+        Ok(self.fill_buf().await?.is_empty())
+    }
+
+    /// Marks `amt` bytes of the data returned by [`Io::fill_buf`] as read.
+    pub fn consume(&mut self, amt: usize) {
+        use tokio::io::AsyncBufReadExt;
+
+        match self.reader.as_mut() {
+            Some(Reader::Seekable(reader)) => reader.consume(amt),
+            Some(Reader::Stream(reader)) => reader.consume(amt),
+            None => {}
+        }
+    }
+
+    pub fn probe_size(&self) -> usize {
+        self.probe_size
+    }
+
+    /// Sets the amount of data returned by [`Io::read_probe`].
+    pub fn with_probe_size(mut self, probe_size: usize) -> Self {
+        self.probe_size = probe_size;
+        self
+    }
+
+    /// Returns up to the probe size of data from the current position, without consuming it.
+    ///
+    /// Less data is only returned if the input ends. Seekable inputs are read ahead and seeked
+    /// back, while the data peeked from streams is kept to be read again.
+    pub async fn read_probe(&mut self) -> Result<&[u8], IoError> {
+        self.read_probe_up_to(self.probe_size).await
+    }
+
+    /// Like [`Io::read_probe`], but with a probe size other than the one of the [Io].
+    pub async fn read_probe_up_to(&mut self, probe_size: usize) -> Result<&[u8], IoError> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut probe = std::mem::take(&mut self.probe);
+        probe.clear();
+
+        match self.reader.take().ok_or(IoError::NotReadable)? {
+            Reader::Seekable(mut reader) => {
+                let position = reader.stream_position().await?;
+                let result = read_up_to(&mut reader, &mut probe, probe_size).await;
+                let seek = reader.seek(SeekFrom::Start(position)).await;
+
+                self.reader = Some(Reader::Seekable(reader));
+                result?;
+                seek?;
+            }
+            Reader::Stream(reader) => {
+                probe.extend_from_slice(reader.buffer());
+                let mut inner = reader.into_inner();
+                let result = read_up_to(&mut inner, &mut probe, probe_size).await;
+
+                // the peeked data is read again before the rest of the stream
+                let peeked: Box<dyn Read> = Box::new(Cursor::new(probe.clone()).chain(inner));
+                self.reader = Some(Reader::Stream(BufReader::new(peeked)));
+                result?;
+            }
+        }
+
+        self.probe = probe;
+
+        Ok(&self.probe)
     }
 
     pub async fn skip(&mut self, amt: u64) -> Result<(), IoError> {
@@ -348,6 +644,30 @@ impl Io {
         Ok(pos)
     }
 
+    /// Returns the current position of a seekable reader.
+    pub async fn read_position(&mut self) -> Result<u64, IoError> {
+        use tokio::io::AsyncSeekExt;
+
+        let reader = self.reader.as_mut().ok_or(IoError::NotReadable)?;
+
+        match reader {
+            Reader::Seekable(reader) => Ok(reader.stream_position().await?),
+            Reader::Stream(_) => Err(IoError::NotSeekable),
+        }
+    }
+
+    /// Seeks a seekable reader, discarding any buffered data.
+    pub async fn seek_read(&mut self, pos: SeekFrom) -> Result<u64, IoError> {
+        use tokio::io::AsyncSeekExt;
+
+        let reader = self.reader.as_mut().ok_or(IoError::NotReadable)?;
+
+        match reader {
+            Reader::Seekable(reader) => Ok(reader.seek(pos).await?),
+            Reader::Stream(_) => Err(IoError::NotSeekable),
+        }
+    }
+
     pub fn seekable(&self) -> bool {
         matches!(self.writer, Some(Writer::Seekable(_)))
     }
@@ -388,4 +708,59 @@ mod test {
 
         assert_eq!(expected, *buf);
     }
+
+    #[tokio::test]
+    async fn write_to_pipe() {
+        use tokio::io::AsyncReadExt;
+
+        let (writer, mut reader) = tokio::io::duplex(64);
+
+        let mut io = Io::from_stream(Box::new(writer));
+        assert!(!io.seekable());
+        io.write(b"hello").await.unwrap();
+        drop(io);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+
+        assert_eq!(b"hello", &buf[..]);
+    }
+
+    #[tokio::test]
+    async fn memory_roundtrip() {
+        let mut io = Io::memory();
+        io.write(b"hello world").await.unwrap();
+        io.seek(SeekFrom::Start(6)).await.unwrap();
+        io.write(b"there").await.unwrap();
+
+        let mut io = Io::from_bytes(io.into_bytes().unwrap());
+        io.seek_read(SeekFrom::Start(6)).await.unwrap();
+
+        let mut buf = [0u8; 5];
+        io.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(b"there", &buf);
+    }
+
+    #[test_case(true ; "seekable")]
+    #[test_case(false ; "stream")]
+    #[tokio::test]
+    async fn read_probe_does_not_consume(seekable: bool) {
+        let data = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+        let reader = Cursor::new(data.clone());
+        let io = if seekable {
+            Io::from_seekable_reader(Box::new(reader))
+        } else {
+            Io::from_reader(Box::new(reader))
+        };
+        let mut io = io.with_probe_size(20_000);
+
+        io.skip(10).await.unwrap();
+        assert_eq!(&data[10..20_010], io.read_probe().await.unwrap());
+        assert_eq!(&data[10..20_010], io.read_probe().await.unwrap());
+
+        let mut buf = vec![0u8; 30_000];
+        io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&data[10..30_010], &buf[..]);
+    }
 }
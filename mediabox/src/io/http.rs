@@ -0,0 +1,292 @@
+//! Reading from `http://` URLs, using range requests for seeking when the server supports them.
+
+use bytes::{Buf, Bytes};
+use hyper::{
+    body::HttpBody,
+    client::{HttpConnector, ResponseFuture},
+    header, Body, Client, Request, Response, StatusCode, Uri,
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use super::IoError;
+
+const MAX_REDIRECTS: usize = 5;
+
+enum State {
+    /// A new request is needed to continue reading from the current position.
+    Idle,
+    Requesting(ResponseFuture),
+    Reading(Body, Bytes),
+    Done,
+}
+
+/// An HTTP response body which can be read as a file.
+///
+/// Seeking is lazy, the request for the new position is only made when reading.
+pub struct HttpReader {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    position: u64,
+    length: Option<u64>,
+    // the response futures are not `Sync`, which is required by `ReadSeek`
+    state: Mutex<State>,
+}
+
+impl HttpReader {
+    /// Requests the start of the resource, following redirects. Returns the reader along with
+    /// whether the server supports range requests.
+    pub async fn open(uri: &str) -> Result<(Self, bool), IoError> {
+        let client = Client::new();
+        let mut uri: Uri = uri
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid HTTP URI {uri:?}: {e}"))?;
+
+        for _ in 0..MAX_REDIRECTS {
+            let response = client
+                .request(range_request(&uri, 0))
+                .await
+                .map_err(io::Error::other)?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .and_then(|l| l.parse().ok())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid redirect from {uri}"))?;
+
+                uri = resolve(&uri, location);
+                continue;
+            }
+
+            let seekable = response.status() == StatusCode::PARTIAL_CONTENT;
+            let length = content_length(&response, seekable);
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!("GET {uri} failed: {}", response.status()).into());
+            }
+
+            let reader = HttpReader {
+                client,
+                uri,
+                position: 0,
+                length,
+                state: Mutex::new(State::Reading(response.into_body(), Bytes::new())),
+            };
+
+            return Ok((reader, seekable));
+        }
+
+        Err(anyhow::anyhow!("Too many redirects for {uri}").into())
+    }
+
+    /// The total length of the resource, if known.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+fn range_request(uri: &Uri, start: u64) -> Request<Body> {
+    Request::get(uri.clone())
+        .header(header::RANGE, format!("bytes={start}-"))
+        .body(Body::empty())
+        .expect("Invalid HTTP request")
+}
+
+/// Resolves a redirect location, which may be relative to the original URI.
+fn resolve(base: &Uri, location: Uri) -> Uri {
+    if location.scheme().is_some() {
+        return location;
+    }
+
+    let mut parts = location.into_parts();
+    parts.scheme = base.scheme().cloned();
+    parts.authority = base.authority().cloned();
+
+    Uri::from_parts(parts).unwrap_or_else(|_| base.clone())
+}
+
+/// The total length of the resource, from either `Content-Range` or `Content-Length`.
+fn content_length(response: &Response<Body>, partial: bool) -> Option<u64> {
+    let headers = response.headers();
+
+    if partial {
+        headers
+            .get(header::CONTENT_RANGE)?
+            .to_str()
+            .ok()?
+            .rsplit_once('/')?
+            .1
+            .parse()
+            .ok()
+    } else {
+        headers
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+}
+
+impl AsyncRead for HttpReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let state = this.state.get_mut().unwrap();
+
+        loop {
+            match state {
+                State::Idle => {
+                    let request = range_request(&this.uri, this.position);
+                    *state = State::Requesting(this.client.request(request));
+                }
+                State::Requesting(response) => {
+                    let response = match Pin::new(response).poll(cx) {
+                        Poll::Ready(response) => response.map_err(io::Error::other)?,
+                        Poll::Pending => return Poll::Pending,
+                    };
+
+                    if response.status() != StatusCode::PARTIAL_CONTENT {
+                        return Poll::Ready(Err(io::Error::other(format!(
+                            "Range request to {} failed: {}",
+                            this.uri,
+                            response.status()
+                        ))));
+                    }
+
+                    *state = State::Reading(response.into_body(), Bytes::new());
+                }
+                State::Reading(_, chunk) if !chunk.is_empty() => {
+                    let n = chunk.len().min(buf.remaining());
+                    buf.put_slice(&chunk[..n]);
+                    chunk.advance(n);
+                    this.position += n as u64;
+
+                    return Poll::Ready(Ok(()));
+                }
+                State::Reading(body, chunk) => match Pin::new(body).poll_data(cx) {
+                    Poll::Ready(Some(data)) => *chunk = data.map_err(io::Error::other)?,
+                    Poll::Ready(None) => *state = State::Done,
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncSeek for HttpReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => this
+                .length
+                .ok_or_else(|| io::Error::other("Length of HTTP resource is unknown"))?
+                .checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
+
+        if position != this.position {
+            *this.state.get_mut().unwrap() = match this.length {
+                Some(length) if position >= length => State::Done,
+                _ => State::Idle,
+            };
+            this.position = position;
+        }
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::Io;
+    use test_case::test_case;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves `data` over HTTP, optionally with support for range requests.
+    async fn serve(data: Vec<u8>, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let data = data.clone();
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        request.push(socket.read_u8().await.unwrap());
+                    }
+
+                    let request = String::from_utf8(request).unwrap();
+                    let start = request
+                        .lines()
+                        .find_map(|l| l.strip_prefix("range: bytes="))
+                        .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok())
+                        .filter(|_| ranges);
+
+                    let head = match start {
+                        Some(start) => format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                            data.len() - 1,
+                            data.len(),
+                            data.len() - start
+                        ),
+                        None => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len()),
+                    };
+
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    let _ = socket.write_all(&data[start.unwrap_or(0)..]).await;
+                });
+            }
+        });
+
+        format!("http://{addr}/file.mkv")
+    }
+
+    #[test_case(true ; "with ranges")]
+    #[test_case(false ; "without ranges")]
+    #[tokio::test]
+    async fn read_and_seek(ranges: bool) {
+        let data = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+        let mut io = Io::open_http(serve(data.clone(), ranges).await)
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 4];
+        io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&data[..4], &buf);
+
+        let seek = io.seek_read(SeekFrom::Start(90_000)).await;
+        assert_eq!(ranges, seek.is_ok());
+
+        if ranges {
+            io.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&data[90_000..90_004], &buf);
+        }
+    }
+}
@@ -2,7 +2,6 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
-use anyhow::Context;
 use codec::{
     CodecDescription, Decoder, DecoderMetadata, Encoder, EncoderMetadata, SubtitleDescription,
 };
@@ -11,46 +10,170 @@ use std::{collections::HashMap, fmt};
 #[cfg(test)]
 mod test;
 
+pub mod cancel;
+pub mod cenc;
+pub mod filter;
 pub mod media;
+#[cfg(feature = "fs")]
+pub mod recorder;
+pub mod remux;
+pub mod simulcast;
+pub mod snapshot;
 pub mod span;
+pub mod time;
 
 pub mod codec;
+pub mod error;
 pub mod format;
 pub mod io;
 
+pub use error::{MediaboxError, Result};
 pub use media::*;
-pub use span::Span;
+pub use remux::{
+    clip, extract_attachment, extract_track, merge, open_input, probe, remux, remux_tracks,
+    remux_with_filter, MovieReport, TrackSelector,
+};
+pub use span::{Span, SpanBuilder};
 
-use format::{DemuxerMetadata, MuxerMetadata, ProbeResult};
+use cancel::CancellationToken;
+use format::{interleave::PacketInterleaver, DemuxerMetadata, MuxerMetadata, ProbeResult};
+use futures::future::{self, Either};
 use io::Io;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// The default score a probe must reach for its demuxer to be chosen.
+const DEFAULT_PROBE_THRESHOLD: f32 = 0.5;
+
+/// The default limit on how much data is probed when a demuxer needs more data to be sure.
+const DEFAULT_MAX_PROBE_SIZE: usize = 1024 * 1024;
 
-#[derive(Default)]
 pub struct MediaContext {
     decoder_meta: HashMap<String, DecoderMetadata>,
     encoder_meta: HashMap<String, EncoderMetadata>,
     demuxer_meta: HashMap<String, DemuxerMetadata>,
     muxer_meta: HashMap<String, MuxerMetadata>,
+    probe_threshold: f32,
+    max_probe_size: usize,
+}
+
+impl Default for MediaContext {
+    fn default() -> Self {
+        MediaContext {
+            decoder_meta: HashMap::new(),
+            encoder_meta: HashMap::new(),
+            demuxer_meta: HashMap::new(),
+            muxer_meta: HashMap::new(),
+            probe_threshold: DEFAULT_PROBE_THRESHOLD,
+            max_probe_size: DEFAULT_MAX_PROBE_SIZE,
+        }
+    }
 }
 
 impl MediaContext {
+    /// Sets the score a [`ProbeResult::Maybe`] must reach for its demuxer to be chosen.
+    pub fn with_probe_threshold(mut self, probe_threshold: f32) -> Self {
+        self.probe_threshold = probe_threshold;
+        self
+    }
+
+    /// Sets how much data may be probed at most when a demuxer reports
+    /// [`ProbeResult::NeedMoreData`].
+    pub fn with_max_probe_size(mut self, max_probe_size: usize) -> Self {
+        self.max_probe_size = max_probe_size;
+        self
+    }
+
     pub fn register_all(&mut self) {
+        self.register_decoders();
+        self.register_encoders();
         self.register_demuxers();
+        self.register_muxers();
+    }
+
+    pub fn register_decoders(&mut self) {
+        let decoders = [
+            codec::ass::DECODER_META,
+            codec::pcm::DECODER_META,
+            codec::rawvideo::DECODER_META,
+            #[cfg(feature = "symphonia")]
+            codec::symphonia::DECODER_META,
+        ];
+
+        for meta in decoders {
+            self.decoder_meta.insert(meta.name.to_string(), meta);
+        }
+
+        // registered last so FFmpeg takes precedence over other decoders for the same codec
+        #[cfg(feature = "ffmpeg")]
+        for meta in codec::ffmpeg::DECODERS {
+            self.decoder_meta.insert(meta.name.to_string(), meta);
+        }
+    }
+
+    pub fn register_encoders(&mut self) {
+        let encoders = [
+            codec::webvtt::ENCODER_META,
+            #[cfg(feature = "ffmpeg")]
+            codec::ffmpeg::H264_ENCODER_META,
+            #[cfg(feature = "rav1e")]
+            codec::rav1e::ENCODER_META,
+        ];
+
+        for meta in encoders {
+            self.encoder_meta.insert(meta.name.to_string(), meta);
+        }
     }
 
     pub fn register_demuxers(&mut self) {
-        let demuxers = [format::mkv::DEMUXER_META];
+        let demuxers = [
+            format::mkv::DEMUXER_META,
+            format::adts::DEMUXER_META,
+            format::flac::DEMUXER_META,
+            format::h264::DEMUXER_META,
+            format::ogg::DEMUXER_META,
+            format::wav::DEMUXER_META,
+            #[cfg(feature = "rtsp")]
+            format::rtsp::DEMUXER_META,
+        ];
 
         for meta in demuxers {
             self.demuxer_meta.insert(meta.name.to_string(), meta);
         }
     }
 
-    pub fn find_decoder_for_track(&self, track: &Track) -> anyhow::Result<Box<dyn Decoder>> {
+    pub fn register_muxers(&mut self) {
+        let muxers = [
+            format::mkv::MUXER_META,
+            format::mkv::webm::MUXER_META,
+            format::mp4::fmp4::MUXER_META,
+            format::mp4::mp4::MUXER_META,
+            format::adts::MUXER_META,
+            format::ass::MUXER_META,
+            format::wav::MUXER_META,
+            #[cfg(feature = "rtmp")]
+            format::rtmp::MUXER_META,
+        ];
+
+        for meta in muxers {
+            self.muxer_meta.insert(meta.name.to_string(), meta);
+        }
+    }
+
+    pub fn find_muxer(&self, name: &str) -> Result<MuxerMetadata> {
+        self.muxer_meta
+            .get(name)
+            .cloned()
+            .ok_or_else(|| MediaboxError::unsupported(format!("No muxer found for name {name:?}")))
+    }
+
+    pub fn find_decoder_for_track(&self, track: &Track) -> Result<Box<dyn Decoder>> {
         let mut decoder = self
             .decoder_meta
             .get(track.info.name)
             .map(|m| m.create())
-            .ok_or_else(|| anyhow::anyhow!("No decoder found for {:?}", track.info.name))?;
+            .ok_or_else(|| {
+                MediaboxError::unsupported(format!("No decoder found for {:?}", track.info.name))
+            })?;
 
         decoder.start(&track.info)?;
 
@@ -61,38 +184,84 @@ impl MediaContext {
         &self,
         name: &str,
         info: &MediaInfo,
-    ) -> anyhow::Result<Box<dyn Encoder>> {
+    ) -> Result<Box<dyn Encoder>> {
         let mut encoder = self.encoder_meta.get(name).map(|m| m.create());
 
         if let Some(ref mut encoder) = &mut encoder {
-            encoder.start(CodecDescription::Subtitle(SubtitleDescription::default()))?;
+            encoder.start(CodecDescription::Subtitle(SubtitleDescription::from_info(
+                info,
+            )))?;
         }
 
-        encoder.ok_or_else(|| anyhow::anyhow!("No encoder found for name {name:?}"))
+        encoder.ok_or_else(|| {
+            MediaboxError::unsupported(format!("No encoder found for name {name:?}"))
+        })
+    }
+
+    /// Creates an encoder without starting it, for encoders which need a [CodecDescription] other
+    /// than for subtitles.
+    pub fn find_encoder(&self, name: &str) -> Result<Box<dyn Encoder>> {
+        self.encoder_meta
+            .get(name)
+            .map(|m| m.create())
+            .ok_or_else(|| {
+                MediaboxError::unsupported(format!("No encoder found for name {name:?}"))
+            })
     }
 
-    pub async fn probe(&self, io: &mut Io) -> anyhow::Result<DemuxerMetadata> {
-        let data = io
-            .read_probe()
-            .await
-            .context("Failed to probe I/O for data")?;
+    pub async fn probe(&self, io: &mut Io) -> Result<DemuxerMetadata> {
+        let uri = io.uri().as_str().to_string();
+
+        // protocol demuxers are selected from the scheme alone, e.g. RTSP servers send nothing
+        // until they receive a request
+        if let Some(meta) = self
+            .demuxer_meta
+            .values()
+            .find(|m| m.probe_uri(&uri) == ProbeResult::Yup)
+        {
+            return Ok(meta.clone());
+        }
 
-        self.find_demuxer(data)
-            .ok_or_else(|| anyhow::anyhow!("Failed to find a demuxer"))
+        let mut probe_size = io.probe_size();
+        loop {
+            let data = io.read_probe_up_to(probe_size).await?;
+
+            // the whole input has been probed if less data than requested was returned
+            let more_data = data.len() >= probe_size && probe_size < self.max_probe_size;
+
+            match self.find_demuxer(&uri, data) {
+                Ok(meta) => return Ok(meta),
+                Err(ProbeResult::NeedMoreData) if more_data => {
+                    probe_size = (probe_size * 4).min(self.max_probe_size);
+                }
+                Err(_) => return Err(MediaboxError::unsupported("Failed to find a demuxer")),
+            }
+        }
     }
 
-    fn find_demuxer(&self, data: &[u8]) -> Option<DemuxerMetadata> {
-        self.demuxer_meta
-            .iter()
-            .map(|(_, m)| (m, m.probe(data)))
+    /// Finds the demuxer with the highest probe score, if it reaches the probe threshold.
+    /// Otherwise the best result is returned, which is [`ProbeResult::NeedMoreData`] if any
+    /// demuxer could decide with more data.
+    fn find_demuxer(&self, uri: &str, data: &[u8]) -> Result<DemuxerMetadata, ProbeResult> {
+        let (meta, result) = self
+            .demuxer_meta
+            .values()
+            .map(|m| (m, m.probe_with_uri(uri, data)))
             .reduce(|accum, m| if accum.1 >= m.1 { accum } else { m })
-            .and_then(|(meta, result)| {
-                if result != ProbeResult::Unsure {
-                    Some(meta.clone())
-                } else {
-                    None
-                }
-            })
+            .ok_or(ProbeResult::Unsure)?;
+
+        match result {
+            ProbeResult::Yup => Ok(meta.clone()),
+            ProbeResult::Maybe(score) if score >= self.probe_threshold => Ok(meta.clone()),
+            _ if self
+                .demuxer_meta
+                .values()
+                .any(|m| m.probe_with_uri(uri, data) == ProbeResult::NeedMoreData) =>
+            {
+                Err(ProbeResult::NeedMoreData)
+            }
+            result => Err(result),
+        }
     }
 }
 
@@ -101,69 +270,202 @@ pub enum Transcode {
         decoder: Box<dyn Decoder>,
         encoder: Box<dyn Encoder>,
     },
+    Audio {
+        decoder: Box<dyn Decoder>,
+        encoder: Box<dyn Encoder>,
+    },
 }
 
+/// How many packets may be queued for a transcoding worker before [PacketTranscoder::process]
+/// waits for it to catch up, and how many transcoded packets the workers may queue before they
+/// wait for them to be taken.
+const WORKER_QUEUE_SIZE: usize = 32;
+
+/// Transcodes the packets of some tracks and passes the packets of the others through.
+///
+/// Every transcoded track gets its own worker thread, so tracks are transcoded in parallel. The
+/// output of the workers and the passed through packets are merged in decode order.
 pub struct PacketTranscoder {
     mapping: HashMap<u32, Transcode>,
+    workers: HashMap<u32, TranscodeWorker>,
+    output_tx: mpsc::Sender<Packet>,
+    output_rx: mpsc::Receiver<Packet>,
+    interleaver: PacketInterleaver,
+    cancel: CancellationToken,
 }
 
 impl PacketTranscoder {
     pub fn new(mapping: HashMap<u32, Transcode>) -> Self {
-        PacketTranscoder { mapping }
+        let (output_tx, output_rx) = mpsc::channel(WORKER_QUEUE_SIZE);
+
+        PacketTranscoder {
+            mapping,
+            workers: HashMap::new(),
+            output_tx,
+            output_rx,
+            interleaver: PacketInterleaver::new(&[]),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Stops queueing packets for the workers once `cancel` is cancelled, so that
+    /// [PacketTranscoder::finish] only has to flush what was queued before.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
     }
 }
 
 impl PacketTranscoder {
-    // TODO: implement some queue and sort output on DTS
-    pub async fn process<F: FnMut(Packet) + Send + 'static>(
-        &mut self,
-        pkt: Packet,
-        mut func: F,
-    ) -> anyhow::Result<()> {
+    /// Registers the tracks of the input, so that no packet is released before every track,
+    /// including the ones still being transcoded, has output queued. Without this, the output
+    /// of a slow worker may end up after later packets of the other tracks.
+    pub fn start(&mut self, tracks: &[Track]) {
+        self.interleaver = PacketInterleaver::new(tracks);
+    }
+
+    /// Queues a packet for transcoding and calls `func` with every packet that is ready, in
+    /// decode order.
+    pub async fn process<F: FnMut(Packet)>(&mut self, pkt: Packet, func: F) -> anyhow::Result<()> {
         let track_id = pkt.track.id;
-        let mut transcoding = if let Some(transcoding) = self.mapping.remove(&track_id) {
-            transcoding
-        } else {
-            func(pkt);
+
+        if self.cancel.is_cancelled() {
+            log::trace!("Dropping packet after cancellation: {pkt:?}");
+            self.emit(func);
+
             return Ok(());
-        };
+        }
 
-        let transcoding = tokio::task::spawn_blocking::<_, anyhow::Result<_>>(move || {
-            process_transcode(pkt, track_id, &mut transcoding, func)?;
+        if let Some(transcoding) = self.mapping.remove(&track_id) {
+            let worker = TranscodeWorker::spawn(track_id, transcoding, self.output_tx.clone());
+            self.workers.insert(track_id, worker);
+        }
 
-            Ok(transcoding)
-        })
-        .await??;
+        match self.workers.get(&track_id) {
+            Some(worker) => {
+                // the workers wait for their output to be taken, so it is taken while waiting for
+                // the worker to accept the packet
+                let mut send = Box::pin(worker.input.send(pkt));
+                let sent = loop {
+                    match future::select(send, Box::pin(self.output_rx.recv())).await {
+                        Either::Left((sent, _)) => break sent,
+                        Either::Right((output, pending)) => {
+                            if let Some(pkt) = output {
+                                self.interleaver.push(pkt);
+                            }
+                            send = pending;
+                        }
+                    }
+                };
+
+                if sent.is_err() {
+                    // the worker only stops early if transcoding failed
+                    let worker = self.workers.remove(&track_id).expect("Worker exists");
+                    worker.handle.await??;
+                }
+            }
+            None => self.interleaver.push(pkt),
+        }
+
+        self.emit(func);
+
+        Ok(())
+    }
+
+    /// Waits for the workers to transcode their queued packets and flush their encoders, then
+    /// calls `func` with all remaining packets in decode order.
+    pub async fn finish<F: FnMut(Packet)>(&mut self, mut func: F) -> anyhow::Result<()> {
+        for (_, worker) in self.workers.drain() {
+            drop(worker.input);
+
+            let mut handle = worker.handle;
+            loop {
+                match future::select(&mut handle, Box::pin(self.output_rx.recv())).await {
+                    Either::Left((result, _)) => break result??,
+                    Either::Right((output, _)) => {
+                        if let Some(pkt) = output {
+                            self.interleaver.push(pkt);
+                        }
+                    }
+                }
+            }
+        }
 
-        self.mapping.insert(track_id, transcoding);
+        self.emit(&mut func);
+        while let Some(pkt) = self.interleaver.flush() {
+            func(pkt);
+        }
 
         Ok(())
     }
+
+    fn emit<F: FnMut(Packet)>(&mut self, mut func: F) {
+        while let Ok(pkt) = self.output_rx.try_recv() {
+            self.interleaver.push(pkt);
+        }
+
+        while let Some(pkt) = self.interleaver.pop() {
+            func(pkt);
+        }
+    }
+}
+
+struct TranscodeWorker {
+    input: mpsc::Sender<Packet>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TranscodeWorker {
+    fn spawn(track_id: u32, mut transcoding: Transcode, output: mpsc::Sender<Packet>) -> Self {
+        let (input, mut packets) = mpsc::channel(WORKER_QUEUE_SIZE);
+
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut func = |pkt| {
+                // the receiver is only gone once the transcoder is dropped
+                let _ = output.blocking_send(pkt);
+            };
+
+            while let Some(pkt) = packets.blocking_recv() {
+                process_transcode(Some(pkt), track_id, &mut transcoding, &mut func)?;
+            }
+
+            process_transcode(None, track_id, &mut transcoding, &mut func)
+        });
+
+        TranscodeWorker { input, handle }
+    }
 }
 
-fn process_transcode<F: FnMut(Packet) + Send + 'static>(
-    pkt: Packet,
+/// Transcodes a packet, or flushes the encoder at the end of the input if there is none.
+fn process_transcode<F: FnMut(Packet)>(
+    pkt: Option<Packet>,
     track_id: u32,
     transcoding: &mut Transcode,
     mut func: F,
 ) -> anyhow::Result<()> {
-    match transcoding {
-        Transcode::Subtitles {
-            ref mut decoder,
-            ref mut encoder,
-        } => {
-            decoder.feed(pkt)?;
-
-            while let Some(decoded) = decoder.receive() {
-                encoder.feed(decoded)?;
+    let (Transcode::Subtitles { decoder, encoder } | Transcode::Audio { decoder, encoder }) =
+        transcoding;
 
-                while let Some(mut pkt) = encoder.receive() {
-                    pkt.track.id = track_id;
+    let flush = pkt.is_none();
+    if let Some(pkt) = pkt {
+        decoder.feed(pkt)?;
+    }
 
-                    func(pkt);
-                }
-            }
+    let mut emit = |encoder: &mut Box<dyn Encoder>| {
+        while let Some(mut pkt) = encoder.receive() {
+            pkt.track.id = track_id;
+            func(pkt);
         }
+    };
+
+    while let Some(decoded) = decoder.receive() {
+        encoder.feed(decoded)?;
+        emit(encoder);
+    }
+
+    if flush {
+        encoder.flush()?;
+        emit(encoder);
     }
 
     Ok(())
@@ -207,3 +509,81 @@ impl fmt::Debug for Fraction {
         write!(f, "{}", self)
     }
 }
+
+#[cfg(test)]
+mod transcode_test {
+    use super::*;
+    use crate::test::{self, SilenceDecoder, SilenceEncoder};
+
+    #[tokio::test]
+    async fn priming_samples_are_left_to_the_muxer() {
+        let mut encoder = SilenceEncoder::new(2048, std::time::Duration::ZERO);
+        let track = encoder
+            .start(CodecDescription::Subtitle(Default::default()))
+            .unwrap();
+        assert_eq!(2048, track.delay);
+
+        let mapping = HashMap::from([(
+            2,
+            Transcode::Audio {
+                decoder: Box::new(SilenceDecoder::default()),
+                encoder: Box::new(encoder),
+            },
+        )]);
+        let mut transcoder = PacketTranscoder::new(mapping);
+
+        let input = test::aac_track();
+        transcoder.start(&[input.clone()]);
+        let mut output = Vec::new();
+        for i in 0..4 {
+            let pkt = test::packet(&input, i * 1024, Some(1024), Vec::new());
+            transcoder.process(pkt, |p| output.push(p)).await.unwrap();
+        }
+        transcoder.finish(|p| output.push(p)).await.unwrap();
+
+        let pts = output.iter().map(|p| p.time.pts).collect::<Vec<_>>();
+        assert_eq!(vec![2048, 3072, 4096, 5120], pts);
+        assert!(output
+            .iter()
+            .all(|p| p.track.id == 2 && p.track.delay == 2048));
+    }
+
+    #[tokio::test]
+    async fn keep_decode_order_with_slow_worker() {
+        let mut encoder = SilenceEncoder::new(0, std::time::Duration::from_millis(20));
+        encoder
+            .start(CodecDescription::Subtitle(Default::default()))
+            .unwrap();
+        let mapping = HashMap::from([(
+            2,
+            Transcode::Audio {
+                decoder: Box::new(SilenceDecoder::default()),
+                encoder: Box::new(encoder),
+            },
+        )]);
+        let mut transcoder = PacketTranscoder::new(mapping);
+
+        let (video, audio) = (test::h264_track(), test::aac_track());
+        transcoder.start(&[video.clone(), audio.clone()]);
+
+        // 400 ms of 25 fps video and AAC frames, in decode order
+        let mut input = (0..10)
+            .map(|i| test::packet(&video, i * 3600, Some(3600), Vec::new()))
+            .chain((0..19).map(|i| test::packet(&audio, i * 1024, Some(1024), Vec::new())))
+            .collect::<Vec<_>>();
+        input.sort_by_key(|p| time::to_duration(p.time.pts, p.time.timebase));
+
+        let mut output = Vec::new();
+        for pkt in input {
+            transcoder.process(pkt, |p| output.push(p)).await.unwrap();
+        }
+        transcoder.finish(|p| output.push(p)).await.unwrap();
+
+        let times = output
+            .iter()
+            .map(|p| time::to_duration(p.time.pts, p.time.timebase))
+            .collect::<Vec<_>>();
+        assert_eq!(29, times.len());
+        assert!(times.windows(2).all(|w| w[0] <= w[1]), "{times:?}");
+    }
+}
@@ -1,40 +1,36 @@
 use anyhow::Context;
+use mediabox::TrackSelector;
 
 async fn run() -> anyhow::Result<()> {
     let mut parser = lexopt::Parser::from_env();
 
     let mut inputs = Vec::new();
-    let mut mappings = Vec::new();
+    let mut mappings: Vec<TrackSelector> = Vec::new();
+    let mut output = None;
 
-    use lexopt::Arg::*;
+    use lexopt::prelude::*;
 
     while let Some(arg) = parser.next().context("Failed parsing arguments")? {
         match arg {
             Short('i') => {
-                inputs.push(parser.value()?);
+                inputs.push(parser.value()?.into_string().map_err(lexopt::Error::from)?);
             }
             Long("map") => {
-                mappings.push(parser.value()?);
+                mappings.push(parser.value()?.parse()?);
+            }
+            Value(val) if output.is_none() => {
+                output = Some(val.into_string().map_err(lexopt::Error::from)?);
             }
             _ => return Err(arg.unexpected()).context("Failed parsing arguments")?,
         }
     }
 
-    dbg!(&inputs);
-    dbg!(&mappings);
-
-    /*let mut ios = Vec::new();
-    for input in inputs {
-        ios.push(Io::open_file(&input).await?);
-    }
-
-    let mut demuxers = Vec::new();
-    for mut io in ios {
-        let meta = mediabox::probe(&mut io).await?;
-        demuxers.push(meta.create(io));
-    }*/
+    let [input] = &inputs[..] else {
+        anyhow::bail!("Expected a single input, got {}", inputs.len());
+    };
+    let output = output.context("Missing output")?;
 
-    Ok(())
+    mediabox::remux_tracks(input, &output, &mappings).await
 }
 
 #[tokio::main]
@@ -7,6 +7,7 @@ use crate::{
         nal::{frame_nal_units, BitstreamFraming},
         SubtitleInfo,
     },
+    time::ClockTime,
     Fraction, Span,
 };
 
@@ -41,10 +42,118 @@ pub struct H264Codec {
     pub pps: Span,
 }
 
+/// The baseline profiles don't allow B-frames.
+const H264_PROFILE_BASELINE: u8 = 66;
+
+impl H264Codec {
+    /// How many frames may precede a frame in decoding order and follow it in output order, if
+    /// the stream tells.
+    pub fn max_reorder_frames(&self) -> Option<u32> {
+        use h264_reader::{
+            nal::sps::SeqParameterSet,
+            rbsp::{decode_nal, BitReader},
+        };
+
+        if self.profile_indication == H264_PROFILE_BASELINE {
+            return Some(0);
+        }
+
+        let sps_slice = self.sps.to_slice();
+        let sps = decode_nal(&sps_slice)
+            .ok()
+            .and_then(|nal| SeqParameterSet::from_bits(BitReader::new(nal.as_ref())).ok())?;
+
+        sps.vui_parameters?
+            .bitstream_restrictions
+            .map(|restrictions| restrictions.max_num_reorder_frames)
+    }
+}
+
+/// Layout of the samples in uncompressed video.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Planar Y, U and V with chroma subsampled both horizontally and vertically.
+    Yuv420p,
+    /// A Y plane followed by an interleaved UV plane, subsampled like [PixelFormat::Yuv420p].
+    Nv12,
+    Rgb24,
+    Rgba,
+}
+
+impl PixelFormat {
+    /// Returns the width in bytes and the height of each plane for a frame of the given size.
+    pub fn plane_sizes(&self, width: u32, height: u32) -> Vec<(usize, usize)> {
+        let (w, h) = (width as usize, height as usize);
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+
+        match self {
+            PixelFormat::Yuv420p => vec![(w, h), (cw, ch), (cw, ch)],
+            PixelFormat::Nv12 => vec![(w, h), (cw * 2, ch)],
+            PixelFormat::Rgb24 => vec![(w * 3, h)],
+            PixelFormat::Rgba => vec![(w * 4, h)],
+        }
+    }
+
+    /// Returns the size in bytes of a tightly packed frame of the given size.
+    pub fn frame_size(&self, width: u32, height: u32) -> usize {
+        self.plane_sizes(width, height)
+            .iter()
+            .map(|(w, h)| w * h)
+            .sum()
+    }
+}
+
+/// Uncompressed video, with planes stored back to back without padding.
+#[derive(Debug, Clone)]
+pub struct RawVideoCodec {
+    pub format: PixelFormat,
+}
+
+#[derive(Clone)]
+pub struct Av1Codec {
+    /// The `AV1CodecConfigurationRecord`, including the sequence header OBU.
+    pub config: Span,
+}
+
+/// The fields of a `VPCodecConfigurationRecord`, describing both VP8 and VP9.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VpxCodec {
+    pub profile: u8,
+    /// The level times ten, e.g. 31 for level 3.1, or 0 if unknown.
+    pub level: u8,
+    pub bit_depth: u8,
+    pub chroma_subsampling: u8,
+    pub full_range: bool,
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+}
+
+impl Default for VpxCodec {
+    fn default() -> Self {
+        VpxCodec {
+            profile: 0,
+            level: 0,
+            bit_depth: 8,
+            // 4:2:0 with chroma collocated with luma
+            chroma_subsampling: 1,
+            full_range: false,
+            // unspecified
+            colour_primaries: 2,
+            transfer_characteristics: 2,
+            matrix_coefficients: 2,
+        }
+    }
+}
+
 /// Information about a specific video codec
 #[derive(Clone)]
 pub enum VideoCodec {
     H264(H264Codec),
+    Av1(Av1Codec),
+    Vp8(VpxCodec),
+    Vp9(VpxCodec),
+    Raw(RawVideoCodec),
 }
 
 /// Information about video media
@@ -57,7 +166,9 @@ pub struct VideoInfo {
 
 impl VideoInfo {
     pub fn parameter_sets(&self) -> Option<Vec<u8>> {
-        let VideoCodec::H264(H264Codec { sps, pps, .. }) = &self.codec;
+        let VideoCodec::H264(H264Codec { sps, pps, .. }) = &self.codec else {
+            return None;
+        };
 
         let nuts = [sps.clone(), pps.clone()];
 
@@ -79,10 +190,14 @@ impl fmt::Debug for VideoInfo {
                 };
 
                 let sps_slice = sps.to_slice();
-                let nal = decode_nal(&sps_slice[1..]).unwrap();
+                let sps = decode_nal(&sps_slice)
+                    .ok()
+                    .and_then(|nal| SeqParameterSet::from_bits(BitReader::new(nal.as_ref())).ok());
 
-                let reader = BitReader::new(nal.as_ref());
-                let sps = SeqParameterSet::from_bits(reader).unwrap();
+                // tools print track info for broken files too, so don't fail on an invalid SPS
+                let Some(sps) = sps else {
+                    return write!(f, "H264 (invalid SPS) {}x{}", self.width, self.height);
+                };
 
                 let aspect_ratio = sps
                     .vui_parameters
@@ -126,6 +241,14 @@ impl fmt::Debug for VideoInfo {
 
                 Ok(())
             }
+            VideoCodec::Av1(_) => write!(f, "AV1 {}x{}", self.width, self.height),
+            VideoCodec::Vp8(_) => write!(f, "VP8 {}x{}", self.width, self.height),
+            VideoCodec::Vp9(VpxCodec { profile, .. }) => {
+                write!(f, "VP9 (Profile {profile}) {}x{}", self.width, self.height)
+            }
+            VideoCodec::Raw(RawVideoCodec { format }) => {
+                write!(f, "Raw ({format:?}) {}x{}", self.width, self.height)
+            }
         }
     }
 }
@@ -135,16 +258,139 @@ pub struct AacCodec {
     pub extra: Vec<u8>,
 }
 
+/// Layout of a single sample in uncompressed audio. Channels are always interleaved.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SampleFormat {
+    U8,
+    S16Le,
+    S16Be,
+    S24Le,
+    S24Be,
+    S32Le,
+    S32Be,
+    F32Le,
+    F32Be,
+    F64Le,
+    F64Be,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16Le | SampleFormat::S16Be => 2,
+            SampleFormat::S24Le | SampleFormat::S24Be => 3,
+            SampleFormat::S32Le
+            | SampleFormat::S32Be
+            | SampleFormat::F32Le
+            | SampleFormat::F32Be => 4,
+            SampleFormat::F64Le | SampleFormat::F64Be => 8,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(
+            self,
+            SampleFormat::F32Le | SampleFormat::F32Be | SampleFormat::F64Le | SampleFormat::F64Be
+        )
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        matches!(
+            self,
+            SampleFormat::S16Be
+                | SampleFormat::S24Be
+                | SampleFormat::S32Be
+                | SampleFormat::F32Be
+                | SampleFormat::F64Be
+        )
+    }
+
+    /// Converts a single sample to the range `-1.0..=1.0`. `data` must be exactly
+    /// [SampleFormat::bytes_per_sample] long.
+    pub fn to_f32(&self, data: &[u8]) -> f32 {
+        // reorder to big endian, which also scales integers up to 32 bits
+        let mut bytes = [0u8; 8];
+        if self.is_big_endian() {
+            bytes[..data.len()].copy_from_slice(data);
+        } else {
+            for (b, d) in bytes[..data.len()].iter_mut().zip(data.iter().rev()) {
+                *b = *d;
+            }
+        }
+
+        match self {
+            SampleFormat::U8 => (data[0] as f32 - 128.0) / 128.0,
+            SampleFormat::F32Le | SampleFormat::F32Be => {
+                f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+            SampleFormat::F64Le | SampleFormat::F64Be => f64::from_be_bytes(bytes) as f32,
+            _ => {
+                i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2f32.powi(31)
+            }
+        }
+    }
+}
+
+/// Uncompressed audio.
+#[derive(Debug, Clone)]
+pub struct PcmCodec {
+    pub format: SampleFormat,
+}
+
+#[derive(Debug, Clone)]
+pub struct OpusCodec {
+    /// The `OpusHead` identification header.
+    pub header: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlacCodec {
+    /// The `fLaC` marker followed by the metadata blocks, starting with `STREAMINFO`.
+    pub header: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VorbisCodec {
+    /// The identification, comment and setup headers, in that order.
+    pub headers: [Vec<u8>; 3],
+}
+
+impl VorbisCodec {
+    /// Packs the headers with Xiph lacing, which is how both Matroska and FFmpeg store them.
+    pub fn to_xiph_laced(&self) -> Vec<u8> {
+        let mut buf = vec![self.headers.len() as u8 - 1];
+
+        // the size of the last header is implied
+        for header in &self.headers[..self.headers.len() - 1] {
+            buf.extend(std::iter::repeat_n(0xff, header.len() / 255));
+            buf.push((header.len() % 255) as u8);
+        }
+
+        for header in &self.headers {
+            buf.extend_from_slice(header);
+        }
+
+        buf
+    }
+}
+
 /// Information about specific audio codecs
 #[derive(Debug, Clone)]
 pub enum AudioCodec {
     Aac(AacCodec),
+    Opus(OpusCodec),
+    Vorbis(VorbisCodec),
+    Flac(FlacCodec),
+    Pcm(PcmCodec),
 }
 
 impl AudioCodec {
     pub fn decoder_specific_data(&self) -> Option<&[u8]> {
         match self {
             Self::Aac(AacCodec { extra }) => Some(&extra),
+            Self::Opus(OpusCodec { header }) | Self::Flac(FlacCodec { header }) => Some(header),
+            Self::Vorbis(_) | Self::Pcm(_) => None,
         }
     }
 }
@@ -236,11 +482,57 @@ pub struct Track {
     pub id: u32,
     pub info: Arc<MediaInfo>,
     pub timebase: Fraction,
+    /// The codec delay in the track timebase, i.e. the amount of priming data at the start of the
+    /// track that should be skipped during presentation.
+    pub delay: u64,
+    pub metadata: TrackMetadata,
+}
+
+/// Information about a track for players, e.g. for choosing which track to play.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackMetadata {
+    /// The language as a BCP 47 tag or ISO 639-2 code, e.g. `en` or `jpn`.
+    pub language: Option<String>,
+    pub title: Option<String>,
+    /// Whether the track should be selected when the user has no preference.
+    pub default: bool,
+    /// Whether the track should be played regardless of the user's preferences, e.g. subtitles
+    /// for foreign dialogue.
+    pub forced: bool,
+    /// The average bitrate in bits per second, if stored by the container.
+    pub bitrate: Option<u64>,
+    /// The length of the track, if stored by the container.
+    pub duration: Option<Duration>,
+}
+
+impl Default for TrackMetadata {
+    fn default() -> Self {
+        TrackMetadata {
+            language: None,
+            title: None,
+            default: true,
+            forced: false,
+            bitrate: None,
+            duration: None,
+        }
+    }
 }
 
 impl fmt::Debug for Track {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "#{}: {:?}", self.id, self.info)
+        write!(f, "#{}: {:?}", self.id, self.info)?;
+
+        if let Some(language) = &self.metadata.language {
+            write!(f, " [{language}]")?;
+        }
+        if let Some(title) = &self.metadata.title {
+            write!(f, " {title:?}")?;
+        }
+        if let Some(bitrate) = self.bitrate() {
+            write!(f, " {} kbit/s", bitrate / 1000)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -248,6 +540,29 @@ impl Track {
     pub fn is_video(&self) -> bool {
         matches!(self.info.kind, MediaKind::Video(_))
     }
+
+    /// The average bitrate in bits per second, either as stored by the container or implied by
+    /// the codec for uncompressed audio.
+    pub fn bitrate(&self) -> Option<u64> {
+        if let Some(bitrate) = self.metadata.bitrate {
+            return Some(bitrate);
+        }
+
+        match &self.info.kind {
+            MediaKind::Audio(AudioInfo {
+                sample_rate,
+                sound_type,
+                codec: AudioCodec::Pcm(PcmCodec { format }),
+                ..
+            }) => Some(
+                *sample_rate as u64
+                    * sound_type.channel_count() as u64
+                    * format.bytes_per_sample() as u64
+                    * 8,
+            ),
+            _ => None,
+        }
+    }
 }
 
 /// A media packet.
@@ -259,9 +574,66 @@ pub struct Packet {
     pub key: bool,
     pub track: Track,
     pub buffer: Span,
+    /// Metadata which demuxers pass along with the packet, for muxers and filters which need it.
+    pub side_data: Vec<SideData>,
+}
+
+/// Per-packet metadata which isn't part of the packet data itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SideData {
+    /// A SEI message of an H.264 access unit, e.g. closed captions or timecodes.
+    Sei { payload_type: u32, payload: Vec<u8> },
+
+    /// Common Encryption parameters of an encrypted sample.
+    Encryption {
+        key_id: [u8; 16],
+        iv: Vec<u8>,
+        /// Pairs of clear and encrypted byte counts, or empty if the whole sample is encrypted.
+        subsamples: Vec<(u16, u32)>,
+    },
+
+    /// The position of a subtitle event in the original script, i.e. the ASS `ReadOrder`.
+    ReadOrder(u64),
+
+    /// Marks a packet where audio and video are known to be in sync, e.g. after a discontinuity
+    /// in a live stream.
+    SyncPoint,
+
+    /// Marks a video packet where decoding can start, for segmenters deciding where to cut.
+    RandomAccess(RandomAccess),
+}
+
+/// How decoding can start at a video packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RandomAccess {
+    /// An IDR picture, which nothing after it refers past.
+    Idr,
+    /// A recovery point SEI message, after which the pictures are correct once `frames` more
+    /// have been decoded.
+    Recovery {
+        frames: u32,
+        exact_match: bool,
+        broken_link: bool,
+    },
 }
 
 impl Packet {
+    /// The [SideData::ReadOrder] of the packet, if the demuxer provided one.
+    pub fn read_order(&self) -> Option<u64> {
+        self.side_data.iter().find_map(|data| match data {
+            SideData::ReadOrder(order) => Some(*order),
+            _ => None,
+        })
+    }
+
+    /// The [SideData::RandomAccess] of the packet, if a filter found one.
+    pub fn random_access(&self) -> Option<RandomAccess> {
+        self.side_data.iter().find_map(|data| match data {
+            SideData::RandomAccess(access) => Some(*access),
+            _ => None,
+        })
+    }
+
     pub fn guess_duration(&self) -> Option<MediaDuration> {
         match &self.track.info.kind {
             MediaKind::Video(VideoInfo {
@@ -274,7 +646,7 @@ impl Packet {
                 };
 
                 let sps_slice = sps.to_slice();
-                let nal = decode_nal(&sps_slice[1..]).unwrap();
+                let nal = decode_nal(&sps_slice).unwrap();
 
                 let reader = BitReader::new(nal.as_ref());
                 let sps = SeqParameterSet::from_bits(reader).unwrap();
@@ -307,6 +679,7 @@ impl fmt::Debug for Packet {
                 &format_args!("{} ({})", self.track.id, self.track.info.name),
             )
             .field("buffer", &format_args!("[{}]", self.buffer.len()))
+            .field("side_data", &self.side_data)
             .finish()
     }
 }
@@ -391,23 +764,16 @@ impl std::ops::Sub for MediaTime {
 
 impl fmt::Debug for MediaTime {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let pts = self.pts as f32 / self.timebase.denominator as f32;
-        write!(f, "{pts:.3}s")?;
+        write!(f, "{}", ClockTime::from_timestamp(self.pts, self.timebase))?;
 
         if let Some(duration) = self.duration {
-            write!(
-                f,
-                "-{:.3}s ",
-                pts + duration as f32 / self.timebase.denominator as f32
-            )?;
+            let end = ClockTime::from_timestamp(self.pts + duration, self.timebase);
+            write!(f, "-{end} ")?;
         }
 
         if let Some(dts) = self.dts {
-            write!(
-                f,
-                "{:.3}s (decode) ",
-                dts as f32 / self.timebase.denominator as f32
-            )?
+            let dts = ClockTime::from_timestamp(dts, self.timebase);
+            write!(f, "{dts} (decode) ")?
         }
 
         Ok(())
@@ -440,8 +806,44 @@ impl MediaTime {
     }
 }
 
-fn convert_timebase(time: u64, original: Fraction, new: Fraction) -> u64 {
-    time * new.denominator as u64 / original.denominator as u64
+/// Converts a timestamp to another timebase, rounding to the nearest tick.
+pub(crate) fn convert_timebase(time: u64, original: Fraction, new: Fraction) -> u64 {
+    let numerator = time as u128 * original.numerator as u128 * new.denominator as u128;
+    let denominator = original.denominator as u128 * new.numerator as u128;
+
+    ((numerator + denominator / 2) / denominator) as u64
+}
+
+#[test]
+fn h264_parameter_sets_and_debug() {
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x0a, 0xac, 0xd9, 0x41, 0x41, 0xfb, 0x01, 0x10, 0x00, 0x00, 0x03, 0x00,
+        0x10, 0x00, 0x00, 0x03, 0x03, 0x20, 0xf1, 0x22, 0x59, 0x60,
+    ];
+    const PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+
+    let info = |sps: &'static [u8]| VideoInfo {
+        width: 320,
+        height: 240,
+        codec: VideoCodec::H264(H264Codec {
+            bitstream_format: BitstreamFraming::FourByteLength,
+            profile_indication: 100,
+            profile_compatibility: 0,
+            level_indication: 10,
+            sps: sps.into(),
+            pps: PPS.into(),
+        }),
+    };
+
+    let sets = info(SPS).parameter_sets().unwrap();
+    assert_eq!(&[0, 0, 0, SPS.len() as u8], &sets[..4]);
+    assert_eq!(SPS, &sets[4..4 + SPS.len()]);
+    assert_eq!(PPS, &sets[8 + SPS.len()..]);
+
+    let debug = format!("{:?}", info(SPS));
+    assert!(debug.starts_with("H264 (High)"), "{debug}");
+    assert!(debug.contains("320x240 [DAR 4:3"), "{debug}");
+    assert_eq!("H264 (invalid SPS) 320x240", format!("{:?}", info(&[0x67])));
 }
 
 #[test]
@@ -0,0 +1,520 @@
+//! Records a live input into a rolling series of files, e.g. to archive a camera around the
+//! clock.
+
+use log::*;
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    cancel::CancellationToken,
+    filter::{TimebaseMapper, TimestampPolicy, TimestampSanitizer},
+    format::{Demuxer, DemuxerEvent, Movie, Muxer, MuxerMetadata},
+    io::Io,
+    time::{from_duration, to_duration},
+    MediaContext, MediaTrackExt, Packet,
+};
+
+/// Recordings are written with this extension added to their name, which is only removed once
+/// they have been finalized. Files which still have it were interrupted.
+const PARTIAL_EXTENSION: &str = "part";
+
+/// A file written by a [`Recorder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub path: PathBuf,
+    /// The time in the input the recording starts at. Timestamps in the file start at zero.
+    pub start: Duration,
+    pub duration: Duration,
+    /// The size of the packets written, without the overhead of the container.
+    pub size: u64,
+}
+
+/// The recording currently being written.
+struct OpenRecording {
+    muxer: Box<dyn Muxer>,
+    partial: PathBuf,
+    recording: Recording,
+}
+
+impl OpenRecording {
+    async fn write(&mut self, mut packet: Packet) -> anyhow::Result<()> {
+        let time = &mut packet.time;
+        let shift = from_duration(self.recording.start, time.timebase);
+        time.pts = time.pts.saturating_sub(shift);
+        time.dts = time.dts.map(|dts| dts.saturating_sub(shift));
+
+        let end = to_duration(time.pts + time.duration.unwrap_or(0), time.timebase);
+        self.recording.duration = self.recording.duration.max(end);
+        self.recording.size += packet.buffer.len() as u64;
+
+        Ok(self.muxer.write(packet).await?)
+    }
+}
+
+/// Reads packets from a live input and writes them into files which are rotated after a
+/// maximum duration or size, named `{prefix}_{index}.{container}`.
+///
+/// Files are always cut on a keyframe of the video track, or the audio track if there is no
+/// video, and the timestamps of every file start at zero. Files being written have a `.part`
+/// extension until they are finalized, so that recordings cut short by a crash can be found and
+/// kept with [`Recorder::recover`].
+///
+/// ```ignore
+/// let mut recorder = Recorder::new("/var/recordings")
+///     .with_prefix("camera")
+///     .with_max_duration(Duration::from_secs(15 * 60));
+///
+/// recorder.run(&cxt, demuxer.as_mut()).await?;
+/// ```
+pub struct Recorder {
+    directory: PathBuf,
+    prefix: String,
+    container: String,
+    max_duration: Option<Duration>,
+    max_size: Option<u64>,
+    next_index: u32,
+    recordings: Vec<Recording>,
+    cancel: CancellationToken,
+}
+
+impl Recorder {
+    /// Creates a recorder writing Matroska files into `directory`.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Recorder {
+            directory: directory.into(),
+            prefix: "recording".into(),
+            container: "mkv".into(),
+            max_duration: None,
+            max_size: None,
+            next_index: 0,
+            recordings: Vec::new(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Writes the files with the muxer of the given name, e.g. `mp4`. Only Matroska files stay
+    /// playable if the recorder is interrupted before finalizing them.
+    pub fn with_container(mut self, container: impl Into<String>) -> Self {
+        self.container = container.into();
+        self
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Starts a new file at the first keyframe after a file is this long.
+    pub fn with_max_duration(mut self, duration: Duration) -> Self {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Starts a new file at the first keyframe after this many bytes have been written to a file.
+    pub fn with_max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Stops recording once `cancel` is cancelled, even while waiting for the input, and
+    /// finalizes the current file as if the input had ended.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// The files which have been finalized so far.
+    pub fn recordings(&self) -> &[Recording] {
+        &self.recordings
+    }
+
+    /// Finds recordings of an earlier run which were never finalized and removes their `.part`
+    /// extension, returning their paths.
+    pub async fn recover(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut recovered = Vec::new();
+
+        for path in self.list_recordings().await? {
+            if path.extension() != Some(PARTIAL_EXTENSION.as_ref()) {
+                continue;
+            }
+
+            let finished = path.with_extension("");
+            tokio::fs::rename(&path, &finished).await?;
+            recovered.push(finished);
+        }
+
+        Ok(recovered)
+    }
+
+    /// Starts the demuxer and records it until the input ends or the recorder is cancelled.
+    /// Interrupted recordings of an earlier run are recovered first, and numbering continues
+    /// after the existing files.
+    pub async fn run(
+        &mut self,
+        cxt: &MediaContext,
+        demuxer: &mut dyn Demuxer,
+    ) -> anyhow::Result<()> {
+        let meta = cxt.find_muxer(&self.container)?;
+
+        tokio::fs::create_dir_all(&self.directory).await?;
+        for path in self.recover().await? {
+            warn!("Recovered interrupted recording {path:?}");
+        }
+        self.next_index = self.find_next_index().await?;
+
+        let movie = demuxer.start().await?;
+        meta.check_tracks(&movie.tracks)?;
+
+        let mut mapper = TimebaseMapper::new();
+        let mut sanitizer = TimestampSanitizer::new(TimestampPolicy::Clamp);
+        let mut movie = Movie {
+            tracks: mapper.start(movie.tracks),
+            ..movie
+        };
+        let mut current: Option<OpenRecording> = None;
+
+        loop {
+            let pkt = match self.cancel.run_until_cancelled(demuxer.read()).await {
+                Some(Ok(pkt)) => pkt,
+                None => {
+                    debug!("Recording cancelled");
+                    break;
+                }
+                Some(Err(e)) if e.is_end_of_input() => {
+                    debug!("Input ended");
+                    break;
+                }
+                Some(Err(e)) => {
+                    // keep what has been recorded so far
+                    self.finish(current.take()).await?;
+                    return Err(e.into());
+                }
+            };
+
+            while let Some(event) = demuxer.next_event() {
+                debug!("Input changed: {event:?}");
+                match event {
+                    DemuxerEvent::NewMovie(new) => {
+                        meta.check_tracks(&new.tracks)?;
+                        movie = Movie {
+                            tracks: mapper.start(new.tracks),
+                            ..new
+                        };
+
+                        // the next file starts with the new tracks
+                        self.finish(current.take()).await?;
+                    }
+                    event => {
+                        if let Some(open) = &mut current {
+                            open.muxer.handle_event(&event).await?;
+                        }
+                    }
+                }
+            }
+
+            if !movie.tracks.iter().any(|t| t.id == pkt.track.id) {
+                continue;
+            }
+
+            let Some(pkt) = sanitizer.filter(mapper.filter(pkt)) else {
+                continue;
+            };
+
+            let reference = movie
+                .tracks
+                .video()
+                .or_else(|| movie.tracks.audio())
+                .unwrap_or(&movie.tracks[0]);
+
+            if pkt.track.id == reference.id && pkt.key {
+                let time = to_duration(pkt.time.dts.unwrap_or(pkt.time.pts), pkt.time.timebase);
+
+                if self.should_rotate(current.as_ref(), time) {
+                    self.finish(current.take()).await?;
+                    current = Some(self.open(&meta, &movie, time).await?);
+                }
+            }
+
+            let Some(open) = &mut current else {
+                trace!("Dropping packet before first keyframe: {pkt:?}");
+                continue;
+            };
+
+            open.write(pkt).await?;
+        }
+
+        self.finish(current).await?;
+        demuxer.stop().await?;
+
+        Ok(())
+    }
+
+    fn should_rotate(&self, current: Option<&OpenRecording>, time: Duration) -> bool {
+        let Some(current) = current else {
+            return true;
+        };
+
+        let recording = &current.recording;
+        let too_long = self
+            .max_duration
+            .is_some_and(|max| time.saturating_sub(recording.start) >= max);
+        let too_large = self.max_size.is_some_and(|max| recording.size >= max);
+
+        too_long || too_large
+    }
+
+    async fn open(
+        &mut self,
+        meta: &MuxerMetadata,
+        movie: &Movie,
+        start: Duration,
+    ) -> anyhow::Result<OpenRecording> {
+        let name = format!("{}_{:05}.{}", self.prefix, self.next_index, self.container);
+        let path = self.directory.join(name);
+        let partial = partial_path(&path);
+        self.next_index += 1;
+
+        debug!("Recording {path:?} from {start:?}");
+
+        let mut muxer = meta.create(Io::create_file(&partial).await?);
+        muxer.start_movie(movie.clone()).await?;
+
+        Ok(OpenRecording {
+            muxer,
+            partial,
+            recording: Recording {
+                path,
+                start,
+                duration: Duration::ZERO,
+                size: 0,
+            },
+        })
+    }
+
+    /// Finalizes a recording and gives it its final name.
+    async fn finish(&mut self, open: Option<OpenRecording>) -> anyhow::Result<()> {
+        let Some(OpenRecording {
+            mut muxer,
+            partial,
+            recording,
+        }) = open
+        else {
+            return Ok(());
+        };
+
+        muxer.stop().await?;
+        drop(muxer);
+
+        tokio::fs::rename(&partial, &recording.path).await?;
+        debug!("Finished recording {recording:?}");
+
+        self.recordings.push(recording);
+
+        Ok(())
+    }
+
+    /// Lists the files in the directory which belong to this recorder, finished or not.
+    async fn list_recordings(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let prefix = format!("{}_", self.prefix);
+        let mut paths = Vec::new();
+
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(paths),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if name.to_str().is_some_and(|name| name.starts_with(&prefix)) {
+                paths.push(entry.path());
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// The index after the highest one of the existing recordings.
+    async fn find_next_index(&self) -> anyhow::Result<u32> {
+        let next = self
+            .list_recordings()
+            .await?
+            .iter()
+            .filter_map(|path| path.file_stem()?.to_str()?.rsplit_once('_')?.1.parse().ok())
+            .map(|index: u32| index + 1)
+            .max()
+            .unwrap_or(0);
+
+        Ok(next)
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+
+    name.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::testsrc::TestSrcDemuxer;
+    use crate::Fraction;
+    use async_trait::async_trait;
+
+    #[tokio::test]
+    async fn rotate_recordings() {
+        let dir = std::env::temp_dir().join(format!("mediabox-recorder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // left behind by a recorder which crashed
+        std::fs::write(dir.join("camera_00003.mkv.part"), b"").unwrap();
+
+        let mut cxt = MediaContext::default();
+        cxt.register_all();
+
+        let mut source = TestSrcDemuxer::new(Duration::from_secs(10))
+            .with_color_bars(64, 64, Fraction::new(25, 1))
+            .with_silence(48_000);
+        let mut recorder = Recorder::new(&dir)
+            .with_prefix("camera")
+            .with_max_duration(Duration::from_secs(4));
+        recorder.run(&cxt, &mut source).await.unwrap();
+
+        let recordings = recorder.recordings();
+        let starts = recordings
+            .iter()
+            .map(|r| r.start.as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 4, 8], starts);
+        assert_eq!(4, recordings[0].duration.as_secs());
+        assert_eq!(dir.join("camera_00004.mkv"), recordings[0].path);
+
+        let mut names = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            vec![
+                "camera_00003.mkv",
+                "camera_00004.mkv",
+                "camera_00005.mkv",
+                "camera_00006.mkv"
+            ],
+            names
+        );
+
+        let io = Io::open_file(&recordings[1].path).await.unwrap();
+        let (_, packets) = crate::test::read_mkv_from_io(io).await;
+        let video = packets
+            .iter()
+            .find(|p| p.track.info.video().is_some())
+            .unwrap();
+        assert!(video.key);
+        assert_eq!(0, video.time.pts);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A live input which fails after some packets, or stalls after cancelling the recorder if
+    /// it is given a token.
+    struct LiveInput {
+        source: TestSrcDemuxer,
+        remaining: usize,
+        cancel: Option<CancellationToken>,
+    }
+
+    #[async_trait(?Send)]
+    impl Demuxer for LiveInput {
+        async fn start(&mut self) -> crate::Result<Movie> {
+            self.source.start().await
+        }
+
+        async fn read(&mut self) -> crate::Result<Packet> {
+            if self.remaining == 0 {
+                let Some(cancel) = &self.cancel else {
+                    return Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into());
+                };
+
+                cancel.cancel();
+                futures::future::pending::<()>().await;
+            }
+
+            self.remaining -= 1;
+            self.source.read().await
+        }
+
+        async fn stop(&mut self) -> crate::Result<()> {
+            self.source.stop().await
+        }
+
+        fn create(io: Io) -> Box<dyn Demuxer> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn finish_recording_when_cancelled() {
+        let dir = std::env::temp_dir().join(format!("mediabox-cancel-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut cxt = MediaContext::default();
+        cxt.register_all();
+
+        let cancel = CancellationToken::new();
+        let fps = Fraction::new(25, 1);
+        let source = TestSrcDemuxer::new(Duration::from_secs(10)).with_color_bars(64, 64, fps);
+        let mut input = LiveInput {
+            source,
+            remaining: 50,
+            cancel: Some(cancel.clone()),
+        };
+        let mut recorder = Recorder::new(&dir).with_cancellation(cancel);
+        recorder.run(&cxt, &mut input).await.unwrap();
+
+        let recordings = recorder.recordings();
+        assert_eq!(1, recordings.len());
+        assert_eq!(2, recordings[0].duration.as_secs());
+
+        let io = Io::open_file(&recordings[0].path).await.unwrap();
+        let (_, packets) = crate::test::read_mkv_from_io(io).await;
+        assert_eq!(50, packets.len());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_when_input_fails() {
+        let dir = std::env::temp_dir().join(format!("mediabox-fail-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut cxt = MediaContext::default();
+        cxt.register_all();
+
+        let fps = Fraction::new(25, 1);
+        let source = TestSrcDemuxer::new(Duration::from_secs(10)).with_color_bars(64, 64, fps);
+        let mut input = LiveInput {
+            source,
+            remaining: 50,
+            cancel: None,
+        };
+        let mut recorder = Recorder::new(&dir);
+        assert!(recorder.run(&cxt, &mut input).await.is_err());
+
+        // what was recorded before the failure is kept
+        assert_eq!(1, recorder.recordings().len());
+        assert_eq!(2, recorder.recordings()[0].duration.as_secs());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,590 @@
+//! Convenience functions which set up a complete pipeline with sensible defaults.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! mediabox::remux("input.mkv", "output.mp4").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use log::*;
+
+use std::{fmt, path::Path, str::FromStr, time::Duration};
+
+use crate::{
+    filter::{Clipper, TimebaseMapper, TimestampPolicy, TimestampSanitizer},
+    format::{
+        merge::{MergeDemuxer, MergeInput},
+        Demuxer, DemuxerEvent, Movie,
+    },
+    io::Io,
+    time::ClockTime,
+    MediaContext, MediaKind, Packet, Track,
+};
+
+/// Selects tracks of an input, e.g. to choose which tracks end up in the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackSelector {
+    /// The track with the given id.
+    Id(u32),
+    /// The track at the given position in the input, counting from 0.
+    Index(usize),
+    /// Video tracks.
+    Video,
+    /// Audio tracks.
+    Audio,
+    /// Subtitle tracks.
+    Subtitle,
+    /// Tracks in the given language, e.g. `jpn` or `en`.
+    Language(String),
+    /// Tracks with the given codec, by [`MediaInfo::name`](crate::MediaInfo::name).
+    Codec(String),
+}
+
+impl TrackSelector {
+    pub fn matches(&self, index: usize, track: &Track) -> bool {
+        match self {
+            TrackSelector::Id(id) => track.id == *id,
+            TrackSelector::Index(i) => index == *i,
+            TrackSelector::Video => matches!(track.info.kind, MediaKind::Video(_)),
+            TrackSelector::Audio => matches!(track.info.kind, MediaKind::Audio(_)),
+            TrackSelector::Subtitle => matches!(track.info.kind, MediaKind::Subtitle(_)),
+            TrackSelector::Language(language) => {
+                track.metadata.language.as_deref().is_some_and(|l| {
+                    // a language also matches its regional variants, e.g. `en` matches `en-US`
+                    l.eq_ignore_ascii_case(language)
+                        || l.split('-').next().unwrap_or(l).eq_ignore_ascii_case(language)
+                })
+            }
+            TrackSelector::Codec(name) => track.info.name.eq_ignore_ascii_case(name),
+        }
+    }
+
+    /// Selects the first matching track.
+    pub fn select<'a>(&self, tracks: &'a [Track]) -> Option<&'a Track> {
+        tracks
+            .iter()
+            .enumerate()
+            .find_map(|(i, track)| self.matches(i, track).then_some(track))
+    }
+
+    /// Selects the tracks matching any of the selectors, in the order of the selectors, or all
+    /// tracks if there are no selectors.
+    pub fn select_all(selectors: &[TrackSelector], tracks: &[Track]) -> Vec<Track> {
+        if selectors.is_empty() {
+            return tracks.to_vec();
+        }
+
+        let mut selected: Vec<Track> = Vec::new();
+        for selector in selectors {
+            for (i, track) in tracks.iter().enumerate() {
+                if selector.matches(i, track) && !selected.iter().any(|t| t.id == track.id) {
+                    selected.push(track.clone());
+                }
+            }
+        }
+
+        selected
+    }
+}
+
+impl FromStr for TrackSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid track selector {val:?}");
+
+        let selector = match val.split_once(':') {
+            Some(("index" | "i", index)) => {
+                TrackSelector::Index(index.parse().map_err(|_| invalid())?)
+            }
+            Some(("language" | "lang", language)) => TrackSelector::Language(language.into()),
+            Some(("codec", codec)) => TrackSelector::Codec(codec.into()),
+            Some(_) => return Err(invalid()),
+            None => match val {
+                "video" | "v" => TrackSelector::Video,
+                "audio" | "a" => TrackSelector::Audio,
+                "subtitle" | "s" => TrackSelector::Subtitle,
+                id => TrackSelector::Id(id.parse().map_err(|_| invalid())?),
+            },
+        };
+
+        Ok(selector)
+    }
+}
+
+/// A summary of an input, see [`probe`].
+#[derive(Debug, Clone)]
+pub struct MovieReport {
+    /// Name of the demuxer which recognized the input.
+    pub format: &'static str,
+    pub movie: Movie,
+}
+
+impl fmt::Display for MovieReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format: {}", self.format)?;
+
+        if let Some(duration) = self.movie.duration() {
+            writeln!(f, "Duration: {}", ClockTime::new(duration))?;
+        }
+
+        if !self.movie.start_offset.is_zero() {
+            writeln!(f, "Start offset: {}", ClockTime::new(self.movie.start_offset))?;
+        }
+
+        for track in &self.movie.tracks {
+            writeln!(f, "  {track:?}")?;
+        }
+
+        for attachment in &self.movie.attachments {
+            writeln!(f, "  Attachment {attachment:?}")?;
+        }
+
+        for chapter in &self.movie.chapters {
+            let start = ClockTime::new(chapter.start);
+            writeln!(f, "  Chapter {start} {:?}", chapter.title.as_deref().unwrap_or(""))?;
+        }
+
+        for (name, value) in &self.movie.metadata {
+            writeln!(f, "  {name}: {value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens an input and lists its tracks.
+pub async fn probe(in_url: &str) -> anyhow::Result<MovieReport> {
+    let (_, format, mut demuxer) = open(in_url).await?;
+    let movie = demuxer.start().await?;
+    demuxer.stop().await?;
+
+    Ok(MovieReport { format, movie })
+}
+
+/// Copies all tracks of an input into an output, choosing the container from the file extension
+/// of the output.
+pub async fn remux(in_url: &str, out_url: &str) -> anyhow::Result<()> {
+    remux_with_filter(in_url, out_url, |pkt| pkt).await
+}
+
+/// Like [`remux`], but runs every packet through `filter` first, e.g. a
+/// [`SubtitleRetimer`](crate::filter::SubtitleRetimer).
+pub async fn remux_with_filter(
+    in_url: &str,
+    out_url: &str,
+    mut filter: impl FnMut(Packet) -> Packet,
+) -> anyhow::Result<()> {
+    let (cxt, _, mut demuxer) = open(in_url).await?;
+    let movie = demuxer.start().await?;
+
+    copy(&cxt, demuxer.as_mut(), movie, out_url, all_tracks, |pkt| {
+        Some(vec![filter(pkt)])
+    })
+    .await
+}
+
+/// Copies the part of an input between `start` and `end` into an output, starting at the
+/// keyframe before `start`, see [`Clipper`].
+pub async fn clip(
+    in_url: &str,
+    out_url: &str,
+    start: Duration,
+    end: Option<Duration>,
+) -> anyhow::Result<()> {
+    let (cxt, _, mut demuxer) = open(in_url).await?;
+    let movie = demuxer.start().await?;
+
+    let mut clipper = Clipper::new(start);
+    if let Some(end) = end {
+        clipper = clipper.with_end(end);
+    }
+
+    let movie = Movie {
+        tracks: clipper.start(movie.tracks),
+        ..movie
+    };
+
+    copy(&cxt, demuxer.as_mut(), movie, out_url, all_tracks, |pkt| {
+        let packets = clipper.filter(pkt);
+        (!clipper.is_finished()).then_some(packets)
+    })
+    .await
+}
+
+/// Copies the tracks matching any of the selectors into an output, see
+/// [`TrackSelector::select_all`].
+pub async fn remux_tracks(
+    in_url: &str,
+    out_url: &str,
+    selectors: &[TrackSelector],
+) -> anyhow::Result<()> {
+    let (cxt, _, mut demuxer) = open(in_url).await?;
+    let movie = demuxer.start().await?;
+
+    if TrackSelector::select_all(selectors, &movie.tracks).is_empty() {
+        anyhow::bail!("No tracks matching {selectors:?} in {in_url:?}");
+    }
+
+    let select = |tracks: &[Track]| TrackSelector::select_all(selectors, tracks);
+    copy(&cxt, demuxer.as_mut(), movie, out_url, select, |pkt| {
+        Some(vec![pkt])
+    })
+    .await
+}
+
+/// Copies a single track of an input into an output, e.g. to extract the audio of a movie into
+/// an `.aac` file.
+pub async fn extract_track(
+    in_url: &str,
+    selector: TrackSelector,
+    out_url: &str,
+) -> anyhow::Result<()> {
+    let (cxt, _, mut demuxer) = open(in_url).await?;
+    let movie = demuxer.start().await?;
+
+    if selector.select(&movie.tracks).is_none() {
+        anyhow::bail!("No track matching {selector:?} in {in_url:?}");
+    }
+
+    let movie = Movie {
+        tracks: movie.tracks,
+        ..Default::default()
+    };
+
+    let select = |tracks: &[Track]| selector.select(tracks).into_iter().cloned().collect();
+    copy(&cxt, demuxer.as_mut(), movie, out_url, select, |pkt| {
+        Some(vec![pkt])
+    })
+    .await
+}
+
+/// Copies the selected tracks of several inputs into one output, e.g. to replace the audio of a
+/// movie, see [`MergeDemuxer`].
+pub async fn merge(inputs: Vec<MergeInput>, out_url: &str) -> anyhow::Result<()> {
+    let mut cxt = MediaContext::default();
+    cxt.register_all();
+
+    let mut demuxer = MergeDemuxer::new(inputs);
+    let movie = demuxer.start().await?;
+
+    copy(&cxt, &mut demuxer, movie, out_url, all_tracks, |pkt| {
+        Some(vec![pkt])
+    })
+    .await
+}
+
+/// Opens an input with the demuxer its contents are recognized as, e.g. for a [`MergeInput`].
+pub async fn open_input(in_url: &str) -> anyhow::Result<Box<dyn Demuxer>> {
+    let (_, _, demuxer) = open(in_url).await?;
+
+    Ok(demuxer)
+}
+
+/// Writes the attachment with the given name, e.g. a font embedded in a Matroska file, to a file.
+pub async fn extract_attachment(in_url: &str, name: &str, out_url: &str) -> anyhow::Result<()> {
+    let (_, _, mut demuxer) = open(in_url).await?;
+    let movie = demuxer.start().await?;
+    demuxer.stop().await?;
+
+    let attachment = movie
+        .attachments
+        .into_iter()
+        .find(|a| a.name == name)
+        .ok_or_else(|| anyhow::anyhow!("No attachment named {name:?} in {in_url:?}"))?;
+
+    let mut io = Io::create(out_url.to_string()).await?;
+    io.write_span(attachment.data).await?;
+
+    Ok(())
+}
+
+async fn open(in_url: &str) -> anyhow::Result<(MediaContext, &'static str, Box<dyn Demuxer>)> {
+    let mut cxt = MediaContext::default();
+    cxt.register_all();
+
+    let mut io = Io::open(in_url.to_string()).await?;
+    let meta = cxt.probe(&mut io).await?;
+
+    debug!("Opened {in_url:?} as {}", meta.name);
+
+    Ok((cxt, meta.name, meta.create(io)))
+}
+
+/// Writes the packets `filter` returns for every packet of the tracks `select` chooses into an
+/// output, until the input ends or `filter` returns `None`. The tracks are chosen again whenever
+/// the input starts a new movie.
+async fn copy(
+    cxt: &MediaContext,
+    demuxer: &mut dyn Demuxer,
+    movie: Movie,
+    out_url: &str,
+    select: impl Fn(&[Track]) -> Vec<Track>,
+    mut filter: impl FnMut(Packet) -> Option<Vec<Packet>>,
+) -> anyhow::Result<()> {
+    let container = container_for_path(out_url)?;
+    let meta = cxt.find_muxer(container)?;
+    let tracks = select(&movie.tracks);
+    meta.check_tracks(&tracks)?;
+
+    let mut muxer = meta.create(Io::create(out_url.to_string()).await?);
+    let mut ids = tracks.iter().map(|t| t.id).collect::<Vec<_>>();
+
+    // the input may lack the decode timestamps the output needs
+    let mut mapper = TimebaseMapper::new();
+    let mut sanitizer = TimestampSanitizer::new(TimestampPolicy::Clamp);
+    let movie = Movie {
+        tracks: mapper.start(tracks),
+        ..movie
+    };
+    muxer.start_movie(movie).await?;
+
+    loop {
+        let pkt = match demuxer.read().await {
+            Ok(pkt) => pkt,
+            Err(e) if e.is_end_of_input() => {
+                debug!("Input ended");
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(event) = demuxer.next_event() {
+            debug!("Input changed: {event:?}");
+            let event = match event {
+                DemuxerEvent::NewMovie(movie) => {
+                    let tracks = select(&movie.tracks);
+                    meta.check_tracks(&tracks)?;
+                    ids = tracks.iter().map(|t| t.id).collect();
+                    DemuxerEvent::NewMovie(Movie {
+                        tracks: mapper.start(tracks),
+                        ..movie
+                    })
+                }
+                event => event,
+            };
+            muxer.handle_event(&event).await?;
+        }
+
+        if !ids.contains(&pkt.track.id) {
+            continue;
+        }
+
+        let Some(packets) = filter(pkt) else {
+            debug!("Stopped reading the input");
+            break;
+        };
+
+        for pkt in packets {
+            if let Some(pkt) = sanitizer.filter(mapper.filter(pkt)) {
+                muxer.write(pkt).await?;
+            }
+        }
+    }
+
+    for id in ids {
+        if let Some(stats) = sanitizer
+            .stats(id)
+            .filter(|s| s.duplicated + s.non_monotonic > 0)
+        {
+            warn!("Repaired timestamps of track {id}: {stats:?}");
+        }
+    }
+
+    demuxer.stop().await?;
+    muxer.stop().await?;
+
+    Ok(())
+}
+
+fn all_tracks(tracks: &[Track]) -> Vec<Track> {
+    tracks.to_vec()
+}
+
+fn container_for_path(url: &str) -> anyhow::Result<&'static str> {
+    let extension = Path::new(url)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    let container = match extension.as_deref() {
+        Some("mkv" | "mka" | "mks") => "mkv",
+        Some("webm") => "webm",
+        Some("mp4" | "m4a" | "m4v") => "mp4",
+        Some("aac") => "adts",
+        Some("ass" | "ssa") => "ass",
+        Some("wav") => "wav",
+        _ => anyhow::bail!("Unable to choose a container for {url:?}"),
+    };
+
+    Ok(container)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{io::Io, test};
+    use async_trait::async_trait;
+    use std::collections::VecDeque;
+    use test_case::test_case;
+
+    #[test_case("out.mkv", Some("mkv"))]
+    #[test_case("out.webm", Some("webm"))]
+    #[test_case("out.M4A", Some("mp4"))]
+    #[test_case("/tmp/audio.aac", Some("adts"))]
+    #[test_case("subs.ass", Some("ass"))]
+    #[test_case("audio.wav", Some("wav"))]
+    #[test_case("out", None)]
+    fn container_from_extension(path: &str, expected: Option<&str>) {
+        assert_eq!(expected, container_for_path(path).ok());
+    }
+
+    #[test_case("audio", TrackSelector::Audio)]
+    #[test_case("v", TrackSelector::Video)]
+    #[test_case("2", TrackSelector::Id(2))]
+    #[test_case("index:0", TrackSelector::Index(0))]
+    #[test_case("lang:jpn", TrackSelector::Language("jpn".into()))]
+    #[test_case("codec:aac", TrackSelector::Codec("aac".into()))]
+    fn parse_selector(val: &str, expected: TrackSelector) {
+        assert_eq!(expected, val.parse().unwrap());
+    }
+
+    #[test_case(&[], &[1, 2, 3, 4] ; "all")]
+    #[test_case(&["a"], &[2, 3] ; "audio")]
+    #[test_case(&["lang:en", "v"], &[3, 1, 4] ; "in selector order")]
+    #[test_case(&["codec:AAC", "index:1"], &[2, 3] ; "no duplicates")]
+    #[test_case(&["s"], &[] ; "none")]
+    fn select_tracks(selectors: &[&str], expected: &[u32]) {
+        let track = |id, name, kind: MediaKind, language: Option<&str>| Track {
+            id,
+            info: std::sync::Arc::new(crate::MediaInfo { name, kind }),
+            timebase: crate::Fraction::new(1, 1000),
+            delay: 0,
+            metadata: crate::TrackMetadata {
+                language: language.map(Into::into),
+                ..Default::default()
+            },
+        };
+        let video = || {
+            MediaKind::Video(crate::VideoInfo {
+                width: 16,
+                height: 16,
+                codec: crate::VideoCodec::Vp8(Default::default()),
+            })
+        };
+        let audio = || {
+            MediaKind::Audio(crate::AudioInfo {
+                sample_rate: 48_000,
+                sample_bpp: 16,
+                sound_type: crate::SoundType::Stereo,
+                codec: crate::AudioCodec::Aac(crate::AacCodec { extra: Vec::new() }),
+            })
+        };
+        let tracks = [
+            track(1, "vp8", video(), None),
+            track(2, "aac", audio(), Some("jpn")),
+            track(3, "aac", audio(), Some("en-US")),
+            track(4, "vp8", video(), Some("und")),
+        ];
+
+        let selectors = selectors.iter().map(|s| s.parse().unwrap()).collect::<Vec<_>>();
+        let ids = TrackSelector::select_all(&selectors, &tracks)
+            .iter()
+            .map(|t| t.id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(expected, ids);
+    }
+
+    /// A live input which starts a new movie with the same tracks halfway through its packets.
+    struct RestartingInput {
+        movie: Movie,
+        packets: VecDeque<Packet>,
+        events: Vec<DemuxerEvent>,
+    }
+
+    #[async_trait(?Send)]
+    impl Demuxer for RestartingInput {
+        async fn start(&mut self) -> crate::Result<Movie> {
+            Ok(self.movie.clone())
+        }
+
+        async fn read(&mut self) -> crate::Result<Packet> {
+            let pkt = self
+                .packets
+                .pop_front()
+                .ok_or(crate::MediaboxError::EndOfInput)?;
+            if self.packets.len() == 4 {
+                self.events.push(DemuxerEvent::NewMovie(self.movie.clone()));
+            }
+
+            Ok(pkt)
+        }
+
+        async fn stop(&mut self) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn next_event(&mut self) -> Option<DemuxerEvent> {
+            self.events.pop()
+        }
+
+        fn create(io: Io) -> Box<dyn Demuxer> {
+            unimplemented!()
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[tokio::test]
+    async fn keep_selection_after_new_movie() {
+        let tracks = [
+            test::aac_track(),
+            Track {
+                id: 3,
+                ..test::aac_track()
+            },
+        ];
+        let movie = Movie {
+            tracks: tracks.to_vec(),
+            ..Default::default()
+        };
+        let mut input = RestartingInput {
+            movie: movie.clone(),
+            packets: (0..8)
+                .map(|i| test::packet(&tracks[i % 2], i as u64 / 2 * 1024, Some(1024), vec![0; 4]))
+                .collect(),
+            events: Vec::new(),
+        };
+
+        let path = std::env::temp_dir().join(format!("mediabox-select-{}.aac", std::process::id()));
+        let mut cxt = MediaContext::default();
+        cxt.register_all();
+
+        let select = |tracks: &[Track]| {
+            TrackSelector::Id(3)
+                .select(tracks)
+                .into_iter()
+                .cloned()
+                .collect()
+        };
+        copy(
+            &cxt,
+            &mut input,
+            movie,
+            path.to_str().unwrap(),
+            select,
+            |pkt| Some(vec![pkt]),
+        )
+        .await
+        .unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut demuxer = crate::format::adts::AdtsDemuxer::new(Io::from_bytes(data));
+        let (_, packets) = test::read_movie_and_packets(&mut demuxer).await;
+
+        assert_eq!(4, packets.len());
+    }
+}
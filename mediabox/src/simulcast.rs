@@ -0,0 +1,406 @@
+use log::*;
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    codec::nal::{convert_bitstream, BitstreamFraming},
+    format::{Demuxer, Movie, Muxer, MuxerOptions},
+    io::Io,
+    H264Codec, MediaContext, MediaInfo, MediaKind, Packet, Track, VideoCodec, VideoInfo,
+};
+
+/// Describes a single output of a [`Simulcast`].
+#[derive(Debug, Clone)]
+pub struct SimulcastOutput {
+    container: String,
+    url: String,
+    tracks: Option<Vec<u32>>,
+    framing: Option<BitstreamFraming>,
+    options: MuxerOptions,
+}
+
+impl SimulcastOutput {
+    /// Creates an output which writes all tracks into the given container.
+    ///
+    /// The container is the name of a registered muxer, or `hls` if the `hls` feature is
+    /// enabled.
+    pub fn new(container: impl Into<String>, url: impl Into<String>) -> Self {
+        SimulcastOutput {
+            container: container.into(),
+            url: url.into(),
+            tracks: None,
+            framing: None,
+            options: MuxerOptions::new(),
+        }
+    }
+
+    /// Only write the tracks with the given ids to this output.
+    pub fn with_tracks(mut self, tracks: &[u32]) -> Self {
+        self.tracks = Some(tracks.to_vec());
+        self
+    }
+
+    /// Converts H.264 tracks to the given bitstream framing before writing them to this output.
+    pub fn with_framing(mut self, framing: BitstreamFraming) -> Self {
+        self.framing = Some(framing);
+        self
+    }
+
+    /// Passes options to the muxer of this output, see [`MuxerOptions`].
+    pub fn with_options(mut self, options: MuxerOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// A started output with the tracks it accepts, keyed by the input track id.
+struct SimulcastSink {
+    url: String,
+    muxer: Box<dyn Muxer>,
+    tracks: HashMap<u32, Track>,
+}
+
+impl SimulcastSink {
+    /// Starts the muxer with the tracks of the output, ordered by their input track id.
+    async fn start(
+        url: String,
+        mut muxer: Box<dyn Muxer>,
+        tracks: HashMap<u32, Track>,
+    ) -> anyhow::Result<Self> {
+        let mut sink_tracks = tracks.values().cloned().collect::<Vec<_>>();
+        sink_tracks.sort_by_key(|t| t.id);
+        muxer.start(sink_tracks).await?;
+
+        Ok(SimulcastSink { url, muxer, tracks })
+    }
+
+    async fn write(&mut self, pkt: &Packet) -> anyhow::Result<()> {
+        let Some(track) = self.tracks.get(&pkt.track.id) else {
+            return Ok(());
+        };
+
+        let mut pkt = pkt.clone();
+        if let (Some(source), Some(target)) = (h264_framing(&pkt.track), h264_framing(track)) {
+            pkt.buffer = convert_bitstream(pkt.buffer, source, target);
+        }
+        pkt.track = track.clone();
+
+        Ok(self.muxer.write(pkt).await?)
+    }
+}
+
+/// Reads packets from one input and writes them to several outputs, e.g. to record a stream
+/// while also serving it over HLS.
+///
+/// Every output can select a subset of the input tracks and request a specific H.264 bitstream
+/// framing, the necessary conversions are set up automatically.
+pub struct Simulcast {
+    demuxer: Box<dyn Demuxer>,
+    sinks: Vec<SimulcastSink>,
+}
+
+impl Simulcast {
+    /// Starts the demuxer and creates and starts a muxer for every output.
+    pub async fn new(
+        cxt: &MediaContext,
+        mut demuxer: Box<dyn Demuxer>,
+        outputs: Vec<SimulcastOutput>,
+    ) -> anyhow::Result<Self> {
+        let movie = demuxer.start().await?;
+
+        let mut sinks = Vec::new();
+        for output in outputs {
+            sinks.push(create_sink(cxt, &movie, output).await?);
+        }
+
+        Ok(Simulcast { demuxer, sinks })
+    }
+
+    /// Copies packets from the input to all outputs until the input ends, and then stops all
+    /// outputs.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            let pkt = match self.demuxer.read().await {
+                Ok(pkt) => pkt,
+                Err(e) if e.is_end_of_input() => {
+                    debug!("Input ended");
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            for sink in &mut self.sinks {
+                sink.write(&pkt).await?;
+            }
+        }
+
+        self.stop().await
+    }
+
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        self.demuxer.stop().await?;
+
+        for sink in &mut self.sinks {
+            debug!("Stopping output {:?}", sink.url);
+
+            sink.muxer.stop().await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn create_sink(
+    cxt: &MediaContext,
+    movie: &Movie,
+    output: SimulcastOutput,
+) -> anyhow::Result<SimulcastSink> {
+    let tracks = output_tracks(movie, &output);
+
+    let sink_movie = Movie {
+        tracks: tracks.values().cloned().collect(),
+        ..movie.clone()
+    };
+
+    let muxer = create_muxer(cxt, &output, &sink_movie).await?;
+
+    SimulcastSink::start(output.url, muxer, tracks).await
+}
+
+/// Returns the tracks an output accepts, converted to its framing and keyed by the input track
+/// id.
+fn output_tracks(movie: &Movie, output: &SimulcastOutput) -> HashMap<u32, Track> {
+    movie
+        .tracks
+        .iter()
+        .filter(|t| output.tracks.as_ref().is_none_or(|ids| ids.contains(&t.id)))
+        .map(|t| (t.id, with_framing(t, output.framing)))
+        .collect()
+}
+
+async fn create_muxer(
+    cxt: &MediaContext,
+    output: &SimulcastOutput,
+    movie: &Movie,
+) -> anyhow::Result<Box<dyn Muxer>> {
+    #[cfg(feature = "hls")]
+    if output.container == "hls" {
+        use crate::format::hls::{HlsMuxer, HlsOptions};
+
+        let options = HlsOptions::try_from(&output.options)?;
+        let mut hls = HlsMuxer::new(&output.url).await?.with_options(options);
+
+        return Ok(Box::new(hls.new_stream(movie).await?));
+    }
+
+    let meta = cxt.find_muxer(&output.container)?;
+    let io = Io::create(output.url.clone()).await?;
+
+    meta.create_with_options(io, &output.options)
+}
+
+fn h264_framing(track: &Track) -> Option<BitstreamFraming> {
+    match &track.info.kind {
+        MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(H264Codec {
+                bitstream_format, ..
+            }),
+            ..
+        }) => Some(*bitstream_format),
+        _ => None,
+    }
+}
+
+/// Returns a copy of the track with its H.264 bitstream framing replaced.
+fn with_framing(track: &Track, framing: Option<BitstreamFraming>) -> Track {
+    let mut track = track.clone();
+
+    let (Some(framing), MediaKind::Video(video)) = (framing, &track.info.kind) else {
+        return track;
+    };
+
+    let VideoCodec::H264(codec) = &video.codec else {
+        return track;
+    };
+
+    let info = MediaInfo {
+        name: track.info.name,
+        kind: MediaKind::Video(VideoInfo {
+            codec: VideoCodec::H264(H264Codec {
+                bitstream_format: framing,
+                ..codec.clone()
+            }),
+            ..video.clone()
+        }),
+    };
+
+    track.info = Arc::new(info);
+
+    track
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{format::Demuxer, test, MediaboxError};
+    use async_trait::async_trait;
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    /// An input which returns the given results and then ends.
+    struct MemoryInput {
+        movie: Movie,
+        results: VecDeque<crate::Result<Packet>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Demuxer for MemoryInput {
+        async fn start(&mut self) -> crate::Result<Movie> {
+            Ok(self.movie.clone())
+        }
+
+        async fn read(&mut self) -> crate::Result<Packet> {
+            self.results
+                .pop_front()
+                .unwrap_or(Err(MediaboxError::EndOfInput))
+        }
+
+        async fn stop(&mut self) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn create(io: Io) -> Box<dyn Demuxer> {
+            unimplemented!()
+        }
+    }
+
+    /// What a [`MemoryOutput`] was started with and written to.
+    #[derive(Default)]
+    struct Written {
+        tracks: Vec<Track>,
+        packets: Vec<Packet>,
+        stopped: bool,
+    }
+
+    struct MemoryOutput(Arc<Mutex<Written>>);
+
+    #[async_trait]
+    impl Muxer for MemoryOutput {
+        async fn start(&mut self, tracks: Vec<Track>) -> crate::Result<()> {
+            self.0.lock().unwrap().tracks = tracks;
+            Ok(())
+        }
+
+        async fn write(&mut self, packet: Packet) -> crate::Result<()> {
+            self.0.lock().unwrap().packets.push(packet);
+            Ok(())
+        }
+
+        async fn stop(&mut self) -> crate::Result<()> {
+            self.0.lock().unwrap().stopped = true;
+            Ok(())
+        }
+
+        fn into_io(self) -> Io {
+            Io::null()
+        }
+    }
+
+    async fn simulcast(
+        results: Vec<crate::Result<Packet>>,
+        outputs: Vec<SimulcastOutput>,
+    ) -> (Simulcast, Vec<Arc<Mutex<Written>>>) {
+        let movie = Movie {
+            tracks: vec![test::h264_track(), test::aac_track()],
+            ..Default::default()
+        };
+
+        let mut sinks = Vec::new();
+        let mut written = Vec::new();
+        for output in outputs {
+            let output_written = Arc::new(Mutex::new(Written::default()));
+            let muxer = Box::new(MemoryOutput(output_written.clone()));
+            let tracks = output_tracks(&movie, &output);
+
+            sinks.push(
+                SimulcastSink::start(output.url, muxer, tracks)
+                    .await
+                    .unwrap(),
+            );
+            written.push(output_written);
+        }
+
+        let demuxer = Box::new(MemoryInput {
+            movie,
+            results: results.into(),
+        });
+
+        (Simulcast { demuxer, sinks }, written)
+    }
+
+    #[tokio::test]
+    async fn write_track_subsets_with_their_framing() {
+        let video = test::h264_track();
+        let audio = test::aac_track();
+        let packets = vec![
+            Ok(test::packet(
+                &video,
+                0,
+                Some(3000),
+                vec![0, 0, 0, 2, 0x65, 0x88],
+            )),
+            Ok(test::packet(&audio, 0, Some(1024), vec![0x21])),
+        ];
+        let outputs = vec![
+            SimulcastOutput::new("h264", "video.h264")
+                .with_tracks(&[video.id])
+                .with_framing(BitstreamFraming::FourByteStartCode),
+            SimulcastOutput::new("mkv", "all.mkv"),
+        ];
+
+        let (mut simulcast, written) = simulcast(packets, outputs).await;
+        simulcast.run().await.unwrap();
+
+        let video_only = written[0].lock().unwrap();
+        assert!(video_only.stopped);
+        assert_eq!(1, video_only.tracks.len());
+        assert_eq!(
+            Some(BitstreamFraming::FourByteStartCode),
+            h264_framing(&video_only.tracks[0])
+        );
+        assert_eq!(1, video_only.packets.len());
+        assert_eq!(
+            &[0, 0, 0, 1, 0x65, 0x88][..],
+            &video_only.packets[0].buffer.to_slice()[..]
+        );
+
+        let all = written[1].lock().unwrap();
+        assert!(all.stopped);
+        assert_eq!(
+            vec![1, 2],
+            all.tracks.iter().map(|t| t.id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            &[0, 0, 0, 2, 0x65, 0x88][..],
+            &all.packets[0].buffer.to_slice()[..]
+        );
+        assert_eq!(2, all.packets.len());
+    }
+
+    #[tokio::test]
+    async fn fail_on_input_errors() {
+        let audio = test::aac_track();
+        let results = vec![
+            Ok(test::packet(&audio, 0, Some(1024), vec![0x21])),
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()),
+        ];
+
+        let (mut simulcast, written) =
+            simulcast(results, vec![SimulcastOutput::new("mkv", "all.mkv")]).await;
+
+        assert!(simulcast.run().await.is_err());
+        assert_eq!(1, written[0].lock().unwrap().packets.len());
+    }
+}
@@ -0,0 +1,254 @@
+//! Grabs decoded frames of a video track, e.g. to generate thumbnails.
+
+use log::*;
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    time::Duration,
+};
+
+use crate::{
+    codec::{Decoder, VideoFrame},
+    time::to_duration,
+    Packet, Track,
+};
+
+/// A decoded frame grabbed by a [`SnapshotService`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The presentation time of the frame.
+    pub time: Duration,
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed 8 bit RGB, see [`VideoFrame::to_rgb24`].
+    pub rgb: Vec<u8>,
+}
+
+impl From<&VideoFrame> for Snapshot {
+    fn from(frame: &VideoFrame) -> Self {
+        Snapshot {
+            time: to_duration(frame.time.pts, frame.time.timebase),
+            width: frame.width,
+            height: frame.height,
+            rgb: frame.to_rgb24(),
+        }
+    }
+}
+
+/// Grabs frames of a video track at a fixed interval or at requested times, decoding only what
+/// is needed for them.
+///
+/// The packets since the last keyframe are kept, so a frame is found by decoding forward from
+/// the keyframe before it once a packet at or after the wanted time has been fed. Every
+/// snapshot is the first frame presented at or after the time it was wanted at.
+///
+/// ```ignore
+/// let decoder = cxt.find_decoder(&track.info)?;
+/// let mut snapshots = SnapshotService::new(&track, decoder)?.with_interval(Duration::from_secs(10));
+///
+/// while let Ok(pkt) = demuxer.read().await {
+///     if pkt.track.id == track.id {
+///         snapshots.feed(pkt)?;
+///     }
+///
+///     while let Some(snapshot) = snapshots.receive() {
+///         write_thumbnail(snapshot);
+///     }
+/// }
+/// ```
+pub struct SnapshotService {
+    decoder: Box<dyn Decoder>,
+    interval: Option<Duration>,
+    /// The time of the next snapshot taken at the interval.
+    next_interval: Option<Duration>,
+    requests: BTreeSet<Duration>,
+    /// The packets since the last keyframe.
+    gop: Vec<Packet>,
+    /// How many packets of the GOP the decoder has been fed.
+    fed: usize,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotService {
+    /// Starts the decoder for the given video track.
+    pub fn new(track: &Track, mut decoder: Box<dyn Decoder>) -> crate::Result<Self> {
+        decoder.start(&track.info)?;
+
+        Ok(SnapshotService {
+            decoder,
+            interval: None,
+            next_interval: None,
+            requests: BTreeSet::new(),
+            gop: Vec::new(),
+            fed: 0,
+            snapshots: VecDeque::new(),
+        })
+    }
+
+    /// Grabs a frame every `interval`, starting with the first frame.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Grabs the first frame at or after `time`. If packets after `time` have already been fed,
+    /// the frame is only found if the decoder hasn't moved past it in the current GOP, otherwise
+    /// the next frame decoded is taken.
+    pub fn request(&mut self, time: Duration) {
+        self.requests.insert(time);
+    }
+
+    pub fn feed(&mut self, packet: Packet) -> crate::Result<()> {
+        let time = to_duration(packet.time.pts, packet.time.timebase);
+
+        if packet.key {
+            if self.fed < self.gop.len() {
+                trace!("Skipping {} packets", self.gop.len() - self.fed);
+            }
+
+            self.gop.clear();
+            self.fed = 0;
+        }
+
+        if self.gop.is_empty() && !packet.key {
+            trace!("Dropping packet before first keyframe: {packet:?}");
+            return Ok(());
+        }
+
+        if self.interval.is_some() && self.next_interval.is_none() {
+            self.next_interval = Some(time);
+        }
+
+        self.gop.push(packet);
+
+        if self.next_target().is_some_and(|target| target <= time) {
+            self.decode_forward()?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the next snapshot, in the order they were grabbed.
+    pub fn receive(&mut self) -> Option<Snapshot> {
+        self.snapshots.pop_front()
+    }
+
+    fn next_target(&self) -> Option<Duration> {
+        let request = self.requests.first().copied();
+
+        match (request, self.next_interval) {
+            (Some(request), Some(interval)) => Some(request.min(interval)),
+            (request, interval) => request.or(interval),
+        }
+    }
+
+    /// Feeds the packets of the GOP the decoder hasn't seen yet and grabs the frames which are
+    /// due.
+    fn decode_forward(&mut self) -> crate::Result<()> {
+        for packet in &self.gop[self.fed..] {
+            self.decoder.feed(packet.clone())?;
+            self.fed += 1;
+
+            while let Some(decoded) = self.decoder.receive() {
+                let Some(frame) = decoded.into_video() else {
+                    continue;
+                };
+
+                let time = to_duration(frame.time.pts, frame.time.timebase);
+                if self.next_target().is_none_or(|target| target > time) {
+                    continue;
+                }
+
+                while let Some(&request) = self.requests.first().filter(|&&r| r <= time) {
+                    self.requests.remove(&request);
+                }
+
+                if let (Some(interval), Some(next)) = (self.interval, self.next_interval) {
+                    // skip the intervals without a frame of their own
+                    let missed = (time - next).as_nanos() / interval.as_nanos().max(1);
+                    self.next_interval = Some(next + interval * (missed as u32 + 1));
+                }
+
+                self.snapshots.push_back(Snapshot::from(&frame));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        codec::rawvideo::RawVideoDecoder, test, Fraction, MediaKind, PixelFormat, RawVideoCodec,
+        VideoCodec, VideoInfo,
+    };
+
+    fn track() -> Track {
+        let kind = MediaKind::Video(VideoInfo {
+            width: 2,
+            height: 2,
+            codec: VideoCodec::Raw(RawVideoCodec {
+                format: PixelFormat::Yuv420p,
+            }),
+        });
+
+        test::track(0, Fraction::new(1, 10), "rawvideo", kind)
+    }
+
+    /// A grey frame which is a little brighter than the one before, one every 100 ms.
+    fn packet(track: &Track, pts: u64, key: bool) -> Packet {
+        let data = [vec![16 + pts as u8; 4], vec![128; 2]].concat();
+
+        Packet {
+            key,
+            ..test::packet(track, pts, Some(1), data)
+        }
+    }
+
+    #[test]
+    fn grab_at_interval() {
+        let track = track();
+        let mut snapshots = SnapshotService::new(&track, Box::new(RawVideoDecoder::new()))
+            .unwrap()
+            .with_interval(Duration::from_millis(450));
+
+        for pts in 0..20 {
+            snapshots.feed(packet(&track, pts, pts % 5 == 0)).unwrap();
+        }
+
+        let times = std::iter::from_fn(|| snapshots.receive())
+            .map(|s| s.time.as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 500, 900, 1400, 1800], times);
+    }
+
+    #[test]
+    fn grab_requested_frames() {
+        let track = track();
+        let mut snapshots = SnapshotService::new(&track, Box::new(RawVideoDecoder::new())).unwrap();
+
+        snapshots.request(Duration::from_millis(1250));
+        snapshots.request(Duration::from_millis(300));
+        for pts in 0..8 {
+            snapshots.feed(packet(&track, pts, pts % 5 == 0)).unwrap();
+        }
+
+        // a frame of the current GOP which has already been fed
+        snapshots.request(Duration::from_millis(600));
+        for pts in 8..20 {
+            snapshots.feed(packet(&track, pts, pts % 5 == 0)).unwrap();
+        }
+
+        let snapshots = std::iter::from_fn(|| snapshots.receive()).collect::<Vec<_>>();
+        let times = snapshots
+            .iter()
+            .map(|s| s.time.as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![300, 600, 1300], times);
+
+        // the luma of frame 3 is 19, just above black
+        assert_eq!(vec![3; 12], snapshots[0].rgb);
+    }
+}
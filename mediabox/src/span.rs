@@ -1,8 +1,11 @@
-use bytes::Bytes;
+use bytes::{buf::UninitSlice, Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, ReadBuf};
 
 use std::borrow::Cow;
-use std::io::IoSlice;
+use std::io::{self, BufRead, IoSlice, Read};
 use std::ops::{Range, RangeBounds};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 pub struct SpanIterator<'a>(&'a Span, usize);
 
@@ -46,6 +49,29 @@ impl FromIterator<Span> for Span {
     }
 }
 
+impl<'a> IntoIterator for &'a Span {
+    type Item = &'a [u8];
+    type IntoIter = SpanIterator<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.spans()
+    }
+}
+
+impl IntoIterator for Span {
+    type Item = Bytes;
+    type IntoIter = std::vec::IntoIter<Bytes>;
+
+    /// Consumes the span, yielding its internal byte sequences without copying them.
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            Span::Many(spans) => spans.into_iter(),
+            Span::Single(span) => vec![span].into_iter(),
+            Span::Static(span) => vec![Bytes::from_static(span)].into_iter(),
+        }
+    }
+}
+
 impl From<&'static [u8]> for Span {
     fn from(bytes: &'static [u8]) -> Self {
         Span::Static(bytes)
@@ -141,6 +167,12 @@ impl Span {
         SpanIterator(self, 0)
     }
 
+    /// Returns a reader over the bytes of the span, which reads them without first coalescing
+    /// them into one slice.
+    pub fn reader(self) -> SpanReader {
+        SpanReader::new(self)
+    }
+
     pub fn to_io_slice<'a>(&'a self) -> Vec<IoSlice<'a>> {
         match self {
             Span::Many(spans) => spans.iter().map(|s| IoSlice::new(s)).collect::<Vec<_>>(),
@@ -179,6 +211,274 @@ impl Span {
     }
 }
 
+/// Reads the bytes of a [Span] one internal byte sequence at a time. Created by [Span::reader].
+#[derive(Debug)]
+pub struct SpanReader {
+    /// Always non-empty, unless the reader is exhausted.
+    current: Bytes,
+    rest: std::vec::IntoIter<Bytes>,
+    remaining: usize,
+}
+
+impl SpanReader {
+    fn new(span: Span) -> Self {
+        let mut reader = SpanReader {
+            current: Bytes::new(),
+            remaining: span.len(),
+            rest: span.into_iter(),
+        };
+        reader.skip_empty();
+
+        reader
+    }
+
+    fn skip_empty(&mut self) {
+        while self.current.is_empty() {
+            match self.rest.next() {
+                Some(next) => self.current = next,
+                None => return,
+            }
+        }
+    }
+}
+
+impl Buf for SpanReader {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &self.current
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let chunks = std::iter::once(&self.current)
+            .chain(self.rest.as_slice())
+            .filter(|b| !b.is_empty());
+
+        dst.iter_mut()
+            .zip(chunks)
+            .map(|(slice, chunk)| *slice = IoSlice::new(chunk))
+            .count()
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.remaining, "Advanced past the end of the span");
+
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let n = cnt.min(self.current.len());
+            self.current.advance(n);
+            self.skip_empty();
+            cnt -= n;
+        }
+    }
+
+    fn copy_to_bytes(&mut self, len: usize) -> Bytes {
+        // only copy if the bytes are spread over multiple sequences
+        if len <= self.current.len() {
+            let bytes = self.current.split_to(len);
+            self.remaining -= len;
+            self.skip_empty();
+
+            return bytes;
+        }
+
+        let mut bytes = BytesMut::with_capacity(len);
+        bytes.put(Buf::take(self, len));
+
+        bytes.freeze()
+    }
+}
+
+impl Read for SpanReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.current.len());
+        self.copy_to_slice(&mut buf[..n]);
+
+        Ok(n)
+    }
+}
+
+impl BufRead for SpanReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(&self.current)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.advance(amt);
+    }
+}
+
+impl AsyncRead for SpanReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let n = buf.remaining().min(this.current.len());
+        buf.put_slice(&this.current[..n]);
+        this.advance(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Spans shorter than this are copied by [SpanBuilder::put_span] instead of being referenced.
+const MIN_SHARED_SPAN: usize = 256;
+
+#[derive(Debug)]
+enum Part {
+    Owned(BytesMut),
+    Shared(Bytes),
+}
+
+/// Builds a [Span] from written bytes and appended spans. Appended spans are referenced rather
+/// than copied, and space can be reserved for fields such as lengths which are only known once
+/// the data following them has been written.
+#[derive(Debug, Default)]
+pub struct SpanBuilder {
+    parts: Vec<Part>,
+    len: usize,
+}
+
+/// Space reserved with [SpanBuilder::reserve], to be filled in with [SpanBuilder::patch].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Reserved {
+    part: usize,
+    offset: usize,
+    position: usize,
+    size: usize,
+}
+
+impl Reserved {
+    /// The position of the reserved space from the start of the builder.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The position just after the reserved space.
+    pub fn end(&self) -> usize {
+        self.position + self.size
+    }
+}
+
+impl SpanBuilder {
+    pub fn new() -> Self {
+        SpanBuilder::default()
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends a span, referencing its data unless it is small enough to copy.
+    pub fn put_span(&mut self, span: Span) {
+        if span.len() < MIN_SHARED_SPAN {
+            for bytes in span.spans() {
+                self.put_slice(bytes);
+            }
+
+            return;
+        }
+
+        self.len += span.len();
+        self.parts.extend(
+            span.to_byte_spans()
+                .into_iter()
+                .filter(|b| !b.is_empty())
+                .map(Part::Shared),
+        );
+    }
+
+    /// Reserves `size` zeroed bytes to be filled in later with [SpanBuilder::patch].
+    pub fn reserve(&mut self, size: usize) -> Reserved {
+        let position = self.len;
+        let current = self.current();
+        let offset = current.len();
+        current.put_bytes(0, size);
+        self.len += size;
+
+        Reserved {
+            part: self.parts.len() - 1,
+            offset,
+            position,
+            size,
+        }
+    }
+
+    /// Fills in previously reserved space.
+    ///
+    /// # Panics
+    ///
+    /// If `bytes` is not the same length as the reserved space.
+    pub fn patch(&mut self, reserved: Reserved, bytes: &[u8]) {
+        assert_eq!(
+            reserved.size,
+            bytes.len(),
+            "Patch must fill the reserved space"
+        );
+
+        match &mut self.parts[reserved.part] {
+            Part::Owned(buf) => {
+                buf[reserved.offset..reserved.offset + reserved.size].copy_from_slice(bytes)
+            }
+            Part::Shared(_) => unreachable!("Reserved space is always owned"),
+        }
+    }
+
+    pub fn build(self) -> Span {
+        let mut spans = self
+            .parts
+            .into_iter()
+            .map(|part| match part {
+                Part::Owned(buf) => buf.freeze(),
+                Part::Shared(bytes) => bytes,
+            })
+            .filter(|b| !b.is_empty())
+            .collect::<Vec<_>>();
+
+        match spans.len() {
+            0 => Span::Static(&[]),
+            1 => Span::Single(spans.remove(0)),
+            _ => Span::Many(spans),
+        }
+    }
+
+    /// The buffer currently being written to.
+    fn current(&mut self) -> &mut BytesMut {
+        if !matches!(self.parts.last(), Some(Part::Owned(_))) {
+            self.parts.push(Part::Owned(BytesMut::new()));
+        }
+
+        match self.parts.last_mut() {
+            Some(Part::Owned(buf)) => buf,
+            _ => unreachable!(),
+        }
+    }
+}
+
+unsafe impl BufMut for SpanBuilder {
+    fn remaining_mut(&self) -> usize {
+        usize::MAX - self.len
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.current().advance_mut(cnt);
+        self.len += cnt;
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        self.current().chunk_mut()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -202,4 +502,49 @@ mod test {
 
         assert_eq!(expected, bytes);
     }
+
+    #[test]
+    fn read_across_spans() {
+        let span = [b"ab", &b""[..], b"cdef", b"g"]
+            .iter()
+            .map(|&s| Span::from(s))
+            .collect::<Span>();
+
+        let mut reader = span.clone().reader();
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(b"abc", &buf);
+        assert_eq!(&b"def"[..], reader.copy_to_bytes(3));
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(b"g", &rest[..]);
+        assert!(!reader.has_remaining());
+
+        let mut slices = [IoSlice::new(&[]); 4];
+        let reader = span.reader();
+        assert_eq!(3, reader.chunks_vectored(&mut slices));
+    }
+
+    #[test]
+    fn build_with_patch() {
+        let shared = Bytes::from(vec![7u8; MIN_SHARED_SPAN]);
+
+        let mut buf = SpanBuilder::new();
+        let length = buf.reserve(4);
+        buf.put_slice(b"data");
+        buf.put_span(shared.clone().into());
+        buf.put_u8(1);
+        let len = (buf.len() - length.end()) as u32;
+        buf.patch(length, &len.to_be_bytes());
+
+        let span = buf.build();
+        let mut expected = [&len.to_be_bytes()[..], b"data", &shared, &[1]].concat();
+
+        assert_eq!(3, span.spans().count());
+        assert_eq!(expected, span.to_bytes());
+
+        expected.truncate(8);
+        assert_eq!(expected, span.slice(..8).to_bytes());
+    }
 }
@@ -1,6 +1,23 @@
+use futures::StreamExt;
 use tokio::fs::File;
 
-use crate::{Packet, format::{mkv::MatroskaDemuxer, Movie, Muxer, Demuxer}, io::Io};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    codec::{nal::BitstreamFraming, AudioFrame, CodecDescription, Decoded, Decoder, Encoder},
+    format::{mkv::MatroskaDemuxer, Demuxer, DemuxerMetadata, Movie, Muxer},
+    io::Io,
+    time::to_duration,
+    AacCodec, AudioCodec, AudioInfo, Fraction, H264Codec, MediaContext, MediaInfo, MediaKind,
+    MediaTime, Packet, SoundType, Track, VideoCodec, VideoInfo,
+};
 
 pub struct TestFile {
     pub path: &'static str,
@@ -47,7 +64,7 @@ pub async fn read_mkv_from_io(io: Io) -> (Movie, Vec<Packet>) {
 }
 
 pub async fn write_movie_and_packets(muxer: &mut dyn Muxer, movie: Movie, packets: &[Packet]) {
-    muxer.start(movie.tracks).await.unwrap();
+    muxer.start_movie(movie).await.unwrap();
     for pkt in packets {
         muxer.write(pkt.clone()).await.unwrap();
     }
@@ -55,14 +72,603 @@ pub async fn write_movie_and_packets(muxer: &mut dyn Muxer, movie: Movie, packet
 }
 
 pub async fn read_movie_and_packets(demuxer: &mut dyn Demuxer) -> (Movie, Vec<Packet>) {
-    let movie = demuxer.start().await.unwrap();
+    try_read_movie_and_packets(demuxer).await.unwrap()
+}
+
+pub async fn try_read_movie_and_packets(
+    demuxer: &mut dyn Demuxer,
+) -> crate::Result<(Movie, Vec<Packet>)> {
+    let movie = demuxer.start().await?;
     let mut packets = Vec::new();
 
-    while let Ok(pkt) = demuxer.read().await {
-        packets.push(pkt);
+    let mut stream = demuxer.packets();
+    while let Some(pkt) = stream.next().await {
+        packets.push(pkt?);
+    }
+    drop(stream);
+
+    demuxer.stop().await?;
+
+    Ok((movie, packets))
+}
+
+pub fn track(id: u32, timebase: Fraction, name: &'static str, kind: MediaKind) -> Track {
+    Track {
+        id,
+        info: Arc::new(MediaInfo { name, kind }),
+        timebase,
+        delay: 0,
+        metadata: Default::default(),
+    }
+}
+
+/// A 320x240 H.264 track with id 1 and a 90 kHz timebase, whose NAL units are prefixed by their
+/// length as in MP4.
+pub fn h264_track() -> Track {
+    h264_track_with_framing(BitstreamFraming::FourByteLength)
+}
+
+pub fn h264_track_with_framing(framing: BitstreamFraming) -> Track {
+    track(
+        1,
+        Fraction::new(1, 90_000),
+        "h264",
+        MediaKind::Video(VideoInfo {
+            width: 320,
+            height: 240,
+            codec: VideoCodec::H264(H264Codec {
+                bitstream_format: framing,
+                profile_indication: 100,
+                profile_compatibility: 0,
+                level_indication: 10,
+                sps: vec![0x67].into(),
+                pps: vec![0x68].into(),
+            }),
+        }),
+    )
+}
+
+/// A 48 kHz stereo AAC-LC track with id 2, timed in samples.
+pub fn aac_track() -> Track {
+    track(
+        2,
+        Fraction::new(1, 48_000),
+        "aac",
+        MediaKind::Audio(AudioInfo {
+            sample_rate: 48_000,
+            sample_bpp: 16,
+            sound_type: SoundType::Stereo,
+            codec: AudioCodec::Aac(AacCodec {
+                extra: vec![0x11, 0x90],
+            }),
+        }),
+    )
+}
+
+/// A keyframe of `track` without a decode timestamp.
+pub fn packet(track: &Track, pts: u64, duration: Option<u64>, data: Vec<u8>) -> Packet {
+    Packet {
+        time: MediaTime {
+            pts,
+            dts: None,
+            duration,
+            timebase: track.timebase,
+        },
+        key: true,
+        track: track.clone(),
+        buffer: data.into(),
+        side_data: Vec::new(),
+    }
+}
+
+/// Decodes every packet into a silent mono audio frame as long as the packet.
+#[derive(Default)]
+pub struct SilenceDecoder {
+    frames: VecDeque<AudioFrame>,
+}
+
+impl Decoder for SilenceDecoder {
+    fn start(&mut self, _info: &MediaInfo) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn feed(&mut self, packet: Packet) -> crate::Result<()> {
+        self.frames.push_back(AudioFrame {
+            samples: vec![0.0; packet.time.duration.unwrap_or(0) as usize],
+            time: packet.time,
+            sample_rate: 48_000,
+            channels: 1,
+        });
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Decoded> {
+        self.frames.pop_front().map(Decoded::Audio)
     }
+}
+
+/// Encodes every audio frame into an empty [`aac_track`] packet, which starts `delay` samples
+/// later than the frame like with an encoder that inserts priming samples. Every frame takes
+/// `latency` to encode.
+pub struct SilenceEncoder {
+    delay: u64,
+    latency: Duration,
+    track: Option<Track>,
+    packets: VecDeque<Packet>,
+}
+
+impl SilenceEncoder {
+    pub fn new(delay: u64, latency: Duration) -> Self {
+        SilenceEncoder {
+            delay,
+            latency,
+            track: None,
+            packets: VecDeque::new(),
+        }
+    }
+}
+
+impl Encoder for SilenceEncoder {
+    fn start(&mut self, _desc: CodecDescription) -> crate::Result<Track> {
+        let track = Track {
+            delay: self.delay(),
+            ..aac_track()
+        };
+        self.track = Some(track.clone());
+
+        Ok(track)
+    }
+
+    fn feed(&mut self, raw: Decoded) -> crate::Result<()> {
+        let Decoded::Audio(frame) = raw else {
+            return Err(crate::MediaboxError::unsupported("Expected audio"));
+        };
+        std::thread::sleep(self.latency);
+
+        let track = self.track.as_ref().expect("Encoder is started");
+        let duration = frame.sample_count() as u64;
+        self.packets.push_back(packet(
+            track,
+            frame.time.pts + self.delay,
+            Some(duration),
+            Vec::new(),
+        ));
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Packet> {
+        self.packets.pop_front()
+    }
+
+    fn delay(&self) -> u64 {
+        self.delay
+    }
+}
+
+/// Splits the data of an MP4 box into its children, by type.
+pub fn mp4_boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+        boxes.push((data[4..8].try_into().unwrap(), &data[8..size]));
+        data = &data[size..];
+    }
+
+    boxes
+}
+
+/// Finds the data of the first box with the given path of types.
+pub fn find_mp4_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let (_, data) = mp4_boxes(data).into_iter().find(|(ty, _)| ty == *first)?;
+
+    if rest.is_empty() {
+        Some(data)
+    } else {
+        find_mp4_box(data, rest)
+    }
+}
+
+/// The outcome of one check of the conformance harness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    Passed,
+    /// The round trip wrote the input back byte-for-byte.
+    Identical,
+    Skipped(String),
+    Failed(String),
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Check::Passed => write!(f, "pass"),
+            Check::Identical => write!(f, "identical"),
+            Check::Skipped(reason) => write!(f, "skipped: {reason}"),
+            Check::Failed(reason) => write!(f, "**FAILED**: {reason}"),
+        }
+    }
+}
+
+/// How a file of the corpus fared in the conformance harness.
+#[derive(Debug)]
+pub struct Conformance {
+    pub file: String,
+    pub format: Option<&'static str>,
+    pub tracks: usize,
+    pub packets: usize,
+    pub demux: Check,
+    /// Whether the decode timestamps of every track never go back.
+    pub timestamps: Check,
+    /// Whether every packet belongs to a track of the movie, with the same codec.
+    pub consistency: Check,
+    /// Whether the matching muxer writes the file back identically, or with the same packets.
+    pub round_trip: Check,
+}
+
+impl Conformance {
+    fn new(file: String, demux: Check) -> Self {
+        let skipped = || Check::Skipped("not demuxed".into());
+
+        Conformance {
+            file,
+            format: None,
+            tracks: 0,
+            packets: 0,
+            demux,
+            timestamps: skipped(),
+            consistency: skipped(),
+            round_trip: skipped(),
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        [
+            &self.demux,
+            &self.timestamps,
+            &self.consistency,
+            &self.round_trip,
+        ]
+        .iter()
+        .any(|check| matches!(check, Check::Failed(_)))
+    }
+}
+
+/// The sample corpus, which is `tests/files` unless overridden with `MEDIABOX_CORPUS`.
+pub fn corpus_dir() -> PathBuf {
+    std::env::var_os("MEDIABOX_CORPUS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/files").into())
+}
+
+/// Runs every file of the corpus through the demuxer it probes as.
+pub async fn run_conformance(cxt: &MediaContext, dir: &Path) -> Vec<Conformance> {
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in paths {
+        results.push(check_file(cxt, &path).await);
+    }
+
+    results
+}
+
+async fn check_file(cxt: &MediaContext, path: &Path) -> Conformance {
+    let file = path.file_name().unwrap().to_string_lossy().into_owned();
+
+    let data = std::fs::read(path).unwrap();
+    if data.starts_with(b"version https://git-lfs") {
+        return Conformance::new(file, Check::Skipped("Git LFS pointer".into()));
+    }
+
+    let mut io = Io::open_file(path).await.unwrap();
+    let meta = match cxt.probe(&mut io).await {
+        Ok(meta) => meta,
+        Err(e) => return Conformance::new(file, Check::Failed(e.to_string())),
+    };
+
+    let mut demuxer = meta.create(io);
+    let (movie, packets) = match try_read_movie_and_packets(demuxer.as_mut()).await {
+        Ok(result) => result,
+        Err(e) => return Conformance::new(file, Check::Failed(e.to_string())),
+    };
+
+    let mut result = Conformance::new(file, Check::Passed);
+    result.format = Some(meta.name);
+    result.tracks = movie.tracks.len();
+    result.packets = packets.len();
+    result.timestamps = check_timestamps(&packets);
+    result.consistency = check_consistency(&movie, &packets);
+    result.round_trip = check_round_trip(cxt, &meta, &data, movie, &packets).await;
+
+    result
+}
+
+pub fn check_timestamps(packets: &[Packet]) -> Check {
+    let mut last = HashMap::new();
+
+    for (i, pkt) in packets.iter().enumerate() {
+        let dts = to_duration(pkt.time.dts.unwrap_or(pkt.time.pts), pkt.time.timebase);
+
+        if let Some(previous) = last.insert(pkt.track.id, dts) {
+            if dts < previous {
+                return Check::Failed(format!(
+                    "packet {i} of track {} goes back from {previous:?} to {dts:?}",
+                    pkt.track.id
+                ));
+            }
+        }
+    }
+
+    Check::Passed
+}
+
+pub fn check_consistency(movie: &Movie, packets: &[Packet]) -> Check {
+    let mut ids = HashSet::new();
+    if let Some(track) = movie.tracks.iter().find(|t| !ids.insert(t.id)) {
+        return Check::Failed(format!("track id {} is used twice", track.id));
+    }
+
+    for (i, pkt) in packets.iter().enumerate() {
+        let Some(track) = movie.tracks.iter().find(|t| t.id == pkt.track.id) else {
+            return Check::Failed(format!("packet {i} has unknown track {}", pkt.track.id));
+        };
+
+        if track.info.name != pkt.track.info.name {
+            return Check::Failed(format!(
+                "packet {i} is {} but track {} is {}",
+                pkt.track.info.name, track.id, track.info.name
+            ));
+        }
+    }
+
+    Check::Passed
+}
+
+async fn check_round_trip(
+    cxt: &MediaContext,
+    meta: &DemuxerMetadata,
+    data: &[u8],
+    movie: Movie,
+    packets: &[Packet],
+) -> Check {
+    let Ok(muxer_meta) = cxt.find_muxer(meta.name) else {
+        return Check::Skipped(format!("no {} muxer", meta.name));
+    };
+    if let Err(e) = muxer_meta.check_tracks(&movie.tracks) {
+        return Check::Skipped(e.to_string());
+    }
+
+    // muxers like MP4 seek back to patch their headers, which needs a file
+    let path = std::env::temp_dir().join(format!(
+        "mediabox-conformance-{}-{}",
+        std::process::id(),
+        meta.name
+    ));
+    let output = async {
+        let mut muxer = muxer_meta.create(Io::create_file(&path).await?);
+        muxer.start_movie(movie.clone()).await?;
+        for pkt in packets {
+            muxer.write(pkt.clone()).await?;
+        }
+        muxer.stop().await?;
+        drop(muxer);
+
+        Ok::<_, crate::MediaboxError>(std::fs::read(&path)?)
+    }
+    .await;
+    let _ = std::fs::remove_file(&path);
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => return Check::Failed(format!("muxing failed: {e}")),
+    };
+    if output == data {
+        return Check::Identical;
+    }
+
+    let mut demuxer = meta.create(Io::from_bytes(output));
+    match try_read_movie_and_packets(demuxer.as_mut()).await {
+        Ok((new_movie, new_packets)) => compare_packets(&movie, packets, &new_movie, &new_packets),
+        Err(e) => Check::Failed(format!("demuxing the output failed: {e}")),
+    }
+}
+
+/// Compares the packets of every track, by the position of the track in the movie as muxers may
+/// renumber them. Timestamps are compared in milliseconds, which every container can store.
+fn compare_packets(
+    movie: &Movie,
+    packets: &[Packet],
+    new_movie: &Movie,
+    new_packets: &[Packet],
+) -> Check {
+    if movie.tracks.len() != new_movie.tracks.len() {
+        return Check::Failed(format!(
+            "{} tracks were written back as {}",
+            movie.tracks.len(),
+            new_movie.tracks.len()
+        ));
+    }
+
+    let by_track = |movie: &Movie, packets: &[Packet]| {
+        let mut tracks = vec![Vec::new(); movie.tracks.len()];
+        for pkt in packets {
+            if let Some(i) = movie.tracks.iter().position(|t| t.id == pkt.track.id) {
+                tracks[i].push(pkt.clone());
+            }
+        }
+
+        tracks
+    };
+
+    let millis = Fraction::new(1, 1000);
+    for (i, (expected, actual)) in by_track(movie, packets)
+        .iter()
+        .zip(by_track(new_movie, new_packets))
+        .enumerate()
+    {
+        if expected.len() != actual.len() {
+            return Check::Failed(format!(
+                "track {i} has {} packets instead of {}",
+                actual.len(),
+                expected.len()
+            ));
+        }
+
+        for (j, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+            let mismatch = if expected.buffer.to_slice() != actual.buffer.to_slice() {
+                "data"
+            } else if expected.key != actual.key {
+                "key flag"
+            } else if expected.time.in_base(millis).pts != actual.time.in_base(millis).pts {
+                "timestamp"
+            } else {
+                continue;
+            };
+
+            return Check::Failed(format!("packet {j} of track {i} differs in {mismatch}"));
+        }
+    }
+
+    Check::Passed
+}
+
+/// Formats the results as a markdown table, followed by the registered demuxers which no file of
+/// the corpus was probed as.
+pub fn conformance_report(cxt: &MediaContext, results: &[Conformance]) -> String {
+    let mut report = String::from("# Demuxer conformance\n\n");
+    report.push_str(
+        "| File | Format | Tracks | Packets | Demux | Timestamps | Consistency | Round trip |\n",
+    );
+    report.push_str("|---|---|---|---|---|---|---|---|\n");
+
+    for result in results {
+        writeln!(
+            report,
+            "| {} | {} | {} | {} | {} | {} | {} | {} |",
+            result.file,
+            result.format.unwrap_or("-"),
+            result.tracks,
+            result.packets,
+            result.demux,
+            result.timestamps,
+            result.consistency,
+            result.round_trip
+        )
+        .unwrap();
+    }
+
+    let mut untested = cxt
+        .demuxer_meta
+        .keys()
+        .filter(|name| !results.iter().any(|r| r.format == Some(name.as_str())))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    untested.sort();
+    if !untested.is_empty() {
+        writeln!(
+            report,
+            "\nDemuxers without samples: {}",
+            untested.join(", ")
+        )
+        .unwrap();
+    }
+
+    report
+}
+
+/// Runs the corpus through the conformance harness, writing the report to the path in
+/// `MEDIABOX_CONFORMANCE_REPORT` if it is set.
+#[tokio::test]
+async fn corpus_conformance() {
+    let mut cxt = MediaContext::default();
+    cxt.register_all();
+
+    let results = run_conformance(&cxt, &corpus_dir()).await;
+    let report = conformance_report(&cxt, &results);
+    match std::env::var_os("MEDIABOX_CONFORMANCE_REPORT") {
+        Some(path) => std::fs::write(path, &report).unwrap(),
+        None => println!("{report}"),
+    }
+
+    assert!(!results.iter().any(Conformance::failed), "{report}");
+}
+
+#[tokio::test]
+async fn generated_corpus() {
+    use crate::{
+        format::wav::WavMuxer, AudioCodec, AudioInfo, MediaInfo, MediaKind, MediaTime, PcmCodec,
+        SampleFormat, SoundType, Track,
+    };
+    use std::sync::Arc;
+
+    let dir = std::env::temp_dir().join(format!("mediabox-corpus-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let track = Track {
+        id: 0,
+        info: Arc::new(MediaInfo {
+            name: "pcm",
+            kind: MediaKind::Audio(AudioInfo {
+                sample_rate: 8000,
+                sample_bpp: 16,
+                sound_type: SoundType::Stereo,
+                codec: AudioCodec::Pcm(PcmCodec {
+                    format: SampleFormat::S16Le,
+                }),
+            }),
+        }),
+        timebase: Fraction::new(1, 8000),
+        delay: 0,
+        metadata: Default::default(),
+    };
+    let packet = Packet {
+        time: MediaTime {
+            pts: 0,
+            dts: None,
+            duration: Some(1000),
+            timebase: track.timebase,
+        },
+        key: true,
+        track: track.clone(),
+        buffer: vec![1; 4000].into(),
+        side_data: Vec::new(),
+    };
+
+    let mut muxer = WavMuxer::new(Io::create_file(dir.join("pcm.wav")).await.unwrap());
+    let movie = Movie {
+        tracks: vec![track],
+        ..Default::default()
+    };
+    write_movie_and_packets(&mut muxer, movie, &[packet.clone()]).await;
+    std::fs::write(dir.join("noise.bin"), [0x5a; 64]).unwrap();
+
+    let mut cxt = MediaContext::default();
+    cxt.register_all();
+    let results = run_conformance(&cxt, &dir).await;
+    let _ = std::fs::remove_dir_all(&dir);
 
-    demuxer.stop().await.unwrap();
+    let report = conformance_report(&cxt, &results);
+    assert_eq!(2, results.len(), "{report}");
+    assert!(results[0].failed(), "{report}");
+    assert_eq!(Some("wav"), results[1].format, "{report}");
+    assert_eq!(Check::Passed, results[1].timestamps, "{report}");
+    assert_eq!(Check::Identical, results[1].round_trip, "{report}");
+    assert!(report.contains("| pcm.wav | wav | 1 |"), "{report}");
 
-    (movie, packets)
+    let mut backwards = packet.clone();
+    backwards.time.pts = 0;
+    let mut forwards = packet;
+    forwards.time.pts = 1000;
+    assert!(matches!(
+        check_timestamps(&[forwards, backwards]),
+        Check::Failed(_)
+    ));
 }
@@ -0,0 +1,289 @@
+//! Helpers for converting, formatting and parsing times.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::Fraction;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TimeParseError {
+    #[error("Empty time")]
+    Empty,
+
+    #[error("Invalid time {0:?}, expected e.g. \"01:02:03.450\", \"90s\" or \"1h2m\"")]
+    Invalid(String),
+}
+
+/// Converts a timestamp in the given timebase to a duration.
+pub fn to_duration(ts: u64, timebase: Fraction) -> Duration {
+    let nanos =
+        ts as u128 * timebase.numerator as u128 * 1_000_000_000 / timebase.denominator as u128;
+
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Converts a duration to a timestamp in the given timebase, rounding down.
+pub fn from_duration(duration: Duration, timebase: Fraction) -> u64 {
+    let ts = duration.as_nanos() * timebase.denominator as u128
+        / timebase.numerator as u128
+        / 1_000_000_000;
+
+    ts as u64
+}
+
+/// Parses a time either in clock format (`"01:02:03.450"`, `"02:03"`) or as a sequence of
+/// numbers with units (`"90s"`, `"1h2m"`, `"1.5s"`, `"250ms"`). A plain number is interpreted as
+/// seconds.
+pub fn parse_duration(val: &str) -> Result<Duration, TimeParseError> {
+    let val = val.trim();
+    if val.is_empty() {
+        return Err(TimeParseError::Empty);
+    }
+
+    let invalid = || TimeParseError::Invalid(val.to_string());
+
+    if val.contains(':') {
+        parse_clock(val).ok_or_else(invalid)
+    } else {
+        parse_units(val).ok_or_else(invalid)
+    }
+}
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+fn parse_clock(val: &str) -> Option<Duration> {
+    let parts = val.split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return None;
+    }
+
+    let (seconds, rest) = parts.split_last()?;
+    let mut total = parse_decimal(&seconds.replace(',', "."), NANOS_PER_SEC)?;
+    if total >= 60 * NANOS_PER_SEC {
+        return None;
+    }
+
+    for (i, part) in rest.iter().rev().enumerate() {
+        if part.contains('.') {
+            return None;
+        }
+
+        let value = parse_decimal(part, 1)?;
+        if i == 0 && rest.len() == 2 && value >= 60 {
+            return None;
+        }
+
+        total = value
+            .checked_mul(60u128.pow(i as u32 + 1) * NANOS_PER_SEC)
+            .and_then(|value| total.checked_add(value))?;
+    }
+
+    Some(nanos_to_duration(total))
+}
+
+fn parse_units(val: &str) -> Option<Duration> {
+    if let Some(nanos) = parse_decimal(val, NANOS_PER_SEC) {
+        return Some(nanos_to_duration(nanos));
+    }
+
+    let mut total = 0u128;
+    let mut rest = val;
+
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|&i| i > 0)?;
+        let (number, tail) = rest.split_at(split);
+
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+
+        let scale = match unit {
+            "h" => 3600 * NANOS_PER_SEC,
+            "m" | "min" => 60 * NANOS_PER_SEC,
+            "s" => NANOS_PER_SEC,
+            "ms" => NANOS_PER_SEC / 1000,
+            _ => return None,
+        };
+
+        total = total.checked_add(parse_decimal(number, scale)?)?;
+        rest = tail;
+    }
+
+    Some(nanos_to_duration(total))
+}
+
+/// Parses a non-negative decimal number, returning it multiplied by `scale`. Digits beyond the
+/// precision of `scale` are truncated.
+fn parse_decimal(val: &str, scale: u128) -> Option<u128> {
+    let (int, fraction) = val.split_once('.').unwrap_or((val, ""));
+
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if int.is_empty() || !is_digits(int) || !is_digits(fraction) {
+        return None;
+    }
+
+    let fraction = &fraction[..fraction.len().min(9)];
+    let fraction_value = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u128>().ok()? * scale / 10u128.pow(fraction.len() as u32)
+    };
+
+    int.parse::<u128>()
+        .ok()?
+        .checked_mul(scale)?
+        .checked_add(fraction_value)
+}
+
+fn nanos_to_duration(nanos: u128) -> Duration {
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// How to round a time which can't be represented exactly with the requested precision.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rounding {
+    Floor,
+    Nearest,
+    Ceil,
+}
+
+/// Formats a duration as `hh:mm:ss.fff`.
+///
+/// Defaults to two hour digits and millisecond precision rounded to the nearest value, as used by
+/// WebVTT. ASS uses a single hour digit and centiseconds instead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockTime {
+    pub duration: Duration,
+    hour_digits: usize,
+    fraction_digits: u32,
+    rounding: Rounding,
+}
+
+impl ClockTime {
+    pub fn new(duration: Duration) -> Self {
+        ClockTime {
+            duration,
+            hour_digits: 2,
+            fraction_digits: 3,
+            rounding: Rounding::Nearest,
+        }
+    }
+
+    /// Creates a time from a timestamp in the given timebase.
+    pub fn from_timestamp(ts: u64, timebase: Fraction) -> Self {
+        Self::new(to_duration(ts, timebase))
+    }
+
+    /// Sets the minimum number of digits used for hours.
+    pub fn with_hour_digits(mut self, digits: usize) -> Self {
+        self.hour_digits = digits;
+        self
+    }
+
+    /// Sets the number of fractional second digits, at most 9.
+    pub fn with_fraction_digits(mut self, digits: u32) -> Self {
+        self.fraction_digits = digits.min(9);
+        self
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+}
+
+impl fmt::Display for ClockTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units_per_second = 10u128.pow(self.fraction_digits);
+        let nanos_per_unit = 1_000_000_000 / units_per_second;
+
+        let nanos = self.duration.as_nanos();
+        let units = match self.rounding {
+            Rounding::Floor => nanos / nanos_per_unit,
+            Rounding::Nearest => (nanos + nanos_per_unit / 2) / nanos_per_unit,
+            Rounding::Ceil => nanos.div_ceil(nanos_per_unit),
+        };
+
+        let fraction = units % units_per_second;
+        let seconds = units / units_per_second;
+        let (h, m, s) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+
+        write!(f, "{h:0width$}:{m:02}:{s:02}", width = self.hour_digits)?;
+
+        if self.fraction_digits > 0 {
+            write!(
+                f,
+                ".{fraction:0width$}",
+                width = self.fraction_digits as usize
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ClockTime {
+    type Err = TimeParseError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        parse_duration(val).map(ClockTime::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("01:02:03.450", 3_723_450 ; "clock")]
+    #[test_case("02:03", 123_000 ; "minutes and seconds")]
+    #[test_case("00:00:01,500", 1_500 ; "srt separator")]
+    #[test_case("90s", 90_000 ; "seconds")]
+    #[test_case("1h2m", 3_720_000 ; "hours and minutes")]
+    #[test_case("1.5s", 1_500 ; "fractional seconds")]
+    #[test_case("250ms", 250 ; "milliseconds")]
+    #[test_case("42", 42_000 ; "plain number")]
+    fn parse(val: &str, expected_ms: u64) {
+        assert_eq!(
+            Duration::from_millis(expected_ms),
+            parse_duration(val).unwrap()
+        );
+    }
+
+    #[test_case("" ; "empty")]
+    #[test_case("1x" ; "unknown unit")]
+    #[test_case("01:60:00" ; "minutes out of range")]
+    #[test_case("1:2:3:4" ; "too many components")]
+    #[test_case("-1s" ; "negative")]
+    #[test_case("999999999999999999999999999999999999h" ; "overflow")]
+    fn parse_invalid(val: &str) {
+        assert!(parse_duration(val).is_err());
+    }
+
+    #[test_case(1_999_500_000, Rounding::Nearest, 2, "0:00:02.00")]
+    #[test_case(1_999_500_000, Rounding::Floor, 2, "0:00:01.99")]
+    #[test_case(1_990_000_001, Rounding::Ceil, 2, "0:00:02.00")]
+    #[test_case(3_599_999_999_999, Rounding::Nearest, 0, "1:00:00")]
+    fn format_rounding(nanos: u64, rounding: Rounding, digits: u32, expected: &str) {
+        let time = ClockTime::new(Duration::from_nanos(nanos))
+            .with_hour_digits(1)
+            .with_fraction_digits(digits)
+            .with_rounding(rounding);
+
+        assert_eq!(expected, time.to_string());
+    }
+
+    #[test]
+    fn timestamp_conversion() {
+        let timebase = Fraction::new(1, 90_000);
+        let duration = to_duration(135_000, timebase);
+
+        assert_eq!(Duration::from_millis(1_500), duration);
+        assert_eq!(135_000, from_duration(duration, timebase));
+    }
+}